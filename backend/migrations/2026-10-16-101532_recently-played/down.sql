@@ -0,0 +1 @@
+DROP TABLE recently_played;
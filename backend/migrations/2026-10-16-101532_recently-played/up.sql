@@ -0,0 +1,11 @@
+CREATE TABLE `spotify_homepage`.`recently_played` (
+  `id` BIGINT NOT NULL AUTO_INCREMENT,
+  `user_id` BIGINT NOT NULL,
+  `mapped_spotify_id` INT NOT NULL,
+  `played_at` DATETIME NOT NULL,
+  `duration_ms` INT UNSIGNED NOT NULL,
+  PRIMARY KEY (`id`),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+  FOREIGN KEY (mapped_spotify_id) REFERENCES spotify_items(id) ON DELETE CASCADE
+);
+ALTER TABLE `spotify_homepage`.`recently_played` ADD UNIQUE `unique_index`(`user_id`, `played_at`);
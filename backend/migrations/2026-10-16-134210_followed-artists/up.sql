@@ -0,0 +1,11 @@
+CREATE TABLE `spotify_homepage`.`followed_artists` (
+  `id` BIGINT NOT NULL AUTO_INCREMENT,
+  `user_id` BIGINT NOT NULL,
+  `mapped_spotify_id` INT NOT NULL,
+  `followed_at` DATETIME NOT NULL,
+  `unfollowed_at` DATETIME NULL,
+  PRIMARY KEY (`id`),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+  FOREIGN KEY (mapped_spotify_id) REFERENCES spotify_items(id) ON DELETE CASCADE
+);
+ALTER TABLE `spotify_homepage`.`followed_artists` ADD INDEX `user_id_followed_at_index`(`user_id`, `followed_at`);
@@ -0,0 +1,2 @@
+ALTER TABLE users DROP COLUMN private_token;
+ALTER TABLE users DROP COLUMN is_private;
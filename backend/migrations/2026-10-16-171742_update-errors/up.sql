@@ -0,0 +1,9 @@
+CREATE TABLE `spotify_homepage`.`update_errors` (
+  `id` BIGINT NOT NULL AUTO_INCREMENT,
+  `user_id` BIGINT NOT NULL,
+  `error_time` DATETIME NOT NULL,
+  `error_message` TEXT NOT NULL,
+  PRIMARY KEY (`id`),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+ALTER TABLE `spotify_homepage`.`update_errors` ADD INDEX `user_id_id_index`(`user_id`, `id`);
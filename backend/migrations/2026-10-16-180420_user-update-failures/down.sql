@@ -0,0 +1 @@
+ALTER TABLE users DROP COLUMN consecutive_update_failures;
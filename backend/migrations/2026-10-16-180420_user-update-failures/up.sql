@@ -0,0 +1,3 @@
+-- Counts the update attempts for each user that have failed permanently, such as because their
+-- refresh token was revoked, since the last successful one so that they can stop being updated
+ALTER TABLE users ADD COLUMN consecutive_update_failures INT NOT NULL DEFAULT 0;
@@ -0,0 +1,12 @@
+CREATE TABLE `spotify_homepage`.`artists` (
+  `spotify_id` VARCHAR(191) NOT NULL,
+  `metadata` MEDIUMTEXT NOT NULL,
+  `last_fetched` DATETIME NOT NULL,
+  PRIMARY KEY (`spotify_id`)
+);
+CREATE TABLE `spotify_homepage`.`tracks` (
+  `spotify_id` VARCHAR(191) NOT NULL,
+  `metadata` MEDIUMTEXT NOT NULL,
+  `last_fetched` DATETIME NOT NULL,
+  PRIMARY KEY (`spotify_id`)
+);
@@ -0,0 +1 @@
+DROP TABLE top_tracks_playlists;
@@ -0,0 +1,7 @@
+CREATE TABLE `spotify_homepage`.`top_tracks_playlists` (
+  `user_id` BIGINT NOT NULL,
+  `timeframe` TINYINT UNSIGNED NOT NULL,
+  `playlist_spotify_id` VARCHAR(64) NOT NULL,
+  PRIMARY KEY (`user_id`, `timeframe`),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,11 @@
+CREATE TABLE `spotify_homepage`.`global_charts` (
+  `entity_type` TINYINT UNSIGNED NOT NULL,
+  `timeframe` TINYINT UNSIGNED NOT NULL,
+  `ranking` SMALLINT UNSIGNED NOT NULL,
+  `mapped_spotify_id` INT NOT NULL,
+  `user_count` BIGINT NOT NULL,
+  `score` BIGINT NOT NULL,
+  `computed_at` DATETIME NOT NULL,
+  PRIMARY KEY (`entity_type`, `timeframe`, `ranking`),
+  FOREIGN KEY (mapped_spotify_id) REFERENCES spotify_items(id) ON DELETE CASCADE
+);
@@ -0,0 +1,7 @@
+CREATE TABLE `spotify_homepage`.`user_settings` (
+  `user_id` BIGINT NOT NULL PRIMARY KEY,
+  `display_name` VARCHAR(255) NULL,
+  `default_timeframe` TINYINT UNSIGNED NOT NULL DEFAULT 0,
+  `update_frequency` TINYINT UNSIGNED NOT NULL DEFAULT 0,
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1 @@
+ALTER TABLE users DROP COLUMN vanity_slug;
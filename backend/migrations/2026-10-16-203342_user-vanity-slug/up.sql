@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN vanity_slug VARCHAR(64) NULL UNIQUE;
@@ -0,0 +1,2 @@
+DROP INDEX unique_ranking_per_update ON `spotify_homepage`.`track_rank_snapshots`;
+DROP INDEX unique_ranking_per_update ON `spotify_homepage`.`artist_rank_snapshots`;
@@ -0,0 +1,18 @@
+-- Remove any duplicate rows left behind by previously retried snapshot stores, keeping the first
+DELETE dup FROM `spotify_homepage`.`track_rank_snapshots` dup
+  INNER JOIN `spotify_homepage`.`track_rank_snapshots` orig
+  ON dup.user_id = orig.user_id
+    AND dup.update_time = orig.update_time
+    AND dup.timeframe = orig.timeframe
+    AND dup.ranking = orig.ranking
+    AND dup.id > orig.id;
+DELETE dup FROM `spotify_homepage`.`artist_rank_snapshots` dup
+  INNER JOIN `spotify_homepage`.`artist_rank_snapshots` orig
+  ON dup.user_id = orig.user_id
+    AND dup.update_time = orig.update_time
+    AND dup.timeframe = orig.timeframe
+    AND dup.ranking = orig.ranking
+    AND dup.id > orig.id;
+
+CREATE UNIQUE INDEX unique_ranking_per_update ON `spotify_homepage`.`track_rank_snapshots` (user_id, update_time, timeframe, ranking);
+CREATE UNIQUE INDEX unique_ranking_per_update ON `spotify_homepage`.`artist_rank_snapshots` (user_id, update_time, timeframe, ranking);
@@ -0,0 +1,20 @@
+-- Materialize all deltas back into full snapshots
+CREATE TABLE `spotify_homepage`.`artist_rank_full` LIKE `spotify_homepage`.`artist_rank_deltas`;
+INSERT INTO `spotify_homepage`.`artist_rank_full` (user_id, update_time, mapped_spotify_id, timeframe, ranking)
+  SELECT user_id, update_time, mapped_spotify_id, timeframe, ranking FROM `spotify_homepage`.`artist_rank_snapshots`;
+CREATE TABLE `spotify_homepage`.`track_rank_full` LIKE `spotify_homepage`.`track_rank_deltas`;
+INSERT INTO `spotify_homepage`.`track_rank_full` (user_id, update_time, mapped_spotify_id, timeframe, ranking)
+  SELECT user_id, update_time, mapped_spotify_id, timeframe, ranking FROM `spotify_homepage`.`track_rank_snapshots`;
+
+DROP VIEW `spotify_homepage`.`artist_rank_snapshots`;
+DROP VIEW `spotify_homepage`.`track_rank_snapshots`;
+DROP TABLE `spotify_homepage`.`artist_rank_deltas`;
+DROP TABLE `spotify_homepage`.`track_rank_deltas`;
+DROP TABLE `spotify_homepage`.`snapshot_updates`;
+
+RENAME TABLE `spotify_homepage`.`artist_rank_full` TO `spotify_homepage`.`artist_rank_snapshots`;
+RENAME TABLE `spotify_homepage`.`track_rank_full` TO `spotify_homepage`.`track_rank_snapshots`;
+DROP INDEX latest_per_ranking ON `spotify_homepage`.`artist_rank_snapshots`;
+DROP INDEX latest_per_ranking ON `spotify_homepage`.`track_rank_snapshots`;
+ALTER TABLE `spotify_homepage`.`artist_rank_snapshots` ADD FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+ALTER TABLE `spotify_homepage`.`track_rank_snapshots` ADD FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
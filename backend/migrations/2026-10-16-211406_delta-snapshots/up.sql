@@ -0,0 +1,65 @@
+-- Rank snapshots are stored delta-encoded: each update only stores rows for the rankings that
+-- changed since the user's previous update.  Every update gets a row in `snapshot_updates` per
+-- timeframe recording how many entries it has, and unchanged rankings are read back from the most
+-- recent row stored for that ranking.  Existing rows are all full snapshots, which are valid deltas.
+RENAME TABLE `spotify_homepage`.`artist_rank_snapshots` TO `spotify_homepage`.`artist_rank_deltas`;
+RENAME TABLE `spotify_homepage`.`track_rank_snapshots` TO `spotify_homepage`.`track_rank_deltas`;
+
+CREATE INDEX latest_per_ranking ON `spotify_homepage`.`artist_rank_deltas` (user_id, timeframe, ranking, update_time);
+CREATE INDEX latest_per_ranking ON `spotify_homepage`.`track_rank_deltas` (user_id, timeframe, ranking, update_time);
+
+CREATE TABLE `spotify_homepage`.`snapshot_updates` (
+  `user_id` BIGINT NOT NULL,
+  `update_time` DATETIME NOT NULL,
+  `timeframe` TINYINT UNSIGNED NOT NULL,
+  `artist_count` TINYINT UNSIGNED NOT NULL,
+  `track_count` TINYINT UNSIGNED NOT NULL,
+  PRIMARY KEY (`user_id`, `update_time`, `timeframe`),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+INSERT INTO `spotify_homepage`.`snapshot_updates` (user_id, update_time, timeframe, artist_count, track_count)
+  SELECT user_id, update_time, timeframe, MAX(artist_count), MAX(track_count)
+  FROM (
+    SELECT user_id, update_time, timeframe, MAX(ranking) + 1 AS artist_count, 0 AS track_count
+      FROM `spotify_homepage`.`artist_rank_deltas`
+      GROUP BY user_id, update_time, timeframe
+    UNION ALL
+    SELECT user_id, update_time, timeframe, 0 AS artist_count, MAX(ranking) + 1 AS track_count
+      FROM `spotify_homepage`.`track_rank_deltas`
+      GROUP BY user_id, update_time, timeframe
+  ) counts
+  GROUP BY user_id, update_time, timeframe;
+
+-- Materialized full snapshots.  These keep the old table names so that everything reading
+-- snapshots is unaffected; writes must go to the deltas tables.
+CREATE VIEW `spotify_homepage`.`artist_rank_snapshots` AS
+  SELECT d.id, u.user_id, u.update_time, d.mapped_spotify_id, u.timeframe, d.ranking
+  FROM `spotify_homepage`.`snapshot_updates` u
+  INNER JOIN `spotify_homepage`.`artist_rank_deltas` d
+    ON d.user_id = u.user_id
+    AND d.timeframe = u.timeframe
+    AND d.ranking < u.artist_count
+    AND d.update_time = (
+      SELECT MAX(latest.update_time)
+      FROM `spotify_homepage`.`artist_rank_deltas` latest
+      WHERE latest.user_id = u.user_id
+        AND latest.timeframe = u.timeframe
+        AND latest.ranking = d.ranking
+        AND latest.update_time <= u.update_time
+    );
+CREATE VIEW `spotify_homepage`.`track_rank_snapshots` AS
+  SELECT d.id, u.user_id, u.update_time, d.mapped_spotify_id, u.timeframe, d.ranking
+  FROM `spotify_homepage`.`snapshot_updates` u
+  INNER JOIN `spotify_homepage`.`track_rank_deltas` d
+    ON d.user_id = u.user_id
+    AND d.timeframe = u.timeframe
+    AND d.ranking < u.track_count
+    AND d.update_time = (
+      SELECT MAX(latest.update_time)
+      FROM `spotify_homepage`.`track_rank_deltas` latest
+      WHERE latest.user_id = u.user_id
+        AND latest.timeframe = u.timeframe
+        AND latest.ranking = d.ranking
+        AND latest.update_time <= u.update_time
+    );
@@ -0,0 +1 @@
+DROP TABLE `spotify_homepage`.`system_stats`;
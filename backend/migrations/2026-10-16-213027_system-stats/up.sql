@@ -0,0 +1,12 @@
+CREATE TABLE `spotify_homepage`.`system_stats` (
+  `id` BIGINT NOT NULL AUTO_INCREMENT,
+  `computed_at` DATETIME NOT NULL,
+  `user_count` BIGINT NOT NULL,
+  `snapshot_count` BIGINT NOT NULL,
+  -- JSON object mapping table name to approximate row count
+  `table_row_counts` TEXT NOT NULL,
+  `database_size_bytes` BIGINT NOT NULL,
+  `cache_size_bytes` BIGINT NULL,
+  PRIMARY KEY (`id`),
+  INDEX `computed_at_ix` (`computed_at`)
+);
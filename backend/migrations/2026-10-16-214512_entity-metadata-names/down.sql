@@ -0,0 +1,2 @@
+ALTER TABLE `spotify_homepage`.`artists` DROP COLUMN `name`;
+ALTER TABLE `spotify_homepage`.`tracks` DROP COLUMN `name`;
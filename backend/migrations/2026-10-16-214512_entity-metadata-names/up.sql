@@ -0,0 +1,5 @@
+-- Extracted from the stored metadata so that users' histories can be searched by name
+ALTER TABLE `spotify_homepage`.`artists`
+  ADD COLUMN `name` VARCHAR(512) GENERATED ALWAYS AS (JSON_UNQUOTE(JSON_EXTRACT(`metadata`, '$.name'))) STORED;
+ALTER TABLE `spotify_homepage`.`tracks`
+  ADD COLUMN `name` VARCHAR(512) GENERATED ALWAYS AS (JSON_UNQUOTE(JSON_EXTRACT(`metadata`, '$.name'))) STORED;
@@ -0,0 +1,11 @@
+-- Links additional Spotify accounts to a primary user so that the primary user's profile can show
+-- stats aggregated across all of them.  An account can be linked to at most one primary user.
+CREATE TABLE `spotify_homepage`.`linked_accounts` (
+  `primary_user_id` BIGINT NOT NULL,
+  `linked_user_id` BIGINT NOT NULL,
+  `created_at` DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (`primary_user_id`, `linked_user_id`),
+  UNIQUE KEY `linked_user_id` (`linked_user_id`),
+  FOREIGN KEY (primary_user_id) REFERENCES users(id) ON DELETE CASCADE,
+  FOREIGN KEY (linked_user_id) REFERENCES users(id) ON DELETE CASCADE
+);
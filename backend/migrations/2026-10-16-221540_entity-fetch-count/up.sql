@@ -0,0 +1,2 @@
+-- Overrides the deployment's `ENTITY_FETCH_COUNT` for the user if set
+ALTER TABLE `spotify_homepage`.`user_settings` ADD COLUMN `entity_fetch_count` TINYINT UNSIGNED NULL;
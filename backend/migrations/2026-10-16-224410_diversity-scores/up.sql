@@ -0,0 +1,11 @@
+-- Listening diversity metrics for each timeframe of a stats snapshot, computed when the snapshot is
+-- stored.  Snapshots stored before this table existed have no scores.
+CREATE TABLE `spotify_homepage`.`diversity_scores` (
+  `user_id` BIGINT NOT NULL,
+  `update_time` DATETIME NOT NULL,
+  `timeframe` TINYINT UNSIGNED NOT NULL,
+  `genre_entropy` FLOAT NOT NULL,
+  `artist_gini` FLOAT NOT NULL,
+  PRIMARY KEY (`user_id`, `update_time`, `timeframe`),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1 @@
+DROP TABLE `spotify_homepage`.`track_isrc_map`;
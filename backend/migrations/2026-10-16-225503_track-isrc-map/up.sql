@@ -0,0 +1,9 @@
+-- Maps tracks to their ISRC so that the same recording released under multiple Spotify IDs (single,
+-- album, deluxe edition, etc.) can be merged.  The lowest Spotify ID with a given ISRC is used as
+-- the canonical ID for all of them.
+CREATE TABLE `spotify_homepage`.`track_isrc_map` (
+  `spotify_id` VARCHAR(191) NOT NULL,
+  `isrc` VARCHAR(32) NOT NULL,
+  PRIMARY KEY (`spotify_id`),
+  INDEX `isrc_ix` (`isrc`)
+);
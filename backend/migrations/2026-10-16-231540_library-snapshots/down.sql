@@ -0,0 +1,2 @@
+DROP TABLE `spotify_homepage`.`saved_tracks`;
+DROP TABLE `spotify_homepage`.`library_snapshots`;
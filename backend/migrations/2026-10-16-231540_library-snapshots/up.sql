@@ -0,0 +1,18 @@
+-- Number of tracks in each user's saved tracks library as of each update
+CREATE TABLE `spotify_homepage`.`library_snapshots` (
+  `user_id` BIGINT NOT NULL,
+  `update_time` DATETIME NOT NULL,
+  `saved_track_count` INT UNSIGNED NOT NULL,
+  PRIMARY KEY (`user_id`, `update_time`),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+-- The most recently saved tracks seen in each user's library at each update
+CREATE TABLE `spotify_homepage`.`saved_tracks` (
+  `user_id` BIGINT NOT NULL,
+  `mapped_spotify_id` INT NOT NULL,
+  `added_at` DATETIME NOT NULL,
+  PRIMARY KEY (`user_id`, `mapped_spotify_id`),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+  FOREIGN KEY (mapped_spotify_id) REFERENCES spotify_items(id) ON DELETE CASCADE
+);
+ALTER TABLE `spotify_homepage`.`saved_tracks` ADD INDEX `user_id_added_at_index`(`user_id`, `added_at`);
@@ -0,0 +1 @@
+DROP TABLE `spotify_homepage`.`email_subscriptions`;
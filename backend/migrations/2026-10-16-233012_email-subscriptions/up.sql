@@ -0,0 +1,10 @@
+-- Users that opted in to a weekly email digest of their stats
+CREATE TABLE `spotify_homepage`.`email_subscriptions` (
+  `user_id` BIGINT NOT NULL PRIMARY KEY,
+  `email` VARCHAR(254) NOT NULL,
+  `unsubscribe_token` VARCHAR(64) NOT NULL,
+  `created_at` DATETIME NOT NULL,
+  `last_sent_at` DATETIME NULL,
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+ALTER TABLE `spotify_homepage`.`email_subscriptions` ADD UNIQUE INDEX `unsubscribe_token_index`(`unsubscribe_token`);
@@ -0,0 +1,2 @@
+DROP INDEX mapped_spotify_id_per_user ON `spotify_homepage`.`artist_rank_deltas`;
+ALTER TABLE `spotify_homepage`.`user_settings` DROP COLUMN `leaderboard_opt_in`;
@@ -0,0 +1,4 @@
+-- Users only appear on artist leaderboards if they opt in
+ALTER TABLE `spotify_homepage`.`user_settings` ADD COLUMN `leaderboard_opt_in` BOOLEAN NOT NULL DEFAULT FALSE;
+-- Used to find every user's rankings of an artist when building its leaderboard
+CREATE INDEX mapped_spotify_id_per_user ON `spotify_homepage`.`artist_rank_deltas` (mapped_spotify_id, user_id, update_time);
@@ -0,0 +1,2 @@
+ALTER TABLE `spotify_homepage`.`user_settings` DROP COLUMN `friends_only_comparisons`;
+DROP TABLE `spotify_homepage`.`friendships`;
@@ -0,0 +1,13 @@
+-- Friend requests between users.  A request becomes a friendship once the addressee accepts it.
+CREATE TABLE `spotify_homepage`.`friendships` (
+  `requester_id` BIGINT NOT NULL,
+  `addressee_id` BIGINT NOT NULL,
+  `requested_at` DATETIME NOT NULL,
+  `accepted_at` DATETIME NULL,
+  PRIMARY KEY (`requester_id`, `addressee_id`),
+  KEY `addressee_id` (`addressee_id`),
+  FOREIGN KEY (requester_id) REFERENCES users(id) ON DELETE CASCADE,
+  FOREIGN KEY (addressee_id) REFERENCES users(id) ON DELETE CASCADE
+);
+-- Semi-private users' stats can only be compared with by their friends
+ALTER TABLE `spotify_homepage`.`user_settings` ADD COLUMN `friends_only_comparisons` BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1 @@
+DROP TABLE `spotify_homepage`.`genre_weights`;
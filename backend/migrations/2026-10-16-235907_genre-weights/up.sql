@@ -0,0 +1,11 @@
+-- Weights of the top genres among the top artists of each timeframe of a stats snapshot, computed
+-- when the snapshot is stored.  Snapshots stored before this table existed have no weights.
+CREATE TABLE `spotify_homepage`.`genre_weights` (
+  `user_id` BIGINT NOT NULL,
+  `update_time` DATETIME NOT NULL,
+  `timeframe` TINYINT UNSIGNED NOT NULL,
+  `genre` VARCHAR(255) NOT NULL,
+  `weight` INT UNSIGNED NOT NULL,
+  PRIMARY KEY (`user_id`, `update_time`, `timeframe`, `genre`),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
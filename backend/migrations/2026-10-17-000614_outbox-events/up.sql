@@ -0,0 +1,15 @@
+-- Side effects of storing stats snapshots.  Events are written in the same transaction as the
+-- snapshot and delivered afterwards by the outbox dispatcher.
+CREATE TABLE `spotify_homepage`.`outbox_events` (
+  `id` BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  `user_id` BIGINT NOT NULL,
+  `event_type` VARCHAR(64) NOT NULL,
+  `payload` TEXT NOT NULL,
+  `created_at` DATETIME NOT NULL,
+  `attempt_count` INT NOT NULL DEFAULT 0,
+  `next_attempt_at` DATETIME NOT NULL,
+  `delivered_at` DATETIME NULL,
+  `last_error` TEXT NULL,
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+ALTER TABLE `spotify_homepage`.`outbox_events` ADD INDEX `outbox_events_pending_index`(`delivered_at`, `next_attempt_at`);
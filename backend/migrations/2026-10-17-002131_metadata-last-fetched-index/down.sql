@@ -0,0 +1,2 @@
+DROP INDEX artists_last_fetched_index ON `spotify_homepage`.`artists`;
+DROP INDEX tracks_last_fetched_index ON `spotify_homepage`.`tracks`;
@@ -0,0 +1,3 @@
+-- Used by the metadata refresh job to find the entities with the oldest metadata
+ALTER TABLE `spotify_homepage`.`artists` ADD INDEX `artists_last_fetched_index`(`last_fetched`);
+ALTER TABLE `spotify_homepage`.`tracks` ADD INDEX `tracks_last_fetched_index`(`last_fetched`);
@@ -0,0 +1 @@
+DROP TABLE `spotify_homepage`.`popularity_history`;
@@ -0,0 +1,14 @@
+-- Spotify's popularity score of each of the top artists and tracks in a stats snapshot.  It changes
+-- over time, so it's recorded when the snapshot is stored.  `entity_type` is 0 for artists and 1
+-- for tracks.  Snapshots stored before this table existed have no popularity scores.
+CREATE TABLE `spotify_homepage`.`popularity_history` (
+  `user_id` BIGINT NOT NULL,
+  `update_time` DATETIME NOT NULL,
+  `timeframe` TINYINT UNSIGNED NOT NULL,
+  `entity_type` TINYINT UNSIGNED NOT NULL,
+  `ranking` TINYINT UNSIGNED NOT NULL,
+  `mapped_spotify_id` INT NOT NULL,
+  `popularity` TINYINT UNSIGNED NOT NULL,
+  PRIMARY KEY (`user_id`, `update_time`, `timeframe`, `entity_type`, `ranking`),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,3 @@
+-- How often the update scheduler updates the user, recomputed periodically from how recently their
+-- profile was viewed.  0 = active, 1 = normal, 2 = dormant.
+ALTER TABLE users ADD COLUMN update_tier TINYINT UNSIGNED NOT NULL DEFAULT 1;
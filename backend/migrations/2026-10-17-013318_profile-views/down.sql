@@ -0,0 +1 @@
+DROP TABLE `spotify_homepage`.`profile_views`;
@@ -0,0 +1,9 @@
+-- Number of times each user's stats profile was viewed per day, with `view_date` at midnight UTC.
+-- Only daily totals are stored; nothing about who viewed the profile is recorded.
+CREATE TABLE `spotify_homepage`.`profile_views` (
+  `user_id` BIGINT NOT NULL,
+  `view_date` DATETIME NOT NULL,
+  `view_count` INT NOT NULL,
+  PRIMARY KEY (`user_id`, `view_date`),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1 @@
+DROP TABLE `spotify_homepage`.`api_keys`;
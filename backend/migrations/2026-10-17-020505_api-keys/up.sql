@@ -0,0 +1,12 @@
+-- Keys that third-party apps authenticate with.  Only a hash of each key is stored; the key itself
+-- is returned once when it's minted.  `scopes` is a comma-separated list of `ApiKeyScope` names.
+CREATE TABLE `spotify_homepage`.`api_keys` (
+  `id` BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  `name` VARCHAR(255) NOT NULL,
+  `key_hash` CHAR(64) NOT NULL,
+  `scopes` VARCHAR(255) NOT NULL,
+  `rate_limit_per_minute` INT NOT NULL,
+  `created_at` DATETIME NOT NULL,
+  `revoked_at` DATETIME NULL
+);
+ALTER TABLE `spotify_homepage`.`api_keys` ADD UNIQUE INDEX `api_keys_key_hash_index`(`key_hash`);
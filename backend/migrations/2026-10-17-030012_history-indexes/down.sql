@@ -0,0 +1,2 @@
+DROP INDEX user_mapped_spotify_id ON `spotify_homepage`.`artist_rank_deltas`;
+DROP INDEX user_mapped_spotify_id ON `spotify_homepage`.`track_rank_deltas`;
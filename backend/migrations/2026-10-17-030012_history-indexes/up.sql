@@ -0,0 +1,5 @@
+-- Used by the rank history endpoints, which look up every appearance of a few artists or tracks in a
+-- user's history.  `artist_rank_deltas` has an index on `(mapped_spotify_id, user_id, update_time)`
+-- already, but it's ordered for finding the users an artist appears for.
+CREATE INDEX user_mapped_spotify_id ON `spotify_homepage`.`artist_rank_deltas` (user_id, mapped_spotify_id, update_time);
+CREATE INDEX user_mapped_spotify_id ON `spotify_homepage`.`track_rank_deltas` (user_id, mapped_spotify_id, update_time);
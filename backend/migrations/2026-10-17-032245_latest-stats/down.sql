@@ -0,0 +1,2 @@
+DROP TABLE `spotify_homepage`.`latest_artist_stats`;
+DROP TABLE `spotify_homepage`.`latest_track_stats`;
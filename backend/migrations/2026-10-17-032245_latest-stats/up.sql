@@ -0,0 +1,37 @@
+-- Full copies of each user's most recent snapshot, replaced in the same transaction that stores a
+-- new one.  Current stats are read from these rather than materialized from the deltas.
+CREATE TABLE `spotify_homepage`.`latest_artist_stats` (
+  `user_id` BIGINT NOT NULL,
+  `timeframe` TINYINT UNSIGNED NOT NULL,
+  `ranking` TINYINT UNSIGNED NOT NULL,
+  `mapped_spotify_id` INT NOT NULL,
+  `update_time` DATETIME NOT NULL,
+  PRIMARY KEY (`user_id`, `timeframe`, `ranking`),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE TABLE `spotify_homepage`.`latest_track_stats` (
+  `user_id` BIGINT NOT NULL,
+  `timeframe` TINYINT UNSIGNED NOT NULL,
+  `ranking` TINYINT UNSIGNED NOT NULL,
+  `mapped_spotify_id` INT NOT NULL,
+  `update_time` DATETIME NOT NULL,
+  PRIMARY KEY (`user_id`, `timeframe`, `ranking`),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+INSERT INTO `spotify_homepage`.`latest_artist_stats` (user_id, timeframe, ranking, mapped_spotify_id, update_time)
+  SELECT s.user_id, s.timeframe, s.ranking, s.mapped_spotify_id, s.update_time
+  FROM `spotify_homepage`.`artist_rank_snapshots` s
+  WHERE s.update_time = (
+    SELECT MAX(u.update_time)
+    FROM `spotify_homepage`.`snapshot_updates` u
+    WHERE u.user_id = s.user_id AND u.artist_count > 0
+  );
+INSERT INTO `spotify_homepage`.`latest_track_stats` (user_id, timeframe, ranking, mapped_spotify_id, update_time)
+  SELECT s.user_id, s.timeframe, s.ranking, s.mapped_spotify_id, s.update_time
+  FROM `spotify_homepage`.`track_rank_snapshots` s
+  WHERE s.update_time = (
+    SELECT MAX(u.update_time)
+    FROM `spotify_homepage`.`snapshot_updates` u
+    WHERE u.user_id = s.user_id AND u.track_count > 0
+  );
@@ -0,0 +1 @@
+ALTER TABLE `spotify_homepage`.`users` DROP COLUMN `min_update_interval_seconds`;
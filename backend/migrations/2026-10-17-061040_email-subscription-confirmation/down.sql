@@ -0,0 +1,3 @@
+ALTER TABLE `spotify_homepage`.`email_subscriptions` DROP INDEX `confirmation_token_index`;
+ALTER TABLE `spotify_homepage`.`email_subscriptions` DROP COLUMN `confirmed_at`;
+ALTER TABLE `spotify_homepage`.`email_subscriptions` DROP COLUMN `confirmation_token`;
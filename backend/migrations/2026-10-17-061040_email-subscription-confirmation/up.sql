@@ -0,0 +1,6 @@
+-- Subscriptions only receive digests once the address has been confirmed via the link emailed to
+-- it.  Existing subscriptions are treated as confirmed.
+ALTER TABLE `spotify_homepage`.`email_subscriptions` ADD COLUMN `confirmation_token` VARCHAR(64) NULL;
+ALTER TABLE `spotify_homepage`.`email_subscriptions` ADD COLUMN `confirmed_at` DATETIME NULL;
+ALTER TABLE `spotify_homepage`.`email_subscriptions` ADD UNIQUE INDEX `confirmation_token_index`(`confirmation_token`);
+UPDATE `spotify_homepage`.`email_subscriptions` SET `confirmed_at` = `created_at`;
@@ -0,0 +1 @@
+DROP INDEX by_artist ON `spotify_homepage`.`latest_artist_stats`;
@@ -0,0 +1 @@
+ALTER TABLE `spotify_homepage`.`user_settings` DROP COLUMN `library_public`;
@@ -0,0 +1,2 @@
+-- Users can opt into showing their saved tracks library on their public profiles
+ALTER TABLE `spotify_homepage`.`user_settings` ADD COLUMN `library_public` BOOLEAN NOT NULL DEFAULT FALSE;
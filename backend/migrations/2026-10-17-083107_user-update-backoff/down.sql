@@ -0,0 +1,2 @@
+ALTER TABLE `spotify_homepage`.`users` DROP COLUMN `next_update_attempt_at`;
+ALTER TABLE `spotify_homepage`.`users` DROP COLUMN `failed_update_attempts`;
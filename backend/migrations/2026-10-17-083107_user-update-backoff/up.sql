@@ -0,0 +1,4 @@
+-- Users whose updates fail are retried with an exponentially increasing delay so that transient
+-- errors like Spotify outages don't cause them to be retried on every scheduler pass
+ALTER TABLE `spotify_homepage`.`users` ADD COLUMN `failed_update_attempts` INT NOT NULL DEFAULT 0;
+ALTER TABLE `spotify_homepage`.`users` ADD COLUMN `next_update_attempt_at` DATETIME NULL;
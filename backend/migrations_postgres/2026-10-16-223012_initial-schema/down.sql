@@ -0,0 +1,26 @@
+DROP TABLE linked_accounts;
+DROP TABLE system_stats;
+DROP TABLE user_settings;
+DROP TABLE global_charts;
+DROP TABLE top_tracks_playlists;
+DROP TABLE tracks;
+DROP TABLE artists;
+DROP TABLE update_errors;
+DROP TABLE followed_artists;
+DROP TABLE recently_played;
+DROP TABLE related_artists;
+DROP TRIGGER update_first_seen_tracks ON track_rank_deltas;
+DROP FUNCTION update_first_seen_tracks();
+DROP TRIGGER update_first_seen_artists ON artist_rank_deltas;
+DROP FUNCTION update_first_seen_artists();
+DROP TABLE tracks_users_first_seen;
+DROP TABLE artists_users_first_seen;
+DROP VIEW track_rank_snapshots;
+DROP VIEW artist_rank_snapshots;
+DROP TABLE snapshot_updates;
+DROP TABLE track_rank_deltas;
+DROP TABLE artist_rank_deltas;
+DROP TABLE artists_genres;
+DROP TABLE tracks_artists;
+DROP TABLE spotify_items;
+DROP TABLE users;
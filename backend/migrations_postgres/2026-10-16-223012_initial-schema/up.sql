@@ -0,0 +1,246 @@
+-- The full schema as of the MySQL migrations up to `2026-10-16-221540_entity-fetch-count`.
+-- Postgres has no unsigned integer types, so MySQL's unsigned columns are stored in the next larger
+-- signed type.  Any new MySQL migration needs a matching migration here.
+
+CREATE TABLE users (
+  id BIGSERIAL PRIMARY KEY,
+  creation_time TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_update_time TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  spotify_id VARCHAR(191) UNIQUE NOT NULL,
+  username TEXT NOT NULL,
+  token TEXT NOT NULL,
+  refresh_token TEXT NOT NULL,
+  external_data_retrieved BOOLEAN NOT NULL DEFAULT TRUE,
+  last_viewed TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_external_data_store TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  is_private BOOLEAN NOT NULL DEFAULT FALSE,
+  private_token VARCHAR(64) NULL,
+  consecutive_update_failures INTEGER NOT NULL DEFAULT 0,
+  timezone VARCHAR(64) NULL,
+  vanity_slug VARCHAR(64) NULL UNIQUE
+);
+CREATE INDEX users_update_time_ix ON users (last_update_time);
+
+-- Table for mapping between spotify IDs and internal IDs
+CREATE TABLE spotify_items (
+  id SERIAL PRIMARY KEY,
+  spotify_id VARCHAR(191) UNIQUE NOT NULL
+);
+
+-- Table for parent/child relationships between artists and tracks.  Tracks can have multiple parents.
+CREATE TABLE tracks_artists (
+  id SERIAL PRIMARY KEY,
+  track_id INTEGER NOT NULL REFERENCES spotify_items(id) ON DELETE CASCADE,
+  artist_id INTEGER NOT NULL REFERENCES spotify_items(id) ON DELETE CASCADE,
+  UNIQUE (track_id, artist_id)
+);
+CREATE INDEX tracks_artists_artist_id_ix ON tracks_artists (artist_id);
+
+-- Table for parent/child relationships between genres and artists.  Artists can have multiple parents.
+CREATE TABLE artists_genres (
+  id BIGSERIAL PRIMARY KEY,
+  artist_id INTEGER NOT NULL REFERENCES spotify_items(id) ON DELETE CASCADE,
+  genre VARCHAR(191) NOT NULL,
+  UNIQUE (genre, artist_id)
+);
+
+-- Rank snapshots are stored delta-encoded: each update only stores rows for the rankings that
+-- changed since the user's previous update.  Every update gets a row in `snapshot_updates` per
+-- timeframe recording how many entries it has, and unchanged rankings are read back from the most
+-- recent row stored for that ranking.
+CREATE TABLE artist_rank_deltas (
+  id BIGSERIAL PRIMARY KEY,
+  user_id BIGINT NOT NULL,
+  update_time TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  mapped_spotify_id INTEGER NOT NULL,
+  timeframe SMALLINT NOT NULL,
+  ranking SMALLINT NOT NULL,
+  UNIQUE (user_id, update_time, timeframe, ranking)
+);
+CREATE INDEX artist_rank_deltas_user_id_ix ON artist_rank_deltas (user_id);
+CREATE INDEX artist_rank_deltas_update_time_ix ON artist_rank_deltas (update_time);
+CREATE INDEX artist_rank_deltas_latest_per_ranking ON artist_rank_deltas (user_id, timeframe, ranking, update_time);
+
+CREATE TABLE track_rank_deltas (
+  id BIGSERIAL PRIMARY KEY,
+  user_id BIGINT NOT NULL,
+  update_time TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  mapped_spotify_id INTEGER NOT NULL,
+  timeframe SMALLINT NOT NULL,
+  ranking SMALLINT NOT NULL,
+  UNIQUE (user_id, update_time, timeframe, ranking)
+);
+CREATE INDEX track_rank_deltas_user_id_ix ON track_rank_deltas (user_id);
+CREATE INDEX track_rank_deltas_update_time_ix ON track_rank_deltas (update_time);
+CREATE INDEX track_rank_deltas_latest_per_ranking ON track_rank_deltas (user_id, timeframe, ranking, update_time);
+
+CREATE TABLE snapshot_updates (
+  user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  update_time TIMESTAMP NOT NULL,
+  timeframe SMALLINT NOT NULL,
+  artist_count SMALLINT NOT NULL,
+  track_count SMALLINT NOT NULL,
+  PRIMARY KEY (user_id, update_time, timeframe)
+);
+
+-- Materialized full snapshots.  Writes must go to the deltas tables.
+CREATE VIEW artist_rank_snapshots AS
+  SELECT d.id, u.user_id, u.update_time, d.mapped_spotify_id, u.timeframe, d.ranking
+  FROM snapshot_updates u
+  INNER JOIN artist_rank_deltas d
+    ON d.user_id = u.user_id
+    AND d.timeframe = u.timeframe
+    AND d.ranking < u.artist_count
+    AND d.update_time = (
+      SELECT MAX(latest.update_time)
+      FROM artist_rank_deltas latest
+      WHERE latest.user_id = u.user_id
+        AND latest.timeframe = u.timeframe
+        AND latest.ranking = d.ranking
+        AND latest.update_time <= u.update_time
+    );
+CREATE VIEW track_rank_snapshots AS
+  SELECT d.id, u.user_id, u.update_time, d.mapped_spotify_id, u.timeframe, d.ranking
+  FROM snapshot_updates u
+  INNER JOIN track_rank_deltas d
+    ON d.user_id = u.user_id
+    AND d.timeframe = u.timeframe
+    AND d.ranking < u.track_count
+    AND d.update_time = (
+      SELECT MAX(latest.update_time)
+      FROM track_rank_deltas latest
+      WHERE latest.user_id = u.user_id
+        AND latest.timeframe = u.timeframe
+        AND latest.ranking = d.ranking
+        AND latest.update_time <= u.update_time
+    );
+
+CREATE TABLE artists_users_first_seen (
+  user_id BIGINT NOT NULL,
+  mapped_spotify_id INTEGER NOT NULL,
+  first_seen TIMESTAMP NOT NULL,
+  PRIMARY KEY (user_id, mapped_spotify_id)
+);
+
+CREATE TABLE tracks_users_first_seen (
+  user_id BIGINT NOT NULL,
+  mapped_spotify_id INTEGER NOT NULL,
+  first_seen TIMESTAMP NOT NULL,
+  PRIMARY KEY (user_id, mapped_spotify_id)
+);
+
+-- Triggers to auto-update these tables every time a track or artist is inserted
+CREATE FUNCTION update_first_seen_artists() RETURNS TRIGGER AS $$
+BEGIN
+  INSERT INTO artists_users_first_seen (user_id, mapped_spotify_id, first_seen)
+  VALUES (NEW.user_id, NEW.mapped_spotify_id, NEW.update_time)
+  ON CONFLICT DO NOTHING;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+CREATE TRIGGER update_first_seen_artists BEFORE INSERT ON artist_rank_deltas
+  FOR EACH ROW EXECUTE FUNCTION update_first_seen_artists();
+
+CREATE FUNCTION update_first_seen_tracks() RETURNS TRIGGER AS $$
+BEGIN
+  INSERT INTO tracks_users_first_seen (user_id, mapped_spotify_id, first_seen)
+  VALUES (NEW.user_id, NEW.mapped_spotify_id, NEW.update_time)
+  ON CONFLICT DO NOTHING;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+CREATE TRIGGER update_first_seen_tracks BEFORE INSERT ON track_rank_deltas
+  FOR EACH ROW EXECUTE FUNCTION update_first_seen_tracks();
+
+CREATE TABLE related_artists (
+  artist_spotify_id INTEGER PRIMARY KEY NOT NULL REFERENCES spotify_items(id),
+  related_artists_json TEXT NOT NULL
+);
+
+CREATE TABLE recently_played (
+  id BIGSERIAL PRIMARY KEY,
+  user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  mapped_spotify_id INTEGER NOT NULL REFERENCES spotify_items(id) ON DELETE CASCADE,
+  played_at TIMESTAMP NOT NULL,
+  duration_ms BIGINT NOT NULL,
+  UNIQUE (user_id, played_at)
+);
+
+CREATE TABLE followed_artists (
+  id BIGSERIAL PRIMARY KEY,
+  user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  mapped_spotify_id INTEGER NOT NULL REFERENCES spotify_items(id) ON DELETE CASCADE,
+  followed_at TIMESTAMP NOT NULL,
+  unfollowed_at TIMESTAMP NULL
+);
+CREATE INDEX followed_artists_user_id_followed_at_index ON followed_artists (user_id, followed_at);
+
+CREATE TABLE update_errors (
+  id BIGSERIAL PRIMARY KEY,
+  user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  error_time TIMESTAMP NOT NULL,
+  error_message TEXT NOT NULL
+);
+CREATE INDEX update_errors_user_id_id_index ON update_errors (user_id, id);
+
+-- `name` is extracted from the stored metadata so that users' histories can be searched by name
+CREATE TABLE artists (
+  spotify_id VARCHAR(191) PRIMARY KEY,
+  metadata TEXT NOT NULL,
+  last_fetched TIMESTAMP NOT NULL,
+  name VARCHAR(512) GENERATED ALWAYS AS (metadata::json->>'name') STORED
+);
+CREATE TABLE tracks (
+  spotify_id VARCHAR(191) PRIMARY KEY,
+  metadata TEXT NOT NULL,
+  last_fetched TIMESTAMP NOT NULL,
+  name VARCHAR(512) GENERATED ALWAYS AS (metadata::json->>'name') STORED
+);
+
+CREATE TABLE top_tracks_playlists (
+  user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  timeframe SMALLINT NOT NULL,
+  playlist_spotify_id VARCHAR(64) NOT NULL,
+  PRIMARY KEY (user_id, timeframe)
+);
+
+CREATE TABLE global_charts (
+  entity_type SMALLINT NOT NULL,
+  timeframe SMALLINT NOT NULL,
+  ranking INTEGER NOT NULL,
+  mapped_spotify_id INTEGER NOT NULL REFERENCES spotify_items(id) ON DELETE CASCADE,
+  user_count BIGINT NOT NULL,
+  score BIGINT NOT NULL,
+  computed_at TIMESTAMP NOT NULL,
+  PRIMARY KEY (entity_type, timeframe, ranking)
+);
+
+CREATE TABLE user_settings (
+  user_id BIGINT NOT NULL PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+  display_name VARCHAR(255) NULL,
+  default_timeframe SMALLINT NOT NULL DEFAULT 0,
+  update_frequency SMALLINT NOT NULL DEFAULT 0,
+  -- Overrides the deployment's `ENTITY_FETCH_COUNT` for the user if set
+  entity_fetch_count SMALLINT NULL
+);
+
+CREATE TABLE system_stats (
+  id BIGSERIAL PRIMARY KEY,
+  computed_at TIMESTAMP NOT NULL,
+  user_count BIGINT NOT NULL,
+  snapshot_count BIGINT NOT NULL,
+  -- JSON object mapping table name to approximate row count
+  table_row_counts TEXT NOT NULL,
+  database_size_bytes BIGINT NOT NULL,
+  cache_size_bytes BIGINT NULL
+);
+CREATE INDEX system_stats_computed_at_ix ON system_stats (computed_at);
+
+-- Links additional Spotify accounts to a primary user so that the primary user's profile can show
+-- stats aggregated across all of them.  An account can be linked to at most one primary user.
+CREATE TABLE linked_accounts (
+  primary_user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  linked_user_id BIGINT NOT NULL UNIQUE REFERENCES users(id) ON DELETE CASCADE,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (primary_user_id, linked_user_id)
+);
@@ -0,0 +1 @@
+DROP TABLE diversity_scores;
@@ -0,0 +1,10 @@
+-- Listening diversity metrics for each timeframe of a stats snapshot, computed when the snapshot is
+-- stored.  Snapshots stored before this table existed have no scores.
+CREATE TABLE diversity_scores (
+  user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  update_time TIMESTAMP NOT NULL,
+  timeframe SMALLINT NOT NULL,
+  genre_entropy REAL NOT NULL,
+  artist_gini REAL NOT NULL,
+  PRIMARY KEY (user_id, update_time, timeframe)
+);
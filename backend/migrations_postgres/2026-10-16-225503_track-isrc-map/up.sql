@@ -0,0 +1,8 @@
+-- Maps tracks to their ISRC so that the same recording released under multiple Spotify IDs (single,
+-- album, deluxe edition, etc.) can be merged.  The lowest Spotify ID with a given ISRC is used as
+-- the canonical ID for all of them.
+CREATE TABLE track_isrc_map (
+  spotify_id VARCHAR(191) PRIMARY KEY,
+  isrc VARCHAR(32) NOT NULL
+);
+CREATE INDEX track_isrc_map_isrc_ix ON track_isrc_map (isrc);
@@ -0,0 +1,2 @@
+-- Deactivated users aren't updated and their stats aren't served until they're reactivated
+ALTER TABLE users ADD COLUMN deactivated_at TIMESTAMP NULL;
@@ -0,0 +1,2 @@
+DROP TABLE saved_tracks;
+DROP TABLE library_snapshots;
@@ -0,0 +1,15 @@
+-- Number of tracks in each user's saved tracks library as of each update
+CREATE TABLE library_snapshots (
+  user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  update_time TIMESTAMP NOT NULL,
+  saved_track_count BIGINT NOT NULL,
+  PRIMARY KEY (user_id, update_time)
+);
+-- The most recently saved tracks seen in each user's library at each update
+CREATE TABLE saved_tracks (
+  user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  mapped_spotify_id INTEGER NOT NULL REFERENCES spotify_items(id) ON DELETE CASCADE,
+  added_at TIMESTAMP NOT NULL,
+  PRIMARY KEY (user_id, mapped_spotify_id)
+);
+CREATE INDEX saved_tracks_user_id_added_at_ix ON saved_tracks (user_id, added_at);
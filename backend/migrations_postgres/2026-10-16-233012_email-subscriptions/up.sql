@@ -0,0 +1,9 @@
+-- Users that opted in to a weekly email digest of their stats
+CREATE TABLE email_subscriptions (
+  user_id BIGINT NOT NULL PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+  email VARCHAR(254) NOT NULL,
+  unsubscribe_token VARCHAR(64) NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  last_sent_at TIMESTAMP NULL
+);
+CREATE UNIQUE INDEX email_subscriptions_unsubscribe_token_ix ON email_subscriptions (unsubscribe_token);
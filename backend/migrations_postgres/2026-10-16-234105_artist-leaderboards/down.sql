@@ -0,0 +1,2 @@
+DROP INDEX artist_rank_deltas_mapped_spotify_id_user_id_ix;
+ALTER TABLE user_settings DROP COLUMN leaderboard_opt_in;
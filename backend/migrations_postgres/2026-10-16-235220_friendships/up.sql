@@ -0,0 +1,11 @@
+-- Friend requests between users.  A request becomes a friendship once the addressee accepts it.
+CREATE TABLE friendships (
+  requester_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  addressee_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  requested_at TIMESTAMP NOT NULL,
+  accepted_at TIMESTAMP NULL,
+  PRIMARY KEY (requester_id, addressee_id)
+);
+CREATE INDEX friendships_addressee_id_ix ON friendships (addressee_id);
+-- Semi-private users' stats can only be compared with by their friends
+ALTER TABLE user_settings ADD COLUMN friends_only_comparisons BOOLEAN NOT NULL DEFAULT FALSE;
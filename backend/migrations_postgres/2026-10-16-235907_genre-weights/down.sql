@@ -0,0 +1 @@
+DROP TABLE genre_weights;
@@ -0,0 +1,10 @@
+-- Weights of the top genres among the top artists of each timeframe of a stats snapshot, computed
+-- when the snapshot is stored.  Snapshots stored before this table existed have no weights.
+CREATE TABLE genre_weights (
+  user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  update_time TIMESTAMP NOT NULL,
+  timeframe SMALLINT NOT NULL,
+  genre VARCHAR(255) NOT NULL,
+  weight BIGINT NOT NULL,
+  PRIMARY KEY (user_id, update_time, timeframe, genre)
+);
@@ -0,0 +1,14 @@
+-- Side effects of storing stats snapshots.  Events are written in the same transaction as the
+-- snapshot and delivered afterwards by the outbox dispatcher.
+CREATE TABLE outbox_events (
+  id BIGSERIAL PRIMARY KEY,
+  user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  event_type VARCHAR(64) NOT NULL,
+  payload TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  attempt_count INT NOT NULL DEFAULT 0,
+  next_attempt_at TIMESTAMP NOT NULL,
+  delivered_at TIMESTAMP NULL,
+  last_error TEXT NULL
+);
+CREATE INDEX outbox_events_pending_ix ON outbox_events (delivered_at, next_attempt_at);
@@ -0,0 +1,2 @@
+DROP INDEX artists_last_fetched_ix;
+DROP INDEX tracks_last_fetched_ix;
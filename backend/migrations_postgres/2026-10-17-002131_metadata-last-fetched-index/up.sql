@@ -0,0 +1,3 @@
+-- Used by the metadata refresh job to find the entities with the oldest metadata
+CREATE INDEX artists_last_fetched_ix ON artists (last_fetched);
+CREATE INDEX tracks_last_fetched_ix ON tracks (last_fetched);
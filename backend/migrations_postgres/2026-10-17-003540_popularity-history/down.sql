@@ -0,0 +1 @@
+DROP TABLE popularity_history;
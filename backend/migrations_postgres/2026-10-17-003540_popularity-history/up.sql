@@ -0,0 +1,13 @@
+-- Spotify's popularity score of each of the top artists and tracks in a stats snapshot.  It changes
+-- over time, so it's recorded when the snapshot is stored.  `entity_type` is 0 for artists and 1
+-- for tracks.  Snapshots stored before this table existed have no popularity scores.
+CREATE TABLE popularity_history (
+  user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  update_time TIMESTAMP NOT NULL,
+  timeframe SMALLINT NOT NULL,
+  entity_type SMALLINT NOT NULL,
+  ranking SMALLINT NOT NULL,
+  mapped_spotify_id INT NOT NULL,
+  popularity SMALLINT NOT NULL,
+  PRIMARY KEY (user_id, update_time, timeframe, entity_type, ranking)
+);
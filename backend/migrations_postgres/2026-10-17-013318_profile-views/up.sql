@@ -0,0 +1,8 @@
+-- Number of times each user's stats profile was viewed per day, with `view_date` at midnight UTC.
+-- Only daily totals are stored; nothing about who viewed the profile is recorded.
+CREATE TABLE profile_views (
+  user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  view_date TIMESTAMP NOT NULL,
+  view_count INT NOT NULL,
+  PRIMARY KEY (user_id, view_date)
+);
@@ -0,0 +1,12 @@
+-- Keys that third-party apps authenticate with.  Only a hash of each key is stored; the key itself
+-- is returned once when it's minted.  `scopes` is a comma-separated list of `ApiKeyScope` names.
+CREATE TABLE api_keys (
+  id BIGSERIAL PRIMARY KEY,
+  name VARCHAR(255) NOT NULL,
+  key_hash CHAR(64) NOT NULL,
+  scopes VARCHAR(255) NOT NULL,
+  rate_limit_per_minute INT NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  revoked_at TIMESTAMP NULL
+);
+CREATE UNIQUE INDEX api_keys_key_hash_ix ON api_keys (key_hash);
@@ -0,0 +1,2 @@
+DROP INDEX artist_rank_deltas_user_mapped_spotify_id_ix;
+DROP INDEX track_rank_deltas_user_mapped_spotify_id_ix;
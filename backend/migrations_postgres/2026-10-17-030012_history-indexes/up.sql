@@ -0,0 +1,4 @@
+-- Used by the rank history endpoints, which look up every appearance of a few artists or tracks in a
+-- user's history
+CREATE INDEX artist_rank_deltas_user_mapped_spotify_id_ix ON artist_rank_deltas (user_id, mapped_spotify_id, update_time);
+CREATE INDEX track_rank_deltas_user_mapped_spotify_id_ix ON track_rank_deltas (user_id, mapped_spotify_id, update_time);
@@ -0,0 +1,2 @@
+DROP TABLE latest_artist_stats;
+DROP TABLE latest_track_stats;
@@ -0,0 +1,35 @@
+-- Full copies of each user's most recent snapshot, replaced in the same transaction that stores a
+-- new one.  Current stats are read from these rather than materialized from the deltas.
+CREATE TABLE latest_artist_stats (
+  user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  timeframe SMALLINT NOT NULL,
+  ranking SMALLINT NOT NULL,
+  mapped_spotify_id INTEGER NOT NULL,
+  update_time TIMESTAMP NOT NULL,
+  PRIMARY KEY (user_id, timeframe, ranking)
+);
+CREATE TABLE latest_track_stats (
+  user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  timeframe SMALLINT NOT NULL,
+  ranking SMALLINT NOT NULL,
+  mapped_spotify_id INTEGER NOT NULL,
+  update_time TIMESTAMP NOT NULL,
+  PRIMARY KEY (user_id, timeframe, ranking)
+);
+
+INSERT INTO latest_artist_stats (user_id, timeframe, ranking, mapped_spotify_id, update_time)
+  SELECT s.user_id, s.timeframe, s.ranking, s.mapped_spotify_id, s.update_time
+  FROM artist_rank_snapshots s
+  WHERE s.update_time = (
+    SELECT MAX(u.update_time)
+    FROM snapshot_updates u
+    WHERE u.user_id = s.user_id AND u.artist_count > 0
+  );
+INSERT INTO latest_track_stats (user_id, timeframe, ranking, mapped_spotify_id, update_time)
+  SELECT s.user_id, s.timeframe, s.ranking, s.mapped_spotify_id, s.update_time
+  FROM track_rank_snapshots s
+  WHERE s.update_time = (
+    SELECT MAX(u.update_time)
+    FROM snapshot_updates u
+    WHERE u.user_id = s.user_id AND u.track_count > 0
+  );
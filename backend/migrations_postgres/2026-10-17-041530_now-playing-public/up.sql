@@ -0,0 +1,2 @@
+-- Users can opt into showing what they're currently listening to on their public profiles
+ALTER TABLE user_settings ADD COLUMN now_playing_public BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1 @@
+ALTER TABLE users DROP COLUMN min_update_interval_seconds;
@@ -0,0 +1,3 @@
+-- Overrides the minimum time between automatic updates of the user set by their update tier, for
+-- example so that supporters can be updated more often
+ALTER TABLE users ADD COLUMN min_update_interval_seconds INTEGER;
@@ -0,0 +1,3 @@
+DROP INDEX email_subscriptions_confirmation_token_ix;
+ALTER TABLE email_subscriptions DROP COLUMN confirmed_at;
+ALTER TABLE email_subscriptions DROP COLUMN confirmation_token;
@@ -0,0 +1,6 @@
+-- Subscriptions only receive digests once the address has been confirmed via the link emailed to
+-- it.  Existing subscriptions are treated as confirmed.
+ALTER TABLE email_subscriptions ADD COLUMN confirmation_token VARCHAR(64) NULL;
+ALTER TABLE email_subscriptions ADD COLUMN confirmed_at TIMESTAMP NULL;
+CREATE UNIQUE INDEX email_subscriptions_confirmation_token_ix ON email_subscriptions (confirmation_token);
+UPDATE email_subscriptions SET confirmed_at = created_at;
@@ -0,0 +1 @@
+DROP INDEX latest_artist_stats_by_artist;
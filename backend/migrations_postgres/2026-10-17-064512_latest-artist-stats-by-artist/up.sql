@@ -0,0 +1,2 @@
+-- Artist leaderboards look up every user's latest ranking of a single artist
+CREATE INDEX latest_artist_stats_by_artist ON latest_artist_stats (mapped_spotify_id);
@@ -0,0 +1,2 @@
+ALTER TABLE users DROP COLUMN next_update_attempt_at;
+ALTER TABLE users DROP COLUMN failed_update_attempts;
@@ -0,0 +1,4 @@
+-- Users whose updates fail are retried with an exponentially increasing delay so that transient
+-- errors like Spotify outages don't cause them to be retried on every scheduler pass
+ALTER TABLE users ADD COLUMN failed_update_attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN next_update_attempt_at TIMESTAMP NULL;
@@ -0,0 +1,374 @@
+//! API keys that let third-party apps consume the API without sharing the admin token.  Keys are
+//! minted and revoked by admins via the `/admin/api_keys` routes and supplied via an
+//! `Authorization: Bearer <key>` header.
+//!
+//! Each key is limited to a set of `ApiKeyScope`s and has its own rate limit.  Requests made with a
+//! key are exempt from the IP address and username rate limits applied by `RateLimitFairing`,
+//! which authenticates them with the `ApiKey` guard and checks the key's scopes instead.  Keys can
+//! only be used for routes covered by one of their scopes, so a key's scopes decide which routes
+//! its holder can call without being subject to the anonymous rate limits.
+//!
+//! Keys are looked up in the database at most once every `API_KEY_CACHE_TTL` per instance so that
+//! authenticating doesn't check out a database connection on every request.  Revoking a key clears
+//! the cache on the instance that handled it, but other instances may keep accepting the key until
+//! their cached lookup expires.  Clients that supply too many keys that don't exist are rejected
+//! without looking their keys up for a while, so that they can't flood the database with lookups
+//! of random keys.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use fnv::FnvHashMap as HashMap;
+use rocket::{
+    http::Status,
+    outcome::Outcome,
+    request::{self, FromRequest},
+    Request,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    db_util,
+    models::{ApiKeyRow, ApiKeyScope},
+    rate_limit::RateLimiter,
+    DbConn,
+};
+
+/// Distinguishes API keys from Spotify access tokens, which are also supplied as bearer tokens
+pub(crate) const API_KEY_PREFIX: &str = "stk_";
+const API_KEY_RANDOM_LENGTH: usize = 40;
+const API_KEY_CACHE_TTL: Duration = Duration::from_secs(60);
+/// Once this many key hashes are cached, expired lookups are dropped.  If the cache is still full,
+/// lookups of keys that don't exist aren't cached and the oldest lookup is evicted for keys that
+/// do.
+const MAX_CACHED_API_KEYS: usize = 10_000;
+/// Number of lookups of keys that don't exist that a single IP address can make per minute
+const MAX_FAILED_LOOKUPS_PER_MINUTE: u32 = 10;
+
+/// Generates a new random API key
+pub(crate) fn generate_api_key() -> String {
+    use rand::{distributions::Alphanumeric, Rng};
+
+    let random: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(API_KEY_RANDOM_LENGTH)
+        .map(char::from)
+        .collect();
+    format!("{}{}", API_KEY_PREFIX, random)
+}
+
+/// Returns the hex-encoded SHA-256 hash of the key, which is what's stored in the database
+pub(crate) fn hash_api_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Returns the API key supplied with the request, if any
+pub(crate) fn get_supplied_api_key<'r>(req: &'r Request<'_>) -> Option<&'r str> {
+    req.headers()
+        .get_one("Authorization")
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .filter(|token| token.starts_with(API_KEY_PREFIX))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ApiKeyError {
+    Invalid,
+    Revoked,
+    MissingScope(ApiKeyScope),
+    /// The route isn't covered by any scope, so it can't be called with an API key
+    UnsupportedRoute,
+    /// The key's rate limit was exceeded, along with how long until it can be used again
+    RateLimited(Duration),
+    Internal,
+}
+
+impl ApiKeyError {
+    pub(crate) fn status(&self) -> Status {
+        match self {
+            ApiKeyError::Invalid | ApiKeyError::Revoked => Status::Unauthorized,
+            ApiKeyError::MissingScope(_) | ApiKeyError::UnsupportedRoute => Status::Forbidden,
+            ApiKeyError::RateLimited(_) => Status::TooManyRequests,
+            ApiKeyError::Internal => Status::InternalServerError,
+        }
+    }
+
+    pub(crate) fn message(&self) -> String {
+        match self {
+            ApiKeyError::Invalid => "Invalid API key supplied".to_owned(),
+            ApiKeyError::Revoked => "API key has been revoked".to_owned(),
+            ApiKeyError::MissingScope(scope) =>
+                format!("API key doesn't have the `{}` scope", scope.as_str()),
+            ApiKeyError::UnsupportedRoute => "API keys can't be used for this route".to_owned(),
+            ApiKeyError::RateLimited(_) => "Too many requests; try again later".to_owned(),
+            ApiKeyError::Internal => "Error checking API key".to_owned(),
+        }
+    }
+}
+
+/// An API key that the request was authenticated with.  Requests without an API key are forwarded,
+/// and requests with an invalid or revoked key or that exceed the key's rate limit are rejected.
+#[derive(Clone, Debug)]
+pub(crate) struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+}
+
+impl ApiKey {
+    pub(crate) fn require_scope(&self, scope: ApiKeyScope) -> Result<(), ApiKeyError> {
+        if self.scopes.contains(&scope) {
+            Ok(())
+        } else {
+            Err(ApiKeyError::MissingScope(scope))
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    /// Rate limiters of each key that has been used along with the limit they were created with,
+    /// keyed by key ID
+    static ref API_KEY_RATE_LIMITERS: Mutex<HashMap<i64, (i32, RateLimiter)>> =
+        Mutex::new(HashMap::default());
+    /// Recently looked up keys along with when they were looked up, keyed by key hash.  Keys that
+    /// don't exist are cached as `None`.
+    static ref API_KEY_CACHE: Mutex<HashMap<String, (Instant, Option<ApiKeyRow>)>> =
+        Mutex::new(HashMap::default());
+    /// Lookups of keys that don't exist, keyed by client IP address
+    static ref FAILED_LOOKUP_LIMITER: RateLimiter =
+        RateLimiter::new(MAX_FAILED_LOOKUPS_PER_MINUTE, MAX_FAILED_LOOKUPS_PER_MINUTE);
+}
+
+fn check_rate_limit(row: &ApiKeyRow, now: Instant) -> Result<(), ApiKeyError> {
+    let mut limiters = API_KEY_RATE_LIMITERS.lock().unwrap();
+    let (limit, limiter) = limiters.entry(row.id).or_insert_with(|| {
+        let per_minute = row.rate_limit_per_minute.max(0) as u32;
+        (
+            row.rate_limit_per_minute,
+            RateLimiter::new(per_minute, per_minute),
+        )
+    });
+    // The key's limit may have been changed since its limiter was created
+    if *limit != row.rate_limit_per_minute {
+        let per_minute = row.rate_limit_per_minute.max(0) as u32;
+        *limit = row.rate_limit_per_minute;
+        *limiter = RateLimiter::new(per_minute, per_minute);
+    }
+    if !limiter.is_enabled() {
+        return Ok(());
+    }
+    limiter.take("", now).map_err(ApiKeyError::RateLimited)
+}
+
+/// Returns the cached lookup of the key with the provided hash if it hasn't expired
+fn get_cached_api_key(key_hash: &str, now: Instant) -> Option<Option<ApiKeyRow>> {
+    let cache = API_KEY_CACHE.lock().unwrap();
+    let (looked_up_at, row) = cache.get(key_hash)?;
+    if now.saturating_duration_since(*looked_up_at) >= API_KEY_CACHE_TTL {
+        return None;
+    }
+    Some(row.clone())
+}
+
+fn cache_api_key(key_hash: String, row: Option<ApiKeyRow>, now: Instant) {
+    insert_cached_api_key(&mut API_KEY_CACHE.lock().unwrap(), key_hash, row, now);
+}
+
+fn insert_cached_api_key(
+    cache: &mut HashMap<String, (Instant, Option<ApiKeyRow>)>,
+    key_hash: String,
+    row: Option<ApiKeyRow>,
+    now: Instant,
+) {
+    if cache.len() >= MAX_CACHED_API_KEYS && !cache.contains_key(&key_hash) {
+        cache.retain(|_, (looked_up_at, _)| {
+            now.saturating_duration_since(*looked_up_at) < API_KEY_CACHE_TTL
+        });
+    }
+    if cache.len() >= MAX_CACHED_API_KEYS && !cache.contains_key(&key_hash) {
+        if row.is_none() {
+            return;
+        }
+        let oldest_key_hash = cache
+            .iter()
+            .min_by_key(|(_, (looked_up_at, _))| *looked_up_at)
+            .map(|(key_hash, _)| key_hash.clone());
+        if let Some(oldest_key_hash) = oldest_key_hash {
+            cache.remove(&oldest_key_hash);
+        }
+    }
+    cache.insert(key_hash, (now, row));
+}
+
+/// Clears all cached key lookups so that changes to keys, such as revocations, take effect
+/// immediately on this instance
+pub(crate) fn clear_api_key_cache() { API_KEY_CACHE.lock().unwrap().clear(); }
+
+async fn lookup_api_key(req: &Request<'_>, key: &str) -> Result<Option<ApiKeyRow>, ApiKeyError> {
+    let key_hash = hash_api_key(key);
+    if let Some(row) = get_cached_api_key(&key_hash, Instant::now()) {
+        return Ok(row);
+    }
+
+    let client_ip = req.client_ip().map(|ip| ip.to_string());
+    if let Some(client_ip) = &client_ip {
+        FAILED_LOOKUP_LIMITER
+            .check(client_ip, Instant::now())
+            .map_err(ApiKeyError::RateLimited)?;
+    }
+
+    let conn = match req.guard::<DbConn>().await {
+        Outcome::Success(conn) => conn,
+        _ => return Err(ApiKeyError::Internal),
+    };
+    match db_util::get_api_key_by_hash(&conn, key_hash.clone()).await {
+        Ok(row) => {
+            if let (None, Some(client_ip)) = (&row, &client_ip) {
+                let _ = FAILED_LOOKUP_LIMITER.take(client_ip, Instant::now());
+            }
+            cache_api_key(key_hash, row.clone(), Instant::now());
+            Ok(row)
+        },
+        Err(err) => {
+            error!("Error looking up API key: {}", err);
+            Err(ApiKeyError::Internal)
+        },
+    }
+}
+
+async fn authenticate(req: &Request<'_>, key: &str) -> Result<ApiKey, ApiKeyError> {
+    let row = lookup_api_key(req, key)
+        .await?
+        .ok_or(ApiKeyError::Invalid)?;
+    if row.revoked_at.is_some() {
+        return Err(ApiKeyError::Revoked);
+    }
+
+    check_rate_limit(&row, Instant::now())?;
+    Ok(ApiKey {
+        scopes: row.scopes(),
+        id: row.id,
+        name: row.name,
+    })
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiKey {
+    type Error = ApiKeyError;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let key = match get_supplied_api_key(req) {
+            Some(key) => key,
+            None => return Outcome::Forward(()),
+        };
+
+        // Cached so that the key is only looked up and counted against its rate limit once per
+        // request, even though both `RateLimitFairing` and routes use this guard
+        let res = req
+            .local_cache_async(async { authenticate(req, key).await })
+            .await;
+        match res {
+            Ok(api_key) => Outcome::Success(api_key.clone()),
+            Err(err) => Outcome::Failure((err.status(), err.clone())),
+        }
+    }
+}
+
+#[test]
+fn api_key_generation_and_hashing() {
+    let key = generate_api_key();
+    assert!(key.starts_with(API_KEY_PREFIX));
+    assert_eq!(key.len(), API_KEY_PREFIX.len() + API_KEY_RANDOM_LENGTH);
+    assert_ne!(key, generate_api_key());
+
+    assert_eq!(
+        hash_api_key("abc"),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(hash_api_key(&key).len(), 64);
+
+    let api_key = ApiKey {
+        id: 1,
+        name: "test".to_owned(),
+        scopes: vec![ApiKeyScope::Stats],
+    };
+    assert!(api_key.require_scope(ApiKeyScope::Stats).is_ok());
+    assert_eq!(
+        api_key.require_scope(ApiKeyScope::Comparisons),
+        Err(ApiKeyError::MissingScope(ApiKeyScope::Comparisons))
+    );
+}
+
+#[test]
+fn api_key_lookups_and_rate_limits() {
+    let start = Instant::now();
+    let mut row = ApiKeyRow {
+        id: -1,
+        name: "test".to_owned(),
+        scopes: "stats".to_owned(),
+        rate_limit_per_minute: 1,
+        created_at: chrono::NaiveDateTime::UNIX_EPOCH,
+        revoked_at: None,
+    };
+
+    let key_hash = hash_api_key("stk_api_key_lookups_and_rate_limits");
+    assert!(get_cached_api_key(&key_hash, start).is_none());
+    cache_api_key(key_hash.clone(), Some(row.clone()), start);
+    assert_eq!(
+        get_cached_api_key(&key_hash, start).and_then(|row| row.map(|row| row.id)),
+        Some(-1)
+    );
+    assert!(get_cached_api_key(&key_hash, start + API_KEY_CACHE_TTL).is_none());
+
+    assert!(check_rate_limit(&row, start).is_ok());
+    assert!(matches!(
+        check_rate_limit(&row, start),
+        Err(ApiKeyError::RateLimited(_))
+    ));
+    // Raising the key's limit takes effect without waiting for its old bucket to refill
+    row.rate_limit_per_minute = 10;
+    assert!(check_rate_limit(&row, start).is_ok());
+}
+
+#[test]
+fn api_key_cache_cap() {
+    let row = ApiKeyRow {
+        id: -1,
+        name: "test".to_owned(),
+        scopes: "stats".to_owned(),
+        rate_limit_per_minute: 1,
+        created_at: chrono::NaiveDateTime::UNIX_EPOCH,
+        revoked_at: None,
+    };
+    let start = Instant::now();
+    let mut cache = HashMap::default();
+    for i in 0..MAX_CACHED_API_KEYS {
+        let looked_up_at = start + Duration::from_micros(i as u64);
+        insert_cached_api_key(&mut cache, i.to_string(), None, looked_up_at);
+    }
+    let now = start + Duration::from_secs(1);
+
+    // Lookups of keys that don't exist aren't cached once the cache is full of unexpired lookups
+    insert_cached_api_key(&mut cache, "missing".to_owned(), None, now);
+    assert_eq!(cache.len(), MAX_CACHED_API_KEYS);
+    assert!(!cache.contains_key("missing"));
+
+    // Keys that exist evict the oldest lookup
+    insert_cached_api_key(&mut cache, "existing".to_owned(), Some(row), now);
+    assert_eq!(cache.len(), MAX_CACHED_API_KEYS);
+    assert!(cache.contains_key("existing"));
+    assert!(!cache.contains_key("0"));
+
+    // Expired lookups are dropped first
+    insert_cached_api_key(
+        &mut cache,
+        "missing".to_owned(),
+        None,
+        now + API_KEY_CACHE_TTL,
+    );
+    assert_eq!(cache.len(), 1);
+}
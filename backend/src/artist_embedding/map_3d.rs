@@ -6,6 +6,7 @@ use tokio::{sync::OnceCell, task::spawn_blocking};
 use crate::{
     artist_embedding::{parse_positions, ArtistEmbeddingContext},
     db_util::{get_artist_spotify_ids_by_internal_id, get_internal_ids_by_spotify_id},
+    error::Error,
     spotify_api::fetch_artists,
     DbConn,
 };
@@ -25,7 +26,7 @@ async fn build_3d_artist_map_ctx(
         .text()
         .await
         .unwrap();
-    println!("Successfully fetched 3d artist map embedding positions.  Parsing...");
+    info!("Successfully fetched 3d artist map embedding positions.  Parsing...");
     let artist_position_by_id = parse_positions(&raw_positions);
 
     let mut map_ctx_3d = ArtistEmbeddingContext::new(artist_position_by_id);
@@ -78,9 +79,7 @@ async fn build_3d_artist_map_ctx(
         new_count
     );
 
-    println!(
-        "Successfully parsed 3d artist map embedding positions.  Setting into global context."
-    );
+    info!("Successfully parsed 3d artist map embedding positions.  Setting into global context.");
     map_ctx_3d
 }
 
@@ -90,16 +89,16 @@ static PACKED_3D_ARTIST_EMBEDDING: OnceCell<Vec<u8>> = OnceCell::const_new();
 async fn get_all_artist_popularities_by_id(
     spotify_access_token: &str,
     all_artist_spotify_ids: Vec<String>,
-) -> Result<HashMap<String, u8>, String> {
+) -> Result<HashMap<String, u8>, Error> {
     let all_artist_spotify_ids: Vec<&str> = all_artist_spotify_ids
         .iter()
         .map(|id| id.as_str())
         .collect();
     let all_artists = fetch_artists(spotify_access_token, &all_artist_spotify_ids).await?;
     let mut artist_popularities_by_id: HashMap<String, u8> = HashMap::default();
-    for artist in all_artists {
+    for (artist_id, artist) in all_artists {
         artist_popularities_by_id.insert(
-            artist.id,
+            artist_id,
             artist
                 .popularity
                 .map(|pop| pop.try_into().unwrap())
@@ -123,7 +122,7 @@ pub(crate) async fn get_map_3d_artist_ctx(
 async fn build_packed_3d_artist_coords(
     conn: &DbConn,
     spotify_access_token: &str,
-) -> Result<Vec<u8>, String> {
+) -> Result<Vec<u8>, Error> {
     let map_ctx_3d = get_map_3d_artist_ctx(conn, spotify_access_token)
         .await
         .clone();
@@ -163,7 +162,7 @@ async fn build_packed_3d_artist_coords(
 pub async fn get_packed_3d_artist_coords(
     conn: &DbConn,
     spotify_access_token: &str,
-) -> Result<&'static [u8], String> {
+) -> Result<&'static [u8], Error> {
     PACKED_3D_ARTIST_EMBEDDING
         .get_or_try_init(|| async {
             let pre_saved_map = spawn_blocking(|| std::fs::read("packed_map_3d.bin").ok())
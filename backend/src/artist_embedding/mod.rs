@@ -306,7 +306,7 @@ pub async fn init_artist_embedding_ctx(positions_url: &str) {
         return;
     }
 
-    println!(
+    info!(
         "Initializing artist embedding ctx.  Fetching pre-computed positions from URL={}...",
         positions_url
     );
@@ -316,9 +316,9 @@ pub async fn init_artist_embedding_ctx(positions_url: &str) {
         .text()
         .await
         .unwrap();
-    println!("Successfully fetched artist embedding positions.  Parsing...");
+    info!("Successfully fetched artist embedding positions.  Parsing...");
     let artist_position_by_id = parse_positions(&raw_positions);
-    println!("Successfully parsed artist embedding positions.  Setting into global context.");
+    info!("Successfully parsed artist embedding positions.  Setting into global context.");
 
     let ctx = Box::new(ArtistEmbeddingContext::new(artist_position_by_id));
     unsafe { ARTIST_EMBEDDING_CTX = Box::into_raw(ctx) };
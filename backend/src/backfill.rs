@@ -0,0 +1,156 @@
+//! The `backfill` subcommand, which updates users' stats from the command line without starting
+//! the web server.  It's meant for operational one-offs and for recovering from outages without
+//! having to go through the `/update_user` route.
+//!
+//! Users are updated in the same way as by the update scheduler: their access token is refreshed,
+//! their current stats are fetched, and a snapshot is stored.  Unlike the scheduler, the minimum
+//! update interval isn't enforced, and `--all` includes users whose updates have been failing.
+//!
+//! With `--dry-run`, users are fetched but nothing is stored, and a report of what would have been
+//! is logged for each of them.
+
+use rocket::{Ignite, Rocket};
+
+use crate::{conf::CONF, db_util, error::Error, scheduler, DbConn};
+
+pub(crate) const USAGE: &str = "Usage: spotify-homepage-backend backfill (--user <spotify id> | \
+                                --all) [--concurrency <count>] [--dry-run]";
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum BackfillTarget {
+    User(String),
+    /// Every user that isn't deactivated
+    All,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct BackfillArgs {
+    pub target: BackfillTarget,
+    /// Number of users updated at once, defaulting to `CONF.scheduler_concurrency`
+    pub concurrency: Option<usize>,
+    pub dry_run: bool,
+}
+
+/// Parses the arguments that follow `backfill`
+pub(crate) fn parse_args(args: impl IntoIterator<Item = String>) -> Result<BackfillArgs, Error> {
+    let mut target = None;
+    let mut concurrency = None;
+    let mut dry_run = false;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let new_target = match arg.as_str() {
+            "--user" => match args.next() {
+                Some(user_id) => BackfillTarget::User(user_id),
+                None =>
+                    return Err(Error::BadRequest(
+                        "`--user` requires a Spotify user ID".to_owned(),
+                    )),
+            },
+            "--all" => BackfillTarget::All,
+            "--concurrency" => {
+                let count = args
+                    .next()
+                    .and_then(|count| count.parse::<usize>().ok())
+                    .filter(|&count| count > 0)
+                    .ok_or_else(|| {
+                        Error::BadRequest("`--concurrency` requires a positive integer".to_owned())
+                    })?;
+                concurrency = Some(count);
+                continue;
+            },
+            "--dry-run" => {
+                dry_run = true;
+                continue;
+            },
+            _ => return Err(Error::BadRequest(format!("Unknown argument `{}`", arg))),
+        };
+        if target.replace(new_target).is_some() {
+            return Err(Error::BadRequest(
+                "Only one of `--user` and `--all` can be provided".to_owned(),
+            ));
+        }
+    }
+
+    match target {
+        Some(target) => Ok(BackfillArgs {
+            target,
+            concurrency,
+            dry_run,
+        }),
+        None => Err(Error::BadRequest(
+            "One of `--user` and `--all` must be provided".to_owned(),
+        )),
+    }
+}
+
+/// Runs the backfill, returning the process's exit code: 0 if every user was updated successfully,
+/// 1 otherwise.
+pub(crate) async fn run(args: BackfillArgs, rocket: &Rocket<Ignite>) -> i32 {
+    let concurrency = args
+        .concurrency
+        .unwrap_or_else(|| CONF.scheduler_concurrency.max(1));
+    let mut conns = Vec::with_capacity(concurrency);
+    for _ in 0..concurrency {
+        match DbConn::get_one(rocket).await {
+            Some(conn) => conns.push(conn),
+            None => {
+                error!("Failed to check out database connection for backfill");
+                return 1;
+            },
+        }
+    }
+
+    let user_ids = match args.target {
+        BackfillTarget::User(user_id) => vec![user_id],
+        BackfillTarget::All => match db_util::get_active_user_spotify_ids(&conns[0]).await {
+            Ok(user_ids) => user_ids,
+            Err(err) => {
+                error!("Error fetching users to backfill: {}", err);
+                return 1;
+            },
+        },
+    };
+
+    info!(
+        "Backfilling {} user(s) with {} worker(s)",
+        user_ids.len(),
+        conns.len()
+    );
+    let (success_count, failure_count) = scheduler::run_pass(&conns, user_ids, args.dry_run).await;
+    info!(
+        "Backfill finished; {} succeeded, {} failed",
+        success_count, failure_count
+    );
+    if failure_count == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+#[test]
+fn backfill_arg_parsing() {
+    let parse = |args: &[&str]| parse_args(args.iter().map(|&arg| arg.to_owned()));
+
+    assert_eq!(
+        parse(&["--user", "abc", "--concurrency", "3"]).ok(),
+        Some(BackfillArgs {
+            target: BackfillTarget::User("abc".to_owned()),
+            concurrency: Some(3),
+            dry_run: false,
+        })
+    );
+    assert_eq!(
+        parse(&["--dry-run", "--all"]).ok().map(|args| args.dry_run),
+        Some(true)
+    );
+    assert_eq!(
+        parse(&["--all"]).ok().map(|args| args.target),
+        Some(BackfillTarget::All)
+    );
+    assert!(parse(&[]).is_err());
+    assert!(parse(&["--user"]).is_err());
+    assert!(parse(&["--user", "abc", "--all"]).is_err());
+    assert!(parse(&["--all", "--concurrency", "0"]).is_err());
+    assert!(parse(&["--everyone"]).is_err());
+}
@@ -0,0 +1,72 @@
+//! Storage underlying the cache.  Which backend is used is selected with `CACHE_BACKEND`:
+//!
+//!  * `redis` (default) stores everything in the Redis instance at `REDIS_URL`
+//!  * `memory` keeps up to `CACHE_MEMORY_MAX_ITEMS` items in the server's memory, evicting the
+//!    least recently used ones first.  Nothing is shared between server instances or kept across
+//!    restarts, which is fine for small self-hosted instances.
+//!  * `none` caches nothing, so everything is re-fetched from the database or Spotify API
+
+use std::time::Duration;
+
+use crate::error::Error;
+
+/// A store of named hashes, each mapping string keys to opaque values
+pub(crate) trait CacheBackend: Send + Sync {
+    /// Returns the value of each of the provided keys in the hash, or `None` for keys that aren't
+    /// cached
+    fn get_hash_items(&self, hash_name: &str, keys: &[&str])
+        -> Result<Vec<Option<Vec<u8>>>, Error>;
+
+    /// Sets the provided items into the hash.  If `ttl` is provided, the whole hash expires once it
+    /// has elapsed.
+    fn set_hash_items(
+        &self,
+        hash_name: &str,
+        kv_pairs: &[(&str, &[u8])],
+        ttl: Option<Duration>,
+    ) -> Result<(), Error>;
+
+    /// Removes the provided keys from the hash, or the whole hash if `keys` is `None`.  Returns the
+    /// number of items removed, or `None` if the whole hash was removed.
+    fn invalidate(&self, hash_name: &str, keys: Option<&[&str]>) -> Result<Option<usize>, Error>;
+
+    /// Checks that the backend is reachable and responding
+    fn ping(&self) -> Result<(), Error> { Ok(()) }
+
+    /// Returns the number of bytes of memory used by the cache
+    fn used_memory_bytes(&self) -> Result<i64, Error>;
+}
+
+pub(crate) enum CacheBackendConf {
+    Redis { url: String },
+    Memory { max_items: usize },
+    Disabled,
+}
+
+/// Backend used if caching is disabled.  Nothing is ever cached.
+pub(crate) struct NoopCacheBackend;
+
+impl CacheBackend for NoopCacheBackend {
+    fn get_hash_items(
+        &self,
+        _hash_name: &str,
+        keys: &[&str],
+    ) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        Ok(vec![None; keys.len()])
+    }
+
+    fn set_hash_items(
+        &self,
+        _hash_name: &str,
+        _kv_pairs: &[(&str, &[u8])],
+        _ttl: Option<Duration>,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn invalidate(&self, _hash_name: &str, keys: Option<&[&str]>) -> Result<Option<usize>, Error> {
+        Ok(keys.map(|_| 0))
+    }
+
+    fn used_memory_bytes(&self) -> Result<i64, Error> { Ok(0) }
+}
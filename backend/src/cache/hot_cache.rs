@@ -0,0 +1,212 @@
+//! Bounded in-process cache of deserialized entities sitting in front of the cache backend.  Stats
+//! for a single user can reference hundreds of artists and tracks, so serving the most frequently
+//! requested ones from memory saves a round trip to Redis and deserializing them on every request.
+//!
+//! Entries expire after `HOT_CACHE_TTL_SECONDS` so that entities refreshed by other server
+//! instances are picked up eventually, and the least recently used entries are evicted once there
+//! are more than `HOT_CACHE_MAX_ITEMS`.  Setting the latter to 0 disables the hot cache.
+
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use fnv::FnvHashMap as HashMap;
+
+use crate::{
+    conf::CONF,
+    metrics::{hot_cache_hits_total, hot_cache_misses_total},
+};
+
+struct HotCacheEntry {
+    value: Arc<dyn Any + Send + Sync>,
+    cached_at: Instant,
+    /// Key of the entry in `HotCache::recency`
+    last_used: u64,
+}
+
+/// Entries are keyed by `(hash_name, key)`, mirroring the hashes in the cache backend
+struct HotCache {
+    max_items: usize,
+    ttl: Duration,
+    entries: HashMap<(String, String), HotCacheEntry>,
+    /// Keys of all entries ordered from least to most recently used
+    recency: BTreeMap<u64, (String, String)>,
+    clock: u64,
+}
+
+impl HotCache {
+    fn new(max_items: usize, ttl: Duration) -> Self {
+        HotCache {
+            max_items,
+            ttl,
+            entries: HashMap::default(),
+            recency: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn get<T: Clone + 'static>(&mut self, hash_name: &str, key: &str, now: Instant) -> Option<T> {
+        let cache_key = (hash_name.to_owned(), key.to_owned());
+        let entry = self.entries.get(&cache_key)?;
+        if now.duration_since(entry.cached_at) >= self.ttl {
+            self.remove(&cache_key);
+            return None;
+        }
+        let value = entry.value.downcast_ref::<T>()?.clone();
+
+        let tick = self.tick();
+        let entry = self.entries.get_mut(&cache_key).unwrap();
+        let previously_used = std::mem::replace(&mut entry.last_used, tick);
+        self.recency.remove(&previously_used);
+        self.recency.insert(tick, cache_key);
+        Some(value)
+    }
+
+    fn insert<T: Clone + Send + Sync + 'static>(
+        &mut self,
+        hash_name: &str,
+        key: &str,
+        value: &T,
+        now: Instant,
+    ) {
+        let cache_key = (hash_name.to_owned(), key.to_owned());
+        let tick = self.tick();
+        let previous = self.entries.insert(cache_key.clone(), HotCacheEntry {
+            value: Arc::new(value.clone()),
+            cached_at: now,
+            last_used: tick,
+        });
+        if let Some(previous) = previous {
+            self.recency.remove(&previous.last_used);
+        }
+        self.recency.insert(tick, cache_key);
+
+        while self.entries.len() > self.max_items {
+            match self.recency.pop_first() {
+                Some((_, evicted_key)) => {
+                    self.entries.remove(&evicted_key);
+                },
+                None => break,
+            }
+        }
+    }
+
+    fn remove(&mut self, cache_key: &(String, String)) {
+        if let Some(entry) = self.entries.remove(cache_key) {
+            self.recency.remove(&entry.last_used);
+        }
+    }
+
+    fn invalidate(&mut self, hash_name: &str, keys: Option<&[&str]>) {
+        match keys {
+            Some(keys) =>
+                for key in keys {
+                    self.remove(&(hash_name.to_owned(), (*key).to_owned()));
+                },
+            None => {
+                let keys_to_remove: Vec<(String, String)> = self
+                    .entries
+                    .keys()
+                    .filter(|(entry_hash_name, _)| entry_hash_name == hash_name)
+                    .cloned()
+                    .collect();
+                for cache_key in keys_to_remove {
+                    self.remove(&cache_key);
+                }
+            },
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref HOT_CACHE: Mutex<HotCache> =
+        Mutex::new(HotCache::new(CONF.hot_cache_max_items, CONF.hot_cache_ttl));
+}
+
+fn is_enabled() -> bool { CONF.hot_cache_max_items > 0 }
+
+/// Returns the hot cached value of each of the provided keys, or `None` for keys that aren't in the
+/// hot cache
+pub(crate) fn get_items<T: Clone + 'static>(hash_name: &str, keys: &[&str]) -> Vec<Option<T>> {
+    if !is_enabled() {
+        return vec![None; keys.len()];
+    }
+
+    let now = Instant::now();
+    let mut cache = HOT_CACHE.lock().unwrap();
+    let items: Vec<Option<T>> = keys
+        .iter()
+        .map(|key| cache.get(hash_name, key, now))
+        .collect();
+    drop(cache);
+
+    let hit_count = items.iter().filter(|item| item.is_some()).count();
+    hot_cache_hits_total().inc_by(hit_count as u64);
+    hot_cache_misses_total().inc_by((items.len() - hit_count) as u64);
+    items
+}
+
+pub(crate) fn set_items<'a, T: Clone + Send + Sync + 'static>(
+    hash_name: &str,
+    kv_pairs: impl IntoIterator<Item = (&'a str, &'a T)>,
+) {
+    if !is_enabled() {
+        return;
+    }
+
+    let now = Instant::now();
+    let mut cache = HOT_CACHE.lock().unwrap();
+    for (key, value) in kv_pairs {
+        cache.insert(hash_name, key, value, now);
+    }
+}
+
+/// Removes the provided keys from the hot cache, or all of the hash's items if `keys` is `None`.
+/// Called whenever items are invalidated in the cache backend.
+pub(crate) fn invalidate(hash_name: &str, keys: Option<&[&str]>) {
+    if !is_enabled() {
+        return;
+    }
+    HOT_CACHE.lock().unwrap().invalidate(hash_name, keys);
+}
+
+#[test]
+fn hot_cache_eviction_and_expiry() {
+    let now = Instant::now();
+    let mut cache = HotCache::new(2, Duration::from_secs(60));
+    cache.insert("artists", "a", &"1".to_owned(), now);
+    cache.insert("artists", "b", &"2".to_owned(), now);
+    // Reading `a` makes `b` the least recently used entry, so it's evicted in place of `a`
+    assert_eq!(
+        cache.get::<String>("artists", "a", now),
+        Some("1".to_owned())
+    );
+    cache.insert("tracks", "a", &"3".to_owned(), now);
+    assert_eq!(cache.get::<String>("artists", "b", now), None);
+    assert_eq!(
+        cache.get::<String>("artists", "a", now),
+        Some("1".to_owned())
+    );
+    assert_eq!(
+        cache.get::<String>("tracks", "a", now),
+        Some("3".to_owned())
+    );
+    // Entries of a different type than requested are treated as missing
+    assert_eq!(cache.get::<u32>("tracks", "a", now), None);
+
+    assert_eq!(
+        cache.get::<String>("artists", "a", now + Duration::from_secs(60)),
+        None
+    );
+    cache.invalidate("tracks", None);
+    assert_eq!(cache.get::<String>("tracks", "a", now), None);
+    assert!(cache.entries.is_empty() && cache.recency.is_empty());
+}
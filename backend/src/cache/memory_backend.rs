@@ -0,0 +1,231 @@
+//! In-process cache backend which evicts the least recently used items once it holds more than a
+//! configured number of them.
+
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use fnv::FnvHashMap as HashMap;
+
+use super::backend::CacheBackend;
+use crate::error::Error;
+
+struct CachedItem {
+    value: Vec<u8>,
+    /// Key of the item in `MemoryCacheState::recency`
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CachedHash {
+    items: HashMap<String, CachedItem>,
+    expires_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct MemoryCacheState {
+    hashes: HashMap<String, CachedHash>,
+    /// `(hash_name, key)` of every cached item ordered from least to most recently used
+    recency: BTreeMap<u64, (String, String)>,
+    /// Incremented every time an item is used
+    clock: u64,
+}
+
+impl MemoryCacheState {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn remove_hash(&mut self, hash_name: &str) -> usize {
+        match self.hashes.remove(hash_name) {
+            Some(hash) => {
+                for item in hash.items.values() {
+                    self.recency.remove(&item.last_used);
+                }
+                hash.items.len()
+            },
+            None => 0,
+        }
+    }
+
+    /// Returns the hash if it exists and hasn't expired, removing it if it has
+    fn get_live_hash(&mut self, hash_name: &str, now: Instant) -> Option<&mut CachedHash> {
+        let expired = self
+            .hashes
+            .get(hash_name)?
+            .expires_at
+            .map(|expires_at| expires_at <= now)
+            .unwrap_or(false);
+        if expired {
+            self.remove_hash(hash_name);
+            return None;
+        }
+        self.hashes.get_mut(hash_name)
+    }
+}
+
+pub(crate) struct MemoryCacheBackend {
+    max_items: usize,
+    state: Mutex<MemoryCacheState>,
+}
+
+impl MemoryCacheBackend {
+    pub(crate) fn new(max_items: usize) -> Self {
+        MemoryCacheBackend {
+            max_items,
+            state: Mutex::new(MemoryCacheState::default()),
+        }
+    }
+}
+
+impl CacheBackend for MemoryCacheBackend {
+    fn get_hash_items(
+        &self,
+        hash_name: &str,
+        keys: &[&str],
+    ) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            let tick = state.tick();
+            let item = state
+                .get_live_hash(hash_name, Instant::now())
+                .and_then(|hash| hash.items.get_mut(*key));
+            match item {
+                Some(item) => {
+                    let previously_used = std::mem::replace(&mut item.last_used, tick);
+                    values.push(Some(item.value.clone()));
+                    if let Some(entry) = state.recency.remove(&previously_used) {
+                        state.recency.insert(tick, entry);
+                    }
+                },
+                None => values.push(None),
+            }
+        }
+        Ok(values)
+    }
+
+    fn set_hash_items(
+        &self,
+        hash_name: &str,
+        kv_pairs: &[(&str, &[u8])],
+        ttl: Option<Duration>,
+    ) -> Result<(), Error> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if state.get_live_hash(hash_name, now).is_none() {
+            state
+                .hashes
+                .insert(hash_name.to_owned(), CachedHash::default());
+        }
+
+        for (key, value) in kv_pairs {
+            let tick = state.tick();
+            let hash = state.hashes.get_mut(hash_name).unwrap();
+            if let Some(ttl) = ttl {
+                hash.expires_at = Some(now + ttl);
+            }
+            let previous = hash.items.insert((*key).to_owned(), CachedItem {
+                value: value.to_vec(),
+                last_used: tick,
+            });
+            if let Some(previous) = previous {
+                state.recency.remove(&previous.last_used);
+            }
+            state
+                .recency
+                .insert(tick, (hash_name.to_owned(), (*key).to_owned()));
+        }
+
+        while state.recency.len() > self.max_items {
+            let (_, (evicted_hash_name, evicted_key)) = match state.recency.pop_first() {
+                Some(entry) => entry,
+                None => break,
+            };
+            if let Some(hash) = state.hashes.get_mut(&evicted_hash_name) {
+                hash.items.remove(&evicted_key);
+                if hash.items.is_empty() {
+                    state.hashes.remove(&evicted_hash_name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn invalidate(&self, hash_name: &str, keys: Option<&[&str]>) -> Result<Option<usize>, Error> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let keys = match keys {
+            Some(keys) => keys,
+            None => {
+                state.remove_hash(hash_name);
+                return Ok(None);
+            },
+        };
+
+        let mut removed_count = 0;
+        for key in keys {
+            let removed = state
+                .get_live_hash(hash_name, Instant::now())
+                .and_then(|hash| hash.items.remove(*key));
+            if let Some(item) = removed {
+                state.recency.remove(&item.last_used);
+                removed_count += 1;
+            }
+        }
+        Ok(Some(removed_count))
+    }
+
+    fn used_memory_bytes(&self) -> Result<i64, Error> {
+        let state = self.state.lock().unwrap();
+        let used_bytes: usize = state
+            .hashes
+            .iter()
+            .flat_map(|(hash_name, hash)| {
+                hash.items
+                    .iter()
+                    .map(move |(key, item)| hash_name.len() + key.len() + item.value.len())
+            })
+            .sum();
+        Ok(used_bytes as i64)
+    }
+}
+
+#[test]
+fn memory_cache_eviction() {
+    let cache = MemoryCacheBackend::new(2);
+    cache
+        .set_hash_items("artists", &[("a", b"1"), ("b", b"2")], None)
+        .unwrap();
+    // Reading `a` makes `b` the least recently used item, so it's evicted in place of `a`
+    assert_eq!(cache.get_hash_items("artists", &["a"]).unwrap(), vec![
+        Some(b"1".to_vec())
+    ]);
+    cache
+        .set_hash_items("tracks", &[("c", b"3")], None)
+        .unwrap();
+    assert_eq!(cache.get_hash_items("artists", &["a", "b"]).unwrap(), vec![
+        Some(b"1".to_vec()),
+        None
+    ]);
+    assert_eq!(cache.get_hash_items("tracks", &["c"]).unwrap(), vec![Some(
+        b"3".to_vec()
+    )]);
+
+    assert_eq!(
+        cache.invalidate("artists", Some(&["a", "b"])).unwrap(),
+        Some(1)
+    );
+    assert_eq!(cache.invalidate("tracks", None).unwrap(), None);
+    assert_eq!(cache.used_memory_bytes().unwrap(), 0);
+
+    cache
+        .set_hash_items("cards", &[("a", b"1")], Some(Duration::from_secs(0)))
+        .unwrap();
+    assert_eq!(cache.get_hash_items("cards", &["a"]).unwrap(), vec![None]);
+}
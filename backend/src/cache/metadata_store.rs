@@ -0,0 +1,281 @@
+//! Database-backed second-level cache for Spotify entity metadata.  Entities are written here
+//! whenever they're fetched from the Spotify API so that history can still be rendered if Redis is
+//! wiped or if Spotify removes an entity.
+//!
+//! This is accessed from outside of Rocket's request handlers, so it uses its own small connection
+//! pool in the same way as the Redis cache.
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::{
+    prelude::*,
+    r2d2::{self, ConnectionManager},
+};
+use fnv::FnvHashMap as HashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    conf::CONF,
+    db_backend::{upsert, DbConnection},
+    error::Error,
+};
+
+const POOL_SIZE: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum MetadataTable {
+    Artists,
+    Tracks,
+}
+
+lazy_static::lazy_static! {
+    static ref METADATA_DB_POOL: Option<r2d2::Pool<ConnectionManager<DbConnection>>> = {
+        let database_url = CONF.database_url.as_ref()?;
+        r2d2::Pool::builder()
+            .max_size(POOL_SIZE)
+            .build(ConnectionManager::new(database_url.as_str()))
+            .map_err(|err| {
+                error!(
+                    "Failed to build metadata database connection pool; entity metadata won't be \
+                     persisted: {:?}",
+                    err
+                );
+            })
+            .ok()
+    };
+}
+
+fn get_conn() -> Result<Option<r2d2::PooledConnection<ConnectionManager<DbConnection>>>, Error> {
+    let pool = match METADATA_DB_POOL.as_ref() {
+        Some(pool) => pool,
+        None => return Ok(None),
+    };
+    pool.get().map(Some).map_err(|err| {
+        error!(
+            "Error getting metadata database connection from pool: {:?}",
+            err
+        );
+        Error::Internal("Error connecting to metadata database".into())
+    })
+}
+
+/// Loads stored metadata for the provided Spotify IDs along with the time it was fetched.  The
+/// returned entries line up with the provided IDs and are `None` for entities that haven't been
+/// stored or if the store is disabled.
+pub(crate) fn get_metadata_items<T: for<'de> Deserialize<'de>>(
+    table: MetadataTable,
+    spotify_ids: &[&str],
+) -> Result<Vec<Option<(T, NaiveDateTime)>>, Error> {
+    let conn = match get_conn()? {
+        Some(conn) if !spotify_ids.is_empty() => conn,
+        _ => return Ok(spotify_ids.iter().map(|_| None).collect()),
+    };
+
+    let rows: Vec<(String, String, NaiveDateTime)> = match table {
+        MetadataTable::Artists => {
+            use crate::schema::artists::dsl::*;
+            artists
+                .filter(spotify_id.eq_any(spotify_ids))
+                .select((spotify_id, metadata, last_fetched))
+                .load(&*conn)
+        },
+        MetadataTable::Tracks => {
+            use crate::schema::tracks::dsl::*;
+            tracks
+                .filter(spotify_id.eq_any(spotify_ids))
+                .select((spotify_id, metadata, last_fetched))
+                .load(&*conn)
+        },
+    }
+    .map_err(|err| {
+        error!("Error loading entity metadata from database: {:?}", err);
+        Error::Internal("Error loading entity metadata from database".into())
+    })?;
+    let mut metadata_by_id: HashMap<String, (String, NaiveDateTime)> = rows
+        .into_iter()
+        .map(|(id, val, fetched)| (id, (val, fetched)))
+        .collect();
+
+    spotify_ids
+        .iter()
+        .map(|id| match metadata_by_id.remove(*id) {
+            Some((val, fetched)) => serde_json::from_str(&val)
+                .map(|item| Some((item, fetched)))
+                .map_err(|err| {
+                    error!(
+                        "Error deserializing stored metadata of {}: {:?}; id={}",
+                        std::any::type_name::<T>(),
+                        err,
+                        id
+                    );
+                    Error::Internal("Error reading entity metadata from database".into())
+                }),
+            None => Ok(None),
+        })
+        .collect()
+}
+
+/// Stores metadata for the provided entities, replacing any that were stored previously.  Does
+/// nothing if the store is disabled.
+pub(crate) fn set_metadata_items<T: Serialize>(
+    table: MetadataTable,
+    kv_pairs: &[(&str, T)],
+) -> Result<(), Error> {
+    let conn = match get_conn()? {
+        Some(conn) if !kv_pairs.is_empty() => conn,
+        _ => return Ok(()),
+    };
+
+    let now = Utc::now().naive_utc();
+    let serialized = kv_pairs
+        .iter()
+        .map(|(key, val)| -> Result<(&str, String), Error> {
+            let serialized = serde_json::to_string(val).map_err(|err| {
+                error!("Error serializing entity metadata: {:?}", err);
+                Error::Internal("Error saving entity metadata to database".into())
+            })?;
+            Ok((key, serialized))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    match table {
+        MetadataTable::Artists => {
+            use crate::schema::artists::dsl::*;
+            let rows = serialized
+                .iter()
+                .map(|(id, val)| (spotify_id.eq(*id), metadata.eq(val), last_fetched.eq(now)))
+                .collect::<Vec<_>>();
+            upsert!(artists, &rows, spotify_id, (metadata, last_fetched)).execute(&*conn)
+        },
+        MetadataTable::Tracks => {
+            use crate::schema::tracks::dsl::*;
+            let rows = serialized
+                .iter()
+                .map(|(id, val)| (spotify_id.eq(*id), metadata.eq(val), last_fetched.eq(now)))
+                .collect::<Vec<_>>();
+            upsert!(tracks, &rows, spotify_id, (metadata, last_fetched)).execute(&*conn)
+        },
+    }
+    .map(drop)
+    .map_err(|err| {
+        error!("Error saving entity metadata to database: {:?}", err);
+        Error::Internal("Error saving entity metadata to database".into())
+    })
+}
+
+/// Marks stored metadata for the provided entities, or all entities if `spotify_ids` is `None`, as
+/// expired so that it's re-fetched from Spotify the next time it's needed.  The metadata is kept
+/// around to be used in case Spotify no longer has the entity.  Returns the number of entities
+/// that were expired.
+pub(crate) fn expire_metadata_items(
+    table: MetadataTable,
+    spotify_ids: Option<&[&str]>,
+) -> Result<usize, Error> {
+    let conn = match get_conn()? {
+        Some(conn) => conn,
+        None => return Ok(0),
+    };
+
+    let expired = NaiveDateTime::UNIX_EPOCH;
+    match (table, spotify_ids) {
+        (MetadataTable::Artists, Some(ids)) => {
+            use crate::schema::artists::dsl::*;
+            diesel::update(artists.filter(spotify_id.eq_any(ids)))
+                .set(last_fetched.eq(expired))
+                .execute(&*conn)
+        },
+        (MetadataTable::Artists, None) => {
+            use crate::schema::artists::dsl::*;
+            diesel::update(artists)
+                .set(last_fetched.eq(expired))
+                .execute(&*conn)
+        },
+        (MetadataTable::Tracks, Some(ids)) => {
+            use crate::schema::tracks::dsl::*;
+            diesel::update(tracks.filter(spotify_id.eq_any(ids)))
+                .set(last_fetched.eq(expired))
+                .execute(&*conn)
+        },
+        (MetadataTable::Tracks, None) => {
+            use crate::schema::tracks::dsl::*;
+            diesel::update(tracks)
+                .set(last_fetched.eq(expired))
+                .execute(&*conn)
+        },
+    }
+    .map_err(|err| {
+        error!("Error expiring entity metadata in database: {:?}", err);
+        Error::Internal("Error expiring entity metadata in database".into())
+    })
+}
+
+/// Returns the IDs of up to `limit` stored entities whose metadata was last fetched at or before
+/// `cutoff`, least recently fetched first
+pub(crate) fn get_stale_metadata_ids(
+    table: MetadataTable,
+    cutoff: NaiveDateTime,
+    limit: i64,
+) -> Result<Vec<String>, Error> {
+    let conn = match get_conn()? {
+        Some(conn) => conn,
+        None => return Ok(Vec::new()),
+    };
+
+    match table {
+        MetadataTable::Artists => {
+            use crate::schema::artists::dsl::*;
+            artists
+                .filter(last_fetched.le(cutoff))
+                .order_by(last_fetched.asc())
+                .limit(limit)
+                .select(spotify_id)
+                .load(&*conn)
+        },
+        MetadataTable::Tracks => {
+            use crate::schema::tracks::dsl::*;
+            tracks
+                .filter(last_fetched.le(cutoff))
+                .order_by(last_fetched.asc())
+                .limit(limit)
+                .select(spotify_id)
+                .load(&*conn)
+        },
+    }
+    .map_err(|err| {
+        error!(
+            "Error loading stale entity metadata IDs from database: {:?}",
+            err
+        );
+        Error::Internal("Error loading stale entity metadata IDs from database".into())
+    })
+}
+
+/// Marks stored metadata for the provided entities as just fetched without changing it
+pub(crate) fn touch_metadata_items(
+    table: MetadataTable,
+    spotify_ids: &[&str],
+) -> Result<usize, Error> {
+    let conn = match get_conn()? {
+        Some(conn) if !spotify_ids.is_empty() => conn,
+        _ => return Ok(0),
+    };
+
+    let now = Utc::now().naive_utc();
+    match table {
+        MetadataTable::Artists => {
+            use crate::schema::artists::dsl::*;
+            diesel::update(artists.filter(spotify_id.eq_any(spotify_ids)))
+                .set(last_fetched.eq(now))
+                .execute(&*conn)
+        },
+        MetadataTable::Tracks => {
+            use crate::schema::tracks::dsl::*;
+            diesel::update(tracks.filter(spotify_id.eq_any(spotify_ids)))
+                .set(last_fetched.eq(now))
+                .execute(&*conn)
+        },
+    }
+    .map_err(|err| {
+        error!("Error updating entity metadata in database: {:?}", err);
+        Error::Internal("Error updating entity metadata in database".into())
+    })
+}
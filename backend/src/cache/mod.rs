@@ -1,105 +1,253 @@
-//! Functions for interacting with Redis which caches data from the Spotify API.
+//! Functions for interacting with the cache of data from the Spotify API.  The cache is stored in
+//! one of the backends in `backend`, which is Redis unless configured otherwise.
+//!
+//! Hashes can't expire individual fields, so the time that each item was cached is stored as a
+//! unix timestamp in a companion hash named `<hash_name>:fetched_at`.  This lets readers treat
+//! items older than some max age as missing via `get_fresh_hash_items`.
+//!
+//! The cache is optional in the sense that callers should fall back to fetching from the Spotify
+//! API if it's unavailable.  After several consecutive Redis failures a circuit breaker trips and
+//! all cache operations fail immediately for a cooldown period so that a dead Redis instance
+//! doesn't add a connection timeout to every request.
 
-use r2d2_redis::{r2d2, redis::Commands, RedisConnectionManager};
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use r2d2_redis::{r2d2, RedisConnectionManager};
 use serde::{Deserialize, Serialize};
 use serde_json;
 
-use crate::conf::CONF;
+use crate::{conf::CONF, error::Error, models::CacheStatus};
 
+pub mod backend;
+pub mod hot_cache;
 pub mod local_cache;
+pub mod memory_backend;
+pub mod metadata_store;
+pub mod now_playing_cache;
+pub mod redis_backend;
+pub mod share_card_cache;
+pub mod snapshot_cache;
+
+use self::{
+    backend::{CacheBackend, CacheBackendConf, NoopCacheBackend},
+    memory_backend::MemoryCacheBackend,
+    redis_backend::RedisCacheBackend,
+};
+
+/// Number of consecutive failures after which the circuit breaker trips
+const CIRCUIT_BREAKER_FAILURE_THRESHOLD: usize = 3;
+/// How long cache operations are skipped once the circuit breaker has tripped
+const CIRCUIT_BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
 
 lazy_static::lazy_static! {
-    pub static ref REDIS_CONN_POOL: r2d2::Pool<RedisConnectionManager> = {
-        let manager = RedisConnectionManager::new(CONF.redis_url.as_str())
-            .map_err(|err| {
-                error!("Failed to create Redis connection manager: {:?}", err);
-                std::process::exit(1);
-            })
-            .unwrap();
-        r2d2::Pool::builder()
-            .build(manager)
-            .map_err(|err| {
-                error!("Failed to build Redis connection pool: {:?}", err);
-                std::process::exit(1);
-            })
-            .unwrap()
+    static ref REDIS_BACKEND: Option<RedisCacheBackend> = match &CONF.cache_backend {
+        CacheBackendConf::Redis { url } => Some(RedisCacheBackend::new(url)),
+        _ => None,
+    };
+    static ref MEMORY_BACKEND: Option<MemoryCacheBackend> = match &CONF.cache_backend {
+        CacheBackendConf::Memory { max_items } => Some(MemoryCacheBackend::new(*max_items)),
+        _ => None,
     };
+    static ref CIRCUIT_BREAKER: Mutex<CircuitBreaker> = Mutex::new(CircuitBreaker::default());
+}
+
+/// Returns the backend selected with `CACHE_BACKEND`
+pub(crate) fn backend() -> &'static dyn CacheBackend {
+    if let Some(redis) = REDIS_BACKEND.as_ref() {
+        return redis;
+    }
+    if let Some(memory) = MEMORY_BACKEND.as_ref() {
+        return memory;
+    }
+    &NoopCacheBackend
+}
+
+#[derive(Default)]
+struct CircuitBreaker {
+    consecutive_failures: usize,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    fn is_open(&self) -> bool {
+        self.open_until
+            .map(|open_until| Instant::now() < open_until)
+            .unwrap_or(false)
+    }
 }
 
-pub fn get_redis_conn() -> Result<diesel::r2d2::PooledConnection<RedisConnectionManager>, String> {
-    REDIS_CONN_POOL.get().map_err(|err| -> String {
-        error!("Error getting client from connection pool: {:?}", err);
-        "Error connecting to Spotify metadata cache".into()
-    })
+fn record_cache_success() {
+    let mut breaker = CIRCUIT_BREAKER.lock().unwrap();
+    if breaker.open_until.is_some() {
+        info!("Redis cache is available again; closing circuit breaker");
+    }
+    *breaker = CircuitBreaker::default();
+}
+
+fn record_cache_failure() {
+    let mut breaker = CIRCUIT_BREAKER.lock().unwrap();
+    breaker.consecutive_failures += 1;
+    if breaker.consecutive_failures >= CIRCUIT_BREAKER_FAILURE_THRESHOLD && !breaker.is_open() {
+        warn!(
+            "Redis cache failed {} times in a row; skipping it for {:?}",
+            breaker.consecutive_failures, CIRCUIT_BREAKER_COOLDOWN
+        );
+        breaker.open_until = Some(Instant::now() + CIRCUIT_BREAKER_COOLDOWN);
+    }
+}
+
+/// Records the outcome of a Redis command with the circuit breaker
+fn track<T>(res: redis::RedisResult<T>) -> redis::RedisResult<T> {
+    match res {
+        Ok(_) => record_cache_success(),
+        Err(_) => record_cache_failure(),
+    }
+    res
+}
+
+pub(crate) fn get_cache_status() -> CacheStatus {
+    let breaker = CIRCUIT_BREAKER.lock().unwrap();
+    let now = Instant::now();
+    CacheStatus {
+        available: !breaker.is_open(),
+        consecutive_failures: breaker.consecutive_failures,
+        retry_in_seconds: breaker
+            .open_until
+            .filter(|&open_until| open_until > now)
+            .map(|open_until| (open_until - now).as_secs()),
+    }
+}
+
+/// Returns a connection to Redis for the admin tools that work with it directly.  Fails if the
+/// cache isn't stored in Redis.
+pub fn get_redis_conn() -> Result<r2d2::PooledConnection<RedisConnectionManager>, Error> {
+    match REDIS_BACKEND.as_ref() {
+        Some(redis) => redis.get_conn(),
+        None => Err(Error::Internal("The cache isn't stored in Redis".into())),
+    }
 }
 
+/// Checks that the cache backend is reachable and responding
+pub(crate) fn ping() -> Result<(), Error> { backend().ping() }
+
+/// Returns the number of bytes of memory used by the cache backend
+pub(crate) fn get_used_memory_bytes() -> Result<i64, Error> { backend().used_memory_bytes() }
+
 pub(crate) fn set_hash_items<T: Serialize>(
     hash_name: &str,
     kv_pairs: &[(&str, T)],
-) -> Result<(), String> {
+) -> Result<(), Error> {
     if kv_pairs.is_empty() {
         return Ok(());
     }
 
     let kv_pairs_serialized = kv_pairs
         .iter()
-        .map(|(key, val)| -> Result<(&str, String), String> {
-            let serialized: String = serde_json::to_string(val).map_err(|err| -> String {
+        .map(|(key, val)| -> Result<(&str, String), Error> {
+            let serialized: String = serde_json::to_string(val).map_err(|err| {
                 error!("Error serializing value to string: {:?}", err);
-                "Error saving items to cache".into()
+                Error::Internal("Error saving items to cache".into())
             })?;
 
             Ok((key, serialized))
         })
-        .collect::<Result<Vec<_>, String>>()?;
-
-    get_redis_conn()?
-        .hset_multiple::<&str, &str, String, ()>(hash_name, &kv_pairs_serialized)
-        .map_err(|err| -> String {
-            error!(
-                "Error setting hash items into hash \"{}\": {:?}",
-                hash_name, err
-            );
-            "Error setting values into cache".into()
-        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    let kv_pairs_serialized = kv_pairs_serialized
+        .iter()
+        .map(|(key, val)| (*key, val.as_bytes()))
+        .collect::<Vec<_>>();
+    let now = Utc::now().timestamp().to_string();
+    let fetched_at_pairs = kv_pairs
+        .iter()
+        .map(|(key, _)| (*key, now.as_bytes()))
+        .collect::<Vec<_>>();
+
+    // Fetch times are set after the items so that an item is never considered fresh without having
+    // been cached
+    backend().set_hash_items(hash_name, &kv_pairs_serialized, None)?;
+    backend().set_hash_items(&fetched_at_hash_name(hash_name), &fetched_at_pairs, None)
 }
 
-pub(crate) fn get_hash_items<T: for<'de> Deserialize<'de>>(
+fn fetched_at_hash_name(hash_name: &str) -> String { format!("{}:fetched_at", hash_name) }
+
+/// Same as `get_hash_items`, but items that were cached more than `max_age` ago are returned as
+/// `None`.  Items cached before fetch times were tracked are always treated as expired.
+pub(crate) fn get_fresh_hash_items<T: for<'de> Deserialize<'de>>(
     hash_name: &str,
     keys: &[&str],
-) -> Result<Vec<Option<T>>, String> {
+    max_age: Duration,
+) -> Result<Vec<Option<T>>, Error> {
     if keys.is_empty() {
         return Ok(Vec::new());
     }
 
-    let mut conn = get_redis_conn()?;
-
-    let mut cmd = redis::cmd("HMGET");
-    let cmd = keys
+    let fetched_ats: Vec<Option<i64>> = backend()
+        .get_hash_items(&fetched_at_hash_name(hash_name), keys)?
+        .into_iter()
+        .map(|fetched_at| {
+            fetched_at.and_then(|fetched_at| std::str::from_utf8(&fetched_at).ok()?.parse().ok())
+        })
+        .collect();
+    let cutoff = Utc::now().timestamp() - max_age.as_secs() as i64;
+    let fresh_keys: Vec<&str> = keys
         .iter()
-        .fold(cmd.arg(hash_name), |acc, key| acc.arg(*key));
+        .zip(&fetched_ats)
+        .filter(|(_, fetched_at)| fetched_at.map(|ts| ts >= cutoff).unwrap_or(false))
+        .map(|(key, _)| *key)
+        .collect();
+
+    let mut fresh_items = get_hash_items::<T>(hash_name, &fresh_keys)?.into_iter();
+    Ok(fetched_ats
+        .into_iter()
+        .map(|fetched_at| match fetched_at {
+            Some(ts) if ts >= cutoff => fresh_items.next().flatten(),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Removes the provided keys from the hash, or all of its items if `keys` is `None`.  Returns the
+/// number of items removed, or `None` if the whole hash was removed.
+pub(crate) fn invalidate_hash_items(
+    hash_name: &str,
+    keys: Option<&[&str]>,
+) -> Result<Option<usize>, Error> {
+    hot_cache::invalidate(hash_name, keys);
+    let invalidated_count = backend().invalidate(hash_name, keys)?;
+    backend().invalidate(&fetched_at_hash_name(hash_name), keys)?;
+    Ok(invalidated_count)
+}
+
+pub(crate) fn get_hash_items<T: for<'de> Deserialize<'de>>(
+    hash_name: &str,
+    keys: &[&str],
+) -> Result<Vec<Option<T>>, Error> {
+    if keys.is_empty() {
+        return Ok(Vec::new());
+    }
 
-    cmd.query::<Vec<Option<String>>>(&mut *conn)
-        .map_err(|err| -> String {
-            error!("Error pulling data from Redis cache: {:?}", err);
-            "Error pulling data from Redis cache".into()
-        })?
+    backend()
+        .get_hash_items(hash_name, keys)?
         .into_iter()
         .enumerate()
-        .map(|(i, opt): (usize, Option<String>)| match opt {
-            Some(val) => serde_json::from_str(&val).map_err(|err| -> String {
+        .map(|(i, opt): (usize, Option<Vec<u8>>)| match opt {
+            Some(val) => serde_json::from_slice(&val).map_err(|err| {
                 error!(
                     "Error deserializing value of {}: {:?}; key={}; val={}",
                     std::any::type_name::<T>(),
                     err,
                     keys.get(i).unwrap_or(&"<NO KEY FOUND FOR INDEX>"),
-                    val
+                    String::from_utf8_lossy(&val)
                 );
-                "Error reading values from cache".into()
+                Error::Internal("Error reading values from cache".into())
             }),
             None => Ok(None),
         })
-        .collect::<Result<Vec<Option<T>>, String>>()
+        .collect::<Result<Vec<Option<T>>, Error>>()
 }
 
 #[test]
@@ -0,0 +1,106 @@
+//! Caches users' playback states fetched from Spotify.  Profile pages poll what the user is
+//! currently listening to, so states are cached briefly to keep everyone viewing a profile from
+//! hitting the Spotify API with the user's token on every poll.
+//!
+//! Each user's state lives in a hash named `now_playing:<user_id>` which expires after
+//! `NOW_PLAYING_CACHE_TTL`.  Users that aren't playing anything are cached as well, as are users
+//! whose tokens lack the playback scope so that their profiles don't retry the request on every
+//! poll.
+
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+use super::backend;
+use crate::{error::Error, models::PlaybackState};
+
+const NOW_PLAYING_CACHE_TTL: Duration = Duration::from_secs(15);
+const CACHE_KEY: &str = "state";
+
+fn hash_name(user_id: i64) -> String { format!("now_playing:{}", user_id) }
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct CachedPlaybackState {
+    pub fetched_at: NaiveDateTime,
+    /// `None` if nothing was playing
+    pub state: Option<PlaybackState>,
+    /// Set if Spotify rejected the request because the user hasn't granted the playback scope
+    #[serde(default)]
+    pub missing_scope: bool,
+}
+
+fn decode(serialized: &[u8]) -> Result<CachedPlaybackState, Error> {
+    serde_json::from_slice(serialized).map_err(|err| {
+        error!("Error deserializing cached playback state: {:?}", err);
+        Error::Internal("Error reading values from cache".into())
+    })
+}
+
+pub(crate) fn get_cached_playback_state(
+    user_id: i64,
+) -> Result<Option<CachedPlaybackState>, Error> {
+    let cached = backend().get_hash_items(&hash_name(user_id), &[CACHE_KEY])?;
+    cached
+        .into_iter()
+        .next()
+        .flatten()
+        .map(|serialized| decode(&serialized))
+        .transpose()
+}
+
+pub(crate) fn set_cached_playback_state(
+    user_id: i64,
+    state: &CachedPlaybackState,
+) -> Result<(), Error> {
+    let serialized = serde_json::to_vec(state).map_err(|err| {
+        error!("Error serializing playback state: {:?}", err);
+        Error::Internal("Error serializing playback state".into())
+    })?;
+    backend().set_hash_items(
+        &hash_name(user_id),
+        &[(CACHE_KEY, &serialized)],
+        Some(NOW_PLAYING_CACHE_TTL),
+    )
+}
+
+#[test]
+fn playback_state_round_trip() {
+    let res = r#"{
+        "device": {"id": "abc", "name": "Phone", "volume_percent": 50},
+        "timestamp": 1792150000000,
+        "progress_ms": 42000,
+        "is_playing": true,
+        "currently_playing_type": "track",
+        "item": {
+            "album": {"artists": [], "id": "album", "images": [], "name": "Album"},
+            "artists": [{"id": "artist", "name": "Artist"}],
+            "duration_ms": 180000,
+            "id": "track",
+            "name": "Track",
+            "popularity": 50,
+            "preview_url": null
+        }
+    }"#;
+    let state: PlaybackState = serde_json::from_str(res).unwrap();
+    let cached = CachedPlaybackState {
+        fetched_at: chrono::DateTime::from_timestamp(1_792_150_000, 0)
+            .unwrap()
+            .naive_utc(),
+        state: Some(state),
+        missing_scope: false,
+    };
+    let decoded = decode(&serde_json::to_vec(&cached).unwrap()).unwrap();
+    assert_eq!(decoded.fetched_at, cached.fetched_at);
+    let state = decoded.state.unwrap();
+    assert!(state.is_playing);
+    assert_eq!(state.progress_ms, Some(42000));
+    assert_eq!(state.item.unwrap().id, "track");
+
+    // Spotify doesn't include episodes unless they're requested via `additional_types`
+    let episode: PlaybackState = serde_json::from_str(
+        r#"{"progress_ms": 1000, "is_playing": false, "currently_playing_type": "episode", "item": null}"#,
+    )
+    .unwrap();
+    assert!(episode.item.is_none());
+}
@@ -0,0 +1,154 @@
+//! Cache backend storing hashes in Redis.  Every command is tracked by the circuit breaker in the
+//! parent module.
+
+use std::time::Duration;
+
+use r2d2_redis::{r2d2, RedisConnectionManager};
+
+use super::{backend::CacheBackend, track, CIRCUIT_BREAKER};
+use crate::error::Error;
+
+const REDIS_CONNECTION_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub(crate) struct RedisCacheBackend {
+    pool: r2d2::Pool<RedisConnectionManager>,
+}
+
+impl RedisCacheBackend {
+    pub(crate) fn new(redis_url: &str) -> Self {
+        let manager = RedisConnectionManager::new(redis_url)
+            .map_err(|err| {
+                error!("Failed to create Redis connection manager: {:?}", err);
+                std::process::exit(1);
+            })
+            .unwrap();
+        // Connections are established lazily so that the server can start while Redis is down
+        let pool = r2d2::Pool::builder()
+            .connection_timeout(REDIS_CONNECTION_TIMEOUT)
+            .build_unchecked(manager);
+        RedisCacheBackend { pool }
+    }
+
+    pub(crate) fn get_conn(&self) -> Result<r2d2::PooledConnection<RedisConnectionManager>, Error> {
+        if CIRCUIT_BREAKER.lock().unwrap().is_open() {
+            return Err(Error::Internal(
+                "Spotify metadata cache is unavailable".into(),
+            ));
+        }
+
+        self.pool.get().map_err(|err| {
+            error!("Error getting client from connection pool: {:?}", err);
+            super::record_cache_failure();
+            Error::Internal("Error connecting to Spotify metadata cache".into())
+        })
+    }
+}
+
+impl CacheBackend for RedisCacheBackend {
+    fn get_hash_items(
+        &self,
+        hash_name: &str,
+        keys: &[&str],
+    ) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // `HMGET` is used explicitly since `hget` issues a plain `HGET` if there's only one key
+        track(
+            redis::cmd("HMGET")
+                .arg(hash_name)
+                .arg(keys)
+                .query(&mut *self.get_conn()?),
+        )
+        .map_err(|err| {
+            error!("Error pulling data from Redis cache: {:?}", err);
+            Error::Internal("Error pulling data from Redis cache".into())
+        })
+    }
+
+    fn set_hash_items(
+        &self,
+        hash_name: &str,
+        kv_pairs: &[(&str, &[u8])],
+        ttl: Option<Duration>,
+    ) -> Result<(), Error> {
+        if kv_pairs.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic().hset_multiple(hash_name, kv_pairs).ignore();
+        if let Some(ttl) = ttl {
+            pipe.expire(hash_name, ttl.as_secs() as usize).ignore();
+        }
+        track(pipe.query::<()>(&mut *self.get_conn()?)).map_err(|err| {
+            error!(
+                "Error setting hash items into hash \"{}\": {:?}",
+                hash_name, err
+            );
+            Error::Internal("Error setting values into cache".into())
+        })
+    }
+
+    fn invalidate(&self, hash_name: &str, keys: Option<&[&str]>) -> Result<Option<usize>, Error> {
+        let mut conn = self.get_conn()?;
+        let res = match keys {
+            Some([]) => Ok(Some(0)),
+            Some(keys) => redis::cmd("HDEL")
+                .arg(hash_name)
+                .arg(keys)
+                .query::<usize>(&mut *conn)
+                .map(Some),
+            None => redis::cmd("DEL")
+                .arg(hash_name)
+                .query::<()>(&mut *conn)
+                .map(|()| None),
+        };
+        track(res).map_err(|err| {
+            error!(
+                "Error invalidating items in hash \"{}\": {:?}",
+                hash_name, err
+            );
+            Error::Internal("Error invalidating cached values".into())
+        })
+    }
+
+    fn ping(&self) -> Result<(), Error> {
+        track(redis::cmd("PING").query::<String>(&mut *self.get_conn()?))
+            .map(drop)
+            .map_err(|err| {
+                error!("Error pinging Redis: {:?}", err);
+                Error::Internal("Error pinging Redis".into())
+            })
+    }
+
+    /// Returns the memory used by Redis, as reported by `INFO memory`
+    fn used_memory_bytes(&self) -> Result<i64, Error> {
+        let info = track(
+            redis::cmd("INFO")
+                .arg("memory")
+                .query::<String>(&mut *self.get_conn()?),
+        )
+        .map_err(|err| {
+            error!("Error fetching Redis memory info: {:?}", err);
+            Error::Internal("Error fetching Redis memory info".into())
+        })?;
+        parse_used_memory(&info)
+            .ok_or_else(|| Error::Internal("Redis memory info is missing `used_memory`".into()))
+    }
+}
+
+fn parse_used_memory(info: &str) -> Option<i64> {
+    info.lines()
+        .find_map(|line| line.trim().strip_prefix("used_memory:"))
+        .and_then(|val| val.parse().ok())
+}
+
+#[test]
+fn redis_used_memory_parsing() {
+    let info =
+        "# Memory\r\nused_memory:1048576\r\nused_memory_human:1.00M\r\nused_memory_rss:2097152\r\n";
+    assert_eq!(parse_used_memory(info), Some(1048576));
+    assert_eq!(parse_used_memory("# Memory\r\n"), None);
+}
@@ -0,0 +1,52 @@
+//! Caches rendered share card images.  Rendering is fairly expensive and link previews tend to be
+//! fetched in bursts whenever a card is shared, so cards are cached until the user is next updated.
+//!
+//! Each user's cards live in a hash named `share_cards:<user_id>` keyed by the user's last update
+//! time and the card's timeframe, and the hash is removed whenever a new snapshot is stored.
+
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+
+use super::backend;
+use crate::error::Error;
+
+const SHARE_CARD_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn hash_name(user_id: i64) -> String { format!("share_cards:{}", user_id) }
+
+fn cache_key(last_update_time: NaiveDateTime, timeframe: &str) -> String {
+    format!("{}:{}", last_update_time.and_utc().timestamp(), timeframe)
+}
+
+/// Returns the PNG cached for the user's most recent update and the provided timeframe, if there
+/// is one
+pub(crate) fn get_cached_share_card(
+    user_id: i64,
+    last_update_time: NaiveDateTime,
+    timeframe: &str,
+) -> Result<Option<Vec<u8>>, Error> {
+    let cached = backend().get_hash_items(&hash_name(user_id), &[&cache_key(
+        last_update_time,
+        timeframe,
+    )])?;
+    Ok(cached.into_iter().next().flatten())
+}
+
+pub(crate) fn set_cached_share_card(
+    user_id: i64,
+    last_update_time: NaiveDateTime,
+    timeframe: &str,
+    png: &[u8],
+) -> Result<(), Error> {
+    backend().set_hash_items(
+        &hash_name(user_id),
+        &[(&cache_key(last_update_time, timeframe), png)],
+        Some(SHARE_CARD_CACHE_TTL),
+    )
+}
+
+/// Removes all cached share cards for the user.  Called whenever a new snapshot is stored.
+pub(crate) fn invalidate_cached_share_cards(user_id: i64) -> Result<(), Error> {
+    backend().invalidate(&hash_name(user_id), None).map(drop)
+}
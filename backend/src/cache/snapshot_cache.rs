@@ -0,0 +1,95 @@
+//! Caches fully serialized `StatsSnapshot` responses so that repeated requests for a user's current
+//! stats can be served with a single cache read rather than re-querying the database and
+//! re-assembling entity metadata.
+//!
+//! Each user's entries live in a hash named `stats_snapshots:<user_id>` which is removed whenever a
+//! new snapshot is stored for them.  Entries are also keyed by the user's last update time so that
+//! a response assembled concurrently with an update can never be served in place of the new one.
+
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+
+use super::backend;
+use crate::{db_util::SnapshotFilter, error::Error};
+
+/// Entity metadata embedded in cached snapshots goes stale eventually, so users' hashes are
+/// expired after this long even if they haven't been updated
+const SNAPSHOT_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn hash_name(user_id: i64) -> String { format!("stats_snapshots:{}", user_id) }
+
+fn cache_key(last_update_time: NaiveDateTime, filter: &SnapshotFilter) -> String {
+    let limit = filter
+        .limit
+        .map(|limit| limit.to_string())
+        .unwrap_or_else(|| "all".into());
+    let timeframe_ids = filter
+        .timeframes
+        .iter()
+        .map(|timeframe| timeframe.id().to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "{}:{}:{}",
+        last_update_time.and_utc().timestamp(),
+        limit,
+        timeframe_ids
+    )
+}
+
+/// Returns the serialized snapshot cached for the user's most recent update and the provided
+/// filter, if there is one
+pub(crate) fn get_cached_snapshot(
+    user_id: i64,
+    last_update_time: NaiveDateTime,
+    filter: &SnapshotFilter,
+) -> Result<Option<String>, Error> {
+    let cached =
+        backend().get_hash_items(&hash_name(user_id), &[&cache_key(last_update_time, filter)])?;
+    match cached.into_iter().next().flatten() {
+        Some(serialized) => String::from_utf8(serialized).map(Some).map_err(|err| {
+            error!("Error reading cached stats snapshot: {:?}", err);
+            Error::Internal("Error reading values from cache".into())
+        }),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn set_cached_snapshot(
+    user_id: i64,
+    last_update_time: NaiveDateTime,
+    filter: &SnapshotFilter,
+    serialized: &str,
+) -> Result<(), Error> {
+    backend().set_hash_items(
+        &hash_name(user_id),
+        &[(&cache_key(last_update_time, filter), serialized.as_bytes())],
+        Some(SNAPSHOT_CACHE_TTL),
+    )
+}
+
+/// Removes all cached snapshots for the user.  Called whenever a new snapshot is stored.
+pub(crate) fn invalidate_cached_snapshots(user_id: i64) -> Result<(), Error> {
+    backend().invalidate(&hash_name(user_id), None).map(drop)
+}
+
+#[test]
+fn snapshot_cache_keys() {
+    use crate::models::Timeframe;
+
+    let last_update_time = chrono::DateTime::from_timestamp(1_700_000_000, 0)
+        .unwrap()
+        .naive_utc();
+    assert_eq!(
+        cache_key(last_update_time, &SnapshotFilter::default()),
+        "1700000000:all:0,1,2"
+    );
+    assert_eq!(
+        cache_key(last_update_time, &SnapshotFilter {
+            limit: Some(10),
+            timeframes: vec![Timeframe::Long],
+        }),
+        "1700000000:10:2"
+    );
+}
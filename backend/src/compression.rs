@@ -0,0 +1,268 @@
+//! Compresses JSON responses for clients that support it.  Stats snapshots with hundreds of artists
+//! and tracks are large, and compressing them saves a lot of bandwidth for mobile users.
+//!
+//! The encoding is negotiated via `Accept-Encoding`, preferring zstd, then brotli, then gzip when
+//! the client accepts more than one equally.  Only JSON responses of at least
+//! `COMPRESSION_MIN_SIZE_BYTES` are compressed since compressing small ones isn't worth the CPU
+//! time, and streamed responses are left alone so that they're still delivered incrementally.
+//!
+//! Each encoding is a separate representation of the response, so the encoding's name is appended
+//! to the `ETag` of compressed responses.  Otherwise caches and conditional requests couldn't tell
+//! a compressed body apart from the uncompressed one.
+
+use std::io::{Cursor, Write};
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{Header, Status},
+    Request, Response,
+};
+use tokio::task::block_in_place;
+
+use crate::conf::CONF;
+
+const GZIP_LEVEL: u32 = 6;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW_SIZE: u32 = 22;
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Encoding {
+    Zstd,
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// Ordered from most to least preferred
+    const ALL: [Encoding; 3] = [Encoding::Zstd, Encoding::Brotli, Encoding::Gzip];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    pub(crate) fn compress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Zstd => zstd::encode_all(data, ZSTD_LEVEL),
+            Encoding::Brotli => {
+                let mut compressed = Vec::new();
+                let mut writer = brotli::CompressorWriter::new(
+                    &mut compressed,
+                    4096,
+                    BROTLI_QUALITY,
+                    BROTLI_WINDOW_SIZE,
+                );
+                writer.write_all(data)?;
+                drop(writer);
+                Ok(compressed)
+            },
+            Encoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(GZIP_LEVEL));
+                encoder.write_all(data)?;
+                encoder.finish()
+            },
+        }
+    }
+}
+
+/// Picks the encoding to use from an `Accept-Encoding` header.  Returns `None` if the client
+/// doesn't accept any of the supported encodings.
+pub(crate) fn negotiate_encoding(accept_encoding: &str) -> Option<Encoding> {
+    let mut wildcard_q = None;
+    let mut accepted: Vec<(&str, f32)> = Vec::new();
+    for entry in accept_encoding.split(',') {
+        let mut parts = entry.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let q = parts
+            .find_map(|param| param.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.);
+        if name == "*" {
+            wildcard_q = Some(q);
+        } else if !name.is_empty() {
+            accepted.push((name, q));
+        }
+    }
+
+    let q_of = |encoding: Encoding| {
+        accepted
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(encoding.name()))
+            .map(|(_, q)| *q)
+            .or(wildcard_q)
+            .unwrap_or(0.)
+    };
+    // `max_by` returns the last of equal elements, so this iterates from least to most preferred
+    Encoding::ALL
+        .iter()
+        .rev()
+        .map(|&encoding| (encoding, q_of(encoding)))
+        .filter(|(_, q)| *q > 0.)
+        .max_by(|(_, q1), (_, q2)| q1.total_cmp(q2))
+        .map(|(encoding, _)| encoding)
+}
+
+/// Returns the `ETag` of a body with the provided tag once it's compressed with `encoding`
+pub(crate) fn encoded_etag(etag: &str, encoding: Encoding) -> String {
+    match etag.strip_suffix('"') {
+        Some(opaque_tag) => format!("{}-{}\"", opaque_tag, encoding.name()),
+        None => etag.to_owned(),
+    }
+}
+
+/// Removes the suffix added by `encoded_etag`, if any, returning the tag of the uncompressed body
+pub(crate) fn strip_encoding_suffix(etag: &str) -> String {
+    Encoding::ALL
+        .iter()
+        .find_map(|encoding| {
+            let suffix = format!("-{}\"", encoding.name());
+            etag.strip_suffix(suffix.as_str())
+                .map(|opaque_tag| format!("{}\"", opaque_tag))
+        })
+        .unwrap_or_else(|| etag.to_owned())
+}
+
+/// `304 Not Modified` responses have no body to compress, but they still need to carry the tag of
+/// the representation that the client has cached, which may be a compressed one
+fn restore_encoded_etag(req: &Request<'_>, res: &mut Response<'_>) {
+    let (etag, if_none_match) = match (
+        res.headers().get_one("ETag"),
+        req.headers().get_one("If-None-Match"),
+    ) {
+        (Some(etag), Some(if_none_match)) => (etag, if_none_match),
+        _ => return,
+    };
+    let cached_etag = if_none_match
+        .split(',')
+        .map(str::trim)
+        .find_map(|cached_etag| {
+            Encoding::ALL
+                .iter()
+                .map(|&encoding| encoded_etag(etag, encoding))
+                .find(|encoded| encoded == cached_etag)
+        });
+    if let Some(cached_etag) = cached_etag {
+        res.set_header(Header::new("ETag", cached_etag));
+    }
+}
+
+pub(crate) struct CompressionFairing;
+
+#[rocket::async_trait]
+impl Fairing for CompressionFairing {
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let min_size = match CONF.compression_min_size {
+            Some(min_size) => min_size,
+            None => return,
+        };
+        if res.status() == Status::NotModified {
+            res.adjoin_header(Header::new("Vary", "Accept-Encoding"));
+            restore_encoded_etag(req, res);
+            return;
+        }
+        let is_json = res
+            .content_type()
+            .map(|content_type| content_type.is_json())
+            .unwrap_or(false);
+        if !is_json || res.headers().contains("Content-Encoding") {
+            return;
+        }
+        match res.body().preset_size() {
+            Some(size) if size >= min_size => (),
+            _ => return,
+        }
+
+        // Responses differ based on `Accept-Encoding` whether or not this one is compressed
+        res.adjoin_header(Header::new("Vary", "Accept-Encoding"));
+        let encoding = match req
+            .headers()
+            .get_one("Accept-Encoding")
+            .and_then(negotiate_encoding)
+        {
+            Some(encoding) => encoding,
+            None => return,
+        };
+
+        let body = match res.body_mut().to_bytes().await {
+            Ok(body) => body,
+            Err(err) => {
+                error!("Error reading response body to compress: {}", err);
+                return;
+            },
+        };
+        match block_in_place(|| encoding.compress(&body)) {
+            Ok(compressed) => {
+                let etag = res
+                    .headers()
+                    .get_one("ETag")
+                    .map(|etag| encoded_etag(etag, encoding));
+                if let Some(etag) = etag {
+                    res.set_header(Header::new("ETag", etag));
+                }
+                res.set_header(Header::new("Content-Encoding", encoding.name()));
+                res.set_sized_body(compressed.len(), Cursor::new(compressed));
+            },
+            Err(err) => {
+                error!(
+                    "Error compressing response with {}: {}",
+                    encoding.name(),
+                    err
+                );
+                res.set_sized_body(body.len(), Cursor::new(body));
+            },
+        }
+    }
+
+    fn info(&self) -> Info {
+        Info {
+            name: "Compression Fairing",
+            kind: Kind::Response,
+        }
+    }
+}
+
+#[test]
+fn encoding_negotiation() {
+    assert_eq!(
+        negotiate_encoding("gzip, deflate, br, zstd"),
+        Some(Encoding::Zstd)
+    );
+    assert_eq!(negotiate_encoding("gzip, br"), Some(Encoding::Brotli));
+    assert_eq!(
+        negotiate_encoding("br;q=0.5, gzip;q=0.8"),
+        Some(Encoding::Gzip)
+    );
+    assert_eq!(
+        negotiate_encoding("*;q=0.1, zstd;q=0"),
+        Some(Encoding::Brotli)
+    );
+    assert_eq!(negotiate_encoding("identity"), None);
+    assert_eq!(negotiate_encoding(""), None);
+
+    let data = "{\"artists\": []} ".repeat(100);
+    for encoding in Encoding::ALL {
+        let compressed = encoding.compress(data.as_bytes()).unwrap();
+        assert!(compressed.len() < data.len(), "{:?}", encoding);
+    }
+    let mut decompressed = String::new();
+    std::io::Read::read_to_string(
+        &mut flate2::read::GzDecoder::new(&Encoding::Gzip.compress(data.as_bytes()).unwrap()[..]),
+        &mut decompressed,
+    )
+    .unwrap();
+    assert_eq!(decompressed, data);
+}
+
+#[test]
+fn etag_encoding_suffix() {
+    assert_eq!(encoded_etag("\"abc\"", Encoding::Gzip), "\"abc-gzip\"");
+    assert_eq!(encoded_etag("W/\"abc\"", Encoding::Brotli), "W/\"abc-br\"");
+    assert_eq!(strip_encoding_suffix("W/\"abc-br\""), "W/\"abc\"");
+    assert_eq!(strip_encoding_suffix("\"abc-zstd\""), "\"abc\"");
+    assert_eq!(strip_encoding_suffix("\"abc\""), "\"abc\"");
+}
@@ -3,24 +3,175 @@ use std::env;
 use base64;
 use chrono::Duration;
 
+use crate::{
+    cache::backend::CacheBackendConf,
+    error::Error,
+    oauth_scopes::{self, SpotifyFeature},
+    public_stats::{self, PublicStatsField},
+    spotify_api::{MAX_ENTITY_FETCH_COUNT, MAX_TOP_ENTITY_COUNT},
+};
+
+/// Upper bound for configured cache TTLs and metadata ages, which are subtracted from the current
+/// time to find cutoffs.  Anything larger could overflow the timestamp.
+const MAX_AGE_SECONDS: u64 = 60 * 60 * 24 * 365 * 100;
+
 pub(crate) struct Conf {
     pub client_id: String,
     pub client_secret: String,
     pub api_server_url: String,
     pub website_url: String,
-    pub redis_url: String,
+    /// Origins allowed to make cross-origin requests to the API.  `*` allows all origins and is
+    /// the default if unset.
+    pub allowed_origins: Vec<String>,
+    /// Where the cache of Spotify API data and rendered responses is stored
+    pub cache_backend: CacheBackendConf,
+    /// Max number of Spotify entities kept in the in-process cache in front of `cache_backend`.
+    /// The hot cache is disabled if 0.
+    pub hot_cache_max_items: usize,
+    /// How long entities are kept in the hot cache before being re-read from `cache_backend`
+    pub hot_cache_ttl: std::time::Duration,
+    /// Used for the database-backed second-level cache of Spotify entity metadata.  That cache is
+    /// disabled if unset.
+    pub database_url: Option<String>,
     // Internal Config
     pub artists_cache_hash_name: String,
     pub tracks_cache_hash_name: String,
+    pub audio_features_cache_hash_name: String,
+    /// Max age of cached Spotify entity metadata before it's re-fetched.  Cached metadata never
+    /// expires if unset.
+    pub entity_cache_ttl: Option<std::time::Duration>,
     // Scraper config
+    /// Minimum time between updates of a user unless overridden for their update tier or for the
+    /// user themselves
     pub min_update_interval: Duration,
     pub admin_api_token: String,
+    /// Key used to encrypt users' Spotify tokens at rest, provided as 32 base64-encoded bytes.
+    /// Tokens are stored in plaintext if unset.
+    pub token_encryption_key: Option<crate::token_encryption::TokenEncryptionKey>,
+    /// Key used to seal users' session cookies, provided as 32 base64-encoded bytes.  It must be
+    /// shared by all servers so that sessions survive restarts and work on any of them.
+    pub session_key: crate::session::SessionKey,
+    /// How long session cookies issued after users authorize remain valid
+    pub session_ttl: Duration,
+    /// If set, users are updated by a scheduler running inside the server rather than by an
+    /// external cron job hitting `/update_user`.
+    pub scheduler_enabled: bool,
+    /// Max number of user updates the scheduler will run at once.  Each one holds a database
+    /// connection for as long as the scheduler is running.
+    pub scheduler_concurrency: usize,
+    /// How long the scheduler waits before checking again once no users are due for an update
+    pub scheduler_poll_interval: std::time::Duration,
+    /// If set, the scheduler's updates are dry runs that log what would be stored rather than
+    /// storing it, and its other periodic jobs are skipped.  Since users are never marked as
+    /// updated, each user is only dry-run once until the server is restarted.
+    pub scheduler_dry_run: bool,
+    /// Users whose updates have failed this many times in a row are no longer updated
+    /// automatically until their failure count is reset via the admin API.
+    pub max_consecutive_update_failures: i32,
+    /// Users whose profiles have been viewed at least `active_tier_min_views` times within this
+    /// long are in the active update tier
+    pub active_tier_max_view_age: Duration,
+    pub active_tier_min_views: i64,
+    /// Users whose profiles haven't been viewed for this long are in the dormant update tier
+    pub dormant_tier_min_view_age: Duration,
+    /// How often users in the active update tier are updated.  Defaults to `min_update_interval`.
+    pub active_tier_update_interval: Option<Duration>,
+    /// How often users in the normal update tier are updated
+    pub normal_tier_update_interval: Duration,
+    /// How often users in the dormant update tier are updated
+    pub dormant_tier_update_interval: Duration,
+    /// How often the scheduler recomputes users' update tiers
+    pub update_tier_refresh_interval: std::time::Duration,
+    /// How long after signing up users' recently played tracks are polled more often than they're
+    /// updated (see `crate::onboarding`).  Disabled if zero.
+    pub onboarding_period: Duration,
+    /// How often the scheduler polls the recently played tracks of users that are onboarding
+    pub onboarding_poll_interval: std::time::Duration,
+    /// How often the scheduler recomputes the global top artists and tracks charts
+    pub global_charts_refresh_interval: std::time::Duration,
+    /// How often the scheduler recomputes the totals stored in `system_stats`
+    pub system_stats_refresh_interval: std::time::Duration,
     pub telemetry_server_port: u16,
+    /// Overrides the size of the database connection pool.  Rocket's default of 4 connections per
+    /// worker is used if unset.
+    pub db_pool_size: Option<u32>,
+    // History retention config
+    pub retention_keep_all_for: Duration,
+    pub retention_keep_weekly_for: Duration,
+    /// If set, only the rankings that changed since a user's previous snapshot are stored for each
+    /// new snapshot rather than all of them.
+    pub snapshot_delta_encoding: bool,
+    /// Number of top artists and tracks fetched and stored per timeframe for users that haven't
+    /// set their own.  Must be between 1 and 99; counts above 50 are fetched as multiple pages.
+    pub entity_fetch_count: usize,
+    // Spotify API client config
+    pub spotify_api_max_attempts: usize,
+    /// Optional features enabled on this server.  Users are only asked to grant the OAuth scopes
+    /// needed by these features when authorizing.  All features are enabled if unset.
+    pub spotify_features: Vec<SpotifyFeature>,
+    /// Aggregates exposed by the public `/stats/global/summary` endpoint.  All are exposed if
+    /// unset, and the endpoint is disabled if set to an empty list.
+    pub public_stats_fields: Vec<PublicStatsField>,
+    // Rate limiting config
+    /// Max number of requests a single IP address can make to the `/stats`, `/compare`, and
+    /// `/graphql` routes in a burst
+    pub rate_limit_ip_burst: u32,
+    /// Rate at which the per-IP burst allowance refills.  Rate limiting by IP is disabled if 0.
+    pub rate_limit_ip_per_minute: u32,
+    /// Max number of requests that can be made for a single username in a burst, across all
+    /// clients
+    pub rate_limit_username_burst: u32,
+    /// Rate at which the per-username burst allowance refills.  Rate limiting by username is
+    /// disabled if 0.
+    pub rate_limit_username_per_minute: u32,
+    /// Rate limit of API keys minted without an explicit one.  API key requests are limited per
+    /// key instead of by IP address and username.
+    pub api_key_default_rate_limit_per_minute: u32,
+    /// Requests that take at least this long are logged.  Slow request logging is disabled if
+    /// `SLOW_REQUEST_THRESHOLD_MS` is 0.
+    pub slow_request_threshold: Option<std::time::Duration>,
+    /// JSON responses at least this many bytes long are compressed for clients that accept it.
+    /// Compression is disabled if `COMPRESSION_MIN_SIZE_BYTES` is 0.
+    pub compression_min_size: Option<usize>,
+    /// SMTP server that weekly digests are emailed via.  Set with `DIGEST_SMTP_URL` and
+    /// `DIGEST_EMAIL_FROM`; digests are disabled if unset.
+    pub digest_smtp: Option<crate::digest::DigestSmtpConf>,
+    /// How often the digest sender checks for users due for a weekly digest email
+    pub digest_check_interval: std::time::Duration,
+    /// Stored artist and track metadata older than this is re-fetched from Spotify by the
+    /// scheduler.  The refresh job is disabled if `METADATA_REFRESH_MAX_AGE_SECONDS` is 0.
+    pub metadata_refresh_max_age: Option<std::time::Duration>,
+    /// How often the scheduler checks for stale metadata to refresh
+    pub metadata_refresh_interval: std::time::Duration,
+    /// Max number of batches of each of artists and tracks that are refreshed each time
+    pub metadata_refresh_max_batches: usize,
+    // Alerting config
+    /// Discord or Slack webhook that alerts are posted to
+    pub alert_webhook_url: Option<String>,
+    /// SMTP server that alerts are emailed via.  Set with `ALERT_SMTP_URL`, `ALERT_EMAIL_FROM`,
+    /// and `ALERT_EMAIL_TO`.
+    pub alert_smtp: Option<crate::alerts::SmtpConf>,
+    /// Number of consecutive failed updates for a user, or failed snapshot stores across all
+    /// users, after which an alert is sent
+    pub alert_failure_threshold: u32,
+    /// An alert is sent if the most overdue user waiting to be updated is overdue by more than
+    /// this.  Update lag alerts are disabled if `ALERT_UPDATE_LAG_SECONDS` is 0.
+    pub alert_update_lag_threshold: Option<std::time::Duration>,
+    /// Minimum time between alerts of the same kind
+    pub alert_cooldown: std::time::Duration,
+    // Outbox config
+    /// How often the outbox dispatcher checks for events that are due, in addition to being woken
+    /// up whenever a snapshot is stored
+    pub outbox_poll_interval: std::time::Duration,
+    /// Number of times delivering an outbox event is attempted before giving up on it
+    pub outbox_max_attempts: i32,
 }
 
 impl Conf {
     pub(crate) fn build_from_env() -> Self {
-        dotenv::dotenv().expect("dotenv file parsing failed");
+        // The server requires a `.env` file to be present (see `main`), but tests configure the
+        // environment directly
+        dotenv::dotenv().ok();
 
         Conf {
             client_id: env::var("SPOTIFY_CLIENT_ID")
@@ -30,10 +181,69 @@ impl Conf {
             api_server_url: env::var("API_SERVER_URL")
                 .expect("The `API_SERVER_URL` environment variable must be set."),
             website_url: env::var("WEBSITE_URL").expect("The `WEBSITE_URL` must be set."),
-            redis_url: env::var("REDIS_URL")
-                .expect("The `REDIS_URL` environment variable must be set."),
+            allowed_origins: env::var("ALLOWED_ORIGINS")
+                .unwrap_or_else(|_| -> String { "*".to_string() })
+                .split(',')
+                .map(|origin| origin.trim().trim_end_matches('/').to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect(),
+            cache_backend: match env::var("CACHE_BACKEND")
+                .unwrap_or_else(|_| -> String { "redis".into() })
+                .as_str()
+            {
+                "redis" => CacheBackendConf::Redis {
+                    url: env::var("REDIS_URL").expect(
+                        "The `REDIS_URL` environment variable must be set if `CACHE_BACKEND` is \
+                         `redis`.",
+                    ),
+                },
+                "memory" => CacheBackendConf::Memory {
+                    max_items: env::var("CACHE_MEMORY_MAX_ITEMS")
+                        .unwrap_or_else(|_| -> String { "100000".into() })
+                        .parse()
+                        .expect(
+                            "Invalid value provided for `CACHE_MEMORY_MAX_ITEMS`; must be an \
+                             unsigned integer",
+                        ),
+                },
+                "none" => CacheBackendConf::Disabled,
+                other => panic!(
+                    "Invalid value provided for `CACHE_BACKEND`: \"{}\"; must be one of `redis`, \
+                     `memory`, or `none`",
+                    other
+                ),
+            },
+            hot_cache_max_items: env::var("HOT_CACHE_MAX_ITEMS")
+                .unwrap_or_else(|_| -> String { "10000".into() })
+                .parse()
+                .expect(
+                    "Invalid value provided for `HOT_CACHE_MAX_ITEMS`; must be an unsigned integer",
+                ),
+            hot_cache_ttl: std::time::Duration::from_secs(
+                env::var("HOT_CACHE_TTL_SECONDS")
+                    .unwrap_or_else(|_| -> String { "300".into() })
+                    .parse()
+                    .expect(
+                        "Invalid value provided for `HOT_CACHE_TTL_SECONDS`; must be an unsigned \
+                         integer",
+                    ),
+            ),
+            database_url: env::var("DATABASE_URL").ok(),
             artists_cache_hash_name: "artists".into(),
             tracks_cache_hash_name: "tracks".into(),
+            audio_features_cache_hash_name: "audio_features".into(),
+            entity_cache_ttl: match env::var("ENTITY_CACHE_TTL_SECONDS")
+                .unwrap_or_else(|_| -> String { (60 * 60 * 24 * 7).to_string() })
+                .parse()
+            {
+                Ok(0) => None,
+                Ok(secs) if secs <= MAX_AGE_SECONDS => Some(std::time::Duration::from_secs(secs)),
+                _ => panic!(
+                    "Invalid value provided for `ENTITY_CACHE_TTL_SECONDS`; must be an integer \
+                     between 0 and {}",
+                    MAX_AGE_SECONDS
+                ),
+            },
             min_update_interval: Duration::seconds(
                 env::var("MIN_UPDATE_INTERVAL_SECONDS")
                     .unwrap_or_else(|_| -> String { (60 * 60 * 6).to_string() })
@@ -45,10 +255,375 @@ impl Conf {
             ),
             admin_api_token: env::var("ADMIN_API_TOKEN")
                 .expect("The `ADMIN_API_TOKEN` environment variable must be set"),
+            token_encryption_key: env::var("TOKEN_ENCRYPTION_KEY")
+                .ok()
+                .filter(|key| !key.is_empty())
+                .map(|key| {
+                    base64::Engine::decode(&base64::engine::general_purpose::STANDARD, key.trim())
+                        .ok()
+                        .and_then(|key| key.try_into().ok())
+                        .expect(
+                            "Invalid value provided for `TOKEN_ENCRYPTION_KEY`; must be 32 \
+                             base64-encoded bytes",
+                        )
+                }),
+            session_key: base64::Engine::decode(
+                &base64::engine::general_purpose::STANDARD,
+                env::var("SESSION_SECRET")
+                    .expect("The `SESSION_SECRET` environment variable must be set")
+                    .trim(),
+            )
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .expect("Invalid value provided for `SESSION_SECRET`; must be 32 base64-encoded bytes"),
+            session_ttl: Duration::days(
+                env::var("SESSION_TTL_DAYS")
+                    .unwrap_or_else(|_| -> String { "30".to_string() })
+                    .parse()
+                    .expect(
+                        "Invalid value provided for `SESSION_TTL_DAYS`; must be an unsigned \
+                         integer",
+                    ),
+            ),
+            scheduler_enabled: env::var("SCHEDULER_ENABLED")
+                .map(|val| val == "1" || val.eq_ignore_ascii_case("true"))
+                .unwrap_or(true),
+            scheduler_concurrency: env::var("SCHEDULER_CONCURRENCY")
+                .unwrap_or_else(|_| -> String { "2".to_string() })
+                .parse()
+                .expect(
+                    "Invalid value provided for `SCHEDULER_CONCURRENCY`; must be an unsigned \
+                     integer",
+                ),
+            scheduler_poll_interval: std::time::Duration::from_secs(
+                env::var("SCHEDULER_POLL_INTERVAL_SECONDS")
+                    .unwrap_or_else(|_| -> String { "60".to_string() })
+                    .parse()
+                    .expect(
+                        "Invalid value provided for `SCHEDULER_POLL_INTERVAL_SECONDS`; must be an \
+                         unsigned integer",
+                    ),
+            ),
+            scheduler_dry_run: env::var("SCHEDULER_DRY_RUN")
+                .map(|val| val == "1" || val.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+            max_consecutive_update_failures: env::var("MAX_CONSECUTIVE_UPDATE_FAILURES")
+                .unwrap_or_else(|_| -> String { "5".to_string() })
+                .parse()
+                .expect(
+                    "Invalid value provided for `MAX_CONSECUTIVE_UPDATE_FAILURES`; must be an \
+                     integer",
+                ),
+            active_tier_max_view_age: Duration::days(
+                env::var("ACTIVE_TIER_MAX_VIEW_AGE_DAYS")
+                    .unwrap_or_else(|_| -> String { "7".to_string() })
+                    .parse()
+                    .expect(
+                        "Invalid value provided for `ACTIVE_TIER_MAX_VIEW_AGE_DAYS`; must be an \
+                         unsigned integer",
+                    ),
+            ),
+            active_tier_min_views: env::var("ACTIVE_TIER_MIN_VIEWS")
+                .unwrap_or_else(|_| -> String { "3".to_string() })
+                .parse()
+                .expect(
+                    "Invalid value provided for `ACTIVE_TIER_MIN_VIEWS`; must be an unsigned \
+                     integer",
+                ),
+            dormant_tier_min_view_age: Duration::days(
+                env::var("DORMANT_TIER_MIN_VIEW_AGE_DAYS")
+                    .unwrap_or_else(|_| -> String { "60".to_string() })
+                    .parse()
+                    .expect(
+                        "Invalid value provided for `DORMANT_TIER_MIN_VIEW_AGE_DAYS`; must be an \
+                         unsigned integer",
+                    ),
+            ),
+            active_tier_update_interval: env::var("ACTIVE_TIER_UPDATE_INTERVAL_SECONDS").ok().map(
+                |secs| {
+                    Duration::seconds(secs.parse().expect(
+                        "Invalid value provided for `ACTIVE_TIER_UPDATE_INTERVAL_SECONDS`; must \
+                         be an unsigned integer",
+                    ))
+                },
+            ),
+            normal_tier_update_interval: Duration::seconds(
+                env::var("NORMAL_TIER_UPDATE_INTERVAL_SECONDS")
+                    .unwrap_or_else(|_| -> String { (60 * 60 * 12).to_string() })
+                    .parse()
+                    .expect(
+                        "Invalid value provided for `NORMAL_TIER_UPDATE_INTERVAL_SECONDS`; must \
+                         be an unsigned integer",
+                    ),
+            ),
+            dormant_tier_update_interval: Duration::seconds(
+                env::var("DORMANT_TIER_UPDATE_INTERVAL_SECONDS")
+                    .unwrap_or_else(|_| -> String { (60 * 60 * 24 * 7).to_string() })
+                    .parse()
+                    .expect(
+                        "Invalid value provided for `DORMANT_TIER_UPDATE_INTERVAL_SECONDS`; must \
+                         be an unsigned integer",
+                    ),
+            ),
+            update_tier_refresh_interval: std::time::Duration::from_secs(
+                env::var("UPDATE_TIER_REFRESH_INTERVAL_SECONDS")
+                    .unwrap_or_else(|_| -> String { (60 * 60).to_string() })
+                    .parse()
+                    .expect(
+                        "Invalid value provided for `UPDATE_TIER_REFRESH_INTERVAL_SECONDS`; must \
+                         be an unsigned integer",
+                    ),
+            ),
+            onboarding_period: Duration::days(
+                env::var("ONBOARDING_PERIOD_DAYS")
+                    .unwrap_or_else(|_| -> String { "7".to_string() })
+                    .parse()
+                    .expect(
+                        "Invalid value provided for `ONBOARDING_PERIOD_DAYS`; must be an unsigned \
+                         integer",
+                    ),
+            ),
+            onboarding_poll_interval: std::time::Duration::from_secs(
+                env::var("ONBOARDING_POLL_INTERVAL_SECONDS")
+                    .unwrap_or_else(|_| -> String { (60 * 60).to_string() })
+                    .parse()
+                    .expect(
+                        "Invalid value provided for `ONBOARDING_POLL_INTERVAL_SECONDS`; must be \
+                         an unsigned integer",
+                    ),
+            ),
+            global_charts_refresh_interval: std::time::Duration::from_secs(
+                env::var("GLOBAL_CHARTS_REFRESH_INTERVAL_SECONDS")
+                    .unwrap_or_else(|_| -> String { (60 * 60 * 6).to_string() })
+                    .parse()
+                    .expect(
+                        "Invalid value provided for `GLOBAL_CHARTS_REFRESH_INTERVAL_SECONDS`; \
+                         must be an unsigned integer",
+                    ),
+            ),
+            system_stats_refresh_interval: std::time::Duration::from_secs(
+                env::var("SYSTEM_STATS_REFRESH_INTERVAL_SECONDS")
+                    .unwrap_or_else(|_| -> String { (60 * 60 * 24).to_string() })
+                    .parse()
+                    .expect(
+                        "Invalid value provided for `SYSTEM_STATS_REFRESH_INTERVAL_SECONDS`; must \
+                         be an unsigned integer",
+                    ),
+            ),
             telemetry_server_port: env::var("TELEMETRY_SERVER_PORT")
                 .unwrap_or_else(|_| -> String { "4101".to_string() })
                 .parse()
                 .expect("Invalid value provided for `TELEMETRY_SERVER_PORT`; must be a u16"),
+            db_pool_size: env::var("DB_POOL_SIZE").ok().map(|pool_size| {
+                pool_size
+                    .parse()
+                    .expect("Invalid value provided for `DB_POOL_SIZE`; must be a u32")
+            }),
+            retention_keep_all_for: Duration::days(
+                env::var("RETENTION_KEEP_ALL_DAYS")
+                    .unwrap_or_else(|_| -> String { "30".to_string() })
+                    .parse()
+                    .expect(
+                        "Invalid value provided for `RETENTION_KEEP_ALL_DAYS`; must be an \
+                         unsigned integer",
+                    ),
+            ),
+            retention_keep_weekly_for: Duration::days(
+                env::var("RETENTION_KEEP_WEEKLY_DAYS")
+                    .unwrap_or_else(|_| -> String { "365".to_string() })
+                    .parse()
+                    .expect(
+                        "Invalid value provided for `RETENTION_KEEP_WEEKLY_DAYS`; must be an \
+                         unsigned integer",
+                    ),
+            ),
+            snapshot_delta_encoding: env::var("SNAPSHOT_DELTA_ENCODING")
+                .map(|val| val == "1" || val.eq_ignore_ascii_case("true"))
+                .unwrap_or(true),
+            entity_fetch_count: match env::var("ENTITY_FETCH_COUNT")
+                .unwrap_or_else(|_| -> String { MAX_ENTITY_FETCH_COUNT.to_string() })
+                .parse()
+            {
+                Ok(count) if (1..=MAX_TOP_ENTITY_COUNT).contains(&count) => count,
+                _ => panic!(
+                    "Invalid value provided for `ENTITY_FETCH_COUNT`; must be an integer between \
+                     1 and {}",
+                    MAX_TOP_ENTITY_COUNT
+                ),
+            },
+            spotify_api_max_attempts: env::var("SPOTIFY_API_MAX_ATTEMPTS")
+                .unwrap_or_else(|_| -> String { "8".to_string() })
+                .parse()
+                .expect(
+                    "Invalid value provided for `SPOTIFY_API_MAX_ATTEMPTS`; must be an unsigned \
+                     integer",
+                ),
+            spotify_features: match env::var("SPOTIFY_FEATURES") {
+                Ok(features) => oauth_scopes::parse_features(&features).unwrap_or_else(|err| {
+                    panic!(
+                        "Invalid value provided for `SPOTIFY_FEATURES`: {}; must be a \
+                         comma-separated list of `recently_played`, `follows`, `library`, \
+                         `playlists`, and `now_playing`",
+                        err
+                    )
+                }),
+                Err(_) => SpotifyFeature::ALL.to_vec(),
+            },
+            public_stats_fields: match env::var("PUBLIC_STATS_FIELDS") {
+                Ok(fields) => public_stats::parse_fields(&fields).unwrap_or_else(|err| {
+                    panic!(
+                        "Invalid value provided for `PUBLIC_STATS_FIELDS`: {}; must be a \
+                         comma-separated list of `user_count`, `snapshot_count`, \
+                         `most_tracked_artist`, and `median_diversity`",
+                        err
+                    )
+                }),
+                Err(_) => PublicStatsField::ALL.to_vec(),
+            },
+            rate_limit_ip_burst: env::var("RATE_LIMIT_IP_BURST")
+                .unwrap_or_else(|_| -> String { "60".to_string() })
+                .parse()
+                .expect("Invalid value provided for `RATE_LIMIT_IP_BURST`; must be a u32"),
+            rate_limit_ip_per_minute: env::var("RATE_LIMIT_IP_PER_MINUTE")
+                .unwrap_or_else(|_| -> String { "30".to_string() })
+                .parse()
+                .expect("Invalid value provided for `RATE_LIMIT_IP_PER_MINUTE`; must be a u32"),
+            rate_limit_username_burst: env::var("RATE_LIMIT_USERNAME_BURST")
+                .unwrap_or_else(|_| -> String { "120".to_string() })
+                .parse()
+                .expect("Invalid value provided for `RATE_LIMIT_USERNAME_BURST`; must be a u32"),
+            rate_limit_username_per_minute: env::var("RATE_LIMIT_USERNAME_PER_MINUTE")
+                .unwrap_or_else(|_| -> String { "60".to_string() })
+                .parse()
+                .expect(
+                    "Invalid value provided for `RATE_LIMIT_USERNAME_PER_MINUTE`; must be a u32",
+                ),
+            api_key_default_rate_limit_per_minute: env::var(
+                "API_KEY_DEFAULT_RATE_LIMIT_PER_MINUTE",
+            )
+            .unwrap_or_else(|_| -> String { "120".to_string() })
+            .parse()
+            .expect(
+                "Invalid value provided for `API_KEY_DEFAULT_RATE_LIMIT_PER_MINUTE`; must be a u32",
+            ),
+            slow_request_threshold: match env::var("SLOW_REQUEST_THRESHOLD_MS")
+                .unwrap_or_else(|_| -> String { "1000".to_string() })
+                .parse()
+                .expect("Invalid value provided for `SLOW_REQUEST_THRESHOLD_MS`; must be a u64")
+            {
+                0 => None,
+                millis => Some(std::time::Duration::from_millis(millis)),
+            },
+            compression_min_size: match env::var("COMPRESSION_MIN_SIZE_BYTES")
+                .unwrap_or_else(|_| -> String { "1024".to_string() })
+                .parse()
+                .expect("Invalid value provided for `COMPRESSION_MIN_SIZE_BYTES`; must be a usize")
+            {
+                0 => None,
+                min_size => Some(min_size),
+            },
+            digest_smtp: env::var("DIGEST_SMTP_URL")
+                .ok()
+                .filter(|url| !url.is_empty())
+                .map(|url| crate::digest::DigestSmtpConf {
+                    url,
+                    from: env::var("DIGEST_EMAIL_FROM").expect(
+                        "The `DIGEST_EMAIL_FROM` environment variable must be set if \
+                         `DIGEST_SMTP_URL` is set",
+                    ),
+                }),
+            digest_check_interval: std::time::Duration::from_secs(
+                env::var("DIGEST_CHECK_INTERVAL_SECONDS")
+                    .unwrap_or_else(|_| -> String { (60 * 60).to_string() })
+                    .parse()
+                    .expect(
+                        "Invalid value provided for `DIGEST_CHECK_INTERVAL_SECONDS`; must be an \
+                         unsigned integer",
+                    ),
+            ),
+            metadata_refresh_max_age: match env::var("METADATA_REFRESH_MAX_AGE_SECONDS")
+                .unwrap_or_else(|_| -> String { (60 * 60 * 24 * 14).to_string() })
+                .parse()
+            {
+                Ok(0) => None,
+                Ok(secs) if secs <= MAX_AGE_SECONDS => Some(std::time::Duration::from_secs(secs)),
+                _ => panic!(
+                    "Invalid value provided for `METADATA_REFRESH_MAX_AGE_SECONDS`; must be an \
+                     integer between 0 and {}",
+                    MAX_AGE_SECONDS
+                ),
+            },
+            metadata_refresh_interval: std::time::Duration::from_secs(
+                env::var("METADATA_REFRESH_INTERVAL_SECONDS")
+                    .unwrap_or_else(|_| -> String { (60 * 60).to_string() })
+                    .parse()
+                    .expect(
+                        "Invalid value provided for `METADATA_REFRESH_INTERVAL_SECONDS`; must be \
+                         an unsigned integer",
+                    ),
+            ),
+            metadata_refresh_max_batches: env::var("METADATA_REFRESH_MAX_BATCHES")
+                .unwrap_or_else(|_| -> String { "20".into() })
+                .parse()
+                .expect(
+                    "Invalid value provided for `METADATA_REFRESH_MAX_BATCHES`; must be an \
+                     unsigned integer",
+                ),
+            alert_webhook_url: env::var("ALERT_WEBHOOK_URL")
+                .ok()
+                .filter(|url| !url.is_empty()),
+            alert_smtp: env::var("ALERT_SMTP_URL")
+                .ok()
+                .filter(|url| !url.is_empty())
+                .map(|url| crate::alerts::SmtpConf {
+                    url,
+                    from: env::var("ALERT_EMAIL_FROM").expect(
+                        "The `ALERT_EMAIL_FROM` environment variable must be set if \
+                         `ALERT_SMTP_URL` is set",
+                    ),
+                    to: env::var("ALERT_EMAIL_TO").expect(
+                        "The `ALERT_EMAIL_TO` environment variable must be set if \
+                         `ALERT_SMTP_URL` is set",
+                    ),
+                }),
+            alert_failure_threshold: env::var("ALERT_FAILURE_THRESHOLD")
+                .unwrap_or_else(|_| -> String { "3".to_string() })
+                .parse()
+                .expect("Invalid value provided for `ALERT_FAILURE_THRESHOLD`; must be a u32"),
+            alert_update_lag_threshold: match env::var("ALERT_UPDATE_LAG_SECONDS")
+                .unwrap_or_else(|_| -> String { (60 * 60 * 24).to_string() })
+                .parse()
+                .expect("Invalid value provided for `ALERT_UPDATE_LAG_SECONDS`; must be a u64")
+            {
+                0 => None,
+                secs => Some(std::time::Duration::from_secs(secs)),
+            },
+            alert_cooldown: std::time::Duration::from_secs(
+                env::var("ALERT_COOLDOWN_SECONDS")
+                    .unwrap_or_else(|_| -> String { (60 * 60).to_string() })
+                    .parse()
+                    .expect(
+                        "Invalid value provided for `ALERT_COOLDOWN_SECONDS`; must be an unsigned \
+                         integer",
+                    ),
+            ),
+            outbox_poll_interval: std::time::Duration::from_secs(
+                env::var("OUTBOX_POLL_INTERVAL_SECONDS")
+                    .unwrap_or_else(|_| -> String { "30".into() })
+                    .parse()
+                    .expect(
+                        "Invalid value provided for `OUTBOX_POLL_INTERVAL_SECONDS`; must be an \
+                         unsigned integer",
+                    ),
+            ),
+            outbox_max_attempts: env::var("OUTBOX_MAX_ATTEMPTS")
+                .unwrap_or_else(|_| -> String { "10".into() })
+                .parse()
+                .ok()
+                .filter(|&max_attempts: &i32| max_attempts > 0)
+                .expect(
+                    "Invalid value provided for `OUTBOX_MAX_ATTEMPTS`; must be a positive integer",
+                ),
         }
     }
 
@@ -56,6 +631,20 @@ impl Conf {
         format!("{}/oauth_cb", CONF.api_server_url)
     }
 
+    pub(crate) fn is_feature_enabled(&self, feature: SpotifyFeature) -> bool {
+        self.spotify_features.contains(&feature)
+    }
+
+    pub(crate) fn is_public_stats_field_enabled(&self, field: PublicStatsField) -> bool {
+        self.public_stats_fields.contains(&field)
+    }
+
+    /// Returns the OAuth scopes to request from users when they authorize the application,
+    /// including those of opt-in features like playlist generation if `include_opt_in` is set
+    pub(crate) fn get_oauth_scopes(&self, include_opt_in: bool) -> Vec<&'static str> {
+        oauth_scopes::build_scopes(&self.spotify_features, include_opt_in)
+    }
+
     pub(crate) fn get_authorization_header_content(&self) -> String {
         format!(
             "Basic {}",
@@ -67,6 +656,33 @@ impl Conf {
     }
 }
 
+/// Parses a comma-separated list of names out of `all`, as used by settings that enable a subset of
+/// some features.  Whitespace around names is ignored and duplicates are dropped.  `kind` describes
+/// the values in the error returned for unknown names.
+pub(crate) fn parse_name_list<T: Copy + PartialEq>(
+    list: &str,
+    all: &[T],
+    name: fn(T) -> &'static str,
+    kind: &str,
+) -> Result<Vec<T>, Error> {
+    let mut parsed = Vec::new();
+    for value_name in list
+        .split(',')
+        .map(str::trim)
+        .filter(|value_name| !value_name.is_empty())
+    {
+        let value = all
+            .iter()
+            .copied()
+            .find(|value| name(*value) == value_name)
+            .ok_or_else(|| Error::BadRequest(format!("Unknown {} \"{}\"", kind, value_name)))?;
+        if !parsed.contains(&value) {
+            parsed.push(value);
+        }
+    }
+    Ok(parsed)
+}
+
 lazy_static::lazy_static! {
     pub(crate) static ref CONF: Conf = Conf::build_from_env();
 }
@@ -1,28 +1,78 @@
 use rocket::{
     fairing::{Fairing, Info, Kind},
-    http::{Method, Status},
+    http::{Header, Method, Status},
     Request, Response,
 };
 
+use crate::conf::CONF;
+
+const ALLOWED_METHODS: &str = "GET, POST, PUT, PATCH, DELETE, OPTIONS";
+const ALLOWED_HEADERS: &str =
+    "sentry-trace, Authorization, X-Private-Token, Content-Type, If-None-Match, If-Modified-Since";
+/// Headers set by the API that the frontend needs to be able to read
+const EXPOSED_HEADERS: &str = "ETag, Last-Modified, Retry-After";
+/// How long browsers can cache the result of preflight requests
+const PREFLIGHT_MAX_AGE_SECONDS: &str = "86400";
+
 pub(crate) struct CorsFairing;
 
+/// Returns the value to send as `Access-Control-Allow-Origin` for a request from `origin`, if
+/// it's allowed
+fn get_allowed_origin<'a>(allowed_origins: &[String], origin: &'a str) -> Option<&'a str> {
+    if allowed_origins.iter().any(|allowed| allowed == "*") {
+        Some("*")
+    } else if allowed_origins.iter().any(|allowed| allowed == origin) {
+        Some(origin)
+    } else {
+        None
+    }
+}
+
 #[rocket::async_trait]
 impl Fairing for CorsFairing {
     async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
-        // Add CORS headers to allow all origins to all outgoing requests
-        res.set_header(rocket::http::Header::new(
-            "Access-Control-Allow-Origin",
-            "*",
-        ));
-        res.set_header(rocket::http::Header::new(
-            "Access-Control-Allow-Headers",
-            "sentry-trace",
-        ));
+        let is_preflight = req.method() == Method::Options
+            && req.headers().contains("Access-Control-Request-Method");
 
-        // Respond to all `OPTIONS` requests with a `204` (no content) status
+        // Respond to all `OPTIONS` requests with a `204` (no content) status since none of our
+        // routes handle them
         if res.status() == Status::NotFound && req.method() == Method::Options {
             res.set_status(Status::NoContent);
         }
+
+        let allowed_origin = match req
+            .headers()
+            .get_one("Origin")
+            .and_then(|origin| get_allowed_origin(&CONF.allowed_origins, origin))
+        {
+            Some(allowed_origin) => allowed_origin,
+            None => return,
+        };
+        res.set_header(Header::new(
+            "Access-Control-Allow-Origin",
+            allowed_origin.to_string(),
+        ));
+        if allowed_origin != "*" {
+            // The response differs depending on the requesting origin, so caches must not serve
+            // it to other origins
+            res.adjoin_header(Header::new("Vary", "Origin"));
+            // Allows the website to make requests with the user's session cookie
+            res.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+        }
+
+        if is_preflight {
+            res.set_header(Header::new("Access-Control-Allow-Methods", ALLOWED_METHODS));
+            res.set_header(Header::new("Access-Control-Allow-Headers", ALLOWED_HEADERS));
+            res.set_header(Header::new(
+                "Access-Control-Max-Age",
+                PREFLIGHT_MAX_AGE_SECONDS,
+            ));
+        } else {
+            res.set_header(Header::new(
+                "Access-Control-Expose-Headers",
+                EXPOSED_HEADERS,
+            ));
+        }
     }
 
     fn info(&self) -> Info {
@@ -32,3 +82,23 @@ impl Fairing for CorsFairing {
         }
     }
 }
+
+#[test]
+fn cors_allowed_origins() {
+    let allowed_origins = vec![
+        "https://spotifytrack.net".to_string(),
+        "http://localhost:9050".to_string(),
+    ];
+    assert_eq!(
+        get_allowed_origin(&allowed_origins, "http://localhost:9050"),
+        Some("http://localhost:9050")
+    );
+    assert_eq!(
+        get_allowed_origin(&allowed_origins, "https://evil.example"),
+        None
+    );
+    assert_eq!(
+        get_allowed_origin(&["*".to_string()], "https://evil.example"),
+        Some("*")
+    );
+}
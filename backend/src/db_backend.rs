@@ -0,0 +1,242 @@
+//! Abstracts over the database backend, which is selected with either the `mysql` (default) or
+//! `postgres` Cargo feature.  Queries are written for MySQL, and the few places where the SQL
+//! understood by the two backends differs are handled here so that self-hosted instances can run
+//! on Postgres instead.
+//!
+//! Postgres doesn't have unsigned integer types, so `Unsigned<T>` columns are stored in the next
+//! larger signed type there and converted when reading and writing them.
+
+use std::borrow::Cow;
+
+use diesel::expression::bound::Bound;
+
+#[cfg(all(feature = "mysql", feature = "postgres"))]
+compile_error!("Only one of the `mysql` and `postgres` features can be enabled");
+#[cfg(not(any(feature = "mysql", feature = "postgres")))]
+compile_error!("One of the `mysql` or `postgres` features must be enabled");
+
+#[cfg(feature = "mysql")]
+pub type DbBackend = diesel::mysql::Mysql;
+#[cfg(feature = "mysql")]
+pub type DbConnection = diesel::MysqlConnection;
+
+#[cfg(feature = "postgres")]
+pub type DbBackend = diesel::pg::Pg;
+#[cfg(feature = "postgres")]
+pub type DbConnection = diesel::PgConnection;
+
+/// SQL types used in `schema.rs` and raw queries.  This is `diesel::sql_types` plus the
+/// MySQL-specific types for backends that don't provide them.
+pub(crate) mod sql_types {
+    pub use diesel::sql_types::*;
+
+    #[cfg(feature = "postgres")]
+    pub use super::pg_types::{Datetime, Unsigned};
+}
+
+#[cfg(feature = "postgres")]
+mod pg_types {
+    use std::io::Write;
+
+    use diesel::{
+        deserialize::{self, FromSql},
+        pg::{Pg, PgMetadataLookup, PgTypeMetadata},
+        serialize::{self, Output, ToSql},
+        sql_types::{BigInt, HasSqlType, Integer, SmallInt, SqlOrd, TinyInt},
+    };
+
+    /// Stored as `TIMESTAMP`, which holds the same values as MySQL's `DATETIME`
+    pub type Datetime = diesel::sql_types::Timestamp;
+
+    /// Unsigned version of the wrapped integer type, stored in the next larger signed type
+    #[derive(Debug, Clone, Copy, Default, SqlType, QueryId)]
+    pub struct Unsigned<ST>(ST);
+
+    macro_rules! unsigned_types {
+        ($($sql_type:ident($rust_type:ty) => $signed_sql_type:ident($signed_rust_type:ty),)+) => {$(
+            impl HasSqlType<Unsigned<$sql_type>> for Pg {
+                fn metadata(lookup: &PgMetadataLookup) -> PgTypeMetadata {
+                    <Pg as HasSqlType<$signed_sql_type>>::metadata(lookup)
+                }
+            }
+
+            impl SqlOrd for Unsigned<$sql_type> {}
+
+            impl ToSql<Unsigned<$sql_type>, Pg> for $rust_type {
+                fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+                    ToSql::<$signed_sql_type, Pg>::to_sql(&(*self as $signed_rust_type), out)
+                }
+            }
+
+            impl FromSql<Unsigned<$sql_type>, Pg> for $rust_type {
+                fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+                    let signed: $signed_rust_type =
+                        FromSql::<$signed_sql_type, Pg>::from_sql(bytes)?;
+                    Ok(signed as $rust_type)
+                }
+            }
+        )+};
+    }
+
+    unsigned_types! {
+        TinyInt(u8) => SmallInt(i16),
+        SmallInt(u16) => Integer(i32),
+        Integer(u32) => BigInt(i64),
+        // Values past `i64::MAX` wrap, but nothing stored comes close to that
+        BigInt(u64) => BigInt(i64),
+    }
+}
+
+/// Builds an insert statement that skips any rows conflicting with existing rows
+#[cfg(feature = "mysql")]
+macro_rules! insert_or_ignore {
+    ($table:expr, $values:expr) => {
+        diesel::insert_or_ignore_into($table).values($values)
+    };
+}
+
+#[cfg(feature = "postgres")]
+macro_rules! insert_or_ignore {
+    ($table:expr, $values:expr) => {
+        diesel::insert_into($table)
+            .values($values)
+            .on_conflict_do_nothing()
+    };
+}
+
+/// Builds an insert statement that overwrites the listed columns of any existing rows that conflict
+/// on `$conflict_target`, which must match a unique index.  MySQL replaces conflicting rows
+/// entirely, so the listed columns should include every column not in `$conflict_target`.
+#[cfg(feature = "mysql")]
+macro_rules! upsert {
+    ($table:expr, $values:expr, $conflict_target:expr, ($($column:expr),+ $(,)?)) => {
+        diesel::replace_into($table).values($values)
+    };
+}
+
+#[cfg(feature = "postgres")]
+macro_rules! upsert {
+    ($table:expr, $values:expr, $conflict_target:expr, ($($column:expr),+ $(,)?)) => {
+        diesel::insert_into($table)
+            .values($values)
+            .on_conflict($conflict_target)
+            .do_update()
+            .set(($(
+                diesel::ExpressionMethods::eq($column, diesel::pg::upsert::excluded($column)),
+            )+))
+    };
+}
+
+/// Implements `Insertable` like `#[derive(Insertable)]` does, but binds every field with the SQL
+/// type of its column.  Diesel can only bind unsigned integers to MySQL's unsigned types, so this
+/// is used in place of the derive for structs with unsigned fields.
+macro_rules! impl_insertable {
+    ($struct:ty => $table:ident { $($field:ident: $field_ty:ty),+ $(,)? }) => {
+        impl diesel::Insertable<$table::table> for $struct {
+            type Values = <($(
+                Option<diesel::dsl::Eq<
+                    $table::$field,
+                    diesel::expression::bound::Bound<
+                        <$table::$field as diesel::Expression>::SqlType,
+                        $field_ty,
+                    >,
+                >>,
+            )+) as diesel::Insertable<$table::table>>::Values;
+
+            fn values(self) -> Self::Values {
+                diesel::Insertable::values(($(
+                    Some(diesel::ExpressionMethods::eq(
+                        $table::$field,
+                        $crate::db_backend::bind(self.$field),
+                    )),
+                )+))
+            }
+        }
+
+        impl<'insert> diesel::Insertable<$table::table> for &'insert $struct {
+            type Values = <($(
+                Option<diesel::dsl::Eq<
+                    $table::$field,
+                    diesel::expression::bound::Bound<
+                        <$table::$field as diesel::Expression>::SqlType,
+                        &'insert $field_ty,
+                    >,
+                >>,
+            )+) as diesel::Insertable<$table::table>>::Values;
+
+            fn values(self) -> Self::Values {
+                diesel::Insertable::values(($(
+                    Some(diesel::ExpressionMethods::eq(
+                        $table::$field,
+                        $crate::db_backend::bind(&self.$field),
+                    )),
+                )+))
+            }
+        }
+
+        impl diesel::query_builder::UndecoratedInsertRecord<$table::table> for $struct {}
+    };
+}
+
+pub(crate) use impl_insertable;
+pub(crate) use insert_or_ignore;
+pub(crate) use upsert;
+
+/// Binds a value with the SQL type it's compared to or inserted into.  This is needed for unsigned
+/// integers, which Diesel can't convert to expressions on its own for every backend.
+pub(crate) fn bind<ST, T>(value: T) -> Bound<ST, T> { Bound::new(value) }
+
+/// `LIKE` is already case-insensitive with MySQL's default collations
+#[cfg(feature = "mysql")]
+pub(crate) const CASE_INSENSITIVE_LIKE: &str = "LIKE";
+#[cfg(feature = "postgres")]
+pub(crate) const CASE_INSENSITIVE_LIKE: &str = "ILIKE";
+
+/// Type to `CAST` to for a 64-bit signed integer
+#[cfg(feature = "mysql")]
+pub(crate) const SIGNED_BIGINT: &str = "SIGNED";
+#[cfg(feature = "postgres")]
+pub(crate) const SIGNED_BIGINT: &str = "BIGINT";
+
+/// Converts a raw query written with MySQL's backtick-quoted identifiers and `?` placeholders to
+/// the syntax of the selected backend.
+pub(crate) fn portable_sql(query: &str) -> Cow<'_, str> {
+    if cfg!(feature = "postgres") {
+        Cow::Owned(to_postgres_sql(query))
+    } else {
+        Cow::Borrowed(query)
+    }
+}
+
+fn to_postgres_sql(query: &str) -> String {
+    let mut converted = String::with_capacity(query.len());
+    let mut in_string = false;
+    let mut placeholder_count = 0;
+    for c in query.chars() {
+        match c {
+            '\'' => {
+                in_string = !in_string;
+                converted.push(c);
+            },
+            '`' if !in_string => converted.push('"'),
+            '?' if !in_string => {
+                placeholder_count += 1;
+                converted.push_str(&format!("${}", placeholder_count));
+            },
+            _ => converted.push(c),
+        }
+    }
+    converted
+}
+
+#[test]
+fn postgres_sql_conversion() {
+    assert_eq!(
+        to_postgres_sql("SELECT `id` FROM `users` WHERE `spotify_id` = ? AND `id` > ? LIMIT ?"),
+        r#"SELECT "id" FROM "users" WHERE "spotify_id" = $1 AND "id" > $2 LIMIT $3"#
+    );
+    assert_eq!(
+        to_postgres_sql("SELECT '`?' AS `a`, ? AS `b`"),
+        r#"SELECT '`?' AS "a", $1 AS "b""#
+    );
+}
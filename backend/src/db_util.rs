@@ -1,32 +1,39 @@
-use std::fmt::Debug;
+use std::{collections::BTreeMap, fmt::Debug};
 
 use chrono::{NaiveDateTime, Utc};
-use diesel::{
-    mysql::{Mysql, MysqlConnection},
-    prelude::*,
-    query_builder::{QueryFragment, QueryId},
-    query_dsl::LoadQuery,
-};
+use diesel::prelude::*;
 use fnv::{FnvHashMap as HashMap, FnvHashSet as HashSet};
 use futures::Future;
-use rocket::{http::Status, response::status};
 use serde::Serialize;
 
 use crate::{
     benchmarking::{mark, start},
     cache::local_cache::{cache_id_entries, get_cached_internal_ids_by_spotify_id},
+    db_backend::{
+        bind, insert_or_ignore, portable_sql, upsert, DbBackend, DbConnection,
+        CASE_INSENSITIVE_LIKE, SIGNED_BIGINT,
+    },
+    error::Error,
+    export::ExportEntity,
     models::{
-        Artist, ArtistGenrePair, ArtistRankHistoryResItem, HasSpotifyId, NewRelatedArtistEntry,
-        NewSpotifyIdMapping, SpotifyIdMapping, StatsHistoryQueryResItem, TimeFrames, Track,
-        TrackArtistPair, User,
+        update_retry_delay, AdminUserListItem, ApiKeyRow, Artist, ArtistGenrePair,
+        DiversityScoreEntry, EmailSubscription, HasSpotifyId, LatestStatsEntry, LinkedAccount,
+        NewApiKey, NewArtistHistoryEntry, NewEmailSubscription, NewFollowedArtistEntry,
+        NewFriendship, NewGlobalChartEntry, NewLibrarySnapshotEntry, NewLinkedAccount,
+        NewRelatedArtistEntry, NewSavedTrackEntry, NewSpotifyIdMapping, NewSystemStats,
+        NewTrackHistoryEntry, NewUpdateError, OutboxEventRow, SnapshotUpdate, SpotifyIdMapping,
+        StatsHistoryQueryResItem, StatsSnapshot, StoredToken, SystemStatsEntry, TimeFrames,
+        Timeframe, Track, TrackArtistPair, UpdateError, UpdateFrequency, UpdateTier, User,
+        UserDeletionSummary, UserHistoryEntry, UserSettingsEntry,
     },
+    pagination::{CursorPagination, Paginated},
     DbConn,
 };
 
 pub(crate) async fn get_user_by_spotify_id(
     conn: &DbConn,
     supplied_spotify_id: String,
-) -> Result<Option<User>, String> {
+) -> Result<Option<User>, Error> {
     use crate::schema::users::dsl::*;
 
     conn.run(move |conn| {
@@ -39,34 +46,165 @@ pub(crate) async fn get_user_by_spotify_id(
     .await
 }
 
-pub(crate) fn stringify_diesel_err(err: diesel::result::Error) -> String {
-    error!("Error querying database: {:?}", err);
-    String::from("Error querying database")
+/// Looks up a user by vanity slug, falling back to looking them up by Spotify ID
+pub(crate) async fn get_user_by_vanity_slug_or_spotify_id(
+    conn: &DbConn,
+    identifier: String,
+) -> Result<Option<User>, Error> {
+    use crate::schema::users::dsl::*;
+
+    let identifier_clone = identifier.clone();
+    let mut matches: Vec<User> = conn
+        .run(move |conn| {
+            users
+                .filter(
+                    vanity_slug
+                        .eq(&identifier_clone)
+                        .or(spotify_id.eq(&identifier_clone)),
+                )
+                .load::<User>(conn)
+        })
+        .await?;
+    // A slug can only equal another user's Spotify ID if that user signed up after the slug was
+    // chosen, in which case the slug wins since it's what the slug's owner links to
+    let slug_match_ix = matches.iter().position(|user| {
+        user.vanity_slug
+            .as_deref()
+            .map(|slug| slug.eq_ignore_ascii_case(&identifier))
+            .unwrap_or(false)
+    });
+    Ok(match slug_match_ix {
+        Some(ix) => Some(matches.swap_remove(ix)),
+        None => matches.pop(),
+    })
 }
 
 pub(crate) fn diesel_not_found_to_none<T>(
     res: Result<T, diesel::result::Error>,
-) -> Result<Option<T>, String> {
+) -> Result<Option<T>, Error> {
     match res {
         Err(diesel::result::Error::NotFound) => Ok(None),
-        Err(err) => Err(stringify_diesel_err(err)),
+        Err(err) => Err(Error::Database(err)),
         Ok(res) => Ok(Some(res)),
     }
 }
 
+#[derive(QueryableByName)]
+struct CountQueryResItem {
+    #[sql_type = "diesel::sql_types::BigInt"]
+    count: i64,
+}
+
 #[derive(Queryable)]
 struct StatsQueryResultItem {
-    timeframe: u8,
+    timeframe: Timeframe,
     spotify_id: String,
 }
 
-/// Returns the top artists for the last update for the given user.  Items are returned as
-/// `(timeframe_id, artist)`.
+/// Restricts which entries of a snapshot are loaded so that metadata doesn't need to be fetched for
+/// entities that won't be displayed
+#[derive(Clone, Debug)]
+pub(crate) struct SnapshotFilter {
+    /// Max number of entities to load per timeframe
+    pub limit: Option<u8>,
+    /// Timeframes to load
+    pub timeframes: Vec<Timeframe>,
+}
+
+impl Default for SnapshotFilter {
+    fn default() -> Self {
+        SnapshotFilter {
+            limit: None,
+            timeframes: Timeframe::ALL.to_vec(),
+        }
+    }
+}
+
+/// Query for the time of the user's most recent update.  This reads `snapshot_updates`, where it's
+/// answered from the primary key, since finding it through the `*_rank_snapshots` views would
+/// materialize every one of the user's snapshots first.
+pub(crate) fn last_snapshot_time_query(
+    user_id: i64,
+) -> crate::schema::snapshot_updates::BoxedQuery<
+    'static,
+    DbBackend,
+    crate::db_backend::sql_types::Datetime,
+> {
+    use crate::schema::snapshot_updates;
+
+    snapshot_updates::table
+        .filter(snapshot_updates::dsl::user_id.eq(user_id))
+        .select(snapshot_updates::dsl::update_time)
+        .order_by(snapshot_updates::dsl::update_time.desc())
+        .into_boxed()
+}
+
+/// Returns the time of the user's most recent update, or `None` if they have no snapshots
+pub(crate) async fn get_last_snapshot_time(
+    conn: &DbConn,
+    user_id: i64,
+) -> QueryResult<Option<NaiveDateTime>> {
+    conn.run(move |conn| last_snapshot_time_query(user_id).first(conn).optional())
+        .await
+}
+
+/// Loads the user's top artists or tracks from their most recent update out of the
+/// `latest_artist_stats` or `latest_track_stats` table, ordered by timeframe and then ranking
+async fn load_latest_stats(
+    conn: &DbConn,
+    user_id: i64,
+    entity: ExportEntity,
+    filter: SnapshotFilter,
+) -> QueryResult<Vec<StatsQueryResultItem>> {
+    use crate::schema::{latest_artist_stats, latest_track_stats, spotify_items};
+
+    let max_ranking = filter.limit.unwrap_or(u8::MAX);
+    let timeframes = filter.timeframes;
+    match entity {
+        ExportEntity::Artists => {
+            let query = latest_artist_stats::table
+                .filter(latest_artist_stats::dsl::user_id.eq(user_id))
+                .filter(latest_artist_stats::dsl::timeframe.eq_any(timeframes))
+                .filter(latest_artist_stats::dsl::ranking.lt(bind(max_ranking)))
+                .inner_join(spotify_items::table)
+                .order_by((
+                    latest_artist_stats::dsl::timeframe,
+                    latest_artist_stats::dsl::ranking,
+                ))
+                .select((
+                    latest_artist_stats::dsl::timeframe,
+                    spotify_items::dsl::spotify_id,
+                ));
+            conn.run(move |conn| query.load(conn)).await
+        },
+        ExportEntity::Tracks => {
+            let query = latest_track_stats::table
+                .filter(latest_track_stats::dsl::user_id.eq(user_id))
+                .filter(latest_track_stats::dsl::timeframe.eq_any(timeframes))
+                .filter(latest_track_stats::dsl::ranking.lt(bind(max_ranking)))
+                .inner_join(spotify_items::table)
+                .order_by((
+                    latest_track_stats::dsl::timeframe,
+                    latest_track_stats::dsl::ranking,
+                ))
+                .select((
+                    latest_track_stats::dsl::timeframe,
+                    spotify_items::dsl::spotify_id,
+                ));
+            conn.run(move |conn| query.load(conn)).await
+        },
+    }
+}
+
+/// Returns the top artists for the given user from the update at `snapshot_time`, or from the last
+/// update if `None`.  Items are returned as `(timeframe, artist)`.
 pub(crate) async fn get_artist_stats(
     user: &User,
     conn: DbConn,
     spotify_access_token: &str,
-) -> Result<Option<Vec<(u8, Artist)>>, String> {
+    snapshot_time: Option<NaiveDateTime>,
+    filter: SnapshotFilter,
+) -> Result<Option<Vec<(Timeframe, Artist)>>, Error> {
     use crate::schema::{
         artist_rank_snapshots::{self, dsl::*},
         spotify_items::{self, dsl::*},
@@ -77,28 +215,24 @@ pub(crate) async fn get_artist_stats(
     }
 
     let tok = start();
-    let query = artist_rank_snapshots
-        .filter(user_id.eq(user.id))
-        .select(update_time)
-        .order_by(update_time.desc());
-    let last_update_time: Option<NaiveDateTime> = conn
-        .run(move |conn| query.first(conn).optional())
-        .await
-        .map_err(stringify_diesel_err)?;
-    let last_update_time = match last_update_time {
-        Some(last_update_time) => last_update_time,
-        None => return Ok(None),
-    };
-
-    let query = artist_rank_snapshots
-        .filter(user_id.eq(user.id))
-        .filter(update_time.eq(last_update_time))
-        .inner_join(spotify_items)
-        .select((artist_rank_snapshots::timeframe, spotify_items::spotify_id));
-    let artist_stats = conn
-        .run(move |conn| query.load::<StatsQueryResultItem>(conn))
-        .await
-        .map_err(stringify_diesel_err)?;
+    let artist_stats = match snapshot_time {
+        Some(snapshot_time) => {
+            let query = artist_rank_snapshots
+                .filter(user_id.eq(user.id))
+                .filter(update_time.eq(snapshot_time))
+                .filter(artist_rank_snapshots::timeframe.eq_any(filter.timeframes))
+                .filter(artist_rank_snapshots::ranking.lt(bind(filter.limit.unwrap_or(u8::MAX))))
+                .inner_join(spotify_items)
+                .order_by((
+                    artist_rank_snapshots::timeframe,
+                    artist_rank_snapshots::ranking,
+                ))
+                .select((artist_rank_snapshots::timeframe, spotify_items::spotify_id));
+            conn.run(move |conn| query.load::<StatsQueryResultItem>(conn))
+                .await
+        },
+        None => load_latest_stats(&conn, user.id, ExportEntity::Artists, filter).await,
+    }?;
     mark(tok, "Got artist stats from database");
 
     if artist_stats.is_empty() {
@@ -110,20 +244,138 @@ pub(crate) async fn get_artist_stats(
         .iter()
         .map(|entry| entry.spotify_id.as_str())
         .collect();
-    let fetched_artists =
-        crate::spotify_api::fetch_artists(spotify_access_token, &artist_spotify_ids)
-            .await?
-            .into_iter()
-            .enumerate()
-            .map(|(i, artist)| {
-                let timeframe_id = artist_stats[i].timeframe;
-                (timeframe_id, artist)
-            })
-            .collect::<Vec<_>>();
+    let artists_by_id =
+        crate::spotify_api::fetch_artists(spotify_access_token, &artist_spotify_ids).await?;
+    let fetched_artists = artist_stats
+        .iter()
+        .map(|entry| (entry.timeframe, artists_by_id[&entry.spotify_id].clone()))
+        .collect::<Vec<_>>();
     mark(tok, "Got artist metadata");
     Ok(Some(fetched_artists))
 }
 
+/// Loads the user's top artists and tracks from the update at `snapshot_time`, or from the last
+/// update if `None`.  Returns `None` if the user has no snapshot at that time.  Timeframes excluded
+/// by `filter` are left empty.
+pub(crate) async fn load_snapshot(
+    conn: DbConn,
+    conn2: DbConn,
+    user: &User,
+    snapshot_time: Option<NaiveDateTime>,
+    spotify_access_token: &str,
+    filter: SnapshotFilter,
+) -> Result<Option<StatsSnapshot>, Error> {
+    let tok = start();
+    let (artist_stats, track_stats) = match tokio::join!(
+        get_artist_stats(
+            user,
+            conn,
+            spotify_access_token,
+            snapshot_time,
+            filter.clone()
+        ),
+        get_track_stats(user, conn2, spotify_access_token, snapshot_time, filter),
+    ) {
+        (Err(err), _) | (Ok(_), Err(err)) => return Err(err),
+        (Ok(None), _) | (_, Ok(None)) => return Ok(None),
+        (Ok(Some(artist_stats)), Ok(Some(track_stats))) => (artist_stats, track_stats),
+    };
+    mark(tok, "Fetched artist and track stats");
+
+    let mut snapshot =
+        StatsSnapshot::new(user.localize(snapshot_time.unwrap_or(user.last_update_time)));
+
+    for (timeframe, artist) in artist_stats {
+        snapshot.artists.add_item(timeframe, artist);
+    }
+
+    for (timeframe, track) in track_stats {
+        snapshot.tracks.add_item(timeframe, track);
+    }
+
+    Ok(Some(snapshot))
+}
+
+/// Spotify IDs of a user's top artists and tracks from a single update, ordered by ranking
+pub(crate) struct TopSpotifyIds {
+    pub update_time: NaiveDateTime,
+    pub artists: TimeFrames<String>,
+    pub tracks: TimeFrames<String>,
+}
+
+/// Loads the Spotify IDs of the user's top artists and tracks from their last update without
+/// fetching any metadata.  Returns `None` if the user has no snapshots.
+pub(crate) async fn get_latest_top_spotify_ids(
+    conn: &DbConn,
+    user: &User,
+    filter: SnapshotFilter,
+) -> Result<Option<TopSpotifyIds>, Error> {
+    use crate::schema::{artist_rank_snapshots, spotify_items, track_rank_snapshots};
+
+    if !user.external_data_retrieved {
+        retrieve_cold_data_for_user(conn, user).await;
+    }
+
+    let user_id = user.id;
+    let last_update_time = get_last_snapshot_time(conn, user_id).await?;
+    let update_time = match last_update_time {
+        Some(update_time) => update_time,
+        None => return Ok(None),
+    };
+
+    let max_ranking = filter.limit.unwrap_or(u8::MAX);
+    let timeframes = filter.timeframes.clone();
+    let artist_query = artist_rank_snapshots::table
+        .filter(artist_rank_snapshots::dsl::user_id.eq(user_id))
+        .filter(artist_rank_snapshots::dsl::update_time.eq(update_time))
+        .filter(artist_rank_snapshots::dsl::timeframe.eq_any(timeframes))
+        .filter(artist_rank_snapshots::dsl::ranking.lt(bind(max_ranking)))
+        .inner_join(spotify_items::table)
+        .order_by((
+            artist_rank_snapshots::dsl::timeframe,
+            artist_rank_snapshots::dsl::ranking,
+        ))
+        .select((
+            artist_rank_snapshots::dsl::timeframe,
+            spotify_items::dsl::spotify_id,
+        ));
+    let track_query = track_rank_snapshots::table
+        .filter(track_rank_snapshots::dsl::user_id.eq(user_id))
+        .filter(track_rank_snapshots::dsl::update_time.eq(update_time))
+        .filter(track_rank_snapshots::dsl::timeframe.eq_any(filter.timeframes))
+        .filter(track_rank_snapshots::dsl::ranking.lt(bind(max_ranking)))
+        .inner_join(spotify_items::table)
+        .order_by((
+            track_rank_snapshots::dsl::timeframe,
+            track_rank_snapshots::dsl::ranking,
+        ))
+        .select((
+            track_rank_snapshots::dsl::timeframe,
+            spotify_items::dsl::spotify_id,
+        ));
+    let (artist_stats, track_stats) = conn
+        .run(move |conn| -> QueryResult<_> {
+            Ok((
+                artist_query.load::<StatsQueryResultItem>(conn)?,
+                track_query.load::<StatsQueryResultItem>(conn)?,
+            ))
+        })
+        .await?;
+
+    let mut top_ids = TopSpotifyIds {
+        update_time,
+        artists: Default::default(),
+        tracks: Default::default(),
+    };
+    for entry in artist_stats {
+        top_ids.artists.add_item(entry.timeframe, entry.spotify_id);
+    }
+    for entry in track_stats {
+        top_ids.tracks.add_item(entry.timeframe, entry.spotify_id);
+    }
+    Ok(Some(top_ids))
+}
+
 async fn retrieve_cold_data_for_user(conn: &DbConn, user: &User) {
     let tok = start();
     crate::external_storage::download::retrieve_external_user_data(
@@ -135,36 +387,370 @@ async fn retrieve_cold_data_for_user(conn: &DbConn, user: &User) {
     mark(tok, "get_artist_stats");
 }
 
+/// Subquery for the internal IDs of the provided Spotify IDs.  Rank history queries filter the
+/// deltas tables on these rather than joining `spotify_items` so that they can use the `(user_id,
+/// mapped_spotify_id)` indexes.
+pub(crate) fn mapped_spotify_ids_query(
+    spotify_ids: Vec<String>,
+) -> diesel::dsl::Select<
+    diesel::dsl::Filter<
+        crate::schema::spotify_items::table,
+        diesel::dsl::EqAny<crate::schema::spotify_items::spotify_id, Vec<String>>,
+    >,
+    crate::schema::spotify_items::id,
+> {
+    use crate::schema::spotify_items;
+
+    spotify_items::table
+        .filter(spotify_items::dsl::spotify_id.eq_any(spotify_ids))
+        .select(spotify_items::dsl::id)
+}
+
+/// Expands delta-encoded rankings into full snapshots.  `updates` holds `(update_time, timeframe,
+/// entry_count)` for each of the user's updates and `deltas` holds the user's stored rows, both
+/// sorted by update time.  Each ranking of an update resolves to the most recent row stored for it
+/// at or before that update, which is the same thing the `*_rank_snapshots` views compute.
+fn resolve_rank_deltas(
+    updates: &[(NaiveDateTime, Timeframe, u8)],
+    deltas: &[UserHistoryEntry],
+) -> Vec<UserHistoryEntry> {
+    let mut latest_by_ranking: HashMap<(Timeframe, u8), &UserHistoryEntry> = HashMap::default();
+    let mut deltas = deltas.iter().peekable();
+    let mut resolved = Vec::new();
+    for &(update_time, timeframe, entry_count) in updates {
+        while let Some(delta) = deltas.next_if(|delta| delta.update_time <= update_time) {
+            latest_by_ranking.insert((delta.timeframe, delta.ranking), delta);
+        }
+
+        for ranking in 0..entry_count {
+            if let Some(delta) = latest_by_ranking.get(&(timeframe, ranking)) {
+                resolved.push(UserHistoryEntry {
+                    update_time,
+                    ..(*delta).clone()
+                });
+            }
+        }
+    }
+    resolved
+}
+
+/// Loads all of the user's artist or track snapshot rows, optionally restricted to a single
+/// timeframe, ordered by update time, timeframe, and ranking.
+///
+/// The `*_rank_snapshots` views look up the latest delta of every ranking separately for each
+/// update, which gets slower the more history a user has.  Paths that read a user's whole history
+/// load the indexed deltas once and resolve them here instead.
+pub(crate) fn load_rank_history(
+    conn: &DbConnection,
+    user_id: i64,
+    entity: ExportEntity,
+    restrict_to_timeframe: Option<Timeframe>,
+) -> QueryResult<Vec<UserHistoryEntry>> {
+    use crate::schema::{artist_rank_deltas, snapshot_updates, track_rank_deltas};
+
+    let (updates, deltas) = match entity {
+        ExportEntity::Artists => {
+            let mut updates_query = snapshot_updates::table
+                .filter(snapshot_updates::dsl::user_id.eq(user_id))
+                .filter(snapshot_updates::dsl::artist_count.gt(bind(0u8)))
+                .order_by((
+                    snapshot_updates::dsl::update_time,
+                    snapshot_updates::dsl::timeframe,
+                ))
+                .select((
+                    snapshot_updates::dsl::update_time,
+                    snapshot_updates::dsl::timeframe,
+                    snapshot_updates::dsl::artist_count,
+                ))
+                .into_boxed();
+            let mut deltas_query = artist_rank_deltas::table
+                .filter(artist_rank_deltas::dsl::user_id.eq(user_id))
+                .order_by(artist_rank_deltas::dsl::update_time)
+                .select(artist_rank_deltas::all_columns)
+                .into_boxed();
+            if let Some(timeframe) = restrict_to_timeframe {
+                updates_query =
+                    updates_query.filter(snapshot_updates::dsl::timeframe.eq(timeframe));
+                deltas_query =
+                    deltas_query.filter(artist_rank_deltas::dsl::timeframe.eq(timeframe));
+            }
+            (updates_query.load(conn)?, deltas_query.load(conn)?)
+        },
+        ExportEntity::Tracks => {
+            let mut updates_query = snapshot_updates::table
+                .filter(snapshot_updates::dsl::user_id.eq(user_id))
+                .filter(snapshot_updates::dsl::track_count.gt(bind(0u8)))
+                .order_by((
+                    snapshot_updates::dsl::update_time,
+                    snapshot_updates::dsl::timeframe,
+                ))
+                .select((
+                    snapshot_updates::dsl::update_time,
+                    snapshot_updates::dsl::timeframe,
+                    snapshot_updates::dsl::track_count,
+                ))
+                .into_boxed();
+            let mut deltas_query = track_rank_deltas::table
+                .filter(track_rank_deltas::dsl::user_id.eq(user_id))
+                .order_by(track_rank_deltas::dsl::update_time)
+                .select(track_rank_deltas::all_columns)
+                .into_boxed();
+            if let Some(timeframe) = restrict_to_timeframe {
+                updates_query =
+                    updates_query.filter(snapshot_updates::dsl::timeframe.eq(timeframe));
+                deltas_query = deltas_query.filter(track_rank_deltas::dsl::timeframe.eq(timeframe));
+            }
+            (updates_query.load(conn)?, deltas_query.load(conn)?)
+        },
+    };
+    Ok(resolve_rank_deltas(&updates, &deltas))
+}
+
+/// Resolves the user's artist or track snapshot at `update_time` from the deltas stored at or
+/// before it, ordered by timeframe and ranking.  Like `load_rank_history`, this reads the deltas
+/// once rather than going through the `*_rank_snapshots` views.
+fn load_snapshot_at(
+    conn: &DbConnection,
+    user_id: i64,
+    entity: ExportEntity,
+    update_time: NaiveDateTime,
+) -> QueryResult<Vec<UserHistoryEntry>> {
+    use crate::schema::{artist_rank_deltas, snapshot_updates, track_rank_deltas};
+
+    let updates_query = snapshot_updates::table
+        .filter(snapshot_updates::dsl::user_id.eq(user_id))
+        .filter(snapshot_updates::dsl::update_time.eq(update_time))
+        .order_by(snapshot_updates::dsl::timeframe);
+    let (updates, deltas): (Vec<(NaiveDateTime, Timeframe, u8)>, Vec<UserHistoryEntry>) =
+        match entity {
+            ExportEntity::Artists => (
+                updates_query
+                    .select((
+                        snapshot_updates::dsl::update_time,
+                        snapshot_updates::dsl::timeframe,
+                        snapshot_updates::dsl::artist_count,
+                    ))
+                    .load(conn)?,
+                artist_rank_deltas::table
+                    .filter(artist_rank_deltas::dsl::user_id.eq(user_id))
+                    .filter(artist_rank_deltas::dsl::update_time.le(update_time))
+                    .order_by(artist_rank_deltas::dsl::update_time)
+                    .select(artist_rank_deltas::all_columns)
+                    .load(conn)?,
+            ),
+            ExportEntity::Tracks => (
+                updates_query
+                    .select((
+                        snapshot_updates::dsl::update_time,
+                        snapshot_updates::dsl::timeframe,
+                        snapshot_updates::dsl::track_count,
+                    ))
+                    .load(conn)?,
+                track_rank_deltas::table
+                    .filter(track_rank_deltas::dsl::user_id.eq(user_id))
+                    .filter(track_rank_deltas::dsl::update_time.le(update_time))
+                    .order_by(track_rank_deltas::dsl::update_time)
+                    .select(track_rank_deltas::all_columns)
+                    .load(conn)?,
+            ),
+        };
+    Ok(resolve_rank_deltas(&updates, &deltas))
+}
+
+/// Query for the rows stored in `artist_rank_deltas` for the artists with the given Spotify IDs.
+/// This is answered from the `(user_id, mapped_spotify_id)` index.
+pub(crate) fn artist_entity_rank_deltas_query(
+    user_id: i64,
+    spotify_ids: Vec<String>,
+) -> crate::schema::artist_rank_deltas::BoxedQuery<'static, DbBackend> {
+    use crate::schema::artist_rank_deltas;
+
+    artist_rank_deltas::table
+        .filter(artist_rank_deltas::dsl::user_id.eq(user_id))
+        .filter(
+            artist_rank_deltas::dsl::mapped_spotify_id
+                .eq_any(mapped_spotify_ids_query(spotify_ids)),
+        )
+        .into_boxed()
+}
+
+/// Query for the rows stored in `track_rank_deltas` for the tracks with the given Spotify IDs.
+/// This is answered from the `(user_id, mapped_spotify_id)` index.
+pub(crate) fn track_entity_rank_deltas_query(
+    user_id: i64,
+    spotify_ids: Vec<String>,
+) -> crate::schema::track_rank_deltas::BoxedQuery<'static, DbBackend> {
+    use crate::schema::track_rank_deltas;
+
+    track_rank_deltas::table
+        .filter(track_rank_deltas::dsl::user_id.eq(user_id))
+        .filter(
+            track_rank_deltas::dsl::mapped_spotify_id.eq_any(mapped_spotify_ids_query(spotify_ids)),
+        )
+        .into_boxed()
+}
+
+/// Same as `load_rank_history`, but only returns the rows of the entities with the given Spotify
+/// IDs.
+///
+/// The entities' own deltas are found by mapped ID.  An entity keeps its ranking until a different
+/// entity is stored for it, so the deltas stored afterwards for the rankings the entities held are
+/// loaded as well to tell when that happened.  These are found with the `latest_per_ranking` index.
+/// Nothing from before the entities' first delta is needed.
+fn load_entities_rank_history(
+    conn: &DbConnection,
+    user_id: i64,
+    entity: ExportEntity,
+    spotify_ids: Vec<String>,
+) -> QueryResult<Vec<UserHistoryEntry>> {
+    use crate::schema::{artist_rank_deltas, snapshot_updates, track_rank_deltas};
+
+    let entity_deltas: Vec<UserHistoryEntry> = match entity {
+        ExportEntity::Artists =>
+            artist_entity_rank_deltas_query(user_id, spotify_ids).load(conn)?,
+        ExportEntity::Tracks => track_entity_rank_deltas_query(user_id, spotify_ids).load(conn)?,
+    };
+    let start_time = match entity_deltas.iter().map(|delta| delta.update_time).min() {
+        Some(start_time) => start_time,
+        None => return Ok(Vec::new()),
+    };
+    let internal_ids: HashSet<i32> = entity_deltas
+        .iter()
+        .map(|delta| delta.mapped_spotify_id)
+        .collect();
+    let mut timeframes: Vec<Timeframe> =
+        entity_deltas.iter().map(|delta| delta.timeframe).collect();
+    timeframes.sort_unstable();
+    timeframes.dedup();
+    let min_ranking = entity_deltas
+        .iter()
+        .map(|delta| delta.ranking)
+        .min()
+        .unwrap();
+    let max_ranking = entity_deltas
+        .iter()
+        .map(|delta| delta.ranking)
+        .max()
+        .unwrap();
+
+    let (updates, deltas) = match entity {
+        ExportEntity::Artists => {
+            let updates = snapshot_updates::table
+                .filter(snapshot_updates::dsl::user_id.eq(user_id))
+                .filter(snapshot_updates::dsl::update_time.ge(start_time))
+                .filter(snapshot_updates::dsl::timeframe.eq_any(timeframes.clone()))
+                .filter(snapshot_updates::dsl::artist_count.gt(bind(0u8)))
+                .order_by((
+                    snapshot_updates::dsl::update_time,
+                    snapshot_updates::dsl::timeframe,
+                ))
+                .select((
+                    snapshot_updates::dsl::update_time,
+                    snapshot_updates::dsl::timeframe,
+                    snapshot_updates::dsl::artist_count,
+                ))
+                .load(conn)?;
+            let deltas = artist_rank_deltas::table
+                .filter(artist_rank_deltas::dsl::user_id.eq(user_id))
+                .filter(artist_rank_deltas::dsl::timeframe.eq_any(timeframes))
+                .filter(artist_rank_deltas::dsl::ranking.ge(bind(min_ranking)))
+                .filter(artist_rank_deltas::dsl::ranking.le(bind(max_ranking)))
+                .filter(artist_rank_deltas::dsl::update_time.ge(start_time))
+                .order_by(artist_rank_deltas::dsl::update_time)
+                .load(conn)?;
+            (updates, deltas)
+        },
+        ExportEntity::Tracks => {
+            let updates = snapshot_updates::table
+                .filter(snapshot_updates::dsl::user_id.eq(user_id))
+                .filter(snapshot_updates::dsl::update_time.ge(start_time))
+                .filter(snapshot_updates::dsl::timeframe.eq_any(timeframes.clone()))
+                .filter(snapshot_updates::dsl::track_count.gt(bind(0u8)))
+                .order_by((
+                    snapshot_updates::dsl::update_time,
+                    snapshot_updates::dsl::timeframe,
+                ))
+                .select((
+                    snapshot_updates::dsl::update_time,
+                    snapshot_updates::dsl::timeframe,
+                    snapshot_updates::dsl::track_count,
+                ))
+                .load(conn)?;
+            let deltas = track_rank_deltas::table
+                .filter(track_rank_deltas::dsl::user_id.eq(user_id))
+                .filter(track_rank_deltas::dsl::timeframe.eq_any(timeframes))
+                .filter(track_rank_deltas::dsl::ranking.ge(bind(min_ranking)))
+                .filter(track_rank_deltas::dsl::ranking.le(bind(max_ranking)))
+                .filter(track_rank_deltas::dsl::update_time.ge(start_time))
+                .order_by(track_rank_deltas::dsl::update_time)
+                .load(conn)?;
+            (updates, deltas)
+        },
+    };
+
+    let mut history = resolve_rank_deltas(&updates, &deltas);
+    history.retain(|entry| internal_ids.contains(&entry.mapped_spotify_id));
+    Ok(history)
+}
+
+/// Loads the user's snapshot rows for the given entities via `load_rank_history`, or all of them if
+/// `mapped_spotify_ids` is `None`, along with the Spotify IDs of the entities.
+async fn load_stats_history(
+    conn: &DbConn,
+    user_id: i64,
+    entity: ExportEntity,
+    restrict_to_timeframe: Option<Timeframe>,
+    mapped_spotify_ids: Option<HashSet<i32>>,
+) -> QueryResult<Vec<StatsHistoryQueryResItem>> {
+    let history = conn
+        .run(move |conn| -> QueryResult<Vec<UserHistoryEntry>> {
+            let mut history = load_rank_history(conn, user_id, entity, restrict_to_timeframe)?;
+            if let Some(mapped_spotify_ids) = mapped_spotify_ids {
+                history.retain(|entry| mapped_spotify_ids.contains(&entry.mapped_spotify_id));
+            }
+            Ok(history)
+        })
+        .await?;
+
+    let internal_ids: HashSet<i32> = history
+        .iter()
+        .map(|entry| entry.mapped_spotify_id)
+        .collect();
+    let spotify_ids_by_internal_id =
+        get_artist_spotify_ids_by_internal_id(conn, internal_ids.into_iter().collect()).await?;
+    Ok(history
+        .into_iter()
+        .filter_map(|entry| {
+            Some(StatsHistoryQueryResItem {
+                spotify_id: spotify_ids_by_internal_id
+                    .get(&entry.mapped_spotify_id)?
+                    .clone(),
+                update_time: entry.update_time,
+                ranking: entry.ranking,
+                timeframe: entry.timeframe,
+            })
+        })
+        .collect())
+}
+
 pub(crate) async fn get_artist_rank_history_single_artist(
     user: &User,
     conn: DbConn,
     artist_spotify_id: String,
-) -> Result<Option<Vec<(NaiveDateTime, [Option<u8>; 3])>>, String> {
-    use crate::schema::{artist_rank_snapshots::dsl::*, spotify_items::dsl::*};
-
+) -> Result<Option<Vec<(NaiveDateTime, [Option<u8>; 3])>>, Error> {
     if !user.external_data_retrieved {
         retrieve_cold_data_for_user(&conn, user).await;
     }
 
     let tok = start();
-    let query = artist_rank_snapshots
-        .filter(user_id.eq(user.id))
-        .inner_join(spotify_items)
-        .filter(spotify_id.eq(artist_spotify_id))
-        .order_by(update_time.asc())
-        .select((update_time, ranking, timeframe));
-    debug!(
-        "{:?}",
-        diesel::debug_query::<diesel::mysql::Mysql, _>(&query)
-    );
-
-    let res = match conn
-        .run(move |conn| diesel_not_found_to_none(query.load::<ArtistRankHistoryResItem>(conn)))
-        .await?
-    {
-        Some(res) => res,
-        None => return Ok(None),
-    };
+    let user_id = user.id;
+    let res = conn
+        .run(move |conn| {
+            load_entities_rank_history(conn, user_id, ExportEntity::Artists, vec![
+                artist_spotify_id,
+            ])
+        })
+        .await?;
     if res.is_empty() {
         return Ok(None);
     }
@@ -181,7 +767,7 @@ pub(crate) async fn get_artist_rank_history_single_artist(
             ));
         }
 
-        cur_update.1[update.timeframe as usize] = Some(update.ranking);
+        cur_update.1[update.timeframe.id() as usize] = Some(update.ranking);
     }
     output.push(cur_update);
     mark(tok, "get_artist_rank_history_single_artist");
@@ -205,40 +791,22 @@ pub(crate) fn group_updates_by_timestamp<T>(
     entity_stats_by_update_timestamp
 }
 
-/// Generic function that handles executing a given SQL query to fetch metrics for a set of entities
-/// of some type. Once the metrics are fetched, it also fetches entity metadata for all of the
-/// fetched updates and returns them as a mapping from spotify id to entity along with the sorted +
-/// grouped metrics.
+/// Generic function that handles grouping the loaded metrics for a set of entities of some type.
+/// It also fetches entity metadata for all of the updates and returns them as a mapping from
+/// spotify id to entity along with the sorted + grouped metrics.
 ///
 /// The data returned by this function is useful for generating graphs on the frontend showing how
 /// the rankings of different entities changes over time.
 async fn get_entity_stats_history<
     T: HasSpotifyId + Debug,
-    Q: RunQueryDsl<MysqlConnection>
-        + QueryFragment<Mysql>
-        + LoadQuery<MysqlConnection, StatsHistoryQueryResItem>
-        + QueryId
-        + Send
-        + 'static,
     U: Serialize + Debug,
-    F: Future<Output = Result<Vec<T>, String>>,
+    F: Future<Output = Result<HashMap<String, T>, Error>>,
 >(
-    conn: DbConn,
-    query: Q,
+    entity_stats: Vec<StatsHistoryQueryResItem>,
     spotify_access_token: &str,
     fetch_entities: fn(spotify_access_token: String, entity_spotify_ids: Vec<String>) -> F,
     get_update_item: fn(&StatsHistoryQueryResItem) -> U,
-) -> Result<Option<(HashMap<String, T>, Vec<(NaiveDateTime, TimeFrames<U>)>)>, String> {
-    debug!("{}", diesel::debug_query::<diesel::mysql::Mysql, _>(&query));
-    let entity_stats_opt: Option<Vec<StatsHistoryQueryResItem>> = conn
-        .run(|conn| diesel_not_found_to_none(query.load::<StatsHistoryQueryResItem>(conn)))
-        .await?;
-
-    let entity_stats: Vec<StatsHistoryQueryResItem> = match entity_stats_opt {
-        None => return Ok(None),
-        Some(res) => res,
-    };
-
+) -> Result<Option<(HashMap<String, T>, Vec<(NaiveDateTime, TimeFrames<U>)>)>, Error> {
     let entity_spotify_ids: HashSet<&str> = entity_stats
         .iter()
         .map(|entry| entry.spotify_id.as_str())
@@ -246,14 +814,8 @@ async fn get_entity_stats_history<
     let entity_spotify_ids: Vec<String> =
         entity_spotify_ids.into_iter().map(String::from).collect();
 
-    let fetched_tracks =
+    let entities_by_id =
         fetch_entities(spotify_access_token.to_owned(), entity_spotify_ids).await?;
-    let entities_by_id = fetched_tracks
-        .into_iter()
-        .fold(HashMap::default(), |mut acc, track| {
-            acc.insert(track.get_spotify_id().to_string(), track);
-            acc
-        });
 
     // Group the entity stats by their update timestamp
     let entity_stats_by_update_timestamp = group_updates_by_timestamp(
@@ -270,7 +832,7 @@ async fn get_entity_stats_history<
             let stats_for_update = entries_for_update.into_iter().fold(
                 TimeFrames::default(),
                 |mut acc, track_history_entry| {
-                    acc.add_item_by_id(
+                    acc.add_item(
                         track_history_entry.timeframe,
                         get_update_item(track_history_entry),
                     );
@@ -290,62 +852,38 @@ pub(crate) async fn get_artist_stats_history(
     user: &User,
     conn: DbConn,
     spotify_access_token: &str,
-    restrict_to_timeframe_id: Option<u8>,
+    restrict_to_timeframe: Option<Timeframe>,
 ) -> Result<
     Option<(
         HashMap<String, Artist>,
         Vec<(NaiveDateTime, TimeFrames<String>)>,
     )>,
-    String,
+    Error,
 > {
-    use crate::schema::{artist_rank_snapshots::dsl::*, spotify_items::dsl::*};
-
     if !user.external_data_retrieved {
         retrieve_cold_data_for_user(&conn, user).await;
     }
 
-    let query = artist_rank_snapshots.filter(user_id.eq(user.id));
-    if let Some(timeframe_id) = restrict_to_timeframe_id {
-        let query = query
-            .filter(timeframe.eq(timeframe_id))
-            .inner_join(spotify_items)
-            .select((spotify_id, update_time, ranking, timeframe));
-
-        get_entity_stats_history(
-            conn,
-            query,
-            spotify_access_token,
-            |spotify_access_token: String, spotify_ids: Vec<String>| async move {
-                let ref_spotify_ids: Vec<&str> = spotify_ids.iter().map(String::as_str).collect();
-                let res =
-                    crate::spotify_api::fetch_artists(&spotify_access_token, &ref_spotify_ids)
-                        .await;
-                res
-            },
-            |update: &StatsHistoryQueryResItem| update.spotify_id.clone(),
-        )
-        .await
-    } else {
-        let query =
-            query
-                .inner_join(spotify_items)
-                .select((spotify_id, update_time, ranking, timeframe));
-
-        get_entity_stats_history(
-            conn,
-            query,
-            spotify_access_token,
-            |spotify_access_token: String, spotify_ids: Vec<String>| async move {
-                let ref_spotify_ids: Vec<&str> = spotify_ids.iter().map(String::as_str).collect();
-                let res =
-                    crate::spotify_api::fetch_artists(&spotify_access_token, &ref_spotify_ids)
-                        .await;
-                res
-            },
-            |update: &StatsHistoryQueryResItem| update.spotify_id.clone(),
-        )
-        .await
-    }
+    let entity_stats = load_stats_history(
+        &conn,
+        user.id,
+        ExportEntity::Artists,
+        restrict_to_timeframe,
+        None,
+    )
+    .await?;
+    get_entity_stats_history(
+        entity_stats,
+        spotify_access_token,
+        |spotify_access_token: String, spotify_ids: Vec<String>| async move {
+            let ref_spotify_ids: Vec<&str> = spotify_ids.iter().map(String::as_str).collect();
+            let res =
+                crate::spotify_api::fetch_artists(&spotify_access_token, &ref_spotify_ids).await;
+            res
+        },
+        |update: &StatsHistoryQueryResItem| update.spotify_id.clone(),
+    )
+    .await
 }
 
 #[derive(Debug, Serialize)]
@@ -364,39 +902,37 @@ pub(crate) async fn get_genre_stats_history(
         HashMap<String, Artist>,
         Vec<(NaiveDateTime, TimeFrames<ArtistRanking>)>,
     )>,
-    String,
+    Error,
 > {
     if !user.external_data_retrieved {
         retrieve_cold_data_for_user(&conn, user).await;
     }
 
-    // Using a raw query here because the `STRAIGHT_JOIN` forces the MySQL query optimizer to do
-    // something different which makes the query run several times faster.
-    let query = diesel::sql_query(
-        r#"
-            SELECT STRAIGHT_JOIN
-                `spotify_items`.`spotify_id`,
-                `artist_rank_snapshots`.`update_time`,
-                `artist_rank_snapshots`.`ranking`,
-                `artist_rank_snapshots`.`timeframe`
-            FROM `artist_rank_snapshots`
-            INNER JOIN `spotify_items`
-                ON `artist_rank_snapshots`.`mapped_spotify_id` = `spotify_items`.`id`
-            WHERE `artist_rank_snapshots`.`user_id` = ?
-                AND `artist_rank_snapshots`.`mapped_spotify_id` IN (
-                    SELECT `spotify_items`.`id` FROM `artists_genres`
-                        INNER JOIN `spotify_items`
-                            ON `artists_genres`.`artist_id` = `spotify_items`.`id`
-                        WHERE `artists_genres`.`genre` = ?
-                )
-    "#,
+    use crate::schema::artists_genres;
+
+    // Artists are matched by any of the Spotify labels of the canonical genre
+    let labels = crate::genres::genre_labels(&target_genre);
+    let genre_artist_ids: HashSet<i32> = conn
+        .run(move |conn| {
+            artists_genres::table
+                .filter(artists_genres::dsl::genre.eq_any(labels))
+                .select(artists_genres::dsl::artist_id)
+                .load::<i32>(conn)
+        })
+        .await?
+        .into_iter()
+        .collect();
+    let entity_stats = load_stats_history(
+        &conn,
+        user.id,
+        ExportEntity::Artists,
+        None,
+        Some(genre_artist_ids),
     )
-    .bind::<diesel::sql_types::BigInt, _>(user.id)
-    .bind::<diesel::sql_types::Text, _>(target_genre);
+    .await?;
 
     get_entity_stats_history(
-        conn,
-        query,
+        entity_stats,
         spotify_access_token,
         |spotify_access_token: String, spotify_ids: Vec<String>| async move {
             let ref_spotify_ids: Vec<&str> = spotify_ids.iter().map(String::as_str).collect();
@@ -414,59 +950,78 @@ pub(crate) async fn get_genre_stats_history(
 
 /// Returns a list of track data items for each of the top tracks for the user's most recent update.
 /// The first item of the tuple is the timeframe ID: short, medium, long.
+/// Returns the top tracks for the given user from the update at `snapshot_time`, or from the last
+/// update if `None`.  Items are returned as `(timeframe, track)`.
 pub(crate) async fn get_track_stats(
     user: &User,
     conn: DbConn,
     spotify_access_token: &str,
-) -> Result<Option<Vec<(u8, Track)>>, String> {
+    snapshot_time: Option<NaiveDateTime>,
+    filter: SnapshotFilter,
+) -> Result<Option<Vec<(Timeframe, Track)>>, Error> {
     use crate::schema::{spotify_items::dsl::*, track_rank_snapshots::dsl::*};
 
     if !user.external_data_retrieved {
         retrieve_cold_data_for_user(&conn, user).await;
     }
 
-    let query = track_rank_snapshots
-        .filter(user_id.eq(user.id))
-        .select(update_time)
-        .order_by(update_time.desc());
-    let last_update_time: Option<NaiveDateTime> = conn
-        .run(move |conn| query.first(conn).optional())
-        .await
-        .map_err(stringify_diesel_err)?;
-    let last_update_time = match last_update_time {
-        Some(last_update_time) => last_update_time,
-        None => return Ok(None),
-    };
-
-    let query = track_rank_snapshots
-        .filter(user_id.eq(user.id))
-        // Only include tracks from the most recent update
-        .filter(update_time.eq(last_update_time))
-        .order_by(update_time)
-        .inner_join(spotify_items)
-        .select((timeframe, spotify_id));
-    let track_stats_opt = conn
-        .run(move |conn| diesel_not_found_to_none(query.load::<StatsQueryResultItem>(conn)))
-        .await?;
-
-    let track_stats = match track_stats_opt {
-        None => return Ok(None),
-        Some(res) => res,
+    let track_stats = match snapshot_time {
+        Some(snapshot_time) => {
+            let query = track_rank_snapshots
+                .filter(user_id.eq(user.id))
+                // Only include tracks from the requested update
+                .filter(update_time.eq(snapshot_time))
+                .filter(timeframe.eq_any(filter.timeframes))
+                .filter(ranking.lt(bind(filter.limit.unwrap_or(u8::MAX))))
+                .order_by((timeframe, ranking))
+                .inner_join(spotify_items)
+                .select((timeframe, spotify_id));
+            let track_stats_opt = conn
+                .run(move |conn| diesel_not_found_to_none(query.load::<StatsQueryResultItem>(conn)))
+                .await?;
+            match track_stats_opt {
+                None => return Ok(None),
+                Some(res) => res,
+            }
+        },
+        None => {
+            let track_stats =
+                load_latest_stats(&conn, user.id, ExportEntity::Tracks, filter).await?;
+            // The user hasn't been updated yet
+            if track_stats.is_empty() {
+                return Ok(None);
+            }
+            track_stats
+        },
     };
 
     let track_spotify_ids: Vec<&str> = track_stats
         .iter()
         .map(|entry| entry.spotify_id.as_str())
         .collect();
-    let fetched_tracks = crate::spotify_api::fetch_tracks(spotify_access_token, &track_spotify_ids)
-        .await?
-        .into_iter()
-        .enumerate()
-        .map(|(i, track)| {
-            let timeframe_id = track_stats[i].timeframe;
-            (timeframe_id, track)
-        })
+    let tracks_by_id =
+        crate::spotify_api::fetch_tracks(spotify_access_token, &track_spotify_ids).await?;
+    let mut fetched_tracks = track_stats
+        .iter()
+        .map(|entry| (entry.timeframe, tracks_by_id[&entry.spotify_id].clone()))
         .collect::<Vec<_>>();
+
+    // Only keep the highest-ranked release of tracks that were released multiple times
+    let canonical_ids = get_canonical_track_ids(
+        &conn,
+        track_stats
+            .iter()
+            .map(|entry| entry.spotify_id.clone())
+            .collect(),
+    )
+    .await?;
+    if !canonical_ids.is_empty() {
+        let mut seen_tracks: HashSet<(Timeframe, String)> = HashSet::default();
+        fetched_tracks.retain(|(timeframe_, track)| {
+            let canonical_id = canonical_ids.get(&track.id).unwrap_or(&track.id);
+            seen_tracks.insert((*timeframe_, canonical_id.clone()))
+        });
+    }
     Ok(Some(fetched_tracks))
 }
 
@@ -483,41 +1038,40 @@ pub(crate) async fn get_track_stats_history(
         HashMap<String, Track>,
         Vec<(NaiveDateTime, TimeFrames<String>)>,
     )>,
-    String,
+    Error,
 > {
     if !user.external_data_retrieved {
         retrieve_cold_data_for_user(&conn, user).await;
     }
 
+    use crate::schema::tracks_artists;
+
     let tok = start();
 
-    // Using a raw query here because the `STRAIGHT_JOIN` forces the MySQL query optimizer to do
-    // something different which makes the query run several times faster.
-    let raw_query = r#"
-    WITH artist_tracks AS (
-        SELECT `track_id`
-        FROM `tracks_artists`
-        WHERE `artist_id` = (SELECT `id` FROM `spotify_items` WHERE `spotify_id` = ?)
-    ),
-    filtered_snapshots AS (
-        SELECT `mapped_spotify_id`, `update_time`, `ranking`, `timeframe`
-        FROM `track_rank_snapshots`
-        WHERE `user_id` = ?
-        AND `mapped_spotify_id` IN (SELECT `track_id` FROM artist_tracks)
+    let artist_track_ids: HashSet<i32> = conn
+        .run(move |conn| {
+            tracks_artists::table
+                .filter(
+                    tracks_artists::dsl::artist_id
+                        .eq_any(mapped_spotify_ids_query(vec![parent_artist_id])),
+                )
+                .select(tracks_artists::dsl::track_id)
+                .load::<i32>(conn)
+        })
+        .await?
+        .into_iter()
+        .collect();
+    let entity_stats = load_stats_history(
+        &conn,
+        user.id,
+        ExportEntity::Tracks,
+        None,
+        Some(artist_track_ids),
     )
-    SELECT `spotify_items`.`spotify_id`, `filtered_snapshots`.`update_time`, `filtered_snapshots`.`ranking`, `filtered_snapshots`.`timeframe`
-    FROM `filtered_snapshots`
-    STRAIGHT_JOIN `spotify_items` ON `filtered_snapshots`.`mapped_spotify_id` = `spotify_items`.`id`
-    ORDER BY `filtered_snapshots`.`update_time`;
-    "#;
-
-    let query = diesel::sql_query(raw_query)
-        .bind::<diesel::sql_types::Text, _>(parent_artist_id)
-        .bind::<diesel::sql_types::BigInt, _>(user.id);
+    .await?;
 
     let res = get_entity_stats_history(
-        conn,
-        query,
+        entity_stats,
         spotify_access_token,
         |spotify_access_token: String, spotify_ids: Vec<String>| async move {
             let ref_spotify_ids: Vec<&str> = spotify_ids.iter().map(String::as_str).collect();
@@ -528,9 +1082,78 @@ pub(crate) async fn get_track_stats_history(
         |update: &StatsHistoryQueryResItem| update.spotify_id.clone(),
     )
     .await?;
+    let (mut tracks_by_id, mut track_history) = match res {
+        Some(res) => res,
+        None => return Ok(None),
+    };
+
+    // Merge releases of the same recording under their canonical ID, fetching metadata for any
+    // canonical tracks that weren't part of the history themselves
+    let canonical_ids =
+        get_canonical_track_ids(&conn, tracks_by_id.keys().cloned().collect()).await?;
+    crate::stats::merge_duplicate_tracks(&mut track_history, &canonical_ids);
+    let missing_canonical_ids: HashSet<&str> = canonical_ids
+        .values()
+        .map(String::as_str)
+        .filter(|canonical_id| !tracks_by_id.contains_key(*canonical_id))
+        .collect();
+    if !missing_canonical_ids.is_empty() {
+        let missing_canonical_ids: Vec<&str> = missing_canonical_ids.into_iter().collect();
+        tracks_by_id.extend(
+            crate::spotify_api::fetch_tracks(spotify_access_token, &missing_canonical_ids).await?,
+        );
+    }
 
     mark(tok, "get_track_stats_history");
-    Ok(res)
+    Ok(Some((tracks_by_id, track_history)))
+}
+
+/// Returns the canonical Spotify ID of each of the provided tracks that has other releases of the
+/// same recording stored, which is the lowest Spotify ID sharing its ISRC.  Tracks that are their
+/// own canonical ID or have no known ISRC aren't included.
+pub(crate) async fn get_canonical_track_ids(
+    conn: &DbConn,
+    spotify_ids: Vec<String>,
+) -> QueryResult<HashMap<String, String>> {
+    use crate::schema::track_isrc_map::dsl::*;
+
+    conn.run(move |conn| {
+        let isrcs_by_id: Vec<(String, String)> = track_isrc_map
+            .filter(spotify_id.eq_any(&spotify_ids))
+            .select((spotify_id, isrc))
+            .load(conn)?;
+        let isrcs: HashSet<&str> = isrcs_by_id
+            .iter()
+            .map(|(_, isrc_)| isrc_.as_str())
+            .collect();
+        let releases: Vec<(String, String)> = track_isrc_map
+            .filter(isrc.eq_any(isrcs))
+            .select((isrc, spotify_id))
+            .load(conn)?;
+
+        let mut canonical_id_by_isrc: HashMap<String, String> = HashMap::default();
+        for (release_isrc, release_id) in releases {
+            let canonical_id = canonical_id_by_isrc
+                .entry(release_isrc)
+                .or_insert_with(|| release_id.clone());
+            if release_id < *canonical_id {
+                *canonical_id = release_id;
+            }
+        }
+
+        Ok(isrcs_by_id
+            .into_iter()
+            .filter_map(|(track_id, track_isrc)| {
+                let canonical_id = &canonical_id_by_isrc[&track_isrc];
+                if *canonical_id == track_id {
+                    None
+                } else {
+                    Some((track_id, canonical_id.clone()))
+                }
+            })
+            .collect())
+    })
+    .await
 }
 
 /// Retrieves a list of the internal mapped Spotify ID for each of the provided spotify IDs,
@@ -541,7 +1164,7 @@ pub(crate) async fn get_internal_ids_by_spotify_id<
 >(
     conn: &DbConn,
     spotify_ids: T,
-) -> Result<HashMap<String, i32>, String> {
+) -> Result<HashMap<String, i32>, Error> {
     use crate::schema::spotify_items::dsl::*;
 
     let spotify_ids_v = spotify_ids.clone().collect::<Vec<_>>();
@@ -571,11 +1194,11 @@ pub(crate) async fn get_internal_ids_by_spotify_id<
     // constraint violations
     for chunk in spotify_id_items.chunks(600) {
         let chunk: Vec<_> = chunk.to_vec();
-        let query = diesel::insert_or_ignore_into(spotify_items).values(chunk);
+        let query = insert_or_ignore!(spotify_items, chunk);
         conn.run(move |conn| {
-            query.execute(conn).map_err(|err| -> String {
+            query.execute(conn).map_err(|err| {
                 error!("Error inserting spotify ids into mapping table: {:?}", err);
-                "Error inserting spotify ids into mapping table".into()
+                Error::Internal("Error inserting spotify ids into mapping table".into())
             })
         })
         .await?;
@@ -588,9 +1211,9 @@ pub(crate) async fn get_internal_ids_by_spotify_id<
         let query = spotify_items.filter(spotify_id.eq_any(missing_ids_chunk));
         let mapped_ids_for_chunk: Vec<SpotifyIdMapping> = conn
             .run(move |conn| {
-                query.load(conn).map_err(|err| -> String {
+                query.load(conn).map_err(|err| {
                     error!("Error retrieving mapped spotify ids: {:?}", err);
-                    "Error retrieving mapped spotify ids".into()
+                    Error::Internal("Error retrieving mapped spotify ids".into())
                 })
             })
             .await?;
@@ -617,10 +1240,10 @@ pub(crate) async fn get_internal_ids_by_spotify_id<
 pub(crate) async fn populate_tracks_artists_table(
     conn: &DbConn,
     spotify_access_token: &str,
-) -> Result<(), String> {
+) -> Result<(), Error> {
     use crate::schema::{
         spotify_items::dsl::*,
-        track_rank_snapshots::{self, dsl::*},
+        track_rank_deltas::{self, dsl::*},
         tracks_artists::dsl::*,
     };
 
@@ -631,18 +1254,18 @@ pub(crate) async fn populate_tracks_artists_table(
     }
 
     // Get all unique track ids in the database mapped to their corresponding spotify IDs
-    let query = track_rank_snapshots
+    let query = track_rank_deltas
         .inner_join(spotify_items)
-        .select((track_rank_snapshots::mapped_spotify_id, spotify_id))
+        .select((track_rank_deltas::mapped_spotify_id, spotify_id))
         .distinct();
     let all_track_spotify_ids: Vec<Ids> = conn
         .run(move |conn| {
-            query.load::<Ids>(conn).map_err(|err| -> String {
+            query.load::<Ids>(conn).map_err(|err| {
                 error!(
                     "Unable to query distinct track spotify IDs from database: {:?}",
                     err
                 );
-                "Unable to query distinct track spotify IDs from database".into()
+                Error::Internal("Unable to query distinct track spotify IDs from database".into())
             })
         })
         .await?;
@@ -662,7 +1285,7 @@ pub(crate) async fn populate_tracks_artists_table(
 
     // Map returned artist spotify ids to internal artist ids
     let artist_spotify_ids: Vec<String> = tracks
-        .iter()
+        .values()
         .flat_map(|track| track.artists.iter().map(|artist| artist.id.clone()))
         .collect();
     let artist_internal_id_mapping =
@@ -671,8 +1294,8 @@ pub(crate) async fn populate_tracks_artists_table(
     // Insert mapping items for each of the (track, artist) pairs
     let pairs: Vec<TrackArtistPair> = tracks
         .iter()
-        .flat_map(|track| {
-            let track_internal_id = track_spotify_id_to_internal_id_mapping[&track.id];
+        .flat_map(|(track_spotify_id, track)| {
+            let track_internal_id = track_spotify_id_to_internal_id_mapping[track_spotify_id];
 
             track
                 .artists
@@ -684,20 +1307,16 @@ pub(crate) async fn populate_tracks_artists_table(
                 })
         })
         .collect();
-    conn.run(move |conn| {
-        diesel::insert_or_ignore_into(tracks_artists)
-            .values(&pairs)
-            .execute(conn)
-    })
-    .await
-    .map_err(|err| -> String {
-        error!(
-            "Error inserting artist/track pairs into mapping table: {:?}",
-            err
-        );
-        "Error inserting artist/track pairs into mapping table".into()
-    })
-    .map(|_| ())
+    conn.run(move |conn| insert_or_ignore!(tracks_artists, &pairs).execute(conn))
+        .await
+        .map_err(|err| {
+            error!(
+                "Error inserting artist/track pairs into mapping table: {:?}",
+                err
+            );
+            Error::Internal("Error inserting artist/track pairs into mapping table".into())
+        })
+        .map(|_| ())
 }
 
 pub(crate) async fn get_artist_spotify_ids_by_internal_id(
@@ -729,9 +1348,9 @@ pub(crate) async fn get_artist_spotify_ids_by_internal_id(
 pub(crate) async fn populate_artists_genres_table(
     conn: &DbConn,
     spotify_access_token: &str,
-) -> Result<(), String> {
+) -> Result<(), Error> {
     use crate::schema::{
-        artist_rank_snapshots::{self, dsl::*},
+        artist_rank_deltas::{self, dsl::*},
         artists_genres::dsl::*,
         spotify_items::dsl::*,
     };
@@ -743,16 +1362,16 @@ pub(crate) async fn populate_artists_genres_table(
     }
 
     // Get the full set of unique artist Spotify IDs for all stored updates
-    let query = artist_rank_snapshots
+    let query = artist_rank_deltas
         .inner_join(spotify_items)
-        .select((artist_rank_snapshots::mapped_spotify_id, spotify_id))
+        .select((artist_rank_deltas::mapped_spotify_id, spotify_id))
         .distinct();
     let all_artist_ids = conn
         .run(move |conn| query.load::<Ids>(conn))
         .await
-        .map_err(|err| -> String {
+        .map_err(|err| {
             error!("Error fetching all artist ids from database: {:?}", err);
-            "Error fetching all artist ids from database".into()
+            Error::Internal("Error fetching all artist ids from database".into())
         })?;
 
     let all_artist_spotify_ids = all_artist_ids
@@ -767,24 +1386,23 @@ pub(crate) async fn populate_artists_genres_table(
 
     // Fetch artist metadata for each of them
     // println!("{:?}", all_artist_spotify_ids);
-    let mut artists =
+    let artists =
         crate::spotify_api::fetch_artists(spotify_access_token, &all_artist_spotify_ids).await?;
-    artists.sort_unstable_by(|a, b| a.id.cmp(&b.id));
-    artists.dedup_by(|a, b| a.id == b.id);
 
     let pairs: Vec<ArtistGenrePair> = artists
         .into_iter()
-        .filter_map(|artist| {
-            let artist_internal_id: i32 = match artist_internal_id_by_spotify_id.get(&artist.id) {
-                Some(&artist_internal_id) => artist_internal_id,
-                None => {
-                    warn!(
-                        "No internal artist ID in mapping for artist with spotify id {}",
-                        artist.id
-                    );
-                    return None;
-                },
-            };
+        .filter_map(|(artist_spotify_id, artist)| {
+            let artist_internal_id: i32 =
+                match artist_internal_id_by_spotify_id.get(&artist_spotify_id) {
+                    Some(&artist_internal_id) => artist_internal_id,
+                    None => {
+                        warn!(
+                            "No internal artist ID in mapping for artist with spotify id {}",
+                            artist_spotify_id
+                        );
+                        return None;
+                    },
+                };
             Some((artist, artist_internal_id))
         })
         .flat_map(|(artist, artist_internal_id)| {
@@ -814,12 +1432,12 @@ pub(crate) async fn populate_artists_genres_table(
         })
     })
     .await
-    .map_err(|err| -> String {
+    .map_err(|err| {
         error!(
             "Error clearing + refreshing artists/genres mapping table: {:?}",
             err
         );
-        "Error clearing + refreshing artists/genres mapping table".into()
+        Error::Internal("Error clearing + refreshing artists/genres mapping table".into())
     })
     .map(|_| ())
 }
@@ -830,188 +1448,3321 @@ pub(crate) async fn update_user_last_updated(
     user: &User,
     conn: &DbConn,
     update_time: NaiveDateTime,
-) -> Result<usize, String> {
+) -> Result<usize, Error> {
     use crate::schema::users::dsl::*;
 
     let query = diesel::update(users.filter(id.eq(user.id))).set(last_update_time.eq(update_time));
     conn.run(move |conn| query.execute(conn))
         .await
-        .map_err(|err| -> String {
+        .map_err(|err| {
             error!("Error updating user's last update time: {:?}", err);
-            "Error updating user's last update time.".into()
+            Error::Internal("Error updating user's last update time.".into())
         })
 }
 
-pub(crate) async fn get_artist_timeline_events(
+/// Returns the Spotify IDs of all users that aren't deactivated, least recently updated first
+pub(crate) async fn get_active_user_spotify_ids(conn: &DbConn) -> QueryResult<Vec<String>> {
+    use crate::schema::users::dsl::*;
+
+    conn.run(move |conn| {
+        users
+            .filter(deactivated_at.is_null())
+            .order_by(last_update_time)
+            .select(spotify_id)
+            .load(conn)
+    })
+    .await
+}
+
+/// Returns the Spotify IDs of all users that are due for an update along with when they became due.
+/// Users in higher update tiers come first, and users within a tier are ordered from the longest
+/// overdue.  Users whose updates have failed permanently too many times in a row are excluded, as
+/// are users whose last update attempt failed and whose retry delay hasn't passed yet.
+pub(crate) async fn get_users_due_for_update(
     conn: &DbConn,
-    user_id: i64,
-    start_day: NaiveDateTime,
-    end_day: NaiveDateTime,
-) -> Result<Vec<(String, NaiveDateTime)>, diesel::result::Error> {
-    use crate::schema::{artists_users_first_seen, spotify_items};
+    now: NaiveDateTime,
+) -> Result<Vec<(String, NaiveDateTime)>, Error> {
+    use crate::schema::{user_settings, users::dsl::*};
 
-    let query = artists_users_first_seen::table
+    // Users without an override are updated at most as often as the most frequently updated tier,
+    // so they're filtered by their preferred frequency, tier, and override after loading
+    let min_tier_interval = [UpdateTier::Active, UpdateTier::Normal, UpdateTier::Dormant]
+        .iter()
+        .map(|tier| tier.update_interval())
+        .min()
+        .unwrap();
+    let query = users
+        .left_join(user_settings::table)
         .filter(
-            artists_users_first_seen::dsl::user_id.eq(user_id).and(
-                artists_users_first_seen::dsl::first_seen
-                    .ge(start_day)
-                    .and(artists_users_first_seen::dsl::first_seen.le(end_day)),
-            ),
+            last_update_time
+                .lt(now - min_tier_interval)
+                .or(min_update_interval_seconds.is_not_null()),
         )
-        .order_by(artists_users_first_seen::dsl::first_seen)
-        .inner_join(
-            spotify_items::table
-                .on(spotify_items::dsl::id.eq(artists_users_first_seen::dsl::mapped_spotify_id)),
+        .filter(consecutive_update_failures.lt(crate::conf::CONF.max_consecutive_update_failures))
+        .filter(
+            next_update_attempt_at
+                .is_null()
+                .or(next_update_attempt_at.le(now)),
         )
+        .filter(deactivated_at.is_null())
+        .order_by(last_update_time)
         .select((
-            spotify_items::dsl::spotify_id,
-            artists_users_first_seen::dsl::first_seen,
+            spotify_id,
+            last_update_time,
+            update_tier,
+            min_update_interval_seconds,
+            user_settings::dsl::update_frequency.nullable(),
         ));
-    conn.run(move |conn| query.load(conn)).await
+    let candidates: Vec<(String, NaiveDateTime, u8, Option<i32>, Option<u8>)> =
+        conn.run(move |conn| query.load(conn)).await?;
+
+    let mut due_users: Vec<(UpdateTier, String, NaiveDateTime)> = candidates
+        .into_iter()
+        .filter_map(
+            |(user_spotify_id, user_last_update_time, tier, override_seconds, frequency)| {
+                let tier = UpdateTier::from_id(tier);
+                let update_interval = tier.user_update_interval(override_seconds);
+                let frequency_interval = match frequency.map(UpdateFrequency::from_id) {
+                    None | Some(UpdateFrequency::Default) => update_interval,
+                    Some(UpdateFrequency::Daily) => chrono::Duration::days(1),
+                    Some(UpdateFrequency::Weekly) => chrono::Duration::weeks(1),
+                };
+                let due_at = user_last_update_time + frequency_interval.max(update_interval);
+                if due_at < now {
+                    Some((tier, user_spotify_id, due_at))
+                } else {
+                    None
+                }
+            },
+        )
+        .collect();
+    due_users.sort_by_key(|(tier, _, due_at)| (tier.id(), *due_at));
+
+    Ok(due_users
+        .into_iter()
+        .map(|(_, user_spotify_id, due_at)| (user_spotify_id, due_at))
+        .collect())
 }
 
-pub(crate) async fn get_track_timeline_events(
+/// Returns all users that signed up after `created_after` and haven't been deactivated
+pub(crate) async fn get_users_created_after(
+    conn: &DbConn,
+    created_after: NaiveDateTime,
+) -> QueryResult<Vec<User>> {
+    use crate::schema::users::dsl::*;
+
+    conn.run(move |conn| {
+        users
+            .filter(creation_time.gt(created_after))
+            .filter(deactivated_at.is_null())
+            .load(conn)
+    })
+    .await
+}
+
+/// Records an error encountered while updating a user.  Errors are kept around so that users whose
+/// updates are failing can be found via the admin user list.
+pub(crate) async fn record_update_error(
     conn: &DbConn,
     user_id: i64,
-    start_day: NaiveDateTime,
-    end_day: NaiveDateTime,
-) -> Result<Vec<(String, NaiveDateTime)>, diesel::result::Error> {
-    use crate::schema::{spotify_items, tracks_users_first_seen};
+    error_message: String,
+) -> QueryResult<usize> {
+    use crate::schema::update_errors;
 
-    let query = tracks_users_first_seen::table
-        .filter(
-            tracks_users_first_seen::dsl::user_id.eq(user_id).and(
-                tracks_users_first_seen::dsl::first_seen
-                    .ge(start_day)
-                    .and(tracks_users_first_seen::dsl::first_seen.le(end_day)),
-            ),
-        )
-        .order_by(tracks_users_first_seen::dsl::first_seen)
-        .inner_join(
-            spotify_items::table
-                .on(spotify_items::dsl::id.eq(tracks_users_first_seen::dsl::mapped_spotify_id)),
-        )
-        .select((
-            spotify_items::dsl::spotify_id,
-            tracks_users_first_seen::dsl::first_seen,
-        ));
-    conn.run(move |conn| query.load(conn)).await
+    let entry = NewUpdateError {
+        user_id,
+        error_time: Utc::now().naive_utc(),
+        error_message,
+    };
+    conn.run(move |conn| {
+        diesel::insert_into(update_errors::table)
+            .values(&entry)
+            .execute(conn)
+    })
+    .await
 }
 
-pub(crate) async fn get_all_top_tracks_for_user(
+/// Records a failed update attempt for the user, delaying their next automatic update by
+/// `update_retry_delay`.  Permanent failures also increment the user's consecutive update failure
+/// count, which is returned.
+pub(crate) async fn increment_update_failures(
     conn: &DbConn,
     user_id: i64,
-) -> Result<Vec<(i32, String)>, diesel::result::Error> {
-    use crate::schema::{spotify_items, tracks_users_first_seen};
+    permanent: bool,
+    now: NaiveDateTime,
+) -> QueryResult<i32> {
+    use crate::schema::users::dsl::*;
 
-    let query = tracks_users_first_seen::table
-        .filter(tracks_users_first_seen::dsl::user_id.eq(user_id))
-        .inner_join(
-            spotify_items::table
-                .on(spotify_items::dsl::id.eq(tracks_users_first_seen::dsl::mapped_spotify_id)),
-        )
-        .select((
-            tracks_users_first_seen::dsl::mapped_spotify_id,
-            spotify_items::dsl::spotify_id,
-        ))
-        .distinct();
-    conn.run(move |conn| query.load(conn)).await
+    conn.run(move |conn| {
+        conn.transaction(|| {
+            let attempts = users
+                .filter(id.eq(user_id))
+                .select(failed_update_attempts)
+                .first::<i32>(conn)?
+                + 1;
+            diesel::update(users.filter(id.eq(user_id)))
+                .set((
+                    failed_update_attempts.eq(attempts),
+                    next_update_attempt_at.eq(Some(now + update_retry_delay(attempts))),
+                    consecutive_update_failures
+                        .eq(consecutive_update_failures + if permanent { 1 } else { 0 }),
+                ))
+                .execute(conn)?;
+            users
+                .filter(id.eq(user_id))
+                .select(consecutive_update_failures)
+                .first(conn)
+        })
+    })
+    .await
 }
 
-pub(crate) async fn get_all_top_artists_for_user(
+/// Overrides the minimum time between automatic updates of the user, or removes the override if
+/// `interval_seconds` is `None`
+pub(crate) async fn set_min_update_interval_override(
     conn: &DbConn,
     user_id: i64,
-) -> Result<Vec<(i32, String)>, diesel::result::Error> {
-    use crate::schema::{artists_users_first_seen, spotify_items};
+    interval_seconds: Option<i32>,
+) -> QueryResult<usize> {
+    use crate::schema::users::dsl::*;
 
-    let query = artists_users_first_seen::table
-        .filter(artists_users_first_seen::dsl::user_id.eq(user_id))
-        .inner_join(
-            spotify_items::table
-                .on(spotify_items::dsl::id.eq(artists_users_first_seen::dsl::mapped_spotify_id)),
-        )
-        .select((
-            artists_users_first_seen::dsl::mapped_spotify_id,
-            spotify_items::dsl::spotify_id,
-        ))
-        .distinct();
-    conn.run(move |conn| query.load(conn)).await
+    conn.run(move |conn| {
+        diesel::update(users.filter(id.eq(user_id)))
+            .set(min_update_interval_seconds.eq(interval_seconds))
+            .execute(conn)
+    })
+    .await
+}
+
+/// Clears the user's update failure counts and retry delay
+pub(crate) async fn reset_update_failures(conn: &DbConn, user_id: i64) -> QueryResult<usize> {
+    use crate::schema::users::dsl::*;
+
+    conn.run(move |conn| {
+        diesel::update(users.filter(id.eq(user_id)))
+            .set((
+                consecutive_update_failures.eq(0),
+                failed_update_attempts.eq(0),
+                next_update_attempt_at.eq(None::<NaiveDateTime>),
+            ))
+            .execute(conn)
+    })
+    .await
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum AdminUserFilter {
+    /// Users that haven't been updated since the provided cutoff
+    Stale(NaiveDateTime),
+    /// Users whose most recent update attempt failed
+    Failing,
 }
 
-pub(crate) async fn refresh_user_access_token(
+#[derive(QueryableByName)]
+struct AdminUserListQueryResItem {
+    #[sql_type = "diesel::sql_types::Text"]
+    spotify_id: String,
+    #[sql_type = "crate::db_backend::sql_types::Datetime"]
+    last_update_time: NaiveDateTime,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    snapshot_count: i64,
+    #[sql_type = "diesel::sql_types::Integer"]
+    consecutive_update_failures: i32,
+    #[sql_type = "diesel::sql_types::Nullable<crate::db_backend::sql_types::Datetime>"]
+    error_time: Option<NaiveDateTime>,
+    #[sql_type = "diesel::sql_types::Nullable<diesel::sql_types::Text>"]
+    error_message: Option<String>,
+}
+
+/// Sort key of the admin user list, which is used as its cursor
+pub(crate) type AdminUserListCursor = (NaiveDateTime, String);
+
+/// Lists users along with their update health, least recently updated first, with ties broken by
+/// Spotify ID.  Snapshot counts only include snapshots stored in the database, not those moved to
+/// external storage.
+pub(crate) async fn get_admin_user_list_page(
     conn: &DbConn,
-    user: &mut User,
-) -> Result<Option<status::Custom<String>>, String> {
-    use crate::schema::users;
+    filter: Option<AdminUserFilter>,
+    pagination: CursorPagination<AdminUserListCursor>,
+) -> QueryResult<Paginated<AdminUserListItem>> {
+    use crate::db_backend::sql_types::{BigInt, Bool, Datetime, Text};
 
-    // Update the access token for that user using the refresh token
-    let updated_access_token =
-        match crate::spotify_api::refresh_user_token(&user.refresh_token).await {
-            Ok(updated_access_token) => updated_access_token,
-            Err(_) => {
-                update_user_last_updated(&user, &conn, Utc::now().naive_utc()).await?;
+    let (include_fresh, stale_cutoff) = match filter {
+        Some(AdminUserFilter::Stale(cutoff)) => (false, cutoff),
+        _ => (true, Utc::now().naive_utc()),
+    };
+    let include_healthy = filter != Some(AdminUserFilter::Failing);
+    let (after_last_update_time, after_spotify_id) = pagination
+        .after
+        .clone()
+        .unwrap_or_else(|| (Utc::now().naive_utc(), String::new()));
+    // Filters are disabled by setting the corresponding `include_*` or `is_first_page` param to
+    // `TRUE`
+    let query = diesel::sql_query(portable_sql(
+        "SELECT `users`.`spotify_id`, `users`.`last_update_time`, (SELECT COUNT(DISTINCT \
+         `update_time`) FROM `snapshot_updates` WHERE `snapshot_updates`.`user_id` = \
+         `users`.`id`) AS `snapshot_count`, `users`.`consecutive_update_failures`, \
+         `latest_error`.`error_time`, `latest_error`.`error_message` FROM `users` LEFT JOIN \
+         `update_errors` AS `latest_error` ON `latest_error`.`id` = (SELECT MAX(`id`) FROM \
+         `update_errors` WHERE `update_errors`.`user_id` = `users`.`id`) WHERE (? OR \
+         `users`.`last_update_time` < ?) AND (? OR `latest_error`.`error_time` >= \
+         `users`.`last_update_time`) AND (? OR `users`.`last_update_time` > ? OR \
+         (`users`.`last_update_time` = ? AND `users`.`spotify_id` > ?)) ORDER BY \
+         `users`.`last_update_time`, `users`.`spotify_id` LIMIT ?",
+    ))
+    .bind::<Bool, _>(include_fresh)
+    .bind::<Datetime, _>(stale_cutoff)
+    .bind::<Bool, _>(include_healthy)
+    .bind::<Bool, _>(pagination.after.is_none())
+    .bind::<Datetime, _>(after_last_update_time)
+    .bind::<Datetime, _>(after_last_update_time)
+    .bind::<Text, _>(after_spotify_id)
+    .bind::<BigInt, _>(pagination.fetch_limit());
 
-                // TODO: Disable auto-updates for the user that has removed their permission grant
-                // to prevent wasted updates in the future
-                let msg = format!(
-                    "Failed to refresh user token for user {}; updating last updated timestamp \
-                     and not updating.",
-                    user.username
-                );
-                info!("{}", msg);
-                return Ok(Some(status::Custom(Status::Unauthorized, msg)));
-            },
+    conn.run(move |conn| {
+        let items = query.load::<AdminUserListQueryResItem>(conn)?;
+        let items = items
+            .into_iter()
+            .map(|item| {
+                let last_update_error = match (item.error_time, item.error_message) {
+                    (Some(error_time), Some(error_message)) => Some(UpdateError {
+                        error_time,
+                        error_message,
+                    }),
+                    _ => None,
+                };
+                AdminUserListItem {
+                    failing: last_update_error
+                        .as_ref()
+                        .map(|err| err.error_time >= item.last_update_time)
+                        .unwrap_or(false),
+                    spotify_id: item.spotify_id,
+                    last_update_time: item.last_update_time,
+                    snapshot_count: item.snapshot_count,
+                    consecutive_update_failures: item.consecutive_update_failures,
+                    last_update_error,
+                }
+            })
+            .collect();
+        Ok(pagination.into_page(items, |item: &AdminUserListItem| {
+            (item.last_update_time, item.spotify_id.clone())
+        }))
+    })
+    .await
+}
+
+/// Encrypts the stored tokens of every user whose tokens were written before encryption was
+/// enabled, in batches of `batch_size` users.  Returns the number of users whose tokens were
+/// encrypted.
+pub(crate) async fn encrypt_stored_tokens(conn: &DbConn, batch_size: i64) -> QueryResult<usize> {
+    use crate::schema::users::dsl::*;
+
+    let mut encrypted_count = 0;
+    let mut last_id = 0i64;
+    loop {
+        // Tokens are selected as raw strings so that they aren't decrypted
+        let batch: Vec<(i64, String, String)> = conn
+            .run(move |conn| {
+                users
+                    .filter(id.gt(last_id))
+                    .order_by(id.asc())
+                    .select((id, token, refresh_token))
+                    .limit(batch_size)
+                    .load(conn)
+            })
+            .await?;
+        last_id = match batch.last() {
+            Some((user_id, ..)) => *user_id,
+            None => break,
         };
-    let query = diesel::update(users::table.filter(users::dsl::id.eq(user.id)))
-        .set(users::dsl::token.eq(updated_access_token.clone()));
+
+        let plaintext_rows: Vec<(i64, String, String)> = batch
+            .into_iter()
+            .filter(|(_, stored_token, stored_refresh_token)| {
+                !crate::token_encryption::is_encrypted(stored_token)
+                    || !crate::token_encryption::is_encrypted(stored_refresh_token)
+            })
+            .collect();
+        encrypted_count += plaintext_rows.len();
+        conn.run(move |conn| {
+            conn.transaction(|| -> QueryResult<()> {
+                for (user_id, stored_token, stored_refresh_token) in plaintext_rows {
+                    // Decrypting first handles rows where only one of the tokens is encrypted
+                    let decrypt = |stored: &str| {
+                        crate::token_encryption::decrypt_token(stored)
+                            .map_err(|err| diesel::result::Error::DeserializationError(err.into()))
+                    };
+                    diesel::update(users.filter(id.eq(user_id)))
+                        .set((
+                            token.eq(StoredToken(decrypt(&stored_token)?)),
+                            refresh_token.eq(StoredToken(decrypt(&stored_refresh_token)?)),
+                        ))
+                        .execute(conn)?;
+                }
+                Ok(())
+            })
+        })
+        .await?;
+    }
+
+    Ok(encrypted_count)
+}
+
+/// Updates the stored tokens and display name for a user that has gone through the OAuth flow again
+/// after already having been registered, marking them as freshly updated.  Deactivated users stay
+/// deactivated until they reactivate themselves via `/users/<username>/deactivation`.
+pub(crate) async fn update_reauthorized_user(
+    conn: &DbConn,
+    user_spotify_id: String,
+    new_username: String,
+    access_token: String,
+    new_refresh_token: String,
+) -> Result<(), Error> {
+    use crate::schema::users::dsl::*;
+
+    let query = diesel::update(users.filter(spotify_id.eq(user_spotify_id.clone()))).set((
+        token.eq(StoredToken(access_token)),
+        refresh_token.eq(StoredToken(new_refresh_token)),
+        username.eq(new_username),
+        last_update_time.eq(Utc::now().naive_utc()),
+    ));
     conn.run(move |conn| query.execute(conn))
         .await
-        .map_err(|err| -> String {
-            error!("{:?}", err);
-            "Error updating user with new access token".into()
+        .map_err(|err| {
+            error!(
+                "Error updating tokens for user id={}: {:?}",
+                user_spotify_id, err
+            );
+            Error::Internal("Internal error occurred when trying to update user".into())
         })?;
-    user.token = updated_access_token;
 
-    Ok(None)
+    Ok(())
 }
 
-pub(crate) async fn insert_related_artists(
-    conn: &DbConn,
-    related_artists: Vec<NewRelatedArtistEntry>,
-) -> QueryResult<()> {
-    use crate::schema::related_artists;
+/// Maps `(timeframe, ranking)` to the mapped Spotify ID of the entity at that ranking in a snapshot
+pub(crate) type SnapshotRankings = HashMap<(Timeframe, u8), i32>;
 
-    let all_artist_ids: Vec<i32> = related_artists
-        .iter()
-        .map(|entry| entry.artist_spotify_id)
-        .collect();
+/// Returns the update time of the user's oldest stored snapshot, if they have any
+pub(crate) async fn get_first_snapshot_time(
+    conn: &DbConn,
+    user_id: i64,
+) -> QueryResult<Option<NaiveDateTime>> {
+    use crate::schema::snapshot_updates;
 
     conn.run(move |conn| {
-        conn.transaction(|| -> QueryResult<()> {
-            diesel::delete(
-                related_artists::table
-                    .filter(related_artists::dsl::artist_spotify_id.eq_any(all_artist_ids)),
-            )
-            .execute(conn)?;
+        snapshot_updates::table
+            .filter(snapshot_updates::dsl::user_id.eq(user_id))
+            .select(snapshot_updates::dsl::update_time)
+            .order_by(snapshot_updates::dsl::update_time.asc())
+            .first(conn)
+            .optional()
+    })
+    .await
+}
 
-            diesel::insert_into(related_artists::table)
-                .values(related_artists)
-                .execute(conn)?;
+/// Returns the diversity scores stored with all of the user's snapshots, oldest first
+pub(crate) async fn get_diversity_scores(
+    conn: &DbConn,
+    user_id: i64,
+) -> QueryResult<Vec<DiversityScoreEntry>> {
+    use crate::schema::diversity_scores;
 
-            Ok(())
-        })
+    conn.run(move |conn| {
+        diversity_scores::table
+            .filter(diversity_scores::dsl::user_id.eq(user_id))
+            .order_by(diversity_scores::dsl::update_time.asc())
+            .load(conn)
     })
-    .await?;
+    .await
+}
 
-    Ok(())
+#[derive(QueryableByName)]
+pub(crate) struct PopularityHistoryQueryResItem {
+    #[sql_type = "diesel::sql_types::Timestamp"]
+    pub update_time: NaiveDateTime,
+    #[sql_type = "crate::db_backend::sql_types::Unsigned<diesel::sql_types::TinyInt>"]
+    pub timeframe: Timeframe,
+    #[sql_type = "crate::db_backend::sql_types::Unsigned<diesel::sql_types::TinyInt>"]
+    pub entity_type: u8,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    pub entity_count: i64,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    pub popularity_sum: i64,
 }
 
-pub(crate) async fn update_user_last_viewed(user: &User, conn: &DbConn) -> QueryResult<()> {
-    use crate::schema::users;
+/// Returns the number of top entities with a known popularity and the sum of their popularities
+/// for each of the user's snapshots, timeframes, and entity types, oldest first
+pub(crate) async fn get_popularity_history(
+    conn: &DbConn,
+    user_id: i64,
+) -> QueryResult<Vec<PopularityHistoryQueryResItem>> {
+    let query = diesel::sql_query(portable_sql(&format!(
+        "SELECT `update_time`, `timeframe`, `entity_type`, COUNT(*) AS `entity_count`, \
+         CAST(SUM(`popularity`) AS {signed}) AS `popularity_sum` FROM `popularity_history` WHERE \
+         `user_id` = ? GROUP BY `update_time`, `timeframe`, `entity_type` ORDER BY `update_time` \
+         ASC",
+        signed = SIGNED_BIGINT,
+    )))
+    .bind::<diesel::sql_types::BigInt, _>(user_id);
 
-    let query = diesel::update(users::table.filter(users::dsl::id.eq(user.id)))
-        .set(users::dsl::last_viewed.eq(diesel::dsl::now));
-    conn.run(move |conn| query.execute(conn)).await?;
+    conn.run(move |conn| query.load(conn)).await
+}
 
-    Ok(())
+/// `(update_time, timeframe, entity_type, ranking, popularity)` of a stored popularity score
+pub(crate) type PopularityRankingRow = (NaiveDateTime, Timeframe, u8, u8, u8);
+
+/// Returns the popularity scores of the top entities of all of the user's snapshots, oldest first
+pub(crate) async fn get_popularity_rankings(
+    conn: &DbConn,
+    user_id: i64,
+) -> QueryResult<Vec<PopularityRankingRow>> {
+    use crate::schema::popularity_history;
+
+    conn.run(move |conn| {
+        popularity_history::table
+            .filter(popularity_history::dsl::user_id.eq(user_id))
+            .order_by(popularity_history::dsl::update_time.asc())
+            .select((
+                popularity_history::dsl::update_time,
+                popularity_history::dsl::timeframe,
+                popularity_history::dsl::entity_type,
+                popularity_history::dsl::ranking,
+                popularity_history::dsl::popularity,
+            ))
+            .load(conn)
+    })
+    .await
+}
+
+/// Returns the popularity scores of the top entities of the user's most recent snapshot with
+/// popularity scores stored
+pub(crate) async fn get_latest_popularity_rankings(
+    conn: &DbConn,
+    user_id: i64,
+) -> QueryResult<Vec<PopularityRankingRow>> {
+    use crate::schema::popularity_history;
+
+    conn.run(move |conn| {
+        let latest_update_time: Option<NaiveDateTime> = popularity_history::table
+            .filter(popularity_history::dsl::user_id.eq(user_id))
+            .select(popularity_history::dsl::update_time)
+            .order_by(popularity_history::dsl::update_time.desc())
+            .first(conn)
+            .optional()?;
+        let latest_update_time = match latest_update_time {
+            Some(latest_update_time) => latest_update_time,
+            None => return Ok(Vec::new()),
+        };
+
+        popularity_history::table
+            .filter(
+                popularity_history::dsl::user_id
+                    .eq(user_id)
+                    .and(popularity_history::dsl::update_time.eq(latest_update_time)),
+            )
+            .select((
+                popularity_history::dsl::update_time,
+                popularity_history::dsl::timeframe,
+                popularity_history::dsl::entity_type,
+                popularity_history::dsl::ranking,
+                popularity_history::dsl::popularity,
+            ))
+            .load(conn)
+    })
+    .await
+}
+
+/// Returns the genre weights stored with all of the user's snapshots for a timeframe as
+/// `(update_time, genre, weight)`, oldest first
+pub(crate) async fn get_genre_weights(
+    conn: &DbConn,
+    user_id: i64,
+    timeframe: Timeframe,
+) -> QueryResult<Vec<(NaiveDateTime, String, u32)>> {
+    use crate::schema::genre_weights;
+
+    conn.run(move |conn| {
+        genre_weights::table
+            .filter(genre_weights::dsl::user_id.eq(user_id))
+            .filter(genre_weights::dsl::timeframe.eq(timeframe))
+            .order_by(genre_weights::dsl::update_time.asc())
+            .select((
+                genre_weights::dsl::update_time,
+                genre_weights::dsl::genre,
+                genre_weights::dsl::weight,
+            ))
+            .load(conn)
+    })
+    .await
+}
+
+/// Stores snapshots synthesized from imported listening history.  They're stored in full rather
+/// than delta-encoded since they predate the user's other snapshots, and the user's last update
+/// time is left unchanged.
+pub(crate) async fn store_synthetic_snapshots(
+    conn: &DbConn,
+    artist_entries: Vec<NewArtistHistoryEntry>,
+    track_entries: Vec<NewTrackHistoryEntry>,
+    track_artist_pairs: Vec<TrackArtistPair>,
+) -> QueryResult<()> {
+    use crate::schema::{artist_rank_deltas, track_rank_deltas, tracks_artists};
+
+    let snapshot_updates = count_snapshot_entries(
+        artist_entries.iter().map(|entry| {
+            (
+                entry.user_id,
+                entry.update_time,
+                entry.timeframe,
+                entry.ranking,
+            )
+        }),
+        track_entries.iter().map(|entry| {
+            (
+                entry.user_id,
+                entry.update_time,
+                entry.timeframe,
+                entry.ranking,
+            )
+        }),
+    );
+    conn.run(move |conn| {
+        conn.transaction(|| {
+            for chunk in artist_entries.chunks(1000) {
+                upsert!(
+                    artist_rank_deltas::table,
+                    chunk,
+                    (
+                        artist_rank_deltas::user_id,
+                        artist_rank_deltas::update_time,
+                        artist_rank_deltas::timeframe,
+                        artist_rank_deltas::ranking,
+                    ),
+                    (artist_rank_deltas::mapped_spotify_id)
+                )
+                .execute(conn)?;
+            }
+            for chunk in track_entries.chunks(1000) {
+                upsert!(
+                    track_rank_deltas::table,
+                    chunk,
+                    (
+                        track_rank_deltas::user_id,
+                        track_rank_deltas::update_time,
+                        track_rank_deltas::timeframe,
+                        track_rank_deltas::ranking,
+                    ),
+                    (track_rank_deltas::mapped_spotify_id)
+                )
+                .execute(conn)?;
+            }
+            merge_snapshot_updates(conn, &snapshot_updates)?;
+            for chunk in track_artist_pairs.chunks(1000) {
+                insert_or_ignore!(tracks_artists::table, chunk).execute(conn)?;
+            }
+            Ok(())
+        })
+    })
+    .await
+}
+
+/// Returns the artist and track rankings of the user's most recent snapshot from before `before`,
+/// materialized from its deltas.  Both are empty if the user has no such snapshot.
+///
+/// This is the snapshot that one stored at `before` is delta-encoded against, so it must be read in
+/// the same transaction that stores the new deltas while holding a lock on the user's row.
+/// Otherwise concurrent updates of the user could both encode against the same snapshot.
+pub(crate) fn load_previous_snapshot_rankings(
+    conn: &DbConnection,
+    user_id: i64,
+    before: NaiveDateTime,
+) -> QueryResult<(SnapshotRankings, SnapshotRankings)> {
+    use crate::schema::snapshot_updates;
+
+    let latest_update_time: Option<NaiveDateTime> = last_snapshot_time_query(user_id)
+        .filter(snapshot_updates::dsl::update_time.lt(before))
+        .first(conn)
+        .optional()?;
+    let latest_update_time = match latest_update_time {
+        Some(latest_update_time) => latest_update_time,
+        None => return Ok(Default::default()),
+    };
+
+    Ok((
+        load_snapshot_rankings(conn, user_id, ExportEntity::Artists, latest_update_time)?,
+        load_snapshot_rankings(conn, user_id, ExportEntity::Tracks, latest_update_time)?,
+    ))
+}
+
+/// Returns the rankings of the user's artist or track snapshot at `update_time`.  That's normally
+/// their latest snapshot, which is read from `latest_artist_stats` or `latest_track_stats` where
+/// it's stored in full.  Older snapshots, such as when backfilling, are resolved from their deltas.
+fn load_snapshot_rankings(
+    conn: &DbConnection,
+    user_id: i64,
+    entity: ExportEntity,
+    update_time: NaiveDateTime,
+) -> QueryResult<SnapshotRankings> {
+    use crate::schema::{latest_artist_stats, latest_track_stats};
+
+    let latest_rankings: Vec<(Timeframe, u8, i32, NaiveDateTime)> = match entity {
+        ExportEntity::Artists => latest_artist_stats::table
+            .filter(latest_artist_stats::dsl::user_id.eq(user_id))
+            .select((
+                latest_artist_stats::dsl::timeframe,
+                latest_artist_stats::dsl::ranking,
+                latest_artist_stats::dsl::mapped_spotify_id,
+                latest_artist_stats::dsl::update_time,
+            ))
+            .load(conn)?,
+        ExportEntity::Tracks => latest_track_stats::table
+            .filter(latest_track_stats::dsl::user_id.eq(user_id))
+            .select((
+                latest_track_stats::dsl::timeframe,
+                latest_track_stats::dsl::ranking,
+                latest_track_stats::dsl::mapped_spotify_id,
+                latest_track_stats::dsl::update_time,
+            ))
+            .load(conn)?,
+    };
+    let is_latest = !latest_rankings.is_empty()
+        && latest_rankings
+            .iter()
+            .all(|&(_, _, _, latest_update_time)| latest_update_time == update_time);
+    if is_latest {
+        return Ok(latest_rankings
+            .into_iter()
+            .map(|(timeframe, ranking, mapped_spotify_id, _)| {
+                ((timeframe, ranking), mapped_spotify_id)
+            })
+            .collect());
+    }
+
+    Ok(load_snapshot_at(conn, user_id, entity, update_time)?
+        .into_iter()
+        .map(|entry| ((entry.timeframe, entry.ranking), entry.mapped_spotify_id))
+        .collect())
+}
+
+/// Drops the entries whose ranking holds the same entity as it did in `previous`.  When the
+/// snapshot is read back, those rankings are filled in from the previous snapshot's rows.
+pub(crate) fn retain_changed_rankings<T>(
+    entries: &mut Vec<T>,
+    previous: &SnapshotRankings,
+    get_ranking: impl Fn(&T) -> (Timeframe, u8, i32),
+) {
+    entries.retain(|entry| {
+        let (timeframe, ranking, mapped_spotify_id) = get_ranking(entry);
+        previous.get(&(timeframe, ranking)) != Some(&mapped_spotify_id)
+    });
+}
+
+/// Computes the number of entries in each timeframe of full snapshots from their
+/// `(user_id, update_time, timeframe, ranking)` rows.  Rankings are contiguous from 0, so the
+/// highest one is used rather than counting rows; that allows counts for a snapshot whose rows are
+/// split across multiple calls to be combined with `merge_snapshot_updates`.
+pub(crate) fn count_snapshot_entries(
+    artist_rows: impl Iterator<Item = (i64, NaiveDateTime, Timeframe, u8)>,
+    track_rows: impl Iterator<Item = (i64, NaiveDateTime, Timeframe, u8)>,
+) -> Vec<SnapshotUpdate> {
+    let mut counts: BTreeMap<(i64, NaiveDateTime, Timeframe), (u8, u8)> = BTreeMap::new();
+    for (user_id, update_time, timeframe, ranking) in artist_rows {
+        let (artist_count, _) = counts.entry((user_id, update_time, timeframe)).or_default();
+        *artist_count = (*artist_count).max(ranking + 1);
+    }
+    for (user_id, update_time, timeframe, ranking) in track_rows {
+        let (_, track_count) = counts.entry((user_id, update_time, timeframe)).or_default();
+        *track_count = (*track_count).max(ranking + 1);
+    }
+
+    counts
+        .into_iter()
+        .map(
+            |((user_id, update_time, timeframe), (artist_count, track_count))| SnapshotUpdate {
+                user_id,
+                update_time,
+                timeframe,
+                artist_count,
+                track_count,
+            },
+        )
+        .collect()
+}
+
+#[cfg(feature = "mysql")]
+const SNAPSHOT_UPDATE_MERGE_CLAUSE: &str =
+    "ON DUPLICATE KEY UPDATE `artist_count` = GREATEST(`artist_count`, VALUES(`artist_count`)), \
+     `track_count` = GREATEST(`track_count`, VALUES(`track_count`))";
+#[cfg(feature = "postgres")]
+const SNAPSHOT_UPDATE_MERGE_CLAUSE: &str =
+    "ON CONFLICT (`user_id`, `update_time`, `timeframe`) DO UPDATE SET `artist_count` = \
+     GREATEST(`snapshot_updates`.`artist_count`, EXCLUDED.`artist_count`), `track_count` = \
+     GREATEST(`snapshot_updates`.`track_count`, EXCLUDED.`track_count`)";
+
+/// Records entry counts for stored snapshots.  If counts are already recorded for a snapshot, the
+/// larger of the two is kept.
+pub(crate) fn merge_snapshot_updates(
+    conn: &DbConnection,
+    updates: &[SnapshotUpdate],
+) -> QueryResult<usize> {
+    let mut updated_count = 0;
+    for chunk in updates.chunks(500) {
+        let values = chunk
+            .iter()
+            .map(|update| {
+                format!(
+                    "({}, '{}', {}, {}, {})",
+                    update.user_id,
+                    update.update_time.format("%Y-%m-%d %H:%M:%S"),
+                    update.timeframe.id(),
+                    update.artist_count,
+                    update.track_count
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        updated_count += diesel::sql_query(portable_sql(&format!(
+            "INSERT INTO `snapshot_updates` (`user_id`, `update_time`, `timeframe`, \
+             `artist_count`, `track_count`) VALUES {} {}",
+            values, SNAPSHOT_UPDATE_MERGE_CLAUSE
+        )))
+        .execute(conn)?;
+    }
+    Ok(updated_count)
+}
+
+/// Replaces the user's rows in `latest_artist_stats` and `latest_track_stats` with the rankings of
+/// a newly stored snapshot.  They're left alone if a more recent snapshot is stored there already.
+/// This must be run in the same transaction that stores the snapshot.
+pub(crate) fn replace_latest_stats(
+    conn: &DbConnection,
+    user_id: i64,
+    update_time: NaiveDateTime,
+    artist_entries: &[LatestStatsEntry],
+    track_entries: &[LatestStatsEntry],
+) -> QueryResult<()> {
+    use crate::schema::{latest_artist_stats, latest_track_stats};
+
+    let newer_artist_count: i64 = latest_artist_stats::table
+        .filter(latest_artist_stats::dsl::user_id.eq(user_id))
+        .filter(latest_artist_stats::dsl::update_time.gt(update_time))
+        .count()
+        .get_result(conn)?;
+    let newer_track_count: i64 = latest_track_stats::table
+        .filter(latest_track_stats::dsl::user_id.eq(user_id))
+        .filter(latest_track_stats::dsl::update_time.gt(update_time))
+        .count()
+        .get_result(conn)?;
+    if newer_artist_count + newer_track_count > 0 {
+        return Ok(());
+    }
+
+    diesel::delete(
+        latest_artist_stats::table.filter(latest_artist_stats::dsl::user_id.eq(user_id)),
+    )
+    .execute(conn)?;
+    diesel::delete(latest_track_stats::table.filter(latest_track_stats::dsl::user_id.eq(user_id)))
+        .execute(conn)?;
+    diesel::insert_into(latest_artist_stats::table)
+        .values(artist_entries)
+        .execute(conn)?;
+    diesel::insert_into(latest_track_stats::table)
+        .values(track_entries)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Rebuilds the user's rows in `latest_artist_stats` and `latest_track_stats` from their most
+/// recent remaining snapshots.  Used after snapshots are deleted, which might include the latest.
+fn rebuild_latest_stats(conn: &DbConnection, user_id: i64) -> QueryResult<()> {
+    use crate::schema::{latest_artist_stats, latest_track_stats};
+
+    diesel::delete(
+        latest_artist_stats::table.filter(latest_artist_stats::dsl::user_id.eq(user_id)),
+    )
+    .execute(conn)?;
+    diesel::delete(latest_track_stats::table.filter(latest_track_stats::dsl::user_id.eq(user_id)))
+        .execute(conn)?;
+
+    for entity in [ExportEntity::Artists, ExportEntity::Tracks] {
+        let update_time = match load_snapshot_update_times(
+            conn,
+            user_id,
+            entity == ExportEntity::Artists,
+        )?
+        .last()
+        {
+            Some(&update_time) => update_time,
+            None => continue,
+        };
+        let entries: Vec<LatestStatsEntry> = load_snapshot_at(conn, user_id, entity, update_time)?
+            .into_iter()
+            .map(|entry| LatestStatsEntry {
+                user_id,
+                timeframe: entry.timeframe,
+                ranking: entry.ranking,
+                mapped_spotify_id: entry.mapped_spotify_id,
+                update_time,
+            })
+            .collect();
+        match entity {
+            ExportEntity::Artists => diesel::insert_into(latest_artist_stats::table)
+                .values(&entries)
+                .execute(conn)?,
+            ExportEntity::Tracks => diesel::insert_into(latest_track_stats::table)
+                .values(&entries)
+                .execute(conn)?,
+        };
+    }
+    Ok(())
+}
+
+/// Returns the distinct update times of all of the user's artist and track snapshots stored in the
+/// database, sorted in ascending order.
+pub(crate) async fn get_snapshot_update_times(
+    conn: &DbConn,
+    user_id: i64,
+) -> QueryResult<(Vec<NaiveDateTime>, Vec<NaiveDateTime>)> {
+    conn.run(move |conn| {
+        Ok((
+            load_snapshot_update_times(conn, user_id, true)?,
+            load_snapshot_update_times(conn, user_id, false)?,
+        ))
+    })
+    .await
+}
+
+fn load_snapshot_update_times(
+    conn: &DbConnection,
+    user_id: i64,
+    artists: bool,
+) -> QueryResult<Vec<NaiveDateTime>> {
+    use crate::schema::snapshot_updates;
+
+    let query = snapshot_updates::table
+        .filter(snapshot_updates::dsl::user_id.eq(user_id))
+        .select(snapshot_updates::dsl::update_time)
+        .distinct()
+        .order_by(snapshot_updates::dsl::update_time);
+    if artists {
+        query
+            .filter(snapshot_updates::dsl::artist_count.gt(bind(0u8)))
+            .load(conn)
+    } else {
+        query
+            .filter(snapshot_updates::dsl::track_count.gt(bind(0u8)))
+            .load(conn)
+    }
+}
+
+/// Given all of a user's update times sorted in ascending order, returns the ones that aren't
+/// being deleted but directly follow one that is.  Since snapshots are delta-encoded, those
+/// snapshots may depend on rows from deleted ones and must be stored in full before deleting.
+fn get_update_times_to_materialize(
+    update_times: &[NaiveDateTime],
+    to_delete: &HashSet<NaiveDateTime>,
+) -> Vec<NaiveDateTime> {
+    update_times
+        .windows(2)
+        .filter(|pair| to_delete.contains(&pair[0]) && !to_delete.contains(&pair[1]))
+        .map(|pair| pair[1])
+        .collect()
+}
+
+/// Deletes all of the user's artist and track snapshots with the provided update times, along with
+/// the genre weights, popularity scores, and diversity scores recorded with them.  Returns the
+/// total number of deleted rows.
+pub(crate) async fn delete_snapshots(
+    conn: &DbConn,
+    user_id: i64,
+    artist_update_times: Vec<NaiveDateTime>,
+    track_update_times: Vec<NaiveDateTime>,
+) -> QueryResult<usize> {
+    use crate::schema::{
+        artist_rank_deltas, diversity_scores, genre_weights, popularity_history, snapshot_updates,
+        track_rank_deltas,
+    };
+
+    conn.run(move |conn| {
+        conn.transaction(|| -> QueryResult<usize> {
+            let artist_times_to_delete: HashSet<NaiveDateTime> =
+                artist_update_times.iter().copied().collect();
+            for update_time in get_update_times_to_materialize(
+                &load_snapshot_update_times(conn, user_id, true)?,
+                &artist_times_to_delete,
+            ) {
+                let entries: Vec<NewArtistHistoryEntry> =
+                    load_snapshot_at(conn, user_id, ExportEntity::Artists, update_time)?
+                        .into_iter()
+                        .map(|entry| NewArtistHistoryEntry {
+                            user_id,
+                            mapped_spotify_id: entry.mapped_spotify_id,
+                            update_time,
+                            timeframe: entry.timeframe,
+                            ranking: entry.ranking,
+                        })
+                        .collect();
+                insert_or_ignore!(artist_rank_deltas::table, &entries).execute(conn)?;
+            }
+
+            let track_times_to_delete: HashSet<NaiveDateTime> =
+                track_update_times.iter().copied().collect();
+            for update_time in get_update_times_to_materialize(
+                &load_snapshot_update_times(conn, user_id, false)?,
+                &track_times_to_delete,
+            ) {
+                let entries: Vec<NewTrackHistoryEntry> =
+                    load_snapshot_at(conn, user_id, ExportEntity::Tracks, update_time)?
+                        .into_iter()
+                        .map(|entry| NewTrackHistoryEntry {
+                            user_id,
+                            mapped_spotify_id: entry.mapped_spotify_id,
+                            update_time,
+                            timeframe: entry.timeframe,
+                            ranking: entry.ranking,
+                        })
+                        .collect();
+                insert_or_ignore!(track_rank_deltas::table, &entries).execute(conn)?;
+            }
+
+            let mut deleted_count = 0;
+            for chunk in artist_update_times.chunks(500) {
+                deleted_count += diesel::delete(
+                    artist_rank_deltas::table.filter(
+                        artist_rank_deltas::dsl::user_id
+                            .eq(user_id)
+                            .and(artist_rank_deltas::dsl::update_time.eq_any(chunk)),
+                    ),
+                )
+                .execute(conn)?;
+                diesel::update(
+                    snapshot_updates::table.filter(
+                        snapshot_updates::dsl::user_id
+                            .eq(user_id)
+                            .and(snapshot_updates::dsl::update_time.eq_any(chunk)),
+                    ),
+                )
+                .set(snapshot_updates::dsl::artist_count.eq(bind(0u8)))
+                .execute(conn)?;
+                diesel::delete(
+                    genre_weights::table.filter(
+                        genre_weights::dsl::user_id
+                            .eq(user_id)
+                            .and(genre_weights::dsl::update_time.eq_any(chunk)),
+                    ),
+                )
+                .execute(conn)?;
+                diesel::delete(
+                    popularity_history::table.filter(
+                        popularity_history::dsl::user_id
+                            .eq(user_id)
+                            .and(popularity_history::dsl::update_time.eq_any(chunk))
+                            .and(
+                                popularity_history::dsl::entity_type
+                                    .eq(bind(entity_type_code(ExportEntity::Artists))),
+                            ),
+                    ),
+                )
+                .execute(conn)?;
+            }
+            for chunk in track_update_times.chunks(500) {
+                deleted_count += diesel::delete(
+                    track_rank_deltas::table.filter(
+                        track_rank_deltas::dsl::user_id
+                            .eq(user_id)
+                            .and(track_rank_deltas::dsl::update_time.eq_any(chunk)),
+                    ),
+                )
+                .execute(conn)?;
+                diesel::update(
+                    snapshot_updates::table.filter(
+                        snapshot_updates::dsl::user_id
+                            .eq(user_id)
+                            .and(snapshot_updates::dsl::update_time.eq_any(chunk)),
+                    ),
+                )
+                .set(snapshot_updates::dsl::track_count.eq(bind(0u8)))
+                .execute(conn)?;
+                diesel::delete(
+                    popularity_history::table.filter(
+                        popularity_history::dsl::user_id
+                            .eq(user_id)
+                            .and(popularity_history::dsl::update_time.eq_any(chunk))
+                            .and(
+                                popularity_history::dsl::entity_type
+                                    .eq(bind(entity_type_code(ExportEntity::Tracks))),
+                            ),
+                    ),
+                )
+                .execute(conn)?;
+            }
+            // Diversity scores are computed from both the artists and tracks of a snapshot, so
+            // they're dropped once either is deleted
+            for chunk in artist_update_times
+                .iter()
+                .chain(track_update_times.iter())
+                .copied()
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect::<Vec<_>>()
+                .chunks(500)
+            {
+                diesel::delete(
+                    diversity_scores::table.filter(
+                        diversity_scores::dsl::user_id
+                            .eq(user_id)
+                            .and(diversity_scores::dsl::update_time.eq_any(chunk)),
+                    ),
+                )
+                .execute(conn)?;
+            }
+            diesel::delete(
+                snapshot_updates::table.filter(
+                    snapshot_updates::dsl::user_id
+                        .eq(user_id)
+                        .and(snapshot_updates::dsl::artist_count.eq(bind(0u8)))
+                        .and(snapshot_updates::dsl::track_count.eq(bind(0u8))),
+                ),
+            )
+            .execute(conn)?;
+            rebuild_latest_stats(conn, user_id)?;
+
+            Ok(deleted_count)
+        })
+    })
+    .await
+}
+
+/// Returns the distinct update times for which snapshots are available for the user, most recent
+/// first, starting after the update time in the cursor.
+pub(crate) async fn get_snapshot_update_times_before(
+    conn: &DbConn,
+    user: &User,
+    pagination: CursorPagination<NaiveDateTime>,
+) -> QueryResult<Paginated<NaiveDateTime>> {
+    use crate::schema::snapshot_updates::dsl::*;
+
+    if !user.external_data_retrieved {
+        retrieve_cold_data_for_user(conn, user).await;
+    }
+
+    let (user_id_, before, limit) = (user.id, pagination.after, pagination.fetch_limit());
+    let update_times = conn
+        .run(move |conn| {
+            let mut query = snapshot_updates
+                .filter(user_id.eq(user_id_))
+                .select(update_time)
+                .distinct()
+                .order_by(update_time.desc())
+                .limit(limit)
+                .into_boxed();
+            if let Some(before) = before {
+                query = query.filter(update_time.lt(before));
+            }
+            query.load(conn)
+        })
+        .await?;
+    Ok(pagination.into_page(update_times, |time| *time))
+}
+
+/// Sort key of the artist discoveries list, which is used as its cursor:
+/// `(first_seen, peak_ranking, internal_id)`
+pub(crate) type ArtistDiscoveryCursor = (NaiveDateTime, u8, i32);
+
+/// Returns `(spotify_id, first_seen, last_seen, peak_ranking)` for every artist that has appeared
+/// in any of the user's snapshots, most recently discovered first and then by peak ranking.
+/// `peak_ranking` is the best 0-indexed rank the artist reached in any timeframe.
+pub(crate) async fn get_artist_discoveries_page(
+    conn: &DbConn,
+    user: &User,
+    pagination: CursorPagination<ArtistDiscoveryCursor>,
+) -> QueryResult<Paginated<(String, NaiveDateTime, NaiveDateTime, u8)>> {
+    if !user.external_data_retrieved {
+        retrieve_cold_data_for_user(conn, user).await;
+    }
+
+    let user_id = user.id;
+    let history = conn
+        .run(move |conn| load_rank_history(conn, user_id, ExportEntity::Artists, None))
+        .await?;
+
+    let mut discoveries_by_id: HashMap<i32, (NaiveDateTime, NaiveDateTime, u8)> =
+        HashMap::default();
+    for entry in history {
+        let (_first_seen, last_seen, peak_ranking) = discoveries_by_id
+            .entry(entry.mapped_spotify_id)
+            .or_insert((entry.update_time, entry.update_time, entry.ranking));
+        *last_seen = entry.update_time;
+        *peak_ranking = (*peak_ranking).min(entry.ranking);
+    }
+
+    let mut discoveries: Vec<(i32, NaiveDateTime, NaiveDateTime, u8)> = discoveries_by_id
+        .into_iter()
+        .map(|(internal_id, (first_seen, last_seen, peak_ranking))| {
+            (internal_id, first_seen, last_seen, peak_ranking)
+        })
+        .collect();
+    let sort_key = |&(internal_id, first_seen, _, peak_ranking): &(i32, NaiveDateTime, _, u8)| {
+        (std::cmp::Reverse(first_seen), peak_ranking, internal_id)
+    };
+    discoveries.sort_unstable_by_key(sort_key);
+    if let Some((first_seen, peak_ranking, internal_id)) = pagination.after {
+        let after = (std::cmp::Reverse(first_seen), peak_ranking, internal_id);
+        discoveries.retain(|discovery| sort_key(discovery) > after);
+    }
+    discoveries.truncate(pagination.fetch_limit() as usize);
+    let page = pagination.into_page(
+        discoveries,
+        |&(internal_id, first_seen, _, peak_ranking)| (first_seen, peak_ranking, internal_id),
+    );
+
+    let spotify_ids_by_internal_id = get_artist_spotify_ids_by_internal_id(
+        conn,
+        page.items
+            .iter()
+            .map(|(internal_id, ..)| *internal_id)
+            .collect(),
+    )
+    .await?;
+    let items = page
+        .items
+        .into_iter()
+        .filter_map(|(internal_id, first_seen, last_seen, peak_ranking)| {
+            Some((
+                spotify_ids_by_internal_id.get(&internal_id)?.clone(),
+                first_seen,
+                last_seen,
+                peak_ranking,
+            ))
+        })
+        .collect();
+    Ok(Paginated {
+        items,
+        next_cursor: page.next_cursor,
+    })
+}
+
+pub(crate) async fn get_artist_timeline_events(
+    conn: &DbConn,
+    user_id: i64,
+    start_day: NaiveDateTime,
+    end_day: NaiveDateTime,
+) -> Result<Vec<(String, NaiveDateTime)>, diesel::result::Error> {
+    use crate::schema::{artists_users_first_seen, spotify_items};
+
+    let query = artists_users_first_seen::table
+        .filter(
+            artists_users_first_seen::dsl::user_id.eq(user_id).and(
+                artists_users_first_seen::dsl::first_seen
+                    .ge(start_day)
+                    .and(artists_users_first_seen::dsl::first_seen.le(end_day)),
+            ),
+        )
+        .order_by(artists_users_first_seen::dsl::first_seen)
+        .inner_join(
+            spotify_items::table
+                .on(spotify_items::dsl::id.eq(artists_users_first_seen::dsl::mapped_spotify_id)),
+        )
+        .select((
+            spotify_items::dsl::spotify_id,
+            artists_users_first_seen::dsl::first_seen,
+        ));
+    conn.run(move |conn| query.load(conn)).await
+}
+
+pub(crate) async fn get_track_timeline_events(
+    conn: &DbConn,
+    user_id: i64,
+    start_day: NaiveDateTime,
+    end_day: NaiveDateTime,
+) -> Result<Vec<(String, NaiveDateTime)>, diesel::result::Error> {
+    use crate::schema::{spotify_items, tracks_users_first_seen};
+
+    let query = tracks_users_first_seen::table
+        .filter(
+            tracks_users_first_seen::dsl::user_id.eq(user_id).and(
+                tracks_users_first_seen::dsl::first_seen
+                    .ge(start_day)
+                    .and(tracks_users_first_seen::dsl::first_seen.le(end_day)),
+            ),
+        )
+        .order_by(tracks_users_first_seen::dsl::first_seen)
+        .inner_join(
+            spotify_items::table
+                .on(spotify_items::dsl::id.eq(tracks_users_first_seen::dsl::mapped_spotify_id)),
+        )
+        .select((
+            spotify_items::dsl::spotify_id,
+            tracks_users_first_seen::dsl::first_seen,
+        ));
+    conn.run(move |conn| query.load(conn)).await
+}
+
+pub(crate) async fn get_last_recently_played_time(
+    conn: &DbConn,
+    user_id: i64,
+) -> Result<Option<NaiveDateTime>, diesel::result::Error> {
+    use crate::schema::recently_played;
+
+    let query = recently_played::table
+        .filter(recently_played::dsl::user_id.eq(user_id))
+        .select(recently_played::dsl::played_at)
+        .order_by(recently_played::dsl::played_at.desc());
+    conn.run(move |conn| query.first(conn).optional()).await
+}
+
+/// Returns the spotify IDs and play times of the user's most recently played tracks, most recent
+/// first, starting after the play time in the cursor.
+pub(crate) async fn get_recently_played(
+    conn: &DbConn,
+    user_id: i64,
+    pagination: CursorPagination<NaiveDateTime>,
+) -> Result<Paginated<(String, NaiveDateTime)>, diesel::result::Error> {
+    use crate::schema::{recently_played, spotify_items};
+
+    let (before, limit) = (pagination.after, pagination.fetch_limit());
+    let plays = conn
+        .run(move |conn| {
+            let mut query = recently_played::table
+                .filter(recently_played::dsl::user_id.eq(user_id))
+                .order_by(recently_played::dsl::played_at.desc())
+                .limit(limit)
+                .inner_join(spotify_items::table)
+                .select((
+                    spotify_items::dsl::spotify_id,
+                    recently_played::dsl::played_at,
+                ))
+                .into_boxed();
+            if let Some(before) = before {
+                query = query.filter(recently_played::dsl::played_at.lt(before));
+            }
+            query.load(conn)
+        })
+        .await?;
+    Ok(pagination.into_page(plays, |(_, played_at)| *played_at))
+}
+
+/// Returns `(track_spotify_id, played_at, duration_ms)` for all of the user's plays since `start`,
+/// or all of their plays if `None`, ordered from oldest to newest.
+pub(crate) async fn get_plays_since(
+    conn: &DbConn,
+    user_id: i64,
+    start: Option<NaiveDateTime>,
+) -> Result<Vec<(String, NaiveDateTime, u32)>, diesel::result::Error> {
+    use crate::schema::{recently_played, spotify_items};
+
+    conn.run(move |conn| {
+        let mut query = recently_played::table
+            .filter(recently_played::dsl::user_id.eq(user_id))
+            .order_by(recently_played::dsl::played_at)
+            .inner_join(spotify_items::table)
+            .select((
+                spotify_items::dsl::spotify_id,
+                recently_played::dsl::played_at,
+                recently_played::dsl::duration_ms,
+            ))
+            .into_boxed();
+        if let Some(start) = start {
+            query = query.filter(recently_played::dsl::played_at.ge(start));
+        }
+        query.load(conn)
+    })
+    .await
+}
+
+/// Returns `(update_time, timeframe, ranking, spotify_id)` for every artist or track snapshot row
+/// stored for the user, ordered by update time, timeframe, and ranking.
+pub(crate) async fn get_full_rank_history(
+    conn: &DbConn,
+    user: &User,
+    entity: ExportEntity,
+) -> Result<Vec<(NaiveDateTime, Timeframe, u8, String)>, Error> {
+    if !user.external_data_retrieved {
+        retrieve_cold_data_for_user(conn, user).await;
+    }
+
+    let user_id = user.id;
+    let history = conn
+        .run(move |conn| load_rank_history(conn, user_id, entity, None))
+        .await?;
+    let internal_ids: HashSet<i32> = history
+        .iter()
+        .map(|entry| entry.mapped_spotify_id)
+        .collect();
+    let spotify_ids_by_internal_id =
+        get_artist_spotify_ids_by_internal_id(conn, internal_ids.into_iter().collect()).await?;
+    Ok(history
+        .into_iter()
+        .filter_map(|entry| {
+            Some((
+                entry.update_time,
+                entry.timeframe,
+                entry.ranking,
+                spotify_ids_by_internal_id
+                    .get(&entry.mapped_spotify_id)?
+                    .clone(),
+            ))
+        })
+        .collect())
+}
+
+/// Returns `(update_time, timeframe, ranking)` for every appearance of a single artist or track
+/// in the user's history, oldest first.
+pub(crate) async fn get_entity_rank_history(
+    conn: &DbConn,
+    user: &User,
+    entity: ExportEntity,
+    entity_spotify_id: String,
+) -> Result<Vec<(NaiveDateTime, Timeframe, u8)>, Error> {
+    if !user.external_data_retrieved {
+        retrieve_cold_data_for_user(conn, user).await;
+    }
+
+    let user_id = user.id;
+    let history = conn
+        .run(move |conn| load_entities_rank_history(conn, user_id, entity, vec![entity_spotify_id]))
+        .await?;
+    Ok(history
+        .into_iter()
+        .map(|entry| (entry.update_time, entry.timeframe, entry.ranking))
+        .collect())
+}
+
+/// Escapes `%`, `_`, and `\` in user-provided text so that it's matched literally by `LIKE`
+fn escape_like_pattern(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[derive(QueryableByName)]
+struct HistorySearchQueryResItem {
+    #[sql_type = "diesel::sql_types::Text"]
+    spotify_id: String,
+}
+
+/// Returns the Spotify IDs of artists or tracks that have appeared in the user's history and whose
+/// names contain `query`, case-insensitively.  Names come from the stored entity metadata, so
+/// entities whose metadata hasn't been stored yet aren't matched.  The `limit` matches that
+/// appeared in the most snapshots are returned.
+pub(crate) async fn search_user_history(
+    conn: &DbConn,
+    user: &User,
+    entity: ExportEntity,
+    query: &str,
+    limit: u32,
+) -> Result<Vec<String>, Error> {
+    let (metadata_table, snapshots_table) = match entity {
+        ExportEntity::Artists => ("artists", "artist_rank_snapshots"),
+        ExportEntity::Tracks => ("tracks", "track_rank_snapshots"),
+    };
+
+    if !user.external_data_retrieved {
+        retrieve_cold_data_for_user(conn, user).await;
+    }
+
+    let user_id = user.id;
+    let pattern = format!("%{}%", escape_like_pattern(query));
+    let res = conn
+        .run(move |conn| {
+            diesel::sql_query(portable_sql(&format!(
+                "SELECT `spotify_items`.`spotify_id` FROM `{metadata}` INNER JOIN `spotify_items` \
+                 ON `spotify_items`.`spotify_id` = `{metadata}`.`spotify_id` INNER JOIN \
+                 `{snapshots}` AS `snapshots` ON `snapshots`.`mapped_spotify_id` = \
+                 `spotify_items`.`id` WHERE `{metadata}`.`name` {like} ? AND \
+                 `snapshots`.`user_id` = ? GROUP BY `spotify_items`.`spotify_id` ORDER BY \
+                 COUNT(DISTINCT `snapshots`.`update_time`) DESC, MIN(`{metadata}`.`name`) LIMIT ?",
+                metadata = metadata_table,
+                like = CASE_INSENSITIVE_LIKE,
+                snapshots = snapshots_table,
+            )))
+            .bind::<diesel::sql_types::Text, _>(pattern)
+            .bind::<diesel::sql_types::BigInt, _>(user_id)
+            .bind::<crate::db_backend::sql_types::Unsigned<diesel::sql_types::Integer>, _>(limit)
+            .load::<HistorySearchQueryResItem>(conn)
+        })
+        .await?;
+    Ok(res.into_iter().map(|item| item.spotify_id).collect())
+}
+
+/// Same as `get_entity_rank_history`, but for several entities at once.  Returns `(spotify_id,
+/// update_time, timeframe, ranking)`, oldest first.
+pub(crate) async fn get_entities_rank_history(
+    conn: &DbConn,
+    user: &User,
+    entity: ExportEntity,
+    spotify_ids: Vec<String>,
+) -> Result<Vec<(String, NaiveDateTime, Timeframe, u8)>, Error> {
+    if spotify_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let user_id = user.id;
+    let history = conn
+        .run(move |conn| load_entities_rank_history(conn, user_id, entity, spotify_ids))
+        .await?;
+    let internal_ids: HashSet<i32> = history
+        .iter()
+        .map(|entry| entry.mapped_spotify_id)
+        .collect();
+    let spotify_ids_by_internal_id =
+        get_artist_spotify_ids_by_internal_id(conn, internal_ids.into_iter().collect()).await?;
+    Ok(history
+        .into_iter()
+        .filter_map(|entry| {
+            Some((
+                spotify_ids_by_internal_id
+                    .get(&entry.mapped_spotify_id)?
+                    .clone(),
+                entry.update_time,
+                entry.timeframe,
+                entry.ranking,
+            ))
+        })
+        .collect())
+}
+
+const PRIVATE_TOKEN_LENGTH: usize = 32;
+
+/// Generates a new random token that grants access to a private user's stats
+pub(crate) fn generate_private_token() -> String {
+    use rand::{distributions::Alphanumeric, Rng};
+
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(PRIVATE_TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Sets whether the user's stats are private.  Users who signed up before private tokens existed
+/// are given one here.  Returns the user's private token.
+pub(crate) async fn set_user_privacy(
+    conn: &DbConn,
+    user: &User,
+    is_private: bool,
+) -> QueryResult<String> {
+    use crate::schema::users;
+
+    let user_id = user.id;
+    let private_token = user
+        .private_token
+        .clone()
+        .unwrap_or_else(generate_private_token);
+    let private_token_clone = private_token.clone();
+    conn.run(move |conn| {
+        diesel::update(users::table.filter(users::dsl::id.eq(user_id)))
+            .set((
+                users::dsl::is_private.eq(is_private),
+                users::dsl::private_token.eq(Some(private_token_clone)),
+            ))
+            .execute(conn)
+    })
+    .await?;
+    Ok(private_token)
+}
+
+/// Deactivates or reactivates the user.  Returns the time the user was deactivated at, which is
+/// `None` if they were reactivated.  Deactivating a user that's already deactivated keeps the
+/// original time.
+pub(crate) async fn set_user_deactivated(
+    conn: &DbConn,
+    user: &User,
+    deactivated: bool,
+) -> QueryResult<Option<NaiveDateTime>> {
+    use crate::schema::users;
+
+    let user_id = user.id;
+    let deactivated_at = if deactivated {
+        Some(
+            user.deactivated_at
+                .unwrap_or_else(|| Utc::now().naive_utc()),
+        )
+    } else {
+        None
+    };
+    conn.run(move |conn| {
+        diesel::update(users::table.filter(users::dsl::id.eq(user_id)))
+            .set(users::dsl::deactivated_at.eq(deactivated_at))
+            .execute(conn)
+    })
+    .await?;
+    Ok(deactivated_at)
+}
+
+/// Returns `true` if the slug is already used by another user, either as their slug or as their
+/// Spotify ID
+pub(crate) async fn is_vanity_slug_taken(
+    conn: &DbConn,
+    user: &User,
+    slug: String,
+) -> QueryResult<bool> {
+    use crate::schema::users::dsl::*;
+
+    let user_id = user.id;
+    conn.run(move |conn| {
+        diesel::select(diesel::dsl::exists(
+            users
+                .filter(id.ne(user_id))
+                .filter(vanity_slug.eq(&slug).or(spotify_id.eq(&slug))),
+        ))
+        .get_result(conn)
+    })
+    .await
+}
+
+pub(crate) async fn set_user_vanity_slug(
+    conn: &DbConn,
+    user: &User,
+    slug: Option<String>,
+) -> QueryResult<usize> {
+    use crate::schema::users;
+
+    let user_id = user.id;
+    conn.run(move |conn| {
+        diesel::update(users::table.filter(users::dsl::id.eq(user_id)))
+            .set(users::dsl::vanity_slug.eq(slug))
+            .execute(conn)
+    })
+    .await
+}
+
+pub(crate) async fn set_user_timezone(
+    conn: &DbConn,
+    user: &User,
+    timezone: String,
+) -> QueryResult<usize> {
+    use crate::schema::users;
+
+    let user_id = user.id;
+    conn.run(move |conn| {
+        diesel::update(users::table.filter(users::dsl::id.eq(user_id)))
+            .set(users::dsl::timezone.eq(Some(timezone)))
+            .execute(conn)
+    })
+    .await
+}
+
+/// Returns the user's settings, or the defaults if they haven't changed any
+pub(crate) async fn get_user_settings(
+    conn: &DbConn,
+    user: &User,
+) -> QueryResult<UserSettingsEntry> {
+    use crate::schema::user_settings;
+
+    let user_id = user.id;
+    let settings = conn
+        .run(move |conn| {
+            user_settings::table
+                .find(user_id)
+                .first::<UserSettingsEntry>(conn)
+                .optional()
+        })
+        .await?;
+    Ok(settings.unwrap_or_else(|| UserSettingsEntry::defaults(user_id)))
+}
+
+pub(crate) async fn set_user_settings(
+    conn: &DbConn,
+    settings: UserSettingsEntry,
+) -> QueryResult<usize> {
+    use crate::schema::user_settings;
+
+    conn.run(move |conn| {
+        upsert!(
+            user_settings::table,
+            &settings,
+            user_settings::user_id,
+            (
+                user_settings::display_name,
+                user_settings::default_timeframe,
+                user_settings::update_frequency,
+                user_settings::entity_fetch_count,
+                user_settings::leaderboard_opt_in,
+                user_settings::friends_only_comparisons,
+                user_settings::now_playing_public,
+                user_settings::library_public,
+            )
+        )
+        .execute(conn)
+    })
+    .await
+}
+
+/// Returns the accounts linked to the user, oldest link first
+pub(crate) async fn get_linked_accounts(
+    conn: &DbConn,
+    user: &User,
+) -> QueryResult<Vec<LinkedAccount>> {
+    use crate::schema::{linked_accounts, users};
+
+    let user_id = user.id;
+    conn.run(move |conn| {
+        linked_accounts::table
+            .filter(linked_accounts::dsl::primary_user_id.eq(user_id))
+            .inner_join(users::table.on(users::dsl::id.eq(linked_accounts::dsl::linked_user_id)))
+            .select((
+                users::dsl::spotify_id,
+                users::dsl::username,
+                linked_accounts::dsl::created_at,
+            ))
+            .order_by(linked_accounts::dsl::created_at)
+            .load(conn)
+    })
+    .await
+}
+
+pub(crate) async fn get_linked_users(conn: &DbConn, user: &User) -> QueryResult<Vec<User>> {
+    use crate::schema::{linked_accounts, users};
+
+    let user_id = user.id;
+    conn.run(move |conn| {
+        linked_accounts::table
+            .filter(linked_accounts::dsl::primary_user_id.eq(user_id))
+            .inner_join(users::table.on(users::dsl::id.eq(linked_accounts::dsl::linked_user_id)))
+            .select(users::all_columns)
+            .order_by(linked_accounts::dsl::created_at)
+            .load(conn)
+    })
+    .await
+}
+
+/// Returns the ID of the user that the provided user's account is linked to, if it's linked
+pub(crate) async fn get_primary_user_id(conn: &DbConn, user: &User) -> QueryResult<Option<i64>> {
+    use crate::schema::linked_accounts::dsl::*;
+
+    let user_id = user.id;
+    conn.run(move |conn| {
+        linked_accounts
+            .filter(linked_user_id.eq(user_id))
+            .select(primary_user_id)
+            .first(conn)
+            .optional()
+    })
+    .await
+}
+
+pub(crate) async fn link_account(
+    conn: &DbConn,
+    primary_user: &User,
+    linked_user: &User,
+) -> QueryResult<usize> {
+    use crate::schema::linked_accounts;
+
+    let entry = NewLinkedAccount {
+        primary_user_id: primary_user.id,
+        linked_user_id: linked_user.id,
+    };
+    conn.run(move |conn| {
+        diesel::insert_into(linked_accounts::table)
+            .values(&entry)
+            .execute(conn)
+    })
+    .await
+}
+
+/// Returns the number of links removed, which is 0 if the accounts weren't linked
+pub(crate) async fn unlink_account(
+    conn: &DbConn,
+    primary_user: &User,
+    linked_user: &User,
+) -> QueryResult<usize> {
+    use crate::schema::linked_accounts::dsl::*;
+
+    let (primary_id, linked_id) = (primary_user.id, linked_user.id);
+    conn.run(move |conn| {
+        diesel::delete(
+            linked_accounts
+                .filter(primary_user_id.eq(primary_id))
+                .filter(linked_user_id.eq(linked_id)),
+        )
+        .execute(conn)
+    })
+    .await
+}
+
+/// Returns `(other_user, display_name, requested_at, accepted_at, is_incoming)` for each of the
+/// user's friendships and pending friend requests, oldest request first.  `display_name` is the
+/// other user's display name override, if they've set one.
+pub(crate) async fn get_friendships(
+    conn: &DbConn,
+    user: &User,
+) -> QueryResult<
+    Vec<(
+        User,
+        Option<String>,
+        NaiveDateTime,
+        Option<NaiveDateTime>,
+        bool,
+    )>,
+> {
+    use crate::schema::{friendships, user_settings, users};
+
+    let user_id = user.id;
+    conn.run(move |conn| {
+        let outgoing: Vec<(User, Option<String>, NaiveDateTime, Option<NaiveDateTime>)> =
+            friendships::table
+                .filter(friendships::dsl::requester_id.eq(user_id))
+                .inner_join(users::table.on(users::dsl::id.eq(friendships::dsl::addressee_id)))
+                .left_join(user_settings::table.on(user_settings::dsl::user_id.eq(users::dsl::id)))
+                .select((
+                    users::all_columns,
+                    user_settings::dsl::display_name.nullable(),
+                    friendships::dsl::requested_at,
+                    friendships::dsl::accepted_at,
+                ))
+                .load(conn)?;
+        let incoming: Vec<(User, Option<String>, NaiveDateTime, Option<NaiveDateTime>)> =
+            friendships::table
+                .filter(friendships::dsl::addressee_id.eq(user_id))
+                .inner_join(users::table.on(users::dsl::id.eq(friendships::dsl::requester_id)))
+                .left_join(user_settings::table.on(user_settings::dsl::user_id.eq(users::dsl::id)))
+                .select((
+                    users::all_columns,
+                    user_settings::dsl::display_name.nullable(),
+                    friendships::dsl::requested_at,
+                    friendships::dsl::accepted_at,
+                ))
+                .load(conn)?;
+
+        let mut all: Vec<_> =
+            outgoing
+                .into_iter()
+                .map(|(other, display_name, requested_at, accepted_at)| {
+                    (other, display_name, requested_at, accepted_at, false)
+                })
+                .chain(incoming.into_iter().map(
+                    |(other, display_name, requested_at, accepted_at)| {
+                        (other, display_name, requested_at, accepted_at, true)
+                    },
+                ))
+                .collect();
+        all.sort_by_key(|(_, _, requested_at, ..)| *requested_at);
+        Ok(all)
+    })
+    .await
+}
+
+/// Returns `(requester_id, accepted_at)` for the friendship or pending request between the two
+/// users, in whichever direction it was sent
+pub(crate) async fn get_friendship(
+    conn: &DbConn,
+    user: &User,
+    other_user: &User,
+) -> QueryResult<Option<(i64, Option<NaiveDateTime>)>> {
+    use crate::schema::friendships::dsl::*;
+
+    let (user_id, other_user_id) = (user.id, other_user.id);
+    conn.run(move |conn| {
+        friendships
+            .filter(
+                requester_id
+                    .eq(user_id)
+                    .and(addressee_id.eq(other_user_id))
+                    .or(requester_id.eq(other_user_id).and(addressee_id.eq(user_id))),
+            )
+            .select((requester_id, accepted_at))
+            .first(conn)
+            .optional()
+    })
+    .await
+}
+
+pub(crate) async fn are_friends(
+    conn: &DbConn,
+    user: &User,
+    other_user: &User,
+) -> QueryResult<bool> {
+    Ok(matches!(
+        get_friendship(conn, user, other_user).await?,
+        Some((_, Some(_)))
+    ))
+}
+
+/// Returns the number of requests sent, which is 0 if the user had already sent one
+pub(crate) async fn request_friendship(
+    conn: &DbConn,
+    requester: &User,
+    addressee: &User,
+) -> QueryResult<usize> {
+    use crate::schema::friendships;
+
+    let entry = NewFriendship {
+        requester_id: requester.id,
+        addressee_id: addressee.id,
+        requested_at: Utc::now().naive_utc(),
+    };
+    conn.run(move |conn| insert_or_ignore!(friendships::table, &entry).execute(conn))
+        .await
+}
+
+/// Returns the number of requests accepted, which is 0 if there was no pending request from
+/// `requester` to `addressee`
+pub(crate) async fn accept_friendship(
+    conn: &DbConn,
+    requester: &User,
+    addressee: &User,
+) -> QueryResult<usize> {
+    use crate::schema::friendships::dsl::*;
+
+    let (requester, addressee) = (requester.id, addressee.id);
+    conn.run(move |conn| {
+        diesel::update(
+            friendships
+                .filter(requester_id.eq(requester))
+                .filter(addressee_id.eq(addressee))
+                .filter(accepted_at.is_null()),
+        )
+        .set(accepted_at.eq(Some(Utc::now().naive_utc())))
+        .execute(conn)
+    })
+    .await
+}
+
+/// Removes the friendship or pending request between the two users, in whichever direction it was
+/// sent.  Returns the number of rows removed, which is 0 if there was none.
+pub(crate) async fn delete_friendship(
+    conn: &DbConn,
+    user: &User,
+    other_user: &User,
+) -> QueryResult<usize> {
+    use crate::schema::friendships::dsl::*;
+
+    let (user_id, other_user_id) = (user.id, other_user.id);
+    conn.run(move |conn| {
+        diesel::delete(
+            friendships.filter(
+                requester_id
+                    .eq(user_id)
+                    .and(addressee_id.eq(other_user_id))
+                    .or(requester_id.eq(other_user_id).and(addressee_id.eq(user_id))),
+            ),
+        )
+        .execute(conn)
+    })
+    .await
+}
+
+/// Deletes the user along with all of their history, including their stored OAuth tokens.
+pub(crate) async fn delete_user(conn: &DbConn, user: &User) -> QueryResult<UserDeletionSummary> {
+    use crate::schema::{
+        artist_rank_deltas, artists_users_first_seen, email_subscriptions, followed_artists,
+        latest_artist_stats, latest_track_stats, library_snapshots, recently_played, saved_tracks,
+        snapshot_updates, top_tracks_playlists, track_rank_deltas, tracks_users_first_seen,
+        update_errors, users,
+    };
+
+    let user_id = user.id;
+    let spotify_id = user.spotify_id.clone();
+    conn.run(move |conn| {
+        conn.transaction(|| -> QueryResult<UserDeletionSummary> {
+            let summary = UserDeletionSummary {
+                spotify_id,
+                artist_rank_snapshots: diesel::delete(
+                    artist_rank_deltas::table.filter(artist_rank_deltas::dsl::user_id.eq(user_id)),
+                )
+                .execute(conn)?,
+                track_rank_snapshots: diesel::delete(
+                    track_rank_deltas::table.filter(track_rank_deltas::dsl::user_id.eq(user_id)),
+                )
+                .execute(conn)?,
+                artists_first_seen: diesel::delete(
+                    artists_users_first_seen::table
+                        .filter(artists_users_first_seen::dsl::user_id.eq(user_id)),
+                )
+                .execute(conn)?,
+                tracks_first_seen: diesel::delete(
+                    tracks_users_first_seen::table
+                        .filter(tracks_users_first_seen::dsl::user_id.eq(user_id)),
+                )
+                .execute(conn)?,
+                recently_played: diesel::delete(
+                    recently_played::table.filter(recently_played::dsl::user_id.eq(user_id)),
+                )
+                .execute(conn)?,
+                followed_artists: diesel::delete(
+                    followed_artists::table.filter(followed_artists::dsl::user_id.eq(user_id)),
+                )
+                .execute(conn)?,
+                saved_tracks: diesel::delete(
+                    saved_tracks::table.filter(saved_tracks::dsl::user_id.eq(user_id)),
+                )
+                .execute(conn)?,
+                library_snapshots: diesel::delete(
+                    library_snapshots::table.filter(library_snapshots::dsl::user_id.eq(user_id)),
+                )
+                .execute(conn)?,
+                email_subscriptions: diesel::delete(
+                    email_subscriptions::table
+                        .filter(email_subscriptions::dsl::user_id.eq(user_id)),
+                )
+                .execute(conn)?,
+                update_errors: diesel::delete(
+                    update_errors::table.filter(update_errors::dsl::user_id.eq(user_id)),
+                )
+                .execute(conn)?,
+                top_tracks_playlists: diesel::delete(
+                    top_tracks_playlists::table
+                        .filter(top_tracks_playlists::dsl::user_id.eq(user_id)),
+                )
+                .execute(conn)?,
+                external_data_deleted: false,
+            };
+            diesel::delete(
+                snapshot_updates::table.filter(snapshot_updates::dsl::user_id.eq(user_id)),
+            )
+            .execute(conn)?;
+            diesel::delete(
+                latest_artist_stats::table.filter(latest_artist_stats::dsl::user_id.eq(user_id)),
+            )
+            .execute(conn)?;
+            diesel::delete(
+                latest_track_stats::table.filter(latest_track_stats::dsl::user_id.eq(user_id)),
+            )
+            .execute(conn)?;
+            diesel::delete(users::table.filter(users::dsl::id.eq(user_id))).execute(conn)?;
+            Ok(summary)
+        })
+    })
+    .await
+}
+
+/// Returns the ID of the playlist previously generated from the user's top tracks for the
+/// timeframe, if any.
+pub(crate) async fn get_top_tracks_playlist_id(
+    conn: &DbConn,
+    user_id: i64,
+    timeframe: Timeframe,
+) -> QueryResult<Option<String>> {
+    use crate::schema::top_tracks_playlists;
+
+    let query = top_tracks_playlists::table
+        .filter(top_tracks_playlists::dsl::user_id.eq(user_id))
+        .filter(top_tracks_playlists::dsl::timeframe.eq(timeframe))
+        .select(top_tracks_playlists::dsl::playlist_spotify_id);
+    conn.run(move |conn| query.first(conn).optional()).await
+}
+
+pub(crate) async fn set_top_tracks_playlist_id(
+    conn: &DbConn,
+    user_id: i64,
+    timeframe: Timeframe,
+    playlist_spotify_id: String,
+) -> QueryResult<usize> {
+    use crate::schema::top_tracks_playlists;
+
+    conn.run(move |conn| {
+        upsert!(
+            top_tracks_playlists::table,
+            (
+                top_tracks_playlists::dsl::user_id.eq(user_id),
+                top_tracks_playlists::dsl::timeframe.eq(timeframe),
+                top_tracks_playlists::dsl::playlist_spotify_id.eq(playlist_spotify_id),
+            ),
+            (
+                top_tracks_playlists::dsl::user_id,
+                top_tracks_playlists::dsl::timeframe,
+            ),
+            (top_tracks_playlists::dsl::playlist_spotify_id)
+        )
+        .execute(conn)
+    })
+    .await
+}
+
+/// Returns `(id, mapped_spotify_id)` for each of the user's followed artists that haven't been
+/// marked as unfollowed.
+pub(crate) async fn get_active_follows(
+    conn: &DbConn,
+    user_id: i64,
+) -> QueryResult<Vec<(i64, i32)>> {
+    use crate::schema::followed_artists;
+
+    let query = followed_artists::table
+        .filter(
+            followed_artists::dsl::user_id
+                .eq(user_id)
+                .and(followed_artists::dsl::unfollowed_at.is_null()),
+        )
+        .select((
+            followed_artists::dsl::id,
+            followed_artists::dsl::mapped_spotify_id,
+        ));
+    conn.run(move |conn| query.load(conn)).await
+}
+
+/// Inserts the provided new follows and sets `unfollowed_at` for the `followed_artists` rows with
+/// the provided ids.
+pub(crate) async fn store_follow_changes(
+    conn: &DbConn,
+    new_follows: Vec<NewFollowedArtistEntry>,
+    unfollowed_row_ids: Vec<i64>,
+    unfollowed_at: NaiveDateTime,
+) -> QueryResult<()> {
+    use crate::schema::followed_artists;
+
+    if new_follows.is_empty() && unfollowed_row_ids.is_empty() {
+        return Ok(());
+    }
+
+    conn.run(move |conn| {
+        conn.transaction(|| -> QueryResult<()> {
+            for chunk in new_follows.chunks(500) {
+                diesel::insert_into(followed_artists::table)
+                    .values(chunk)
+                    .execute(conn)?;
+            }
+            for chunk in unfollowed_row_ids.chunks(500) {
+                diesel::update(
+                    followed_artists::table.filter(followed_artists::dsl::id.eq_any(chunk)),
+                )
+                .set(followed_artists::dsl::unfollowed_at.eq(Some(unfollowed_at)))
+                .execute(conn)?;
+            }
+            Ok(())
+        })
+    })
+    .await
+}
+
+/// Returns `(artist_spotify_id, followed_at, unfollowed_at)` for every follow recorded for the
+/// user, ordered from oldest to newest follow.
+pub(crate) async fn get_follow_history(
+    conn: &DbConn,
+    user_id: i64,
+) -> QueryResult<Vec<(String, NaiveDateTime, Option<NaiveDateTime>)>> {
+    use crate::schema::{followed_artists, spotify_items};
+
+    let query = followed_artists::table
+        .filter(followed_artists::dsl::user_id.eq(user_id))
+        .order_by(followed_artists::dsl::followed_at)
+        .inner_join(spotify_items::table)
+        .select((
+            spotify_items::dsl::spotify_id,
+            followed_artists::dsl::followed_at,
+            followed_artists::dsl::unfollowed_at,
+        ));
+    conn.run(move |conn| query.load(conn)).await
+}
+
+/// Stores a snapshot of the size of the user's library along with its most recently saved tracks.
+/// Tracks that were saved again since they were last seen have their save time updated.
+pub(crate) async fn store_library_snapshot(
+    conn: &DbConn,
+    snapshot: NewLibrarySnapshotEntry,
+    saves: Vec<NewSavedTrackEntry>,
+) -> QueryResult<()> {
+    use crate::schema::{library_snapshots, saved_tracks};
+
+    conn.run(move |conn| {
+        conn.transaction(|| -> QueryResult<()> {
+            insert_or_ignore!(library_snapshots::table, snapshot).execute(conn)?;
+            if !saves.is_empty() {
+                upsert!(
+                    saved_tracks::table,
+                    &saves,
+                    (saved_tracks::user_id, saved_tracks::mapped_spotify_id),
+                    (saved_tracks::added_at)
+                )
+                .execute(conn)?;
+            }
+            Ok(())
+        })
+    })
+    .await
+}
+
+/// Returns `(update_time, saved_track_count)` for each of the user's library snapshots, oldest
+/// first
+pub(crate) async fn get_library_history(
+    conn: &DbConn,
+    user_id: i64,
+) -> QueryResult<Vec<(NaiveDateTime, u32)>> {
+    use crate::schema::library_snapshots;
+
+    conn.run(move |conn| {
+        library_snapshots::table
+            .filter(library_snapshots::dsl::user_id.eq(user_id))
+            .order_by(library_snapshots::dsl::update_time.asc())
+            .select((
+                library_snapshots::dsl::update_time,
+                library_snapshots::dsl::saved_track_count,
+            ))
+            .load(conn)
+    })
+    .await
+}
+
+/// Returns the number of saved tracks in the user's most recent library snapshot, if they have one
+pub(crate) async fn get_latest_library_size(
+    conn: &DbConn,
+    user_id: i64,
+) -> QueryResult<Option<u32>> {
+    use crate::schema::library_snapshots;
+
+    conn.run(move |conn| {
+        library_snapshots::table
+            .filter(library_snapshots::dsl::user_id.eq(user_id))
+            .order_by(library_snapshots::dsl::update_time.desc())
+            .select(library_snapshots::dsl::saved_track_count)
+            .first(conn)
+            .optional()
+    })
+    .await
+}
+
+/// Returns `(track_spotify_id, added_at)` for the user's most recently saved tracks, most recent
+/// first
+pub(crate) async fn get_newest_saved_tracks(
+    conn: &DbConn,
+    user_id: i64,
+    limit: i64,
+) -> QueryResult<Vec<(String, NaiveDateTime)>> {
+    use crate::schema::{saved_tracks, spotify_items};
+
+    let query = saved_tracks::table
+        .filter(saved_tracks::dsl::user_id.eq(user_id))
+        .order_by(saved_tracks::dsl::added_at.desc())
+        .limit(limit)
+        .inner_join(spotify_items::table)
+        .select((spotify_items::dsl::spotify_id, saved_tracks::dsl::added_at));
+    conn.run(move |conn| query.load(conn)).await
+}
+
+/// Columns that `EmailSubscription` is loaded from
+const EMAIL_SUBSCRIPTION_COLUMNS: (
+    crate::schema::email_subscriptions::email,
+    crate::schema::email_subscriptions::unsubscribe_token,
+    crate::schema::email_subscriptions::confirmation_token,
+    crate::schema::email_subscriptions::confirmed_at,
+    crate::schema::email_subscriptions::last_sent_at,
+) = (
+    crate::schema::email_subscriptions::email,
+    crate::schema::email_subscriptions::unsubscribe_token,
+    crate::schema::email_subscriptions::confirmation_token,
+    crate::schema::email_subscriptions::confirmed_at,
+    crate::schema::email_subscriptions::last_sent_at,
+);
+
+/// Subscribes the user to the weekly digest at `email`, replacing any existing subscription.  The
+/// new subscription is unconfirmed until its confirmation token is passed to
+/// `confirm_email_subscription`.  New unsubscribe and confirmation tokens are generated each time.
+pub(crate) async fn set_email_subscription(
+    conn: &DbConn,
+    user_id: i64,
+    email: String,
+) -> QueryResult<EmailSubscription> {
+    use crate::schema::email_subscriptions;
+
+    let subscription = NewEmailSubscription {
+        user_id,
+        email,
+        unsubscribe_token: generate_private_token(),
+        created_at: Utc::now().naive_utc(),
+        confirmation_token: generate_private_token(),
+    };
+    conn.run(move |conn| {
+        conn.transaction(|| {
+            diesel::delete(
+                email_subscriptions::table.filter(email_subscriptions::dsl::user_id.eq(user_id)),
+            )
+            .execute(conn)?;
+            diesel::insert_into(email_subscriptions::table)
+                .values(&subscription)
+                .execute(conn)?;
+            email_subscriptions::table
+                .filter(email_subscriptions::dsl::user_id.eq(user_id))
+                .select(EMAIL_SUBSCRIPTION_COLUMNS)
+                .first(conn)
+        })
+    })
+    .await
+}
+
+/// Confirms the subscription with the provided confirmation token if it was created at or after
+/// `created_after`, returning the number of confirmed subscriptions
+pub(crate) async fn confirm_email_subscription(
+    conn: &DbConn,
+    token: String,
+    created_after: NaiveDateTime,
+) -> QueryResult<usize> {
+    use crate::schema::email_subscriptions;
+
+    conn.run(move |conn| {
+        diesel::update(
+            email_subscriptions::table
+                .filter(email_subscriptions::dsl::confirmation_token.eq(token))
+                .filter(email_subscriptions::dsl::created_at.ge(created_after)),
+        )
+        .set((
+            email_subscriptions::dsl::confirmation_token.eq(None::<String>),
+            email_subscriptions::dsl::confirmed_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)
+    })
+    .await
+}
+
+pub(crate) async fn get_email_subscription(
+    conn: &DbConn,
+    user_id: i64,
+) -> QueryResult<Option<EmailSubscription>> {
+    use crate::schema::email_subscriptions;
+
+    conn.run(move |conn| {
+        email_subscriptions::table
+            .filter(email_subscriptions::dsl::user_id.eq(user_id))
+            .select(EMAIL_SUBSCRIPTION_COLUMNS)
+            .first(conn)
+            .optional()
+    })
+    .await
+}
+
+pub(crate) async fn delete_email_subscription(conn: &DbConn, user_id: i64) -> QueryResult<usize> {
+    use crate::schema::email_subscriptions;
+
+    conn.run(move |conn| {
+        diesel::delete(
+            email_subscriptions::table.filter(email_subscriptions::dsl::user_id.eq(user_id)),
+        )
+        .execute(conn)
+    })
+    .await
+}
+
+/// Deletes the subscription with the provided unsubscribe token, returning the number of deleted
+/// subscriptions
+pub(crate) async fn delete_email_subscription_by_token(
+    conn: &DbConn,
+    token: String,
+) -> QueryResult<usize> {
+    use crate::schema::email_subscriptions;
+
+    conn.run(move |conn| {
+        diesel::delete(
+            email_subscriptions::table
+                .filter(email_subscriptions::dsl::unsubscribe_token.eq(token)),
+        )
+        .execute(conn)
+    })
+    .await
+}
+
+/// Returns the confirmed subscriptions of active users that haven't been sent a digest since
+/// `cutoff`, along with their users
+pub(crate) async fn get_email_subscriptions_due(
+    conn: &DbConn,
+    cutoff: NaiveDateTime,
+) -> QueryResult<Vec<(EmailSubscription, User)>> {
+    use crate::schema::{email_subscriptions, users};
+
+    let query = email_subscriptions::table
+        .inner_join(users::table)
+        .filter(
+            email_subscriptions::dsl::last_sent_at
+                .is_null()
+                .or(email_subscriptions::dsl::last_sent_at.lt(cutoff)),
+        )
+        .filter(email_subscriptions::dsl::confirmed_at.is_not_null())
+        .filter(users::dsl::deactivated_at.is_null())
+        .order_by(email_subscriptions::dsl::last_sent_at.asc())
+        .select((EMAIL_SUBSCRIPTION_COLUMNS, users::all_columns));
+    conn.run(move |conn| query.load(conn)).await
+}
+
+pub(crate) async fn set_digest_sent(
+    conn: &DbConn,
+    user_id: i64,
+    sent_at: NaiveDateTime,
+) -> QueryResult<usize> {
+    use crate::schema::email_subscriptions;
+
+    conn.run(move |conn| {
+        diesel::update(
+            email_subscriptions::table.filter(email_subscriptions::dsl::user_id.eq(user_id)),
+        )
+        .set(email_subscriptions::dsl::last_sent_at.eq(sent_at))
+        .execute(conn)
+    })
+    .await
+}
+
+/// Returns up to `limit` undelivered outbox events that are due to be attempted, oldest first.
+/// Events that have already been attempted `max_attempts` times are left alone.
+pub(crate) async fn get_pending_outbox_events(
+    conn: &DbConn,
+    now: NaiveDateTime,
+    max_attempts: i32,
+    limit: i64,
+) -> QueryResult<Vec<OutboxEventRow>> {
+    use crate::schema::outbox_events;
+
+    let query = outbox_events::table
+        .filter(outbox_events::dsl::delivered_at.is_null())
+        .filter(outbox_events::dsl::next_attempt_at.le(now))
+        .filter(outbox_events::dsl::attempt_count.lt(max_attempts))
+        .order_by(outbox_events::dsl::id.asc())
+        .limit(limit)
+        .select((
+            outbox_events::dsl::id,
+            outbox_events::dsl::user_id,
+            outbox_events::dsl::event_type,
+            outbox_events::dsl::payload,
+            outbox_events::dsl::attempt_count,
+        ));
+    conn.run(move |conn| query.load(conn)).await
+}
+
+pub(crate) async fn set_outbox_event_delivered(
+    conn: &DbConn,
+    event_id: i64,
+    delivered_at: NaiveDateTime,
+) -> QueryResult<usize> {
+    use crate::schema::outbox_events;
+
+    conn.run(move |conn| {
+        diesel::update(outbox_events::table.find(event_id))
+            .set((
+                outbox_events::dsl::delivered_at.eq(delivered_at),
+                outbox_events::dsl::attempt_count.eq(outbox_events::dsl::attempt_count + 1),
+            ))
+            .execute(conn)
+    })
+    .await
+}
+
+pub(crate) async fn set_outbox_event_failed(
+    conn: &DbConn,
+    event_id: i64,
+    next_attempt_at: NaiveDateTime,
+    error: String,
+) -> QueryResult<usize> {
+    use crate::schema::outbox_events;
+
+    conn.run(move |conn| {
+        diesel::update(outbox_events::table.find(event_id))
+            .set((
+                outbox_events::dsl::attempt_count.eq(outbox_events::dsl::attempt_count + 1),
+                outbox_events::dsl::next_attempt_at.eq(next_attempt_at),
+                outbox_events::dsl::last_error.eq(Some(error)),
+            ))
+            .execute(conn)
+    })
+    .await
+}
+
+/// Deletes outbox events that were delivered before `cutoff`
+pub(crate) async fn delete_delivered_outbox_events(
+    conn: &DbConn,
+    cutoff: NaiveDateTime,
+) -> QueryResult<usize> {
+    use crate::schema::outbox_events;
+
+    conn.run(move |conn| {
+        diesel::delete(outbox_events::table.filter(outbox_events::dsl::delivered_at.lt(cutoff)))
+            .execute(conn)
+    })
+    .await
+}
+
+/// Returns `(timeframe, spotify_id)` for each of the user's top artists from their most recent
+/// update, ordered by timeframe and then ranking.
+pub(crate) async fn get_latest_top_artist_ids(
+    conn: &DbConn,
+    user_id: i64,
+) -> Result<Vec<(Timeframe, String)>, diesel::result::Error> {
+    use crate::schema::{artist_rank_snapshots, spotify_items};
+
+    let last_update_time = get_last_snapshot_time(conn, user_id).await?;
+    let last_update_time = match last_update_time {
+        Some(last_update_time) => last_update_time,
+        None => return Ok(Vec::new()),
+    };
+
+    let query = artist_rank_snapshots::table
+        .filter(
+            artist_rank_snapshots::dsl::user_id
+                .eq(user_id)
+                .and(artist_rank_snapshots::dsl::update_time.eq(last_update_time)),
+        )
+        .inner_join(spotify_items::table)
+        .order_by((
+            artist_rank_snapshots::dsl::timeframe,
+            artist_rank_snapshots::dsl::ranking,
+        ))
+        .select((
+            artist_rank_snapshots::dsl::timeframe,
+            spotify_items::dsl::spotify_id,
+        ));
+    conn.run(move |conn| query.load(conn)).await
+}
+
+/// Returns `(timeframe, spotify_id)` for each of the user's top tracks from their most recent
+/// update, ordered by timeframe and then ranking.
+pub(crate) async fn get_latest_top_track_ids(
+    conn: &DbConn,
+    user_id: i64,
+) -> Result<Vec<(Timeframe, String)>, diesel::result::Error> {
+    use crate::schema::{spotify_items, track_rank_snapshots};
+
+    let last_update_time = get_last_snapshot_time(conn, user_id).await?;
+    let last_update_time = match last_update_time {
+        Some(last_update_time) => last_update_time,
+        None => return Ok(Vec::new()),
+    };
+
+    let query = track_rank_snapshots::table
+        .filter(
+            track_rank_snapshots::dsl::user_id
+                .eq(user_id)
+                .and(track_rank_snapshots::dsl::update_time.eq(last_update_time)),
+        )
+        .inner_join(spotify_items::table)
+        .order_by((
+            track_rank_snapshots::dsl::timeframe,
+            track_rank_snapshots::dsl::ranking,
+        ))
+        .select((
+            track_rank_snapshots::dsl::timeframe,
+            spotify_items::dsl::spotify_id,
+        ));
+    conn.run(move |conn| query.load(conn)).await
+}
+
+pub(crate) async fn get_all_top_tracks_for_user(
+    conn: &DbConn,
+    user_id: i64,
+) -> Result<Vec<(i32, String)>, diesel::result::Error> {
+    use crate::schema::{spotify_items, tracks_users_first_seen};
+
+    let query = tracks_users_first_seen::table
+        .filter(tracks_users_first_seen::dsl::user_id.eq(user_id))
+        .inner_join(
+            spotify_items::table
+                .on(spotify_items::dsl::id.eq(tracks_users_first_seen::dsl::mapped_spotify_id)),
+        )
+        .select((
+            tracks_users_first_seen::dsl::mapped_spotify_id,
+            spotify_items::dsl::spotify_id,
+        ))
+        .distinct();
+    conn.run(move |conn| query.load(conn)).await
+}
+
+pub(crate) async fn get_all_top_artists_for_user(
+    conn: &DbConn,
+    user_id: i64,
+) -> Result<Vec<(i32, String)>, diesel::result::Error> {
+    use crate::schema::{artists_users_first_seen, spotify_items};
+
+    let query = artists_users_first_seen::table
+        .filter(artists_users_first_seen::dsl::user_id.eq(user_id))
+        .inner_join(
+            spotify_items::table
+                .on(spotify_items::dsl::id.eq(artists_users_first_seen::dsl::mapped_spotify_id)),
+        )
+        .select((
+            artists_users_first_seen::dsl::mapped_spotify_id,
+            spotify_items::dsl::spotify_id,
+        ))
+        .distinct();
+    conn.run(move |conn| query.load(conn)).await
+}
+
+/// Returns the Spotify IDs of all tracks that have appeared in the user's top tracks or recently
+/// played tracks.
+pub(crate) async fn get_all_track_spotify_ids_for_user(
+    conn: &DbConn,
+    user_id: i64,
+) -> Result<HashSet<String>, diesel::result::Error> {
+    use crate::schema::{recently_played, spotify_items, tracks_users_first_seen};
+
+    let top_tracks_query = tracks_users_first_seen::table
+        .filter(tracks_users_first_seen::dsl::user_id.eq(user_id))
+        .inner_join(
+            spotify_items::table
+                .on(spotify_items::dsl::id.eq(tracks_users_first_seen::dsl::mapped_spotify_id)),
+        )
+        .select(spotify_items::dsl::spotify_id);
+    let recently_played_query = recently_played::table
+        .filter(recently_played::dsl::user_id.eq(user_id))
+        .inner_join(spotify_items::table)
+        .select(spotify_items::dsl::spotify_id)
+        .distinct();
+    conn.run(move |conn| {
+        let mut spotify_ids: HashSet<String> =
+            top_tracks_query.load::<String>(conn)?.into_iter().collect();
+        spotify_ids.extend(recently_played_query.load::<String>(conn)?);
+        Ok(spotify_ids)
+    })
+    .await
+}
+
+/// Number of entries stored in the global charts for each timeframe
+pub(crate) const GLOBAL_CHART_SIZE: usize = 100;
+
+/// Value stored in `entity_type` columns for each kind of entity
+pub(crate) fn entity_type_code(entity: ExportEntity) -> u8 {
+    match entity {
+        ExportEntity::Artists => 0,
+        ExportEntity::Tracks => 1,
+    }
+}
+
+#[derive(QueryableByName)]
+struct GlobalChartQueryResItem {
+    #[sql_type = "crate::db_backend::sql_types::Unsigned<diesel::sql_types::TinyInt>"]
+    timeframe: Timeframe,
+    #[sql_type = "diesel::sql_types::Integer"]
+    mapped_spotify_id: i32,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    user_count: i64,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    score: i64,
+}
+
+#[derive(QueryableByName)]
+struct SpotifyIdQueryResItem {
+    #[sql_type = "diesel::sql_types::Text"]
+    spotify_id: String,
+}
+
+/// Returns the distinct Spotify IDs of the given entity across the most recent snapshot of every
+/// user.  Users whose snapshots have all been moved to external storage aren't included.
+pub(crate) async fn get_latest_snapshot_spotify_ids(
+    conn: &DbConn,
+    entity: ExportEntity,
+) -> QueryResult<Vec<String>> {
+    let snapshots_table = match entity {
+        ExportEntity::Artists => "artist_rank_snapshots",
+        ExportEntity::Tracks => "track_rank_snapshots",
+    };
+
+    conn.run(move |conn| {
+        diesel::sql_query(portable_sql(&format!(
+            "SELECT DISTINCT `spotify_items`.`spotify_id` FROM `{table}` AS `snapshots` INNER \
+             JOIN (SELECT `user_id`, MAX(`update_time`) AS `update_time` FROM `{table}` GROUP BY \
+             `user_id`) AS `latest` ON `snapshots`.`user_id` = `latest`.`user_id` AND \
+             `snapshots`.`update_time` = `latest`.`update_time` INNER JOIN `spotify_items` ON \
+             `spotify_items`.`id` = `snapshots`.`mapped_spotify_id`",
+            table = snapshots_table,
+        )))
+        .load::<SpotifyIdQueryResItem>(conn)
+    })
+    .await
+    .map(|items| items.into_iter().map(|item| item.spotify_id).collect())
+}
+
+#[derive(QueryableByName)]
+struct LeaderboardQueryResItem {
+    #[sql_type = "diesel::sql_types::Text"]
+    spotify_id: String,
+    #[sql_type = "diesel::sql_types::Text"]
+    username: String,
+    #[sql_type = "diesel::sql_types::Nullable<diesel::sql_types::Text>"]
+    vanity_slug: Option<String>,
+    #[sql_type = "diesel::sql_types::Nullable<diesel::sql_types::Text>"]
+    display_name: Option<String>,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    best_ranking: i64,
+}
+
+/// Returns `(spotify_id, vanity_slug, display_name, best_ranking)` for the `limit` users that
+/// rank the artist highest in their most recent snapshot, best ranking first.  Only users that
+/// have opted in to leaderboards and whose stats are public and active are included.  Rankings are
+/// read from `latest_artist_stats` rather than searching the snapshot history.
+pub(crate) async fn get_artist_leaderboard(
+    conn: &DbConn,
+    artist_spotify_id: String,
+    limit: u32,
+) -> QueryResult<Vec<(String, Option<String>, String, u16)>> {
+    let query = diesel::sql_query(portable_sql(&format!(
+        "SELECT `users`.`spotify_id`, `users`.`username`, `users`.`vanity_slug`, \
+         `user_settings`.`display_name`, CAST(MIN(`latest`.`ranking`) AS {signed}) AS \
+         `best_ranking` FROM `latest_artist_stats` AS `latest` INNER JOIN `spotify_items` ON \
+         `spotify_items`.`id` = `latest`.`mapped_spotify_id` INNER JOIN `users` ON `users`.`id` = \
+         `latest`.`user_id` INNER JOIN `user_settings` ON `user_settings`.`user_id` = \
+         `users`.`id` WHERE `spotify_items`.`spotify_id` = ? AND \
+         `user_settings`.`leaderboard_opt_in` AND NOT `users`.`is_private` AND \
+         `users`.`deactivated_at` IS NULL GROUP BY `users`.`id`, `users`.`spotify_id`, \
+         `users`.`username`, `users`.`vanity_slug`, `user_settings`.`display_name` ORDER BY \
+         `best_ranking`, `users`.`id` LIMIT ?",
+        signed = SIGNED_BIGINT,
+    )))
+    .bind::<diesel::sql_types::Text, _>(artist_spotify_id)
+    .bind::<diesel::sql_types::BigInt, _>(limit as i64);
+
+    let items = conn
+        .run(move |conn| query.load::<LeaderboardQueryResItem>(conn))
+        .await?;
+    Ok(items
+        .into_iter()
+        .map(|item| {
+            (
+                item.spotify_id,
+                item.vanity_slug,
+                item.display_name.unwrap_or(item.username),
+                item.best_ranking as u16,
+            )
+        })
+        .collect())
+}
+
+/// Recomputes the global charts for the given entity from the most recent snapshot of every user.
+/// Each appearance in a user's top 50 is worth `50 - ranking` points; deeper rankings are ignored
+/// so that users with a larger `entity_fetch_count` don't carry more weight.  Users whose
+/// snapshots have all been moved to external storage aren't included.
+pub(crate) async fn refresh_global_charts(conn: &DbConn, entity: ExportEntity) -> QueryResult<()> {
+    let snapshots_table = match entity {
+        ExportEntity::Artists => "artist_rank_snapshots",
+        ExportEntity::Tracks => "track_rank_snapshots",
+    };
+    let entity_type = entity_type_code(entity);
+
+    conn.run(move |conn| {
+        let items = diesel::sql_query(portable_sql(&format!(
+            "SELECT `snapshots`.`timeframe`, `snapshots`.`mapped_spotify_id`, COUNT(*) AS \
+             `user_count`, CAST(SUM(50 - CAST(`snapshots`.`ranking` AS {signed})) AS {signed}) AS \
+             `score` FROM `{table}` AS `snapshots` INNER JOIN (SELECT `user_id`, \
+             MAX(`update_time`) AS `update_time` FROM `{table}` GROUP BY `user_id`) AS `latest` \
+             ON `snapshots`.`user_id` = `latest`.`user_id` AND `snapshots`.`update_time` = \
+             `latest`.`update_time` WHERE `snapshots`.`ranking` < 50 GROUP BY \
+             `snapshots`.`timeframe`, `snapshots`.`mapped_spotify_id` ORDER BY \
+             `snapshots`.`timeframe`, `score` DESC, `user_count` DESC",
+            table = snapshots_table,
+            signed = SIGNED_BIGINT,
+        )))
+        .load::<GlobalChartQueryResItem>(conn)?;
+
+        let computed_at = Utc::now().naive_utc();
+        let mut entries: Vec<NewGlobalChartEntry> = Vec::new();
+        let mut counts_by_timeframe: HashMap<Timeframe, u16> = HashMap::default();
+        for item in items {
+            let count = counts_by_timeframe.entry(item.timeframe).or_insert(0);
+            if *count as usize >= GLOBAL_CHART_SIZE {
+                continue;
+            }
+            *count += 1;
+
+            entries.push(NewGlobalChartEntry {
+                entity_type,
+                timeframe: item.timeframe,
+                ranking: *count - 1,
+                mapped_spotify_id: item.mapped_spotify_id,
+                user_count: item.user_count,
+                score: item.score,
+                computed_at,
+            });
+        }
+
+        conn.transaction(|| {
+            use crate::schema::global_charts;
+
+            diesel::delete(
+                global_charts::table.filter(global_charts::dsl::entity_type.eq(bind(entity_type))),
+            )
+            .execute(conn)?;
+            diesel::insert_into(global_charts::table)
+                .values(&entries)
+                .execute(conn)?;
+            Ok(())
+        })
+    })
+    .await
+}
+
+/// Returns `(timeframe, ranking, spotify_id, user_count, score, computed_at)` for the top
+/// `limit` entries of each timeframe of the global charts, ordered by timeframe and then ranking.
+pub(crate) async fn get_global_chart(
+    conn: &DbConn,
+    entity: ExportEntity,
+    limit: u16,
+) -> QueryResult<Vec<(Timeframe, u16, String, i64, i64, NaiveDateTime)>> {
+    use crate::schema::{global_charts, spotify_items};
+
+    let query = global_charts::table
+        .filter(
+            global_charts::dsl::entity_type
+                .eq(bind(entity_type_code(entity)))
+                .and(global_charts::dsl::ranking.lt(bind(limit))),
+        )
+        .inner_join(spotify_items::table)
+        .order_by((global_charts::dsl::timeframe, global_charts::dsl::ranking))
+        .select((
+            global_charts::dsl::timeframe,
+            global_charts::dsl::ranking,
+            spotify_items::dsl::spotify_id,
+            global_charts::dsl::user_count,
+            global_charts::dsl::score,
+            global_charts::dsl::computed_at,
+        ));
+    conn.run(move |conn| query.load(conn)).await
+}
+
+#[derive(QueryableByName)]
+struct TableSizeQueryResItem {
+    #[sql_type = "diesel::sql_types::Text"]
+    table_name: String,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    row_count: i64,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    size_bytes: i64,
+}
+
+#[cfg(feature = "mysql")]
+const TABLE_SIZES_QUERY: &str = "SELECT `TABLE_NAME` AS `table_name`, CAST(COALESCE(`TABLE_ROWS`, \
+                                 0) AS SIGNED) AS `row_count`, CAST(COALESCE(`DATA_LENGTH`, 0) + \
+                                 COALESCE(`INDEX_LENGTH`, 0) AS SIGNED) AS `size_bytes` FROM \
+                                 `information_schema`.`TABLES` WHERE `TABLE_SCHEMA` = DATABASE() \
+                                 AND `TABLE_TYPE` = 'BASE TABLE'";
+#[cfg(feature = "postgres")]
+const TABLE_SIZES_QUERY: &str =
+    "SELECT CAST(`relname` AS TEXT) AS `table_name`, CAST(`n_live_tup` AS BIGINT) AS `row_count`, \
+     CAST(pg_total_relation_size(`relid`) AS BIGINT) AS `size_bytes` FROM `pg_stat_user_tables`";
+
+/// Computes totals for the whole database and stores them along with the provided cache size.
+/// Row counts and sizes come from the database's table statistics, so they're estimates.
+pub(crate) async fn store_system_stats(
+    conn: &DbConn,
+    cache_size_bytes: Option<i64>,
+) -> QueryResult<()> {
+    use crate::schema::{system_stats, users};
+
+    conn.run(move |conn| {
+        let user_count: i64 = users::table.count().get_result(conn)?;
+        let snapshot_count = diesel::sql_query(portable_sql(
+            "SELECT COUNT(*) AS `count` FROM (SELECT DISTINCT `user_id`, `update_time` FROM \
+             `snapshot_updates`) AS `snapshots`",
+        ))
+        .get_result::<CountQueryResItem>(conn)?
+        .count;
+        let tables = diesel::sql_query(portable_sql(TABLE_SIZES_QUERY))
+            .load::<TableSizeQueryResItem>(conn)?;
+
+        let database_size_bytes = tables.iter().map(|table| table.size_bytes).sum();
+        let table_row_counts: BTreeMap<String, i64> = tables
+            .into_iter()
+            .map(|table| (table.table_name, table.row_count))
+            .collect();
+
+        diesel::insert_into(system_stats::table)
+            .values(&NewSystemStats {
+                computed_at: Utc::now().naive_utc(),
+                user_count,
+                snapshot_count,
+                table_row_counts: serde_json::to_string(&table_row_counts)
+                    .expect("Failed to serialize table row counts"),
+                database_size_bytes,
+                cache_size_bytes,
+            })
+            .execute(conn)?;
+        Ok(())
+    })
+    .await
+}
+
+/// Returns the most recently computed system stats, newest first
+pub(crate) async fn get_system_stats_history(
+    conn: &DbConn,
+    limit: i64,
+) -> QueryResult<Vec<SystemStatsEntry>> {
+    use crate::schema::system_stats::dsl::*;
+
+    conn.run(move |conn| {
+        system_stats
+            .select((
+                computed_at,
+                user_count,
+                snapshot_count,
+                table_row_counts,
+                database_size_bytes,
+                cache_size_bytes,
+            ))
+            .order_by(computed_at.desc())
+            .limit(limit)
+            .load(conn)
+    })
+    .await
+}
+
+pub(crate) async fn get_latest_system_stats(
+    conn: &DbConn,
+) -> QueryResult<Option<SystemStatsEntry>> {
+    Ok(get_system_stats_history(conn, 1).await?.pop())
+}
+
+#[derive(QueryableByName)]
+struct GenreEntropyQueryResItem {
+    #[sql_type = "diesel::sql_types::Float"]
+    genre_entropy: f32,
+}
+
+/// Returns the genre entropy from each user's most recent diversity score for `timeframe`
+pub(crate) async fn get_latest_genre_entropies(
+    conn: &DbConn,
+    timeframe: Timeframe,
+) -> QueryResult<Vec<f32>> {
+    let query = diesel::sql_query(portable_sql(
+        "SELECT `diversity_scores`.`genre_entropy` FROM `diversity_scores` INNER JOIN (SELECT \
+         `user_id`, MAX(`update_time`) AS `update_time` FROM `diversity_scores` WHERE `timeframe` \
+         = ? GROUP BY `user_id`) AS `latest` ON `diversity_scores`.`user_id` = `latest`.`user_id` \
+         AND `diversity_scores`.`update_time` = `latest`.`update_time` WHERE \
+         `diversity_scores`.`timeframe` = ?",
+    ))
+    .bind::<diesel::sql_types::Integer, _>(timeframe.id() as i32)
+    .bind::<diesel::sql_types::Integer, _>(timeframe.id() as i32);
+
+    conn.run(move |conn| {
+        Ok(query
+            .load::<GenreEntropyQueryResItem>(conn)?
+            .into_iter()
+            .map(|item| item.genre_entropy)
+            .collect())
+    })
+    .await
+}
+
+pub(crate) async fn refresh_user_access_token(conn: &DbConn, user: &mut User) -> Result<(), Error> {
+    use crate::schema::users;
+
+    // Update the access token for that user using the refresh token
+    let updated_access_token =
+        match crate::spotify_api::refresh_user_token(&user.refresh_token).await {
+            Ok(updated_access_token) => updated_access_token,
+            // The user has revoked our access, so they're deactivated rather than failing every
+            // update until they sign in again and reactivate their account
+            Err(Error::SpotifyUnauthorized) => {
+                set_user_deactivated(conn, user, true).await?;
+                let msg = format!(
+                    "Refresh token for user {} was revoked; deactivating user",
+                    user.username
+                );
+                info!("{}", msg);
+                return Err(Error::Unauthorized(msg));
+            },
+            Err(_) => {
+                update_user_last_updated(&user, &conn, Utc::now().naive_utc()).await?;
+
+                let msg = format!(
+                    "Failed to refresh user token for user {}; updating last updated timestamp \
+                     and not updating.",
+                    user.username
+                );
+                info!("{}", msg);
+                return Err(Error::SpotifyApi(msg));
+            },
+        };
+    let query = diesel::update(users::table.filter(users::dsl::id.eq(user.id)))
+        .set(users::dsl::token.eq(StoredToken(updated_access_token.clone())));
+    conn.run(move |conn| query.execute(conn))
+        .await
+        .map_err(|err| {
+            error!("{:?}", err);
+            Error::Internal("Error updating user with new access token".into())
+        })?;
+    user.token = updated_access_token;
+
+    Ok(())
+}
+
+pub(crate) async fn insert_related_artists(
+    conn: &DbConn,
+    related_artists: Vec<NewRelatedArtistEntry>,
+) -> QueryResult<()> {
+    use crate::schema::related_artists;
+
+    let all_artist_ids: Vec<i32> = related_artists
+        .iter()
+        .map(|entry| entry.artist_spotify_id)
+        .collect();
+
+    conn.run(move |conn| {
+        conn.transaction(|| -> QueryResult<()> {
+            diesel::delete(
+                related_artists::table
+                    .filter(related_artists::dsl::artist_spotify_id.eq_any(all_artist_ids)),
+            )
+            .execute(conn)?;
+
+            diesel::insert_into(related_artists::table)
+                .values(related_artists)
+                .execute(conn)?;
+
+            Ok(())
+        })
+    })
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(feature = "mysql")]
+const PROFILE_VIEW_INCREMENT_CLAUSE: &str =
+    "ON DUPLICATE KEY UPDATE `view_count` = `view_count` + VALUES(`view_count`)";
+#[cfg(feature = "postgres")]
+const PROFILE_VIEW_INCREMENT_CLAUSE: &str = "ON CONFLICT (`user_id`, `view_date`) DO UPDATE SET \
+                                             `view_count` = `profile_views`.`view_count` + \
+                                             EXCLUDED.`view_count`";
+
+/// Returns midnight UTC of the day containing `time`, which daily profile view counts are keyed by
+pub(crate) fn start_of_day(time: NaiveDateTime) -> NaiveDateTime {
+    time.date().and_hms_opt(0, 0, 0).unwrap()
+}
+
+/// Adds batches of profile views to their users' daily totals and updates the users' last viewed
+/// times.  Each entry is `(user_id, view_date, view_count, last_viewed)`, where `view_date` is the
+/// start of the day the views happened on.
+pub(crate) async fn record_profile_views(
+    conn: &DbConn,
+    views: Vec<(i64, NaiveDateTime, i32, NaiveDateTime)>,
+) -> QueryResult<()> {
+    use crate::schema::users;
+
+    if views.is_empty() {
+        return Ok(());
+    }
+
+    let increment_sql = portable_sql(&format!(
+        "INSERT INTO `profile_views` (`user_id`, `view_date`, `view_count`) VALUES (?, ?, ?) {}",
+        PROFILE_VIEW_INCREMENT_CLAUSE
+    ))
+    .into_owned();
+    let mut last_viewed_by_user_id: HashMap<i64, NaiveDateTime> = HashMap::default();
+    for &(user_id, _, _, last_viewed) in &views {
+        let entry = last_viewed_by_user_id.entry(user_id).or_insert(last_viewed);
+        *entry = (*entry).max(last_viewed);
+    }
+    conn.run(move |conn| {
+        conn.transaction(|| {
+            for (user_id, view_date, view_count, _) in views {
+                diesel::sql_query(increment_sql.clone())
+                    .bind::<diesel::sql_types::BigInt, _>(user_id)
+                    .bind::<diesel::sql_types::Timestamp, _>(view_date)
+                    .bind::<diesel::sql_types::Integer, _>(view_count)
+                    .execute(conn)?;
+            }
+            for (user_id, last_viewed) in last_viewed_by_user_id {
+                diesel::update(users::table.filter(users::dsl::id.eq(user_id)))
+                    .set(users::dsl::last_viewed.eq(last_viewed))
+                    .execute(conn)?;
+            }
+            Ok(())
+        })
+    })
+    .await
+}
+
+/// Returns the total number of times the user's profile has been viewed
+pub(crate) async fn get_profile_view_count(conn: &DbConn, user_id: i64) -> QueryResult<i64> {
+    let query = diesel::sql_query(portable_sql(&format!(
+        "SELECT CAST(COALESCE(SUM(`view_count`), 0) AS {}) AS `count` FROM `profile_views` WHERE \
+         `user_id` = ?",
+        SIGNED_BIGINT
+    )))
+    .bind::<diesel::sql_types::BigInt, _>(user_id);
+
+    conn.run(move |conn| query.get_result::<CountQueryResItem>(conn))
+        .await
+        .map(|res| res.count)
+}
+
+/// Selects the IDs of users whose profiles have been viewed at least `?` times since `?`
+const ACTIVE_USERS_SUBQUERY: &str = "SELECT `user_id` FROM `profile_views` WHERE `view_date` >= ? \
+                                     GROUP BY `user_id` HAVING SUM(`view_count`) >= ?";
+
+/// Moves users between update tiers.  Users whose profiles have been viewed often enough recently
+/// are active, users whose profiles haven't been viewed in a long time are dormant, and everyone
+/// else is normal.  Returns the number of users whose tier changed.
+pub(crate) async fn refresh_update_tiers(conn: &DbConn, now: NaiveDateTime) -> QueryResult<usize> {
+    use crate::schema::users;
+
+    let active_cutoff = start_of_day(now - crate::conf::CONF.active_tier_max_view_age);
+    let active_min_views = crate::conf::CONF.active_tier_min_views;
+    let dormant_cutoff = now - crate::conf::CONF.dormant_tier_min_view_age;
+    let set_tier_query = |tier: UpdateTier, condition: &str| {
+        diesel::sql_query(portable_sql(&format!(
+            "UPDATE `users` SET `update_tier` = ? WHERE `update_tier` <> ? AND `last_viewed` > ? \
+             AND `id` {} ({})",
+            condition, ACTIVE_USERS_SUBQUERY
+        )))
+        .bind::<diesel::sql_types::Integer, _>(tier.id() as i32)
+        .bind::<diesel::sql_types::Integer, _>(tier.id() as i32)
+        .bind::<diesel::sql_types::Timestamp, _>(dormant_cutoff)
+        .bind::<diesel::sql_types::Timestamp, _>(active_cutoff)
+        .bind::<diesel::sql_types::BigInt, _>(active_min_views)
+    };
+    let active_query = set_tier_query(UpdateTier::Active, "IN");
+    let normal_query = set_tier_query(UpdateTier::Normal, "NOT IN");
+    let dormant = UpdateTier::Dormant.id();
+
+    conn.run(move |conn| {
+        conn.transaction(|| {
+            let dormant_count = diesel::update(
+                users::table
+                    .filter(users::dsl::last_viewed.le(dormant_cutoff))
+                    .filter(users::dsl::update_tier.ne(bind(dormant))),
+            )
+            .set(users::dsl::update_tier.eq(bind(dormant)))
+            .execute(conn)?;
+            let active_count = active_query.execute(conn)?;
+            let normal_count = normal_query.execute(conn)?;
+            Ok(dormant_count + active_count + normal_count)
+        })
+    })
+    .await
+}
+
+/// Columns that `ApiKeyRow` is loaded from
+pub(crate) const API_KEY_COLUMNS: (
+    crate::schema::api_keys::id,
+    crate::schema::api_keys::name,
+    crate::schema::api_keys::scopes,
+    crate::schema::api_keys::rate_limit_per_minute,
+    crate::schema::api_keys::created_at,
+    crate::schema::api_keys::revoked_at,
+) = (
+    crate::schema::api_keys::id,
+    crate::schema::api_keys::name,
+    crate::schema::api_keys::scopes,
+    crate::schema::api_keys::rate_limit_per_minute,
+    crate::schema::api_keys::created_at,
+    crate::schema::api_keys::revoked_at,
+);
+
+/// Stores a newly minted API key and returns the stored row
+pub(crate) async fn insert_api_key(conn: &DbConn, api_key: NewApiKey) -> QueryResult<ApiKeyRow> {
+    use crate::schema::api_keys;
+
+    conn.run(move |conn| {
+        conn.transaction(|| {
+            diesel::insert_into(api_keys::table)
+                .values(&api_key)
+                .execute(conn)?;
+            api_keys::table
+                .filter(api_keys::dsl::key_hash.eq(&api_key.key_hash))
+                .select(API_KEY_COLUMNS)
+                .first(conn)
+        })
+    })
+    .await
+}
+
+/// Returns the API key with the provided hash, including revoked keys
+pub(crate) async fn get_api_key_by_hash(
+    conn: &DbConn,
+    key_hash: String,
+) -> QueryResult<Option<ApiKeyRow>> {
+    use crate::schema::api_keys;
+
+    let query = api_keys::table
+        .filter(api_keys::dsl::key_hash.eq(key_hash))
+        .select(API_KEY_COLUMNS);
+    conn.run(move |conn| query.first(conn).optional()).await
+}
+
+/// Returns all API keys including revoked ones, newest first
+pub(crate) async fn get_api_keys(conn: &DbConn) -> QueryResult<Vec<ApiKeyRow>> {
+    use crate::schema::api_keys;
+
+    let query = api_keys::table
+        .order_by(api_keys::dsl::id.desc())
+        .select(API_KEY_COLUMNS);
+    conn.run(move |conn| query.load(conn)).await
+}
+
+/// Revokes the API key so that it's no longer accepted.  Returns the number of keys revoked, which
+/// is 0 if the key doesn't exist or was already revoked.
+pub(crate) async fn revoke_api_key(
+    conn: &DbConn,
+    api_key_id: i64,
+    now: NaiveDateTime,
+) -> QueryResult<usize> {
+    use crate::schema::api_keys;
+
+    let query = diesel::update(
+        api_keys::table
+            .filter(api_keys::dsl::id.eq(api_key_id))
+            .filter(api_keys::dsl::revoked_at.is_null()),
+    )
+    .set(api_keys::dsl::revoked_at.eq(now));
+    conn.run(move |conn| query.execute(conn)).await
+}
+
+#[test]
+fn delta_encoded_snapshots() {
+    let at = |day: u32| {
+        chrono::NaiveDate::from_ymd_opt(2026, 10, day)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+    };
+
+    let (short, medium, long) = (Timeframe::Short, Timeframe::Medium, Timeframe::Long);
+    let previous: SnapshotRankings = [((short, 0), 10), ((short, 1), 11), ((medium, 0), 12)]
+        .into_iter()
+        .collect();
+    let mut entries = vec![
+        (short, 0, 10),
+        (short, 1, 20),
+        (medium, 0, 12),
+        (medium, 1, 13),
+    ];
+    retain_changed_rankings(&mut entries, &previous, |&entry| entry);
+    assert_eq!(entries, vec![(short, 1, 20), (medium, 1, 13)]);
+
+    let counts = count_snapshot_entries(
+        [
+            (1, at(1), short, 0),
+            (1, at(1), short, 1),
+            (1, at(1), long, 0),
+        ]
+        .into_iter(),
+        [(1, at(1), short, 0)].into_iter(),
+    );
+    assert_eq!(counts, vec![
+        SnapshotUpdate {
+            user_id: 1,
+            update_time: at(1),
+            timeframe: short,
+            artist_count: 2,
+            track_count: 1,
+        },
+        SnapshotUpdate {
+            user_id: 1,
+            update_time: at(1),
+            timeframe: long,
+            artist_count: 1,
+            track_count: 0,
+        },
+    ]);
+
+    let update_times = [at(1), at(2), at(3), at(4), at(5)];
+    let to_delete = [at(2), at(3), at(5)].into_iter().collect();
+    assert_eq!(
+        get_update_times_to_materialize(&update_times, &to_delete),
+        vec![at(4)]
+    );
+
+    // The second update only stores its changed ranking and drops its second entry; the third
+    // stores nothing new
+    let delta = |id, day, mapped_spotify_id, ranking| UserHistoryEntry {
+        id,
+        user_id: 1,
+        update_time: at(day),
+        mapped_spotify_id,
+        timeframe: short,
+        ranking,
+    };
+    let deltas = [delta(1, 1, 10, 0), delta(2, 1, 11, 1), delta(3, 2, 12, 0)];
+    let resolved: Vec<(NaiveDateTime, i64, i32, u8)> = resolve_rank_deltas(
+        &[(at(1), short, 2), (at(2), short, 1), (at(3), short, 2)],
+        &deltas,
+    )
+    .into_iter()
+    .map(|entry| {
+        (
+            entry.update_time,
+            entry.id,
+            entry.mapped_spotify_id,
+            entry.ranking,
+        )
+    })
+    .collect();
+    assert_eq!(resolved, vec![
+        (at(1), 1, 10, 0),
+        (at(1), 2, 11, 1),
+        (at(2), 3, 12, 0),
+        (at(3), 3, 12, 0),
+        (at(3), 2, 11, 1),
+    ]);
+}
+
+#[test]
+fn like_pattern_escaping() {
+    assert_eq!(escape_like_pattern("100% Pure_Love"), "100\\% Pure\\_Love");
+    assert_eq!(escape_like_pattern("AC\\DC"), "AC\\\\DC");
+    assert_eq!(escape_like_pattern("Radiohead"), "Radiohead");
+}
+
+/// Runs the queries whose SQL differs between backends against a real database.  Needs
+/// `DATABASE_URL` to point at a migrated database, so it only runs in CI.
+#[test]
+#[ignore]
+fn backend_query_compatibility() {
+    use chrono::Timelike;
+
+    use crate::schema::{user_settings, users};
+
+    let database_url = std::env::var("DATABASE_URL").expect("`DATABASE_URL` must be set");
+    let conn = DbConnection::establish(&database_url).unwrap();
+    conn.test_transaction::<_, diesel::result::Error, _>(|| {
+        let now = Utc::now().naive_utc().with_nanosecond(0).unwrap();
+        // Tokens are inserted as raw strings since `StoredToken` needs `CONF` for the encryption
+        // key
+        diesel::insert_into(users::table)
+            .values((
+                users::creation_time.eq(now),
+                users::last_update_time.eq(now),
+                users::spotify_id.eq("compat-test-user"),
+                users::username.eq("Compat Test"),
+                users::token.eq(""),
+                users::refresh_token.eq(""),
+            ))
+            .execute(&conn)?;
+        let user_id: i64 = users::table
+            .filter(users::spotify_id.eq("compat-test-user"))
+            .select(users::id)
+            .first(&conn)?;
+
+        let update = |artist_count, track_count| SnapshotUpdate {
+            user_id,
+            update_time: now,
+            timeframe: Timeframe::Short,
+            artist_count,
+            track_count,
+        };
+        merge_snapshot_updates(&conn, &[update(2, 0)])?;
+        merge_snapshot_updates(&conn, &[update(0, 3)])?;
+        assert_eq!(load_snapshot_update_times(&conn, user_id, true)?, vec![now]);
+        assert_eq!(load_snapshot_update_times(&conn, user_id, false)?, vec![
+            now
+        ]);
+
+        let mut settings = UserSettingsEntry::defaults(user_id);
+        for entity_fetch_count in [None, Some(99)] {
+            settings.entity_fetch_count = entity_fetch_count;
+            upsert!(
+                user_settings::table,
+                &settings,
+                user_settings::user_id,
+                (
+                    user_settings::display_name,
+                    user_settings::default_timeframe,
+                    user_settings::update_frequency,
+                    user_settings::entity_fetch_count,
+                )
+            )
+            .execute(&conn)?;
+        }
+        let stored = user_settings::table
+            .find(user_id)
+            .first::<UserSettingsEntry>(&conn)?;
+        assert_eq!(stored.entity_fetch_count, Some(99));
+
+        let pattern = format!("{}%", escape_like_pattern("compat-test"));
+        let count = diesel::sql_query(portable_sql(&format!(
+            "SELECT COUNT(*) AS `count` FROM `users` WHERE `spotify_id` {} ?",
+            CASE_INSENSITIVE_LIKE
+        )))
+        .bind::<diesel::sql_types::Text, _>(pattern)
+        .get_result::<CountQueryResItem>(&conn)?
+        .count;
+        assert_eq!(count, 1);
+        Ok(())
+    });
 }
@@ -0,0 +1,173 @@
+//! Dry-run updates, which fetch everything a real update would from Spotify and report what would
+//! be stored without writing anything to the database.  They're meant for debugging issues with
+//! the data Spotify returns for a specific user's account.
+//!
+//! The user's access token is refreshed in memory only, so the stored token may still be expired
+//! afterwards.  Real updates refresh it again before using it.
+
+use crate::{
+    conf::CONF,
+    db_util,
+    error::Error,
+    models::{DryRunTimeframeDiff, Timeframe, UpdateDryRunReport, User},
+    oauth_scopes::SpotifyFeature,
+    spotify_api, DbConn,
+};
+
+/// Compares the Spotify IDs of one timeframe's fetched entities with those of the latest snapshot,
+/// both ordered by rank
+pub(crate) fn diff_timeframe(
+    timeframe: Timeframe,
+    previous: &[&str],
+    current: &[&str],
+) -> DryRunTimeframeDiff {
+    let changed_count = current
+        .iter()
+        .enumerate()
+        .filter(|(ranking, spotify_id)| previous.get(*ranking) != Some(spotify_id))
+        .count();
+    DryRunTimeframeDiff {
+        timeframe,
+        fetched_count: current.len(),
+        changed_count,
+        added: current
+            .iter()
+            .filter(|spotify_id| !previous.contains(spotify_id))
+            .map(|&spotify_id| spotify_id.to_owned())
+            .collect(),
+        removed: previous
+            .iter()
+            .filter(|spotify_id| !current.contains(spotify_id))
+            .map(|&spotify_id| spotify_id.to_owned())
+            .collect(),
+    }
+}
+
+fn diff_timeframes<'a>(
+    previous: &[(Timeframe, String)],
+    current: impl Iterator<Item = (Timeframe, Vec<&'a str>)>,
+) -> Vec<DryRunTimeframeDiff> {
+    current
+        .map(|(timeframe, current_ids)| {
+            let previous_ids: Vec<&str> = previous
+                .iter()
+                .filter(|(previous_timeframe, _)| *previous_timeframe == timeframe)
+                .map(|(_, spotify_id)| spotify_id.as_str())
+                .collect();
+            diff_timeframe(timeframe, &previous_ids, &current_ids)
+        })
+        .collect()
+}
+
+/// Fetches the user's current stats along with everything else collected by updates and compares
+/// them with what's stored.  Returns `None` if there's no user with the provided Spotify ID.
+pub(crate) async fn dry_run_update(
+    conn: &DbConn,
+    spotify_id: String,
+) -> Result<Option<UpdateDryRunReport>, Error> {
+    let mut user: User = match db_util::get_user_by_spotify_id(conn, spotify_id).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    user.token = spotify_api::refresh_user_token(&user.refresh_token).await?;
+
+    let entity_fetch_count = db_util::get_user_settings(conn, &user)
+        .await?
+        .entity_fetch_count();
+    let stats = spotify_api::fetch_cur_stats(&user, entity_fetch_count)
+        .await?
+        .ok_or_else(|| Error::SpotifyApi("No data from Spotify API for that user".into()))?;
+    let previous_update_time = db_util::get_last_snapshot_time(conn, user.id).await?;
+    let previous_artist_ids = db_util::get_latest_top_artist_ids(conn, user.id).await?;
+    let previous_track_ids = db_util::get_latest_top_track_ids(conn, user.id).await?;
+
+    let artists = diff_timeframes(
+        &previous_artist_ids,
+        stats.artists.iter().map(|(timeframe, artists)| {
+            let ids = artists.iter().map(|artist| artist.id.as_str()).collect();
+            (timeframe, ids)
+        }),
+    );
+    let tracks = diff_timeframes(
+        &previous_track_ids,
+        stats.tracks.iter().map(|(timeframe, tracks)| {
+            let ids = tracks.iter().map(|track| track.id.as_str()).collect();
+            (timeframe, ids)
+        }),
+    );
+
+    let mut warnings = Vec::new();
+    let new_recently_played_count = if CONF.is_feature_enabled(SpotifyFeature::RecentlyPlayed) {
+        let last_played_at = db_util::get_last_recently_played_time(conn, user.id).await?;
+        match spotify_api::fetch_recently_played(&user.token, last_played_at).await {
+            Ok(items) => Some(items.len()),
+            Err(err) => {
+                warnings.push(format!("Error fetching recently played tracks: {}", err));
+                None
+            },
+        }
+    } else {
+        None
+    };
+    let followed_artist_count = if CONF.is_feature_enabled(SpotifyFeature::Follows) {
+        match spotify_api::fetch_followed_artists(&user.token).await {
+            Ok((artists, _truncated)) => Some(artists.len()),
+            Err(err) => {
+                warnings.push(format!("Error fetching followed artists: {}", err));
+                None
+            },
+        }
+    } else {
+        None
+    };
+    let saved_track_count = if CONF.is_feature_enabled(SpotifyFeature::Library) {
+        match spotify_api::fetch_saved_tracks(&user.token, 1).await {
+            Ok(saved_tracks) => Some(saved_tracks.total),
+            Err(err) => {
+                warnings.push(format!("Error fetching saved tracks: {}", err));
+                None
+            },
+        }
+    } else {
+        None
+    };
+
+    Ok(Some(UpdateDryRunReport {
+        spotify_id: user.spotify_id,
+        previous_update_time,
+        artists,
+        tracks,
+        new_recently_played_count,
+        followed_artist_count,
+        saved_track_count,
+        warnings,
+    }))
+}
+
+#[test]
+fn dry_run_timeframe_diffs() {
+    let diff = diff_timeframe(Timeframe::Short, &["a", "b", "c"], &["b", "a", "c", "d"]);
+    assert_eq!(diff, DryRunTimeframeDiff {
+        timeframe: Timeframe::Short,
+        fetched_count: 4,
+        changed_count: 3,
+        added: vec!["d".to_owned()],
+        removed: Vec::new(),
+    });
+
+    let previous = vec![
+        (Timeframe::Short, "a".to_owned()),
+        (Timeframe::Medium, "b".to_owned()),
+    ];
+    let diffs = diff_timeframes(
+        &previous,
+        vec![
+            (Timeframe::Short, vec!["a"]),
+            (Timeframe::Medium, vec!["c"]),
+        ]
+        .into_iter(),
+    );
+    assert_eq!(diffs[0].changed_count, 0);
+    assert_eq!(diffs[1].added, vec!["c"]);
+    assert_eq!(diffs[1].removed, vec!["b"]);
+}
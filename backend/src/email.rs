@@ -0,0 +1,44 @@
+//! Sending email via SMTP.  Used for operator alerts and users' weekly digests, which are each
+//! sent via their own configured SMTP server.
+
+use lettre::{message::MultiPart, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+use crate::error::Error;
+
+/// Returns `true` if `address` can be sent email
+pub(crate) fn is_valid_address(address: &str) -> bool { address.parse::<lettre::Address>().is_ok() }
+
+/// Sends an email via the SMTP server at `smtp_url` with a plain text body, and an HTML body as an
+/// alternative if provided
+pub(crate) async fn send_email(
+    smtp_url: &str,
+    from: &str,
+    to: &str,
+    subject: String,
+    text: String,
+    html: Option<String>,
+) -> Result<(), Error> {
+    let builder = Message::builder()
+        .from(
+            from.parse()
+                .map_err(|err| Error::Internal(format!("Invalid from address: {}", err)))?,
+        )
+        .to(to
+            .parse()
+            .map_err(|err| Error::Internal(format!("Invalid to address: {}", err)))?)
+        .subject(subject);
+    let email = match html {
+        Some(html) => builder.multipart(MultiPart::alternative_plain_html(text, html)),
+        None => builder.body(text),
+    }
+    .map_err(|err| Error::Internal(format!("Error building email: {}", err)))?;
+
+    let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(smtp_url)
+        .map_err(|err| Error::Internal(format!("Invalid SMTP URL: {}", err)))?
+        .build();
+    transport
+        .send(email)
+        .await
+        .map_err(|err| Error::Internal(format!("Error sending email: {}", err)))?;
+    Ok(())
+}
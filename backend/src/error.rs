@@ -0,0 +1,69 @@
+//! Crate-wide error type.  Errors are mapped to an HTTP status when returned from a route so that
+//! clients can distinguish bad input from upstream failures and internal errors.
+
+use rocket::{
+    http::Status,
+    response::{self, status, Responder},
+    Request,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum Error {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    /// The request is authenticated, but not as someone allowed to make it
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("Rate limited by the Spotify API")]
+    SpotifyRateLimited,
+    /// The Spotify API rejected the access token used for the request, or the refresh token used
+    /// to get a new one
+    #[error("Spotify access token is invalid or expired")]
+    SpotifyUnauthorized,
+    /// The Spotify API rejected the request because the access token lacks a scope it needs,
+    /// which happens for users that authorized before the scope was requested
+    #[error("Spotify access token is missing a required scope")]
+    SpotifyForbidden,
+    #[error("{0}")]
+    SpotifyApi(String),
+    #[error("Error querying database: {0}")]
+    Database(#[from] diesel::result::Error),
+    #[error("{0}")]
+    Internal(String),
+}
+
+impl Error {
+    pub(crate) fn status(&self) -> Status {
+        match self {
+            Error::BadRequest(_) => Status::BadRequest,
+            Error::Unauthorized(_) => Status::Unauthorized,
+            Error::Forbidden(_) => Status::Forbidden,
+            Error::NotFound(_) => Status::NotFound,
+            Error::SpotifyRateLimited => Status::ServiceUnavailable,
+            Error::SpotifyUnauthorized | Error::SpotifyForbidden | Error::SpotifyApi(_) =>
+                Status::BadGateway,
+            Error::Database(diesel::result::Error::NotFound) => Status::NotFound,
+            Error::Database(_) | Error::Internal(_) => Status::InternalServerError,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Error {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status();
+        if status == Status::InternalServerError {
+            error!("Internal error while handling {}: {}", req.uri(), self);
+        }
+
+        // Database errors can contain details about queries that shouldn't be exposed to clients
+        let body = match self {
+            Error::Database(_) => String::from("Error querying database"),
+            err => err.to_string(),
+        };
+        status::Custom(status, body).respond_to(req)
+    }
+}
@@ -0,0 +1,134 @@
+//! In-process event channels streamed as Server-Sent Events.
+//!
+//! Notifications of newly stored stats snapshots are streamed to open frontend tabs by
+//! `/events/<username>` so that they can refresh without polling.  They're published by the outbox
+//! dispatcher when it delivers `SnapshotStored` events, after the user's cached responses have been
+//! invalidated, so clients that refetch on receiving one get the new snapshot.  Outbox delivery is
+//! at-least-once, so clients may be notified more than once for the same snapshot.
+//!
+//! Scheduler activity and Spotify API requests are streamed to operators by `/admin/events` as a
+//! live tail.  These are only built and published while someone is subscribed.
+//!
+//! Events only reach clients connected to the instance that published them.
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Max number of notifications buffered for each subscriber before it starts missing them
+const CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SnapshotNotification {
+    pub user_id: i64,
+    pub update_time: NaiveDateTime,
+}
+
+/// Activity streamed to operators by `/admin/events`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub(crate) enum AdminEvent {
+    UpdatePassStarted {
+        user_count: usize,
+    },
+    UpdatePassFinished {
+        success_count: usize,
+        failure_count: usize,
+    },
+    UserUpdateStarted {
+        spotify_id: String,
+    },
+    UserUpdateFinished {
+        spotify_id: String,
+        duration_ms: u64,
+        /// Set if the update failed
+        error: Option<String>,
+    },
+    SpotifyApiRequest {
+        endpoint: &'static str,
+        /// `None` if no response was received
+        status: Option<u16>,
+        duration_ms: u64,
+    },
+}
+
+impl AdminEvent {
+    /// Name of the Server-Sent Event
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            AdminEvent::UpdatePassStarted { .. } => "update_pass_started",
+            AdminEvent::UpdatePassFinished { .. } => "update_pass_finished",
+            AdminEvent::UserUpdateStarted { .. } => "user_update_started",
+            AdminEvent::UserUpdateFinished { .. } => "user_update_finished",
+            AdminEvent::SpotifyApiRequest { .. } => "spotify_api_request",
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref SNAPSHOT_NOTIFICATIONS: broadcast::Sender<SnapshotNotification> =
+        broadcast::channel(CHANNEL_CAPACITY).0;
+    static ref ADMIN_EVENTS: broadcast::Sender<AdminEvent> = broadcast::channel(CHANNEL_CAPACITY).0;
+}
+
+/// Notifies all subscribers that a snapshot taken at `update_time` was stored for the user
+pub(crate) fn publish_snapshot_stored(user_id: i64, update_time: NaiveDateTime) {
+    // Sending only fails if nobody is subscribed
+    let _ = SNAPSHOT_NOTIFICATIONS.send(SnapshotNotification {
+        user_id,
+        update_time,
+    });
+}
+
+/// Subscribes to notifications for all users.  Only notifications published after subscribing are
+/// received.
+pub(crate) fn subscribe() -> broadcast::Receiver<SnapshotNotification> {
+    SNAPSHOT_NOTIFICATIONS.subscribe()
+}
+
+/// Publishes the event built by `build_event` to operators watching `/admin/events`.  The event
+/// isn't built if nobody is watching.
+pub(crate) fn publish_admin_event(build_event: impl FnOnce() -> AdminEvent) {
+    if ADMIN_EVENTS.receiver_count() == 0 {
+        return;
+    }
+    let _ = ADMIN_EVENTS.send(build_event());
+}
+
+pub(crate) fn subscribe_admin() -> broadcast::Receiver<AdminEvent> { ADMIN_EVENTS.subscribe() }
+
+#[test]
+fn snapshot_notifications() {
+    let update_time = chrono::NaiveDate::from_ymd_opt(2026, 10, 16)
+        .unwrap()
+        .and_hms_opt(12, 0, 0)
+        .unwrap();
+    publish_snapshot_stored(1, update_time);
+
+    let mut receiver = subscribe();
+    assert!(receiver.try_recv().is_err());
+    publish_snapshot_stored(2, update_time);
+    assert_eq!(receiver.try_recv().unwrap(), SnapshotNotification {
+        user_id: 2,
+        update_time,
+    });
+}
+
+#[test]
+fn admin_events() {
+    let event = AdminEvent::UserUpdateFinished {
+        spotify_id: "abc".to_owned(),
+        duration_ms: 1200,
+        error: None,
+    };
+    assert_eq!(event.name(), "user_update_finished");
+    assert_eq!(
+        serde_json::to_string(&event).unwrap(),
+        r#"{"spotify_id":"abc","duration_ms":1200,"error":null}"#
+    );
+
+    // Other tests may publish Spotify API requests concurrently
+    let mut receiver = subscribe_admin();
+    publish_admin_event(|| event.clone());
+    assert!(std::iter::from_fn(|| receiver.try_recv().ok()).any(|received| received == event));
+}
@@ -0,0 +1,174 @@
+//! Exporting of user history, either as CSV or as a full JSON takeout of everything stored for a
+//! user.
+
+use std::borrow::Cow;
+
+use chrono::{NaiveDateTime, Utc};
+use fnv::FnvHashSet as HashSet;
+
+use crate::{
+    db_util,
+    error::Error,
+    models::{
+        ExportedFollow, ExportedPlay, ExportedRankHistoryEntry, ExportedUser, Timeframe, User,
+        UserDataExport,
+    },
+    DbConn,
+};
+
+pub(crate) const RANK_HISTORY_CSV_HEADER: &str = "timestamp,timeframe,rank,name,spotify_id\n";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ExportEntity {
+    Tracks,
+    Artists,
+}
+
+impl ExportEntity {
+    pub(crate) fn parse(entity: &str) -> Option<Self> {
+        match entity {
+            "tracks" => Some(Self::Tracks),
+            "artists" => Some(Self::Artists),
+            _ => None,
+        }
+    }
+}
+
+/// Quotes the field if it contains any characters that have special meaning in CSV.
+fn escape_csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Formats a single row of the rank history CSV, including the trailing newline.  Rankings are
+/// exported 1-indexed.
+pub(crate) fn format_rank_history_csv_row(
+    update_time: NaiveDateTime,
+    timeframe: Timeframe,
+    ranking: u8,
+    name: &str,
+    spotify_id: &str,
+) -> String {
+    format!(
+        "{},{},{},{},{}\n",
+        update_time.format("%Y-%m-%dT%H:%M:%S"),
+        timeframe,
+        ranking as usize + 1,
+        escape_csv_field(name),
+        escape_csv_field(spotify_id)
+    )
+}
+
+fn to_exported_rank_history(
+    history: Vec<(NaiveDateTime, Timeframe, u8, String)>,
+) -> Vec<ExportedRankHistoryEntry> {
+    history
+        .into_iter()
+        .map(
+            |(update_time, timeframe, ranking, spotify_id)| ExportedRankHistoryEntry {
+                update_time,
+                timeframe: timeframe.id(),
+                ranking,
+                spotify_id,
+            },
+        )
+        .collect()
+}
+
+/// Collects all of the user's stored history along with metadata for every referenced artist and
+/// track.
+pub(crate) async fn build_user_data_export(
+    conn: &DbConn,
+    user: &User,
+    spotify_access_token: &str,
+) -> Result<UserDataExport, Error> {
+    let artist_rank_history = to_exported_rank_history(
+        db_util::get_full_rank_history(conn, user, ExportEntity::Artists).await?,
+    );
+    let track_rank_history = to_exported_rank_history(
+        db_util::get_full_rank_history(conn, user, ExportEntity::Tracks).await?,
+    );
+    let recently_played: Vec<ExportedPlay> = db_util::get_plays_since(conn, user.id, None)
+        .await?
+        .into_iter()
+        .map(|(spotify_id, played_at, _duration_ms)| ExportedPlay {
+            spotify_id,
+            played_at,
+        })
+        .collect();
+    let followed_artists: Vec<ExportedFollow> = db_util::get_follow_history(conn, user.id)
+        .await?
+        .into_iter()
+        .map(|(spotify_id, followed_at, unfollowed_at)| ExportedFollow {
+            spotify_id,
+            followed_at,
+            unfollowed_at,
+        })
+        .collect();
+
+    let artist_ids: Vec<&str> = artist_rank_history
+        .iter()
+        .map(|entry| entry.spotify_id.as_str())
+        .chain(
+            followed_artists
+                .iter()
+                .map(|follow| follow.spotify_id.as_str()),
+        )
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    let track_ids: Vec<&str> = track_rank_history
+        .iter()
+        .map(|entry| entry.spotify_id.as_str())
+        .chain(recently_played.iter().map(|play| play.spotify_id.as_str()))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    let artists = crate::spotify_api::fetch_artists(spotify_access_token, &artist_ids).await?;
+    let tracks = crate::spotify_api::fetch_tracks(spotify_access_token, &track_ids).await?;
+    let artists_by_id = artists.into_iter().collect();
+    let tracks_by_id = tracks.into_iter().collect();
+
+    Ok(UserDataExport {
+        exported_at: Utc::now().naive_utc(),
+        user: ExportedUser {
+            spotify_id: user.spotify_id.clone(),
+            username: user.username.clone(),
+            creation_time: user.creation_time,
+            last_update_time: user.last_update_time,
+            last_viewed: user.last_viewed,
+        },
+        artist_rank_history,
+        track_rank_history,
+        recently_played,
+        followed_artists,
+        artists_by_id,
+        tracks_by_id,
+    })
+}
+
+#[test]
+fn rank_history_csv_formatting() {
+    let update_time = chrono::NaiveDate::from_ymd_opt(2024, 3, 4)
+        .unwrap()
+        .and_hms_opt(12, 30, 0)
+        .unwrap();
+
+    assert_eq!(
+        format_rank_history_csv_row(
+            update_time,
+            Timeframe::Medium,
+            0,
+            "Crosby, Stills & Nash",
+            "abc123"
+        ),
+        "2024-03-04T12:30:00,medium,1,\"Crosby, Stills & Nash\",abc123\n"
+    );
+    assert_eq!(
+        format_rank_history_csv_row(update_time, Timeframe::Long, 9, "The \"Band\"", "def456"),
+        "2024-03-04T12:30:00,long,10,\"The \"\"Band\"\"\",def456\n"
+    );
+}
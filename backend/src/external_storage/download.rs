@@ -15,12 +15,16 @@ use parquet::arrow::{
 use tokio::sync::watch;
 
 use crate::{
-    db_util::get_user_by_spotify_id,
+    db_backend::insert_or_ignore,
+    db_util::{count_snapshot_entries, get_user_by_spotify_id, merge_snapshot_updates},
     metrics::{
         external_user_data_retrieval_failure_total, external_user_data_retrieval_success_total,
         external_user_data_retrieval_time,
     },
-    models::{ArtistHistoryEntry, TrackHistoryEntry},
+    models::{
+        ArtistHistoryEntry, NewArtistHistoryEntry, NewTrackHistoryEntry, Timeframe,
+        TrackHistoryEntry,
+    },
     DbConn,
 };
 
@@ -73,11 +77,38 @@ async fn insert_artist_snapshots(
     records: Vec<ArtistHistoryEntry>,
 ) -> QueryResult<usize> {
     conn.run(move |conn| {
-        use crate::schema::artist_rank_snapshots;
-
-        diesel::insert_or_ignore_into(artist_rank_snapshots::table)
-            .values(records)
-            .execute(conn)
+        use crate::schema::artist_rank_deltas;
+
+        // Stored snapshots are full, so their rows are valid deltas as-is.  IDs are dropped since
+        // rows of materialized snapshots can share them.
+        let snapshot_updates = count_snapshot_entries(
+            records.iter().map(|record| {
+                (
+                    record.user_id,
+                    record.update_time,
+                    record.timeframe,
+                    record.ranking,
+                )
+            }),
+            std::iter::empty(),
+        );
+        let entries: Vec<NewArtistHistoryEntry> = records
+            .into_iter()
+            .map(|record| NewArtistHistoryEntry {
+                user_id: record.user_id,
+                mapped_spotify_id: record.mapped_spotify_id,
+                update_time: record.update_time,
+                timeframe: record.timeframe,
+                ranking: record.ranking,
+            })
+            .collect();
+
+        conn.transaction(|| {
+            let inserted_count =
+                insert_or_ignore!(artist_rank_deltas::table, entries).execute(conn)?;
+            merge_snapshot_updates(conn, &snapshot_updates)?;
+            Ok(inserted_count)
+        })
     })
     .await
 }
@@ -87,16 +118,45 @@ async fn insert_track_snapshots(
     records: Vec<TrackHistoryEntry>,
 ) -> QueryResult<usize> {
     conn.run(move |conn| {
-        use crate::schema::track_rank_snapshots;
-
-        diesel::insert_or_ignore_into(track_rank_snapshots::table)
-            .values(records)
-            .execute(conn)
+        use crate::schema::track_rank_deltas;
+
+        let snapshot_updates = count_snapshot_entries(
+            std::iter::empty(),
+            records.iter().map(|record| {
+                (
+                    record.user_id,
+                    record.update_time,
+                    record.timeframe,
+                    record.ranking,
+                )
+            }),
+        );
+        let entries: Vec<NewTrackHistoryEntry> = records
+            .into_iter()
+            .map(|record| NewTrackHistoryEntry {
+                user_id: record.user_id,
+                mapped_spotify_id: record.mapped_spotify_id,
+                update_time: record.update_time,
+                timeframe: record.timeframe,
+                ranking: record.ranking,
+            })
+            .collect();
+
+        conn.transaction(|| {
+            let inserted_count =
+                insert_or_ignore!(track_rank_deltas::table, entries).execute(conn)?;
+            merge_snapshot_updates(conn, &snapshot_updates)?;
+            Ok(inserted_count)
+        })
     })
     .await
 }
 
-fn record_batch_to_history_entries(record_batch: RecordBatch) -> Vec<ArtistHistoryEntry> {
+/// Converts a record batch read from a user's stored snapshots into history entries.  Fails if the
+/// batch contains a timeframe ID that doesn't exist.
+fn record_batch_to_history_entries(
+    record_batch: RecordBatch,
+) -> Result<Vec<ArtistHistoryEntry>, Box<dyn std::error::Error + Send + Sync + 'static>> {
     let id = record_batch
         .column(0)
         .as_any()
@@ -136,12 +196,13 @@ fn record_batch_to_history_entries(record_batch: RecordBatch) -> Vec<ArtistHisto
             update_time: NaiveDateTime::from_timestamp_opt(update_time.value(i), 0)
                 .unwrap_or_else(|| panic!("Invalid timestamp: {}", update_time.value(i))),
             mapped_spotify_id: mapped_spotify_id.value(i) as i32,
-            timeframe: timeframe.value(i),
+            timeframe: Timeframe::from_id(timeframe.value(i))
+                .ok_or_else(|| format!("Invalid timeframe ID: {}", timeframe.value(i)))?,
             ranking: ranking.value(i),
         });
     }
 
-    artist_history_entries
+    Ok(artist_history_entries)
 }
 
 async fn consume_and_insert_track_record_batches(
@@ -160,7 +221,7 @@ async fn consume_and_insert_track_record_batches(
             },
         };
 
-        let track_history_entries = record_batch_to_history_entries(record_batch);
+        let track_history_entries = record_batch_to_history_entries(record_batch)?;
         // ;)
         let track_history_entries: Vec<TrackHistoryEntry> =
             unsafe { std::mem::transmute(track_history_entries) };
@@ -207,7 +268,7 @@ async fn consume_and_insert_artist_record_batches(
             },
         };
 
-        let artist_history_entries = record_batch_to_history_entries(record_batch);
+        let artist_history_entries = record_batch_to_history_entries(record_batch)?;
         total_records_received += artist_history_entries.len();
         let mut last_err = None;
         for _ in 0..8 {
@@ -277,7 +338,7 @@ pub(crate) async fn load_external_user_data(
                 },
             };
 
-            let artist_history_chunk = record_batch_to_history_entries(record_batch);
+            let artist_history_chunk = record_batch_to_history_entries(record_batch)?;
             artist_entries.extend(artist_history_chunk);
         }
     }
@@ -294,7 +355,7 @@ pub(crate) async fn load_external_user_data(
                 },
             };
 
-            let track_history_chunk = record_batch_to_history_entries(record_batch);
+            let track_history_chunk = record_batch_to_history_entries(record_batch)?;
             // ;)
             let track_history_chunk: Vec<TrackHistoryEntry> =
                 unsafe { std::mem::transmute(track_history_chunk) };
@@ -72,6 +72,28 @@ fn build_filenames(user_spotify_id: &str) -> (String, String) {
     )
 }
 
+/// Deletes any of the user's history that has been moved to external storage.  Missing objects are
+/// not treated as an error.
+pub(crate) async fn delete_external_user_data(
+    user_spotify_id: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    use object_store::ObjectStore;
+
+    let object_store = build_object_store()?;
+    let (artists_filename, tracks_filename) = build_filenames(user_spotify_id);
+    for filename in [artists_filename, tracks_filename] {
+        let location: object_store::path::Path = filename.into();
+        match object_store.delete(&location).await {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => (),
+            Err(err) => {
+                error!("Error deleting external user data at {}: {}", location, err);
+                return Err(err.into());
+            },
+        }
+    }
+    Ok(())
+}
+
 async fn set_data_retrieved_flag_for_user(
     conn: &DbConn,
     user_spotify_id: String,
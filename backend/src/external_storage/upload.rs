@@ -17,6 +17,9 @@ use parquet::{
 use tokio::io::AsyncWrite;
 
 use crate::{
+    db_backend::DbConnection,
+    db_util::load_rank_history,
+    export::ExportEntity,
     external_storage::download::load_external_user_data,
     metrics::{
         external_user_data_export_failure_total, external_user_data_export_success_total,
@@ -63,7 +66,7 @@ fn build_record_batch(items: Vec<UserHistoryEntry>) -> RecordBatch {
         user_id_array_builder.append_value(item.user_id as u64);
         update_time_array_builder.append_value(item.update_time.and_utc().timestamp());
         mapped_spotify_id_array_builder.append_value(item.mapped_spotify_id as u32);
-        timeframe_array_builder.append_value(item.timeframe as u8);
+        timeframe_array_builder.append_value(item.timeframe.id());
         ranking_array_builder.append_value(item.ranking as u8);
     }
 
@@ -86,6 +89,25 @@ fn build_record_batch(items: Vec<UserHistoryEntry>) -> RecordBatch {
     RecordBatch::try_new(schema, columns).unwrap()
 }
 
+/// Loads all of the user's artist or track snapshot rows stored in the database
+fn load_local_rank_history(
+    conn: &DbConnection,
+    user_spotify_id: &str,
+    entity: ExportEntity,
+) -> QueryResult<Vec<UserHistoryEntry>> {
+    use crate::schema::users;
+
+    match users::table
+        .filter(users::dsl::spotify_id.eq(user_spotify_id))
+        .select(users::dsl::id)
+        .first::<i64>(conn)
+        .optional()?
+    {
+        Some(user_id) => load_rank_history(conn, user_id, entity, None),
+        None => Ok(Vec::new()),
+    }
+}
+
 async fn store_external_user_data_inner(
     conn: &DbConn,
     user_spotify_id: String,
@@ -101,16 +123,9 @@ async fn store_external_user_data_inner(
     let user_spotify_id_clone = user_spotify_id.clone();
     let mut artist_stats_for_user: Vec<UserHistoryEntry> = conn
         .run(move |conn| {
-            use crate::schema::{artist_rank_snapshots, users};
-
             let mut last_err = None;
             for _ in 0..8 {
-                match artist_rank_snapshots::table
-                    .inner_join(users::table)
-                    .filter(users::dsl::spotify_id.eq(user_spotify_id_clone.clone()))
-                    .select(artist_rank_snapshots::all_columns)
-                    .load::<UserHistoryEntry>(conn)
-                {
+                match load_local_rank_history(conn, &user_spotify_id_clone, ExportEntity::Artists) {
                     Ok(rows) => return Ok(rows),
                     Err(err) => {
                         error!("Error loading artist rank snapshots: {}", err);
@@ -192,16 +207,9 @@ async fn store_external_user_data_inner(
     let user_spotify_id_clone = user_spotify_id.clone();
     let mut track_stats_for_user: Vec<UserHistoryEntry> = conn
         .run(move |conn| {
-            use crate::schema::{track_rank_snapshots, users};
-
             let mut last_err = None;
             for _ in 0..8 {
-                match track_rank_snapshots::table
-                    .inner_join(users::table)
-                    .filter(users::dsl::spotify_id.eq(user_spotify_id_clone.clone()))
-                    .select(track_rank_snapshots::all_columns)
-                    .load::<UserHistoryEntry>(conn)
-                {
+                match load_local_rank_history(conn, &user_spotify_id_clone, ExportEntity::Tracks) {
                     Ok(rows) => return Ok(rows),
                     Err(err) => {
                         error!("Error loading track rank snapshots: {}", err);
@@ -377,7 +385,7 @@ async fn delete_local_user_data(conn: &DbConn, user_spotify_id: String) -> Query
     let user_spotify_id_clone = user_spotify_id.clone();
     info!("Deleting local data for user {user_spotify_id} after upload to cold storage...");
     let fut = conn.run(move |conn| -> QueryResult<()> {
-        use crate::schema::{artist_rank_snapshots, track_rank_snapshots, users};
+        use crate::schema::{artist_rank_deltas, snapshot_updates, track_rank_deltas, users};
 
         let user_internal_id = users::table
             .filter(users::dsl::spotify_id.eq(user_spotify_id.clone()))
@@ -388,9 +396,9 @@ async fn delete_local_user_data(conn: &DbConn, user_spotify_id: String) -> Query
         loop {
             // Delete in batches to try to avoid deadlocks and other issues with the table since
             // this is a huge, busy table with lots of reads and writes all the time
-            let ids_to_delete = artist_rank_snapshots::table
-                .filter(artist_rank_snapshots::dsl::user_id.eq(user_internal_id))
-                .select(artist_rank_snapshots::dsl::id)
+            let ids_to_delete = artist_rank_deltas::table
+                .filter(artist_rank_deltas::dsl::user_id.eq(user_internal_id))
+                .select(artist_rank_deltas::dsl::id)
                 .limit(500)
                 .load::<i64>(conn)?;
             if ids_to_delete.is_empty() {
@@ -398,8 +406,7 @@ async fn delete_local_user_data(conn: &DbConn, user_spotify_id: String) -> Query
             }
 
             match diesel::delete(
-                artist_rank_snapshots::table
-                    .filter(artist_rank_snapshots::dsl::id.eq_any(ids_to_delete)),
+                artist_rank_deltas::table.filter(artist_rank_deltas::dsl::id.eq_any(ids_to_delete)),
             )
             .execute(conn)
             {
@@ -421,9 +428,9 @@ async fn delete_local_user_data(conn: &DbConn, user_spotify_id: String) -> Query
         loop {
             // Delete in batches to try to avoid deadlocks and other issues with the table since
             // this is a huge, busy table with lots of reads and writes all the time
-            let ids_to_delete = track_rank_snapshots::table
-                .filter(track_rank_snapshots::dsl::user_id.eq(user_internal_id))
-                .select(track_rank_snapshots::dsl::id)
+            let ids_to_delete = track_rank_deltas::table
+                .filter(track_rank_deltas::dsl::user_id.eq(user_internal_id))
+                .select(track_rank_deltas::dsl::id)
                 .limit(500)
                 .load::<i64>(conn)?;
             if ids_to_delete.is_empty() {
@@ -431,8 +438,7 @@ async fn delete_local_user_data(conn: &DbConn, user_spotify_id: String) -> Query
             }
 
             match diesel::delete(
-                track_rank_snapshots::table
-                    .filter(track_rank_snapshots::dsl::id.eq_any(ids_to_delete)),
+                track_rank_deltas::table.filter(track_rank_deltas::dsl::id.eq_any(ids_to_delete)),
             )
             .execute(conn)
             {
@@ -450,6 +456,11 @@ async fn delete_local_user_data(conn: &DbConn, user_spotify_id: String) -> Query
             user_spotify_id
         );
 
+        diesel::delete(
+            snapshot_updates::table.filter(snapshot_updates::dsl::user_id.eq(user_internal_id)),
+        )
+        .execute(conn)?;
+
         Ok(())
     });
 
@@ -0,0 +1,179 @@
+//! Canonical genres.  Spotify's genre labels are free-form, so the same genre often shows up under
+//! several labels ("k-pop" and "korean pop") or spellings ("reggaeton" and "reguetón").  Genre
+//! statistics are aggregated by canonical slug so that they aren't split across these synonyms.
+//!
+//! Labels listed in `CANONICAL_GENRES` map to their genre's slug and display name.  Any other label
+//! is its own genre, with a slug made by lowercasing it and joining its words with `-`.
+
+use fnv::FnvHashMap as HashMap;
+
+/// `(slug, display name, Spotify labels)` of a canonical genre.  Labels are as they're stored in
+/// `artists_genres`.
+type CanonicalGenre = (&'static str, &'static str, &'static [&'static str]);
+
+const CANONICAL_GENRES: &[CanonicalGenre] = &[
+    ("k-pop", "K-Pop", &["k-pop", "korean pop", "kpop"]),
+    ("j-pop", "J-Pop", &["j-pop", "japanese pop", "jpop"]),
+    ("c-pop", "C-Pop", &["c-pop", "chinese pop", "cpop"]),
+    ("j-rock", "J-Rock", &["j-rock", "japanese rock"]),
+    ("hip-hop", "Hip Hop", &["hip hop", "hiphop"]),
+    ("r-and-b", "R&B", &["r&b", "rnb", "rhythm and blues"]),
+    ("lo-fi", "Lo-Fi", &["lo-fi", "lofi"]),
+    ("edm", "EDM", &["edm", "electronic dance music"]),
+    ("drum-and-bass", "Drum and Bass", &[
+        "drum and bass",
+        "drum & bass",
+        "drum n bass",
+        "dnb",
+    ]),
+    ("synth-pop", "Synth-Pop", &["synthpop", "synth-pop"]),
+    ("electropop", "Electropop", &["electropop", "electro-pop"]),
+    ("alternative-rock", "Alternative Rock", &[
+        "alternative rock",
+        "alt rock",
+    ]),
+    ("reggaeton", "Reggaeton", &[
+        "reggaeton",
+        "reguetón",
+        "regueton",
+    ]),
+    ("latin-pop", "Latin Pop", &["latin pop", "pop latino"]),
+    ("musica-mexicana", "Música Mexicana", &[
+        "musica mexicana",
+        "música mexicana",
+    ]),
+    ("mpb", "MPB", &["mpb", "musica popular brasileira"]),
+    ("french-hip-hop", "French Hip Hop", &[
+        "french hip hop",
+        "rap francais",
+        "rap français",
+    ]),
+    ("german-hip-hop", "German Hip Hop", &[
+        "german hip hop",
+        "deutschrap",
+        "deutscher hip hop",
+    ]),
+];
+
+lazy_static::lazy_static! {
+    /// Canonical genres keyed by the folded forms of their slugs and labels
+    static ref CANONICAL_GENRES_BY_KEY: HashMap<String, CanonicalGenre> = {
+        let mut genres_by_key = HashMap::default();
+        for &genre in CANONICAL_GENRES {
+            let (slug, _display_name, labels) = genre;
+            genres_by_key.insert(fold_label(slug), genre);
+            for label in labels {
+                genres_by_key.insert(fold_label(label), genre);
+            }
+        }
+        genres_by_key
+    };
+}
+
+/// Lowercases the label and separates its words with single spaces, treating `-` and `_` as word
+/// separators, so that differently formatted labels of a genre can be matched.
+fn fold_label(label: &str) -> String {
+    label
+        .to_lowercase()
+        .split(|c: char| c.is_whitespace() || c == '-' || c == '_')
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct NormalizedGenre {
+    pub slug: String,
+    pub display_name: String,
+}
+
+/// Maps a Spotify genre label, or the slug of a genre, to its canonical genre
+pub(crate) fn normalize_genre(label: &str) -> NormalizedGenre {
+    let key = fold_label(label);
+    match CANONICAL_GENRES_BY_KEY.get(&key) {
+        Some((slug, display_name, _labels)) => NormalizedGenre {
+            slug: (*slug).to_owned(),
+            display_name: (*display_name).to_owned(),
+        },
+        None => NormalizedGenre {
+            slug: key.replace(' ', "-"),
+            display_name: key,
+        },
+    }
+}
+
+/// Normalizes the genres of an artist, merging the ones that are synonyms of each other so that the
+/// artist counts toward each canonical genre once
+pub(crate) fn normalize_genres<'a>(
+    labels: impl IntoIterator<Item = &'a str>,
+) -> Vec<NormalizedGenre> {
+    let mut genres: Vec<NormalizedGenre> = Vec::new();
+    for label in labels {
+        let genre = normalize_genre(label);
+        if !genres.iter().any(|existing| existing.slug == genre.slug) {
+            genres.push(genre);
+        }
+    }
+    genres
+}
+
+/// Returns the Spotify labels that map to the same canonical genre as the provided label or slug,
+/// for looking up the artists of the genre
+pub(crate) fn genre_labels(genre: &str) -> Vec<String> {
+    let key = fold_label(genre);
+    match CANONICAL_GENRES_BY_KEY.get(&key) {
+        Some((_slug, _display_name, labels)) =>
+            labels.iter().map(|&label| label.to_owned()).collect(),
+        None => {
+            let mut labels = vec![genre.to_owned()];
+            if key != genre {
+                labels.push(key);
+            }
+            labels
+        },
+    }
+}
+
+#[test]
+fn genre_normalization() {
+    let k_pop = NormalizedGenre {
+        slug: "k-pop".to_owned(),
+        display_name: "K-Pop".to_owned(),
+    };
+    assert_eq!(normalize_genre("k-pop"), k_pop);
+    assert_eq!(normalize_genre("korean pop"), k_pop);
+    assert_eq!(normalize_genre("K Pop"), k_pop);
+    assert_eq!(normalize_genre("Reguetón").slug, "reggaeton");
+    assert_eq!(normalize_genre("rap français").slug, "french-hip-hop");
+    assert_eq!(normalize_genre("hip-hop").display_name, "Hip Hop");
+
+    assert_eq!(normalize_genre("indie rock"), NormalizedGenre {
+        slug: "indie-rock".to_owned(),
+        display_name: "indie rock".to_owned(),
+    });
+    assert_eq!(normalize_genre("indie-rock"), normalize_genre("indie rock"));
+    assert_eq!(
+        normalize_genre(&normalize_genre("escape room").slug).slug,
+        "escape-room"
+    );
+
+    let genres = normalize_genres(["kpop", "k-pop", "indie rock", "indie-rock"]);
+    assert_eq!(
+        genres
+            .iter()
+            .map(|genre| genre.slug.as_str())
+            .collect::<Vec<_>>(),
+        vec!["k-pop", "indie-rock"]
+    );
+
+    assert_eq!(genre_labels("korean-pop"), vec![
+        "k-pop",
+        "korean pop",
+        "kpop"
+    ]);
+    assert_eq!(genre_labels("indie-rock"), vec!["indie-rock", "indie rock"]);
+    assert_eq!(genre_labels("jazz"), vec!["jazz"]);
+    for (slug, _display_name, _labels) in CANONICAL_GENRES {
+        assert_eq!(normalize_genre(slug).slug, *slug);
+    }
+}
@@ -0,0 +1,361 @@
+//! GraphQL API served at `/graphql`.  This exposes the same data as the REST API but lets clients
+//! request only the fields they need, which avoids resolving metadata for the full
+//! `StatsSnapshot` when only a handful of entities are displayed.
+
+use async_graphql::{
+    ComplexObject, Context, EmptyMutation, EmptySubscription, Object, Schema, SimpleObject,
+};
+use chrono::NaiveDateTime;
+use fnv::FnvHashSet as HashSet;
+
+use crate::{
+    db_util,
+    error::Error,
+    export::ExportEntity,
+    models::{Artist, Timeframe, Track, User},
+    pagination::CursorPagination,
+    routes::{comparison_allowed, CurrentUser, PrivateAccessToken},
+    DbConn,
+};
+
+/// Queries nested deeper than this are rejected
+const MAX_QUERY_DEPTH: usize = 8;
+/// Queries with a higher total complexity than this are rejected, so that a single query can't
+/// use aliases to request the top entities of many users at once
+const MAX_QUERY_COMPLEXITY: usize = 100;
+/// Complexity of fields that resolve a list of artists or tracks, which may have to be fetched
+/// from the Spotify API.  Other fields count as 1.
+const ENTITY_LIST_COMPLEXITY: usize = 10;
+
+pub(crate) type StatsSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+pub(crate) fn build_schema() -> StatsSchema {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .limit_depth(MAX_QUERY_DEPTH)
+        .limit_complexity(MAX_QUERY_COMPLEXITY)
+        .finish()
+}
+
+/// Per-request state made available to resolvers
+pub(crate) struct RequestContext {
+    pub conn: DbConn,
+    pub spotify_access_token: String,
+    pub access_token: PrivateAccessToken,
+    pub current_user: Option<CurrentUser>,
+}
+
+/// Converts errors the same way as the `Error` responder so that database details aren't exposed
+fn to_gql_err(err: Error) -> async_graphql::Error {
+    match err {
+        Error::Database(err) => {
+            error!("Database error while resolving GraphQL query: {:?}", err);
+            async_graphql::Error::new("Error querying database")
+        },
+        err => async_graphql::Error::new(err.to_string()),
+    }
+}
+
+/// Looks up a user, treating private users as not existing unless the request grants access
+async fn get_user(ctx: &Context<'_>, username: String) -> async_graphql::Result<Option<User>> {
+    let req_ctx = ctx.data::<RequestContext>()?;
+    let user = db_util::get_user_by_spotify_id(&req_ctx.conn, username)
+        .await
+        .map_err(to_gql_err)?;
+    Ok(user.filter(|user| req_ctx.access_token.grants_access_to(user)))
+}
+
+/// Spotify IDs of the user's top artists or tracks from their most recent update for one timeframe,
+/// ordered by ranking
+async fn get_latest_top_ids(
+    ctx: &Context<'_>,
+    user: &User,
+    entity: ExportEntity,
+    timeframe: Timeframe,
+) -> async_graphql::Result<Vec<String>> {
+    let conn = &ctx.data::<RequestContext>()?.conn;
+    let ids = match entity {
+        ExportEntity::Artists => db_util::get_latest_top_artist_ids(conn, user.id).await,
+        ExportEntity::Tracks => db_util::get_latest_top_track_ids(conn, user.id).await,
+    }
+    .map_err(|err| to_gql_err(err.into()))?;
+    Ok(ids
+        .into_iter()
+        .filter(|(entry_timeframe, _)| *entry_timeframe == timeframe)
+        .map(|(_, spotify_id)| spotify_id)
+        .collect())
+}
+
+async fn fetch_artists(ctx: &Context<'_>, ids: &[String]) -> async_graphql::Result<Vec<Artist>> {
+    let req_ctx = ctx.data::<RequestContext>()?;
+    let ids = ids.iter().map(String::as_str).collect::<Vec<_>>();
+    let artists_by_id = crate::spotify_api::fetch_artists(&req_ctx.spotify_access_token, &ids)
+        .await
+        .map_err(to_gql_err)?;
+    Ok(ids.iter().map(|id| artists_by_id[*id].clone()).collect())
+}
+
+async fn fetch_tracks(ctx: &Context<'_>, ids: &[String]) -> async_graphql::Result<Vec<Track>> {
+    let req_ctx = ctx.data::<RequestContext>()?;
+    let ids = ids.iter().map(String::as_str).collect::<Vec<_>>();
+    let tracks_by_id = crate::spotify_api::fetch_tracks(&req_ctx.spotify_access_token, &ids)
+        .await
+        .map_err(to_gql_err)?;
+    Ok(ids.iter().map(|id| tracks_by_id[*id].clone()).collect())
+}
+
+fn take_limit(mut ids: Vec<String>, limit: Option<usize>) -> Vec<String> {
+    if let Some(limit) = limit {
+        ids.truncate(limit);
+    }
+    ids
+}
+
+pub(crate) struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Looks up a user by Spotify ID.  Private users are only returned if their private token is
+    /// supplied via the `X-Private-Token` header.
+    async fn user(
+        &self,
+        ctx: &Context<'_>,
+        username: String,
+    ) -> async_graphql::Result<Option<UserNode>> {
+        Ok(get_user(ctx, username).await?.map(UserNode))
+    }
+
+    /// Compares the most recent top artists and tracks of two users.  Users that have made their
+    /// comparisons friends-only are treated as not existing unless the request is authenticated as
+    /// the user themselves or as the other user being compared, if they're friends.
+    async fn compare(
+        &self,
+        ctx: &Context<'_>,
+        user1: String,
+        user2: String,
+    ) -> async_graphql::Result<Option<Comparison>> {
+        let user1 = match get_user(ctx, user1).await? {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+        let user2 = match get_user(ctx, user2).await? {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+        let req_ctx = ctx.data::<RequestContext>()?;
+        let (user1_settings, user2_settings) = futures::try_join!(
+            db_util::get_user_settings(&req_ctx.conn, &user1),
+            db_util::get_user_settings(&req_ctx.conn, &user2),
+        )
+        .map_err(|err| to_gql_err(err.into()))?;
+        if !comparison_allowed(
+            &req_ctx.conn,
+            [(&user1, &user1_settings), (&user2, &user2_settings)],
+            req_ctx.current_user.as_ref(),
+        )
+        .await
+        .map_err(to_gql_err)?
+        {
+            return Ok(None);
+        }
+        Ok(Some(Comparison {
+            user1: UserNode(user1),
+            user2: UserNode(user2),
+        }))
+    }
+}
+
+pub(crate) struct UserNode(User);
+
+#[Object(name = "User")]
+impl UserNode {
+    async fn spotify_id(&self) -> &str { &self.0.spotify_id }
+
+    /// Display name of the user
+    async fn username(&self) -> &str { &self.0.username }
+
+    async fn last_update_time(&self) -> NaiveDateTime { self.0.last_update_time }
+
+    /// Top artists from the user's most recent update, ordered by ranking
+    #[graphql(complexity = "ENTITY_LIST_COMPLEXITY + child_complexity")]
+    async fn top_artists(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default_with = "Timeframe::Short")] timeframe: Timeframe,
+        limit: Option<usize>,
+    ) -> async_graphql::Result<Vec<Artist>> {
+        let ids = get_latest_top_ids(ctx, &self.0, ExportEntity::Artists, timeframe).await?;
+        fetch_artists(ctx, &take_limit(ids, limit)).await
+    }
+
+    /// Top tracks from the user's most recent update, ordered by ranking
+    #[graphql(complexity = "ENTITY_LIST_COMPLEXITY + child_complexity")]
+    async fn top_tracks(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default_with = "Timeframe::Short")] timeframe: Timeframe,
+        limit: Option<usize>,
+    ) -> async_graphql::Result<Vec<Track>> {
+        let ids = get_latest_top_ids(ctx, &self.0, ExportEntity::Tracks, timeframe).await?;
+        fetch_tracks(ctx, &take_limit(ids, limit)).await
+    }
+
+    /// Update times of the user's snapshots, most recent first.  `cursor` is the `nextCursor` of
+    /// the previous page.
+    async fn snapshots(
+        &self,
+        ctx: &Context<'_>,
+        cursor: Option<String>,
+        limit: Option<u32>,
+    ) -> async_graphql::Result<SnapshotPage> {
+        let conn = &ctx.data::<RequestContext>()?.conn;
+        let pagination = CursorPagination::new(cursor.as_deref(), limit).map_err(to_gql_err)?;
+        let page = db_util::get_snapshot_update_times_before(conn, &self.0, pagination)
+            .await
+            .map_err(|err| to_gql_err(err.into()))?;
+        Ok(SnapshotPage {
+            update_times: page.items,
+            next_cursor: page.next_cursor,
+        })
+    }
+
+    /// Every ranking the artist has had in the user's top artists, oldest first
+    async fn artist_history(
+        &self,
+        ctx: &Context<'_>,
+        artist_id: String,
+    ) -> async_graphql::Result<Vec<RankHistoryEntry>> {
+        let conn = &ctx.data::<RequestContext>()?.conn;
+        let history =
+            db_util::get_entity_rank_history(conn, &self.0, ExportEntity::Artists, artist_id)
+                .await
+                .map_err(to_gql_err)?;
+        Ok(history.into_iter().map(RankHistoryEntry::from).collect())
+    }
+
+    /// Every ranking the track has had in the user's top tracks, oldest first
+    async fn track_history(
+        &self,
+        ctx: &Context<'_>,
+        track_id: String,
+    ) -> async_graphql::Result<Vec<RankHistoryEntry>> {
+        let conn = &ctx.data::<RequestContext>()?.conn;
+        let history =
+            db_util::get_entity_rank_history(conn, &self.0, ExportEntity::Tracks, track_id)
+                .await
+                .map_err(to_gql_err)?;
+        Ok(history.into_iter().map(RankHistoryEntry::from).collect())
+    }
+}
+
+#[derive(SimpleObject)]
+pub(crate) struct SnapshotPage {
+    update_times: Vec<NaiveDateTime>,
+    /// Supplied as `cursor` to fetch the next page; null if this is the last page
+    next_cursor: Option<String>,
+}
+
+#[derive(SimpleObject)]
+pub(crate) struct RankHistoryEntry {
+    update_time: NaiveDateTime,
+    timeframe: Timeframe,
+    /// 1-indexed
+    ranking: u8,
+}
+
+impl From<(NaiveDateTime, Timeframe, u8)> for RankHistoryEntry {
+    fn from((update_time, timeframe, ranking): (NaiveDateTime, Timeframe, u8)) -> Self {
+        RankHistoryEntry {
+            update_time,
+            timeframe,
+            ranking: ranking + 1,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub(crate) struct Comparison {
+    user1: UserNode,
+    user2: UserNode,
+}
+
+#[ComplexObject]
+impl Comparison {
+    /// Artists in both users' most recent top artists for the timeframe, in `user1`'s order
+    #[graphql(complexity = "ENTITY_LIST_COMPLEXITY + child_complexity")]
+    async fn shared_artists(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default_with = "Timeframe::Short")] timeframe: Timeframe,
+    ) -> async_graphql::Result<Vec<Artist>> {
+        let ids = self
+            .shared_ids(ctx, ExportEntity::Artists, timeframe)
+            .await?;
+        fetch_artists(ctx, &ids).await
+    }
+
+    /// Tracks in both users' most recent top tracks for the timeframe, in `user1`'s order
+    #[graphql(complexity = "ENTITY_LIST_COMPLEXITY + child_complexity")]
+    async fn shared_tracks(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default_with = "Timeframe::Short")] timeframe: Timeframe,
+    ) -> async_graphql::Result<Vec<Track>> {
+        let ids = self
+            .shared_ids(ctx, ExportEntity::Tracks, timeframe)
+            .await?;
+        fetch_tracks(ctx, &ids).await
+    }
+}
+
+impl Comparison {
+    async fn shared_ids(
+        &self,
+        ctx: &Context<'_>,
+        entity: ExportEntity,
+        timeframe: Timeframe,
+    ) -> async_graphql::Result<Vec<String>> {
+        let user1_ids = get_latest_top_ids(ctx, &self.user1.0, entity, timeframe).await?;
+        let user2_ids: HashSet<String> = get_latest_top_ids(ctx, &self.user2.0, entity, timeframe)
+            .await?
+            .into_iter()
+            .collect();
+        Ok(user1_ids
+            .into_iter()
+            .filter(|id| user2_ids.contains(id))
+            .collect())
+    }
+}
+
+#[test]
+fn graphql_schema_builds() {
+    let sdl = build_schema().sdl();
+    assert!(sdl.contains("topArtists(timeframe: Timeframe! = SHORT, limit: Int): [Artist!]!"));
+    assert!(sdl.contains("sharedTracks"));
+}
+
+#[test]
+fn graphql_complexity_limit() {
+    let top_artists = |alias: usize| {
+        format!(
+            "u{}: user(username: \"{}\") {{ topArtists {{ name }} }}",
+            alias, alias
+        )
+    };
+    let query = |user_count: usize| {
+        format!(
+            "{{ {} }}",
+            (0..user_count)
+                .map(top_artists)
+                .collect::<Vec<_>>()
+                .join(" ")
+        )
+    };
+    let schema = build_schema();
+    // Complexity is checked before anything is resolved, so queries within the limit fail later
+    // on for lack of a `RequestContext` instead
+    let res = futures::executor::block_on(schema.execute(query(2)));
+    assert!(!res.errors[0].message.contains("too complex"));
+    let res = futures::executor::block_on(schema.execute(query(20)));
+    assert!(res.errors[0].message.contains("too complex"));
+}
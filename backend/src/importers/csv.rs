@@ -0,0 +1,93 @@
+//! Imports listening history from a generic CSV file with a `timestamp,artist,track` row for each
+//! play, such as one converted from an Apple Music play activity export.  A header row is optional.
+//!
+//! Timestamps can be given in RFC 3339 format, as `YYYY-MM-DD HH:MM:SS` or `YYYY-MM-DDTHH:MM:SS`
+//! in UTC, or as seconds or milliseconds since the Unix epoch.
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+use super::{parse_csv_record, ImportedPlay};
+use crate::error::Error;
+
+/// Integer timestamps larger than this are treated as milliseconds rather than seconds
+const MAX_TIMESTAMP_SECS: i64 = 100_000_000_000;
+
+fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    if let Ok(timestamp) = timestamp.parse::<i64>() {
+        return if timestamp > MAX_TIMESTAMP_SECS {
+            DateTime::from_timestamp_millis(timestamp)
+        } else {
+            DateTime::from_timestamp(timestamp, 0)
+        };
+    }
+
+    DateTime::parse_from_rfc3339(timestamp)
+        .map(|time| time.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"]
+                .iter()
+                .find_map(|format| NaiveDateTime::parse_from_str(timestamp, format).ok())
+                .map(|time| time.and_utc())
+        })
+}
+
+/// Parses the CSV into plays.  Rows with an empty artist or track name are skipped.
+pub(crate) fn parse_history(csv: &str) -> Result<Vec<ImportedPlay>, Error> {
+    let mut plays = Vec::new();
+    for (i, line) in csv.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields = parse_csv_record(line);
+        let (timestamp, artist_name, track_name) = match fields.as_slice() {
+            [timestamp, artist_name, track_name] => (timestamp.trim(), artist_name, track_name),
+            _ =>
+                return Err(Error::BadRequest(format!(
+                    "Invalid row on line {}; expected `timestamp,artist,track`",
+                    i + 1
+                ))),
+        };
+        let played_at = match parse_timestamp(timestamp) {
+            Some(played_at) => played_at,
+            // Header row
+            None if i == 0 => continue,
+            None =>
+                return Err(Error::BadRequest(format!(
+                    "Invalid timestamp \"{}\" on line {}",
+                    timestamp,
+                    i + 1
+                ))),
+        };
+        if artist_name.trim().is_empty() || track_name.trim().is_empty() {
+            continue;
+        }
+
+        plays.push(ImportedPlay {
+            played_at,
+            artist_name: artist_name.trim().to_owned(),
+            track_name: track_name.trim().to_owned(),
+        });
+    }
+    Ok(plays)
+}
+
+#[test]
+fn history_csv_parsing() {
+    let plays = parse_history(
+        "timestamp,artist,track\n2021-03-05T18:22:00Z,Boards of \
+         Canada,Roygbiv\n1614968520,\"Crosby, Stills & Nash\",Helplessly \
+         Hoping\n1614968520000,Aphex Twin,Xtal\n2021-03-05 18:22:00,,\n",
+    )
+    .unwrap();
+    assert_eq!(plays.len(), 3);
+    assert!(plays
+        .iter()
+        .all(|play| play.played_at.timestamp() == 1614968520));
+    assert_eq!(plays[1].artist_name, "Crosby, Stills & Nash");
+    assert_eq!(plays[2].track_name, "Xtal");
+
+    assert!(parse_history("2021-03-05,Aphex Twin,Xtal,extra").is_err());
+    assert!(parse_history("2021-03-05T18:22:00Z,a,b\nyesterday,a,b").is_err());
+}
@@ -0,0 +1,298 @@
+//! Imports scrobbles from Last.fm, either from a CSV export or by fetching them from the Last.fm
+//! API.  Exports are expected in the `artist,album,track,date` format produced by the commonly used
+//! lastfm-to-csv exporter.
+
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+use super::{parse_csv_record, ImportedPlay};
+use crate::{error::Error, spotify_api::get_reqwest_client};
+
+const LASTFM_API_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+/// Max number of scrobbles Last.fm returns per page
+const PAGE_SIZE: usize = 200;
+/// Upper bound on the number of pages fetched, which limits imports from the API to the user's
+/// 50,000 most recent scrobbles
+const MAX_PAGES: usize = 250;
+const EXPORT_DATE_FORMAT: &str = "%d %b %Y %H:%M";
+/// Max number of times each page is requested when Last.fm rate limits us or fails to respond
+const MAX_ATTEMPTS: usize = 5;
+const RETRY_BASE_BACKOFF_SECS: u64 = 5;
+const RETRY_MAX_BACKOFF_SECS: u64 = 120;
+/// Error code Last.fm returns when the API key has made too many requests
+const RATE_LIMIT_EXCEEDED_ERROR_CODE: u32 = 29;
+
+#[derive(Deserialize)]
+struct Text {
+    #[serde(rename = "#text")]
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct ScrobbleDate {
+    /// Seconds since the Unix epoch
+    uts: String,
+}
+
+#[derive(Deserialize)]
+struct Scrobble {
+    artist: Text,
+    name: String,
+    /// Unset for the track that's currently playing
+    date: Option<ScrobbleDate>,
+}
+
+#[derive(Deserialize)]
+struct PageInfo {
+    #[serde(rename = "totalPages")]
+    total_pages: String,
+}
+
+#[derive(Deserialize)]
+struct RecentTracks {
+    track: Vec<Scrobble>,
+    #[serde(rename = "@attr")]
+    attr: PageInfo,
+}
+
+#[derive(Deserialize)]
+struct RecentTracksResponse {
+    recenttracks: RecentTracks,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: u32,
+    message: String,
+}
+
+/// Parses a CSV export of the user's scrobbles.  Scrobbles without a date are skipped.
+pub(crate) fn parse_export(csv: &str) -> Result<Vec<ImportedPlay>, Error> {
+    let mut plays = Vec::new();
+    for (i, line) in csv.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields = parse_csv_record(line);
+        let (artist_name, track_name, date) = match fields.as_slice() {
+            [artist_name, _album, track_name, date] => (artist_name, track_name, date),
+            _ =>
+                return Err(Error::BadRequest(format!(
+                    "Invalid row on line {}; expected `artist,album,track,date`",
+                    i + 1
+                ))),
+        };
+        if date.is_empty() {
+            continue;
+        }
+        let played_at = NaiveDateTime::parse_from_str(date, EXPORT_DATE_FORMAT).map_err(|_| {
+            Error::BadRequest(format!(
+                "Invalid date \"{}\" on line {}; expected a date like \"05 Mar 2021 18:22\"",
+                date,
+                i + 1
+            ))
+        })?;
+
+        plays.push(ImportedPlay {
+            played_at: played_at.and_utc(),
+            artist_name: artist_name.clone(),
+            track_name: track_name.clone(),
+        });
+    }
+    Ok(plays)
+}
+
+fn parse_scrobbles(scrobbles: Vec<Scrobble>) -> Vec<ImportedPlay> {
+    scrobbles
+        .into_iter()
+        .filter_map(|scrobble| {
+            let uts = scrobble.date?.uts.parse::<i64>().ok()?;
+            Some(ImportedPlay {
+                played_at: DateTime::<Utc>::from_timestamp(uts, 0)?,
+                artist_name: scrobble.artist.text,
+                track_name: scrobble.name,
+            })
+        })
+        .collect()
+}
+
+/// Returns the number of seconds to wait before the next attempt at a request that was rate
+/// limited or failed, preferring the delay from the `Retry-After` header if there is one
+fn retry_delay_secs(attempt: usize, retry_after: Option<u64>) -> u64 {
+    retry_after
+        .unwrap_or_else(|| RETRY_BASE_BACKOFF_SECS << (attempt - 1).min(6))
+        .min(RETRY_MAX_BACKOFF_SECS)
+}
+
+/// Fetches a page of the Last.fm user's scrobbles, retrying with backoff if Last.fm rate limits us
+/// or responds with a server error
+async fn fetch_scrobbles_page(
+    client: &reqwest::Client,
+    lastfm_username: &str,
+    api_key: &str,
+    page: usize,
+) -> Result<RecentTracksResponse, Error> {
+    let page_size = PAGE_SIZE.to_string();
+    let page_param = page.to_string();
+    let mut attempt = 1;
+    loop {
+        let res = client
+            .get(LASTFM_API_URL)
+            .query(&[
+                ("method", "user.getrecenttracks"),
+                ("format", "json"),
+                ("user", lastfm_username),
+                ("api_key", api_key),
+                ("limit", &page_size),
+                ("page", &page_param),
+            ])
+            .send()
+            .await
+            .map_err(|err| {
+                error!("Error communicating with the Last.fm API: {:?}", err);
+                Error::Internal("Error communicating with the Last.fm API".into())
+            })?;
+        let status = res.status();
+        let retry_after = res
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|val| val.to_str().ok())
+            .and_then(|val| val.trim().parse::<u64>().ok());
+        let body = res.text().await.map_err(|err| {
+            error!("Error reading Last.fm API response: {:?}", err);
+            Error::Internal("Error reading Last.fm API response".into())
+        })?;
+
+        let error_res = if status.is_success() {
+            None
+        } else {
+            serde_json::from_str::<ErrorResponse>(&body).ok()
+        };
+        let rate_limited = status == StatusCode::TOO_MANY_REQUESTS
+            || error_res
+                .as_ref()
+                .map_or(false, |res| res.error == RATE_LIMIT_EXCEEDED_ERROR_CODE);
+        if (rate_limited || status.is_server_error()) && attempt < MAX_ATTEMPTS {
+            let delay_secs = retry_delay_secs(attempt, retry_after);
+            warn!(
+                "Got status code {} from Last.fm API, waiting {} seconds before retrying (attempt \
+                 {}/{}, Retry-After={:?})...",
+                status, delay_secs, attempt, MAX_ATTEMPTS, retry_after
+            );
+            tokio::time::sleep(Duration::from_secs(delay_secs)).await;
+            attempt += 1;
+            continue;
+        }
+
+        if rate_limited {
+            error!(
+                "Still rate limited by Last.fm API after {} attempts",
+                attempt
+            );
+            return Err(Error::Internal(
+                "Rate limited by the Last.fm API; try again later".into(),
+            ));
+        } else if status.is_client_error() {
+            let message = error_res
+                .map(|res| res.message)
+                .unwrap_or_else(|| status.to_string());
+            return Err(Error::BadRequest(format!(
+                "Error fetching scrobbles from Last.fm: {}",
+                message
+            )));
+        } else if !status.is_success() {
+            error!(
+                "Got bad status code of {} from Last.fm API: {}",
+                status, body
+            );
+            return Err(Error::Internal(
+                "Error fetching scrobbles from Last.fm".into(),
+            ));
+        }
+
+        return serde_json::from_str(&body).map_err(|err| {
+            error!(
+                "Error parsing Last.fm API response: {:?}; body={}",
+                err, body
+            );
+            Error::Internal("Error parsing Last.fm API response".into())
+        });
+    }
+}
+
+/// Fetches the Last.fm user's scrobbles from the Last.fm API, most recent first.  `on_page` is
+/// called with the number of scrobbles fetched so far after each page.
+pub(crate) async fn fetch_scrobbles(
+    lastfm_username: &str,
+    api_key: &str,
+    mut on_page: impl FnMut(usize),
+) -> Result<Vec<ImportedPlay>, Error> {
+    let client = get_reqwest_client().await;
+    let mut plays = Vec::new();
+    for page in 1..=MAX_PAGES {
+        let res = fetch_scrobbles_page(&client, lastfm_username, api_key, page).await?;
+        let total_pages = res
+            .recenttracks
+            .attr
+            .total_pages
+            .parse::<usize>()
+            .unwrap_or(0);
+        plays.extend(parse_scrobbles(res.recenttracks.track));
+        on_page(plays.len());
+
+        if page >= total_pages {
+            break;
+        }
+    }
+    Ok(plays)
+}
+
+#[test]
+fn lastfm_scrobble_parsing() {
+    let plays = parse_export(
+        "Boards of Canada,Music Has the Right to Children,Roygbiv,05 Mar 2021 18:22\n\"Crosby, \
+         Stills & Nash\",CSN,Helplessly Hoping,,\n",
+    );
+    assert!(plays.is_err());
+
+    let plays = parse_export(
+        "Boards of Canada,Music Has the Right to Children,Roygbiv,05 Mar 2021 18:22\n\n\"Crosby, \
+         Stills & Nash\",CSN,Helplessly Hoping,\n",
+    )
+    .unwrap();
+    assert_eq!(plays.len(), 1);
+    assert_eq!(plays[0].artist_name, "Boards of Canada");
+    assert_eq!(plays[0].track_name, "Roygbiv");
+    assert_eq!(plays[0].played_at.timestamp(), 1614968520);
+    assert!(parse_export("a,b,c,yesterday").is_err());
+
+    let res: RecentTracksResponse = serde_json::from_str(
+        r##"{"recenttracks":{"track":[
+            {"artist":{"mbid":"","#text":"Aphex Twin"},"name":"Xtal","@attr":{"nowplaying":"true"}},
+            {"artist":{"mbid":"","#text":"Aphex Twin"},"name":"Heliosphan","date":{"uts":"1614968520","#text":"05 Mar 2021, 18:22"}}
+        ],"@attr":{"user":"foo","totalPages":"3","page":"1","perPage":"200","total":"401"}}}"##,
+    )
+    .unwrap();
+    assert_eq!(res.recenttracks.attr.total_pages, "3");
+    let plays = parse_scrobbles(res.recenttracks.track);
+    assert_eq!(plays.len(), 1);
+    assert_eq!(plays[0].track_name, "Heliosphan");
+    assert_eq!(plays[0].played_at.timestamp(), 1614968520);
+}
+
+#[test]
+fn lastfm_retry_delays() {
+    assert_eq!(retry_delay_secs(1, None), RETRY_BASE_BACKOFF_SECS);
+    assert_eq!(retry_delay_secs(3, None), RETRY_BASE_BACKOFF_SECS * 4);
+    assert_eq!(retry_delay_secs(20, None), RETRY_MAX_BACKOFF_SECS);
+    assert_eq!(retry_delay_secs(1, Some(30)), 30);
+    assert_eq!(retry_delay_secs(1, Some(3600)), RETRY_MAX_BACKOFF_SECS);
+
+    let res: ErrorResponse =
+        serde_json::from_str(r#"{"error":29,"message":"Rate Limit Exceeded"}"#).unwrap();
+    assert_eq!(res.error, RATE_LIMIT_EXCEEDED_ERROR_CODE);
+}
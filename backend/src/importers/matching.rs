@@ -0,0 +1,132 @@
+//! Fuzzy matching of imported track and artist names against Spotify search results.  Other
+//! services often name tracks slightly differently than Spotify does, such as by including or
+//! omitting "Remastered" suffixes or featured artists, so names are normalized and compared by edit
+//! distance rather than exactly.
+
+use crate::models::Track;
+
+/// Min similarity between normalized names for a search result to be considered a match
+const MIN_NAME_SIMILARITY: f32 = 0.75;
+
+/// Lowercases the name and strips everything that commonly differs between services: parenthesized
+/// or bracketed qualifiers, suffixes following " - ", a leading "the", and punctuation.
+fn normalize_name(name: &str) -> String {
+    let name = name.to_lowercase();
+    let name = name.split(" - ").next().unwrap_or(&name);
+
+    let mut normalized = String::with_capacity(name.len());
+    let mut depth = 0usize;
+    for c in name.chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth = depth.saturating_sub(1),
+            '&' if depth == 0 => normalized.push_str(" and "),
+            c if depth == 0 && c.is_alphanumeric() => normalized.push(c),
+            _ if depth == 0 => normalized.push(' '),
+            _ => (),
+        }
+    }
+
+    let words: Vec<&str> = normalized.split_whitespace().collect();
+    match words.as_slice() {
+        ["the", rest @ ..] if !rest.is_empty() => rest.join(" "),
+        words => words.join(" "),
+    }
+}
+
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut prev_row: Vec<usize> = (0..=b.len()).collect();
+    let mut row = vec![0; b.len() + 1];
+    for (i, a_char) in a.iter().enumerate() {
+        row[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution_cost = if a_char == b_char { 0 } else { 1 };
+            row[j + 1] = (prev_row[j] + substitution_cost)
+                .min(prev_row[j + 1] + 1)
+                .min(row[j] + 1);
+        }
+        std::mem::swap(&mut row, &mut prev_row);
+    }
+    prev_row[b.len()]
+}
+
+/// Similarity of the two names from 0 to 1 after normalizing them, where 1 means they're identical
+fn name_similarity(a: &str, b: &str) -> f32 {
+    let a: Vec<char> = normalize_name(a).chars().collect();
+    let b: Vec<char> = normalize_name(b).chars().collect();
+    let max_len = a.len().max(b.len());
+    if max_len == 0 {
+        return 1.;
+    }
+
+    1. - edit_distance(&a, &b) as f32 / max_len as f32
+}
+
+/// Picks the search result that best matches the track and artist names.  Both the track name and
+/// the name of one of the track's artists must be similar enough for a result to match.
+pub(crate) fn best_match<'a>(
+    candidates: &'a [Track],
+    artist_name: &str,
+    track_name: &str,
+) -> Option<&'a Track> {
+    candidates
+        .iter()
+        .filter_map(|track| {
+            let track_similarity = name_similarity(&track.name, track_name);
+            let artist_similarity = track
+                .artists
+                .iter()
+                .map(|artist| name_similarity(&artist.name, artist_name))
+                .fold(0., f32::max);
+            if track_similarity < MIN_NAME_SIMILARITY || artist_similarity < MIN_NAME_SIMILARITY {
+                return None;
+            }
+
+            Some((track, track_similarity + artist_similarity))
+        })
+        // Ties go to the first result since Spotify orders them by relevance
+        .fold(None, |best: Option<(&Track, f32)>, (track, score)| match best {
+            Some((_, best_score)) if best_score >= score => best,
+            _ => Some((track, score)),
+        })
+        .map(|(track, _)| track)
+}
+
+#[test]
+fn fuzzy_track_matching() {
+    use crate::models::Artist;
+
+    assert_eq!(
+        normalize_name("Here Comes the Sun - Remastered 2009"),
+        "here comes the sun"
+    );
+    assert_eq!(normalize_name("The Beatles"), "beatles");
+    assert_eq!(
+        normalize_name("Simon & Garfunkel"),
+        normalize_name("Simon and Garfunkel")
+    );
+    assert_eq!(normalize_name("Xtal (Live) [Bonus]"), "xtal");
+    assert_eq!(name_similarity("Roygbiv", "Roygbiv"), 1.);
+    assert!(name_similarity("Roygbiv", "Aquarius") < MIN_NAME_SIMILARITY);
+
+    let track = |id: &str, name: &str, artist_name: &str| {
+        let mut track = Track::placeholder(id);
+        track.name = name.into();
+        let mut artist = Artist::placeholder("artist");
+        artist.name = artist_name.into();
+        track.artists = vec![artist];
+        track
+    };
+    let candidates = vec![
+        track("cover", "Here Comes The Sun", "Some Cover Band"),
+        track(
+            "original",
+            "Here Comes The Sun - Remastered 2009",
+            "The Beatles",
+        ),
+        track("other", "Something", "The Beatles"),
+    ];
+    let matched = best_match(&candidates, "Beatles", "Here Comes the Sun").unwrap();
+    assert_eq!(matched.id, "original");
+    assert!(best_match(&candidates, "Aphex Twin", "Xtal").is_none());
+}
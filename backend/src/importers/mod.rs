@@ -0,0 +1,402 @@
+//! Importing of listening history recorded by other services so that new users don't start with an
+//! empty history.  Imported plays only identify tracks by name, so they're matched to Spotify
+//! tracks via the search API and stored as recently played tracks alongside the plays fetched from
+//! Spotify.  Monthly snapshots of the user's top artists and tracks can also be synthesized from
+//! the plays so that users migrating from other services keep their archives.
+//!
+//! Imports can take a long time for large histories, so they run in the background.  Each user can
+//! only have one import running at a time, and the progress of their most recent import is kept in
+//! memory so that it can be polled.  Imports that are running when the server restarts are lost.
+
+use std::{cmp::Reverse, sync::Mutex, time::Duration};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use fnv::{FnvHashMap as HashMap, FnvHashSet as HashSet};
+use futures::future::try_join_all;
+
+use crate::{
+    db_util,
+    error::Error,
+    models::{
+        ImportProgress, ImportStage, ImportSummary, NewArtistHistoryEntry, NewTrackHistoryEntry,
+        PlayHistoryItem, Timeframe, Track, TrackArtistPair, User,
+    },
+    spotify_api::{search_tracks, store_recently_played},
+    stats::{synthesize_rankings, synthetic_snapshot_times, SyntheticSnapshotPeriod},
+    DbConn,
+};
+
+pub(crate) mod csv;
+pub(crate) mod lastfm;
+mod matching;
+
+/// Max number of distinct tracks searched for per import.  Tracks are searched for in order of play
+/// count, and plays of any tracks past the limit are skipped.
+const MAX_IMPORTED_TRACKS: usize = 1000;
+/// Number of track searches made at once
+const SEARCH_CONCURRENCY: usize = 8;
+/// Number of search results considered when matching each track
+const SEARCH_CANDIDATE_COUNT: usize = 5;
+/// Max number of plays inserted with a single query
+const INSERT_BATCH_SIZE: usize = 1000;
+/// Max number of times a batch of searches is retried when Spotify is still rate limiting us after
+/// the retries made for each request
+const MAX_RATE_LIMITED_BATCH_RETRIES: u32 = 5;
+/// Delay before retrying a rate limited batch of searches, doubled after each retry
+const RATE_LIMITED_BATCH_BASE_DELAY_SECS: u64 = 60;
+
+lazy_static::lazy_static! {
+    /// Progress of each user's most recent import, keyed by user ID
+    static ref IMPORT_PROGRESS: Mutex<HashMap<i64, ImportProgress>> =
+        Mutex::new(HashMap::default());
+}
+
+/// Where the plays of an import come from
+pub(crate) enum ImportSource {
+    /// Scrobbles fetched from the Last.fm API
+    Lastfm {
+        lastfm_username: String,
+        api_key: String,
+    },
+    /// Plays parsed from an uploaded export
+    Plays(Vec<ImportedPlay>),
+}
+
+/// A play of a track recorded by another service
+pub(crate) struct ImportedPlay {
+    pub played_at: DateTime<Utc>,
+    pub artist_name: String,
+    pub track_name: String,
+}
+
+/// Splits a line of CSV into its fields, unquoting any quoted fields.  Quoted fields spanning
+/// multiple lines aren't supported.
+pub(crate) fn parse_csv_record(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            },
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+fn update_progress(user_id: i64, update: impl FnOnce(&mut ImportProgress)) {
+    if let Some(progress) = IMPORT_PROGRESS.lock().unwrap().get_mut(&user_id) {
+        update(progress);
+    }
+}
+
+/// Returns the progress of the user's most recent import since the server started, if any
+pub(crate) fn get_import_progress(user_id: i64) -> Option<ImportProgress> {
+    IMPORT_PROGRESS.lock().unwrap().get(&user_id).cloned()
+}
+
+/// Starts importing plays from `source` for the user in the background, returning its initial
+/// progress.  Fails if the user already has an import running.
+pub(crate) fn start_import(
+    conn: DbConn,
+    user: User,
+    spotify_access_token: String,
+    source: ImportSource,
+    synthesize_snapshots: bool,
+) -> Result<ImportProgress, Error> {
+    let progress = {
+        let mut imports = IMPORT_PROGRESS.lock().unwrap();
+        if let Some(progress) = imports.get(&user.id) {
+            if !progress.stage.is_done() {
+                return Err(Error::BadRequest(String::from(
+                    "An import is already running for this user",
+                )));
+            }
+        }
+
+        let (stage, play_count) = match &source {
+            ImportSource::Lastfm { .. } => (ImportStage::Fetching, 0),
+            ImportSource::Plays(plays) => (ImportStage::Matching, plays.len()),
+        };
+        let progress = ImportProgress {
+            stage,
+            started_at: Utc::now().naive_utc(),
+            play_count,
+            track_count: 0,
+            searched_track_count: 0,
+            summary: None,
+            error: None,
+        };
+        imports.insert(user.id, progress.clone());
+        progress
+    };
+
+    tokio::task::spawn(async move {
+        let res = async {
+            let plays = match source {
+                ImportSource::Lastfm {
+                    lastfm_username,
+                    api_key,
+                } => {
+                    let plays = lastfm::fetch_scrobbles(&lastfm_username, &api_key, |play_count| {
+                        update_progress(user.id, |progress| progress.play_count = play_count)
+                    })
+                    .await?;
+                    update_progress(user.id, |progress| progress.stage = ImportStage::Matching);
+                    plays
+                },
+                ImportSource::Plays(plays) => plays,
+            };
+            import_plays(
+                &conn,
+                &user,
+                &spotify_access_token,
+                plays,
+                synthesize_snapshots,
+            )
+            .await
+        }
+        .await;
+
+        update_progress(user.id, |progress| match res {
+            Ok(summary) => {
+                progress.stage = ImportStage::Finished;
+                progress.summary = Some(summary);
+            },
+            Err(err) => {
+                error!("Error importing plays for {}: {}", user.username, err);
+                progress.stage = ImportStage::Failed;
+                progress.error = Some(match err {
+                    Error::Database(_) => String::from("Error querying database"),
+                    err => err.to_string(),
+                });
+            },
+        });
+    });
+
+    Ok(progress)
+}
+
+/// Searches for a batch of tracks on Spotify.  If Spotify keeps rate limiting us after the retries
+/// made for each request, the whole batch is retried after a longer delay.
+async fn search_batch(
+    spotify_access_token: &str,
+    batch: &[((&str, &str), usize)],
+) -> Result<Vec<Vec<Track>>, Error> {
+    let mut retry_count = 0;
+    loop {
+        let res = try_join_all(batch.iter().map(|&((artist_name, track_name), _)| {
+            search_tracks(
+                spotify_access_token,
+                artist_name,
+                track_name,
+                SEARCH_CANDIDATE_COUNT,
+            )
+        }))
+        .await;
+        match res {
+            Err(Error::SpotifyRateLimited) if retry_count < MAX_RATE_LIMITED_BATCH_RETRIES => {
+                let delay_secs = RATE_LIMITED_BATCH_BASE_DELAY_SECS << retry_count;
+                warn!(
+                    "Rate limited while searching for imported tracks, waiting {} seconds before \
+                     retrying the batch...",
+                    delay_secs
+                );
+                tokio::time::sleep(Duration::from_secs(delay_secs)).await;
+                retry_count += 1;
+            },
+            res => return res,
+        }
+    }
+}
+
+/// Matches the plays to Spotify tracks and stores them for the user.  Each distinct track is only
+/// searched for once.  If `synthesize_snapshots` is set, snapshots are also synthesized from the
+/// matched plays.  The user's import progress is updated as tracks are searched for.
+async fn import_plays(
+    conn: &DbConn,
+    user: &User,
+    spotify_access_token: &str,
+    plays: Vec<ImportedPlay>,
+    synthesize_snapshots: bool,
+) -> Result<ImportSummary, Error> {
+    let mut play_counts: HashMap<(&str, &str), usize> = HashMap::default();
+    for play in &plays {
+        *play_counts
+            .entry((&play.artist_name, &play.track_name))
+            .or_insert(0) += 1;
+    }
+    let mut tracks_to_search: Vec<_> = play_counts.into_iter().collect();
+    tracks_to_search.sort_unstable_by_key(|&(names, play_count)| (Reverse(play_count), names));
+    tracks_to_search.truncate(MAX_IMPORTED_TRACKS);
+    let searched_track_count = tracks_to_search.len();
+    update_progress(user.id, |progress| {
+        progress.play_count = plays.len();
+        progress.track_count = searched_track_count;
+    });
+
+    let mut matched_tracks: HashMap<(&str, &str), Track> = HashMap::default();
+    for batch in tracks_to_search.chunks(SEARCH_CONCURRENCY) {
+        let results = search_batch(spotify_access_token, batch).await?;
+        for (&((artist_name, track_name), _), candidates) in batch.iter().zip(results) {
+            if let Some(track) = matching::best_match(&candidates, artist_name, track_name) {
+                matched_tracks.insert((artist_name, track_name), track.clone());
+            }
+        }
+        update_progress(user.id, |progress| {
+            progress.searched_track_count += batch.len()
+        });
+    }
+    update_progress(user.id, |progress| progress.stage = ImportStage::Storing);
+
+    let mut matched_play_count = 0;
+    let mut imported_play_count = 0;
+    for batch in plays.chunks(INSERT_BATCH_SIZE) {
+        let items: Vec<PlayHistoryItem> = batch
+            .iter()
+            .filter_map(|play| {
+                let track =
+                    matched_tracks.get(&(play.artist_name.as_str(), play.track_name.as_str()))?;
+                Some(PlayHistoryItem {
+                    track: track.clone(),
+                    played_at: play.played_at,
+                })
+            })
+            .collect();
+        matched_play_count += items.len();
+        imported_play_count += store_recently_played(conn, user, items).await?;
+    }
+
+    let synthetic_snapshot_count = if synthesize_snapshots {
+        let matched_plays: Vec<(NaiveDateTime, &Track)> = plays
+            .iter()
+            .filter_map(|play| {
+                let track =
+                    matched_tracks.get(&(play.artist_name.as_str(), play.track_name.as_str()))?;
+                Some((play.played_at.naive_utc(), track))
+            })
+            .collect();
+        store_synthetic_snapshots(conn, user, matched_plays, SyntheticSnapshotPeriod::Month).await?
+    } else {
+        0
+    };
+
+    Ok(ImportSummary {
+        play_count: plays.len(),
+        searched_track_count,
+        matched_track_count: matched_tracks.len(),
+        matched_play_count,
+        imported_play_count,
+        synthetic_snapshot_count,
+    })
+}
+
+/// Stores a snapshot of the user's top artists and tracks as of the last play in each `period`,
+/// ranking them by play count.  Plays are attributed to the track's first artist.  Only plays from
+/// before the user's first stored snapshot are used so that synthetic snapshots never interleave
+/// with real ones.  Returns the number of snapshots stored.
+pub(crate) async fn store_synthetic_snapshots(
+    conn: &DbConn,
+    user: &User,
+    mut plays: Vec<(NaiveDateTime, &Track)>,
+    period: SyntheticSnapshotPeriod,
+) -> Result<usize, Error> {
+    if let Some(first_snapshot_time) = db_util::get_first_snapshot_time(conn, user.id).await? {
+        plays.retain(|(played_at, _)| *played_at < first_snapshot_time);
+    }
+    plays.sort_unstable_by_key(|(played_at, _)| *played_at);
+
+    let track_plays: Vec<(NaiveDateTime, &str)> = plays
+        .iter()
+        .map(|(played_at, track)| (*played_at, track.id.as_str()))
+        .collect();
+    let artist_plays: Vec<(NaiveDateTime, &str)> = plays
+        .iter()
+        .filter_map(|(played_at, track)| Some((*played_at, track.artists.first()?.id.as_str())))
+        .collect();
+    let tracks: HashMap<&str, &Track> = plays
+        .iter()
+        .map(|(_, track)| (track.id.as_str(), *track))
+        .collect();
+    let track_spotify_ids: Vec<String> = tracks.keys().map(|id| id.to_string()).collect();
+    let artist_spotify_ids: Vec<String> = tracks
+        .values()
+        .flat_map(|track| track.artists.iter().map(|artist| artist.id.as_str()))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .map(String::from)
+        .collect();
+    let mapped_track_ids =
+        db_util::get_internal_ids_by_spotify_id(conn, track_spotify_ids.iter()).await?;
+    let mapped_artist_ids =
+        db_util::get_internal_ids_by_spotify_id(conn, artist_spotify_ids.iter()).await?;
+
+    let entity_fetch_count = db_util::get_user_settings(conn, user)
+        .await?
+        .entity_fetch_count();
+    let snapshot_times = synthetic_snapshot_times(&track_plays, period);
+    let mut artist_entries = Vec::new();
+    let mut track_entries = Vec::new();
+    for &update_time in &snapshot_times {
+        let artist_rankings = synthesize_rankings(&artist_plays, update_time, entity_fetch_count);
+        for (timeframe, ranking) in Timeframe::ALL.into_iter().zip(&artist_rankings) {
+            artist_entries.extend(ranking.iter().enumerate().map(|(rank, spotify_id)| {
+                NewArtistHistoryEntry {
+                    user_id: user.id,
+                    mapped_spotify_id: mapped_artist_ids[*spotify_id],
+                    update_time,
+                    timeframe,
+                    ranking: rank as u8,
+                }
+            }));
+        }
+
+        let track_rankings = synthesize_rankings(&track_plays, update_time, entity_fetch_count);
+        for (timeframe, ranking) in Timeframe::ALL.into_iter().zip(&track_rankings) {
+            track_entries.extend(ranking.iter().enumerate().map(|(rank, spotify_id)| {
+                NewTrackHistoryEntry {
+                    user_id: user.id,
+                    mapped_spotify_id: mapped_track_ids[*spotify_id],
+                    update_time,
+                    timeframe,
+                    ranking: rank as u8,
+                }
+            }));
+        }
+    }
+    let track_artist_pairs: Vec<TrackArtistPair> = tracks
+        .values()
+        .flat_map(|track| {
+            let track_id = mapped_track_ids[&track.id];
+            track
+                .artists
+                .iter()
+                .map(move |artist| (track_id, &artist.id))
+        })
+        .map(|(track_id, artist_spotify_id)| TrackArtistPair {
+            track_id,
+            artist_id: mapped_artist_ids[artist_spotify_id],
+        })
+        .collect();
+
+    db_util::store_synthetic_snapshots(conn, artist_entries, track_entries, track_artist_pairs)
+        .await?;
+    Ok(snapshot_times.len())
+}
+
+#[test]
+fn csv_record_parsing() {
+    assert_eq!(parse_csv_record("a,b,,c"), vec!["a", "b", "", "c"]);
+    assert_eq!(
+        parse_csv_record(r#""Crosby, Stills & Nash","The ""Band""",x"#),
+        vec!["Crosby, Stills & Nash", "The \"Band\"", "x"]
+    );
+    assert_eq!(parse_csv_record(""), vec![""]);
+}
@@ -0,0 +1,154 @@
+//! Structured JSON logging.  Each request is assigned a correlation ID which is attached to every
+//! log line emitted while handling it, including those from Spotify API calls made on its behalf,
+//! and returned to the client in the `X-Request-Id` header.
+//!
+//! The `error!`/`warn!`/`info!` macros exported by Rocket log through the `log` crate; those
+//! records are forwarded into `tracing` so they're emitted as JSON along with everything else.
+
+use std::time::Instant;
+
+use rand::Rng;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Header,
+    route::{self, Handler},
+    Data, Request, Response, Route,
+};
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// Used if `RUST_LOG` isn't set.  Rocket logs every request it handles at `info`, which would
+/// duplicate the lines logged by `RequestLoggingFairing`.
+const DEFAULT_LOG_FILTER: &str = "info,rocket=warn,_=warn";
+
+/// Installs the global JSON logger.  Must be called before anything is logged.
+pub(crate) fn init() {
+    tracing_subscriber::fmt()
+        .json()
+        .with_env_filter(
+            EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_FILTER)),
+        )
+        .with_current_span(true)
+        .with_span_list(false)
+        .init();
+}
+
+/// Correlation ID and start time of the request, stored in its local cache
+struct RequestInfo {
+    id: String,
+    start: Instant,
+}
+
+impl RequestInfo {
+    fn get<'r>(req: &'r Request<'_>) -> &'r RequestInfo {
+        req.local_cache(|| RequestInfo {
+            id: req
+                .headers()
+                .get_one(REQUEST_ID_HEADER)
+                .filter(|id| is_valid_request_id(id))
+                .map(String::from)
+                .unwrap_or_else(generate_request_id),
+            start: Instant::now(),
+        })
+    }
+}
+
+/// IDs provided by clients or upstream proxies are used as-is as long as they can't be used to
+/// inject anything into the logs
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn generate_request_id() -> String { format!("{:016x}", rand::thread_rng().gen::<u64>()) }
+
+/// Returns the user that the request is for if the matched route takes a username
+fn get_request_user<'r>(req: &'r Request<'_>) -> Option<&'r str> {
+    let route = req.route()?;
+    let index = route
+        .uri
+        .path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .position(|segment| segment == "<username>" || segment == "<user1>")?;
+    req.uri().path().segments().get(index)
+}
+
+fn get_route_name<'r>(req: &'r Request<'_>) -> Option<&'r str> {
+    req.route().and_then(|route| route.name.as_deref())
+}
+
+/// Assigns each request its correlation ID and logs a line for every completed request
+pub(crate) struct RequestLoggingFairing;
+
+#[rocket::async_trait]
+impl Fairing for RequestLoggingFairing {
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        RequestInfo::get(req);
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let info = RequestInfo::get(req);
+        res.set_header(Header::new(REQUEST_ID_HEADER, info.id.clone()));
+
+        tracing::info!(
+            request_id = %info.id,
+            method = %req.method(),
+            path = %req.uri().path(),
+            route = get_route_name(req),
+            user = get_request_user(req),
+            status = res.status().code,
+            latency_ms = info.start.elapsed().as_millis() as u64,
+            "Request completed"
+        );
+    }
+
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Logging Fairing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+}
+
+/// Runs the wrapped route handler within a span identifying the request so that everything logged
+/// while handling it includes the request's correlation ID
+#[derive(Clone)]
+struct TracedHandler(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for TracedHandler {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        let span = tracing::info_span!(
+            "request",
+            request_id = %RequestInfo::get(req).id,
+            route = get_route_name(req),
+            user = get_request_user(req),
+        );
+        self.0.handle(req, data).instrument(span).await
+    }
+}
+
+pub(crate) fn trace_routes(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(TracedHandler(route.handler));
+            route
+        })
+        .collect()
+}
+
+#[test]
+fn request_id_validation() {
+    assert!(is_valid_request_id("3f2a9c0d-11b4-4c3e-9e0f-5a2b7c8d9e10"));
+    assert!(is_valid_request_id(&generate_request_id()));
+    assert!(!is_valid_request_id(""));
+    assert!(!is_valid_request_id("abc\"}\n{\"level\":\"ERROR\""));
+    assert!(!is_valid_request_id(&"a".repeat(65)));
+}
@@ -8,42 +8,144 @@ extern crate serde_derive;
 #[macro_use]
 extern crate rocket;
 
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use artist_embedding::{init_artist_embedding_ctx, map_3d::get_packed_3d_artist_coords};
 use foundations::telemetry::{
     settings::{MetricsSettings, ServiceNameFormat, TelemetryServerSettings, TelemetrySettings},
     tokio_runtime_metrics::record_runtime_metrics_sample,
 };
-// use rocket_async_compression::Compression;
 use tokio::sync::Mutex;
 
+pub mod alerts;
+pub mod api_keys;
 pub mod artist_embedding;
+pub mod backfill;
 pub mod benchmarking;
 pub mod cache;
+pub mod compression;
 pub mod conf;
 pub mod cors;
+pub mod db_backend;
 pub mod db_util;
+pub mod digest;
+pub mod dry_run;
+pub mod email;
+pub mod error;
+pub mod events;
+pub mod export;
 pub mod external_storage;
+pub mod genres;
+pub mod graphql;
+pub mod importers;
+pub mod logging;
 pub mod metrics;
 pub mod models;
+pub mod oauth_scopes;
+pub mod onboarding;
+pub mod openapi;
+pub mod outbox;
+pub mod pagination;
+pub mod profile_views;
+pub mod public_stats;
+pub mod rate_limit;
+pub mod request_timing;
+pub mod retention;
 pub mod routes;
+pub mod scheduler;
 pub mod schema;
+pub mod sealing;
+pub mod seed;
+pub mod session;
+pub mod share_card;
 pub mod shared_playlist_gen;
 pub mod spotify_api;
 pub mod spotify_token;
 pub mod stats;
+#[cfg(test)]
+pub mod test_harness;
+pub mod token_encryption;
 
 use crate::{cache::local_cache::init_spotify_id_map_cache, conf::CONF};
 
 use self::spotify_token::SpotifyTokenData;
 
 #[rocket_sync_db_pools::database("spotify_homepage")]
-pub struct DbConn(diesel::MysqlConnection);
+pub struct DbConn(db_backend::DbConnection);
+
+/// Builds Rocket's configuration, including the database pool settings
+fn build_figment() -> rocket::figment::Figment {
+    // Routes like `get_current_stats` check out multiple connections per request in order to run
+    // queries concurrently, so the pool size may need to be raised to match.
+    let mut figment = rocket::Config::figment();
+    if let Some(pool_size) = CONF.db_pool_size {
+        info!("Using database connection pool size of {}", pool_size);
+        figment = figment.merge(("databases.spotify_homepage.pool_size", pool_size));
+    }
+    figment
+}
+
+enum Subcommand {
+    Backfill(backfill::BackfillArgs),
+    Seed(seed::SeedArgs),
+}
+
+/// Runs one of the command line subcommands, which use the database without launching the server.
+/// Returns the process's exit code.
+async fn run_subcommand(subcommand: &str, args: impl Iterator<Item = String>) -> i32 {
+    let parsed = match subcommand {
+        "backfill" => backfill::parse_args(args)
+            .map(Subcommand::Backfill)
+            .map_err(|err| (err, backfill::USAGE)),
+        "seed" => seed::parse_args(args)
+            .map(Subcommand::Seed)
+            .map_err(|err| (err, seed::USAGE)),
+        _ => {
+            eprintln!(
+                "Unknown subcommand `{}`\n{}\n{}",
+                subcommand,
+                backfill::USAGE,
+                seed::USAGE
+            );
+            return 2;
+        },
+    };
+    let subcommand = match parsed {
+        Ok(subcommand) => subcommand,
+        Err((err, usage)) => {
+            eprintln!("{}\n{}", err, usage);
+            return 2;
+        },
+    };
+
+    init_spotify_id_map_cache().await;
+    // Rocket is only ignited, not launched, in order to set up the database pool
+    let rocket = match rocket::custom(build_figment())
+        .attach(DbConn::fairing())
+        .ignite()
+        .await
+    {
+        Ok(rocket) => rocket,
+        Err(err) => {
+            error!("Error setting up database pool: {:?}", err);
+            return 1;
+        },
+    };
+    match subcommand {
+        Subcommand::Backfill(args) => backfill::run(args, &rocket).await,
+        Subcommand::Seed(args) => seed::run(args, &rocket).await,
+    }
+}
 
 #[rocket::main]
 pub async fn main() {
     dotenv::dotenv().expect("dotenv file parsing failed");
+    logging::init();
+
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>().into_iter();
+    if let Some(subcommand) = args.next() {
+        std::process::exit(run_subcommand(&subcommand, args).await);
+    }
 
     let tele_serv_fut = foundations::telemetry::init_with_server(
         &foundations::service_info!(),
@@ -63,12 +165,12 @@ pub async fn main() {
     )
     .expect("Failed to initialize telemetry server");
     let tele_serv_addr = tele_serv_fut.server_addr().unwrap();
-    println!("Telemetry server is listening on http://{}", tele_serv_addr);
+    info!("Telemetry server is listening on http://{}", tele_serv_addr);
     tokio::task::spawn(tele_serv_fut);
 
     let handle = tokio::runtime::Handle::current();
     foundations::telemetry::tokio_runtime_metrics::register_runtime(None, None, &handle);
-    println!("Registered tokio runtime metrics");
+    info!("Registered tokio runtime metrics");
 
     tokio::task::spawn(async move {
         loop {
@@ -82,21 +184,94 @@ pub async fn main() {
     tokio::task::spawn(init_spotify_id_map_cache());
     init_artist_embedding_ctx("https://ameo.dev/artist_embedding_8d.w2v").await;
 
-    let all_routes = routes![
+    let all_routes = logging::trace_routes(routes![
         routes::index,
+        routes::healthz,
+        routes::readyz,
+        routes::get_openapi_spec,
+        routes::graphql,
         routes::get_current_stats,
         routes::oauth_cb,
         routes::authorize,
+        routes::logout,
         routes::update_user,
+        routes::get_scheduler_status,
+        routes::invalidate_cache,
+        routes::warm_cache,
+        routes::encrypt_tokens,
+        routes::get_cache_status,
+        routes::refresh_global_charts,
+        routes::get_global_top_artists,
+        routes::get_global_top_tracks,
+        routes::get_about_stats,
+        routes::get_global_summary,
+        routes::get_artist_leaderboard,
+        routes::get_admin_users,
+        routes::get_admin_stats,
+        routes::stream_admin_events,
+        routes::create_api_key,
+        routes::get_api_keys,
+        routes::revoke_api_key,
+        routes::set_user_update_interval,
+        routes::reset_user_update_failures,
         routes::get_artist_stats,
         routes::get_genre_history,
         routes::populate_tracks_artists_mapping_table,
         routes::populate_artists_genres_mapping_table,
         routes::get_genre_stats,
+        routes::get_genre_breakdown,
+        routes::get_diversity,
+        routes::get_genre_timeline,
+        routes::get_popularity_trends,
+        routes::get_mainstream_score,
+        routes::get_crossover,
+        routes::get_audio_features,
         routes::get_timeline,
+        routes::get_aggregated_timeline,
+        routes::get_chart_data,
+        routes::get_snapshots,
+        routes::get_artist_discoveries,
+        routes::get_snapshot,
+        routes::get_recently_played,
+        routes::get_follows,
+        routes::get_library,
+        routes::get_recommendations,
+        routes::export_rank_history_csv,
+        routes::export_user_data,
+        routes::delete_user,
+        routes::set_privacy,
+        routes::set_deactivation,
+        routes::get_email_subscription,
+        routes::set_email_subscription,
+        routes::delete_email_subscription,
+        routes::confirm_email_subscription,
+        routes::unsubscribe_email,
+        routes::get_friends,
+        routes::send_friend_request,
+        routes::accept_friend_request,
+        routes::remove_friend,
+        routes::get_friends_feed,
+        routes::get_user_settings,
+        routes::update_user_settings,
+        routes::get_linked_accounts,
+        routes::link_account,
+        routes::unlink_account,
+        routes::import_lastfm_scrobbles,
+        routes::import_lastfm_export,
+        routes::import_listening_history_csv,
+        routes::get_import_progress,
+        routes::generate_top_tracks_playlist,
+        routes::get_now_playing,
+        routes::stream_user_events,
+        routes::get_listening_time,
         routes::compare_users,
         routes::get_related_artists_graph,
+        routes::get_artist_graph,
         routes::get_related_artists,
+        routes::get_share_card,
+        routes::get_share_page,
+        routes::get_stats_feed,
+        routes::search_user_history,
         routes::get_display_name,
         routes::dump_redis_related_artists_to_database,
         routes::crawl_related_artists,
@@ -113,19 +288,38 @@ pub async fn main() {
         routes::transfer_user_data_to_external_storage,
         routes::transfer_user_data_from_external_storage,
         routes::bulk_transfer_user_data_to_external_storage,
-    ];
+        routes::compact_history,
+    ]);
 
     // Pre-populate the packed 3D artist map embedding to make the first request for it instant
     // tokio::task::spawn(async {
     //     get_packed_3d_artist_coords().await;
     // });
 
-    let builder = rocket::build()
+    let builder = rocket::custom(build_figment())
         .mount("/", all_routes.clone())
         .mount("/api/", all_routes)
-        .manage(Mutex::new(SpotifyTokenData::new().await))
+        .manage(Arc::new(Mutex::new(SpotifyTokenData::new().await)))
+        .manage(graphql::build_schema())
         .attach(DbConn::fairing())
-        .attach(cors::CorsFairing);
+        .attach(logging::RequestLoggingFairing)
+        .attach(request_timing::RequestTimingFairing::new())
+        .attach(cors::CorsFairing)
+        .attach(profile_views::ProfileViewFairing)
+        .attach(rate_limit::RateLimitFairing::from_conf())
+        .attach(compression::CompressionFairing)
+        .attach(rocket::fairing::AdHoc::on_liftoff(
+            "Update scheduler",
+            |rocket| Box::pin(scheduler::start(rocket)),
+        ))
+        .attach(rocket::fairing::AdHoc::on_liftoff(
+            "Outbox dispatcher",
+            |rocket| Box::pin(outbox::start(rocket)),
+        ))
+        .attach(rocket::fairing::AdHoc::on_liftoff(
+            "Digest sender",
+            |rocket| Box::pin(digest::start(rocket)),
+        ));
 
     builder.launch().await.expect("Error launching Rocket");
     info!("Rocket exited cleanly");
@@ -1,4 +1,4 @@
-use foundations::telemetry::metrics::{metrics, Counter, HistogramBuilder, TimeHistogram};
+use foundations::telemetry::metrics::{metrics, Counter, Gauge, HistogramBuilder, TimeHistogram};
 
 use foundations;
 
@@ -51,6 +51,27 @@ pub(crate) mod metrics {
         buckets: &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 15.0, 20.0, 30.0, 60.0, 120.0, 300.0, 600.0],
     }]
     pub fn external_user_data_export_time() -> TimeHistogram;
+
+    /// Latency in microseconds of each route at the given quantile over its most recent requests
+    pub fn http_request_latency_quantile_us(route: &'static str, quantile: &'static str) -> Gauge;
+
+    /// Total number of entities served from the in-process hot cache
+    pub fn hot_cache_hits_total() -> Counter;
+
+    /// Total number of entities looked up in the in-process hot cache but not found there
+    pub fn hot_cache_misses_total() -> Counter;
+
+    /// Total number of alerts sent to operators
+    pub fn alerts_sent_total(kind: &'static str) -> Counter;
+
+    /// Total number of entities whose stored metadata was re-fetched by the refresh job
+    pub fn metadata_entities_refreshed_total(table: &'static str) -> Counter;
+
+    /// Total number of outbox events delivered
+    pub fn outbox_events_delivered_total(event_type: &'static str) -> Counter;
+
+    /// Total number of failed attempts to deliver outbox events
+    pub fn outbox_event_failures_total(event_type: &'static str) -> Counter;
 }
 
 pub use metrics::*;
@@ -1,14 +1,34 @@
-use std::{default::Default, fmt::Debug, vec};
-
-use chrono::{NaiveDate, NaiveDateTime};
+use std::{default::Default, fmt::Debug, io::Write, str::FromStr, vec};
+
+use async_graphql::{Enum, SimpleObject};
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use diesel::{
+    deserialize::{self, FromSql},
+    serialize::{self, Output, ToSql},
+};
 use float_ord::FloatOrd;
 use fnv::FnvHashMap as HashMap;
+use rocket::request::FromParam;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::schema::{
-    artist_rank_snapshots, artists_genres, related_artists, spotify_items, track_rank_snapshots,
-    tracks_artists, users,
+use crate::{
+    conf::CONF,
+    db_backend::{
+        impl_insertable,
+        sql_types::{Text, TinyInt, Unsigned},
+        DbBackend,
+    },
+    error::Error,
+    schema::{
+        api_keys, artist_rank_deltas, artists_genres, diversity_scores, email_subscriptions,
+        followed_artists, friendships, genre_weights, global_charts, latest_artist_stats,
+        latest_track_stats, library_snapshots, linked_accounts, outbox_events, popularity_history,
+        recently_played, related_artists, saved_tracks, spotify_items, system_stats,
+        track_isrc_map, track_rank_deltas, tracks_artists, update_errors, user_settings, users,
+    },
 };
 
 #[derive(Insertable)]
@@ -18,8 +38,9 @@ pub(crate) struct NewUser {
     pub last_update_time: NaiveDateTime,
     pub spotify_id: String,
     pub username: String,
-    pub token: String,
-    pub refresh_token: String,
+    pub token: StoredToken,
+    pub refresh_token: StoredToken,
+    pub private_token: Option<String>,
 }
 
 #[derive(Serialize, Queryable, Clone, Debug)]
@@ -29,67 +50,272 @@ pub(crate) struct User {
     pub last_update_time: NaiveDateTime,
     pub spotify_id: String,
     pub username: String,
+    #[diesel(deserialize_as = "StoredToken")]
     pub token: String,
+    #[diesel(deserialize_as = "StoredToken")]
     pub refresh_token: String,
     pub external_data_retrieved: bool,
     pub last_viewed: NaiveDateTime,
     pub last_external_data_store: NaiveDateTime,
+    /// If set, the user's stats can only be viewed by supplying their `private_token`
+    pub is_private: bool,
+    pub private_token: Option<String>,
+    /// Number of update attempts for the user that have failed permanently, such as because their
+    /// refresh token was rejected, since the last successful one
+    pub consecutive_update_failures: i32,
+    /// IANA name of the timezone that timestamps in the user's stats are returned in.  UTC if
+    /// unset.
+    pub timezone: Option<String>,
+    /// User-chosen identifier that can be used in place of the Spotify ID in stats URLs
+    pub vanity_slug: Option<String>,
+    /// If set, the user isn't updated and their stats aren't served.  Users are deactivated at
+    /// their own request or when their refresh token is revoked.
+    pub deactivated_at: Option<NaiveDateTime>,
+    /// ID of the user's `UpdateTier`
+    pub update_tier: u8,
+    /// Overrides the minimum time between automatic updates of the user set by their update tier
+    pub min_update_interval_seconds: Option<i32>,
+    /// Number of update attempts for the user that have failed for any reason since the last
+    /// successful one
+    pub failed_update_attempts: i32,
+    /// If set, the user isn't updated automatically until this time because their last update
+    /// attempt failed
+    pub next_update_attempt_at: Option<NaiveDateTime>,
+}
+
+/// Spotify access or refresh token, which is encrypted when written to the `users` table and
+/// decrypted when read from it
+#[derive(Clone, Debug, AsExpression, FromSqlRow)]
+#[sql_type = "Text"]
+pub(crate) struct StoredToken(pub String);
+
+impl ToSql<Text, DbBackend> for StoredToken {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, DbBackend>) -> serialize::Result {
+        ToSql::<Text, DbBackend>::to_sql(&crate::token_encryption::encrypt_token(&self.0), out)
+    }
 }
 
-#[derive(Serialize, Insertable, Associations)]
+impl FromSql<Text, DbBackend> for StoredToken {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        let stored: String = FromSql::<Text, DbBackend>::from_sql(bytes)?;
+        Ok(StoredToken(crate::token_encryption::decrypt_token(
+            &stored,
+        )?))
+    }
+}
+
+impl From<StoredToken> for String {
+    fn from(token: StoredToken) -> Self { token.0 }
+}
+
+/// A timestamp converted into a user's timezone.  These serialize as RFC 3339 with the offset
+/// included.
+pub(crate) type LocalDateTime = DateTime<FixedOffset>;
+
+impl User {
+    pub(crate) fn tz(&self) -> Tz {
+        self.timezone
+            .as_deref()
+            .and_then(|timezone| timezone.parse().ok())
+            .unwrap_or(Tz::UTC)
+    }
+
+    /// Converts a naive UTC timestamp into the user's timezone
+    pub(crate) fn localize(&self, time: NaiveDateTime) -> LocalDateTime {
+        self.tz().from_utc_datetime(&time).fixed_offset()
+    }
+
+    /// Minimum time between automatic updates of the user
+    pub(crate) fn min_update_interval(&self) -> chrono::Duration {
+        UpdateTier::from_id(self.update_tier).user_update_interval(self.min_update_interval_seconds)
+    }
+}
+
+#[derive(Serialize, Associations)]
 #[belongs_to(User)]
-#[table_name = "track_rank_snapshots"]
+#[table_name = "track_rank_deltas"]
 pub(crate) struct NewTrackHistoryEntry {
     pub user_id: i64,
     pub mapped_spotify_id: i32,
     pub update_time: NaiveDateTime,
-    pub timeframe: u8,
+    pub timeframe: Timeframe,
     pub ranking: u8,
 }
 
-#[derive(Serialize, Insertable, Associations)]
+impl_insertable!(NewTrackHistoryEntry => track_rank_deltas {
+    user_id: i64,
+    mapped_spotify_id: i32,
+    update_time: NaiveDateTime,
+    timeframe: Timeframe,
+    ranking: u8,
+});
+
+#[derive(Serialize, Associations)]
 #[belongs_to(User)]
-#[table_name = "artist_rank_snapshots"]
+#[table_name = "artist_rank_deltas"]
 pub(crate) struct NewArtistHistoryEntry {
     pub user_id: i64,
     pub mapped_spotify_id: i32,
     pub update_time: NaiveDateTime,
-    pub timeframe: u8,
+    pub timeframe: Timeframe,
     pub ranking: u8,
 }
 
+impl_insertable!(NewArtistHistoryEntry => artist_rank_deltas {
+    user_id: i64,
+    mapped_spotify_id: i32,
+    update_time: NaiveDateTime,
+    timeframe: Timeframe,
+    ranking: u8,
+});
+
+/// One ranking of a user's most recent snapshot, stored in full in `latest_artist_stats` or
+/// `latest_track_stats`
+#[derive(Clone, Debug)]
+pub(crate) struct LatestStatsEntry {
+    pub user_id: i64,
+    pub timeframe: Timeframe,
+    pub ranking: u8,
+    pub mapped_spotify_id: i32,
+    pub update_time: NaiveDateTime,
+}
+
+impl_insertable!(LatestStatsEntry => latest_artist_stats {
+    user_id: i64,
+    timeframe: Timeframe,
+    ranking: u8,
+    mapped_spotify_id: i32,
+    update_time: NaiveDateTime,
+});
+
+impl_insertable!(LatestStatsEntry => latest_track_stats {
+    user_id: i64,
+    timeframe: Timeframe,
+    ranking: u8,
+    mapped_spotify_id: i32,
+    update_time: NaiveDateTime,
+});
+
+/// Number of entries in one timeframe of a stored snapshot.  Snapshots are delta-encoded, so this
+/// is needed to know which rankings the snapshot includes since they don't all have rows.
+#[derive(Debug, PartialEq)]
+pub(crate) struct SnapshotUpdate {
+    pub user_id: i64,
+    pub update_time: NaiveDateTime,
+    pub timeframe: Timeframe,
+    pub artist_count: u8,
+    pub track_count: u8,
+}
+
+/// Listening diversity metrics for one timeframe of a stored snapshot
+#[derive(Queryable)]
+pub(crate) struct DiversityScoreEntry {
+    pub user_id: i64,
+    pub update_time: NaiveDateTime,
+    pub timeframe: Timeframe,
+    pub genre_entropy: f32,
+    pub artist_gini: f32,
+}
+
+impl_insertable!(DiversityScoreEntry => diversity_scores {
+    user_id: i64,
+    update_time: NaiveDateTime,
+    timeframe: Timeframe,
+    genre_entropy: f32,
+    artist_gini: f32,
+});
+
+/// Weight of one of the top genres in one timeframe of a stored snapshot.  See
+/// `stats::compute_genre_weights`.
 #[derive(Queryable)]
+pub(crate) struct GenreWeightEntry {
+    pub user_id: i64,
+    pub update_time: NaiveDateTime,
+    pub timeframe: Timeframe,
+    pub genre: String,
+    pub weight: u32,
+}
+
+impl_insertable!(GenreWeightEntry => genre_weights {
+    user_id: i64,
+    update_time: NaiveDateTime,
+    timeframe: Timeframe,
+    genre: String,
+    weight: u32,
+});
+
+/// Spotify's popularity score of one of the top artists or tracks of a stored snapshot at the time
+/// that it was stored
+pub(crate) struct PopularityEntry {
+    pub user_id: i64,
+    pub update_time: NaiveDateTime,
+    pub timeframe: Timeframe,
+    /// See `db_util::entity_type_code`
+    pub entity_type: u8,
+    pub ranking: u8,
+    pub mapped_spotify_id: i32,
+    /// From 0 to 100
+    pub popularity: u8,
+}
+
+impl_insertable!(PopularityEntry => popularity_history {
+    user_id: i64,
+    update_time: NaiveDateTime,
+    timeframe: Timeframe,
+    entity_type: u8,
+    ranking: u8,
+    mapped_spotify_id: i32,
+    popularity: u8,
+});
+
+#[derive(Queryable, Clone)]
 pub(crate) struct UserHistoryEntry {
     pub id: i64,
     pub user_id: i64,
     pub update_time: NaiveDateTime,
     pub mapped_spotify_id: i32,
-    pub timeframe: u8,
+    pub timeframe: Timeframe,
     pub ranking: u8,
 }
 
-#[derive(Clone, Insertable)]
-#[table_name = "track_rank_snapshots"]
+#[derive(Clone)]
 pub(crate) struct TrackHistoryEntry {
     pub id: i64,
     pub user_id: i64,
     pub update_time: NaiveDateTime,
     pub mapped_spotify_id: i32,
-    pub timeframe: u8,
+    pub timeframe: Timeframe,
     pub ranking: u8,
 }
 
-#[derive(Clone, Insertable)]
-#[table_name = "artist_rank_snapshots"]
+impl_insertable!(TrackHistoryEntry => track_rank_deltas {
+    id: i64,
+    user_id: i64,
+    update_time: NaiveDateTime,
+    mapped_spotify_id: i32,
+    timeframe: Timeframe,
+    ranking: u8,
+});
+
+#[derive(Clone)]
 pub(crate) struct ArtistHistoryEntry {
     pub id: i64,
     pub user_id: i64,
     pub update_time: NaiveDateTime,
     pub mapped_spotify_id: i32,
-    pub timeframe: u8,
+    pub timeframe: Timeframe,
     pub ranking: u8,
 }
 
+impl_insertable!(ArtistHistoryEntry => artist_rank_deltas {
+    id: i64,
+    user_id: i64,
+    update_time: NaiveDateTime,
+    mapped_spotify_id: i32,
+    timeframe: Timeframe,
+    ranking: u8,
+});
+
 impl From<ArtistHistoryEntry> for UserHistoryEntry {
     fn from(entry: ArtistHistoryEntry) -> Self {
         UserHistoryEntry {
@@ -136,6 +362,13 @@ pub(crate) struct TrackArtistPair {
     pub artist_id: i32,
 }
 
+#[derive(Insertable)]
+#[table_name = "track_isrc_map"]
+pub(crate) struct TrackIsrcMapping {
+    pub spotify_id: String,
+    pub isrc: String,
+}
+
 #[derive(Insertable)]
 #[table_name = "artists_genres"]
 pub(crate) struct ArtistGenrePair {
@@ -143,7 +376,106 @@ pub(crate) struct ArtistGenrePair {
     pub genre: String,
 }
 
-#[derive(Serialize)]
+/// Period that Spotify computes a user's top artists and tracks over.  Stored in the database as
+/// its ID.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    Enum,
+    AsExpression,
+    FromSqlRow,
+)]
+#[serde(rename_all = "lowercase")]
+#[sql_type = "Unsigned<TinyInt>"]
+pub(crate) enum Timeframe {
+    /// About the last 4 weeks
+    Short,
+    /// About the last 6 months
+    Medium,
+    /// All time
+    Long,
+}
+
+impl Timeframe {
+    pub(crate) const ALL: [Timeframe; 3] = [Timeframe::Short, Timeframe::Medium, Timeframe::Long];
+
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Timeframe::Short => 0,
+            Timeframe::Medium => 1,
+            Timeframe::Long => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> { Timeframe::ALL.get(id as usize).copied() }
+
+    /// Name used by the Spotify API, in query params, and in serialized `TimeFrames`
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Timeframe::Short => "short",
+            Timeframe::Medium => "medium",
+            Timeframe::Long => "long",
+        }
+    }
+
+    /// Human-readable name shown on share cards and in feeds
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            Timeframe::Short => "Last 4 Weeks",
+            Timeframe::Medium => "Last 6 Months",
+            Timeframe::Long => "All Time",
+        }
+    }
+}
+
+impl FromStr for Timeframe {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self, Error> {
+        Timeframe::ALL
+            .into_iter()
+            .find(|timeframe| timeframe.name() == name)
+            .ok_or_else(|| {
+                Error::BadRequest(String::from(
+                    "Invalid `timeframe`; must be one of \"short\", \"medium\", \"long\"",
+                ))
+            })
+    }
+}
+
+impl std::fmt::Display for Timeframe {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result { fmt.write_str(self.name()) }
+}
+
+impl<'a> FromParam<'a> for Timeframe {
+    type Error = Error;
+
+    fn from_param(param: &'a str) -> Result<Self, Error> { param.parse() }
+}
+
+impl ToSql<Unsigned<TinyInt>, DbBackend> for Timeframe {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, DbBackend>) -> serialize::Result {
+        ToSql::<Unsigned<TinyInt>, DbBackend>::to_sql(&self.id(), out)
+    }
+}
+
+impl FromSql<Unsigned<TinyInt>, DbBackend> for Timeframe {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        let id: u8 = FromSql::<Unsigned<TinyInt>, DbBackend>::from_sql(bytes)?;
+        Timeframe::from_id(id).ok_or_else(|| format!("Invalid timeframe ID {}", id).into())
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
 pub(crate) struct TimeFrames<T: Serialize> {
     pub short: Vec<T>,
     pub medium: Vec<T>,
@@ -151,23 +483,19 @@ pub(crate) struct TimeFrames<T: Serialize> {
 }
 
 impl<T: Serialize> TimeFrames<T> {
-    pub(crate) fn add_item(&mut self, timeframe: &str, item: T) {
-        let collection = match timeframe {
-            "short" => &mut self.short,
-            "medium" => &mut self.medium,
-            "long" => &mut self.long,
-            _ => panic!("Invalid timeframe passed to `TimeFrames::add_item`"),
-        };
-
-        collection.push(item);
+    pub(crate) fn get(&self, timeframe: Timeframe) -> &Vec<T> {
+        match timeframe {
+            Timeframe::Short => &self.short,
+            Timeframe::Medium => &self.medium,
+            Timeframe::Long => &self.long,
+        }
     }
 
-    pub(crate) fn add_item_by_id(&mut self, timeframe_id: u8, item: T) {
-        let collection = match timeframe_id {
-            0 => &mut self.short,
-            1 => &mut self.medium,
-            2 => &mut self.long,
-            _ => panic!("Invalid timeframe id passed to `TimeFrames::add_item_by_id`"),
+    pub(crate) fn add_item(&mut self, timeframe: Timeframe, item: T) {
+        let collection = match timeframe {
+            Timeframe::Short => &mut self.short,
+            Timeframe::Medium => &mut self.medium,
+            Timeframe::Long => &mut self.long,
         };
 
         collection.push(item);
@@ -207,13 +535,13 @@ impl<T: Serialize> Default for TimeFrames<T> {
 
 impl<T: Serialize> IntoIterator for TimeFrames<T> {
     type IntoIter = vec::IntoIter<Self::Item>;
-    type Item = (&'static str, Vec<T>);
+    type Item = (Timeframe, Vec<T>);
 
     fn into_iter(self) -> Self::IntoIter {
         vec![
-            ("short", self.short),
-            ("medium", self.medium),
-            ("long", self.long),
+            (Timeframe::Short, self.short),
+            (Timeframe::Medium, self.medium),
+            (Timeframe::Long, self.long),
         ]
         .into_iter()
     }
@@ -233,25 +561,25 @@ where
 }
 
 impl<'a, T: Serialize> TimeFrames<T> {
-    pub(crate) fn iter(&'a self) -> impl Iterator<Item = (&'static str, &'a Vec<T>)> {
+    pub(crate) fn iter(&'a self) -> impl Iterator<Item = (Timeframe, &'a Vec<T>)> {
         vec![
-            ("short", &self.short),
-            ("medium", &self.medium),
-            ("long", &self.long),
+            (Timeframe::Short, &self.short),
+            (Timeframe::Medium, &self.medium),
+            (Timeframe::Long, &self.long),
         ]
         .into_iter()
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 pub(crate) struct StatsSnapshot {
-    pub last_update_time: NaiveDateTime,
+    pub last_update_time: LocalDateTime,
     pub tracks: TimeFrames<Track>,
     pub artists: TimeFrames<Artist>,
 }
 
 impl StatsSnapshot {
-    pub(crate) fn new(last_update_time: NaiveDateTime) -> Self {
+    pub(crate) fn new(last_update_time: LocalDateTime) -> Self {
         StatsSnapshot {
             last_update_time,
             tracks: TimeFrames::default(),
@@ -260,6 +588,149 @@ impl StatsSnapshot {
     }
 }
 
+#[derive(Serialize, Debug, JsonSchema)]
+pub(crate) struct GenreScore {
+    /// Canonical slug of the genre
+    pub genre: String,
+    pub display_name: String,
+    pub score: usize,
+    /// The number of (artist, timeframe) entries which contributed to this genre's score
+    pub artist_count: usize,
+}
+
+/// Genres of a user's current top artists across all timeframes, sorted from highest to lowest
+/// score.
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct GenreBreakdown {
+    pub last_update_time: LocalDateTime,
+    pub genres: Vec<GenreScore>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct DiversityScore {
+    pub update_time: LocalDateTime,
+    /// Shannon entropy, in bits, of the genres of the top artists.  Higher values mean that the
+    /// artists are spread across more genres more evenly.
+    pub genre_entropy: f32,
+    /// Gini coefficient of the number of top tracks by each artist, from 0 when every track is by
+    /// a different artist to nearly 1 when they're all by the same one.
+    pub artist_gini: f32,
+}
+
+/// Diversity scores of every snapshot of a user's stats that has them, oldest first
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct DiversityHistory {
+    pub history: TimeFrames<DiversityScore>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct GenreTrend {
+    /// Canonical slug of the genre
+    pub genre: String,
+    pub display_name: String,
+    /// Weight of the genre in each snapshot, aligned with `GenreTimeline::timestamps`.  `null` if
+    /// it wasn't among the top genres of that snapshot.
+    pub weights: Vec<Option<u32>>,
+    pub peak_weight: u32,
+    pub peaked_at: LocalDateTime,
+}
+
+/// Weights of the user's top genres in each snapshot of one timeframe, oldest first.  Genres are
+/// sorted by their peak weight.
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct GenreTimeline {
+    pub timeframe: Timeframe,
+    pub timestamps: Vec<LocalDateTime>,
+    pub genres: Vec<GenreTrend>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct PopularityPoint {
+    pub update_time: LocalDateTime,
+    /// Mean Spotify popularity, from 0 to 100, of the top artists at the time of the snapshot
+    pub artist_popularity: Option<f32>,
+    /// Mean Spotify popularity, from 0 to 100, of the top tracks at the time of the snapshot
+    pub track_popularity: Option<f32>,
+}
+
+/// Least-squares fit of how the popularity of the user's favorites has changed over one timeframe's
+/// history
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct PopularityTrend {
+    pub timeframe: Timeframe,
+    /// Change in the mean popularity of the top artists per 30 days.  Positive if the user's
+    /// favorites are getting more mainstream.  `null` if there are fewer than two snapshots with
+    /// popularity scores.
+    pub artist_trend: Option<f32>,
+    /// Change in the mean popularity of the top tracks per 30 days, like `artist_trend`
+    pub track_trend: Option<f32>,
+}
+
+/// How popular the user's top artists and tracks were in each of their snapshots, oldest first
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct PopularityTrends {
+    pub history: TimeFrames<PopularityPoint>,
+    pub trends: Vec<PopularityTrend>,
+}
+
+/// How mainstream the user's top artists and tracks were in one snapshot, from 0 to 100.  See
+/// `stats::compute_mainstream_score`.
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct MainstreamScore {
+    pub update_time: LocalDateTime,
+    /// Mean of `artist_score` and `track_score`, or whichever of them is known
+    pub score: f32,
+    pub artist_score: Option<f32>,
+    pub track_score: Option<f32>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct MainstreamHistory {
+    /// Score of the most recent snapshot for each timeframe, keyed by timeframe
+    pub current: HashMap<&'static str, f32>,
+    /// Scores of each of the user's snapshots, oldest first
+    pub history: TimeFrames<MainstreamScore>,
+}
+
+/// Favorites that have moved between timeframes in the user's most recent snapshot
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct TimeframeCrossover<T> {
+    /// Short-term favorites that have also made it into the medium or long-term top list, in order
+    /// of short-term ranking
+    pub graduated: Vec<T>,
+    /// Long-term favorites that are in neither the short nor medium-term top list anymore, in
+    /// order of long-term ranking
+    pub fallen_out: Vec<T>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct Crossover {
+    pub artists: TimeframeCrossover<Artist>,
+    pub tracks: TimeframeCrossover<Track>,
+}
+
+/// Mean audio features of a set of tracks
+#[derive(Serialize, Debug, JsonSchema)]
+pub(crate) struct MoodProfile {
+    pub danceability: f32,
+    pub energy: f32,
+    pub valence: f32,
+    pub tempo: f32,
+    pub acousticness: f32,
+    pub instrumentalness: f32,
+    pub speechiness: f32,
+    pub liveness: f32,
+    pub loudness: f32,
+    /// The number of tracks that audio features were available for
+    pub track_count: usize,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct AudioFeaturesProfile {
+    pub last_update_time: LocalDateTime,
+    pub mood_by_timeframe: HashMap<&'static str, MoodProfile>,
+}
+
 #[derive(Deserialize)]
 #[serde(untagged)]
 pub enum OAuthTokenResponse {
@@ -282,14 +753,14 @@ pub(crate) struct Followers {
     pub total: usize,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema, SimpleObject)]
 pub(crate) struct Image {
     // pub height: Option<usize>,
     pub url: String,
     // pub width: Option<usize>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema, SimpleObject)]
 pub(crate) struct Album {
     // pub album_group: Option<String>,
     // pub album_type: String,
@@ -313,37 +784,1094 @@ pub(crate) struct TopTracksResponse {
 pub(crate) struct StatsHistoryQueryResItem {
     #[sql_type = "::diesel::sql_types::Text"]
     pub spotify_id: String,
-    #[sql_type = "::diesel::sql_types::Datetime"]
+    #[sql_type = "crate::db_backend::sql_types::Datetime"]
     pub update_time: NaiveDateTime,
-    #[sql_type = "::diesel::sql_types::Unsigned<::diesel::sql_types::TinyInt>"]
+    #[sql_type = "crate::db_backend::sql_types::Unsigned<::diesel::sql_types::TinyInt>"]
     pub ranking: u8,
-    #[sql_type = "::diesel::sql_types::Unsigned<::diesel::sql_types::TinyInt>"]
-    pub timeframe: u8,
+    #[sql_type = "crate::db_backend::sql_types::Unsigned<::diesel::sql_types::TinyInt>"]
+    pub timeframe: Timeframe,
 }
 
-#[derive(Queryable)]
-pub(crate) struct ArtistRankHistoryResItem {
-    pub update_time: NaiveDateTime,
-    pub ranking: u8,
-    pub timeframe: u8,
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema, SimpleObject)]
 pub(crate) struct Track {
     pub album: Album,
     pub artists: Vec<Artist>,
     // pub available_markets: Vec<String>,
     // pub disc_number: usize,
-    // pub duration_ms: usize,
+    pub duration_ms: Option<usize>,
     // pub explicit: bool,
     // pub href: Option<String>,
     pub id: String,
     // pub is_playable: Option<bool>,
     pub name: String,
-    // pub popularity: usize,
+    pub popularity: Option<usize>,
     pub preview_url: Option<String>,
     /* pub track_number: usize,
      * pub uri: String, */
+    pub external_ids: Option<ExternalIds>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema, SimpleObject)]
+pub(crate) struct ExternalIds {
+    /// International Standard Recording Code, which is shared by every release of the same
+    /// recording
+    pub isrc: Option<String>,
+}
+
+impl Track {
+    /// Stand-in for a track that is no longer available from Spotify
+    pub(crate) fn placeholder(spotify_id: &str) -> Self {
+        Track {
+            album: Album {
+                artists: Vec::new(),
+                id: String::new(),
+                images: Vec::new(),
+                name: "Unavailable Album".into(),
+            },
+            artists: Vec::new(),
+            duration_ms: None,
+            id: spotify_id.to_owned(),
+            name: "Unavailable Track".into(),
+            popularity: None,
+            preview_url: None,
+            external_ids: None,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub(crate) struct PlayHistoryItem {
+    pub track: Track,
+    pub played_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub(crate) struct RecentlyPlayedResponse {
+    pub items: Vec<PlayHistoryItem>,
+    pub next: Option<String>,
+}
+
+/// Response of Spotify's playback state endpoint.  `item` is `None` if the user is listening to
+/// something other than a track, such as a podcast episode.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct PlaybackState {
+    pub is_playing: bool,
+    pub progress_ms: Option<usize>,
+    pub item: Option<Track>,
+}
+
+/// How a user's top artists or tracks for one timeframe would change if a dry-run update were
+/// stored
+#[derive(Serialize, Debug, PartialEq, JsonSchema)]
+pub(crate) struct DryRunTimeframeDiff {
+    pub timeframe: Timeframe,
+    /// Number of entities fetched from Spotify, which is the number of rows stored in full
+    pub fetched_count: usize,
+    /// Number of rankings that differ from the latest snapshot, which is the number of rows stored
+    /// when delta encoding is enabled
+    pub changed_count: usize,
+    /// Spotify IDs of fetched entities that aren't in the latest snapshot
+    pub added: Vec<String>,
+    /// Spotify IDs of entities in the latest snapshot that weren't fetched
+    pub removed: Vec<String>,
+}
+
+/// What updating a user would store, produced without writing anything to the database
+#[derive(Serialize, Debug, JsonSchema)]
+pub(crate) struct UpdateDryRunReport {
+    pub spotify_id: String,
+    /// Time of the snapshot that the diffs are relative to, if the user has one
+    pub previous_update_time: Option<NaiveDateTime>,
+    pub artists: Vec<DryRunTimeframeDiff>,
+    pub tracks: Vec<DryRunTimeframeDiff>,
+    /// Number of plays since the most recently stored one, if recently played tracks are collected
+    pub new_recently_played_count: Option<usize>,
+    /// Number of artists the user follows, if follows are tracked
+    pub followed_artist_count: Option<usize>,
+    /// Number of tracks in the user's library, if libraries are snapshotted
+    pub saved_track_count: Option<u32>,
+    /// Errors fetching optional data, which don't fail real updates either
+    pub warnings: Vec<String>,
+}
+
+/// Data of the `snapshot_stored` events streamed by `/events/<username>`
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct SnapshotStoredEvent {
+    pub update_time: LocalDateTime,
+}
+
+/// What a user is currently listening to
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct NowPlaying {
+    /// `None` if nothing is playing or the user is listening to something other than a track
+    pub track: Option<Track>,
+    /// `false` if playback is paused
+    pub is_playing: bool,
+    /// Playback position in the track as of `fetched_at`, in milliseconds
+    pub progress_ms: Option<usize>,
+    /// When the playback state was fetched from Spotify.  It's cached for a few seconds.
+    pub fetched_at: LocalDateTime,
+    /// `true` if the user authorized before now playing was enabled and has to sign in again to
+    /// grant access to their playback state
+    pub reauthorize_required: bool,
+}
+
+pub(crate) struct NewRecentlyPlayedEntry {
+    pub user_id: i64,
+    pub mapped_spotify_id: i32,
+    pub played_at: NaiveDateTime,
+    pub duration_ms: u32,
+}
+
+impl_insertable!(NewRecentlyPlayedEntry => recently_played {
+    user_id: i64,
+    mapped_spotify_id: i32,
+    played_at: NaiveDateTime,
+    duration_ms: u32,
+});
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct RecentlyPlayedItem {
+    pub track: Track,
+    pub played_at: NaiveDateTime,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub(crate) struct FollowedArtistsPage {
+    pub items: Vec<Artist>,
+    pub next: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub(crate) struct FollowedArtistsResponse {
+    pub artists: FollowedArtistsPage,
+}
+
+#[derive(Insertable)]
+#[table_name = "followed_artists"]
+pub(crate) struct NewFollowedArtistEntry {
+    pub user_id: i64,
+    pub mapped_spotify_id: i32,
+    pub followed_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FollowEventKind {
+    Follow,
+    Unfollow,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct FollowEvent {
+    pub artist: Artist,
+    pub kind: FollowEventKind,
+    /// The time of the first update at which the follow or unfollow was observed
+    pub timestamp: NaiveDateTime,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct FollowHistory {
+    /// Sorted from most to least recent
+    pub events: Vec<FollowEvent>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub(crate) struct SavedTrackItem {
+    pub added_at: DateTime<Utc>,
+    pub track: Track,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub(crate) struct SavedTracksResponse {
+    /// Sorted from most to least recently saved
+    pub items: Vec<SavedTrackItem>,
+    /// Total number of tracks in the user's library
+    pub total: u32,
+}
+
+pub(crate) struct NewLibrarySnapshotEntry {
+    pub user_id: i64,
+    pub update_time: NaiveDateTime,
+    pub saved_track_count: u32,
+}
+
+impl_insertable!(NewLibrarySnapshotEntry => library_snapshots {
+    user_id: i64,
+    update_time: NaiveDateTime,
+    saved_track_count: u32,
+});
+
+#[derive(Insertable)]
+#[table_name = "saved_tracks"]
+pub(crate) struct NewSavedTrackEntry {
+    pub user_id: i64,
+    pub mapped_spotify_id: i32,
+    pub added_at: NaiveDateTime,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct LibrarySize {
+    pub update_time: LocalDateTime,
+    pub saved_track_count: u32,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct SavedTrack {
+    pub track: Track,
+    pub added_at: LocalDateTime,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct LibraryHistory {
+    /// Size of the user's saved tracks library at each update, oldest first
+    pub history: Vec<LibrarySize>,
+    /// Sorted from most to least recently saved
+    pub newest_saves: Vec<SavedTrack>,
+}
+
+/// The subset of a user's row that is included in data exports; tokens are excluded.
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct ExportedUser {
+    pub spotify_id: String,
+    pub username: String,
+    pub creation_time: NaiveDateTime,
+    pub last_update_time: NaiveDateTime,
+    pub last_viewed: NaiveDateTime,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct ExportedRankHistoryEntry {
+    pub update_time: NaiveDateTime,
+    pub timeframe: u8,
+    pub ranking: u8,
+    pub spotify_id: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct ExportedPlay {
+    pub spotify_id: String,
+    pub played_at: NaiveDateTime,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct ExportedFollow {
+    pub spotify_id: String,
+    pub followed_at: NaiveDateTime,
+    pub unfollowed_at: Option<NaiveDateTime>,
+}
+
+/// Everything stored for a user along with metadata for every artist and track referenced.
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct UserDataExport {
+    pub exported_at: NaiveDateTime,
+    pub user: ExportedUser,
+    pub artist_rank_history: Vec<ExportedRankHistoryEntry>,
+    pub track_rank_history: Vec<ExportedRankHistoryEntry>,
+    pub recently_played: Vec<ExportedPlay>,
+    pub followed_artists: Vec<ExportedFollow>,
+    pub artists_by_id: HashMap<String, Artist>,
+    pub tracks_by_id: HashMap<String, Track>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct PrivacySettingsRequest {
+    pub is_private: bool,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct DeactivationRequest {
+    pub deactivated: bool,
+}
+
+/// How often the update scheduler updates a user.  Users are never updated more often than their
+/// update tier or per-user override allows.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum UpdateFrequency {
+    Default,
+    Daily,
+    Weekly,
+}
+
+impl UpdateFrequency {
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            UpdateFrequency::Default => 0,
+            UpdateFrequency::Daily => 1,
+            UpdateFrequency::Weekly => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Self {
+        match id {
+            1 => UpdateFrequency::Daily,
+            2 => UpdateFrequency::Weekly,
+            _ => UpdateFrequency::Default,
+        }
+    }
+}
+
+/// Priority of a user's automatic updates, based on how recently their profile was viewed.  Users
+/// in higher tiers are updated more often and are first in the update queue.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum UpdateTier {
+    Active,
+    Normal,
+    Dormant,
+}
+
+/// Shortest per-user update interval override that can be set, so that overridden users can't be
+/// updated on every scheduler pass
+pub(crate) const MIN_UPDATE_INTERVAL_OVERRIDE_SECONDS: u32 = 15 * 60;
+
+/// Time to wait before retrying the update of a user whose last update attempt failed, which
+/// doubles with each consecutive failure up to a day
+pub(crate) fn update_retry_delay(failed_attempts: i32) -> chrono::Duration {
+    let base_delay_minutes: i64 = 5;
+    let max_delay_minutes: i64 = 24 * 60;
+    let exponent = (failed_attempts - 1).clamp(0, 16) as u32;
+    chrono::Duration::minutes((base_delay_minutes << exponent).min(max_delay_minutes))
+}
+
+impl UpdateTier {
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            UpdateTier::Active => 0,
+            UpdateTier::Normal => 1,
+            UpdateTier::Dormant => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Self {
+        match id {
+            0 => UpdateTier::Active,
+            2 => UpdateTier::Dormant,
+            _ => UpdateTier::Normal,
+        }
+    }
+
+    /// Minimum time between automatic updates of users in this tier that don't have an override
+    pub(crate) fn update_interval(self) -> chrono::Duration {
+        match self {
+            UpdateTier::Active => CONF
+                .active_tier_update_interval
+                .unwrap_or(CONF.min_update_interval),
+            UpdateTier::Normal => CONF.normal_tier_update_interval,
+            UpdateTier::Dormant => CONF.dormant_tier_update_interval,
+        }
+    }
+
+    /// Minimum time between automatic updates of a user in this tier, given the user's override.
+    /// Overrides are never shorter than `MIN_UPDATE_INTERVAL_OVERRIDE_SECONDS`.
+    pub(crate) fn user_update_interval(self, override_seconds: Option<i32>) -> chrono::Duration {
+        match override_seconds {
+            Some(seconds) => chrono::Duration::seconds(
+                i64::from(seconds).max(MIN_UPDATE_INTERVAL_OVERRIDE_SECONDS.into()),
+            ),
+            None => self.update_interval(),
+        }
+    }
+}
+
+#[derive(Queryable, Clone, Debug)]
+pub(crate) struct UserSettingsEntry {
+    pub user_id: i64,
+    /// Shown in place of the user's Spotify display name if set
+    pub display_name: Option<String>,
+    /// Timeframe used by routes that accept a `timeframe` when one isn't provided
+    pub default_timeframe: Timeframe,
+    pub update_frequency: u8,
+    /// Number of top artists and tracks fetched per timeframe.  `CONF.entity_fetch_count` is used
+    /// if unset.
+    pub entity_fetch_count: Option<u8>,
+    /// If set, the user is listed on the leaderboards of the artists in their top artists
+    pub leaderboard_opt_in: bool,
+    /// If set, the user is semi-private: their stats are public, but only their friends can
+    /// compare their stats with the user's
+    pub friends_only_comparisons: bool,
+    /// If set, what the user is currently listening to can be viewed by anyone with access to
+    /// their stats rather than only by the user
+    pub now_playing_public: bool,
+    /// If set, the user's saved tracks library can be viewed by anyone with access to their stats
+    /// rather than only by the user
+    pub library_public: bool,
+}
+
+impl_insertable!(UserSettingsEntry => user_settings {
+    user_id: i64,
+    display_name: Option<String>,
+    default_timeframe: Timeframe,
+    update_frequency: u8,
+    entity_fetch_count: Option<u8>,
+    leaderboard_opt_in: bool,
+    friends_only_comparisons: bool,
+    now_playing_public: bool,
+    library_public: bool,
+});
+
+impl UserSettingsEntry {
+    /// Settings used for users that haven't changed any
+    pub(crate) fn defaults(user_id: i64) -> Self {
+        UserSettingsEntry {
+            user_id,
+            display_name: None,
+            default_timeframe: Timeframe::Short,
+            update_frequency: UpdateFrequency::Default.id(),
+            entity_fetch_count: None,
+            leaderboard_opt_in: false,
+            friends_only_comparisons: false,
+            now_playing_public: false,
+            library_public: false,
+        }
+    }
+
+    pub(crate) fn entity_fetch_count(&self) -> usize {
+        self.entity_fetch_count
+            .map(usize::from)
+            .unwrap_or(CONF.entity_fetch_count)
+    }
+
+    pub(crate) fn display_name<'a>(&'a self, user: &'a User) -> &'a str {
+        self.display_name.as_deref().unwrap_or(&user.username)
+    }
+}
+
+/// Fields that aren't provided are left unchanged
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct UserSettingsRequest {
+    /// IANA timezone name such as `America/Chicago`
+    pub timezone: Option<String>,
+    /// Between 3 and 32 lowercase letters, digits, `-`, or `_`.  An empty string clears the slug.
+    pub vanity_slug: Option<String>,
+    /// An empty string clears the override
+    pub display_name: Option<String>,
+    pub is_private: Option<bool>,
+    pub default_timeframe: Option<Timeframe>,
+    pub update_frequency: Option<UpdateFrequency>,
+    /// Number of top artists and tracks stored per timeframe on each update, from 1 to 99.  0
+    /// resets it to the server's default.
+    pub entity_fetch_count: Option<u8>,
+    pub leaderboard_opt_in: Option<bool>,
+    pub friends_only_comparisons: Option<bool>,
+    pub now_playing_public: Option<bool>,
+    pub library_public: Option<bool>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct UserSettings {
+    /// IANA name of the timezone that timestamps in the user's stats are returned in
+    pub timezone: String,
+    /// Can be used in place of the Spotify ID in stats URLs
+    pub vanity_slug: Option<String>,
+    pub display_name: String,
+    pub is_private: bool,
+    /// Must be supplied via the `X-Private-Token` header to view the user's stats while they're
+    /// private
+    pub private_token: Option<String>,
+    pub default_timeframe: Timeframe,
+    pub update_frequency: UpdateFrequency,
+    /// Number of top artists and tracks stored per timeframe on each update
+    pub entity_fetch_count: usize,
+    /// Whether the user is listed on artist leaderboards.  Private users are never listed.
+    pub leaderboard_opt_in: bool,
+    /// Whether only the user's friends can compare their stats with the user's
+    pub friends_only_comparisons: bool,
+    /// Whether anyone who can view the user's stats can see what they're currently listening to
+    pub now_playing_public: bool,
+    /// Whether anyone who can view the user's stats can see their saved tracks library
+    pub library_public: bool,
+    /// Total number of times the user's stats profile has been viewed
+    pub profile_view_count: u64,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct PrivacySettings {
+    pub is_private: bool,
+    /// Must be supplied via the `X-Private-Token` header to view the user's stats while they're
+    /// private
+    pub private_token: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct DeactivationStatus {
+    /// When the user was deactivated; `None` if they're active
+    pub deactivated_at: Option<LocalDateTime>,
+}
+
+#[derive(Queryable, Clone, Debug)]
+pub(crate) struct EmailSubscription {
+    pub email: String,
+    /// Included in the unsubscribe link of each digest so that users can unsubscribe without
+    /// signing in
+    pub unsubscribe_token: String,
+    /// Included in the link emailed to the address when subscribing.  Cleared once the address is
+    /// confirmed.
+    pub confirmation_token: Option<String>,
+    /// Digests are only sent once the address has been confirmed
+    pub confirmed_at: Option<NaiveDateTime>,
+    pub last_sent_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "email_subscriptions"]
+pub(crate) struct NewEmailSubscription {
+    pub user_id: i64,
+    pub email: String,
+    pub unsubscribe_token: String,
+    pub created_at: NaiveDateTime,
+    pub confirmation_token: String,
+}
+
+/// A side effect of storing a stats snapshot waiting to be delivered.  See `crate::outbox`.
+#[derive(Queryable, Clone, Debug)]
+pub(crate) struct OutboxEventRow {
+    pub id: i64,
+    pub user_id: i64,
+    pub event_type: String,
+    /// JSON-serialized `crate::outbox::OutboxEvent`
+    pub payload: String,
+    /// Number of attempts to deliver the event so far
+    pub attempt_count: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "outbox_events"]
+pub(crate) struct NewOutboxEvent {
+    pub user_id: i64,
+    pub event_type: String,
+    pub payload: String,
+    pub created_at: NaiveDateTime,
+    pub next_attempt_at: NaiveDateTime,
+}
+
+/// Parts of the API that an API key grants access to.  See `crate::api_keys`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ApiKeyScope {
+    /// Read-only access to users' stats
+    Stats,
+    /// Comparing the stats of two users
+    Comparisons,
+}
+
+impl ApiKeyScope {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            ApiKeyScope::Stats => "stats",
+            ApiKeyScope::Comparisons => "comparisons",
+        }
+    }
+}
+
+impl FromStr for ApiKeyScope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stats" => Ok(ApiKeyScope::Stats),
+            "comparisons" => Ok(ApiKeyScope::Comparisons),
+            _ => Err(()),
+        }
+    }
+}
+
+/// An API key as stored, other than the hash of the key.  Selected with
+/// `db_util::API_KEY_COLUMNS`.
+#[derive(Queryable, Clone, Debug)]
+pub(crate) struct ApiKeyRow {
+    pub id: i64,
+    /// Name of the app or consumer that the key was minted for
+    pub name: String,
+    /// Comma-separated `ApiKeyScope` names
+    pub scopes: String,
+    pub rate_limit_per_minute: i32,
+    pub created_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+impl ApiKeyRow {
+    /// Unknown scopes are ignored
+    pub(crate) fn scopes(&self) -> Vec<ApiKeyScope> {
+        self.scopes
+            .split(',')
+            .filter_map(|scope| scope.parse().ok())
+            .collect()
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "api_keys"]
+pub(crate) struct NewApiKey {
+    pub name: String,
+    /// Hex-encoded SHA-256 hash of the key
+    pub key_hash: String,
+    pub scopes: String,
+    pub rate_limit_per_minute: i32,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct NewApiKeyRequest {
+    /// Name of the app or consumer that the key is for
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    /// Defaults to `API_KEY_DEFAULT_RATE_LIMIT_PER_MINUTE`
+    pub rate_limit_per_minute: Option<u32>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct ApiKeyInfo {
+    pub id: i64,
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    pub rate_limit_per_minute: i32,
+    pub created_at: NaiveDateTime,
+    /// When the key was revoked; `None` if it's still valid
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+impl From<ApiKeyRow> for ApiKeyInfo {
+    fn from(row: ApiKeyRow) -> Self {
+        ApiKeyInfo {
+            scopes: row.scopes(),
+            id: row.id,
+            name: row.name,
+            rate_limit_per_minute: row.rate_limit_per_minute,
+            created_at: row.created_at,
+            revoked_at: row.revoked_at,
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct MintedApiKey {
+    /// Supplied via an `Authorization: Bearer <key>` header.  This is the only time the key is
+    /// returned; only its hash is stored.
+    pub key: String,
+    #[serde(flatten)]
+    pub info: ApiKeyInfo,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct EmailSubscriptionRequest {
+    /// Address that the weekly digest is sent to
+    pub email: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct EmailSubscriptionStatus {
+    /// Address that the weekly digest is sent to; `None` if the user isn't subscribed
+    pub email: Option<String>,
+    /// Whether the address has been confirmed via the link emailed to it.  Digests aren't sent
+    /// until it has been.
+    pub confirmed: bool,
+    pub last_sent_at: Option<LocalDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "linked_accounts"]
+pub(crate) struct NewLinkedAccount {
+    pub primary_user_id: i64,
+    pub linked_user_id: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "friendships"]
+pub(crate) struct NewFriendship {
+    pub requester_id: i64,
+    pub addressee_id: i64,
+    pub requested_at: NaiveDateTime,
+}
+
+/// The other user in a friendship or pending friend request
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct Friend {
+    pub spotify_id: String,
+    /// Can be used in place of the Spotify ID in stats URLs
+    pub vanity_slug: Option<String>,
+    pub display_name: String,
+    /// When the request was accepted, or when it was sent if it's still pending
+    pub since: NaiveDateTime,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct FriendList {
+    pub friends: Vec<Friend>,
+    /// Requests sent to the user that they haven't accepted yet
+    pub incoming_requests: Vec<Friend>,
+    /// Requests the user sent that haven't been accepted yet
+    pub outgoing_requests: Vec<Friend>,
+}
+
+/// Highlights from a friend's most recent week of stats
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct FriendsFeedItem {
+    pub friend: Friend,
+    /// Update time of the friend's last snapshot of the week
+    pub update_time: LocalDateTime,
+    /// The friend's preferred timeframe, which the highlights are for
+    pub timeframe: Timeframe,
+    pub artist_highlights: Vec<String>,
+    pub track_highlights: Vec<String>,
+}
+
+/// Friends' highlights, most recently updated friend first.  Friends with no changes in their
+/// most recent week aren't included.
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct FriendsFeed {
+    pub items: Vec<FriendsFeedItem>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct LinkAccountRequest {
+    /// Spotify access token belonging to the account being linked, proving that the requester
+    /// controls it
+    pub access_token: String,
+}
+
+/// A Spotify account whose stats are aggregated into another user's profile
+#[derive(Serialize, JsonSchema, Queryable)]
+pub(crate) struct LinkedAccount {
+    pub spotify_id: String,
+    pub username: String,
+    pub linked_at: NaiveDateTime,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct LastfmImportRequest {
+    /// Name of the Last.fm user whose scrobbles are imported
+    pub lastfm_username: String,
+    /// Last.fm API key used to fetch the scrobbles
+    pub api_key: String,
+}
+
+/// Outcome of importing listening history from another service
+#[derive(Serialize, JsonSchema, Clone)]
+pub(crate) struct ImportSummary {
+    /// Number of plays found in the imported history
+    pub play_count: usize,
+    /// Number of distinct tracks searched for on Spotify
+    pub searched_track_count: usize,
+    pub matched_track_count: usize,
+    /// Number of plays of the tracks that were matched
+    pub matched_play_count: usize,
+    /// Number of plays stored.  Plays that were already stored for the user, including those
+    /// fetched from Spotify, aren't stored again.
+    pub imported_play_count: usize,
+    /// Number of monthly snapshots synthesized from plays that predate the user's first snapshot
+    pub synthetic_snapshot_count: usize,
+}
+
+/// Stage that an import of listening history running in the background has reached
+#[derive(Serialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ImportStage {
+    /// Fetching the plays from the other service
+    Fetching,
+    /// Searching for the played tracks on Spotify
+    Matching,
+    /// Storing the matched plays and any synthesized snapshots
+    Storing,
+    Finished,
+    Failed,
+}
+
+impl ImportStage {
+    pub(crate) fn is_done(self) -> bool {
+        matches!(self, ImportStage::Finished | ImportStage::Failed)
+    }
+}
+
+/// Progress of an import of listening history running in the background
+#[derive(Serialize, JsonSchema, Clone)]
+pub(crate) struct ImportProgress {
+    pub stage: ImportStage,
+    pub started_at: NaiveDateTime,
+    /// Number of plays fetched so far
+    pub play_count: usize,
+    /// Number of distinct tracks to search for on Spotify, known once the plays have been fetched
+    pub track_count: usize,
+    pub searched_track_count: usize,
+    /// Set once the import has finished
+    pub summary: Option<ImportSummary>,
+    /// Set if the import failed
+    pub error: Option<String>,
+}
+
+/// The number of rows that were deleted from each table when deleting a user
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct UserDeletionSummary {
+    pub spotify_id: String,
+    pub artist_rank_snapshots: usize,
+    pub track_rank_snapshots: usize,
+    pub artists_first_seen: usize,
+    pub tracks_first_seen: usize,
+    pub recently_played: usize,
+    pub followed_artists: usize,
+    pub saved_tracks: usize,
+    pub library_snapshots: usize,
+    pub email_subscriptions: usize,
+    pub update_errors: usize,
+    pub top_tracks_playlists: usize,
+    pub external_data_deleted: bool,
+}
+
+#[derive(Insertable)]
+#[table_name = "update_errors"]
+pub(crate) struct NewUpdateError {
+    pub user_id: i64,
+    pub error_time: NaiveDateTime,
+    pub error_message: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct UpdateError {
+    pub error_time: NaiveDateTime,
+    pub error_message: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct UpdateIntervalOverrideRequest {
+    /// Minimum time between automatic updates of the user, at least
+    /// `MIN_UPDATE_INTERVAL_OVERRIDE_SECONDS`.  The override is removed if unset, so that the
+    /// user's update tier decides again.
+    pub min_update_interval_seconds: Option<u32>,
+}
+
+/// Update health for a single user, as shown in the admin user list
+#[derive(Serialize)]
+pub(crate) struct AdminUserListItem {
+    pub spotify_id: String,
+    pub last_update_time: NaiveDateTime,
+    pub snapshot_count: i64,
+    pub consecutive_update_failures: i32,
+    /// The most recent error encountered while updating the user, if any
+    pub last_update_error: Option<UpdateError>,
+    /// Whether the most recent update attempt for the user failed
+    pub failing: bool,
+}
+
+/// Current state of the background user update scheduler
+#[derive(Clone, Default, Serialize)]
+pub(crate) struct SchedulerStatus {
+    pub enabled: bool,
+    pub concurrency: usize,
+    /// Number of users that are due for an update and haven't been processed yet
+    pub queue_depth: usize,
+    pub last_run_started_at: Option<NaiveDateTime>,
+    pub last_run_finished_at: Option<NaiveDateTime>,
+    pub last_run_success_count: usize,
+    pub last_run_failure_count: usize,
+    pub total_success_count: usize,
+    pub total_failure_count: usize,
+    pub last_charts_refresh_at: Option<NaiveDateTime>,
+    pub last_system_stats_refresh_at: Option<NaiveDateTime>,
+    pub last_metadata_refresh_at: Option<NaiveDateTime>,
+    pub last_update_tier_refresh_at: Option<NaiveDateTime>,
+    pub last_onboarding_poll_at: Option<NaiveDateTime>,
+}
+
+/// Current state of the Redis cache's circuit breaker
+#[derive(Serialize)]
+pub(crate) struct CacheStatus {
+    /// `false` while the circuit breaker is open and cache operations are being skipped
+    pub available: bool,
+    pub consecutive_failures: usize,
+    /// Time until cache operations will be attempted again if the circuit breaker is open
+    pub retry_in_seconds: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct HealthStatus {
+    pub status: &'static str,
+}
+
+/// Result of checking that one of the server's dependencies is usable
+#[derive(Serialize)]
+pub(crate) struct DependencyStatus {
+    pub ok: bool,
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ReadinessStatus {
+    /// `true` if all dependencies are usable
+    pub ready: bool,
+    pub database: DependencyStatus,
+    pub redis: DependencyStatus,
+    pub spotify_token: DependencyStatus,
+}
+
+#[derive(Insertable)]
+#[table_name = "system_stats"]
+pub(crate) struct NewSystemStats {
+    pub computed_at: NaiveDateTime,
+    pub user_count: i64,
+    pub snapshot_count: i64,
+    pub table_row_counts: String,
+    pub database_size_bytes: i64,
+    pub cache_size_bytes: Option<i64>,
+}
+
+#[derive(Queryable)]
+pub(crate) struct SystemStatsEntry {
+    pub computed_at: NaiveDateTime,
+    pub user_count: i64,
+    pub snapshot_count: i64,
+    pub table_row_counts: String,
+    pub database_size_bytes: i64,
+    pub cache_size_bytes: Option<i64>,
+}
+
+/// Totals computed periodically by the scheduler for capacity planning
+#[derive(Serialize)]
+pub(crate) struct SystemStats {
+    pub computed_at: NaiveDateTime,
+    pub user_count: i64,
+    /// Number of distinct stats snapshots stored across all users
+    pub snapshot_count: i64,
+    /// Approximate row count of each table, as estimated by MySQL
+    pub table_row_counts: std::collections::BTreeMap<String, i64>,
+    /// Size of all tables' data and indexes
+    pub database_size_bytes: i64,
+    /// Memory used by Redis; `None` if it couldn't be retrieved
+    pub cache_size_bytes: Option<i64>,
+}
+
+impl From<SystemStatsEntry> for SystemStats {
+    fn from(entry: SystemStatsEntry) -> Self {
+        SystemStats {
+            computed_at: entry.computed_at,
+            user_count: entry.user_count,
+            snapshot_count: entry.snapshot_count,
+            table_row_counts: serde_json::from_str(&entry.table_row_counts).unwrap_or_default(),
+            database_size_bytes: entry.database_size_bytes,
+            cache_size_bytes: entry.cache_size_bytes,
+        }
+    }
+}
+
+/// Public totals displayed on the about page
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct AboutStats {
+    pub user_count: i64,
+    pub snapshot_count: i64,
+    /// When the totals were last computed
+    pub computed_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub(crate) struct MostTrackedArtist {
+    pub artist: Artist,
+    /// Number of users that have the artist in their all-time top artists
+    pub user_count: i64,
+}
+
+/// Anonymized aggregates across all users of this instance.  Each field is `None` if the operator
+/// has chosen not to expose it, or if it hasn't been computed yet.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub(crate) struct GlobalSummary {
+    pub user_count: Option<i64>,
+    /// Number of stats snapshots stored across all users
+    pub snapshot_count: Option<i64>,
+    pub most_tracked_artist: Option<MostTrackedArtist>,
+    /// Median of each user's latest all-time genre diversity score.  See `DiversityScore`.
+    pub median_genre_diversity: Option<f32>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct ListeningTimePeriod {
+    pub start: NaiveDate,
+    pub total_minutes: f32,
+    /// `(track_spotify_id, minutes)`, sorted from most to least listened
+    pub tracks: Vec<(String, f32)>,
+    /// `(artist_spotify_id, minutes)`, sorted from most to least listened
+    pub artists: Vec<(String, f32)>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct ListeningTime {
+    pub granularity: crate::stats::ListeningTimeGranularity,
+    pub periods: Vec<ListeningTimePeriod>,
+    pub tracks_by_id: HashMap<String, Track>,
+    pub artists_by_id: HashMap<String, Artist>,
+}
+
+/// Summary of an artist's or track's appearances in a user's history
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct RankHistorySummary {
+    pub first_seen: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+    /// Number of snapshots the entity appeared in for any timeframe
+    pub snapshot_count: usize,
+    /// Best 1-indexed rank reached in each timeframe, indexed by timeframe ID
+    pub best_ranks: [Option<u8>; 3],
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct HistorySearchMatch<T> {
+    pub item: T,
+    pub history: RankHistorySummary,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct HistorySearchResults {
+    pub artists: Vec<HistorySearchMatch<Artist>>,
+    pub tracks: Vec<HistorySearchMatch<Track>>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct AggregatedRanking {
+    pub spotify_id: String,
+    /// Number of snapshots in the period that the entity appeared in
+    pub appearance_count: usize,
+    /// 1-indexed rank averaged over the snapshots the entity appeared in
+    pub average_rank: f32,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct AggregatedTimelinePeriod {
+    /// ISO-8601 week (`2024-W05`) or month (`2024-03`)
+    pub period: String,
+    pub snapshot_count: usize,
+    /// Sorted by appearance count and then by average rank
+    pub rankings: Vec<AggregatedRanking>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct AggregatedTimeline {
+    pub granularity: crate::stats::TimelineGranularity,
+    pub periods: Vec<AggregatedTimelinePeriod>,
+    pub tracks_by_id: HashMap<String, Track>,
+    pub artists_by_id: HashMap<String, Artist>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct ChartPoint {
+    pub snapshot_time: LocalDateTime,
+    /// 1-indexed, or `null` if the entity wasn't in the snapshot
+    pub rank: Option<u8>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct ChartSeries {
+    pub spotify_id: String,
+    /// Number of snapshots that the entity appeared in
+    pub appearance_count: usize,
+    /// One point for every snapshot of the timeframe, oldest first
+    pub points: Vec<ChartPoint>,
+}
+
+/// Rank history of a user's most frequent top artists or tracks, ready to be plotted
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct ChartData {
+    pub timeframe: Timeframe,
+    /// Sorted by appearance count and then by average rank
+    pub series: Vec<ChartSeries>,
+    pub tracks_by_id: HashMap<String, Track>,
+    pub artists_by_id: HashMap<String, Artist>,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -351,7 +1879,7 @@ pub(crate) struct TopArtistsResponse {
     pub items: Vec<Artist>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema, SimpleObject)]
 pub(crate) struct Artist {
     // pub followers: Option<Followers>,
     pub genres: Option<Vec<String>>,
@@ -363,6 +1891,19 @@ pub(crate) struct Artist {
     // pub uri: String,
 }
 
+impl Artist {
+    /// Stand-in for an artist that is no longer available from Spotify
+    pub(crate) fn placeholder(spotify_id: &str) -> Self {
+        Artist {
+            genres: None,
+            id: spotify_id.to_owned(),
+            images: None,
+            name: "Unavailable Artist".into(),
+            popularity: None,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub(crate) struct UserProfile {
     pub display_name: String,
@@ -381,7 +1922,8 @@ pub(crate) struct UserProfile {
 // }
 #[derive(Deserialize, Clone, Debug)]
 pub(crate) struct SpotifyBatchArtistsResponse {
-    pub artists: Vec<Artist>,
+    /// Entries are `null` for artists that have been removed from Spotify
+    pub artists: Vec<Option<Artist>>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -408,13 +1950,14 @@ pub(crate) enum SpotifyResponse<T: std::fmt::Debug + Clone> {
 }
 
 impl<T: for<'de> Deserialize<'de> + std::fmt::Debug + Clone> SpotifyResponse<T> {
-    pub fn into_result(self) -> Result<T, String> {
+    pub fn into_result(self) -> Result<T, Error> {
         match self {
             Self::Success(val) => Ok(val),
-            Self::Error(err) => Err(err
-                .error
-                .message
-                .unwrap_or_else(|| -> String { "No error message supplied".into() })),
+            Self::Error(err) => Err(Error::SpotifyApi(
+                err.error
+                    .message
+                    .unwrap_or_else(|| "No error message supplied".into()),
+            )),
         }
     }
 }
@@ -458,7 +2001,28 @@ impl<T: for<'de> Deserialize<'de> + std::fmt::Debug + Clone> std::ops::Try for S
 
 #[derive(Deserialize, Clone, Debug)]
 pub(crate) struct SpotifyBatchTracksResponse {
-    pub tracks: Vec<Track>,
+    /// Entries are `null` for tracks that have been removed from Spotify
+    pub tracks: Vec<Option<Track>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct TrackAudioFeatures {
+    pub id: String,
+    pub danceability: f32,
+    pub energy: f32,
+    pub valence: f32,
+    pub tempo: f32,
+    pub acousticness: f32,
+    pub instrumentalness: f32,
+    pub speechiness: f32,
+    pub liveness: f32,
+    pub loudness: f32,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct SpotifyBatchAudioFeaturesResponse {
+    /// Entries are `null` for tracks that Spotify has no audio features for
+    pub audio_features: Vec<Option<TrackAudioFeatures>>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -480,7 +2044,11 @@ impl HasSpotifyId for Track {
     fn get_spotify_id(&self) -> &str { &self.id }
 }
 
-#[derive(Serialize)]
+impl HasSpotifyId for TrackAudioFeatures {
+    fn get_spotify_id(&self) -> &str { &self.id }
+}
+
+#[derive(Serialize, JsonSchema)]
 #[serde(tag = "type")]
 pub(crate) enum TimelineEventType {
     #[serde(rename = "firstUpdate")]
@@ -491,7 +2059,7 @@ pub(crate) enum TimelineEventType {
     TopTrackFirstSeen { track: Track },
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 pub(crate) struct TimelineEvent {
     pub date: NaiveDate,
     pub id: usize,
@@ -499,18 +2067,49 @@ pub(crate) struct TimelineEvent {
     pub event_type: TimelineEventType,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 pub(crate) struct Timeline {
     pub events: Vec<TimelineEvent>,
 }
 
-#[derive(Serialize)]
-pub(crate) struct UserComparison {
+/// Percentage of top artists and tracks that two users have in common for a single timeframe
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct TimeframeOverlap {
+    pub artists: f32,
+    pub tracks: f32,
+}
+
+/// Current top artists and tracks of one user which have never appeared in the other user's top
+/// artists or tracks
+#[derive(Serialize, Default, JsonSchema)]
+pub(crate) struct UniqueFavorites {
+    pub artists: Vec<Artist>,
+    pub tracks: Vec<Track>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct ComparisonResult {
+    /// Tracks that have appeared in the top tracks of both users at any point
     pub tracks: Vec<Track>,
+    /// Artists that have appeared in the top artists of both users at any point
     pub artists: Vec<Artist>,
     pub genres: Vec<String>,
     pub user1_username: String,
     pub user2_username: String,
+    /// Overlap between the users' most recent top artists and tracks, keyed by timeframe
+    pub overlap_by_timeframe: HashMap<&'static str, TimeframeOverlap>,
+    pub user1_unique_favorites: UniqueFavorites,
+    pub user2_unique_favorites: UniqueFavorites,
+    /// Current mainstream scores of both users, keyed by timeframe
+    pub mainstream_by_timeframe: HashMap<&'static str, MainstreamComparison>,
+}
+
+/// Mainstream scores of two users for a single timeframe.  `null` for users without any popularity
+/// scores stored for the timeframe.
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct MainstreamComparison {
+    pub user1: Option<f32>,
+    pub user2: Option<f32>,
 }
 
 #[derive(Default, Debug, Clone, Deserialize)]
@@ -578,6 +2177,97 @@ pub(crate) struct UpdatePlaylistResponse {
     pub snapshot_id: String,
 }
 
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct RecommendationsResponse {
+    pub tracks: Vec<Track>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct Recommendations {
+    pub seed_artists: Vec<Artist>,
+    pub seed_tracks: Vec<Track>,
+    /// Recommended tracks that haven't appeared in the user's history
+    pub tracks: Vec<Track>,
+}
+
+/// A playlist generated from a user's top tracks
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct GeneratedPlaylist {
+    pub id: String,
+    pub uri: String,
+    pub track_count: usize,
+    /// `false` if an existing playlist was updated
+    pub created: bool,
+}
+
+pub(crate) struct NewGlobalChartEntry {
+    pub entity_type: u8,
+    pub timeframe: Timeframe,
+    pub ranking: u16,
+    pub mapped_spotify_id: i32,
+    pub user_count: i64,
+    pub score: i64,
+    pub computed_at: NaiveDateTime,
+}
+
+impl_insertable!(NewGlobalChartEntry => global_charts {
+    entity_type: u8,
+    timeframe: Timeframe,
+    ranking: u16,
+    mapped_spotify_id: i32,
+    user_count: i64,
+    score: i64,
+    computed_at: NaiveDateTime,
+});
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct ArtistLeaderboardEntry {
+    /// 1-indexed position on the leaderboard
+    pub position: usize,
+    pub spotify_id: String,
+    /// Can be used in place of the Spotify ID in stats URLs
+    pub vanity_slug: Option<String>,
+    pub display_name: String,
+    /// 1-indexed best ranking of the artist across the timeframes of the user's latest snapshot
+    pub best_ranking: u16,
+}
+
+/// Opted-in users that rank an artist highest, best ranking first
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct ArtistLeaderboard {
+    pub artist: Artist,
+    pub entries: Vec<ArtistLeaderboardEntry>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct GlobalChartEntry<T: Serialize> {
+    /// 1-indexed position in the chart
+    pub ranking: u16,
+    /// Number of users that have the entity in their top list for this timeframe
+    pub user_count: i64,
+    pub score: i64,
+    pub item: T,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct ArtistDiscovery {
+    pub artist: Artist,
+    /// Update time of the first snapshot the artist appeared in
+    pub first_seen: LocalDateTime,
+    /// Update time of the most recent snapshot the artist appeared in
+    pub last_seen: LocalDateTime,
+    /// Best 1-indexed rank the artist reached in any timeframe
+    pub peak_rank: u8,
+}
+
+/// Most popular artists or tracks across all users, as of the last time the charts were refreshed
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct GlobalChart<T: Serialize> {
+    /// `None` if the charts haven't been computed yet
+    pub computed_at: Option<NaiveDateTime>,
+    pub charts: TimeFrames<GlobalChartEntry<T>>,
+}
+
 #[derive(Deserialize)]
 pub(crate) struct CreateSharedPlaylistRequest {
     pub user1_id: String,
@@ -594,13 +2284,30 @@ pub(crate) struct GetRelatedArtistsResponse {
     pub artists: Vec<Artist>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct RelatedArtistsGraph {
     pub extra_artists: HashMap<String, Artist>,
     pub related_artists: HashMap<String, Vec<String>>,
 }
 
+/// Edge between two of a user's top artists, pointing from `source` to an artist that Spotify lists
+/// as related to it.  `mutual` is set if both artists list each other, in which case only one edge
+/// is included for the pair.
+#[derive(Serialize, Debug, PartialEq, JsonSchema)]
+pub(crate) struct ArtistGraphEdge {
+    pub source: String,
+    pub target: String,
+    pub mutual: bool,
+}
+
+/// Graph of a user's top artists across all timeframes and which of them are related to each other
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct ArtistGraph {
+    pub nodes: Vec<Artist>,
+    pub edges: Vec<ArtistGraphEdge>,
+}
+
 #[derive(Clone, Insertable)]
 #[table_name = "related_artists"]
 pub(crate) struct NewRelatedArtistEntry {
@@ -608,7 +2315,7 @@ pub(crate) struct NewRelatedArtistEntry {
     pub related_artists_json: String,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct ArtistSearchResult {
     #[serde(rename = "spotifyID")]
@@ -0,0 +1,107 @@
+//! Spotify OAuth scopes requested when users authorize the application.  Each optional feature
+//! declares the scopes it needs, and only the scopes of the features enabled with
+//! `SPOTIFY_FEATURES` are requested so that deployments don't ask users for access they never use.
+
+use crate::error::Error;
+
+/// Scopes needed to collect users' top artists and tracks, which are always requested
+const BASE_SCOPES: &[&str] = &["user-top-read"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SpotifyFeature {
+    /// Collecting users' recently played tracks
+    RecentlyPlayed,
+    /// Tracking which artists users follow
+    Follows,
+    /// Snapshotting users' saved tracks libraries
+    Library,
+    /// Generating playlists on users' accounts.  Its scopes are only requested from users that
+    /// opt in when authorizing.
+    Playlists,
+    /// Showing what users are currently listening to on their profiles
+    NowPlaying,
+}
+
+impl SpotifyFeature {
+    pub(crate) const ALL: [SpotifyFeature; 5] = [
+        SpotifyFeature::RecentlyPlayed,
+        SpotifyFeature::Follows,
+        SpotifyFeature::Library,
+        SpotifyFeature::Playlists,
+        SpotifyFeature::NowPlaying,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            SpotifyFeature::RecentlyPlayed => "recently_played",
+            SpotifyFeature::Follows => "follows",
+            SpotifyFeature::Library => "library",
+            SpotifyFeature::Playlists => "playlists",
+            SpotifyFeature::NowPlaying => "now_playing",
+        }
+    }
+
+    pub(crate) fn required_scopes(self) -> &'static [&'static str] {
+        match self {
+            SpotifyFeature::RecentlyPlayed => &["user-read-recently-played"],
+            SpotifyFeature::Follows => &["user-follow-read"],
+            SpotifyFeature::Library => &["user-library-read"],
+            SpotifyFeature::Playlists => &["playlist-modify-public", "playlist-modify-private"],
+            SpotifyFeature::NowPlaying => &["user-read-playback-state"],
+        }
+    }
+
+    fn is_opt_in(self) -> bool { self == SpotifyFeature::Playlists }
+}
+
+/// Parses a comma-separated list of feature names, as provided in `SPOTIFY_FEATURES`
+pub(crate) fn parse_features(features: &str) -> Result<Vec<SpotifyFeature>, Error> {
+    crate::conf::parse_name_list(
+        features,
+        &SpotifyFeature::ALL,
+        SpotifyFeature::name,
+        "feature",
+    )
+}
+
+/// Returns the scopes needed by `features`.  The scopes of opt-in features are only included if
+/// `include_opt_in` is set.
+pub(crate) fn build_scopes(features: &[SpotifyFeature], include_opt_in: bool) -> Vec<&'static str> {
+    let mut scopes: Vec<&'static str> = BASE_SCOPES.to_vec();
+    for feature in features {
+        if feature.is_opt_in() && !include_opt_in {
+            continue;
+        }
+        for scope in feature.required_scopes() {
+            if !scopes.contains(scope) {
+                scopes.push(scope);
+            }
+        }
+    }
+    scopes
+}
+
+#[test]
+fn scopes_are_built_from_enabled_features() {
+    let all_features =
+        parse_features("recently_played, follows,library,playlists,follows,now_playing").unwrap();
+    assert_eq!(all_features, SpotifyFeature::ALL);
+    assert_eq!(build_scopes(&all_features, false), vec![
+        "user-top-read",
+        "user-read-recently-played",
+        "user-follow-read",
+        "user-library-read",
+        "user-read-playback-state"
+    ]);
+    assert_eq!(build_scopes(&all_features, true).len(), 7);
+
+    let features = parse_features("playlists").unwrap();
+    assert_eq!(build_scopes(&features, false), vec!["user-top-read"]);
+    assert_eq!(build_scopes(&features, true), vec![
+        "user-top-read",
+        "playlist-modify-public",
+        "playlist-modify-private"
+    ]);
+    assert_eq!(parse_features("").unwrap(), Vec::new());
+    assert!(parse_features("playback").is_err());
+}
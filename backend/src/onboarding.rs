@@ -0,0 +1,176 @@
+//! Onboarding for newly signed-up users.  Signing up only creates a single stats snapshot, so new
+//! users' charts are mostly empty until they've been updated for a while.
+//!
+//! Signing up queues a capture of their recently played tracks, which the scheduler runs on its
+//! next pass so that the OAuth callback doesn't wait on it.  Captured plays are stored and used to
+//! synthesize a snapshot for each day they cover, so new users' charts start out with some
+//! history.  Their recently played tracks are then polled by the scheduler every
+//! `CONF.onboarding_poll_interval` for `CONF.onboarding_period` afterwards.  Spotify only returns
+//! the 50 most recent plays, so polling more often than users are updated keeps plays from being
+//! missed while their history is still sparse.  Once the period is over, recently played tracks are
+//! only fetched by regular updates.
+
+use std::sync::Mutex;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+
+use crate::{
+    conf::CONF,
+    db_util,
+    error::Error,
+    importers,
+    models::{PlayHistoryItem, User},
+    oauth_scopes::SpotifyFeature,
+    spotify_api,
+    stats::SyntheticSnapshotPeriod,
+    DbConn,
+};
+
+lazy_static::lazy_static! {
+    /// Spotify IDs of users that signed up since the scheduler's last pass and whose recently
+    /// played tracks haven't been captured yet
+    static ref PENDING_CAPTURES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+/// Returns the earliest signup time of users that are still onboarding, or `None` if onboarding is
+/// disabled
+fn onboarding_cutoff(now: NaiveDateTime, onboarding_period: Duration) -> Option<NaiveDateTime> {
+    if onboarding_period <= Duration::zero() {
+        return None;
+    }
+    Some(now - onboarding_period)
+}
+
+/// Fetches and stores the user's recently played tracks, refreshing their access token and retrying
+/// if Spotify rejects it.  Returns the number of plays stored.
+async fn poll_recently_played(conn: &DbConn, user: &mut User) -> Result<usize, Error> {
+    match spotify_api::update_recently_played(conn, user).await {
+        Err(Error::SpotifyUnauthorized) => {
+            db_util::refresh_user_access_token(conn, user).await?;
+
+            spotify_api::update_recently_played(conn, user).await
+        },
+        res => res,
+    }
+}
+
+/// Fetches all of the user's recently played tracks that Spotify returns, refreshing their access
+/// token and retrying if Spotify rejects it
+async fn fetch_recently_played(
+    conn: &DbConn,
+    user: &mut User,
+) -> Result<Vec<PlayHistoryItem>, Error> {
+    match spotify_api::fetch_recently_played(&user.token, None).await {
+        Err(Error::SpotifyUnauthorized) => {
+            db_util::refresh_user_access_token(conn, user).await?;
+
+            spotify_api::fetch_recently_played(&user.token, None).await
+        },
+        res => res,
+    }
+}
+
+/// Queues a capture of the recently played tracks of a user that just signed up, to be run by the
+/// scheduler on its next pass
+pub(crate) fn queue_initial_history_capture(user_spotify_id: String) {
+    if !CONF.is_feature_enabled(SpotifyFeature::RecentlyPlayed) {
+        return;
+    }
+
+    PENDING_CAPTURES.lock().unwrap().push(user_spotify_id);
+}
+
+/// Stores the recently played tracks of a user that just signed up and synthesizes a snapshot for
+/// each day they cover.  Returns the number of plays and snapshots stored.
+async fn capture_initial_history(conn: &DbConn, user: &mut User) -> Result<(usize, usize), Error> {
+    let items = fetch_recently_played(conn, user).await?;
+    let inserted_count = spotify_api::store_recently_played(conn, user, items.clone()).await?;
+    let plays = items
+        .iter()
+        .map(|item| (item.played_at.naive_utc(), &item.track))
+        .collect();
+    let snapshot_count =
+        importers::store_synthetic_snapshots(conn, user, plays, SyntheticSnapshotPeriod::Day)
+            .await?;
+    Ok((inserted_count, snapshot_count))
+}
+
+/// Captures the recently played tracks of every user queued by `queue_initial_history_capture`.
+/// Failures are logged rather than retried since onboarding polls will still pick up the users'
+/// plays.
+pub(crate) async fn capture_initial_histories(conn: &DbConn) {
+    let user_spotify_ids = std::mem::take(&mut *PENDING_CAPTURES.lock().unwrap());
+    for user_spotify_id in user_spotify_ids {
+        let mut user = match db_util::get_user_by_spotify_id(conn, user_spotify_id.clone()).await {
+            Ok(Some(user)) => user,
+            Ok(None) => continue,
+            Err(err) => {
+                warn!("Error loading new user {}: {}", user_spotify_id, err);
+                continue;
+            },
+        };
+
+        match capture_initial_history(conn, &mut user).await {
+            Ok((inserted_count, snapshot_count)) => info!(
+                "Captured {} recently played tracks and synthesized {} snapshot(s) for new user {}",
+                inserted_count, snapshot_count, user.spotify_id
+            ),
+            Err(err) => warn!(
+                "Error capturing recently played tracks for new user {}: {}",
+                user.spotify_id, err
+            ),
+        }
+    }
+}
+
+/// Polls the recently played tracks of every user that's still onboarding.  Returns the number of
+/// users polled successfully and the number whose polls failed.
+pub(crate) async fn poll_onboarding_users(conn: &DbConn) -> Result<(usize, usize), Error> {
+    if !CONF.is_feature_enabled(SpotifyFeature::RecentlyPlayed) {
+        return Ok((0, 0));
+    }
+    let cutoff = match onboarding_cutoff(Utc::now().naive_utc(), CONF.onboarding_period) {
+        Some(cutoff) => cutoff,
+        None => return Ok((0, 0)),
+    };
+
+    let users = db_util::get_users_created_after(conn, cutoff).await?;
+    let mut success_count = 0usize;
+    let mut failure_count = 0usize;
+    for mut user in users {
+        match poll_recently_played(conn, &mut user).await {
+            Ok(inserted_count) => {
+                if inserted_count > 0 {
+                    info!(
+                        "Stored {} new recently played tracks for onboarding user {}",
+                        inserted_count, user.spotify_id
+                    );
+                }
+                success_count += 1;
+            },
+            Err(err) => {
+                warn!(
+                    "Error polling recently played tracks for onboarding user {}: {}",
+                    user.spotify_id, err
+                );
+                failure_count += 1;
+            },
+        }
+    }
+    Ok((success_count, failure_count))
+}
+
+#[test]
+fn onboarding_cutoffs() {
+    let now = chrono::NaiveDate::from_ymd_opt(2026, 10, 16)
+        .unwrap()
+        .and_hms_opt(12, 0, 0)
+        .unwrap();
+    assert_eq!(
+        onboarding_cutoff(now, Duration::days(7)),
+        chrono::NaiveDate::from_ymd_opt(2026, 10, 9)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+    );
+    assert_eq!(onboarding_cutoff(now, Duration::zero()), None);
+}
@@ -0,0 +1,946 @@
+//! OpenAPI specification for the public API, served at `/openapi.json`.  Schemas for request and
+//! response bodies are generated from the models via `schemars`, but endpoints are listed here by
+//! hand and must be kept in sync with the routes.  Admin and internal endpoints aren't included.
+
+use chrono::NaiveDateTime;
+use schemars::{
+    gen::{SchemaGenerator, SchemaSettings},
+    JsonSchema,
+};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    conf::CONF,
+    models::{
+        AboutStats, AggregatedTimeline, Artist, ArtistDiscovery, ArtistGraph, ArtistLeaderboard,
+        ArtistSearchResult, AudioFeaturesProfile, ChartData, ComparisonResult, Crossover,
+        DeactivationRequest, DeactivationStatus, DiversityHistory, EmailSubscriptionRequest,
+        EmailSubscriptionStatus, FollowHistory, FriendList, FriendsFeed, GeneratedPlaylist,
+        GenreBreakdown, GenreTimeline, GlobalChart, GlobalSummary, HistorySearchResults,
+        ImportProgress, LastfmImportRequest, LibraryHistory, LinkAccountRequest, LinkedAccount,
+        ListeningTime, MainstreamHistory, NowPlaying, PopularityTrends, PrivacySettings,
+        PrivacySettingsRequest, RecentlyPlayedItem, Recommendations, RelatedArtistsGraph,
+        StatsSnapshot, Timeline, Track, UserDataExport, UserDeletionSummary, UserSettings,
+        UserSettingsRequest,
+    },
+    pagination::Paginated,
+    routes::{ArtistStats, GenreStats, GenresHistory},
+    session::SESSION_COOKIE_NAME,
+};
+
+#[derive(Clone, Copy)]
+enum Auth {
+    None,
+    /// The user's private token, needed to view the stats of users whose profiles are private
+    PrivateToken,
+    /// The user's session cookie or a Spotify access token belonging to the user
+    SpotifyToken,
+    /// Like `SpotifyToken`, but the endpoint is also available to anyone that can view the user's
+    /// stats if the user opted into making it public
+    SpotifyTokenOrOptIn,
+}
+
+#[derive(Clone, Copy)]
+enum Body {
+    Json(fn(&mut SchemaGenerator) -> Value),
+    Text(&'static str),
+}
+
+struct Param {
+    name: &'static str,
+    location: &'static str,
+    required: bool,
+    schema_type: &'static str,
+    description: &'static str,
+}
+
+const fn path_param(name: &'static str, description: &'static str) -> Param {
+    Param {
+        name,
+        location: "path",
+        required: true,
+        schema_type: "string",
+        description,
+    }
+}
+
+const fn query_param(
+    name: &'static str,
+    schema_type: &'static str,
+    description: &'static str,
+) -> Param {
+    Param {
+        name,
+        location: "query",
+        required: false,
+        schema_type,
+        description,
+    }
+}
+
+struct Endpoint {
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    params: &'static [Param],
+    auth: Auth,
+    request_body: Option<Body>,
+    response: Body,
+}
+
+fn schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Value {
+    serde_json::to_value(gen.subschema_for::<T>()).unwrap()
+}
+
+/// `/stats/<username>/timeline` is handled by two routes depending on the query params provided
+fn timeline_schema(gen: &mut SchemaGenerator) -> Value {
+    json!({ "oneOf": [schema::<Timeline>(gen), schema::<AggregatedTimeline>(gen)] })
+}
+
+const USERNAME: Param = path_param("username", "Spotify ID of the user");
+const STATS_USERNAME: Param = path_param("username", "Vanity slug or Spotify ID of the user");
+const FRIEND_USERNAME: Param = path_param(
+    "friend_username",
+    "Vanity slug or Spotify ID of the other user",
+);
+const CURSOR: Param = query_param(
+    "cursor",
+    "string",
+    "`next_cursor` of the previous page; omit to fetch the first page",
+);
+const CURSOR_LIMIT: Param = query_param("limit", "integer", "Max number of items per page");
+const SNAPSHOT_LIMIT: Param = query_param(
+    "limit",
+    "integer",
+    "Max number of artists and tracks to return per timeframe",
+);
+const SNAPSHOT_TIMEFRAMES: Param = query_param(
+    "timeframes",
+    "string",
+    "Comma-separated list of timeframes to include out of `short`, `medium`, and `long`",
+);
+
+const ENDPOINTS: &[Endpoint] = &[
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}",
+        summary: "Get the user's top artists and tracks from their most recent update",
+        params: &[
+            STATS_USERNAME,
+            SNAPSHOT_LIMIT,
+            SNAPSHOT_TIMEFRAMES,
+            query_param(
+                "linked",
+                "boolean",
+                "Merge in the stats of the accounts linked to the user",
+            ),
+        ],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<StatsSnapshot>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/snapshot/{timestamp}",
+        summary: "Get the user's top artists and tracks as of a past update",
+        params: &[
+            STATS_USERNAME,
+            path_param(
+                "timestamp",
+                "Update time of the snapshot as seconds since the Unix epoch, as RFC 3339 (as \
+                 listed by `/snapshots`), or as `YYYY-MM-DDTHH:MM:SS` in UTC",
+            ),
+            SNAPSHOT_LIMIT,
+            SNAPSHOT_TIMEFRAMES,
+        ],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<StatsSnapshot>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/snapshots",
+        summary: "List the update times of all of the user's snapshots, most recent first",
+        params: &[STATS_USERNAME, CURSOR, CURSOR_LIMIT],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<Paginated<NaiveDateTime>>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/artist/{artist_id}",
+        summary: "Get the user's ranking history for an artist and their tracks",
+        params: &[
+            STATS_USERNAME,
+            path_param("artist_id", "Spotify ID of the artist"),
+        ],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<ArtistStats>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/genre_history",
+        summary: "Get the popularity of the user's top genres over time",
+        params: &[STATS_USERNAME],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<GenresHistory>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/genre/{genre}",
+        summary: "Get the user's ranking history for the artists in a genre",
+        params: &[STATS_USERNAME, path_param("genre", "Name of the genre")],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<GenreStats>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/genres",
+        summary: "Get the breakdown of genres in the user's top artists",
+        params: &[STATS_USERNAME],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<GenreBreakdown>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/audio_features",
+        summary: "Get the average audio features of the user's top tracks",
+        params: &[STATS_USERNAME],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<AudioFeaturesProfile>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/diversity",
+        summary: "Get the genre entropy and artist concentration of each of the user's snapshots",
+        params: &[STATS_USERNAME],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<DiversityHistory>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/genres/timeline",
+        summary: "Get the weights of the user's top genres in each of their snapshots",
+        params: &[
+            STATS_USERNAME,
+            query_param(
+                "timeframe",
+                "string",
+                "`short`, `medium`, or `long`; defaults to the user's preferred timeframe",
+            ),
+            query_param(
+                "limit",
+                "integer",
+                "Max number of genres to return; defaults to 20",
+            ),
+        ],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<GenreTimeline>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/popularity_trends",
+        summary: "Get how popular the user's top artists and tracks were in each of their \
+                  snapshots",
+        params: &[STATS_USERNAME],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<PopularityTrends>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/mainstream",
+        summary: "Get how mainstream the user's top artists and tracks are, with history",
+        params: &[STATS_USERNAME],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<MainstreamHistory>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/crossover",
+        summary: "List favorites that have moved between timeframes in the user's latest snapshot",
+        params: &[STATS_USERNAME],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<Crossover>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/discoveries",
+        summary: "List when each artist first and last appeared in the user's top artists",
+        params: &[STATS_USERNAME, CURSOR, CURSOR_LIMIT],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<Paginated<ArtistDiscovery>>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/timeline",
+        summary: "Get artists and tracks discovered between two days, or rankings aggregated by \
+                  week or month if `granularity` is provided",
+        params: &[
+            STATS_USERNAME,
+            query_param(
+                "start_day_id",
+                "string",
+                "First day to include, as `YYYY-MM-DD`",
+            ),
+            query_param(
+                "end_day_id",
+                "string",
+                "Last day to include, as `YYYY-MM-DD`",
+            ),
+            query_param("granularity", "string", "`week` or `month`"),
+            query_param("entity", "string", "`artists` or `tracks`"),
+            query_param(
+                "timeframe",
+                "string",
+                "`short`, `medium`, or `long`; defaults to the user's preferred timeframe",
+            ),
+        ],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(timeline_schema),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/chart_data",
+        summary: "Get the rank of each of the user's most frequent top artists or tracks in every \
+                  one of their snapshots, ready to be plotted",
+        params: &[
+            STATS_USERNAME,
+            query_param(
+                "entity",
+                "string",
+                "`artists` or `tracks`; defaults to `artists`",
+            ),
+            query_param(
+                "top_n",
+                "integer",
+                "Number of artists or tracks to include, from 1 to 50; defaults to 10",
+            ),
+            query_param(
+                "timeframe",
+                "string",
+                "`short`, `medium`, or `long`; defaults to the user's preferred timeframe",
+            ),
+        ],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<ChartData>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/recently_played",
+        summary: "List the user's recently played tracks, most recent first",
+        params: &[STATS_USERNAME, CURSOR, CURSOR_LIMIT],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<Paginated<RecentlyPlayedItem>>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/listening_time",
+        summary: "Get the estimated time the user spent listening per period",
+        params: &[
+            STATS_USERNAME,
+            query_param("granularity", "string", "`day` or `week`"),
+        ],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<ListeningTime>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/follows",
+        summary: "Get the history of artists the user has followed and unfollowed",
+        params: &[STATS_USERNAME],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<FollowHistory>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/library",
+        summary: "Get the size of the user's saved tracks library over time and their newest \
+                  saves.  Only available to the user unless they've enabled `library_public` in \
+                  their settings.",
+        params: &[STATS_USERNAME],
+        auth: Auth::SpotifyTokenOrOptIn,
+        request_body: None,
+        response: Body::Json(schema::<LibraryHistory>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/export.csv",
+        summary: "Export the user's full ranking history as CSV",
+        params: &[
+            STATS_USERNAME,
+            query_param("entity", "string", "`artists` or `tracks`"),
+        ],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Text("text/csv"),
+    },
+    Endpoint {
+        method: "get",
+        path: "/share/{username}/card.png",
+        summary: "Render the user's top 5 artists and tracks as an image for sharing",
+        params: &[
+            STATS_USERNAME,
+            query_param(
+                "timeframe",
+                "string",
+                "`short`, `medium`, or `long`; defaults to the user's default timeframe",
+            ),
+        ],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Text("image/png"),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/feed.atom",
+        summary: "Atom feed summarizing how the user's top artists and tracks changed each week",
+        params: &[
+            STATS_USERNAME,
+            query_param(
+                "timeframe",
+                "string",
+                "`short`, `medium`, or `long`; defaults to the user's default timeframe",
+            ),
+        ],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Text("application/atom+xml"),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/search",
+        summary: "Search the artists and tracks in the user's history by name",
+        params: &[
+            STATS_USERNAME,
+            query_param("q", "string", "Text to search for; at least 2 characters"),
+            query_param(
+                "limit",
+                "integer",
+                "Max number of artists and of tracks to return",
+            ),
+        ],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<HistorySearchResults>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/recommendations",
+        summary: "Recommend tracks based on the user's top artists and tracks",
+        params: &[
+            STATS_USERNAME,
+            query_param("limit", "integer", "Max number of tracks to recommend"),
+        ],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<Recommendations>),
+    },
+    Endpoint {
+        method: "post",
+        path: "/stats/{username}/playlist",
+        summary: "Create or update a playlist of the user's top tracks in their Spotify account",
+        params: &[
+            STATS_USERNAME,
+            query_param(
+                "timeframe",
+                "string",
+                "`short`, `medium`, or `long`; defaults to the user's preferred timeframe",
+            ),
+        ],
+        auth: Auth::SpotifyToken,
+        request_body: None,
+        response: Body::Json(schema::<GeneratedPlaylist>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/events/{username}",
+        summary: "Stream a `snapshot_stored` Server-Sent Event whenever a new snapshot is stored \
+                  for the user, or a `resync` event if some may have been missed",
+        params: &[STATS_USERNAME],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Text("text/event-stream"),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/now_playing",
+        summary: "Get what the user is currently listening to.  Only available to the user unless \
+                  they've enabled `now_playing_public` in their settings.",
+        params: &[STATS_USERNAME],
+        auth: Auth::SpotifyTokenOrOptIn,
+        request_body: None,
+        response: Body::Json(schema::<NowPlaying>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{user_id}/related_artists_graph",
+        summary: "Get the graph of related artists for the user's top artists",
+        params: &[path_param("user_id", "Spotify ID of the user")],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<RelatedArtistsGraph>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/artist_graph",
+        summary: "Get the graph of the user's top artists and which of them are related to each \
+                  other",
+        params: &[STATS_USERNAME],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<ArtistGraph>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/export/{username}",
+        summary: "Export everything stored for the user as JSON",
+        params: &[STATS_USERNAME],
+        auth: Auth::SpotifyToken,
+        request_body: None,
+        response: Body::Json(schema::<UserDataExport>),
+    },
+    Endpoint {
+        method: "put",
+        path: "/users/{username}/privacy",
+        summary: "Make the user's stats private or public",
+        params: &[USERNAME],
+        auth: Auth::SpotifyToken,
+        request_body: Some(Body::Json(schema::<PrivacySettingsRequest>)),
+        response: Body::Json(schema::<PrivacySettings>),
+    },
+    Endpoint {
+        method: "put",
+        path: "/users/{username}/deactivation",
+        summary: "Deactivate or reactivate the user.  Deactivated users aren't updated and their \
+                  stats aren't served.",
+        params: &[USERNAME],
+        auth: Auth::SpotifyToken,
+        request_body: Some(Body::Json(schema::<DeactivationRequest>)),
+        response: Body::Json(schema::<DeactivationStatus>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/users/{username}/friends",
+        summary: "Get the user's friends and pending friend requests",
+        params: &[USERNAME],
+        auth: Auth::SpotifyToken,
+        request_body: None,
+        response: Body::Json(schema::<FriendList>),
+    },
+    Endpoint {
+        method: "post",
+        path: "/users/{username}/friend_requests/{friend_username}",
+        summary: "Send a friend request, or accept one the other user already sent",
+        params: &[USERNAME, FRIEND_USERNAME],
+        auth: Auth::SpotifyToken,
+        request_body: None,
+        response: Body::Json(schema::<FriendList>),
+    },
+    Endpoint {
+        method: "post",
+        path: "/users/{username}/friend_requests/{friend_username}/accept",
+        summary: "Accept a pending friend request sent to the user",
+        params: &[USERNAME, FRIEND_USERNAME],
+        auth: Auth::SpotifyToken,
+        request_body: None,
+        response: Body::Json(schema::<FriendList>),
+    },
+    Endpoint {
+        method: "delete",
+        path: "/users/{username}/friends/{friend_username}",
+        summary: "Remove a friend, or decline or cancel a pending friend request",
+        params: &[USERNAME, FRIEND_USERNAME],
+        auth: Auth::SpotifyToken,
+        request_body: None,
+        response: Body::Json(schema::<FriendList>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/{username}/friends_feed",
+        summary: "Get highlights from the most recent week of each of the user's friends' stats",
+        params: &[USERNAME],
+        auth: Auth::SpotifyToken,
+        request_body: None,
+        response: Body::Json(schema::<FriendsFeed>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/users/{username}/email_subscription",
+        summary: "Get the user's weekly digest email subscription",
+        params: &[USERNAME],
+        auth: Auth::SpotifyToken,
+        request_body: None,
+        response: Body::Json(schema::<EmailSubscriptionStatus>),
+    },
+    Endpoint {
+        method: "put",
+        path: "/users/{username}/email_subscription",
+        summary: "Subscribe the user to a weekly digest of their stats by email.  Digests are \
+                  only sent once the address is confirmed via the link emailed to it.",
+        params: &[USERNAME],
+        auth: Auth::SpotifyToken,
+        request_body: Some(Body::Json(schema::<EmailSubscriptionRequest>)),
+        response: Body::Json(schema::<EmailSubscriptionStatus>),
+    },
+    Endpoint {
+        method: "delete",
+        path: "/users/{username}/email_subscription",
+        summary: "Unsubscribe the user from the weekly digest",
+        params: &[USERNAME],
+        auth: Auth::SpotifyToken,
+        request_body: None,
+        response: Body::Json(schema::<EmailSubscriptionStatus>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/email/confirm",
+        summary: "Confirm a weekly digest subscription using the link emailed when subscribing",
+        params: &[query_param(
+            "token",
+            "string",
+            "Confirmation token from the confirmation email",
+        )],
+        auth: Auth::None,
+        request_body: None,
+        response: Body::Text("text/plain"),
+    },
+    Endpoint {
+        method: "get",
+        path: "/email/unsubscribe",
+        summary: "Unsubscribe from the weekly digest using the link included in each email",
+        params: &[query_param(
+            "token",
+            "string",
+            "Unsubscribe token from the digest email",
+        )],
+        auth: Auth::None,
+        request_body: None,
+        response: Body::Text("text/plain"),
+    },
+    Endpoint {
+        method: "get",
+        path: "/users/{username}/settings",
+        summary: "Get the user's settings",
+        params: &[USERNAME],
+        auth: Auth::SpotifyToken,
+        request_body: None,
+        response: Body::Json(schema::<UserSettings>),
+    },
+    Endpoint {
+        method: "patch",
+        path: "/users/{username}/settings",
+        summary: "Update the user's settings",
+        params: &[USERNAME],
+        auth: Auth::SpotifyToken,
+        request_body: Some(Body::Json(schema::<UserSettingsRequest>)),
+        response: Body::Json(schema::<UserSettings>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/users/{username}/linked_accounts",
+        summary: "List the Spotify accounts linked to the user",
+        params: &[USERNAME],
+        auth: Auth::SpotifyToken,
+        request_body: None,
+        response: Body::Json(schema::<Vec<LinkedAccount>>),
+    },
+    Endpoint {
+        method: "post",
+        path: "/users/{username}/linked_accounts",
+        summary: "Link another Spotify account to the user",
+        params: &[USERNAME],
+        auth: Auth::SpotifyToken,
+        request_body: Some(Body::Json(schema::<LinkAccountRequest>)),
+        response: Body::Json(schema::<Vec<LinkedAccount>>),
+    },
+    Endpoint {
+        method: "delete",
+        path: "/users/{username}/linked_accounts/{linked_username}",
+        summary: "Unlink an account from the user",
+        params: &[
+            USERNAME,
+            path_param("linked_username", "Spotify ID of the linked account"),
+        ],
+        auth: Auth::SpotifyToken,
+        request_body: None,
+        response: Body::Json(schema::<Vec<LinkedAccount>>),
+    },
+    Endpoint {
+        method: "post",
+        path: "/users/{username}/import/lastfm",
+        summary: "Start importing the user's listening history from Last.fm in the background.  A \
+                  CSV export can be uploaded with a `text/csv` body in place of the JSON body.",
+        params: &[USERNAME],
+        auth: Auth::SpotifyToken,
+        request_body: Some(Body::Json(schema::<LastfmImportRequest>)),
+        response: Body::Json(schema::<ImportProgress>),
+    },
+    Endpoint {
+        method: "post",
+        path: "/users/{username}/import/csv",
+        summary: "Start importing the user's listening history from a CSV file with a \
+                  `timestamp,artist,track` row for each play in the background",
+        params: &[USERNAME],
+        auth: Auth::SpotifyToken,
+        request_body: Some(Body::Text("text/csv")),
+        response: Body::Json(schema::<ImportProgress>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/users/{username}/import",
+        summary: "Get the progress of the user's most recent import of listening history",
+        params: &[USERNAME],
+        auth: Auth::SpotifyToken,
+        request_body: None,
+        response: Body::Json(schema::<ImportProgress>),
+    },
+    Endpoint {
+        method: "delete",
+        path: "/users/{username}",
+        summary: "Delete the user and everything stored for them",
+        params: &[USERNAME],
+        auth: Auth::SpotifyToken,
+        request_body: None,
+        response: Body::Json(schema::<UserDeletionSummary>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/compare/{user1}/{user2}",
+        summary: "Find the artists and tracks two users have in common.  Users that have made \
+                  comparisons friends-only can only be compared with by their friends.",
+        params: &[
+            path_param("user1", "Spotify ID of the first user"),
+            path_param("user2", "Spotify ID of the second user"),
+        ],
+        auth: Auth::PrivateToken,
+        request_body: None,
+        response: Body::Json(schema::<ComparisonResult>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/related_artists/{artist_id}",
+        summary: "Get the graph of artists related to an artist",
+        params: &[path_param("artist_id", "Spotify ID of the artist")],
+        auth: Auth::None,
+        request_body: None,
+        response: Body::Json(schema::<RelatedArtistsGraph>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/charts/top_artists",
+        summary: "Get the most popular artists across all users",
+        params: &[query_param(
+            "limit",
+            "integer",
+            "Max number of artists to return per timeframe",
+        )],
+        auth: Auth::None,
+        request_body: None,
+        response: Body::Json(schema::<GlobalChart<Artist>>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/charts/top_tracks",
+        summary: "Get the most popular tracks across all users",
+        params: &[query_param(
+            "limit",
+            "integer",
+            "Max number of tracks to return per timeframe",
+        )],
+        auth: Auth::None,
+        request_body: None,
+        response: Body::Json(schema::<GlobalChart<Track>>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/about/stats",
+        summary: "Get the number of users tracked and snapshots stored",
+        params: &[],
+        auth: Auth::None,
+        request_body: None,
+        response: Body::Json(schema::<AboutStats>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/leaderboard/artist/{artist_id}",
+        summary: "Get the opted-in users that rank an artist highest",
+        params: &[
+            path_param("artist_id", "Spotify ID of the artist"),
+            query_param(
+                "limit",
+                "integer",
+                "Max number of users to return, up to 100",
+            ),
+        ],
+        auth: Auth::None,
+        request_body: None,
+        response: Body::Json(schema::<ArtistLeaderboard>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/stats/global/summary",
+        summary: "Get anonymized aggregates across all users of this instance",
+        params: &[],
+        auth: Auth::None,
+        request_body: None,
+        response: Body::Json(schema::<GlobalSummary>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/display_name/{username}",
+        summary: "Get the user's display name",
+        params: &[STATS_USERNAME],
+        auth: Auth::None,
+        request_body: None,
+        response: Body::Text("text/plain"),
+    },
+    Endpoint {
+        method: "get",
+        path: "/search_artist",
+        summary: "Search for artists by name",
+        params: &[query_param("q", "string", "Search query")],
+        auth: Auth::None,
+        request_body: None,
+        response: Body::Json(schema::<Vec<ArtistSearchResult>>),
+    },
+];
+
+fn body_content(body: Body, gen: &mut SchemaGenerator) -> Value {
+    match body {
+        Body::Json(get_schema) => json!({ "application/json": { "schema": get_schema(gen) } }),
+        Body::Text(content_type) => json!({ content_type: { "schema": { "type": "string" } } }),
+    }
+}
+
+fn build_operation(endpoint: &Endpoint, gen: &mut SchemaGenerator) -> Value {
+    let parameters: Vec<Value> = endpoint
+        .params
+        .iter()
+        .map(|param| {
+            json!({
+                "name": param.name,
+                "in": param.location,
+                "required": param.required,
+                "description": param.description,
+                "schema": { "type": param.schema_type },
+            })
+        })
+        .collect();
+
+    let mut responses = json!({
+        "200": { "description": "Success", "content": body_content(endpoint.response, gen) },
+        "400": { "description": "Invalid parameters" },
+        "500": { "description": "Internal error" },
+    });
+    if endpoint.params.iter().any(|param| param.location == "path") {
+        responses["404"] = json!({ "description": "Not found" });
+    }
+
+    let mut operation = json!({
+        "summary": endpoint.summary,
+        "parameters": parameters,
+        "responses": responses,
+    });
+    if let Some(request_body) = endpoint.request_body {
+        operation["requestBody"] = json!({
+            "required": true,
+            "content": body_content(request_body, gen),
+        });
+    }
+    match endpoint.auth {
+        Auth::None => (),
+        Auth::PrivateToken => operation["security"] = json!([{}, { "privateToken": [] }]),
+        Auth::SpotifyToken => {
+            operation["security"] = json!([{ "session": [] }, { "spotifyToken": [] }]);
+            operation["responses"]["401"] =
+                json!({ "description": "Invalid session or access token" });
+            operation["responses"]["403"] =
+                json!({ "description": "Authenticated as a different user" });
+        },
+        Auth::SpotifyTokenOrOptIn =>
+            operation["security"] = json!([
+                { "session": [] },
+                { "spotifyToken": [] },
+                {},
+                { "privateToken": [] }
+            ]),
+    }
+    operation
+}
+
+pub(crate) fn build_spec(server_url: &str) -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+
+    let mut paths = Map::new();
+    for endpoint in ENDPOINTS {
+        let operation = build_operation(endpoint, &mut gen);
+        let path_item = paths
+            .entry(endpoint.path)
+            .or_insert_with(|| Value::Object(Map::new()));
+        path_item[endpoint.method] = operation;
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Spotifytrack API",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": server_url }],
+        "paths": paths,
+        "components": {
+            "schemas": gen.take_definitions(),
+            "securitySchemes": {
+                "privateToken": { "type": "apiKey", "in": "header", "name": "X-Private-Token" },
+                "spotifyToken": { "type": "http", "scheme": "bearer" },
+                "session": { "type": "apiKey", "in": "cookie", "name": SESSION_COOKIE_NAME },
+            },
+        },
+    })
+}
+
+lazy_static::lazy_static! {
+    pub(crate) static ref SPEC: String = build_spec(&CONF.api_server_url).to_string();
+}
+
+#[test]
+fn openapi_spec_path_params() {
+    let spec = build_spec("http://localhost");
+
+    for endpoint in ENDPOINTS {
+        let operation = &spec["paths"][endpoint.path][endpoint.method];
+        assert!(operation.is_object(), "missing {}", endpoint.path);
+
+        let template_params: Vec<&str> = endpoint
+            .path
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+            .collect();
+        let declared_params: Vec<&str> = endpoint
+            .params
+            .iter()
+            .filter(|param| param.location == "path")
+            .map(|param| param.name)
+            .collect();
+        assert_eq!(template_params, declared_params, "{}", endpoint.path);
+    }
+
+    let schemas = spec["components"]["schemas"].as_object().unwrap();
+    assert!(schemas.contains_key("StatsSnapshot"));
+}
@@ -0,0 +1,226 @@
+//! Transactional outbox for side effects of storing stats snapshots.
+//!
+//! Events are inserted into the `outbox_events` table in the same transaction as the snapshot they
+//! belong to, so they're recorded if and only if the snapshot is.  A background dispatcher then
+//! delivers them and marks them as delivered.  Deliveries that fail are retried with exponential
+//! backoff up to `OUTBOX_MAX_ATTEMPTS` times, and events are retried after crashes or restarts
+//! since they're only marked once delivered.  This makes delivery at-least-once, so handlers must
+//! be safe to run more than once for the same event.
+//!
+//! The dispatcher is woken up whenever a snapshot is stored and otherwise checks for due events
+//! every `OUTBOX_POLL_INTERVAL_SECONDS`.
+
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, Utc};
+use rocket::{Orbit, Rocket};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Notify, task::block_in_place};
+
+use crate::{
+    conf::CONF,
+    db_util,
+    error::Error,
+    metrics::{outbox_event_failures_total, outbox_events_delivered_total},
+    models::{NewOutboxEvent, OutboxEventRow},
+    DbConn,
+};
+
+/// Max number of events delivered in one pass of the dispatcher
+const DISPATCH_BATCH_SIZE: i64 = 100;
+/// Delay before the first retry of a failed delivery, doubled for every subsequent failure
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60 * 6);
+/// Delivered events are kept around for this long for debugging before being deleted
+const DELIVERED_EVENT_RETENTION: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OutboxEvent {
+    /// A stats snapshot taken at `update_time` was stored for the user
+    SnapshotStored { update_time: NaiveDateTime },
+}
+
+impl OutboxEvent {
+    pub(crate) fn event_type(&self) -> &'static str {
+        match self {
+            OutboxEvent::SnapshotStored { .. } => "snapshot_stored",
+        }
+    }
+
+    /// Builds the row to insert for this event, due to be delivered immediately
+    pub(crate) fn to_new_row(&self, user_id: i64, now: NaiveDateTime) -> NewOutboxEvent {
+        NewOutboxEvent {
+            user_id,
+            event_type: self.event_type().to_owned(),
+            payload: serde_json::to_string(self).expect("Failed to serialize outbox event"),
+            created_at: now,
+            next_attempt_at: now,
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref DISPATCH_NOTIFY: Notify = Notify::new();
+}
+
+/// Wakes up the dispatcher so that newly committed events are delivered right away
+pub(crate) fn notify_dispatcher() { DISPATCH_NOTIFY.notify_one(); }
+
+/// Returns how long to wait before attempting delivery again after the `attempt_count`th failed
+/// attempt
+pub(crate) fn retry_delay(attempt_count: i32) -> Duration {
+    let exponent = (attempt_count.max(1) - 1).min(16) as u32;
+    BASE_RETRY_DELAY
+        .saturating_mul(2u32.pow(exponent))
+        .min(MAX_RETRY_DELAY)
+}
+
+async fn deliver(user_id: i64, event: &OutboxEvent) -> Result<(), Error> {
+    match event {
+        OutboxEvent::SnapshotStored { update_time } => {
+            block_in_place(|| crate::cache::snapshot_cache::invalidate_cached_snapshots(user_id))
+                .map_err(|err| {
+                Error::Internal(format!(
+                    "Error invalidating cached stats snapshots: {}",
+                    err
+                ))
+            })?;
+            block_in_place(|| {
+                crate::cache::share_card_cache::invalidate_cached_share_cards(user_id)
+            })
+            .map_err(|err| {
+                Error::Internal(format!("Error invalidating cached share cards: {}", err))
+            })?;
+            // Open tabs refetch when notified, so this has to happen after invalidating the caches
+            crate::events::publish_snapshot_stored(user_id, *update_time);
+            Ok(())
+        },
+    }
+}
+
+async fn dispatch_event(conn: &DbConn, row: OutboxEventRow) {
+    let (event_type, res) = match serde_json::from_str::<OutboxEvent>(&row.payload) {
+        Ok(event) => (event.event_type(), deliver(row.user_id, &event).await),
+        Err(err) => (
+            "invalid",
+            Err(Error::Internal(format!(
+                "Invalid outbox event payload: {}",
+                err
+            ))),
+        ),
+    };
+
+    let now = Utc::now().naive_utc();
+    let update_res = match res {
+        Ok(()) => {
+            outbox_events_delivered_total(event_type).inc();
+            db_util::set_outbox_event_delivered(conn, row.id, now).await
+        },
+        Err(err) => {
+            outbox_event_failures_total(event_type).inc();
+            let attempt_count = row.attempt_count + 1;
+            if attempt_count >= CONF.outbox_max_attempts {
+                error!(
+                    "Giving up on delivering {} outbox event {} after {} attempts: {}",
+                    row.event_type, row.id, attempt_count, err
+                );
+            } else {
+                warn!(
+                    "Error delivering {} outbox event {}; retrying later: {}",
+                    row.event_type, row.id, err
+                );
+            }
+            let next_attempt_at = now
+                + chrono::Duration::from_std(retry_delay(attempt_count))
+                    .expect("Outbox retry delay out of range");
+            db_util::set_outbox_event_failed(conn, row.id, next_attempt_at, err.to_string()).await
+        },
+    };
+    if let Err(err) = update_res {
+        error!(
+            "Error recording delivery of outbox event {}: {}",
+            row.id, err
+        );
+    }
+}
+
+/// Delivers all events that are currently due.  Returns the number of events attempted.
+async fn dispatch_due_events(conn: &DbConn) -> usize {
+    let mut attempted_count = 0;
+    loop {
+        let now = Utc::now().naive_utc();
+        let due = match db_util::get_pending_outbox_events(
+            conn,
+            now,
+            CONF.outbox_max_attempts,
+            DISPATCH_BATCH_SIZE,
+        )
+        .await
+        {
+            Ok(due) => due,
+            Err(err) => {
+                error!("Error fetching pending outbox events: {}", err);
+                return attempted_count;
+            },
+        };
+
+        let batch_size = due.len();
+        for row in due {
+            dispatch_event(conn, row).await;
+        }
+        attempted_count += batch_size;
+        // Failed events are pushed back, so a full batch means there may be more due right now
+        if (batch_size as i64) < DISPATCH_BATCH_SIZE {
+            return attempted_count;
+        }
+    }
+}
+
+async fn run(conn: DbConn) {
+    loop {
+        let attempted_count = dispatch_due_events(&conn).await;
+        if attempted_count > 0 {
+            info!("Attempted delivery of {} outbox event(s)", attempted_count);
+        }
+
+        let cutoff =
+            Utc::now().naive_utc() - chrono::Duration::from_std(DELIVERED_EVENT_RETENTION).unwrap();
+        if let Err(err) = db_util::delete_delivered_outbox_events(&conn, cutoff).await {
+            error!("Error deleting delivered outbox events: {}", err);
+        }
+
+        let _ = tokio::time::timeout(CONF.outbox_poll_interval, DISPATCH_NOTIFY.notified()).await;
+    }
+}
+
+/// Checks out a database connection for the dispatcher and spawns it.  Called once Rocket has
+/// launched so that the database pool is available.
+pub(crate) async fn start(rocket: &Rocket<Orbit>) {
+    match DbConn::get_one(rocket).await {
+        Some(conn) => {
+            info!("Starting outbox dispatcher");
+            tokio::task::spawn(run(conn));
+        },
+        None => error!("Failed to check out database connection for outbox dispatcher"),
+    }
+}
+
+#[test]
+fn outbox_event_payloads_and_retries() {
+    let now = chrono::NaiveDate::from_ymd_opt(2026, 10, 16)
+        .unwrap()
+        .and_hms_opt(12, 0, 0)
+        .unwrap();
+    let event = OutboxEvent::SnapshotStored { update_time: now };
+    let row = event.to_new_row(1, now);
+    assert_eq!(row.event_type, "snapshot_stored");
+    assert_eq!(
+        serde_json::from_str::<OutboxEvent>(&row.payload).unwrap(),
+        event
+    );
+
+    assert_eq!(retry_delay(1), Duration::from_secs(30));
+    assert_eq!(retry_delay(3), Duration::from_secs(120));
+    assert_eq!(retry_delay(100), MAX_RETRY_DELAY);
+}
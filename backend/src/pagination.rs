@@ -0,0 +1,115 @@
+//! Cursor-based pagination for listing routes.  Unlike page numbers, cursors stay valid while new
+//! items are added in front of the ones already fetched, which history is constantly.
+//!
+//! A cursor is the sort key of the last item of a page, serialized as JSON and base64-encoded so
+//! that clients treat it as opaque.  Routes fetch one more item than the limit to find out whether
+//! there's a next page without counting everything.
+
+use base64::Engine;
+use schemars::JsonSchema;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::Error;
+
+pub(crate) const DEFAULT_PAGE_SIZE: u32 = 50;
+pub(crate) const MAX_PAGE_SIZE: u32 = 500;
+
+/// One page of results from a cursor-paginated endpoint
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct Paginated<T: Serialize> {
+    pub items: Vec<T>,
+    /// Supplied as `cursor` to fetch the next page; `None` if this is the last page
+    pub next_cursor: Option<String>,
+}
+
+impl<T: Serialize> Paginated<T> {
+    /// Converts the items of the page, keeping the cursor
+    pub(crate) fn map<U: Serialize>(self, f: impl FnMut(T) -> U) -> Paginated<U> {
+        Paginated {
+            items: self.items.into_iter().map(f).collect(),
+            next_cursor: self.next_cursor,
+        }
+    }
+}
+
+pub(crate) fn encode_cursor<K: Serialize>(key: &K) -> String {
+    let json = serde_json::to_vec(key).expect("Failed to serialize cursor");
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(json)
+}
+
+pub(crate) fn decode_cursor<K: DeserializeOwned>(cursor: &str) -> Result<K, Error> {
+    let invalid = || Error::BadRequest("Invalid `cursor`".into());
+    let json = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(cursor)
+        .map_err(|_| invalid())?;
+    serde_json::from_slice(&json).map_err(|_| invalid())
+}
+
+/// Cursor pagination parameters for list endpoints.  `after` is the sort key of the last item of
+/// the previous page, or `None` for the first page.
+#[derive(Clone, Debug)]
+pub(crate) struct CursorPagination<K> {
+    pub after: Option<K>,
+    pub limit: u32,
+}
+
+impl<K: DeserializeOwned> CursorPagination<K> {
+    pub(crate) fn new(cursor: Option<&str>, limit: Option<u32>) -> Result<Self, Error> {
+        Ok(CursorPagination {
+            after: cursor.map(decode_cursor).transpose()?,
+            limit: limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE),
+        })
+    }
+}
+
+impl<K: Serialize> CursorPagination<K> {
+    /// Number of items to fetch, which includes one extra to tell if there's a next page
+    pub(crate) fn fetch_limit(&self) -> i64 { self.limit as i64 + 1 }
+
+    /// Builds the page from up to `fetch_limit()` items, using `sort_key` to build the cursor of
+    /// the next page
+    pub(crate) fn into_page<T: Serialize>(
+        self,
+        mut items: Vec<T>,
+        sort_key: impl FnOnce(&T) -> K,
+    ) -> Paginated<T> {
+        let has_next_page = items.len() > self.limit as usize;
+        items.truncate(self.limit as usize);
+        let next_cursor = match items.last() {
+            Some(last) if has_next_page => Some(encode_cursor(&sort_key(last))),
+            _ => None,
+        };
+        Paginated { items, next_cursor }
+    }
+}
+
+#[test]
+fn cursor_pagination() {
+    use chrono::NaiveDateTime;
+
+    let time = |hour| {
+        chrono::NaiveDate::from_ymd_opt(2026, 10, 17)
+            .unwrap()
+            .and_hms_opt(hour, 0, 0)
+            .unwrap()
+    };
+    let pagination = CursorPagination::<NaiveDateTime>::new(None, Some(2)).unwrap();
+    assert_eq!(pagination.fetch_limit(), 3);
+    let page = pagination.into_page(vec![time(3), time(2), time(1)], |item| *item);
+    assert_eq!(page.items, vec![time(3), time(2)]);
+
+    let cursor = page.next_cursor.expect("Missing next cursor");
+    let pagination = CursorPagination::<NaiveDateTime>::new(Some(&cursor), Some(2)).unwrap();
+    assert_eq!(pagination.after, Some(time(2)));
+    let page = pagination.into_page(vec![time(1)], |item| *item);
+    assert_eq!(page.items, vec![time(1)]);
+    assert_eq!(page.next_cursor, None);
+
+    assert!(CursorPagination::<NaiveDateTime>::new(Some("not a cursor"), None).is_err());
+    assert_eq!(
+        CursorPagination::<NaiveDateTime>::new(None, Some(100_000))
+            .unwrap()
+            .limit,
+        MAX_PAGE_SIZE
+    );
+}
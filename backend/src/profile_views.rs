@@ -0,0 +1,186 @@
+//! Counts views of users' stats profiles.  Only the number of views of each profile per day is
+//! stored in `profile_views`; nothing about who viewed it is recorded.  Profiles that are viewed
+//! often are moved into the active update tier by the scheduler so that they're kept up to date
+//! while people are looking at them.
+//!
+//! Views are tallied in memory as responses are sent and written to the database in batches by
+//! the scheduler, so serving a profile never waits on the database.
+
+use std::{borrow::Cow, sync::Mutex};
+
+use chrono::{NaiveDateTime, Utc};
+use fnv::FnvHashMap as HashMap;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{Method, Status},
+    Request, Response,
+};
+
+use crate::{db_util, DbConn};
+
+/// Caps the number of distinct profiles that views are buffered for between flushes so that memory
+/// use stays bounded if flushes stop happening.  Views of other profiles are dropped until the next
+/// flush.
+const MAX_PENDING_PROFILES: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct PendingViews {
+    count: i32,
+    last_viewed: NaiveDateTime,
+}
+
+lazy_static::lazy_static! {
+    /// Views that haven't been written to the database yet, keyed by the username or vanity slug
+    /// the profile was requested by and the day it was viewed
+    static ref PENDING_VIEWS: Mutex<HashMap<(String, NaiveDateTime), PendingViews>> =
+        Mutex::new(HashMap::default());
+}
+
+fn buffer_view(
+    pending: &mut HashMap<(String, NaiveDateTime), PendingViews>,
+    username: String,
+    now: NaiveDateTime,
+) {
+    let key = (username, db_util::start_of_day(now));
+    if let Some(views) = pending.get_mut(&key) {
+        views.count += 1;
+        views.last_viewed = views.last_viewed.max(now);
+    } else if pending.len() < MAX_PENDING_PROFILES {
+        pending.insert(key, PendingViews {
+            count: 1,
+            last_viewed: now,
+        });
+    }
+}
+
+/// Writes all buffered profile views to the database.  Views of profiles that no longer exist are
+/// discarded, as are the buffered views if writing them fails.
+pub(crate) async fn flush_profile_views(conn: &DbConn) {
+    let pending = std::mem::take(&mut *PENDING_VIEWS.lock().unwrap());
+    if pending.is_empty() {
+        return;
+    }
+
+    let mut user_ids_by_username: HashMap<String, Option<i64>> = HashMap::default();
+    let mut views = Vec::with_capacity(pending.len());
+    for ((username, view_date), PendingViews { count, last_viewed }) in pending {
+        let user_id = match user_ids_by_username.get(&username) {
+            Some(user_id) => *user_id,
+            None => {
+                let user_id =
+                    match db_util::get_user_by_vanity_slug_or_spotify_id(conn, username.clone())
+                        .await
+                    {
+                        Ok(user) => user.map(|user| user.id),
+                        Err(err) => {
+                            error!("Error looking up user to record profile views: {:?}", err);
+                            continue;
+                        },
+                    };
+                user_ids_by_username.insert(username, user_id);
+                user_id
+            },
+        };
+        if let Some(user_id) = user_id {
+            views.push((user_id, view_date, count, last_viewed));
+        }
+    }
+
+    let view_count = views.len();
+    if let Err(err) = db_util::record_profile_views(conn, views).await {
+        error!(
+            "Error recording {} profile view count(s): {}",
+            view_count, err
+        );
+    }
+}
+
+/// Routes that count as a view of the profile of the user in their first path param
+const PROFILE_VIEW_ROUTES: &[&str] = &["get_current_stats"];
+
+fn is_profile_view(method: Method, route_name: Option<&str>, status: Status) -> bool {
+    let is_profile_route = route_name
+        .map(|route_name| PROFILE_VIEW_ROUTES.contains(&route_name))
+        .unwrap_or(false);
+    // Revalidated responses are still views
+    method == Method::Get
+        && is_profile_route
+        && (status.class().is_success() || status == Status::NotModified)
+}
+
+pub(crate) struct ProfileViewFairing;
+
+#[rocket::async_trait]
+impl Fairing for ProfileViewFairing {
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let route_name = req
+            .route()
+            .and_then(|route| route.name.as_ref())
+            .map(Cow::as_ref);
+        if !is_profile_view(req.method(), route_name, res.status()) {
+            return;
+        }
+        let username: String = match req.param(0) {
+            Some(Ok(username)) => username,
+            _ => return,
+        };
+
+        buffer_view(
+            &mut PENDING_VIEWS.lock().unwrap(),
+            username,
+            Utc::now().naive_utc(),
+        );
+    }
+
+    fn info(&self) -> Info {
+        Info {
+            name: "Profile View Fairing",
+            kind: Kind::Response,
+        }
+    }
+}
+
+#[test]
+fn profile_view_detection() {
+    let route = Some("get_current_stats");
+    assert!(is_profile_view(Method::Get, route, Status::Ok));
+    assert!(is_profile_view(Method::Get, route, Status::NotModified));
+    assert!(!is_profile_view(Method::Get, route, Status::NotFound));
+    assert!(!is_profile_view(Method::Head, route, Status::Ok));
+    assert!(!is_profile_view(
+        Method::Get,
+        Some("get_display_name"),
+        Status::Ok
+    ));
+    assert!(!is_profile_view(Method::Get, None, Status::Ok));
+}
+
+#[test]
+fn profile_view_buffering() {
+    let now = chrono::NaiveDate::from_ymd_opt(2026, 3, 14)
+        .unwrap()
+        .and_hms_opt(23, 59, 0)
+        .unwrap();
+    let later = now + chrono::Duration::minutes(30);
+    let mut pending = HashMap::default();
+    buffer_view(&mut pending, "ameo".to_string(), now);
+    buffer_view(&mut pending, "ameo".to_string(), now);
+    buffer_view(&mut pending, "ameo".to_string(), later);
+    buffer_view(&mut pending, "other".to_string(), now);
+
+    assert_eq!(pending.len(), 3);
+    assert_eq!(
+        pending[&("ameo".to_string(), db_util::start_of_day(now))],
+        PendingViews {
+            count: 2,
+            last_viewed: now,
+        }
+    );
+    assert_eq!(
+        pending[&("ameo".to_string(), db_util::start_of_day(later))],
+        PendingViews {
+            count: 1,
+            last_viewed: later,
+        }
+    );
+}
@@ -0,0 +1,77 @@
+//! Anonymized aggregates across all users of the instance, served publicly from
+//! `/stats/global/summary`.  Operators choose which of them are exposed with `PUBLIC_STATS_FIELDS`;
+//! the endpoint is disabled entirely if none are.
+
+use crate::error::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PublicStatsField {
+    /// Total number of users tracked
+    UserCount,
+    /// Total number of stats snapshots stored
+    SnapshotCount,
+    /// The artist in the most users' all-time top artists
+    MostTrackedArtist,
+    /// Median of each user's latest all-time genre diversity score
+    MedianDiversity,
+}
+
+impl PublicStatsField {
+    pub(crate) const ALL: [PublicStatsField; 4] = [
+        PublicStatsField::UserCount,
+        PublicStatsField::SnapshotCount,
+        PublicStatsField::MostTrackedArtist,
+        PublicStatsField::MedianDiversity,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            PublicStatsField::UserCount => "user_count",
+            PublicStatsField::SnapshotCount => "snapshot_count",
+            PublicStatsField::MostTrackedArtist => "most_tracked_artist",
+            PublicStatsField::MedianDiversity => "median_diversity",
+        }
+    }
+}
+
+/// Parses a comma-separated list of field names, as provided in `PUBLIC_STATS_FIELDS`
+pub(crate) fn parse_fields(fields: &str) -> Result<Vec<PublicStatsField>, Error> {
+    crate::conf::parse_name_list(
+        fields,
+        &PublicStatsField::ALL,
+        PublicStatsField::name,
+        "field",
+    )
+}
+
+/// Returns the median of `values`, or `None` if it's empty
+pub(crate) fn median(mut values: Vec<f32>) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let mid = values.len() / 2;
+    if values.len() % 2 == 0 {
+        Some((values[mid - 1] + values[mid]) / 2.)
+    } else {
+        Some(values[mid])
+    }
+}
+
+#[test]
+fn fields_and_median() {
+    assert_eq!(
+        parse_fields("user_count, median_diversity,user_count").unwrap(),
+        vec![
+            PublicStatsField::UserCount,
+            PublicStatsField::MedianDiversity
+        ]
+    );
+    assert_eq!(parse_fields("").unwrap(), Vec::new());
+    assert!(parse_fields("top_users").is_err());
+
+    assert_eq!(median(Vec::new()), None);
+    assert_eq!(median(vec![3., 1., 2.]), Some(2.));
+    assert_eq!(median(vec![4., 1., 3., 2.]), Some(2.5));
+}
@@ -0,0 +1,283 @@
+//! Token-bucket rate limiting for the public `/stats`, `/compare`, and `/graphql` routes.  Requests
+//! for entities that aren't cached have to be fetched from the Spotify API, so without limits a
+//! single client scraping many users could exhaust our Spotify API quota.
+//!
+//! Requests are limited both by client IP address and by each username in the path.  GraphQL
+//! queries don't have usernames in their paths, so they're only limited by IP address.  Requests
+//! made with an API key are instead limited by the key's own rate limit and must have the scope
+//! needed for the route; keys are rejected on routes that no scope covers.  See `crate::api_keys`.
+//! Rocket fairings can't respond to requests directly, so rejected requests are re-routed to a path
+//! with no matching route and their response is replaced with a 429 (or the API key error) once
+//! it's produced.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use fnv::FnvHashMap as HashMap;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{uri::Origin, Header, Method, Status},
+    outcome::Outcome,
+    Data, Request, Response,
+};
+
+use crate::{
+    api_keys::{get_supplied_api_key, ApiKey, ApiKeyError},
+    conf::CONF,
+    models::ApiKeyScope,
+};
+
+/// Once this many keys are being tracked, buckets that have fully refilled are dropped
+const MAX_TRACKED_KEYS: usize = 100_000;
+const RATE_LIMITED_PATH: &str = "/__rate_limited";
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+pub(crate) struct RateLimiter {
+    burst: f64,
+    tokens_per_second: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(burst: u32, per_minute: u32) -> Self {
+        RateLimiter {
+            burst: burst.max(1) as f64,
+            tokens_per_second: per_minute as f64 / 60.,
+            buckets: Mutex::new(HashMap::default()),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool { self.tokens_per_second > 0. }
+
+    /// Takes a token from the bucket for `key`.  If the bucket is empty, returns how long until a
+    /// token will be available.
+    pub(crate) fn take(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_KEYS {
+            let (burst, tokens_per_second) = (self.burst, self.tokens_per_second);
+            buckets.retain(|_, bucket| {
+                let elapsed = now.saturating_duration_since(bucket.last_refill);
+                bucket.tokens + elapsed.as_secs_f64() * tokens_per_second < burst
+            });
+        }
+
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: self.burst,
+            last_refill: now,
+        });
+        bucket.tokens = self.refilled_tokens(bucket, now);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            Ok(())
+        } else {
+            Err(self.time_until_token(bucket.tokens))
+        }
+    }
+
+    /// Checks whether a token could be taken from the bucket for `key` without taking it.  If the
+    /// bucket is empty, returns how long until a token will be available.
+    pub(crate) fn check(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let buckets = self.buckets.lock().unwrap();
+        let tokens = match buckets.get(key) {
+            Some(bucket) => self.refilled_tokens(bucket, now),
+            None => return Ok(()),
+        };
+        if tokens >= 1. {
+            Ok(())
+        } else {
+            Err(self.time_until_token(tokens))
+        }
+    }
+
+    fn refilled_tokens(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        (bucket.tokens + elapsed.as_secs_f64() * self.tokens_per_second).min(self.burst)
+    }
+
+    fn time_until_token(&self, tokens: f64) -> Duration {
+        Duration::from_secs_f64((1. - tokens) / self.tokens_per_second)
+    }
+}
+
+struct Rejection {
+    status: Status,
+    message: String,
+    /// How long the client should wait to retry if the request was rate limited
+    retry_after: Option<Duration>,
+}
+
+impl Rejection {
+    fn rate_limited(retry_after: Duration) -> Self {
+        Rejection {
+            status: Status::TooManyRequests,
+            message: "Too many requests; try again later".to_owned(),
+            retry_after: Some(retry_after),
+        }
+    }
+}
+
+impl From<ApiKeyError> for Rejection {
+    fn from(err: ApiKeyError) -> Self {
+        match err {
+            ApiKeyError::RateLimited(retry_after) => Rejection::rate_limited(retry_after),
+            err => Rejection {
+                status: err.status(),
+                message: err.message(),
+                retry_after: None,
+            },
+        }
+    }
+}
+
+/// Set on requests that were rejected along with why
+struct Rejected(Option<Rejection>);
+
+pub(crate) struct RateLimitFairing {
+    by_ip: RateLimiter,
+    by_username: RateLimiter,
+}
+
+impl RateLimitFairing {
+    pub(crate) fn from_conf() -> Self {
+        RateLimitFairing {
+            by_ip: RateLimiter::new(CONF.rate_limit_ip_burst, CONF.rate_limit_ip_per_minute),
+            by_username: RateLimiter::new(
+                CONF.rate_limit_username_burst,
+                CONF.rate_limit_username_per_minute,
+            ),
+        }
+    }
+
+    async fn check(&self, req: &Request<'_>, now: Instant) -> Result<(), Rejection> {
+        if req.method() == Method::Options {
+            return Ok(());
+        }
+        let path = req.uri().path();
+        let rate_limited_path = parse_rate_limited_path(path.as_str());
+
+        // Keys are checked on every route so that they're never silently ignored
+        if get_supplied_api_key(req).is_some() {
+            return match req.guard::<ApiKey>().await {
+                Outcome::Success(api_key) => {
+                    debug!(
+                        "Request to {} made with API key {} ({})",
+                        path, api_key.id, api_key.name
+                    );
+                    match rate_limited_path {
+                        Some((Some(scope), _)) =>
+                            api_key.require_scope(scope).map_err(Rejection::from),
+                        _ => Err(ApiKeyError::UnsupportedRoute.into()),
+                    }
+                },
+                Outcome::Failure((_, err)) => Err(err.into()),
+                Outcome::Forward(()) => Ok(()),
+            };
+        }
+
+        let usernames = match rate_limited_path {
+            Some((_, usernames)) => usernames,
+            None => return Ok(()),
+        };
+
+        if self.by_ip.is_enabled() {
+            if let Some(ip) = req.client_ip() {
+                self.by_ip
+                    .take(&ip.to_string(), now)
+                    .map_err(Rejection::rate_limited)?;
+            }
+        }
+        if self.by_username.is_enabled() {
+            for username in usernames {
+                self.by_username
+                    .take(username, now)
+                    .map_err(Rejection::rate_limited)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the scope that API keys need in order to access the path, or `None` if no scope covers
+/// it, along with the usernames in it if it's for a rate limited route
+fn parse_rate_limited_path(path: &str) -> Option<(Option<ApiKeyScope>, Vec<&str>)> {
+    let path = path.strip_prefix("/api").unwrap_or(path);
+    let mut segments = path.split('/').filter(|segment| !segment.is_empty());
+    match segments.next()? {
+        "stats" => Some((Some(ApiKeyScope::Stats), segments.take(1).collect())),
+        "compare" => Some((Some(ApiKeyScope::Comparisons), segments.take(2).collect())),
+        "graphql" => Some((None, Vec::new())),
+        _ => None,
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RateLimitFairing {
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        if let Err(rejection) = self.check(req, Instant::now()).await {
+            req.local_cache(|| Rejected(Some(rejection)));
+            req.set_method(Method::Get);
+            req.set_uri(Origin::parse(RATE_LIMITED_PATH).unwrap());
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let rejection = match req.local_cache(|| Rejected(None)) {
+            Rejected(Some(rejection)) => rejection,
+            Rejected(None) => return,
+        };
+
+        let body = rejection.message.clone();
+        res.set_status(rejection.status);
+        if let Some(retry_after) = rejection.retry_after {
+            res.set_header(Header::new(
+                "Retry-After",
+                retry_after.as_secs_f64().ceil().to_string(),
+            ));
+        }
+        res.set_sized_body(body.len(), std::io::Cursor::new(body));
+    }
+
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate Limiting Fairing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+}
+
+#[test]
+fn rate_limiter_refills_tokens() {
+    let limiter = RateLimiter::new(2, 60);
+    let start = Instant::now();
+    assert!(limiter.take("a", start).is_ok());
+    assert!(limiter.take("a", start).is_ok());
+    let retry_after = limiter.take("a", start).unwrap_err();
+    assert_eq!(retry_after.as_secs_f64().ceil(), 1.);
+    // Buckets are tracked separately for each key
+    assert!(limiter.take("b", start).is_ok());
+    assert!(limiter.check("a", start + Duration::from_secs(1)).is_ok());
+    assert!(limiter.take("a", start + Duration::from_secs(1)).is_ok());
+    assert!(limiter.check("a", start + Duration::from_secs(1)).is_err());
+
+    assert_eq!(
+        parse_rate_limited_path("/api/stats/foo/artist/bar"),
+        Some((Some(ApiKeyScope::Stats), vec!["foo"]))
+    );
+    assert_eq!(
+        parse_rate_limited_path("/compare/foo/bar"),
+        Some((Some(ApiKeyScope::Comparisons), vec!["foo", "bar"]))
+    );
+    assert_eq!(
+        parse_rate_limited_path("/api/graphql"),
+        Some((None, vec![]))
+    );
+    assert_eq!(parse_rate_limited_path("/charts/top_artists"), None);
+}
@@ -0,0 +1,194 @@
+//! Per-route request latency tracking.  The duration of every request is recorded against the
+//! route that handled it, and the p50/p95/p99 latencies over each route's most recent requests are
+//! exported as metrics on the telemetry server.  Latencies are counted in fixed buckets so that
+//! percentiles can be read off without sorting on every request.  Requests slower than
+//! `SLOW_REQUEST_THRESHOLD_MS` are logged along with their query parameters, with any credentials
+//! redacted.
+
+use std::{borrow::Cow, collections::VecDeque, sync::Mutex, time::Instant};
+
+use fnv::FnvHashMap as HashMap;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Data, Request, Response,
+};
+
+use crate::{conf::CONF, metrics::http_request_latency_quantile_us};
+
+/// Number of each route's most recent requests that its latency percentiles are computed over
+const LATENCY_WINDOW_SIZE: usize = 1024;
+/// Upper bounds of the buckets that latencies are counted in, in microseconds.  Percentiles are
+/// reported as the upper bound of the bucket they fall in, and latencies above the last bound are
+/// reported as the last bound.
+const LATENCY_BUCKET_BOUNDS_US: [u64; 20] = [
+    500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 75_000, 100_000, 150_000, 200_000, 250_000,
+    350_000, 500_000, 750_000, 1_000_000, 2_500_000, 5_000_000, 10_000_000, 30_000_000,
+];
+const UNMATCHED_ROUTE_NAME: &str = "unmatched";
+const QUANTILES: [(&str, f64); 3] = [("p50", 0.5), ("p95", 0.95), ("p99", 0.99)];
+/// Values of query parameters with these names, or containing `token`, are never logged
+const SENSITIVE_QUERY_PARAMS: &[&str] = &["code", "state", "password", "secret"];
+
+/// Time at which the request was received, stored in its local cache
+struct RequestStart(Instant);
+
+#[derive(Default)]
+struct LatencyWindow {
+    /// Bucket of each of the most recent requests, oldest first, so that they can be removed from
+    /// `bucket_counts` once they fall out of the window
+    buckets: VecDeque<usize>,
+    /// Number of requests in the window in each bucket, with an extra bucket for latencies above
+    /// the last bound
+    bucket_counts: [usize; LATENCY_BUCKET_BOUNDS_US.len() + 1],
+}
+
+impl LatencyWindow {
+    fn record(&mut self, latency_us: u64) {
+        if self.buckets.len() >= LATENCY_WINDOW_SIZE {
+            if let Some(evicted) = self.buckets.pop_front() {
+                self.bucket_counts[evicted] -= 1;
+            }
+        }
+        let bucket = LATENCY_BUCKET_BOUNDS_US.partition_point(|&bound| bound < latency_us);
+        self.buckets.push_back(bucket);
+        self.bucket_counts[bucket] += 1;
+    }
+
+    /// Returns the latency at each of `QUANTILES` using the nearest-rank method, rounded up to the
+    /// bound of the bucket it falls in
+    fn quantiles(&self) -> [u64; QUANTILES.len()] {
+        let mut out = [0; QUANTILES.len()];
+        let count = self.buckets.len();
+        if count == 0 {
+            return out;
+        }
+        for (i, (_, quantile)) in QUANTILES.iter().enumerate() {
+            let rank = ((quantile * count as f64).ceil() as usize).clamp(1, count);
+            let mut seen = 0;
+            let bucket = self
+                .bucket_counts
+                .iter()
+                .position(|&bucket_count| {
+                    seen += bucket_count;
+                    seen >= rank
+                })
+                .unwrap_or(LATENCY_BUCKET_BOUNDS_US.len());
+            out[i] = LATENCY_BUCKET_BOUNDS_US[bucket.min(LATENCY_BUCKET_BOUNDS_US.len() - 1)];
+        }
+        out
+    }
+}
+
+/// Route names are generated by Rocket's codegen, so they're `'static` for every route defined
+/// with the route attributes
+fn get_route_name(req: &Request<'_>) -> &'static str {
+    match req.route().and_then(|route| route.name.as_ref()) {
+        Some(Cow::Borrowed(name)) => *name,
+        _ => UNMATCHED_ROUTE_NAME,
+    }
+}
+
+fn is_sensitive_query_param(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.contains("token") || SENSITIVE_QUERY_PARAMS.contains(&name.as_str())
+}
+
+/// Returns the request's query string with the values of any sensitive parameters redacted
+fn sanitize_query(query: &str) -> String {
+    query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| match param.split_once('=') {
+            Some((name, _)) if is_sensitive_query_param(name) => format!("{}=[redacted]", name),
+            _ => param.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Records the latency of every request and logs slow requests
+pub(crate) struct RequestTimingFairing {
+    windows: Mutex<HashMap<&'static str, LatencyWindow>>,
+}
+
+impl RequestTimingFairing {
+    pub(crate) fn new() -> Self {
+        RequestTimingFairing {
+            windows: Mutex::new(HashMap::default()),
+        }
+    }
+
+    fn record(&self, route_name: &'static str, latency_us: u64) {
+        let quantiles = {
+            let mut windows = self.windows.lock().unwrap();
+            let window = windows.entry(route_name).or_default();
+            window.record(latency_us);
+            window.quantiles()
+        };
+
+        for ((quantile_name, _), latency_us) in QUANTILES.iter().zip(quantiles) {
+            http_request_latency_quantile_us(route_name, quantile_name).set(latency_us as i64);
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RequestTimingFairing {
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        req.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let latency = req.local_cache(|| RequestStart(Instant::now())).0.elapsed();
+        let route_name = get_route_name(req);
+        self.record(route_name, latency.as_micros() as u64);
+
+        if let Some(threshold) = CONF.slow_request_threshold {
+            if latency >= threshold {
+                tracing::warn!(
+                    method = %req.method(),
+                    path = %req.uri().path(),
+                    query = req.uri().query().map(|query| sanitize_query(query.as_str())),
+                    route = route_name,
+                    status = res.status().code,
+                    latency_ms = latency.as_millis() as u64,
+                    "Slow request"
+                );
+            }
+        }
+    }
+
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Timing Fairing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+}
+
+#[test]
+fn latency_quantiles_and_query_sanitization() {
+    let mut window = LatencyWindow::default();
+    assert_eq!(window.quantiles(), [0, 0, 0]);
+    for latency_ms in 1..=100 {
+        window.record(latency_ms * 1000);
+    }
+    assert_eq!(window.quantiles(), [50_000, 100_000, 100_000]);
+    // Only the most recent requests are kept
+    for _ in 0..LATENCY_WINDOW_SIZE {
+        window.record(1_000_000);
+    }
+    assert_eq!(window.quantiles(), [1_000_000, 1_000_000, 1_000_000]);
+    window.record(60_000_000);
+    assert_eq!(window.quantiles(), [1_000_000, 1_000_000, 1_000_000]);
+    for _ in 0..LATENCY_WINDOW_SIZE {
+        window.record(60_000_000);
+    }
+    assert_eq!(window.quantiles(), [30_000_000, 30_000_000, 30_000_000]);
+
+    assert_eq!(
+        sanitize_query("code=abc123&state=xyz&limit=10&api_token=secret"),
+        "code=[redacted]&state=[redacted]&limit=10&api_token=[redacted]"
+    );
+    assert_eq!(sanitize_query("q=foo&linked"), "q=foo&linked");
+}
@@ -0,0 +1,140 @@
+//! Downsampling of old user stats snapshots.
+//!
+//! Every update records a row per timeframe in `snapshot_updates` plus the rankings that changed in
+//! the `artist_rank_deltas` and `track_rank_deltas` tables, and those rows are kept forever.  Very
+//! old history doesn't need that level of detail, so we thin it out according to a retention
+//! policy: all snapshots are kept for a recent period, then only one snapshot per week, and past
+//! that only one snapshot per month.  Snapshots that remain after a deleted one are stored in full
+//! first so that they can still be reconstructed (see `db_util::delete_snapshots`).
+
+use chrono::{Datelike, Duration, NaiveDateTime};
+use fnv::FnvHashSet as HashSet;
+
+use crate::{conf::CONF, error::Error, DbConn};
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RetentionPolicy {
+    /// Snapshots newer than this are all kept
+    pub keep_all_for: Duration,
+    /// Snapshots older than `keep_all_for` but newer than this are kept at one per week.  Anything
+    /// older is kept at one per month.
+    pub keep_weekly_for: Duration,
+}
+
+impl RetentionPolicy {
+    pub(crate) fn from_conf() -> Self {
+        RetentionPolicy {
+            keep_all_for: CONF.retention_keep_all_for,
+            keep_weekly_for: CONF.retention_keep_weekly_for,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum RetentionBucket {
+    Week { year: i32, week: u32 },
+    Month { year: i32, month: u32 },
+}
+
+/// Given the distinct update times of a user's snapshots sorted in ascending order, returns the
+/// ones that should be deleted according to the policy.  The oldest snapshot in each week/month
+/// bucket is the one that is kept.
+pub(crate) fn get_update_times_to_delete(
+    update_times: &[NaiveDateTime],
+    policy: RetentionPolicy,
+    now: NaiveDateTime,
+) -> Vec<NaiveDateTime> {
+    let keep_all_cutoff = now - policy.keep_all_for;
+    let keep_weekly_cutoff = now - policy.keep_weekly_for;
+
+    let mut seen_buckets: HashSet<RetentionBucket> = HashSet::default();
+    let mut to_delete = Vec::new();
+    for &update_time in update_times {
+        if update_time >= keep_all_cutoff {
+            continue;
+        }
+
+        let bucket = if update_time >= keep_weekly_cutoff {
+            let week = update_time.iso_week();
+            RetentionBucket::Week {
+                year: week.year(),
+                week: week.week(),
+            }
+        } else {
+            RetentionBucket::Month {
+                year: update_time.year(),
+                month: update_time.month(),
+            }
+        };
+
+        if !seen_buckets.insert(bucket) {
+            to_delete.push(update_time);
+        }
+    }
+
+    to_delete
+}
+
+/// Downsamples the artist and track snapshots for a single user.  Returns the number of deleted
+/// rows.
+pub(crate) async fn compact_user_history(
+    conn: &DbConn,
+    user_id: i64,
+    policy: RetentionPolicy,
+    now: NaiveDateTime,
+) -> Result<usize, Error> {
+    let (artist_update_times, track_update_times) =
+        crate::db_util::get_snapshot_update_times(conn, user_id).await?;
+
+    let artist_update_times_to_delete =
+        get_update_times_to_delete(&artist_update_times, policy, now);
+    let track_update_times_to_delete = get_update_times_to_delete(&track_update_times, policy, now);
+    if artist_update_times_to_delete.is_empty() && track_update_times_to_delete.is_empty() {
+        return Ok(0);
+    }
+
+    let deleted_row_count = crate::db_util::delete_snapshots(
+        conn,
+        user_id,
+        artist_update_times_to_delete,
+        track_update_times_to_delete,
+    )
+    .await?;
+    Ok(deleted_row_count)
+}
+
+#[test]
+fn retention_policy_downsampling() {
+    use chrono::NaiveDate;
+
+    let at = |year: i32, month: u32, day: u32| {
+        NaiveDate::from_ymd_opt(year, month, day)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+    };
+    let policy = RetentionPolicy {
+        keep_all_for: Duration::days(30),
+        keep_weekly_for: Duration::days(365),
+    };
+    let now = at(2024, 6, 30);
+
+    let update_times = vec![
+        // Monthly range; only the first snapshot of each month is kept
+        at(2023, 1, 2),
+        at(2023, 1, 20),
+        at(2023, 2, 1),
+        // Weekly range; 2024-03-04 is a Monday, so the 5th is in the same week
+        at(2024, 3, 4),
+        at(2024, 3, 5),
+        at(2024, 3, 11),
+        // Recent; everything is kept
+        at(2024, 6, 20),
+        at(2024, 6, 21),
+    ];
+
+    assert_eq!(
+        get_update_times_to_delete(&update_times, policy, now),
+        vec![at(2023, 1, 20), at(2024, 3, 5)]
+    );
+}
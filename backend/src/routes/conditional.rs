@@ -0,0 +1,207 @@
+//! Conditional request support for the stats routes.  A user's stats only change when a new
+//! snapshot is stored for them, so `last_update_time` is used as the validator for everything
+//! derived from their snapshots.  Clients that send back the `ETag` or `Last-Modified` value they
+//! were given get an empty `304 Not Modified` response if nothing has changed since.
+
+use chrono::NaiveDateTime;
+use rocket::{
+    http::{Header, Status},
+    request::{FromRequest, Outcome},
+    response::{self, Responder, Response},
+    Request,
+};
+
+use crate::models::User;
+
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Validators identifying the current version of a user's stats
+pub(crate) struct CacheValidators {
+    etag: String,
+    last_modified: NaiveDateTime,
+    is_private: bool,
+}
+
+impl CacheValidators {
+    pub(crate) fn for_user(user: &User) -> Self {
+        CacheValidators {
+            // Weak since entity metadata embedded in the response is fetched from Spotify and can
+            // change without the stats themselves changing
+            // The timezone is included since timestamps are converted into it
+            etag: format!(
+                "W/\"{}-{}-{}\"",
+                user.spotify_id,
+                user.last_update_time.and_utc().timestamp(),
+                user.tz().name()
+            ),
+            last_modified: user.last_update_time,
+            is_private: user.is_private,
+        }
+    }
+
+    /// Validators for stats aggregated across a user and the accounts linked to them, which change
+    /// whenever any of the accounts is updated or the set of linked accounts changes
+    pub(crate) fn for_linked_users(user: &User, linked_users: &[User]) -> Self {
+        let last_modified = linked_users
+            .iter()
+            .map(|linked_user| linked_user.last_update_time)
+            .fold(user.last_update_time, NaiveDateTime::max);
+        CacheValidators {
+            etag: format!(
+                "W/\"{}-{}-{}-linked-{}\"",
+                user.spotify_id,
+                last_modified.and_utc().timestamp(),
+                user.tz().name(),
+                linked_users
+                    .iter()
+                    .map(|linked_user| linked_user.spotify_id.as_str())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            last_modified,
+            is_private: user.is_private,
+        }
+    }
+
+    fn cache_control(&self) -> &'static str {
+        // Clients always revalidate since updates can be forced before `min_update_interval` has
+        // elapsed and since the user can change their privacy settings at any time.  Revalidating
+        // is cheap as it only needs to look up the user.
+        if self.is_private {
+            "private, no-cache"
+        } else {
+            "public, no-cache"
+        }
+    }
+}
+
+/// The `If-None-Match` and `If-Modified-Since` headers sent with a request, if any
+pub(crate) struct ConditionalRequest {
+    if_none_match: Option<String>,
+    if_modified_since: Option<NaiveDateTime>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ConditionalRequest {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = req.headers();
+        Outcome::Success(ConditionalRequest {
+            if_none_match: headers.get_one("If-None-Match").map(String::from),
+            // Unparseable dates are ignored as required by RFC 7232
+            if_modified_since: headers
+                .get_one("If-Modified-Since")
+                .and_then(|date| NaiveDateTime::parse_from_str(date, HTTP_DATE_FORMAT).ok()),
+        })
+    }
+}
+
+impl ConditionalRequest {
+    /// Returns `true` if the client's cached copy matches the provided validators.
+    /// `If-Modified-Since` is only considered if `If-None-Match` isn't provided.
+    pub(crate) fn is_fresh(&self, validators: &CacheValidators) -> bool {
+        if let Some(if_none_match) = &self.if_none_match {
+            let current = validators.etag.trim_start_matches("W/");
+            return if_none_match.split(',').any(|etag| {
+                // Clients that were sent a compressed response send back its tag, which has the
+                // encoding appended (see `crate::compression`)
+                let etag = crate::compression::strip_encoding_suffix(etag.trim());
+                etag == "*" || etag.trim_start_matches("W/") == current
+            });
+        }
+
+        match self.if_modified_since {
+            // HTTP dates only have second precision
+            Some(if_modified_since) =>
+                validators.last_modified.and_utc().timestamp()
+                    <= if_modified_since.and_utc().timestamp(),
+            None => false,
+        }
+    }
+}
+
+/// Wraps a response with cache validator headers, replacing it with `304 Not Modified` if the
+/// client's cached copy is still current
+pub(crate) struct Conditional<R> {
+    body: Option<R>,
+    validators: CacheValidators,
+}
+
+impl<R> Conditional<R> {
+    pub(crate) fn new(body: R, validators: CacheValidators) -> Self {
+        Conditional {
+            body: Some(body),
+            validators,
+        }
+    }
+
+    pub(crate) fn not_modified(validators: CacheValidators) -> Self {
+        Conditional {
+            body: None,
+            validators,
+        }
+    }
+}
+
+impl<'r, R: Responder<'r, 'static>> Responder<'r, 'static> for Conditional<R> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let mut builder = match self.body {
+            Some(body) => Response::build_from(body.respond_to(req)?),
+            None => {
+                let mut builder = Response::build();
+                builder.status(Status::NotModified);
+                builder
+            },
+        };
+        builder
+            .header(Header::new("ETag", self.validators.etag.clone()))
+            .header(Header::new(
+                "Last-Modified",
+                self.validators
+                    .last_modified
+                    .format(HTTP_DATE_FORMAT)
+                    .to_string(),
+            ))
+            .header(Header::new(
+                "Cache-Control",
+                self.validators.cache_control(),
+            ))
+            .ok()
+    }
+}
+
+#[test]
+fn conditional_request_freshness() {
+    let last_modified =
+        NaiveDateTime::parse_from_str("Fri, 16 Oct 2026 12:00:00 GMT", HTTP_DATE_FORMAT).unwrap();
+    let validators = CacheValidators {
+        etag: format!("W/\"user-{}\"", last_modified.and_utc().timestamp()),
+        last_modified,
+        is_private: false,
+    };
+    let req = |if_none_match: Option<&str>, if_modified_since: Option<NaiveDateTime>| {
+        ConditionalRequest {
+            if_none_match: if_none_match.map(String::from),
+            if_modified_since,
+        }
+    };
+
+    let current_etag = validators.etag.clone();
+    assert!(req(Some(&current_etag), None).is_fresh(&validators));
+    assert!(req(Some(&format!("\"foo\", {}", &current_etag[2..])), None).is_fresh(&validators));
+    assert!(req(Some("*"), None).is_fresh(&validators));
+    assert!(req(
+        Some(&crate::compression::encoded_etag(
+            &current_etag,
+            crate::compression::Encoding::Gzip
+        )),
+        None
+    )
+    .is_fresh(&validators));
+    // `If-None-Match` takes precedence over `If-Modified-Since`
+    assert!(!req(Some("W/\"user-0\""), Some(last_modified)).is_fresh(&validators));
+    assert!(req(None, Some(last_modified)).is_fresh(&validators));
+    assert!(!req(None, Some(last_modified - chrono::Duration::seconds(1))).is_fresh(&validators));
+    assert!(!req(None, None).is_fresh(&validators));
+}
@@ -0,0 +1,88 @@
+//! Minimal Atom (RFC 4287) serialization for the stats feeds
+
+use chrono::{NaiveDateTime, SecondsFormat};
+
+use crate::share_card::escape_xml;
+
+pub(crate) struct AtomEntry {
+    pub id: String,
+    pub title: String,
+    pub link: String,
+    pub updated: NaiveDateTime,
+    /// HTML summary of the entry; escaped when serialized
+    pub content_html: String,
+}
+
+pub(crate) struct AtomFeed {
+    pub id: String,
+    pub title: String,
+    /// Page that the feed is for
+    pub link: String,
+    /// URL of the feed itself
+    pub self_link: String,
+    pub updated: NaiveDateTime,
+    pub entries: Vec<AtomEntry>,
+}
+
+fn format_time(time: NaiveDateTime) -> String {
+    time.and_utc().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+impl AtomFeed {
+    pub(crate) fn to_xml(&self) -> String {
+        let mut xml = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<feed \
+             xmlns=\"http://www.w3.org/2005/Atom\">\n  <id>{}</id>\n  <title>{}</title>\n  \
+             <updated>{}</updated>\n  <link href=\"{}\" />\n  <link rel=\"self\" href=\"{}\" />\n  \
+             <author><name>Spotifytrack</name></author>\n",
+            escape_xml(&self.id),
+            escape_xml(&self.title),
+            format_time(self.updated),
+            escape_xml(&self.link),
+            escape_xml(&self.self_link),
+        );
+        for entry in &self.entries {
+            xml.push_str(&format!(
+                "  <entry>\n    <id>{}</id>\n    <title>{}</title>\n    <updated>{}</updated>\n    \
+                 <link href=\"{}\" />\n    <content type=\"html\">{}</content>\n  </entry>\n",
+                escape_xml(&entry.id),
+                escape_xml(&entry.title),
+                format_time(entry.updated),
+                escape_xml(&entry.link),
+                escape_xml(&entry.content_html),
+            ));
+        }
+        xml.push_str("</feed>\n");
+        xml
+    }
+}
+
+#[test]
+fn atom_feed_serialization() {
+    let updated = chrono::DateTime::from_timestamp(1_700_000_000, 0)
+        .unwrap()
+        .naive_utc();
+    let feed = AtomFeed {
+        id: "https://spotifytrack.net/stats/foo/feed.atom".into(),
+        title: "foo's top changes".into(),
+        link: "https://spotifytrack.net/stats/foo".into(),
+        self_link: "https://spotifytrack.net/stats/foo/feed.atom".into(),
+        updated,
+        entries: vec![AtomEntry {
+            id: "https://spotifytrack.net/stats/foo#short-1700000000".into(),
+            title: "Week of 2023-11-13: Simon & Garfunkel is the new top artist".into(),
+            link: "https://spotifytrack.net/stats/foo".into(),
+            updated,
+            content_html: "<ul><li>Simon &amp; Garfunkel</li></ul>".into(),
+        }],
+    };
+    let xml = feed.to_xml();
+    assert!(xml.contains("<title>foo&apos;s top changes</title>"));
+    assert!(xml.contains("<updated>2023-11-14T22:13:20Z</updated>"));
+    assert!(xml.contains("Simon &amp; Garfunkel is the new top artist</title>"));
+    assert!(xml.contains(
+        "<content type=\"html\">&lt;ul&gt;&lt;li&gt;Simon &amp;amp; \
+         Garfunkel&lt;/li&gt;&lt;/ul&gt;</content>"
+    ));
+    assert!(xml.ends_with("</entry>\n</feed>\n"));
+}
@@ -1,22 +1,31 @@
-use std::{cmp::Reverse, sync::Arc};
+use std::{cmp::Reverse, collections::BTreeMap, future::Future, sync::Arc, time::Instant};
 
-use chrono::{NaiveDateTime, Utc};
+use chrono::{Datelike, NaiveDateTime, Utc};
 use diesel::{self, prelude::*};
 use fnv::{FnvHashMap as HashMap, FnvHashSet};
 use futures::{stream::FuturesUnordered, StreamExt, TryFutureExt, TryStreamExt};
 use redis::Commands;
 use rocket::{
     data::ToByteUnit,
-    http::{RawStr, Status},
-    response::{status, Redirect},
+    http::{uri::Origin, ContentType, CookieJar, Header, RawStr, Status},
+    request::{FromRequest, Outcome},
+    response::{
+        content::{RawHtml, RawJson},
+        status,
+        stream::{Event, EventStream, TextStream},
+        Redirect, Responder, Response,
+    },
     serde::json::Json,
-    State,
+    Shutdown, State,
 };
+use schemars::JsonSchema;
+use subtle::ConstantTimeEq;
 use tokio::{
     sync::Mutex,
     task::{block_in_place, spawn_blocking},
 };
 
+use self::conditional::{CacheValidators, Conditional, ConditionalRequest};
 use crate::{
     artist_embedding::{
         get_artist_embedding_ctx, get_average_artists,
@@ -24,82 +33,470 @@ use crate::{
         ArtistEmbeddingError,
     },
     benchmarking::{mark, start},
-    cache::{get_hash_items, get_redis_conn, set_hash_items},
+    cache::{
+        get_fresh_hash_items, get_hash_items, get_redis_conn, invalidate_hash_items,
+        metadata_store::{expire_metadata_items, MetadataTable},
+        now_playing_cache::{
+            get_cached_playback_state, set_cached_playback_state, CachedPlaybackState,
+        },
+        set_hash_items,
+        share_card_cache::{get_cached_share_card, set_cached_share_card},
+        snapshot_cache::{get_cached_snapshot, invalidate_cached_snapshots, set_cached_snapshot},
+    },
     conf::CONF,
     db_util::{
         self, get_all_top_artists_for_user, get_artist_spotify_ids_by_internal_id,
-        get_internal_ids_by_spotify_id, insert_related_artists,
+        get_internal_ids_by_spotify_id, insert_related_artists, PopularityRankingRow,
+        SnapshotFilter,
     },
+    error::Error,
+    export::ExportEntity,
+    graphql::{RequestContext, StatsSchema},
+    importers::{self, ImportSource},
     metrics::{user_updates_failure_total, user_updates_success_total},
     models::{
-        Artist, ArtistSearchResult, AverageArtistItem, AverageArtistsResponse, CompareToRequest,
-        CreateSharedPlaylistRequest, NewRelatedArtistEntry, NewUser, OAuthTokenResponse, Playlist,
-        RelatedArtistsGraph, StatsSnapshot, TimeFrames, Timeline, TimelineEvent, TimelineEventType,
-        Track, User, UserComparison,
+        AboutStats, AdminUserListItem, AggregatedTimeline, ApiKeyInfo, Artist, ArtistDiscovery,
+        ArtistGraph, ArtistLeaderboard, ArtistLeaderboardEntry, ArtistSearchResult,
+        AudioFeaturesProfile, AverageArtistItem, AverageArtistsResponse, CacheStatus, ChartData,
+        ChartPoint, ChartSeries, CompareToRequest, ComparisonResult, CreateSharedPlaylistRequest,
+        Crossover, DeactivationRequest, DeactivationStatus, DependencyStatus, DiversityHistory,
+        DiversityScore, EmailSubscription, EmailSubscriptionRequest, EmailSubscriptionStatus,
+        FollowEvent, FollowEventKind, FollowHistory, Friend, FriendList, FriendsFeed,
+        FriendsFeedItem, GeneratedPlaylist, GenreBreakdown, GenreTimeline, GenreTrend, GlobalChart,
+        GlobalChartEntry, GlobalSummary, HealthStatus, HistorySearchMatch, HistorySearchResults,
+        ImportProgress, LastfmImportRequest, LibraryHistory, LibrarySize, LinkAccountRequest,
+        LinkedAccount, ListeningTime, ListeningTimePeriod, LocalDateTime, MainstreamComparison,
+        MainstreamHistory, MainstreamScore, MintedApiKey, MostTrackedArtist, NewApiKey,
+        NewApiKeyRequest, NewRelatedArtistEntry, NewUser, NowPlaying, OAuthTokenResponse, Playlist,
+        PopularityPoint, PopularityTrend, PopularityTrends, PrivacySettings,
+        PrivacySettingsRequest, ReadinessStatus, RecentlyPlayedItem, Recommendations,
+        RelatedArtistsGraph, SavedTrack, SchedulerStatus, SnapshotStoredEvent, StatsSnapshot,
+        StoredToken, SystemStats, TimeFrames, Timeframe, TimeframeOverlap, Timeline, TimelineEvent,
+        TimelineEventType, Track, TrackAudioFeatures, UniqueFavorites, UpdateDryRunReport,
+        UpdateFrequency, UpdateIntervalOverrideRequest, User, UserDataExport, UserDeletionSummary,
+        UserSettings, UserSettingsEntry, UserSettingsRequest, MIN_UPDATE_INTERVAL_OVERRIDE_SECONDS,
     },
+    oauth_scopes::SpotifyFeature,
+    pagination::{CursorPagination, Paginated},
+    public_stats::PublicStatsField,
+    share_card::{self, ShareCard},
     spotify_api::{
         fetch_artists, fetch_top_tracks_for_artist, get_multiple_related_artists,
-        get_reqwest_client, search_artists,
+        get_reqwest_client, search_artists, warm_entity_cache, MAX_TOP_ENTITY_COUNT,
     },
+    stats::{merge_rankings, ListeningTimeGranularity, RankingChanges, TimelineGranularity},
     DbConn, SpotifyTokenData,
 };
 
+mod conditional;
+mod feed;
+
 const SPOTIFY_TOKEN_FETCH_URL: &str = "https://accounts.spotify.com/api/token";
+/// Max number of unique favorite artists and tracks returned for each user when comparing users
+const UNIQUE_FAVORITES_COUNT: usize = 10;
+/// Number of the user's most recently saved tracks returned with their library history
+const LIBRARY_NEWEST_SAVES_COUNT: i64 = 50;
 
 #[get("/")]
 pub(crate) fn index() -> &'static str { "Application successfully started!" }
 
-/// Retrieves the current top tracks and artist for the current user
-#[get("/stats/<username>")]
+/// Liveness probe.  Always succeeds as long as the server is able to handle requests.
+#[get("/healthz")]
+pub(crate) fn healthz() -> Json<HealthStatus> { Json(HealthStatus { status: "ok" }) }
+
+async fn check_dependency(check: impl Future<Output = Result<(), Error>>) -> DependencyStatus {
+    let start = Instant::now();
+    let res = check.await;
+    DependencyStatus {
+        ok: res.is_ok(),
+        latency_ms: start.elapsed().as_millis() as u64,
+        error: res.err().map(|err| err.to_string()),
+    }
+}
+
+/// Readiness probe.  Checks that the database and Redis are reachable and that a Spotify access
+/// token can be obtained, responding with a `503` if any of them aren't.
+#[get("/readyz")]
+pub(crate) async fn readyz(
+    conn: Option<DbConn>,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+) -> status::Custom<Json<ReadinessStatus>> {
+    let database = check_dependency(async {
+        let conn =
+            conn.ok_or_else(|| Error::Internal("Error getting database connection".into()))?;
+        conn.run(|conn| diesel::sql_query("SELECT 1").execute(conn))
+            .await
+            .map(drop)
+            .map_err(|err| {
+                error!("Error querying database for readiness check: {:?}", err);
+                Error::Internal("Error querying database".into())
+            })
+    })
+    .await;
+    let redis = check_dependency(async { block_in_place(crate::cache::ping) }).await;
+    let spotify_token = check_dependency(async {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await.map(drop)
+    })
+    .await;
+
+    let ready = database.ok && redis.ok && spotify_token.ok;
+    status::Custom(
+        if ready {
+            Status::Ok
+        } else {
+            Status::ServiceUnavailable
+        },
+        Json(ReadinessStatus {
+            ready,
+            database,
+            redis,
+            spotify_token,
+        }),
+    )
+}
+
+/// Executes a GraphQL query against the stats API.  See `crate::graphql` for the schema.
+#[post("/graphql", data = "<request>")]
+pub(crate) async fn graphql(
+    conn: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    access_token: PrivateAccessToken,
+    current_user: Option<CurrentUser>,
+    schema: &State<StatsSchema>,
+    request: Json<async_graphql::Request>,
+) -> Result<Json<async_graphql::Response>, Error> {
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+
+    let request = request.into_inner().data(RequestContext {
+        conn,
+        spotify_access_token,
+        access_token,
+        current_user,
+    });
+    Ok(Json(schema.execute(request).await))
+}
+
+/// Serves the OpenAPI specification describing the public API
+#[get("/openapi.json")]
+pub(crate) fn get_openapi_spec() -> (ContentType, &'static str) {
+    (ContentType::JSON, crate::openapi::SPEC.as_str())
+}
+
+/// Parses the `limit` and `timeframes` params accepted by the snapshot routes.  `timeframes` is a
+/// comma-separated list of timeframe names.
+fn parse_snapshot_filter(
+    limit: Option<u8>,
+    timeframes: Option<String>,
+) -> Result<SnapshotFilter, Error> {
+    let mut filter = SnapshotFilter {
+        limit: limit.map(|limit| limit.max(1)),
+        ..Default::default()
+    };
+    if let Some(timeframes) = timeframes {
+        filter.timeframes = timeframes
+            .split(',')
+            .map(|timeframe| timeframe.trim().parse())
+            .collect::<Result<_, _>>()?;
+    }
+    Ok(filter)
+}
+
+/// Either a user's current stats or a redirect to the URL for them using the user's vanity slug
+pub(crate) enum CurrentStatsResponse {
+    Stats(Conditional<RawJson<String>>),
+    Redirect(Redirect),
+}
+
+impl<'r> Responder<'r, 'static> for CurrentStatsResponse {
+    fn respond_to(self, req: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
+        match self {
+            CurrentStatsResponse::Stats(stats) => stats.respond_to(req),
+            CurrentStatsResponse::Redirect(redirect) => redirect.respond_to(req),
+        }
+    }
+}
+
+/// Retrieves the current top tracks and artist for the current user.  `limit` caps the number of
+/// artists and tracks returned per timeframe and `timeframes` is a comma-separated list of
+/// timeframes to include; all are included by default.  Serialized snapshots are cached in Redis
+/// until the user is next updated.
+///
+/// Users can be looked up by either their vanity slug or Spotify ID.  Requests using the Spotify ID
+/// of a user with a vanity slug are permanently redirected to the same URL using the slug.
+///
+/// If `linked` is set, the latest snapshots of the user and every account linked to them are
+/// merged into a single set of rankings.  Linked accounts that are private or deactivated are left
+/// out unless the supplied private token grants access to them.
+#[get("/stats/<username>?<limit>&<timeframes>&<linked>")]
 pub(crate) async fn get_current_stats(
     conn: DbConn,
     conn2: DbConn,
+    origin: &Origin<'_>,
     username: String,
-    token_data: &State<Mutex<SpotifyTokenData>>,
-) -> Result<Option<Json<StatsSnapshot>>, String> {
+    access_token: PrivateAccessToken,
+    conditional: ConditionalRequest,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    limit: Option<u8>,
+    timeframes: Option<String>,
+    linked: Option<bool>,
+) -> Result<Option<CurrentStatsResponse>, Error> {
+    let filter = parse_snapshot_filter(limit, timeframes)?;
     let tok = start();
-    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username.clone()).await?
+    {
         Some(user) => user,
         None => {
             return Ok(None);
         },
     };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    if let Some(slug) = user.vanity_slug.as_ref().filter(|slug| **slug != username) {
+        let path = origin.path().as_str();
+        let base_path = path.rsplit_once('/').map(|(base, _)| base).unwrap_or("");
+        let location = match origin.query() {
+            Some(query) => format!("{}/{}?{}", base_path, slug, query.as_str()),
+            None => format!("{}/{}", base_path, slug),
+        };
+        return Ok(Some(CurrentStatsResponse::Redirect(Redirect::permanent(
+            location,
+        ))));
+    }
+    if linked == Some(true) {
+        let linked_users =
+            access_token.accessible_users(db_util::get_linked_users(&conn, &user).await?);
+        if !linked_users.is_empty() {
+            return get_linked_stats(conn, user, linked_users, conditional, token_data, filter)
+                .await
+                .map(|stats| stats.map(CurrentStatsResponse::Stats));
+        }
+    }
+    let validators = CacheValidators::for_user(&user);
+    if conditional.is_fresh(&validators) {
+        return Ok(Some(CurrentStatsResponse::Stats(
+            Conditional::not_modified(validators),
+        )));
+    }
     mark(tok, "Finished getting spotify user by id");
 
+    let cached = block_in_place(|| get_cached_snapshot(user.id, user.last_update_time, &filter))
+        .unwrap_or_else(|err| {
+            warn!("Error reading from cache; skipping it: {}", err);
+            None
+        });
+    if let Some(serialized) = cached {
+        return Ok(Some(CurrentStatsResponse::Stats(Conditional::new(
+            RawJson(serialized),
+            validators,
+        ))));
+    }
+
     let spotify_access_token = {
         let token_data = &mut *(&*token_data).lock().await;
         token_data.get().await
     }?;
 
-    let tok = start();
-    let (artist_stats, track_stats) = match tokio::join!(
-        db_util::get_artist_stats(&user, conn, &spotify_access_token),
-        db_util::get_track_stats(&user, conn2, &spotify_access_token),
-    ) {
-        (Err(err), _) | (Ok(_), Err(err)) => return Err(err),
-        (Ok(None), _) | (_, Ok(None)) => return Ok(None),
-        (Ok(Some(artist_stats)), Ok(Some(track_stats))) => (artist_stats, track_stats),
+    let snapshot = match db_util::load_snapshot(
+        conn,
+        conn2,
+        &user,
+        None,
+        &spotify_access_token,
+        filter.clone(),
+    )
+    .await?
+    {
+        Some(snapshot) => snapshot,
+        None => return Ok(None),
+    };
+    let serialized = serde_json::to_string(&snapshot).map_err(|err| {
+        error!("Error serializing stats snapshot: {:?}", err);
+        Error::Internal("Error serializing stats snapshot".into())
+    })?;
+    if let Err(err) =
+        block_in_place(|| set_cached_snapshot(user.id, user.last_update_time, &filter, &serialized))
+    {
+        warn!("Error writing to cache: {}", err);
+    }
+
+    Ok(Some(CurrentStatsResponse::Stats(Conditional::new(
+        RawJson(serialized),
+        validators,
+    ))))
+}
+
+/// Merges the latest snapshots of the user and the accounts linked to them.  Items are ranked using
+/// `stats::merge_rankings`, and timestamps are converted into the timezone of `user`.  Merged
+/// snapshots aren't cached since they depend on the updates of several users.
+async fn get_linked_stats(
+    conn: DbConn,
+    user: User,
+    linked_users: Vec<User>,
+    conditional: ConditionalRequest,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    filter: SnapshotFilter,
+) -> Result<Option<Conditional<RawJson<String>>>, Error> {
+    let validators = CacheValidators::for_linked_users(&user, &linked_users);
+    if conditional.is_fresh(&validators) {
+        return Ok(Some(Conditional::not_modified(validators)));
+    }
+
+    // Every account's full rankings are needed to merge them, so `limit` is applied afterwards
+    let account_filter = SnapshotFilter {
+        limit: None,
+        ..filter.clone()
+    };
+    let mut account_ids = Vec::with_capacity(linked_users.len() + 1);
+    for account in std::iter::once(&user).chain(linked_users.iter()) {
+        if let Some(top_ids) =
+            db_util::get_latest_top_spotify_ids(&conn, account, account_filter.clone()).await?
+        {
+            account_ids.push(top_ids);
+        }
+    }
+    let last_update_time = match account_ids.iter().map(|top_ids| top_ids.update_time).max() {
+        Some(last_update_time) => last_update_time,
+        None => return Ok(None),
+    };
+
+    let max_items = match filter.limit {
+        Some(limit) => usize::from(limit),
+        None => db_util::get_user_settings(&conn, &user)
+            .await?
+            .entity_fetch_count(),
     };
-    mark(tok, "Fetched artist and track stats");
+    let mut merged_artist_ids: Vec<(Timeframe, &str)> = Vec::new();
+    let mut merged_track_ids: Vec<(Timeframe, &str)> = Vec::new();
+    for &timeframe in &filter.timeframes {
+        let artist_rankings: Vec<&[String]> = account_ids
+            .iter()
+            .map(|top_ids| top_ids.artists.get(timeframe).as_slice())
+            .collect();
+        merged_artist_ids.extend(
+            merge_rankings(&artist_rankings, max_items)
+                .into_iter()
+                .map(|spotify_id| (timeframe, spotify_id)),
+        );
+        let track_rankings: Vec<&[String]> = account_ids
+            .iter()
+            .map(|top_ids| top_ids.tracks.get(timeframe).as_slice())
+            .collect();
+        merged_track_ids.extend(
+            merge_rankings(&track_rankings, max_items)
+                .into_iter()
+                .map(|spotify_id| (timeframe, spotify_id)),
+        );
+    }
+
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+    let artist_spotify_ids: Vec<&str> = merged_artist_ids
+        .iter()
+        .map(|(_, spotify_id)| *spotify_id)
+        .collect();
+    let track_spotify_ids: Vec<&str> = merged_track_ids
+        .iter()
+        .map(|(_, spotify_id)| *spotify_id)
+        .collect();
+    let (artists, tracks) = tokio::try_join!(
+        fetch_artists(&spotify_access_token, &artist_spotify_ids),
+        crate::spotify_api::fetch_tracks(&spotify_access_token, &track_spotify_ids),
+    )?;
+
+    let mut snapshot = StatsSnapshot::new(user.localize(last_update_time));
+    for (timeframe, spotify_id) in &merged_artist_ids {
+        snapshot
+            .artists
+            .add_item(*timeframe, artists[*spotify_id].clone());
+    }
+    for (timeframe, spotify_id) in &merged_track_ids {
+        snapshot
+            .tracks
+            .add_item(*timeframe, tracks[*spotify_id].clone());
+    }
+    let serialized = serde_json::to_string(&snapshot).map_err(|err| {
+        error!("Error serializing stats snapshot: {:?}", err);
+        Error::Internal("Error serializing stats snapshot".into())
+    })?;
 
-    let mut snapshot = StatsSnapshot::new(user.last_update_time);
+    Ok(Some(Conditional::new(RawJson(serialized), validators)))
+}
 
-    for (timeframe_id, artist) in artist_stats {
-        snapshot.artists.add_item_by_id(timeframe_id, artist);
+/// Parses a snapshot timestamp given as seconds since the Unix epoch, as RFC 3339 with an offset
+/// (the format snapshot times are listed in by `/snapshots`), or in UTC in `YYYY-MM-DDTHH:MM:SS`
+/// format
+fn parse_snapshot_timestamp(timestamp: &str) -> Option<NaiveDateTime> {
+    if let Ok(secs) = timestamp.parse::<i64>() {
+        return chrono::DateTime::from_timestamp(secs, 0).map(|dt| dt.naive_utc());
     }
+    if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(timestamp) {
+        return Some(dt.naive_utc());
+    }
+    NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S").ok()
+}
 
-    for (timeframe_id, track) in track_stats {
-        snapshot.tracks.add_item_by_id(timeframe_id, track);
+/// Retrieves the top tracks and artists for the user from the update at the provided timestamp
+/// (see `parse_snapshot_timestamp` for the accepted formats).  Accepts the same `limit` and
+/// `timeframes` params as `get_current_stats`.
+#[get("/stats/<username>/snapshot/<timestamp>?<limit>&<timeframes>")]
+pub(crate) async fn get_snapshot(
+    conn: DbConn,
+    conn2: DbConn,
+    username: String,
+    access_token: PrivateAccessToken,
+    conditional: ConditionalRequest,
+    timestamp: String,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    limit: Option<u8>,
+    timeframes: Option<String>,
+) -> Result<Option<Conditional<Json<StatsSnapshot>>>, Error> {
+    let filter = parse_snapshot_filter(limit, timeframes)?;
+    let snapshot_time = parse_snapshot_timestamp(&timestamp)
+        .ok_or_else(|| Error::BadRequest(String::from("Invalid `timestamp` provided")))?;
+
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
     }
+    let validators = CacheValidators::for_user(&user);
+    if conditional.is_fresh(&validators) {
+        return Ok(Some(Conditional::not_modified(validators)));
+    }
+
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
 
-    Ok(Some(Json(snapshot)))
+    db_util::load_snapshot(
+        conn,
+        conn2,
+        &user,
+        Some(snapshot_time),
+        &spotify_access_token,
+        filter,
+    )
+    .await
+    .map(|snapshot| snapshot.map(|snapshot| Conditional::new(Json(snapshot), validators)))
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 pub(crate) struct ArtistStats {
     pub artist: Artist,
     pub tracks_by_id: HashMap<String, Track>,
-    pub popularity_history: Vec<(NaiveDateTime, [Option<u8>; 3])>,
+    pub popularity_history: Vec<(LocalDateTime, [Option<u8>; 3])>,
     pub top_tracks: Vec<(String, usize)>,
 }
 
@@ -107,17 +504,26 @@ pub(crate) struct ArtistStats {
 pub(crate) async fn get_artist_stats(
     conn: DbConn,
     conn2: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
     username: String,
+    access_token: PrivateAccessToken,
+    conditional: ConditionalRequest,
     artist_id: String,
-) -> Result<Option<Json<ArtistStats>>, String> {
+) -> Result<Option<Conditional<Json<ArtistStats>>>, Error> {
     let tok = start();
-    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
         Some(user) => user,
         None => {
             return Ok(None);
         },
     };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let validators = CacheValidators::for_user(&user);
+    if conditional.is_fresh(&validators) {
+        return Ok(Some(Conditional::not_modified(validators)));
+    }
     mark(tok, "Finished getting spotify user by id");
 
     let spotify_access_token = {
@@ -157,8 +563,7 @@ pub(crate) async fn get_artist_stats(
     let tok = start();
     let artist = match crate::spotify_api::fetch_artists(&spotify_access_token, &[&artist_id])
         .await?
-        .drain(..)
-        .next()
+        .remove(artist_id.as_str())
     {
         Some(artist) => artist,
         None => return Ok(None),
@@ -168,72 +573,107 @@ pub(crate) async fn get_artist_stats(
     let stats = ArtistStats {
         artist,
         tracks_by_id,
-        popularity_history: artist_popularity_history,
+        popularity_history: artist_popularity_history
+            .into_iter()
+            .map(|(update_time, rankings)| (user.localize(update_time), rankings))
+            .collect(),
         top_tracks: top_track_scores,
     };
-    Ok(Some(Json(stats)))
+    Ok(Some(Conditional::new(Json(stats), validators)))
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 pub(crate) struct GenresHistory {
-    pub timestamps: Vec<NaiveDateTime>,
+    pub timestamps: Vec<LocalDateTime>,
+    /// Keyed by canonical genre slug
     pub history_by_genre: HashMap<String, Vec<Option<usize>>>,
+    pub display_names: HashMap<String, String>,
 }
 
 #[get("/stats/<username>/genre_history")]
 pub(crate) async fn get_genre_history(
     conn: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
     username: String,
-) -> Result<Option<Json<GenresHistory>>, String> {
-    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+    access_token: PrivateAccessToken,
+    conditional: ConditionalRequest,
+) -> Result<Option<Conditional<Json<GenresHistory>>>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
         Some(user) => user,
         None => {
             return Ok(None);
         },
     };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let validators = CacheValidators::for_user(&user);
+    if conditional.is_fresh(&validators) {
+        return Ok(Some(Conditional::not_modified(validators)));
+    }
     let spotify_access_token = {
         let token_data = &mut *(&*token_data).lock().await;
         token_data.get().await
     }?;
 
     // Only include data from the "short" timeframe since we're producing a timeseries
-    let (artists_by_id, artist_stats_history) =
-        match db_util::get_artist_stats_history(&user, conn, &spotify_access_token, Some(0)).await?
-        {
-            Some(res) => res,
-            None => return Ok(None),
-        };
+    let (artists_by_id, artist_stats_history) = match db_util::get_artist_stats_history(
+        &user,
+        conn,
+        &spotify_access_token,
+        Some(Timeframe::Short),
+    )
+    .await?
+    {
+        Some(res) => res,
+        None => return Ok(None),
+    };
 
-    let (timestamps, history_by_genre) =
+    let (timestamps, history_by_genre, display_names) =
         crate::stats::get_top_genres_by_artists(&artists_by_id, &artist_stats_history, true);
-    Ok(Some(Json(GenresHistory {
-        timestamps,
-        history_by_genre,
-    })))
+    Ok(Some(Conditional::new(
+        Json(GenresHistory {
+            timestamps: timestamps
+                .into_iter()
+                .map(|timestamp| user.localize(timestamp))
+                .collect(),
+            history_by_genre,
+            display_names,
+        }),
+        validators,
+    )))
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 pub(crate) struct GenreStats {
     pub artists_by_id: HashMap<String, Artist>,
     pub top_artists: Vec<(String, f32)>,
-    pub timestamps: Vec<NaiveDateTime>,
+    pub timestamps: Vec<LocalDateTime>,
     pub popularity_history: TimeFrames<usize>,
 }
 
 #[get("/stats/<username>/genre/<genre>")]
 pub(crate) async fn get_genre_stats(
     conn: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
     username: String,
+    access_token: PrivateAccessToken,
+    conditional: ConditionalRequest,
     genre: String,
-) -> Result<Option<Json<GenreStats>>, String> {
-    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+) -> Result<Option<Conditional<Json<GenreStats>>>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
         Some(user) => user,
         None => {
             return Ok(None);
         },
     };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let validators = CacheValidators::for_user(&user);
+    if conditional.is_fresh(&validators) {
+        return Ok(Some(Conditional::not_modified(validators)));
+    }
     let spotify_access_token = {
         let token_data = &mut *(&*token_data).lock().await;
         token_data.get().await
@@ -249,160 +689,2834 @@ pub(crate) async fn get_genre_stats(
     let (timestamps, ranking_by_artist_spotify_id_by_timeframe, popularity_history) =
         crate::stats::compute_genre_ranking_history(genre_stats_history);
 
-    Ok(Some(Json(GenreStats {
-        artists_by_id,
-        top_artists: ranking_by_artist_spotify_id_by_timeframe,
-        popularity_history,
-        timestamps,
-    })))
+    Ok(Some(Conditional::new(
+        Json(GenreStats {
+            artists_by_id,
+            top_artists: ranking_by_artist_spotify_id_by_timeframe,
+            popularity_history,
+            timestamps: timestamps
+                .into_iter()
+                .map(|timestamp| user.localize(timestamp))
+                .collect(),
+        }),
+        validators,
+    )))
 }
 
-#[get("/stats/<username>/timeline?<start_day_id>&<end_day_id>")]
-pub(crate) async fn get_timeline(
+/// Returns a breakdown of the genres of the user's current top artists, weighted by artist ranking
+/// and combined across all timeframes.
+#[get("/stats/<username>/genres")]
+pub(crate) async fn get_genre_breakdown(
     conn: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
-    conn_2: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
     username: String,
-    start_day_id: String,
-    end_day_id: String,
-) -> Result<Option<Json<Timeline>>, String> {
-    let start_day = NaiveDateTime::parse_from_str(
-        &format!("{}T08:00:00+08:00", start_day_id),
-        "%Y-%m-%dT%H:%M:%S%z",
-    )
-    .map_err(|_| String::from("Invalid `start_day_id` provided"))?;
-    let end_day = NaiveDateTime::parse_from_str(
-        &format!("{}T08:00:00+08:00", end_day_id),
-        "%Y-%m-%dT%H:%M:%S%z",
-    )
-    .map_err(|_| String::from("Invalid `end_day_id` provided"))?;
-
-    let User { id: user_id, .. } = match db_util::get_user_by_spotify_id(&conn, username).await? {
+    access_token: PrivateAccessToken,
+    conditional: ConditionalRequest,
+) -> Result<Option<Conditional<Json<GenreBreakdown>>>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
         Some(user) => user,
         None => {
             return Ok(None);
         },
     };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let validators = CacheValidators::for_user(&user);
+    if conditional.is_fresh(&validators) {
+        return Ok(Some(Conditional::not_modified(validators)));
+    }
     let spotify_access_token = {
         let token_data = &mut *(&*token_data).lock().await;
         token_data.get().await
     }?;
 
-    let (artist_events, track_events) = tokio::join!(
-        crate::db_util::get_artist_timeline_events(&conn, user_id, start_day, end_day)
-            .map_err(crate::db_util::stringify_diesel_err),
-        crate::db_util::get_track_timeline_events(&conn_2, user_id, start_day, end_day)
-            .map_err(crate::db_util::stringify_diesel_err),
-    );
-    let (artist_events, track_events) = (artist_events?, track_events?);
-
-    let artist_ids = artist_events
-        .iter()
-        .map(|evt| evt.0.as_str())
-        .collect::<Vec<_>>();
-    let track_ids = track_events
-        .iter()
-        .map(|evt| evt.0.as_str())
-        .collect::<Vec<_>>();
-
-    // Join to artist/track metadata
-    let items = tokio::try_join!(
-        crate::spotify_api::fetch_artists(&spotify_access_token, &artist_ids),
-        crate::spotify_api::fetch_tracks(&spotify_access_token, &track_ids),
-    )?;
-    let (artists, tracks) = items;
-
-    let mut events = Vec::new();
-    let mut event_count = 0;
-    events.extend(artist_events.into_iter().zip(artists.into_iter()).map(
-        |((_artist_id, first_seen), artist)| {
-            event_count += 1;
-            TimelineEvent {
-                event_type: TimelineEventType::ArtistFirstSeen { artist },
-                date: first_seen.date(),
-                id: event_count,
-            }
-        },
-    ));
-    events.extend(track_events.into_iter().zip(tracks.into_iter()).map(
-        |((_track_id, first_seen), track)| {
-            event_count += 1;
-            TimelineEvent {
-                event_type: TimelineEventType::TopTrackFirstSeen { track },
-                date: first_seen.date(),
-                id: event_count,
-            }
-        },
-    ));
-
-    events.sort_unstable_by_key(|evt| evt.date);
-
-    Ok(Some(Json(Timeline { events })))
-}
-
-/// Redirects to the Spotify authorization page for the application
-#[get("/authorize?<playlist_perms>&<state>")]
-pub(crate) fn authorize(playlist_perms: Option<&str>, state: Option<&str>) -> Redirect {
-    let scopes = match playlist_perms {
-        None | Some("false") | Some("False") | Some("0") => "user-top-read",
-        _ => "user-top-read%20playlist-modify-public",
+    let artist_stats = match db_util::get_artist_stats(
+        &user,
+        conn,
+        &spotify_access_token,
+        None,
+        SnapshotFilter::default(),
+    )
+    .await?
+    {
+        Some(artist_stats) => artist_stats,
+        None => return Ok(None),
     };
-    let callback_uri = crate::conf::CONF.get_absolute_oauth_cb_uri();
 
-    Redirect::to(format!(
-        "https://accounts.spotify.com/authorize?client_id={}&response_type=code&redirect_uri={}&scope={}&state={}",
-        CONF.client_id,
-        callback_uri,
-        scopes,
-        RawStr::new(state.unwrap_or("")).percent_encode()
-    ))
+    Ok(Some(Conditional::new(
+        Json(GenreBreakdown {
+            last_update_time: user.localize(user.last_update_time),
+            genres: crate::stats::compute_genre_breakdown(&artist_stats),
+        }),
+        validators,
+    )))
 }
 
-/// The playlist will be generated on the account of user2
-async fn generate_shared_playlist(
-    conn1: DbConn,
-    conn2: DbConn,
-    conn3: DbConn,
-    conn4: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
-    bearer_token: &str,
-    user1: &str,
-    user2: &str,
-) -> Result<Option<Playlist>, String> {
-    let (user1_res, user2_res) = tokio::join!(
-        async move {
-            db_util::get_user_by_spotify_id(&conn1, user1.to_owned())
-                .await
-                .map(|user_opt| user_opt.map(|user| (user, conn1)))
-        },
-        async move {
-            db_util::get_user_by_spotify_id(&conn2, user2.to_owned())
-                .await
-                .map(|user_opt| user_opt.map(|user| (user, conn2)))
-        },
-    );
-    let (user1, conn1) = match user1_res? {
+/// Returns the genre entropy and artist concentration of each of the user's snapshots for each
+/// timeframe, so that users can see whether their taste is narrowing over time.
+#[get("/stats/<username>/diversity")]
+pub(crate) async fn get_diversity(
+    conn: DbConn,
+    username: String,
+    access_token: PrivateAccessToken,
+    conditional: ConditionalRequest,
+) -> Result<Option<Conditional<Json<DiversityHistory>>>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
         Some(user) => user,
         None => {
             return Ok(None);
         },
     };
-    let (mut user2, conn2) = match user2_res? {
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let validators = CacheValidators::for_user(&user);
+    if conditional.is_fresh(&validators) {
+        return Ok(Some(Conditional::not_modified(validators)));
+    }
+
+    let scores = db_util::get_diversity_scores(&conn, user.id).await?;
+    let mut history = TimeFrames::default();
+    for score in scores {
+        history.add_item(score.timeframe, DiversityScore {
+            update_time: user.localize(score.update_time),
+            genre_entropy: score.genre_entropy,
+            artist_gini: score.artist_gini,
+        });
+    }
+
+    Ok(Some(Conditional::new(
+        Json(DiversityHistory { history }),
+        validators,
+    )))
+}
+
+/// Default and max number of genres included in a genre timeline
+const GENRE_TIMELINE_DEFAULT_LIMIT: usize = 20;
+const GENRE_TIMELINE_MAX_LIMIT: usize = 100;
+
+/// Returns the weights of the user's top genres in each of their snapshots for a timeframe,
+/// defaulting to their preferred one.  Weights are stored when each snapshot is taken, so snapshots
+/// from before they were stored are left out.
+#[get("/stats/<username>/genres/timeline?<timeframe>&<limit>")]
+pub(crate) async fn get_genre_timeline(
+    conn: DbConn,
+    username: String,
+    access_token: PrivateAccessToken,
+    conditional: ConditionalRequest,
+    timeframe: Option<String>,
+    limit: Option<usize>,
+) -> Result<Option<Conditional<Json<GenreTimeline>>>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
         Some(user) => user,
-        None => {
-            return Ok(None);
-        },
+        None => return Ok(None),
     };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let validators = CacheValidators::for_user(&user);
+    if conditional.is_fresh(&validators) {
+        return Ok(Some(Conditional::not_modified(validators)));
+    }
 
-    let spotify_access_token = {
-        let token_data = &mut *(&*token_data).lock().await;
-        token_data.get().await
-    }?;
+    let settings = db_util::get_user_settings(&conn, &user).await?;
+    let timeframe = resolve_timeframe(timeframe, &settings)?;
+    let limit = limit
+        .unwrap_or(GENRE_TIMELINE_DEFAULT_LIMIT)
+        .clamp(1, GENRE_TIMELINE_MAX_LIMIT);
 
-    if let Some(res) = db_util::refresh_user_access_token(&conn1, &mut user2).await? {
-        error!("Error refreshing access token: {:?}", res);
-        return Err("Error refreshing access token".to_string());
-    }
+    let weights = db_util::get_genre_weights(&conn, user.id, timeframe).await?;
+    let weights: Vec<(NaiveDateTime, &str, u32)> = weights
+        .iter()
+        .map(|(update_time, genre, weight)| (*update_time, genre.as_str(), *weight))
+        .collect();
+    let (timestamps, genres) = crate::stats::build_genre_timeline(&weights, limit);
+
+    let genres = genres
+        .into_iter()
+        .filter_map(|(genre, weights)| {
+            let (peak_ix, peak_weight) = weights
+                .iter()
+                .enumerate()
+                .filter_map(|(i, weight)| weight.map(|weight| (i, weight)))
+                // The earliest snapshot wins ties
+                .max_by_key(|&(i, weight)| (weight, Reverse(i)))?;
+            Some(GenreTrend {
+                genre: genre.slug,
+                display_name: genre.display_name,
+                weights,
+                peak_weight,
+                peaked_at: user.localize(timestamps[peak_ix]),
+            })
+        })
+        .collect();
+
+    Ok(Some(Conditional::new(
+        Json(GenreTimeline {
+            timeframe,
+            timestamps: timestamps
+                .into_iter()
+                .map(|timestamp| user.localize(timestamp))
+                .collect(),
+            genres,
+        }),
+        validators,
+    )))
+}
+
+/// Returns the mean popularity of the user's top artists and tracks in each of their snapshots for
+/// each timeframe along with how it's trending, so that users can see whether their taste is
+/// getting more or less mainstream.  Popularity is stored when each snapshot is taken, so snapshots
+/// from before it was stored are left out.
+#[get("/stats/<username>/popularity_trends")]
+pub(crate) async fn get_popularity_trends(
+    conn: DbConn,
+    username: String,
+    access_token: PrivateAccessToken,
+    conditional: ConditionalRequest,
+) -> Result<Option<Conditional<Json<PopularityTrends>>>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let validators = CacheValidators::for_user(&user);
+    if conditional.is_fresh(&validators) {
+        return Ok(Some(Conditional::not_modified(validators)));
+    }
+
+    let rows = db_util::get_popularity_history(&conn, user.id).await?;
+    let artists_code = db_util::entity_type_code(ExportEntity::Artists);
+    // `(artist_popularity, track_popularity)` keyed by `(timeframe, update_time)`
+    let mut mean_popularities: BTreeMap<(Timeframe, NaiveDateTime), (Option<f32>, Option<f32>)> =
+        BTreeMap::new();
+    for row in rows {
+        if row.entity_count == 0 {
+            continue;
+        }
+        let mean = row.popularity_sum as f32 / row.entity_count as f32;
+        let entry = mean_popularities
+            .entry((row.timeframe, row.update_time))
+            .or_default();
+        if row.entity_type == artists_code {
+            entry.0 = Some(mean);
+        } else {
+            entry.1 = Some(mean);
+        }
+    }
+
+    let mut history = TimeFrames::default();
+    let mut trends = Vec::new();
+    for timeframe in Timeframe::ALL {
+        let points: Vec<(NaiveDateTime, Option<f32>, Option<f32>)> = mean_popularities
+            .range((timeframe, NaiveDateTime::MIN)..=(timeframe, NaiveDateTime::MAX))
+            .map(|(&(_, update_time), &(artists, tracks))| (update_time, artists, tracks))
+            .collect();
+        if points.is_empty() {
+            continue;
+        }
+
+        let trend = |get: fn(&(NaiveDateTime, Option<f32>, Option<f32>)) -> Option<f32>| {
+            let values: Vec<(NaiveDateTime, f32)> = points
+                .iter()
+                .filter_map(|point| get(point).map(|value| (point.0, value)))
+                .collect();
+            crate::stats::compute_linear_trend(&values)
+        };
+        trends.push(PopularityTrend {
+            timeframe,
+            artist_trend: trend(|point| point.1),
+            track_trend: trend(|point| point.2),
+        });
+        for (update_time, artist_popularity, track_popularity) in points {
+            history.add_item(timeframe, PopularityPoint {
+                update_time: user.localize(update_time),
+                artist_popularity,
+                track_popularity,
+            });
+        }
+    }
+
+    Ok(Some(Conditional::new(
+        Json(PopularityTrends { history, trends }),
+        validators,
+    )))
+}
+
+/// Computes the mainstream score of each snapshot's top artists and tracks for each timeframe as
+/// `(score, artist_score, track_score)`, keyed by `(timeframe, update_time)`
+fn compute_mainstream_scores(
+    rows: &[PopularityRankingRow],
+) -> BTreeMap<(Timeframe, NaiveDateTime), (f32, Option<f32>, Option<f32>)> {
+    let artists_code = db_util::entity_type_code(ExportEntity::Artists);
+    let mut rankings: BTreeMap<(Timeframe, NaiveDateTime), (Vec<(u8, u8)>, Vec<(u8, u8)>)> =
+        BTreeMap::new();
+    for &(update_time, timeframe, entity_type, ranking, popularity) in rows {
+        let (artists, tracks) = rankings.entry((timeframe, update_time)).or_default();
+        if entity_type == artists_code {
+            artists.push((ranking, popularity));
+        } else {
+            tracks.push((ranking, popularity));
+        }
+    }
+
+    rankings
+        .into_iter()
+        .filter_map(|(key, (artists, tracks))| {
+            let artist_score = crate::stats::compute_mainstream_score(artists);
+            let track_score = crate::stats::compute_mainstream_score(tracks);
+            let score = match (artist_score, track_score) {
+                (Some(artist_score), Some(track_score)) => (artist_score + track_score) / 2.,
+                (Some(score), None) | (None, Some(score)) => score,
+                (None, None) => return None,
+            };
+            Some((key, (score, artist_score, track_score)))
+        })
+        .collect()
+}
+
+/// Returns how mainstream the user's top artists and tracks are for each timeframe, based on their
+/// Spotify popularity scores, along with the scores of all of their past snapshots.
+#[get("/stats/<username>/mainstream")]
+pub(crate) async fn get_mainstream_score(
+    conn: DbConn,
+    username: String,
+    access_token: PrivateAccessToken,
+    conditional: ConditionalRequest,
+) -> Result<Option<Conditional<Json<MainstreamHistory>>>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let validators = CacheValidators::for_user(&user);
+    if conditional.is_fresh(&validators) {
+        return Ok(Some(Conditional::not_modified(validators)));
+    }
+
+    let rows = db_util::get_popularity_rankings(&conn, user.id).await?;
+    let mut current = HashMap::default();
+    let mut history = TimeFrames::default();
+    // Scores are ordered by timeframe and then by update time, so the last one for each timeframe
+    // is the current one
+    for ((timeframe, update_time), (score, artist_score, track_score)) in
+        compute_mainstream_scores(&rows)
+    {
+        current.insert(timeframe.name(), score);
+        history.add_item(timeframe, MainstreamScore {
+            update_time: user.localize(update_time),
+            score,
+            artist_score,
+            track_score,
+        });
+    }
+
+    Ok(Some(Conditional::new(
+        Json(MainstreamHistory { current, history }),
+        validators,
+    )))
+}
+
+/// Returns which of the user's short-term favorites have graduated into their medium or long-term
+/// top lists and which long-term favorites have fallen out of rotation, based on their most recent
+/// snapshot.
+#[get("/stats/<username>/crossover")]
+pub(crate) async fn get_crossover(
+    conn: DbConn,
+    conn_2: DbConn,
+    conn_3: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    username: String,
+    access_token: PrivateAccessToken,
+    conditional: ConditionalRequest,
+) -> Result<Option<Conditional<Json<Crossover>>>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let validators = CacheValidators::for_user(&user);
+    if conditional.is_fresh(&validators) {
+        return Ok(Some(Conditional::not_modified(validators)));
+    }
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+
+    let (artist_stats, track_stats) = tokio::try_join!(
+        db_util::get_artist_stats(
+            &user,
+            conn_2,
+            &spotify_access_token,
+            None,
+            SnapshotFilter::default()
+        ),
+        db_util::get_track_stats(
+            &user,
+            conn_3,
+            &spotify_access_token,
+            None,
+            SnapshotFilter::default()
+        ),
+    )?;
+    let (artist_stats, track_stats) = match (artist_stats, track_stats) {
+        (Some(artist_stats), Some(track_stats)) => (artist_stats, track_stats),
+        _ => return Ok(None),
+    };
+
+    Ok(Some(Conditional::new(
+        Json(Crossover {
+            artists: crate::stats::compute_timeframe_crossover(&artist_stats),
+            tracks: crate::stats::compute_timeframe_crossover(&track_stats),
+        }),
+        validators,
+    )))
+}
+
+/// Returns the average audio features of the user's current top tracks for each timeframe
+#[get("/stats/<username>/audio_features")]
+pub(crate) async fn get_audio_features(
+    conn: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    username: String,
+    access_token: PrivateAccessToken,
+    conditional: ConditionalRequest,
+) -> Result<Option<Conditional<Json<AudioFeaturesProfile>>>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let validators = CacheValidators::for_user(&user);
+    if conditional.is_fresh(&validators) {
+        return Ok(Some(Conditional::not_modified(validators)));
+    }
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+
+    let track_stats = match db_util::get_track_stats(
+        &user,
+        conn,
+        &spotify_access_token,
+        None,
+        SnapshotFilter::default(),
+    )
+    .await?
+    {
+        Some(track_stats) => track_stats,
+        None => return Ok(None),
+    };
+
+    let track_ids: Vec<&str> = track_stats
+        .iter()
+        .map(|(_timeframe, track)| track.id.as_str())
+        .collect();
+    let audio_features =
+        crate::spotify_api::fetch_audio_features(&spotify_access_token, &track_ids).await?;
+
+    let mut mood_by_timeframe = HashMap::default();
+    for timeframe in Timeframe::ALL {
+        let features_for_timeframe: Vec<&TrackAudioFeatures> = track_stats
+            .iter()
+            .zip(audio_features.iter())
+            .filter(|((track_timeframe, _track), _)| *track_timeframe == timeframe)
+            .filter_map(|(_, features)| features.as_ref())
+            .collect();
+        if let Some(mood) = crate::stats::compute_mood_profile(&features_for_timeframe) {
+            mood_by_timeframe.insert(timeframe.name(), mood);
+        }
+    }
+
+    Ok(Some(Conditional::new(
+        Json(AudioFeaturesProfile {
+            last_update_time: user.localize(user.last_update_time),
+            mood_by_timeframe,
+        }),
+        validators,
+    )))
+}
+
+/// Lists the update times of all snapshots available for the user, most recent first
+#[get("/stats/<username>/snapshots?<cursor>&<limit>")]
+pub(crate) async fn get_snapshots(
+    conn: DbConn,
+    username: String,
+    access_token: PrivateAccessToken,
+    cursor: Option<String>,
+    limit: Option<u32>,
+) -> Result<Option<Json<Paginated<LocalDateTime>>>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+
+    let pagination = CursorPagination::new(cursor.as_deref(), limit)?;
+    let update_times = db_util::get_snapshot_update_times_before(&conn, &user, pagination).await?;
+    Ok(Some(Json(
+        update_times.map(|update_time| user.localize(update_time)),
+    )))
+}
+
+/// Lists every artist that has appeared in the user's top artists along with when they first and
+/// last appeared and the best rank they reached, most recently discovered first.
+#[get("/stats/<username>/discoveries?<cursor>&<limit>")]
+pub(crate) async fn get_artist_discoveries(
+    conn: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    username: String,
+    access_token: PrivateAccessToken,
+    cursor: Option<String>,
+    limit: Option<u32>,
+) -> Result<Option<Json<Paginated<ArtistDiscovery>>>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+
+    let pagination = CursorPagination::new(cursor.as_deref(), limit)?;
+    let discoveries = db_util::get_artist_discoveries_page(&conn, &user, pagination).await?;
+
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+    let artist_ids = discoveries
+        .items
+        .iter()
+        .map(|(spotify_id, ..)| spotify_id.as_str())
+        .collect::<Vec<_>>();
+    let artists = crate::spotify_api::fetch_artists(&spotify_access_token, &artist_ids).await?;
+
+    Ok(Some(Json(discoveries.map(
+        |(spotify_id, first_seen, last_seen, peak_ranking)| ArtistDiscovery {
+            artist: artists[&spotify_id].clone(),
+            first_seen: user.localize(first_seen),
+            last_seen: user.localize(last_seen),
+            peak_rank: peak_ranking + 1,
+        },
+    ))))
+}
+
+/// Aggregates how often each artist or track appeared in the user's top list for `timeframe`
+/// (default the user's preferred timeframe) per ISO-8601 week or month, along with its average
+/// rank.  Requests without a `granularity` are handled by `get_timeline`.
+#[get("/stats/<username>/timeline?<granularity>&<entity>&<timeframe>")]
+pub(crate) async fn get_aggregated_timeline(
+    conn: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    username: String,
+    access_token: PrivateAccessToken,
+    granularity: String,
+    entity: Option<String>,
+    timeframe: Option<String>,
+) -> Result<Option<Json<AggregatedTimeline>>, Error> {
+    let granularity = TimelineGranularity::parse(&granularity).ok_or_else(|| {
+        Error::BadRequest(String::from(
+            "Invalid `granularity`; must be one of \"week\", \"month\"",
+        ))
+    })?;
+    let entity = match entity.as_deref() {
+        None => ExportEntity::Artists,
+        Some(entity) => ExportEntity::parse(entity).ok_or_else(|| {
+            Error::BadRequest(String::from(
+                "Invalid `entity`; must be one of \"artists\", \"tracks\"",
+            ))
+        })?,
+    };
+    let timeframe = timeframe
+        .as_deref()
+        .map(str::parse::<Timeframe>)
+        .transpose()?;
+
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let timeframe = match timeframe {
+        Some(timeframe) => timeframe,
+        None =>
+            db_util::get_user_settings(&conn, &user)
+                .await?
+                .default_timeframe,
+    };
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+
+    let history = db_util::get_full_rank_history(&conn, &user, entity).await?;
+    let periods = crate::stats::aggregate_rank_history(&history, timeframe, granularity);
+
+    let spotify_ids: Vec<&str> = periods
+        .iter()
+        .flat_map(|period| {
+            period
+                .rankings
+                .iter()
+                .map(|ranking| ranking.spotify_id.as_str())
+        })
+        .collect::<FnvHashSet<_>>()
+        .into_iter()
+        .collect();
+    let (artists_by_id, tracks_by_id) = match entity {
+        ExportEntity::Artists => (
+            fetch_artists(&spotify_access_token, &spotify_ids).await?,
+            HashMap::default(),
+        ),
+        ExportEntity::Tracks => (
+            HashMap::default(),
+            crate::spotify_api::fetch_tracks(&spotify_access_token, &spotify_ids).await?,
+        ),
+    };
+
+    Ok(Some(Json(AggregatedTimeline {
+        granularity,
+        periods,
+        tracks_by_id,
+        artists_by_id,
+    })))
+}
+
+/// Default number of entities included in chart data
+const DEFAULT_CHART_TOP_N: usize = 10;
+/// Max number of entities that can be included in chart data
+const MAX_CHART_TOP_N: usize = 50;
+
+/// Returns the rank history of the `top_n` artists or tracks that appeared in the most of the
+/// user's snapshots for a timeframe.  Each series has a point for every snapshot, with a null rank
+/// where the entity wasn't in it, so that the frontend can plot them without lining up the sparse
+/// history itself.
+#[get("/stats/<username>/chart_data?<entity>&<top_n>&<timeframe>")]
+pub(crate) async fn get_chart_data(
+    conn: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    username: String,
+    access_token: PrivateAccessToken,
+    entity: Option<String>,
+    top_n: Option<usize>,
+    timeframe: Option<String>,
+) -> Result<Option<Json<ChartData>>, Error> {
+    let entity = match entity.as_deref() {
+        None => ExportEntity::Artists,
+        Some(entity) => ExportEntity::parse(entity).ok_or_else(|| {
+            Error::BadRequest(String::from(
+                "Invalid `entity`; must be one of \"artists\", \"tracks\"",
+            ))
+        })?,
+    };
+    let top_n = top_n.unwrap_or(DEFAULT_CHART_TOP_N);
+    if top_n == 0 || top_n > MAX_CHART_TOP_N {
+        return Err(Error::BadRequest(format!(
+            "`top_n` must be between 1 and {}",
+            MAX_CHART_TOP_N
+        )));
+    }
+    let timeframe = timeframe
+        .as_deref()
+        .map(str::parse::<Timeframe>)
+        .transpose()?;
+
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let timeframe = match timeframe {
+        Some(timeframe) => timeframe,
+        None =>
+            db_util::get_user_settings(&conn, &user)
+                .await?
+                .default_timeframe,
+    };
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+
+    let history = db_util::get_full_rank_history(&conn, &user, entity).await?;
+    let (snapshot_times, series) =
+        crate::stats::build_dense_rank_series(&history, timeframe, top_n);
+
+    let spotify_ids: Vec<&str> = series.iter().map(|series| series.spotify_id).collect();
+    let (artists_by_id, tracks_by_id) = match entity {
+        ExportEntity::Artists => (
+            fetch_artists(&spotify_access_token, &spotify_ids).await?,
+            HashMap::default(),
+        ),
+        ExportEntity::Tracks => (
+            HashMap::default(),
+            crate::spotify_api::fetch_tracks(&spotify_access_token, &spotify_ids).await?,
+        ),
+    };
+
+    let snapshot_times: Vec<LocalDateTime> = snapshot_times
+        .into_iter()
+        .map(|snapshot_time| user.localize(snapshot_time))
+        .collect();
+    let series = series
+        .into_iter()
+        .map(|series| ChartSeries {
+            spotify_id: series.spotify_id.to_owned(),
+            appearance_count: series.appearance_count,
+            points: snapshot_times
+                .iter()
+                .zip(series.ranks)
+                .map(|(snapshot_time, rank)| ChartPoint {
+                    snapshot_time: *snapshot_time,
+                    rank,
+                })
+                .collect(),
+        })
+        .collect();
+
+    Ok(Some(Json(ChartData {
+        timeframe,
+        series,
+        tracks_by_id,
+        artists_by_id,
+    })))
+}
+
+#[get("/stats/<username>/timeline?<start_day_id>&<end_day_id>", rank = 2)]
+pub(crate) async fn get_timeline(
+    conn: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    conn_2: DbConn,
+    username: String,
+    access_token: PrivateAccessToken,
+    start_day_id: String,
+    end_day_id: String,
+) -> Result<Option<Json<Timeline>>, Error> {
+    let start_day = NaiveDateTime::parse_from_str(
+        &format!("{}T08:00:00+08:00", start_day_id),
+        "%Y-%m-%dT%H:%M:%S%z",
+    )
+    .map_err(|_| Error::BadRequest(String::from("Invalid `start_day_id` provided")))?;
+    let end_day = NaiveDateTime::parse_from_str(
+        &format!("{}T08:00:00+08:00", end_day_id),
+        "%Y-%m-%dT%H:%M:%S%z",
+    )
+    .map_err(|_| Error::BadRequest(String::from("Invalid `end_day_id` provided")))?;
+
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let user_id = user.id;
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+
+    let (artist_events, track_events) = tokio::join!(
+        crate::db_util::get_artist_timeline_events(&conn, user_id, start_day, end_day),
+        crate::db_util::get_track_timeline_events(&conn_2, user_id, start_day, end_day),
+    );
+    let (artist_events, track_events) = (artist_events?, track_events?);
+
+    let artist_ids = artist_events
+        .iter()
+        .map(|evt| evt.0.as_str())
+        .collect::<Vec<_>>();
+    let track_ids = track_events
+        .iter()
+        .map(|evt| evt.0.as_str())
+        .collect::<Vec<_>>();
+
+    // Join to artist/track metadata
+    let items = tokio::try_join!(
+        crate::spotify_api::fetch_artists(&spotify_access_token, &artist_ids),
+        crate::spotify_api::fetch_tracks(&spotify_access_token, &track_ids),
+    )?;
+    let (artists, tracks) = items;
+
+    let mut events = Vec::new();
+    let mut event_count = 0;
+    events.extend(artist_events.into_iter().map(|(artist_id, first_seen)| {
+        event_count += 1;
+        TimelineEvent {
+            event_type: TimelineEventType::ArtistFirstSeen {
+                artist: artists[&artist_id].clone(),
+            },
+            date: first_seen.date(),
+            id: event_count,
+        }
+    }));
+    events.extend(track_events.into_iter().map(|(track_id, first_seen)| {
+        event_count += 1;
+        TimelineEvent {
+            event_type: TimelineEventType::TopTrackFirstSeen {
+                track: tracks[&track_id].clone(),
+            },
+            date: first_seen.date(),
+            id: event_count,
+        }
+    }));
+
+    events.sort_unstable_by_key(|evt| evt.date);
+
+    Ok(Some(Json(Timeline { events })))
+}
+
+/// Lists the user's recently played tracks, most recent first
+#[get("/stats/<username>/recently_played?<cursor>&<limit>")]
+pub(crate) async fn get_recently_played(
+    conn: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    username: String,
+    access_token: PrivateAccessToken,
+    cursor: Option<String>,
+    limit: Option<u32>,
+) -> Result<Option<Json<Paginated<RecentlyPlayedItem>>>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let user_id = user.id;
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+
+    let pagination = CursorPagination::new(cursor.as_deref(), limit)?;
+    let plays = db_util::get_recently_played(&conn, user_id, pagination).await?;
+
+    let track_ids = plays
+        .items
+        .iter()
+        .map(|(spotify_id, _)| spotify_id.as_str())
+        .collect::<Vec<_>>();
+    let tracks = crate::spotify_api::fetch_tracks(&spotify_access_token, &track_ids).await?;
+
+    Ok(Some(Json(plays.map(|(track_id, played_at)| {
+        RecentlyPlayedItem {
+            track: tracks[&track_id].clone(),
+            played_at,
+        }
+    }))))
+}
+
+/// Returns the artists that the user has followed and unfollowed over time, most recent first
+#[get("/stats/<username>/follows")]
+pub(crate) async fn get_follows(
+    conn: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    username: String,
+    access_token: PrivateAccessToken,
+) -> Result<Option<Json<FollowHistory>>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let user_id = user.id;
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+
+    let follows = db_util::get_follow_history(&conn, user_id).await?;
+
+    let artist_ids = follows
+        .iter()
+        .map(|(spotify_id, ..)| spotify_id.as_str())
+        .collect::<Vec<_>>();
+    let artists = crate::spotify_api::fetch_artists(&spotify_access_token, &artist_ids).await?;
+
+    let mut events = Vec::with_capacity(follows.len());
+    for (artist_id, followed_at, unfollowed_at) in follows {
+        let artist = artists[&artist_id].clone();
+        if let Some(unfollowed_at) = unfollowed_at {
+            events.push(FollowEvent {
+                artist: artist.clone(),
+                kind: FollowEventKind::Unfollow,
+                timestamp: unfollowed_at,
+            });
+        }
+        events.push(FollowEvent {
+            artist,
+            kind: FollowEventKind::Follow,
+            timestamp: followed_at,
+        });
+    }
+    events.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+
+    Ok(Some(Json(FollowHistory { events })))
+}
+
+/// Returns the size of the user's saved tracks library over time along with their most recently
+/// saved tracks.  Only the user can see it unless they've enabled `library_public` in their
+/// settings, in which case anyone that can view their stats can.
+#[get("/stats/<username>/library")]
+pub(crate) async fn get_library(
+    conn: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    username: String,
+    access_token: PrivateAccessToken,
+    current_user: Option<CurrentUser>,
+) -> Result<Option<Json<LibraryHistory>>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+    let is_owner = current_user
+        .map(|current_user| current_user.spotify_id == user.spotify_id)
+        .unwrap_or(false);
+    if !is_owner {
+        if !access_token.grants_access_to(&user) {
+            return Ok(None);
+        }
+        let settings = db_util::get_user_settings(&conn, &user).await?;
+        if !settings.library_public {
+            return Ok(None);
+        }
+    }
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+
+    let history = db_util::get_library_history(&conn, user.id).await?;
+    let saves =
+        db_util::get_newest_saved_tracks(&conn, user.id, LIBRARY_NEWEST_SAVES_COUNT).await?;
+
+    let track_ids = saves
+        .iter()
+        .map(|(spotify_id, _)| spotify_id.as_str())
+        .collect::<Vec<_>>();
+    let tracks = crate::spotify_api::fetch_tracks(&spotify_access_token, &track_ids).await?;
+
+    Ok(Some(Json(LibraryHistory {
+        history: history
+            .into_iter()
+            .map(|(update_time, saved_track_count)| LibrarySize {
+                update_time: user.localize(update_time),
+                saved_track_count,
+            })
+            .collect(),
+        newest_saves: saves
+            .into_iter()
+            .map(|(track_id, added_at)| SavedTrack {
+                track: tracks[&track_id].clone(),
+                added_at: user.localize(added_at),
+            })
+            .collect(),
+    })))
+}
+
+/// Streams CSV rows to the client as a file download
+pub(crate) struct CsvExportResponder {
+    rows: Vec<String>,
+    filename: String,
+}
+
+impl<'r> Responder<'r, 'r> for CsvExportResponder {
+    fn respond_to(self, req: &'r rocket::Request<'_>) -> rocket::response::Result<'r> {
+        Response::build_from(TextStream(futures::stream::iter(self.rows)).respond_to(req)?)
+            .header(ContentType::CSV)
+            .header(Header::new(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", self.filename),
+            ))
+            .ok()
+    }
+}
+
+/// Exports the user's full artist or track ranking history as CSV
+#[get("/stats/<username>/export.csv?<entity>")]
+pub(crate) async fn export_rank_history_csv(
+    conn: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    username: String,
+    access_token: PrivateAccessToken,
+    entity: &str,
+) -> Result<Option<CsvExportResponder>, Error> {
+    let entity = ExportEntity::parse(entity).ok_or_else(|| {
+        Error::BadRequest(String::from(
+            "Invalid `entity` provided; must be `tracks` or `artists`",
+        ))
+    })?;
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+
+    let history = db_util::get_full_rank_history(&conn, &user, entity).await?;
+
+    let unique_spotify_ids: Vec<&str> = history
+        .iter()
+        .map(|(_, _, _, spotify_id)| spotify_id.as_str())
+        .collect::<FnvHashSet<_>>()
+        .into_iter()
+        .collect();
+    let names_by_spotify_id: HashMap<String, String> = match entity {
+        ExportEntity::Artists =>
+            crate::spotify_api::fetch_artists(&spotify_access_token, &unique_spotify_ids)
+                .await?
+                .into_iter()
+                .map(|(id, artist)| (id, artist.name))
+                .collect(),
+        ExportEntity::Tracks =>
+            crate::spotify_api::fetch_tracks(&spotify_access_token, &unique_spotify_ids)
+                .await?
+                .into_iter()
+                .map(|(id, track)| (id, track.name))
+                .collect(),
+    };
+
+    let mut rows = Vec::with_capacity(history.len() + 1);
+    rows.push(crate::export::RANK_HISTORY_CSV_HEADER.to_owned());
+    for (update_time, timeframe, ranking, spotify_id) in &history {
+        rows.push(crate::export::format_rank_history_csv_row(
+            *update_time,
+            *timeframe,
+            *ranking,
+            &names_by_spotify_id[spotify_id.as_str()],
+            spotify_id,
+        ));
+    }
+
+    let filename = match entity {
+        ExportEntity::Artists => "artists",
+        ExportEntity::Tracks => "tracks",
+    };
+    Ok(Some(CsvExportResponder {
+        rows,
+        filename: format!("{}-{}.csv", user.spotify_id, filename),
+    }))
+}
+
+/// The Spotify ID of the user making the request.  Users are identified by the session cookie
+/// issued by `oauth_cb` if it's present, and otherwise by a Spotify access token belonging to them
+/// supplied via an `Authorization: Bearer <token>` header.  Tokens are verified with Spotify and
+/// then cached briefly; see `session`.
+///
+/// The `Authorization` header is shared by three guards, which tell its bearer tokens apart as
+/// follows:
+///  - `ApiKey` handles tokens starting with `API_KEY_PREFIX`
+///  - `AdminToken` handles the admin API token
+///  - `CurrentUser` treats any other token as a Spotify access token.  API keys and the admin token
+///    are rejected without being sent to Spotify.
+pub(crate) struct CurrentUser {
+    pub spotify_id: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CurrentUser {
+    type Error = Error;
+
+    async fn from_request(req: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        if let Some(spotify_id) = crate::session::get_session_user(req.cookies()) {
+            return Outcome::Success(CurrentUser { spotify_id });
+        }
+
+        let token = match req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "))
+        {
+            Some(token) => token,
+            None =>
+                return Outcome::Failure((
+                    Status::Unauthorized,
+                    Error::Unauthorized("Missing session cookie or bearer token".into()),
+                )),
+        };
+        if token.starts_with(crate::api_keys::API_KEY_PREFIX)
+            || tokens_match(token, &CONF.admin_api_token)
+        {
+            return Outcome::Failure((
+                Status::Unauthorized,
+                Error::Unauthorized("Invalid token supplied".into()),
+            ));
+        }
+        if let Some(spotify_id) = crate::session::get_verified_token_user(token) {
+            return Outcome::Success(CurrentUser { spotify_id });
+        }
+
+        match crate::spotify_api::get_user_profile_info(token).await {
+            Ok(profile) => {
+                crate::session::cache_verified_token_user(token, profile.id.clone());
+                Outcome::Success(CurrentUser {
+                    spotify_id: profile.id,
+                })
+            },
+            Err(_) => Outcome::Failure((
+                Status::Unauthorized,
+                Error::Unauthorized("Invalid token supplied".into()),
+            )),
+        }
+    }
+}
+
+impl CurrentUser {
+    /// Checks that the request was made by the provided user
+    fn verify(&self, user: &User) -> Result<(), Error> {
+        if self.spotify_id != user.spotify_id {
+            return Err(Error::Forbidden("Not authenticated as this user".into()));
+        }
+        Ok(())
+    }
+}
+
+/// Proof that the request supplied the admin API token via an `Authorization: Bearer <token>`
+/// header.  Requests without it are rejected with 401 Unauthorized before reaching the handler.
+/// See `CurrentUser` for the other guards that read the header.
+pub(crate) struct AdminToken;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminToken {
+    type Error = Error;
+
+    async fn from_request(req: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        match req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "))
+        {
+            Some(token) if tokens_match(token, &CONF.admin_api_token) =>
+                Outcome::Success(AdminToken),
+            _ => Outcome::Failure((
+                Status::Unauthorized,
+                Error::Unauthorized("Invalid API token supplied".into()),
+            )),
+        }
+    }
+}
+
+/// Compares a supplied secret against the expected one in constant time so that response timing
+/// doesn't reveal how much of it was correct
+fn tokens_match(supplied: &str, expected: &str) -> bool {
+    bool::from(supplied.as_bytes().ct_eq(expected.as_bytes()))
+}
+
+const PRIVATE_TOKEN_HEADER_NAME: &str = "X-Private-Token";
+
+/// A user's private token, optionally supplied via the `X-Private-Token` header in order to view
+/// the stats of a user whose profile is private
+pub(crate) struct PrivateAccessToken(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PrivateAccessToken {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(PrivateAccessToken(
+            req.headers()
+                .get_one(PRIVATE_TOKEN_HEADER_NAME)
+                .map(String::from),
+        ))
+    }
+}
+
+impl PrivateAccessToken {
+    /// Private users' stats are treated as not existing unless their private token is supplied.
+    /// Deactivated users' stats are treated as not existing regardless.
+    pub(crate) fn grants_access_to(&self, user: &User) -> bool {
+        if user.deactivated_at.is_some() {
+            return false;
+        }
+        if !user.is_private {
+            return true;
+        }
+
+        match (&self.0, &user.private_token) {
+            (Some(supplied_token), Some(private_token)) =>
+                tokens_match(supplied_token, private_token),
+            _ => false,
+        }
+    }
+
+    /// Drops the users whose stats can't be viewed with this token.  Linked accounts are filtered
+    /// with this before being merged so that a private or deactivated account's rankings aren't
+    /// exposed through the user it's linked to.
+    pub(crate) fn accessible_users(&self, users: Vec<User>) -> Vec<User> {
+        users
+            .into_iter()
+            .filter(|user| self.grants_access_to(user))
+            .collect()
+    }
+}
+
+#[derive(Responder)]
+#[response(status = 200, content_type = "application/json")]
+pub(crate) struct UserDataExportResponder {
+    inner: Json<UserDataExport>,
+    content_disposition: Header<'static>,
+}
+
+/// Exports everything stored for the user as a single JSON document.  Users can be looked up by
+/// either their vanity slug or Spotify ID.  Requests must be authenticated as the user being
+/// exported.
+#[get("/export/<username>")]
+pub(crate) async fn export_user_data(
+    conn: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    current_user: CurrentUser,
+    username: String,
+) -> Result<Option<UserDataExportResponder>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+
+    let export = crate::export::build_user_data_export(&conn, &user, &spotify_access_token).await?;
+    Ok(Some(UserDataExportResponder {
+        inner: Json(export),
+        content_disposition: Header::new(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}.json\"", user.spotify_id),
+        ),
+    }))
+}
+
+/// Makes the user's stats private or public.  Requests must be authenticated with a Spotify access
+/// token belonging to the user.  Returns the private token needed to view the user's stats.
+#[put("/users/<username>/privacy", data = "<settings>")]
+pub(crate) async fn set_privacy(
+    conn: DbConn,
+    current_user: CurrentUser,
+    username: String,
+    settings: Json<PrivacySettingsRequest>,
+) -> Result<Option<Json<PrivacySettings>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    let private_token = db_util::set_user_privacy(&conn, &user, settings.is_private).await?;
+    Ok(Some(Json(PrivacySettings {
+        is_private: settings.is_private,
+        private_token,
+    })))
+}
+
+/// Deactivates or reactivates the user.  Deactivated users aren't updated and their stats aren't
+/// served until they're reactivated.  Requests must be authenticated with a Spotify access token
+/// belonging to the user.
+#[put("/users/<username>/deactivation", data = "<request>")]
+pub(crate) async fn set_deactivation(
+    conn: DbConn,
+    current_user: CurrentUser,
+    username: String,
+    request: Json<DeactivationRequest>,
+) -> Result<Option<Json<DeactivationStatus>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    let deactivated_at = db_util::set_user_deactivated(&conn, &user, request.deactivated).await?;
+    if let Err(err) = block_in_place(|| invalidate_cached_snapshots(user.id)) {
+        warn!("Error invalidating cached stats snapshots: {}", err);
+    }
+    info!(
+        "{} user {}",
+        if request.deactivated {
+            "Deactivated"
+        } else {
+            "Reactivated"
+        },
+        user.spotify_id
+    );
+
+    Ok(Some(Json(DeactivationStatus {
+        deactivated_at: deactivated_at.map(|time| user.localize(time)),
+    })))
+}
+
+fn build_email_subscription_status(
+    user: &User,
+    subscription: Option<EmailSubscription>,
+) -> EmailSubscriptionStatus {
+    EmailSubscriptionStatus {
+        email: subscription.as_ref().map(|sub| sub.email.clone()),
+        confirmed: subscription
+            .as_ref()
+            .map_or(false, |sub| sub.confirmed_at.is_some()),
+        last_sent_at: subscription
+            .and_then(|sub| sub.last_sent_at)
+            .map(|time| user.localize(time)),
+    }
+}
+
+/// Returns the user's weekly digest email subscription.  Requests must be authenticated as the
+/// user.
+#[get("/users/<username>/email_subscription")]
+pub(crate) async fn get_email_subscription(
+    conn: DbConn,
+    current_user: CurrentUser,
+    username: String,
+) -> Result<Option<Json<EmailSubscriptionStatus>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    let subscription = db_util::get_email_subscription(&conn, user.id).await?;
+    Ok(Some(Json(build_email_subscription_status(
+        &user,
+        subscription,
+    ))))
+}
+
+/// Subscribes the user to a weekly digest of their stats sent to the provided email address,
+/// replacing any existing subscription.  A confirmation link is emailed to the address, and digests
+/// are only sent once it has been followed.  Subscribing again with an already confirmed address
+/// leaves the subscription as-is.  Requests must be authenticated as the user.
+#[put("/users/<username>/email_subscription", data = "<request>")]
+pub(crate) async fn set_email_subscription(
+    conn: DbConn,
+    current_user: CurrentUser,
+    username: String,
+    request: Json<EmailSubscriptionRequest>,
+) -> Result<Option<Json<EmailSubscriptionStatus>>, Error> {
+    if !crate::digest::is_enabled() {
+        return Err(Error::BadRequest(
+            "Email digests aren't enabled on this server".into(),
+        ));
+    }
+    let email = request.into_inner().email.trim().to_owned();
+    if email.len() > 254 || !crate::email::is_valid_address(&email) {
+        return Err(Error::BadRequest("Invalid email address".into()));
+    }
+
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    let existing = db_util::get_email_subscription(&conn, user.id).await?;
+    if let Some(existing) = existing {
+        if existing.email == email && existing.confirmed_at.is_some() {
+            return Ok(Some(Json(build_email_subscription_status(
+                &user,
+                Some(existing),
+            ))));
+        }
+    }
+
+    let subscription = db_util::set_email_subscription(&conn, user.id, email).await?;
+    if let Some(confirmation_token) = &subscription.confirmation_token {
+        crate::digest::send_confirmation_email(&subscription.email, confirmation_token)
+            .await
+            .map_err(|err| {
+                error!(
+                    "Error sending digest confirmation email for user {}: {}",
+                    user.spotify_id, err
+                );
+                Error::Internal("Error sending confirmation email".into())
+            })?;
+    }
+    Ok(Some(Json(build_email_subscription_status(
+        &user,
+        Some(subscription),
+    ))))
+}
+
+/// Target of the confirmation link emailed when subscribing to the weekly digest
+#[get("/email/confirm?<token>")]
+pub(crate) async fn confirm_email_subscription(
+    conn: DbConn,
+    token: String,
+) -> Result<String, Error> {
+    let created_after = Utc::now().naive_utc() - crate::digest::confirmation_link_lifetime();
+    let confirmed_count = db_util::confirm_email_subscription(&conn, token, created_after).await?;
+    if confirmed_count == 0 {
+        return Err(Error::NotFound(
+            "This confirmation link is invalid, expired, or has already been used".into(),
+        ));
+    }
+    Ok(
+        "Your email address has been confirmed.  You'll receive a digest of your stats weekly."
+            .into(),
+    )
+}
+
+/// Unsubscribes the user from the weekly digest.  Requests must be authenticated as the user.
+#[delete("/users/<username>/email_subscription")]
+pub(crate) async fn delete_email_subscription(
+    conn: DbConn,
+    current_user: CurrentUser,
+    username: String,
+) -> Result<Option<Json<EmailSubscriptionStatus>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    db_util::delete_email_subscription(&conn, user.id).await?;
+    Ok(Some(Json(build_email_subscription_status(&user, None))))
+}
+
+/// Target of the unsubscribe link included in each weekly digest, so that users can unsubscribe
+/// without signing in
+#[get("/email/unsubscribe?<token>")]
+pub(crate) async fn unsubscribe_email(conn: DbConn, token: String) -> Result<String, Error> {
+    let deleted_count = db_util::delete_email_subscription_by_token(&conn, token).await?;
+    if deleted_count == 0 {
+        return Err(Error::NotFound(
+            "This unsubscribe link is invalid or has already been used".into(),
+        ));
+    }
+    Ok("You've been unsubscribed from Spotifytrack emails.".into())
+}
+
+fn build_friend(other_user: &User, display_name: Option<String>, since: NaiveDateTime) -> Friend {
+    Friend {
+        spotify_id: other_user.spotify_id.clone(),
+        vanity_slug: other_user.vanity_slug.clone(),
+        display_name: display_name.unwrap_or_else(|| other_user.username.clone()),
+        since,
+    }
+}
+
+async fn build_friend_list(conn: &DbConn, user: &User) -> Result<FriendList, Error> {
+    let mut friend_list = FriendList {
+        friends: Vec::new(),
+        incoming_requests: Vec::new(),
+        outgoing_requests: Vec::new(),
+    };
+    for (other_user, display_name, requested_at, accepted_at, is_incoming) in
+        db_util::get_friendships(conn, user).await?
+    {
+        match (accepted_at, is_incoming) {
+            (Some(accepted_at), _) =>
+                friend_list
+                    .friends
+                    .push(build_friend(&other_user, display_name, accepted_at)),
+            (None, true) => friend_list.incoming_requests.push(build_friend(
+                &other_user,
+                display_name,
+                requested_at,
+            )),
+            (None, false) => friend_list.outgoing_requests.push(build_friend(
+                &other_user,
+                display_name,
+                requested_at,
+            )),
+        }
+    }
+    Ok(friend_list)
+}
+
+/// Returns the user's friends and pending friend requests.  Requests must be authenticated as the
+/// user.
+#[get("/users/<username>/friends")]
+pub(crate) async fn get_friends(
+    conn: DbConn,
+    current_user: CurrentUser,
+    username: String,
+) -> Result<Option<Json<FriendList>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    Ok(Some(Json(build_friend_list(&conn, &user).await?)))
+}
+
+/// Sends a friend request from the user to another user.  If the other user has already sent the
+/// user a request, it's accepted instead.  Requests must be authenticated as the user.
+#[post("/users/<username>/friend_requests/<friend_username>")]
+pub(crate) async fn send_friend_request(
+    conn: DbConn,
+    current_user: CurrentUser,
+    username: String,
+    friend_username: String,
+) -> Result<Option<Json<FriendList>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    let friend =
+        match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, friend_username).await? {
+            Some(friend) if friend.deactivated_at.is_none() => friend,
+            _ => {
+                return Ok(None);
+            },
+        };
+    if friend.id == user.id {
+        return Err(Error::BadRequest(String::from(
+            "Users can't befriend themselves",
+        )));
+    }
+
+    match db_util::get_friendship(&conn, &user, &friend).await? {
+        None => {
+            db_util::request_friendship(&conn, &user, &friend).await?;
+        },
+        Some((requester_id, None)) if requester_id == friend.id => {
+            db_util::accept_friendship(&conn, &friend, &user).await?;
+        },
+        Some((_, None)) => {
+            return Err(Error::BadRequest(String::from(
+                "A friend request has already been sent to this user",
+            )));
+        },
+        Some((_, Some(_))) => {
+            return Err(Error::BadRequest(String::from(
+                "The users are already friends",
+            )));
+        },
+    }
+
+    Ok(Some(Json(build_friend_list(&conn, &user).await?)))
+}
+
+/// Accepts a pending friend request sent to the user.  Requests must be authenticated as the user.
+#[post("/users/<username>/friend_requests/<friend_username>/accept")]
+pub(crate) async fn accept_friend_request(
+    conn: DbConn,
+    current_user: CurrentUser,
+    username: String,
+    friend_username: String,
+) -> Result<Option<Json<FriendList>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    let friend =
+        match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, friend_username).await? {
+            Some(friend) => friend,
+            None => {
+                return Ok(None);
+            },
+        };
+    let accepted_count = db_util::accept_friendship(&conn, &friend, &user).await?;
+    if accepted_count == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(Json(build_friend_list(&conn, &user).await?)))
+}
+
+/// Removes a friend, or declines or cancels a pending friend request.  Requests must be
+/// authenticated as the user.
+#[delete("/users/<username>/friends/<friend_username>")]
+pub(crate) async fn remove_friend(
+    conn: DbConn,
+    current_user: CurrentUser,
+    username: String,
+    friend_username: String,
+) -> Result<Option<Json<FriendList>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    let friend =
+        match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, friend_username).await? {
+            Some(friend) => friend,
+            None => {
+                return Ok(None);
+            },
+        };
+    let removed_count = db_util::delete_friendship(&conn, &user, &friend).await?;
+    if removed_count == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(Json(build_friend_list(&conn, &user).await?)))
+}
+
+/// Max number of friends included in the friends feed, most recently updated first
+const FRIENDS_FEED_MAX_FRIENDS: usize = 25;
+
+/// Summarizes how each of the user's friends' top artists and tracks changed over their most
+/// recent week, for each friend's preferred timeframe.  Private and deactivated friends aren't
+/// included.  Requests must be authenticated as the user.
+#[get("/stats/<username>/friends_feed")]
+pub(crate) async fn get_friends_feed(
+    conn: DbConn,
+    current_user: CurrentUser,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    username: String,
+) -> Result<Option<Json<FriendsFeed>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    let mut friends: Vec<(User, Option<String>, NaiveDateTime)> =
+        db_util::get_friendships(&conn, &user)
+            .await?
+            .into_iter()
+            .filter_map(|(friend, display_name, _, accepted_at, _)| {
+                accepted_at.map(|accepted_at| (friend, display_name, accepted_at))
+            })
+            .filter(|(friend, ..)| !friend.is_private && friend.deactivated_at.is_none())
+            .collect();
+    friends.sort_by_key(|(friend, ..)| Reverse(friend.last_update_time));
+    friends.truncate(FRIENDS_FEED_MAX_FRIENDS);
+
+    let mut histories = Vec::with_capacity(friends.len());
+    for (friend, ..) in &friends {
+        let settings = db_util::get_user_settings(&conn, friend).await?;
+        let artist_history =
+            db_util::get_full_rank_history(&conn, friend, ExportEntity::Artists).await?;
+        let track_history =
+            db_util::get_full_rank_history(&conn, friend, ExportEntity::Tracks).await?;
+        histories.push((settings.default_timeframe, artist_history, track_history));
+    }
+
+    // `(friend_index, timeframe, update_time, artist_changes, track_changes)`
+    let mut highlights = Vec::new();
+    for (i, (timeframe, artist_history, track_history)) in histories.iter().enumerate() {
+        let artist_changes =
+            crate::stats::latest_weekly_changes(artist_history, *timeframe, FEED_MAX_CHANGES);
+        let track_changes =
+            crate::stats::latest_weekly_changes(track_history, *timeframe, FEED_MAX_CHANGES);
+        let update_time = match artist_changes
+            .iter()
+            .chain(&track_changes)
+            .map(|(update_time, _)| *update_time)
+            .max()
+        {
+            Some(update_time) => update_time,
+            None => continue,
+        };
+        let artist_changes = artist_changes.map(|(_, changes)| changes);
+        let track_changes = track_changes.map(|(_, changes)| changes);
+        if artist_changes
+            .iter()
+            .chain(&track_changes)
+            .all(|changes| changes.is_empty())
+        {
+            continue;
+        }
+        highlights.push((i, *timeframe, update_time, artist_changes, track_changes));
+    }
+
+    let artist_ids: Vec<&str> = highlights
+        .iter()
+        .flat_map(|(_, _, _, artist_changes, _)| {
+            artist_changes.iter().flat_map(RankingChanges::spotify_ids)
+        })
+        .collect::<FnvHashSet<_>>()
+        .into_iter()
+        .collect();
+    let track_ids: Vec<&str> = highlights
+        .iter()
+        .flat_map(|(_, _, _, _, track_changes)| {
+            track_changes.iter().flat_map(RankingChanges::spotify_ids)
+        })
+        .collect::<FnvHashSet<_>>()
+        .into_iter()
+        .collect();
+
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+    let artist_names_by_id: HashMap<String, String> =
+        fetch_artists(&spotify_access_token, &artist_ids)
+            .await?
+            .into_iter()
+            .map(|(id, artist)| (id, artist.name))
+            .collect();
+    let track_names_by_id: HashMap<String, String> =
+        crate::spotify_api::fetch_tracks(&spotify_access_token, &track_ids)
+            .await?
+            .into_iter()
+            .map(|(id, track)| {
+                let name = match track.artists.first() {
+                    Some(artist) => format!("{} by {}", track.name, artist.name),
+                    None => track.name,
+                };
+                (id, name)
+            })
+            .collect();
+
+    let mut items: Vec<FriendsFeedItem> = highlights
+        .iter()
+        .map(
+            |(i, timeframe, update_time, artist_changes, track_changes)| {
+                let (friend, display_name, accepted_at) = &friends[*i];
+                FriendsFeedItem {
+                    friend: build_friend(friend, display_name.clone(), *accepted_at),
+                    update_time: user.localize(*update_time),
+                    timeframe: *timeframe,
+                    artist_highlights: artist_changes
+                        .as_ref()
+                        .map(|changes| changes.describe(&artist_names_by_id))
+                        .unwrap_or_default(),
+                    track_highlights: track_changes
+                        .as_ref()
+                        .map(|changes| changes.describe(&track_names_by_id))
+                        .unwrap_or_default(),
+                }
+            },
+        )
+        .collect();
+    items.sort_by_key(|item| Reverse(item.update_time));
+
+    Ok(Some(Json(FriendsFeed { items })))
+}
+
+/// Returns an error describing why the slug is invalid, if it is
+fn validate_vanity_slug(slug: &str) -> Result<(), Error> {
+    if !(3..=32).contains(&slug.len()) {
+        return Err(Error::BadRequest(String::from(
+            "`vanity_slug` must be between 3 and 32 characters",
+        )));
+    }
+    if !slug
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+    {
+        return Err(Error::BadRequest(String::from(
+            "`vanity_slug` may only contain lowercase letters, digits, `-`, and `_`",
+        )));
+    }
+    Ok(())
+}
+
+fn build_user_settings(
+    user: &User,
+    settings: &UserSettingsEntry,
+    profile_view_count: i64,
+) -> UserSettings {
+    UserSettings {
+        timezone: user.tz().name().to_owned(),
+        vanity_slug: user.vanity_slug.clone(),
+        display_name: settings.display_name(user).to_owned(),
+        is_private: user.is_private,
+        private_token: user.private_token.clone().filter(|_| user.is_private),
+        default_timeframe: settings.default_timeframe,
+        update_frequency: UpdateFrequency::from_id(settings.update_frequency),
+        entity_fetch_count: settings.entity_fetch_count(),
+        leaderboard_opt_in: settings.leaderboard_opt_in,
+        friends_only_comparisons: settings.friends_only_comparisons,
+        now_playing_public: settings.now_playing_public,
+        library_public: settings.library_public,
+        profile_view_count: profile_view_count.max(0) as u64,
+    }
+}
+
+/// Returns the user's settings.  Requests must be authenticated with a Spotify access token
+/// belonging to the user.
+#[get("/users/<username>/settings")]
+pub(crate) async fn get_user_settings(
+    conn: DbConn,
+    current_user: CurrentUser,
+    username: String,
+) -> Result<Option<Json<UserSettings>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    let settings = db_util::get_user_settings(&conn, &user).await?;
+    let profile_view_count = db_util::get_profile_view_count(&conn, user.id).await?;
+    Ok(Some(Json(build_user_settings(
+        &user,
+        &settings,
+        profile_view_count,
+    ))))
+}
+
+/// Updates the user's settings, leaving any that aren't provided unchanged.  Requests must be
+/// authenticated with a Spotify access token belonging to the user.
+#[patch("/users/<username>/settings", data = "<settings>")]
+pub(crate) async fn update_user_settings(
+    conn: DbConn,
+    current_user: CurrentUser,
+    username: String,
+    settings: Json<UserSettingsRequest>,
+) -> Result<Option<Json<UserSettings>>, Error> {
+    let mut user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    let UserSettingsRequest {
+        timezone,
+        vanity_slug,
+        display_name,
+        is_private,
+        default_timeframe,
+        update_frequency,
+        entity_fetch_count,
+        leaderboard_opt_in,
+        friends_only_comparisons,
+        now_playing_public,
+        library_public,
+    } = settings.into_inner();
+    // Validate everything before making any changes
+    let timezone = timezone
+        .map(|timezone| {
+            timezone.parse::<chrono_tz::Tz>().map_err(|_| {
+                Error::BadRequest(format!(
+                    "Invalid `timezone` \"{}\"; must be an IANA timezone name",
+                    timezone
+                ))
+            })
+        })
+        .transpose()?;
+    let vanity_slug = vanity_slug.map(|slug| Some(slug).filter(|slug| !slug.is_empty()));
+    if let Some(Some(slug)) = &vanity_slug {
+        validate_vanity_slug(slug)?;
+        let slug_taken = db_util::is_vanity_slug_taken(&conn, &user, slug.clone()).await?;
+        if slug_taken {
+            return Err(Error::BadRequest(format!(
+                "The vanity slug \"{}\" is already taken",
+                slug
+            )));
+        }
+    }
+    if entity_fetch_count.map(usize::from) > Some(MAX_TOP_ENTITY_COUNT) {
+        return Err(Error::BadRequest(format!(
+            "`entity_fetch_count` must be at most {}",
+            MAX_TOP_ENTITY_COUNT
+        )));
+    }
+    let display_name = display_name.map(|display_name| display_name.trim().to_owned());
+    if display_name.as_ref().map(|name| name.chars().count() > 255) == Some(true) {
+        return Err(Error::BadRequest(String::from(
+            "`display_name` must be at most 255 characters",
+        )));
+    }
+
+    if let Some(timezone) = timezone {
+        db_util::set_user_timezone(&conn, &user, timezone.name().to_owned()).await?;
+        user.timezone = Some(timezone.name().to_owned());
+
+        // Cached snapshots contain timestamps converted into the old timezone
+        if let Err(err) = block_in_place(|| invalidate_cached_snapshots(user.id)) {
+            warn!("Error invalidating cached stats snapshots: {}", err);
+        }
+    }
+
+    if let Some(vanity_slug) = vanity_slug {
+        db_util::set_user_vanity_slug(&conn, &user, vanity_slug.clone())
+            .await
+            .map_err(|err| match err {
+                // Another user claimed the slug since it was checked
+                diesel::result::Error::DatabaseError(
+                    diesel::result::DatabaseErrorKind::UniqueViolation,
+                    _,
+                ) => Error::BadRequest(String::from("The vanity slug is already taken")),
+                err => Error::from(err),
+            })?;
+        user.vanity_slug = vanity_slug;
+    }
+
+    if let Some(is_private) = is_private {
+        let private_token = db_util::set_user_privacy(&conn, &user, is_private).await?;
+        user.is_private = is_private;
+        user.private_token = Some(private_token);
+    }
+
+    let mut user_settings = db_util::get_user_settings(&conn, &user).await?;
+    if display_name.is_some()
+        || default_timeframe.is_some()
+        || update_frequency.is_some()
+        || entity_fetch_count.is_some()
+        || leaderboard_opt_in.is_some()
+        || friends_only_comparisons.is_some()
+        || now_playing_public.is_some()
+        || library_public.is_some()
+    {
+        if let Some(display_name) = display_name {
+            user_settings.display_name = Some(display_name).filter(|name| !name.is_empty());
+        }
+        if let Some(default_timeframe) = default_timeframe {
+            user_settings.default_timeframe = default_timeframe;
+        }
+        if let Some(update_frequency) = update_frequency {
+            user_settings.update_frequency = update_frequency.id();
+        }
+        if let Some(entity_fetch_count) = entity_fetch_count {
+            user_settings.entity_fetch_count = Some(entity_fetch_count).filter(|count| *count != 0);
+        }
+        if let Some(leaderboard_opt_in) = leaderboard_opt_in {
+            user_settings.leaderboard_opt_in = leaderboard_opt_in;
+        }
+        if let Some(friends_only_comparisons) = friends_only_comparisons {
+            user_settings.friends_only_comparisons = friends_only_comparisons;
+        }
+        if let Some(now_playing_public) = now_playing_public {
+            user_settings.now_playing_public = now_playing_public;
+        }
+        if let Some(library_public) = library_public {
+            user_settings.library_public = library_public;
+        }
+        db_util::set_user_settings(&conn, user_settings.clone()).await?;
+    }
+
+    let profile_view_count = db_util::get_profile_view_count(&conn, user.id).await?;
+    Ok(Some(Json(build_user_settings(
+        &user,
+        &user_settings,
+        profile_view_count,
+    ))))
+}
+
+/// Returns the Spotify accounts linked to the user.  Requests must be authenticated with a Spotify
+/// access token belonging to the user.
+#[get("/users/<username>/linked_accounts")]
+pub(crate) async fn get_linked_accounts(
+    conn: DbConn,
+    current_user: CurrentUser,
+    username: String,
+) -> Result<Option<Json<Vec<LinkedAccount>>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    let linked_accounts = db_util::get_linked_accounts(&conn, &user).await?;
+    Ok(Some(Json(linked_accounts)))
+}
+
+/// Links another Spotify account to the user so that its stats are included when viewing the
+/// user's stats with `linked` set.  Requests must be authenticated with a Spotify access token
+/// belonging to the user, and the body must contain an access token belonging to the account being
+/// linked, which must have signed in to Spotifytrack already.  Returns the user's linked accounts.
+///
+/// Links are only one level deep: an account can be linked to one user at a time, and users with
+/// linked accounts can't themselves be linked to another user.
+#[post("/users/<username>/linked_accounts", data = "<request>")]
+pub(crate) async fn link_account(
+    conn: DbConn,
+    current_user: CurrentUser,
+    username: String,
+    request: Json<LinkAccountRequest>,
+) -> Result<Option<Json<Vec<LinkedAccount>>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    let linked_profile = crate::spotify_api::get_user_profile_info(&request.access_token)
+        .await
+        .map_err(|_| {
+            Error::Unauthorized("Invalid token supplied for the account being linked".into())
+        })?;
+    let linked_user = db_util::get_user_by_spotify_id(&conn, linked_profile.id)
+        .await?
+        .ok_or_else(|| {
+            Error::BadRequest(String::from(
+                "The account being linked must sign in to Spotifytrack before it can be linked",
+            ))
+        })?;
+    if linked_user.id == user.id {
+        return Err(Error::BadRequest(String::from(
+            "An account can't be linked to itself",
+        )));
+    }
+    if db_util::get_primary_user_id(&conn, &linked_user)
+        .await?
+        .is_some()
+    {
+        return Err(Error::BadRequest(String::from(
+            "The account being linked is already linked to a user; it must be unlinked first",
+        )));
+    }
+    if db_util::get_primary_user_id(&conn, &user).await?.is_some() {
+        return Err(Error::BadRequest(String::from(
+            "Accounts can't be linked to a user that is itself linked to another user",
+        )));
+    }
+    let has_linked_accounts = !db_util::get_linked_users(&conn, &linked_user)
+        .await?
+        .is_empty();
+    if has_linked_accounts {
+        return Err(Error::BadRequest(String::from(
+            "Users with linked accounts can't be linked to another user",
+        )));
+    }
+
+    db_util::link_account(&conn, &user, &linked_user)
+        .await
+        .map_err(|err| match err {
+            // The account was linked to another user since it was checked
+            diesel::result::Error::DatabaseError(
+                diesel::result::DatabaseErrorKind::UniqueViolation,
+                _,
+            ) => Error::BadRequest(String::from(
+                "The account being linked is already linked to a user",
+            )),
+            err => Error::from(err),
+        })?;
+
+    let linked_accounts = db_util::get_linked_accounts(&conn, &user).await?;
+    Ok(Some(Json(linked_accounts)))
+}
+
+/// Unlinks an account from the user.  Requests must be authenticated with a Spotify access token
+/// belonging to either the user or the linked account.  Returns the user's remaining linked
+/// accounts.
+#[delete("/users/<username>/linked_accounts/<linked_username>")]
+pub(crate) async fn unlink_account(
+    conn: DbConn,
+    current_user: CurrentUser,
+    username: String,
+    linked_username: String,
+) -> Result<Option<Json<Vec<LinkedAccount>>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+    let linked_user = match db_util::get_user_by_spotify_id(&conn, linked_username).await? {
+        Some(linked_user) => linked_user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    if current_user.verify(&user).is_err() && current_user.verify(&linked_user).is_err() {
+        return Err(Error::Forbidden(
+            "Not authenticated as either account".into(),
+        ));
+    }
+
+    let unlinked_count = db_util::unlink_account(&conn, &user, &linked_user).await?;
+    if unlinked_count == 0 {
+        return Ok(None);
+    }
+
+    let linked_accounts = db_util::get_linked_accounts(&conn, &user).await?;
+    Ok(Some(Json(linked_accounts)))
+}
+
+/// Max size of uploaded listening history exports
+const MAX_IMPORT_SIZE_MEBIBYTES: usize = 64;
+
+async fn read_import_body(data: rocket::data::Data<'_>) -> Result<String, Error> {
+    let body = data
+        .open(MAX_IMPORT_SIZE_MEBIBYTES.mebibytes())
+        .into_string()
+        .await
+        .map_err(|err| {
+            error!("Error reading uploaded listening history: {:?}", err);
+            Error::BadRequest(String::from("Error reading request body"))
+        })?;
+    if !body.is_complete() {
+        return Err(Error::BadRequest(format!(
+            "Uploaded listening history must be at most {} MiB",
+            MAX_IMPORT_SIZE_MEBIBYTES
+        )));
+    }
+    Ok(body.into_inner())
+}
+
+async fn get_import_spotify_access_token(
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+) -> Result<String, Error> {
+    let token_data = &mut *token_data.lock().await;
+    Ok(token_data.get().await?)
+}
+
+/// Backfills the user's listening history with their scrobbles fetched from the Last.fm API.  Each
+/// scrobbled track is matched to a Spotify track via search, and matched scrobbles are stored as
+/// recently played tracks.  The import runs in the background; its progress is returned and can be
+/// polled via `get_import_progress`.  Requests must be authenticated with a Spotify access token
+/// belonging to the user.
+#[post("/users/<username>/import/lastfm", format = "json", data = "<request>")]
+pub(crate) async fn import_lastfm_scrobbles(
+    conn: DbConn,
+    current_user: CurrentUser,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    username: String,
+    request: Json<LastfmImportRequest>,
+) -> Result<Option<Json<ImportProgress>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    let spotify_access_token = get_import_spotify_access_token(token_data).await?;
+    let LastfmImportRequest {
+        lastfm_username,
+        api_key,
+    } = request.into_inner();
+    let progress = importers::start_import(
+        conn,
+        user,
+        spotify_access_token,
+        ImportSource::Lastfm {
+            lastfm_username,
+            api_key,
+        },
+        false,
+    )?;
+    Ok(Some(Json(progress)))
+}
+
+/// Same as `import_lastfm_scrobbles`, but reads the scrobbles from a CSV export uploaded as the
+/// request body rather than fetching them from Last.fm
+#[post(
+    "/users/<username>/import/lastfm",
+    format = "text/csv",
+    data = "<export>",
+    rank = 2
+)]
+pub(crate) async fn import_lastfm_export(
+    conn: DbConn,
+    current_user: CurrentUser,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    username: String,
+    export: rocket::data::Data<'_>,
+) -> Result<Option<Json<ImportProgress>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    let export = read_import_body(export).await?;
+    let plays = importers::lastfm::parse_export(&export)?;
+    let spotify_access_token = get_import_spotify_access_token(token_data).await?;
+    let progress = importers::start_import(
+        conn,
+        user,
+        spotify_access_token,
+        ImportSource::Plays(plays),
+        false,
+    )?;
+    Ok(Some(Json(progress)))
+}
+
+/// Backfills the user's listening history from a CSV file uploaded as the request body with a
+/// `timestamp,artist,track` row for each play.  Plays are matched to Spotify tracks and stored like
+/// those imported from Last.fm, and monthly snapshots of the user's top artists and tracks are
+/// synthesized from plays that predate the user's first snapshot.  The import runs in the
+/// background like those from Last.fm.  Requests must be authenticated with a Spotify access token
+/// belonging to the user.
+#[post(
+    "/users/<username>/import/csv",
+    format = "text/csv",
+    data = "<history>"
+)]
+pub(crate) async fn import_listening_history_csv(
+    conn: DbConn,
+    current_user: CurrentUser,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    username: String,
+    history: rocket::data::Data<'_>,
+) -> Result<Option<Json<ImportProgress>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    let history = read_import_body(history).await?;
+    let plays = importers::csv::parse_history(&history)?;
+    let spotify_access_token = get_import_spotify_access_token(token_data).await?;
+    let progress = importers::start_import(
+        conn,
+        user,
+        spotify_access_token,
+        ImportSource::Plays(plays),
+        true,
+    )?;
+    Ok(Some(Json(progress)))
+}
+
+/// Returns the progress of the user's most recent import of listening history, or 404 if they
+/// haven't started one since the server started.  Requests must be authenticated with a Spotify
+/// access token belonging to the user.
+#[get("/users/<username>/import")]
+pub(crate) async fn get_import_progress(
+    conn: DbConn,
+    current_user: CurrentUser,
+    username: String,
+) -> Result<Option<Json<ImportProgress>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    Ok(importers::get_import_progress(user.id).map(Json))
+}
+
+/// Deletes the user and everything stored for them.  Requests must be authenticated with a Spotify
+/// access token belonging to the user being deleted.
+#[delete("/users/<username>")]
+pub(crate) async fn delete_user(
+    conn: DbConn,
+    current_user: CurrentUser,
+    username: String,
+) -> Result<Option<Json<UserDeletionSummary>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    let mut summary = db_util::delete_user(&conn, &user).await?;
+    if let Err(err) = block_in_place(|| invalidate_cached_snapshots(user.id)) {
+        warn!("Error invalidating cached stats snapshots: {}", err);
+    }
+    // The database rows are already gone at this point, so failing to clean up cold storage is
+    // reported in the summary rather than failing the request.
+    summary.external_data_deleted =
+        match crate::external_storage::delete_external_user_data(&user.spotify_id).await {
+            Ok(()) => true,
+            Err(err) => {
+                error!(
+                    "Error deleting external data for user {}: {}",
+                    user.spotify_id, err
+                );
+                false
+            },
+        };
+    info!("Deleted user {}", user.spotify_id);
+
+    Ok(Some(Json(summary)))
+}
+
+/// Creates a private playlist in the user's Spotify account containing their current top tracks for
+/// `timeframe` (default the user's preferred timeframe).  If a playlist was already generated for
+/// that timeframe, its tracks are replaced instead.  Requests must be authenticated with a Spotify
+/// access token belonging to the user, and the user must have granted playlist permissions.
+#[post("/stats/<username>/playlist?<timeframe>")]
+pub(crate) async fn generate_top_tracks_playlist(
+    conn: DbConn,
+    conn_2: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    current_user: CurrentUser,
+    username: String,
+    timeframe: Option<String>,
+) -> Result<Option<Json<GeneratedPlaylist>>, Error> {
+    if !CONF.is_feature_enabled(SpotifyFeature::Playlists) {
+        return Err(
+            Error::BadRequest("Playlist generation is disabled on this server".into()).into(),
+        );
+    }
+    let timeframe = timeframe
+        .as_deref()
+        .map(str::parse::<Timeframe>)
+        .transpose()?;
+
+    let mut user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    current_user.verify(&user)?;
+
+    let settings = db_util::get_user_settings(&conn, &user).await?;
+    let timeframe = timeframe.unwrap_or(settings.default_timeframe);
+
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+    let track_uris: Vec<String> = match db_util::get_track_stats(
+        &user,
+        conn_2,
+        &spotify_access_token,
+        None,
+        SnapshotFilter::default(),
+    )
+    .await?
+    {
+        Some(track_stats) => track_stats
+            .into_iter()
+            .filter(|(track_timeframe, _)| *track_timeframe == timeframe)
+            .map(|(_, track)| format!("spotify:track:{}", track.id))
+            .collect(),
+        None => Vec::new(),
+    };
+    if track_uris.is_empty() {
+        return Err(Error::NotFound(
+            "User has no top tracks for that timeframe".into(),
+        ));
+    }
+
+    // Playlists are modified using the user's own token, which has the playlist scopes if the user
+    // granted them when authorizing
+    db_util::refresh_user_access_token(&conn, &mut user).await?;
+
+    let existing_playlist_id =
+        db_util::get_top_tracks_playlist_id(&conn, user.id, timeframe).await?;
+    if let Some(playlist_id) = existing_playlist_id {
+        match crate::spotify_api::replace_playlist_tracks(&user.token, &playlist_id, &track_uris)
+            .await
+        {
+            Ok(_snapshot_id) =>
+                return Ok(Some(Json(GeneratedPlaylist {
+                    uri: format!("spotify:playlist:{}", playlist_id),
+                    id: playlist_id,
+                    track_count: track_uris.len(),
+                    created: false,
+                }))),
+            // The playlist may have been deleted by the user, so fall back to creating a new one
+            Err(err) => warn!(
+                "Error updating existing top tracks playlist {} for user {}; creating a new one: \
+                 {}",
+                playlist_id, user.spotify_id, err
+            ),
+        }
+    }
+
+    let playlist = crate::spotify_api::create_playlist(
+        &user.token,
+        &user,
+        format!(
+            "{}'s Top Tracks ({})",
+            settings.display_name(&user),
+            timeframe.label()
+        ),
+        Some(format!(
+            "{}'s most listened to tracks, generated by spotifytrack.net",
+            settings.display_name(&user)
+        )),
+        false,
+        &track_uris,
+    )
+    .await?;
+    db_util::set_top_tracks_playlist_id(&conn, user.id, timeframe, playlist.id.clone()).await?;
+
+    Ok(Some(Json(GeneratedPlaylist {
+        id: playlist.id,
+        uri: playlist.uri,
+        track_count: playlist.tracks.total,
+        created: true,
+    })))
+}
+
+/// Returns what the user is currently listening to.  Only the user can see it unless they've
+/// enabled `now_playing_public` in their settings, in which case anyone that can view their stats
+/// can.  Playback states are cached for a few seconds since profile pages poll this.  Users that
+/// authorized before now playing was enabled get `reauthorize_required` rather than an error.
+#[get("/stats/<username>/now_playing")]
+pub(crate) async fn get_now_playing(
+    conn: DbConn,
+    username: String,
+    access_token: PrivateAccessToken,
+    current_user: Option<CurrentUser>,
+) -> Result<Option<Json<NowPlaying>>, Error> {
+    if !CONF.is_feature_enabled(SpotifyFeature::NowPlaying) {
+        return Err(Error::BadRequest(
+            "Now playing is disabled on this server".into(),
+        ));
+    }
+
+    let mut user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    if user.deactivated_at.is_some() {
+        return Ok(None);
+    }
+    let is_owner = current_user
+        .map(|current_user| current_user.spotify_id == user.spotify_id)
+        .unwrap_or(false);
+    if !is_owner {
+        if !access_token.grants_access_to(&user) {
+            return Ok(None);
+        }
+        let settings = db_util::get_user_settings(&conn, &user).await?;
+        if !settings.now_playing_public {
+            return Ok(None);
+        }
+    }
+
+    let cached = block_in_place(|| get_cached_playback_state(user.id)).unwrap_or_else(|err| {
+        warn!("Error reading from cache; skipping it: {}", err);
+        None
+    });
+    let playback_state = match cached {
+        Some(cached) => cached,
+        None => {
+            let (state, missing_scope) =
+                match crate::spotify_api::fetch_playback_state_with_token_refresh(&conn, &mut user)
+                    .await
+                {
+                    Ok(state) => (state, false),
+                    Err(Error::SpotifyForbidden) => (None, true),
+                    Err(err) => return Err(err),
+                };
+            let playback_state = CachedPlaybackState {
+                fetched_at: Utc::now().naive_utc(),
+                state,
+                missing_scope,
+            };
+            if let Err(err) = block_in_place(|| set_cached_playback_state(user.id, &playback_state))
+            {
+                warn!("Error writing to cache: {}", err);
+            }
+            playback_state
+        },
+    };
+
+    let fetched_at = user.localize(playback_state.fetched_at);
+    let reauthorize_required = playback_state.missing_scope;
+    Ok(Some(Json(match playback_state.state {
+        Some(state) => NowPlaying {
+            track: state.item,
+            is_playing: state.is_playing,
+            progress_ms: state.progress_ms,
+            fetched_at,
+            reauthorize_required,
+        },
+        None => NowPlaying {
+            track: None,
+            is_playing: false,
+            progress_ms: None,
+            fetched_at,
+            reauthorize_required,
+        },
+    })))
+}
+
+/// Streams a `snapshot_stored` Server-Sent Event whenever a new snapshot is stored for the user so
+/// that open tabs can refresh their stats.  A `resync` event is sent if notifications had to be
+/// dropped because the client fell behind, since one of them may have been for this user.
+#[get("/events/<username>")]
+pub(crate) async fn stream_user_events(
+    conn: DbConn,
+    username: String,
+    access_token: PrivateAccessToken,
+    mut shutdown: Shutdown,
+) -> Result<Option<EventStream![]>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+
+    let mut notifications = crate::events::subscribe();
+    Ok(Some(EventStream! {
+        loop {
+            let notification = tokio::select! {
+                res = notifications.recv() => match res {
+                    Ok(notification) => notification,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {
+                        yield Event::empty().event("resync");
+                        continue;
+                    },
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                },
+                _ = &mut shutdown => break,
+            };
+            if notification.user_id != user.id {
+                continue;
+            }
+
+            yield Event::json(&SnapshotStoredEvent {
+                update_time: user.localize(notification.update_time),
+            })
+            .event("snapshot_stored");
+        }
+    }))
+}
+
+/// Suggests new music for the user by seeding Spotify's recommendations with their current short
+/// term top artists and tracks.  Tracks that have already appeared in the user's history are
+/// excluded.
+#[get("/stats/<username>/recommendations?<limit>")]
+pub(crate) async fn get_recommendations(
+    conn: DbConn,
+    conn_2: DbConn,
+    conn_3: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    username: String,
+    access_token: PrivateAccessToken,
+    limit: Option<usize>,
+) -> Result<Option<Json<Recommendations>>, Error> {
+    let limit = limit.unwrap_or(20).clamp(1, 50);
+
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+
+    let (artist_stats, track_stats) = tokio::try_join!(
+        db_util::get_artist_stats(
+            &user,
+            conn_2,
+            &spotify_access_token,
+            None,
+            SnapshotFilter::default()
+        ),
+        db_util::get_track_stats(
+            &user,
+            conn_3,
+            &spotify_access_token,
+            None,
+            SnapshotFilter::default()
+        ),
+    )?;
+    let top_artists: Vec<Artist> = artist_stats
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(timeframe, _)| *timeframe == Timeframe::Short)
+        .map(|(_, artist)| artist)
+        .collect();
+    let top_tracks: Vec<Track> = track_stats
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(timeframe, _)| *timeframe == Timeframe::Short)
+        .map(|(_, track)| track)
+        .collect();
+
+    let (artist_seed_count, track_seed_count) = crate::stats::recommendation_seed_counts(
+        top_artists.len(),
+        top_tracks.len(),
+        crate::spotify_api::MAX_RECOMMENDATION_SEEDS,
+    );
+    let seed_artists: Vec<Artist> = top_artists.into_iter().take(artist_seed_count).collect();
+    let seed_tracks: Vec<Track> = top_tracks.into_iter().take(track_seed_count).collect();
+    if seed_artists.is_empty() && seed_tracks.is_empty() {
+        return Ok(Some(Json(Recommendations {
+            seed_artists,
+            seed_tracks,
+            tracks: Vec::new(),
+        })));
+    }
+
+    let seed_artist_ids: Vec<&str> = seed_artists
+        .iter()
+        .map(|artist| artist.id.as_str())
+        .collect();
+    let seed_track_ids: Vec<&str> = seed_tracks.iter().map(|track| track.id.as_str()).collect();
+    // Fetch as many as possible since many of them may already be in the user's history
+    let (recommended_tracks, mut seen_track_ids) = tokio::try_join!(
+        crate::spotify_api::fetch_recommendations(
+            &spotify_access_token,
+            &seed_artist_ids,
+            &seed_track_ids,
+            crate::spotify_api::MAX_RECOMMENDATIONS_LIMIT,
+        ),
+        db_util::get_all_track_spotify_ids_for_user(&conn, user.id).map_err(Error::from),
+    )?;
+
+    let tracks = recommended_tracks
+        .into_iter()
+        // Also de-duplicates the recommendations themselves
+        .filter(|track| seen_track_ids.insert(track.id.clone()))
+        .take(limit)
+        .collect();
+
+    Ok(Some(Json(Recommendations {
+        seed_artists,
+        seed_tracks,
+        tracks,
+    })))
+}
+
+/// Estimates the number of minutes the user spent listening to each track and artist per day or
+/// per week, based on their recently played tracks.
+#[get("/stats/<username>/listening_time?<granularity>")]
+pub(crate) async fn get_listening_time(
+    conn: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    username: String,
+    access_token: PrivateAccessToken,
+    granularity: Option<&str>,
+) -> Result<Option<Json<ListeningTime>>, Error> {
+    let granularity =
+        ListeningTimeGranularity::parse(granularity.unwrap_or("day")).ok_or_else(|| {
+            Error::BadRequest(String::from(
+                "Invalid `granularity` provided; must be `day` or `week`",
+            ))
+        })?;
+    let lookback = match granularity {
+        ListeningTimeGranularity::Day => chrono::Duration::days(30),
+        ListeningTimeGranularity::Week => chrono::Duration::weeks(26),
+    };
+
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let user_id = user.id;
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+
+    let start = Utc::now().naive_utc() - lookback;
+    let plays = db_util::get_plays_since(&conn, user_id, Some(start)).await?;
+    let ms_by_track_by_period = crate::stats::estimate_listening_time(&plays, granularity);
+
+    let track_ids = plays
+        .iter()
+        .map(|(track_id, ..)| track_id.as_str())
+        .collect::<FnvHashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let tracks_by_id = crate::spotify_api::fetch_tracks(&spotify_access_token, &track_ids).await?;
+
+    let to_minutes = |ms: u64| ms as f32 / (60. * 1000.);
+    let sorted_by_minutes = |ms_by_id: HashMap<String, u64>| {
+        let mut minutes_by_id: Vec<(String, f32)> = ms_by_id
+            .into_iter()
+            .map(|(id, ms)| (id, to_minutes(ms)))
+            .collect();
+        minutes_by_id.sort_unstable_by_key(|(_, minutes)| Reverse((minutes * 1000.) as u64));
+        minutes_by_id
+    };
+
+    let mut artists_by_id: HashMap<String, Artist> = HashMap::default();
+    let periods = ms_by_track_by_period
+        .into_iter()
+        .map(|(start, ms_by_track_id)| {
+            let mut ms_by_artist_id: HashMap<String, u64> = HashMap::default();
+            for (track_id, ms) in &ms_by_track_id {
+                let track = match tracks_by_id.get(track_id) {
+                    Some(track) => track,
+                    None => continue,
+                };
+                for artist in &track.artists {
+                    *ms_by_artist_id.entry(artist.id.clone()).or_insert(0) += ms;
+                    artists_by_id
+                        .entry(artist.id.clone())
+                        .or_insert_with(|| artist.clone());
+                }
+            }
+
+            ListeningTimePeriod {
+                start,
+                total_minutes: to_minutes(ms_by_track_id.values().sum()),
+                tracks: sorted_by_minutes(ms_by_track_id),
+                artists: sorted_by_minutes(ms_by_artist_id),
+            }
+        })
+        .collect();
+
+    Ok(Some(Json(ListeningTime {
+        granularity,
+        periods,
+        tracks_by_id,
+        artists_by_id,
+    })))
+}
+
+/// Redirects to the Spotify authorization page for the application, requesting the scopes needed by
+/// the features enabled on this server.  Playlist scopes are only requested if `playlist_perms` is
+/// set.
+#[get("/authorize?<playlist_perms>&<state>")]
+pub(crate) fn authorize(playlist_perms: Option<&str>, state: Option<&str>) -> Redirect {
+    let include_playlist_scopes = !matches!(
+        playlist_perms,
+        None | Some("false") | Some("False") | Some("0")
+    );
+    let scopes = CONF.get_oauth_scopes(include_playlist_scopes).join("%20");
+    let callback_uri = crate::conf::CONF.get_absolute_oauth_cb_uri();
+
+    Redirect::to(format!(
+        "https://accounts.spotify.com/authorize?client_id={}&response_type=code&redirect_uri={}&scope={}&state={}",
+        CONF.client_id,
+        callback_uri,
+        scopes,
+        RawStr::new(state.unwrap_or("")).percent_encode()
+    ))
+}
+
+/// The playlist will be generated on the account of user2
+async fn generate_shared_playlist(
+    conn1: DbConn,
+    conn2: DbConn,
+    conn3: DbConn,
+    conn4: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    bearer_token: &str,
+    user1: &str,
+    user2: &str,
+) -> Result<Option<Playlist>, Error> {
+    let (user1_res, user2_res) = tokio::join!(
+        async move {
+            db_util::get_user_by_spotify_id(&conn1, user1.to_owned())
+                .await
+                .map(|user_opt| user_opt.map(|user| (user, conn1)))
+        },
+        async move {
+            db_util::get_user_by_spotify_id(&conn2, user2.to_owned())
+                .await
+                .map(|user_opt| user_opt.map(|user| (user, conn2)))
+        },
+    );
+    let (user1, conn1) = match user1_res? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+    let (mut user2, conn2) = match user2_res? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+
+    if let Err(err) = db_util::refresh_user_access_token(&conn1, &mut user2).await {
+        error!("Error refreshing access token: {}", err);
+        return Err(Error::Internal("Error refreshing access token".into()));
+    }
 
     let playlist_track_spotify_ids =
         crate::shared_playlist_gen::generate_shared_playlist_track_spotify_ids(
@@ -416,708 +3530,2059 @@ async fn generate_shared_playlist(
         )
         .await?;
 
-    let created_playlist = crate::spotify_api::create_playlist(
-        bearer_token,
-        &user2,
-        format!("Shared Tastes of {} and {}", user1.username, user2.username),
-        Some(format!(
-            "Contains tracks and artists that both {} and {} enjoy, {}",
-            user1.username, user2.username, "generated by spotifytrack.net"
+    let created_playlist = crate::spotify_api::create_playlist(
+        bearer_token,
+        &user2,
+        format!("Shared Tastes of {} and {}", user1.username, user2.username),
+        Some(format!(
+            "Contains tracks and artists that both {} and {} enjoy, {}",
+            user1.username, user2.username, "generated by spotifytrack.net"
+        )),
+        true,
+        &playlist_track_spotify_ids,
+    )
+    .await?;
+
+    Ok(Some(created_playlist))
+}
+
+/// This handles the OAuth authentication process for new users.  It is hit as the callback for the
+/// authentication request and handles retrieving user tokens, creating an entry for the user in the
+/// users table, and fetching an initial stats snapshot.
+#[get("/oauth_cb?<error>&<code>&<state>")]
+pub(crate) async fn oauth_cb(
+    conn1: DbConn,
+    conn2: DbConn,
+    conn3: DbConn,
+    conn4: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    cookies: &CookieJar<'_>,
+    error: Option<&str>,
+    code: &str,
+    state: Option<&str>,
+) -> Result<Redirect, Error> {
+    if error.is_some() {
+        error!("Error during Oauth authorization process: {:?}", error);
+        return Err(Error::BadRequest(
+            "An error occured while authenticating with Spotify.".into(),
+        ));
+    }
+
+    let oauth_cb_url = crate::conf::CONF.get_absolute_oauth_cb_uri();
+
+    // Shoot the code back to Spotify and get an API token for the user in return
+    let mut params = HashMap::default();
+    params.insert("grant_type", "authorization_code");
+    params.insert("code", code);
+    params.insert("redirect_uri", oauth_cb_url.as_str());
+    params.insert("client_id", CONF.client_id.as_str());
+    params.insert("client_secret", CONF.client_secret.as_str());
+
+    let req = get_reqwest_client()
+        .await
+        .post(SPOTIFY_TOKEN_FETCH_URL)
+        .form(&params)
+        .build()
+        .map_err(|_| Error::Internal("Error building token request to Spotify".into()))?;
+    info!("Making request to fetch user token from OAuth CB response...");
+    let res = crate::spotify_api::client::spotify_client()
+        .execute(req)
+        .await
+        .map_err(|_| {
+            Error::SpotifyApi("Error fetching token from Spotify from response Oauth code".into())
+        })?;
+
+    let res: OAuthTokenResponse = match res.json().await {
+        Ok(res) => res,
+        Err(err) => {
+            error!("Failed to fetch user tokens from OAuth CB code: {:?}", err);
+            return Err(Error::SpotifyApi(
+                "Error parsing response from token fetch endpoint".into(),
+            ));
+        },
+    };
+
+    let (access_token, refresh_token) = match res {
+        OAuthTokenResponse::Success {
+            access_token,
+            refresh_token,
+            token_type,
+            ..
+        } => {
+            info!("Successfully received token of type: {}", token_type);
+            (access_token, refresh_token)
+        },
+        OAuthTokenResponse::Error {
+            error,
+            error_description,
+        } => {
+            error!(
+                "Error fetching tokens for user: {}; {}",
+                error, error_description
+            );
+            return Err(Error::SpotifyApi(
+                "Error fetching user access tokens from Spotify API.".into(),
+            ));
+        },
+    };
+
+    info!("Fetched user tokens.  Inserting user into database...");
+
+    // Fetch the user's username and spotify ID from the Spotify API
+    let user_profile_info = crate::spotify_api::get_user_profile_info(&access_token).await?;
+    let user_spotify_id = user_profile_info.id;
+    let username = user_profile_info.display_name;
+
+    // Users that have authorized before just get their tokens refreshed.  Their history is already
+    // being tracked, so there's no need to create an initial snapshot for them.
+    let is_new_user = match db_util::get_user_by_spotify_id(&conn1, user_spotify_id.clone()).await?
+    {
+        Some(_) => {
+            db_util::update_reauthorized_user(
+                &conn1,
+                user_spotify_id.clone(),
+                username.clone(),
+                access_token.clone(),
+                refresh_token,
+            )
+            .await?;
+            info!("Already have a row for user; skipping manual update and redirecting directly.");
+            false
+        },
+        None => {
+            let user = NewUser {
+                creation_time: Utc::now().naive_utc(),
+                last_update_time: Utc::now().naive_utc(),
+                spotify_id: user_spotify_id.clone(),
+                username: username.clone(),
+                token: StoredToken(access_token.clone()),
+                refresh_token: StoredToken(refresh_token.clone()),
+                private_token: Some(db_util::generate_private_token()),
+            };
+
+            let query = diesel::insert_into(crate::schema::users::table).values(user);
+            match conn1.run(move |conn| query.execute(conn)).await {
+                // Another callback for the same user could have inserted it concurrently
+                Err(diesel::result::Error::DatabaseError(
+                    diesel::result::DatabaseErrorKind::UniqueViolation,
+                    _,
+                )) => {
+                    db_util::update_reauthorized_user(
+                        &conn1,
+                        user_spotify_id.clone(),
+                        username.clone(),
+                        access_token.clone(),
+                        refresh_token,
+                    )
+                    .await?;
+                    false
+                },
+                Err(err) => {
+                    error!("Error inserting row: {:?}", err);
+                    return Err(Error::Internal("Error inserting user into database".into()));
+                },
+                Ok(_) => true,
+            }
+        },
+    };
+
+    if is_new_user {
+        // Retrieve the inserted user row
+        let mut user = crate::db_util::get_user_by_spotify_id(&conn1, user_spotify_id.clone())
+            .await?
+            .expect("Failed to load just inserted user from database");
+
+        // Create an initial stats snapshot to store for the user
+        let cur_user_stats = match crate::spotify_api::fetch_cur_stats_with_token_refresh(
+            &conn1, &mut user,
+        )
+        .await?
+        {
+            Some(stats) => stats,
+            None => {
+                error!(
+                    "Failed to fetch stats for user \"{}\"; bad response from Spotify API?",
+                    username
+                );
+                return Err(Error::SpotifyApi(
+                    "Error fetching user stats from the Spotify API.".into(),
+                ));
+            },
+        };
+
+        crate::spotify_api::store_stats_snapshot(&conn1, &user, cur_user_stats).await?;
+        crate::onboarding::queue_initial_history_capture(user_spotify_id.clone());
+    }
+
+    // The user has proven that they own the account, so they're signed in to it from here on
+    crate::session::start_session(cookies, &user_spotify_id);
+
+    match state {
+        Some(s) if !s.is_empty() => {
+            if s == "galaxy" {
+                return Ok(Redirect::to(format!(
+                    "https://galaxy.spotifytrack.net/?spotifyID={}",
+                    user_spotify_id
+                )));
+            }
+
+            let s = RawStr::new(s);
+            let percent_decoded =
+                s.percent_decode()
+                    .map(|s| -> String { s.into() })
+                    .map_err(|_| {
+                        error!("Invalid URL-Encoded `state` param; dropping");
+                        Error::BadRequest(
+                            "Invalid URL-encoded `state` param provided; can't parse.".into(),
+                        )
+                    })?;
+
+            match serde_json::from_str(percent_decoded.as_ref()) {
+                Ok(CreateSharedPlaylistRequest { .. })
+                    if !CONF.is_feature_enabled(SpotifyFeature::Playlists) =>
+                    return Err(Error::BadRequest(
+                        "Playlist generation is disabled on this server".into(),
+                    )),
+                Ok(CreateSharedPlaylistRequest { user1_id, user2_id }) => {
+                    let playlist = generate_shared_playlist(
+                        conn1,
+                        conn2,
+                        conn3,
+                        conn4,
+                        token_data,
+                        &access_token,
+                        &user1_id,
+                        &user2_id,
+                    )
+                    .await?;
+
+                    match playlist {
+                        Some(playlist) => {
+                            let encoded_playlist = serde_json::to_string(&serde_json::json!({
+                                "uri": playlist.uri,
+                                "track_count": playlist.tracks.total,
+                                "name": playlist.name
+                            }))
+                            .map_err(|err| {
+                                error!("Error JSON-encoding playlist: {:?}", err);
+                                Error::Internal("Internal error while generating playlist".into())
+                            })?;
+                            let encoded_playlist =
+                                RawStr::percent_encode(&RawStr::new(encoded_playlist.as_str()));
+                            let redirect_url = format!(
+                                "{}/compare/{}/{}?playlist={}",
+                                CONF.website_url, user1_id, user2_id, encoded_playlist
+                            );
+                            return Ok(Redirect::to(redirect_url));
+                        },
+                        None =>
+                            return Err(Error::BadRequest(format!(
+                                "One or both of the supplied users has never {}",
+                                "connected to Spotifytrack before"
+                            ))),
+                    }
+                },
+                Err(err) => {
+                    if let Ok(CompareToRequest { compare_to }) =
+                        serde_json::from_str(percent_decoded.as_ref())
+                    {
+                        let redirect_url = format!(
+                            "{}/compare/{}/{}",
+                            CONF.website_url, compare_to, user_spotify_id
+                        );
+                        return Ok(Redirect::to(redirect_url));
+                    }
+
+                    warn!(
+                        "Error parsing JSON body of what we presume is a playlist generation \
+                         request: {:?}",
+                        err
+                    );
+                    return Err(Error::BadRequest(
+                        "Error parsing state param for presumed shared playlist generation request"
+                            .into(),
+                    ));
+                },
+            }
+        },
+        _ => (),
+    }
+
+    let redirect_url = match state {
+        Some(s) if s.starts_with("/") => format!("{}{}", CONF.website_url, s),
+        _ => format!("{}/stats/{}", CONF.website_url, user_spotify_id),
+    };
+
+    // Redirect the user to their stats page
+    Ok(Redirect::to(redirect_url))
+}
+
+/// Ends the session started when the user authorized, if any
+#[post("/logout")]
+pub(crate) fn logout(cookies: &CookieJar<'_>) -> Status {
+    crate::session::end_session(cookies);
+    Status::NoContent
+}
+
+/// Result of an update that didn't fail
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum UpdateOutcome {
+    /// The user's current stats were fetched and stored
+    Updated,
+    /// The user was updated more recently than their minimum update interval, so nothing was done
+    Skipped,
+}
+
+/// Updates the user with the provided Spotify ID, or the least recently updated user if none is
+/// provided.  Also used by the background update scheduler.
+///
+/// Users whose updates have failed permanently `CONF.max_consecutive_update_failures` times in a
+/// row are never picked as the least recently updated user so that they don't block updates for
+/// everyone else.  Failed updates of any kind delay the user's next automatic update by
+/// `update_retry_delay`.
+pub(crate) async fn update_user_inner(
+    conn: &DbConn,
+    user_id: Option<String>,
+) -> Result<UpdateOutcome, Error> {
+    use crate::schema::users::dsl::*;
+
+    // Get the least recently updated user
+    let user: User = match user_id.clone().map(|s| -> Result<String, _> {
+        let s = RawStr::new(s.as_str());
+        match s.percent_decode() {
+            Ok(decoded) => Ok(decoded.into()),
+            Err(err) => Err(err),
+        }
+    }) {
+        Some(s) => {
+            let user_id: String = s.map_err(|_| {
+                error!("Invalid `user_id` param provided to `/update/user`");
+                Error::BadRequest("Invalid `user_id` param; couldn't decode".into())
+            })?;
+
+            conn.run(move |conn| users.filter(spotify_id.eq(user_id)).first(conn))
+                .await
+        },
+        None =>
+            conn.run(move |conn| {
+                let now = Utc::now().naive_utc();
+                users
+                    .filter(consecutive_update_failures.lt(CONF.max_consecutive_update_failures))
+                    .filter(
+                        next_update_attempt_at
+                            .is_null()
+                            .or(next_update_attempt_at.le(now)),
+                    )
+                    .filter(deactivated_at.is_null())
+                    .order_by(last_update_time)
+                    .first(conn)
+            })
+            .await,
+    }?;
+
+    let user_db_id = user.id;
+    let user_spotify_id = user.spotify_id.clone();
+    let had_failures = user.consecutive_update_failures > 0 || user.failed_update_attempts > 0;
+    let res = update_user_stats(conn, user, user_id.is_none()).await;
+    match &res {
+        Ok(UpdateOutcome::Updated) if had_failures => {
+            if let Err(err) = db_util::reset_update_failures(conn, user_db_id).await {
+                error!("Error resetting update failure count for user: {:?}", err);
+            }
+        },
+        Ok(_) => (),
+        Err(err) => {
+            if let Err(db_err) =
+                db_util::record_update_error(conn, user_db_id, err.to_string()).await
+            {
+                error!("Error recording update error for user: {:?}", db_err);
+            }
+            // Only failures that retrying won't fix, like a rejected refresh token, count toward
+            // the limit.  Other failures such as rate limits and Spotify outages just delay the
+            // user's next update.
+            let permanent = matches!(err, Error::Unauthorized(_) | Error::SpotifyUnauthorized);
+            match db_util::increment_update_failures(
+                conn,
+                user_db_id,
+                permanent,
+                Utc::now().naive_utc(),
+            )
+            .await
+            {
+                Ok(failure_count) => {
+                    if permanent {
+                        crate::alerts::record_user_update_failure(&user_spotify_id, failure_count);
+                    }
+                    if permanent && failure_count >= CONF.max_consecutive_update_failures {
+                        warn!(
+                            "Updates for user {} have failed {} times in a row; they will no \
+                             longer be updated automatically",
+                            user_db_id, failure_count
+                        );
+                    }
+                },
+                Err(db_err) => error!(
+                    "Error incrementing update failure count for user: {:?}",
+                    db_err
+                ),
+            }
+        },
+    }
+    res
+}
+
+/// Refreshes the user's access token and then fetches and stores their current stats.  If
+/// `enforce_min_update_interval` is set, users that were updated more recently than their minimum
+/// update interval (see `User::min_update_interval`) are skipped.
+async fn update_user_stats(
+    conn: &DbConn,
+    mut user: User,
+    enforce_min_update_interval: bool,
+) -> Result<UpdateOutcome, Error> {
+    db_util::refresh_user_access_token(&conn, &mut user).await?;
+
+    // Only update the user if it's been longer than their minimum update interval
+    let now = chrono::Utc::now().naive_utc();
+    let diff = now - user.last_update_time;
+    if enforce_min_update_interval && diff < user.min_update_interval() {
+        info!("{diff} since last update; not updating anything right now.");
+        return Ok(UpdateOutcome::Skipped);
+    }
+    info!("{diff} since last update; proceeding with update.");
+
+    if let Err(err) =
+        crate::db_util::update_user_last_updated(&user, &conn, Utc::now().naive_utc()).await
+    {
+        error!(
+            "Error updating user {:?} last updated time: {:?}",
+            user, err
+        );
+        return Err(Error::Internal(
+            "Error updating user last updated time".into(),
+        ));
+    }
+
+    let stats = match crate::spotify_api::fetch_cur_stats_with_token_refresh(&conn, &mut user).await
+    {
+        Ok(Some(stats)) => stats,
+        Ok(None) => {
+            error!(
+                "Error when fetching stats for user {:?}; no stats returned.",
+                user
+            );
+            return Err(Error::Internal(
+                "No data from Spotify API for that user".into(),
+            ));
+        },
+        Err(err) => {
+            error!("Error fetching user stats: {:?}", err);
+            return Err(err);
+        },
+    };
+
+    crate::spotify_api::store_stats_snapshot(&conn, &user, stats).await?;
+
+    // Users who authorized before these features were added won't have granted the required
+    // scopes, so failures here aren't fatal to the update.  Features that are disabled on this
+    // server are skipped entirely since users are never asked for their scopes.
+    if CONF.is_feature_enabled(SpotifyFeature::RecentlyPlayed) {
+        match crate::spotify_api::update_recently_played(&conn, &user).await {
+            Ok(inserted_count) => info!(
+                "Stored {} new recently played tracks for user {}",
+                inserted_count, user.spotify_id
+            ),
+            Err(err) => warn!(
+                "Error updating recently played tracks for user {}: {}",
+                user.spotify_id, err
+            ),
+        }
+    }
+
+    if CONF.is_feature_enabled(SpotifyFeature::Follows) {
+        match crate::spotify_api::update_followed_artists(&conn, &user).await {
+            Ok((followed_count, unfollowed_count)) => info!(
+                "Recorded {} new follows and {} unfollows for user {}",
+                followed_count, unfollowed_count, user.spotify_id
+            ),
+            Err(err) => warn!(
+                "Error updating followed artists for user {}: {}",
+                user.spotify_id, err
+            ),
+        }
+    }
+
+    if CONF.is_feature_enabled(SpotifyFeature::Library) {
+        match crate::spotify_api::update_library_snapshot(&conn, &user).await {
+            Ok(Some(saved_track_count)) => info!(
+                "Recorded library of {} saved tracks for user {}",
+                saved_track_count, user.spotify_id
+            ),
+            Ok(None) => info!(
+                "Saved tracks library of user {} is unchanged; not recording it",
+                user.spotify_id
+            ),
+            Err(err) => warn!(
+                "Error updating saved tracks library for user {}: {}",
+                user.spotify_id, err
+            ),
+        }
+    }
+
+    info!("Successfully updated user {}", user.spotify_id);
+
+    Ok(UpdateOutcome::Updated)
+}
+
+#[derive(Responder)]
+pub(crate) enum UpdateUserResponse {
+    Status(status::Custom<String>),
+    DryRun(Json<UpdateDryRunReport>),
+}
+
+/// This route is internal and can be used to manually update the stats for a specific user or for
+/// the least recently updated users.  Users are normally kept up to date by the background update
+/// scheduler (see `crate::scheduler`).
+///
+/// With `dry_run=true`, the user's stats are fetched but nothing is stored, and a report of what
+/// would have been is returned instead (see `crate::dry_run`).  Dry runs require `user_id`.
+#[post("/update_user?<user_id>&<count>&<dry_run>")]
+pub(crate) async fn update_user(
+    conn: DbConn,
+    _admin: AdminToken,
+    user_id: Option<String>,
+    count: Option<usize>,
+    dry_run: Option<bool>,
+) -> Result<UpdateUserResponse, Error> {
+    if dry_run.unwrap_or(false) {
+        let user_id =
+            user_id.ok_or_else(|| Error::BadRequest("Dry runs require a `user_id`".into()))?;
+        return match crate::dry_run::dry_run_update(&conn, user_id).await? {
+            Some(report) => Ok(UpdateUserResponse::DryRun(Json(report))),
+            None => Err(Error::NotFound("User not found".into())),
+        };
+    }
+
+    if let Some(user_id) = user_id {
+        let msg = match update_user_inner(&conn, Some(user_id)).await {
+            Ok(UpdateOutcome::Updated) => {
+                user_updates_success_total().inc();
+                "User updated"
+            },
+            Ok(UpdateOutcome::Skipped) => "User was updated too recently; not updating",
+            Err(err) => {
+                user_updates_failure_total().inc();
+                return Err(err);
+            },
+        };
+        return Ok(UpdateUserResponse::Status(status::Custom(
+            Status::Ok,
+            msg.into(),
+        )));
+    }
+
+    let count = count.unwrap_or(1);
+    let mut success_count = 0usize;
+    let mut skip_count = 0usize;
+    let mut fail_count = 0usize;
+    for _ in 0..count {
+        match update_user_inner(&conn, None).await {
+            Ok(UpdateOutcome::Updated) => {
+                user_updates_success_total().inc();
+                success_count += 1;
+            },
+            Ok(UpdateOutcome::Skipped) => skip_count += 1,
+            Err(_) => {
+                user_updates_failure_total().inc();
+                fail_count += 1;
+            },
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+
+    Ok(UpdateUserResponse::Status(status::Custom(
+        Status::Ok,
+        format!(
+            "Successfully updated {} user(s); skipped {} user(s); failed to update {} user(s)",
+            success_count, skip_count, fail_count
+        ),
+    )))
+}
+
+/// Returns the current state of the background update scheduler, including the number of users
+/// that are still waiting to be updated in the current pass.
+#[post("/admin/scheduler_status")]
+pub(crate) async fn get_scheduler_status(
+    _admin: AdminToken,
+) -> Result<Json<SchedulerStatus>, Error> {
+    Ok(Json(crate::scheduler::get_status()))
+}
+
+/// Purges cached metadata for `entity` (`artists`, `tracks`, or `audio_features`) so that it's
+/// re-fetched from Spotify the next time it's needed.  `ids` is a comma-separated list of Spotify
+/// IDs to purge; all cached entries for the entity are purged if it isn't provided.
+#[post("/admin/cache/invalidate?<entity>&<ids>")]
+pub(crate) async fn invalidate_cache(
+    _admin: AdminToken,
+    entity: String,
+    ids: Option<String>,
+) -> Result<status::Custom<String>, Error> {
+    let (hash_name, metadata_table) = match entity.as_str() {
+        "artists" => (&CONF.artists_cache_hash_name, Some(MetadataTable::Artists)),
+        "tracks" => (&CONF.tracks_cache_hash_name, Some(MetadataTable::Tracks)),
+        "audio_features" => (&CONF.audio_features_cache_hash_name, None),
+        _ =>
+            return Err(Error::BadRequest(
+                "`entity` must be one of `artists`, `tracks`, or `audio_features`".into(),
+            )),
+    };
+    let ids: Option<Vec<&str>> = ids.as_ref().map(|ids| {
+        ids.split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .collect()
+    });
+
+    let invalidated_count = block_in_place(|| -> Result<_, Error> {
+        let invalidated_count = invalidate_hash_items(hash_name, ids.as_deref())?;
+        if let Some(metadata_table) = metadata_table {
+            expire_metadata_items(metadata_table, ids.as_deref())?;
+        }
+        Ok(invalidated_count)
+    })?;
+
+    let msg = match invalidated_count {
+        Some(count) => format!("Invalidated {} cached {}", count, entity),
+        None => format!("Invalidated all cached {}", entity),
+    };
+    info!("{}", msg);
+    Ok(status::Custom(Status::Ok, msg))
+}
+
+/// Delay between batches of entities fetched while warming the cache if `batch_delay_ms` isn't
+/// provided
+const DEFAULT_CACHE_WARM_BATCH_DELAY_MS: u64 = 500;
+const ENCRYPT_TOKENS_BATCH_SIZE: i64 = 500;
+
+/// Repopulates the artist and track caches with every entity in the most recent snapshot of every
+/// user, such as after Redis is flushed by a deployment.  Entities that are already cached are
+/// skipped.  `batch_delay_ms` is the delay between each batch of entities fetched from Spotify.
+#[post("/admin/cache/warm?<batch_delay_ms>")]
+pub(crate) async fn warm_cache(
+    conn: DbConn,
+    _admin: AdminToken,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    batch_delay_ms: Option<u64>,
+) -> Result<status::Custom<String>, Error> {
+    let batch_delay = std::time::Duration::from_millis(
+        batch_delay_ms.unwrap_or(DEFAULT_CACHE_WARM_BATCH_DELAY_MS),
+    );
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+
+    let mut summaries = Vec::new();
+    for (entity_name, entity, metadata_table) in [
+        ("artists", ExportEntity::Artists, MetadataTable::Artists),
+        ("tracks", ExportEntity::Tracks, MetadataTable::Tracks),
+    ] {
+        let spotify_ids = db_util::get_latest_snapshot_spotify_ids(&conn, entity).await?;
+        let spotify_ids: Vec<&str> = spotify_ids.iter().map(String::as_str).collect();
+        let warmed_count = warm_entity_cache(
+            &spotify_access_token,
+            metadata_table,
+            &spotify_ids,
+            batch_delay,
+        )
+        .await?;
+        summaries.push(format!(
+            "{} of {} {}",
+            warmed_count,
+            spotify_ids.len(),
+            entity_name
+        ));
+    }
+
+    let msg = format!("Repopulated {}", summaries.join(" and "));
+    info!("{}", msg);
+    Ok(status::Custom(Status::Ok, msg))
+}
+
+/// Encrypts the Spotify tokens of all users that were stored before `TOKEN_ENCRYPTION_KEY` was set.
+/// This only needs to be run once after enabling encryption; tokens written afterwards are
+/// encrypted automatically.
+#[post("/admin/encrypt_tokens")]
+pub(crate) async fn encrypt_tokens(
+    conn: DbConn,
+    _admin: AdminToken,
+) -> Result<status::Custom<String>, Error> {
+    if CONF.token_encryption_key.is_none() {
+        return Err(Error::BadRequest(
+            "`TOKEN_ENCRYPTION_KEY` must be set to encrypt tokens".into(),
+        ));
+    }
+
+    let encrypted_count = db_util::encrypt_stored_tokens(&conn, ENCRYPT_TOKENS_BATCH_SIZE).await?;
+    let msg = format!("Encrypted tokens for {} users", encrypted_count);
+    info!("{}", msg);
+    Ok(status::Custom(Status::Ok, msg))
+}
+
+/// Returns the state of the Redis cache's circuit breaker
+#[post("/admin/cache/status")]
+pub(crate) async fn get_cache_status(_admin: AdminToken) -> Result<Json<CacheStatus>, Error> {
+    Ok(Json(crate::cache::get_cache_status()))
+}
+
+/// Recomputes the global charts immediately rather than waiting for the scheduler to do it.
+#[post("/admin/refresh_global_charts")]
+pub(crate) async fn refresh_global_charts(
+    conn: DbConn,
+    _admin: AdminToken,
+) -> Result<status::Custom<String>, Error> {
+    crate::scheduler::refresh_global_charts(&conn).await;
+    Ok(status::Custom(Status::Ok, String::new()))
+}
+
+/// Lists users along with their update health.  `filter` can be set to `stale` to only include
+/// users that haven't been updated within twice the minimum update interval or to `failing` to only
+/// include users whose most recent update attempt failed.
+#[get("/admin/users?<filter>&<cursor>&<limit>")]
+pub(crate) async fn get_admin_users(
+    conn: DbConn,
+    _admin: AdminToken,
+    filter: Option<String>,
+    cursor: Option<String>,
+    limit: Option<u32>,
+) -> Result<Json<Paginated<AdminUserListItem>>, Error> {
+    let filter = match filter.as_deref() {
+        None => None,
+        Some("stale") => Some(db_util::AdminUserFilter::Stale(
+            Utc::now().naive_utc() - CONF.min_update_interval * 2,
         )),
-        &playlist_track_spotify_ids,
-    )
+        Some("failing") => Some(db_util::AdminUserFilter::Failing),
+        Some(_) =>
+            return Err(Error::BadRequest(
+                "Invalid `filter`; must be one of \"stale\", \"failing\"".into(),
+            )),
+    };
+
+    let pagination = CursorPagination::new(cursor.as_deref(), limit)?;
+    let users = db_util::get_admin_user_list_page(&conn, filter, pagination).await?;
+    Ok(Json(users))
+}
+
+/// Streams scheduler activity and Spotify API requests as Server-Sent Events, as a live tail for
+/// operators.  A `lagged` event with the number of dropped events is sent if the client falls
+/// behind.
+#[get("/admin/events")]
+pub(crate) fn stream_admin_events(_admin: AdminToken, mut shutdown: Shutdown) -> EventStream![] {
+    let mut events = crate::events::subscribe_admin();
+    EventStream! {
+        loop {
+            let event = tokio::select! {
+                res = events.recv() => match res {
+                    Ok(event) => event,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped_count)) => {
+                        yield Event::data(skipped_count.to_string()).event("lagged");
+                        continue;
+                    },
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                },
+                _ = &mut shutdown => break,
+            };
+            yield Event::json(&event).event(event.name());
+        }
+    }
+}
+
+/// Returns the most recently computed system stats, newest first, for capacity planning
+#[get("/admin/stats?<limit>")]
+pub(crate) async fn get_admin_stats(
+    conn: DbConn,
+    _admin: AdminToken,
+    limit: Option<u32>,
+) -> Result<Json<Vec<SystemStats>>, Error> {
+    let limit = limit.unwrap_or(30).clamp(1, 1000);
+    let history = db_util::get_system_stats_history(&conn, limit as i64).await?;
+    Ok(Json(history.into_iter().map(SystemStats::from).collect()))
+}
+
+/// Mints a new API key for a third-party app.  The key is only included in this response.
+#[post("/admin/api_keys", data = "<request>")]
+pub(crate) async fn create_api_key(
+    conn: DbConn,
+    _admin: AdminToken,
+    request: Json<NewApiKeyRequest>,
+) -> Result<Json<MintedApiKey>, Error> {
+    let NewApiKeyRequest {
+        name,
+        mut scopes,
+        rate_limit_per_minute,
+    } = request.into_inner();
+    let name = name.trim().to_owned();
+    if name.is_empty() || name.len() > 255 {
+        return Err(Error::BadRequest(
+            "`name` must be between 1 and 255 characters long".into(),
+        ));
+    }
+    scopes.sort_by_key(|scope| scope.as_str());
+    scopes.dedup();
+    if scopes.is_empty() {
+        return Err(Error::BadRequest(
+            "At least one scope must be provided".into(),
+        ));
+    }
+    let rate_limit_per_minute =
+        rate_limit_per_minute.unwrap_or(CONF.api_key_default_rate_limit_per_minute);
+    let rate_limit_per_minute = i32::try_from(rate_limit_per_minute)
+        .map_err(|_| Error::BadRequest("`rate_limit_per_minute` is too large".into()))?;
+
+    let key = crate::api_keys::generate_api_key();
+    let row = db_util::insert_api_key(&conn, NewApiKey {
+        name,
+        key_hash: crate::api_keys::hash_api_key(&key),
+        scopes: scopes
+            .iter()
+            .map(|scope| scope.as_str())
+            .collect::<Vec<_>>()
+            .join(","),
+        rate_limit_per_minute,
+        created_at: Utc::now().naive_utc(),
+    })
     .await?;
+    info!("Minted API key {} for {}", row.id, row.name);
+    Ok(Json(MintedApiKey {
+        key,
+        info: row.into(),
+    }))
+}
+
+/// Lists all API keys, including revoked ones, newest first
+#[get("/admin/api_keys")]
+pub(crate) async fn get_api_keys(
+    conn: DbConn,
+    _admin: AdminToken,
+) -> Result<Json<Vec<ApiKeyInfo>>, Error> {
+    let keys = db_util::get_api_keys(&conn).await?;
+    Ok(Json(keys.into_iter().map(ApiKeyInfo::from).collect()))
+}
+
+/// Revokes an API key so that requests made with it are rejected from then on
+#[delete("/admin/api_keys/<api_key_id>")]
+pub(crate) async fn revoke_api_key(
+    conn: DbConn,
+    _admin: AdminToken,
+    api_key_id: i64,
+) -> Result<Status, Error> {
+    let revoked_count = db_util::revoke_api_key(&conn, api_key_id, Utc::now().naive_utc()).await?;
+    if revoked_count == 0 {
+        return Err(Error::NotFound(
+            "No API key with that ID or it was already revoked".into(),
+        ));
+    }
+    crate::api_keys::clear_api_key_cache();
+    info!("Revoked API key {}", api_key_id);
+    Ok(Status::NoContent)
+}
+
+/// Overrides how often a user is automatically updated regardless of their update tier, for example
+/// to update supporters hourly.  Also applies to updates of the least recently updated user via
+/// `/update_user`.
+#[put("/admin/users/<username>/update_interval", data = "<request>")]
+pub(crate) async fn set_user_update_interval(
+    conn: DbConn,
+    _admin: AdminToken,
+    username: String,
+    request: Json<UpdateIntervalOverrideRequest>,
+) -> Result<Status, Error> {
+    if let Some(seconds) = request.min_update_interval_seconds {
+        if seconds < MIN_UPDATE_INTERVAL_OVERRIDE_SECONDS {
+            return Err(Error::BadRequest(format!(
+                "`min_update_interval_seconds` must be at least {}",
+                MIN_UPDATE_INTERVAL_OVERRIDE_SECONDS
+            )));
+        }
+    }
+    let interval_seconds = request
+        .min_update_interval_seconds
+        .map(i32::try_from)
+        .transpose()
+        .map_err(|_| Error::BadRequest("`min_update_interval_seconds` is too large".into()))?;
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => return Err(Error::NotFound("User not found".into())),
+    };
+    db_util::set_min_update_interval_override(&conn, user.id, interval_seconds).await?;
+    info!(
+        "Set minimum update interval override for user {} to {:?} seconds",
+        user.spotify_id, interval_seconds
+    );
+    Ok(Status::NoContent)
+}
+
+/// Resets the consecutive update failure count for a user so that they're picked up by automatic
+/// updates again.  If `retry` is set, the user is also updated immediately.
+#[post("/admin/users/<username>/reset_update_failures?<retry>")]
+pub(crate) async fn reset_user_update_failures(
+    conn: DbConn,
+    _admin: AdminToken,
+    username: String,
+    retry: Option<bool>,
+) -> Result<status::Custom<String>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => return Err(Error::NotFound("User not found".into())),
+    };
+    db_util::reset_update_failures(&conn, user.id).await?;
+
+    if !retry.unwrap_or(false) {
+        return Ok(status::Custom(
+            Status::Ok,
+            "Update failure count reset".into(),
+        ));
+    }
+
+    match update_user_inner(&conn, Some(user.spotify_id)).await {
+        Ok(UpdateOutcome::Updated) => {
+            user_updates_success_total().inc();
+            Ok(status::Custom(
+                Status::Ok,
+                "Update failure count reset; user updated".into(),
+            ))
+        },
+        Ok(UpdateOutcome::Skipped) => Ok(status::Custom(
+            Status::Ok,
+            "Update failure count reset; user was updated too recently to update again".into(),
+        )),
+        Err(err) => {
+            user_updates_failure_total().inc();
+            Err(err)
+        },
+    }
+}
+
+#[post("/populate_tracks_artists_mapping_table")]
+pub(crate) async fn populate_tracks_artists_mapping_table(
+    conn: DbConn,
+    _admin: AdminToken,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+) -> Result<status::Custom<String>, Error> {
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+
+    crate::db_util::populate_tracks_artists_table(&conn, &spotify_access_token).await?;
+
+    Ok(status::Custom(
+        Status::Ok,
+        "Sucessfully populated mapping table".into(),
+    ))
+}
+
+#[post("/populate_artists_genres_mapping_table")]
+pub(crate) async fn populate_artists_genres_mapping_table(
+    conn: DbConn,
+    _admin: AdminToken,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+) -> Result<status::Custom<String>, Error> {
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+
+    crate::db_util::populate_artists_genres_table(&conn, &spotify_access_token).await?;
+
+    Ok(status::Custom(
+        Status::Ok,
+        "Sucessfully populated mapping table".into(),
+    ))
+}
 
-    Ok(Some(created_playlist))
+/// Semi-private users, which have made their comparisons friends-only, can only be compared with
+/// when the request is authenticated as the user themselves or as the other user being compared if
+/// they're friends.  Used by both the REST and GraphQL comparisons.
+pub(crate) async fn comparison_allowed(
+    conn: &DbConn,
+    users: [(&User, &UserSettingsEntry); 2],
+    current_user: Option<&CurrentUser>,
+) -> Result<bool, Error> {
+    let requested_by = current_user.map(|current_user| current_user.spotify_id.as_str());
+    for (i, (user, settings)) in users.iter().enumerate() {
+        if !settings.friends_only_comparisons {
+            continue;
+        }
+        let other_user = users[1 - i].0;
+        let allowed = if requested_by == Some(user.spotify_id.as_str()) {
+            true
+        } else if requested_by == Some(other_user.spotify_id.as_str()) {
+            db_util::are_friends(conn, user, other_user).await?
+        } else {
+            false
+        };
+        if !allowed {
+            return Ok(false);
+        }
+    }
+    Ok(true)
 }
 
-/// This handles the OAuth authentication process for new users.  It is hit as the callback for the
-/// authentication request and handles retrieving user tokens, creating an entry for the user in the
-/// users table, and fetching an initial stats snapshot.
-#[get("/oauth_cb?<error>&<code>&<state>")]
-pub(crate) async fn oauth_cb(
+async fn compute_comparison(
+    user1: String,
+    user2: String,
     conn1: DbConn,
     conn2: DbConn,
     conn3: DbConn,
     conn4: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
-    error: Option<&str>,
-    code: &str,
-    state: Option<&str>,
-) -> Result<Redirect, String> {
-    use crate::schema::users;
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    current_user: Option<CurrentUser>,
+    access_token: &PrivateAccessToken,
+) -> Result<Option<ComparisonResult>, Error> {
+    let (user1_res, user2_res) = tokio::join!(
+        async move {
+            db_util::get_user_by_spotify_id(&conn1, user1)
+                .await
+                .map(|user_opt| user_opt.map(|user| (user, conn1)))
+        },
+        async move {
+            db_util::get_user_by_spotify_id(&conn2, user2)
+                .await
+                .map(|user_opt| user_opt.map(|user| (user, conn2)))
+        },
+    );
+    let (user1, conn1) = match user1_res? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+    let (user2, conn2) = match user2_res? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+    if !access_token.grants_access_to(&user1) || !access_token.grants_access_to(&user2) {
+        return Ok(None);
+    }
+    let (user1_id, user2_id) = (user1.id, user2.id);
 
-    if error.is_some() {
-        error!("Error during Oauth authorization process: {:?}", error);
-        return Err("An error occured while authenticating with Spotify.".into());
+    let (user1_settings, user2_settings) = tokio::try_join!(
+        db_util::get_user_settings(&conn1, &user1),
+        db_util::get_user_settings(&conn2, &user2),
+    )?;
+    if !comparison_allowed(
+        &conn1,
+        [(&user1, &user1_settings), (&user2, &user2_settings)],
+        current_user.as_ref(),
+    )
+    .await?
+    {
+        return Ok(None);
     }
 
-    let oauth_cb_url = crate::conf::CONF.get_absolute_oauth_cb_uri();
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
 
-    // Shoot the code back to Spotify and get an API token for the user in return
-    let mut params = HashMap::default();
-    params.insert("grant_type", "authorization_code");
-    params.insert("code", code);
-    params.insert("redirect_uri", oauth_cb_url.as_str());
-    params.insert("client_id", CONF.client_id.as_str());
-    params.insert("client_secret", CONF.client_secret.as_str());
+    let stats = tokio::try_join!(
+        crate::db_util::get_all_top_tracks_for_user(&conn1, user1_id),
+        crate::db_util::get_all_top_tracks_for_user(&conn2, user2_id),
+        crate::db_util::get_all_top_artists_for_user(&conn3, user1_id),
+        crate::db_util::get_all_top_artists_for_user(&conn4, user2_id),
+    )?;
+    let (user1_tracks, user2_tracks, user1_artists, user2_artists) = stats;
 
-    let client = get_reqwest_client().await;
-    info!("Making request to fetch user token from OAuth CB response...");
-    let res = client
-        .post(SPOTIFY_TOKEN_FETCH_URL)
-        .form(&params)
-        .send()
-        .await
-        .map_err(|_| -> String {
-            "Error fetching token from Spotify from response Oauth code".into()
-        })?;
+    let latest = tokio::try_join!(
+        crate::db_util::get_latest_top_track_ids(&conn1, user1_id),
+        crate::db_util::get_latest_top_track_ids(&conn2, user2_id),
+        crate::db_util::get_latest_top_artist_ids(&conn3, user1_id),
+        crate::db_util::get_latest_top_artist_ids(&conn4, user2_id),
+    )?;
+    let (user1_latest_tracks, user2_latest_tracks, user1_latest_artists, user2_latest_artists) =
+        latest;
 
-    let res: OAuthTokenResponse = match res.json().await {
-        Ok(res) => res,
-        Err(err) => {
-            error!("Failed to fetch user tokens from OAuth CB code: {:?}", err);
-            return Err("Error parsing response from token fetch endpoint".into());
-        },
+    let (user1_popularity, user2_popularity) = tokio::try_join!(
+        db_util::get_latest_popularity_rankings(&conn1, user1_id),
+        db_util::get_latest_popularity_rankings(&conn2, user2_id),
+    )?;
+    let current_mainstream_score = |rows: &[PopularityRankingRow], timeframe: Timeframe| {
+        compute_mainstream_scores(rows)
+            .into_iter()
+            .find(|((score_timeframe, _), _)| *score_timeframe == timeframe)
+            .map(|(_, (score, ..))| score)
     };
+    let mut mainstream_by_timeframe = HashMap::default();
+    for timeframe in Timeframe::ALL {
+        mainstream_by_timeframe.insert(timeframe.name(), MainstreamComparison {
+            user1: current_mainstream_score(&user1_popularity, timeframe),
+            user2: current_mainstream_score(&user2_popularity, timeframe),
+        });
+    }
 
-    let (access_token, refresh_token) = match res {
-        OAuthTokenResponse::Success {
-            access_token,
-            refresh_token,
-            token_type,
-            ..
-        } => {
-            info!("Successfully received token of type: {}", token_type);
-            (access_token, refresh_token)
-        },
-        OAuthTokenResponse::Error {
-            error,
-            error_description,
-        } => {
-            error!(
-                "Error fetching tokens for user: {}; {}",
-                error, error_description
-            );
-            return Err("Error fetching user access tokens from Spotify API.".into());
-        },
+    let ids_for_timeframe = |items: &[(Timeframe, String)], timeframe: Timeframe| -> Vec<String> {
+        items
+            .iter()
+            .filter(|(item_timeframe, _)| *item_timeframe == timeframe)
+            .map(|(_, spotify_id)| spotify_id.clone())
+            .collect()
     };
+    let mut overlap_by_timeframe = HashMap::default();
+    for timeframe in Timeframe::ALL {
+        overlap_by_timeframe.insert(timeframe.name(), TimeframeOverlap {
+            artists: crate::stats::compute_overlap_percentage(
+                &ids_for_timeframe(&user1_latest_artists, timeframe),
+                &ids_for_timeframe(&user2_latest_artists, timeframe),
+            ),
+            tracks: crate::stats::compute_overlap_percentage(
+                &ids_for_timeframe(&user1_latest_tracks, timeframe),
+                &ids_for_timeframe(&user2_latest_tracks, timeframe),
+            ),
+        });
+    }
 
-    info!("Fetched user tokens.  Inserting user into database...");
+    // Unique favorites are a user's current top items, in order of timeframe and ranking, that have
+    // never shown up in the other user's top items
+    let unique_favorite_ids = |latest: &[(Timeframe, String)], other_all_time: &[(i32, String)]| {
+        let other_all_time: FnvHashSet<&str> = other_all_time
+            .iter()
+            .map(|(_, spotify_id)| spotify_id.as_str())
+            .collect();
+        let mut seen: FnvHashSet<&str> = FnvHashSet::default();
+        latest
+            .iter()
+            .map(|(_, spotify_id)| spotify_id.as_str())
+            .filter(|spotify_id| !other_all_time.contains(spotify_id) && seen.insert(spotify_id))
+            .take(UNIQUE_FAVORITES_COUNT)
+            .map(String::from)
+            .collect::<Vec<_>>()
+    };
+    let user1_unique_artist_ids = unique_favorite_ids(&user1_latest_artists, &user2_artists);
+    let user2_unique_artist_ids = unique_favorite_ids(&user2_latest_artists, &user1_artists);
+    let user1_unique_track_ids = unique_favorite_ids(&user1_latest_tracks, &user2_tracks);
+    let user2_unique_track_ids = unique_favorite_ids(&user2_latest_tracks, &user1_tracks);
 
-    // Fetch the user's username and spotify ID from the Spotify API
-    let user_profile_info = crate::spotify_api::get_user_profile_info(&access_token).await?;
-    let user_spotify_id = user_profile_info.id;
-    let username = user_profile_info.display_name;
+    let mut tracks_intersection = user1_tracks;
+    tracks_intersection.retain(|(id, _)| user2_tracks.iter().any(|(o_id, _)| *o_id == *id));
+    let mut artists_intersection = user1_artists;
+    artists_intersection.retain(|(id, _)| user2_artists.iter().any(|(o_id, _)| *o_id == *id));
 
-    let user = NewUser {
-        creation_time: Utc::now().naive_utc(),
-        last_update_time: Utc::now().naive_utc(),
-        spotify_id: user_spotify_id.clone(),
-        username: username.clone(),
-        token: access_token.clone(),
-        refresh_token: refresh_token.clone(),
-    };
-
-    let query = diesel::insert_into(crate::schema::users::table).values(user);
-    match conn1.run(move |conn| query.execute(conn)).await {
-        Err(diesel::result::Error::DatabaseError(
-            diesel::result::DatabaseErrorKind::UniqueViolation,
-            _,
-        )) => {
-            let query = diesel::update(users::table)
-                .filter(users::dsl::spotify_id.eq(user_spotify_id.clone()))
-                .set((
-                    users::dsl::refresh_token.eq(refresh_token),
-                    users::dsl::token.eq(access_token.clone()),
-                ));
-            conn1
-                .run(move |conn| query.execute(conn))
-                .await
-                .map_err(|err| {
-                    error!(
-                        "Error updating tokens for user id={}: {:?}",
-                        user_spotify_id, err
-                    );
-                    String::from("Internal error occurred when trying to update user")
-                })?;
+    let track_ids = tracks_intersection
+        .iter()
+        .map(|(_, spotify_id)| spotify_id)
+        .chain(user1_unique_track_ids.iter())
+        .chain(user2_unique_track_ids.iter())
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let artist_ids = artists_intersection
+        .iter()
+        .map(|(_, spotify_id)| spotify_id)
+        .chain(user1_unique_artist_ids.iter())
+        .chain(user2_unique_artist_ids.iter())
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let (tracks_by_id, artists_by_id) = tokio::try_join!(
+        crate::spotify_api::fetch_tracks(&spotify_access_token, &track_ids),
+        crate::spotify_api::fetch_artists(&spotify_access_token, &artist_ids),
+    )?;
 
-            info!("Already have a row for user; skipping manual update and redirecting directly.");
-        },
-        Err(err) => {
-            error!("Error inserting row: {:?}", err);
-            return Err("Error inserting user into database".into());
+    fn lookup<'a, T: Clone>(
+        entities_by_id: &HashMap<String, T>,
+        spotify_ids: impl IntoIterator<Item = &'a String>,
+    ) -> Vec<T> {
+        spotify_ids
+            .into_iter()
+            .map(|spotify_id| entities_by_id[spotify_id].clone())
+            .collect()
+    }
+    let tracks = lookup(&tracks_by_id, tracks_intersection.iter().map(|(_, id)| id));
+    let artists = lookup(
+        &artists_by_id,
+        artists_intersection.iter().map(|(_, id)| id),
+    );
+    let user1_unique_tracks = lookup(&tracks_by_id, &user1_unique_track_ids);
+    let user2_unique_tracks = lookup(&tracks_by_id, &user2_unique_track_ids);
+    let user1_unique_artists = lookup(&artists_by_id, &user1_unique_artist_ids);
+    let user2_unique_artists = lookup(&artists_by_id, &user2_unique_artist_ids);
+
+    Ok(Some(ComparisonResult {
+        tracks,
+        artists,
+        genres: Vec::new(), // TODO
+        user1_username: user1_settings.display_name(&user1).to_owned(),
+        user2_username: user2_settings.display_name(&user2).to_owned(),
+        overlap_by_timeframe,
+        user1_unique_favorites: UniqueFavorites {
+            artists: user1_unique_artists,
+            tracks: user1_unique_tracks,
         },
-        Ok(_) => {
-            // Retrieve the inserted user row
-            let user = crate::db_util::get_user_by_spotify_id(&conn1, user_spotify_id.clone())
-                .await?
-                .expect("Failed to load just inserted user from database");
-
-            // Create an initial stats snapshot to store for the user
-            let cur_user_stats = match crate::spotify_api::fetch_cur_stats(&user).await? {
-                Some(stats) => stats,
-                None => {
-                    error!(
-                        "Failed to fetch stats for user \"{}\"; bad response from Spotify API?",
-                        username
-                    );
-                    return Err("Error fetching user stats from the Spotify API.".into());
-                },
-            };
-
-            crate::spotify_api::store_stats_snapshot(&conn1, &user, cur_user_stats).await?;
+        user2_unique_favorites: UniqueFavorites {
+            artists: user2_unique_artists,
+            tracks: user2_unique_tracks,
         },
-    };
-
-    match state {
-        Some(s) if !s.is_empty() => {
-            if s == "galaxy" {
-                return Ok(Redirect::to(format!(
-                    "https://galaxy.spotifytrack.net/?spotifyID={}",
-                    user_spotify_id
-                )));
-            }
+        mainstream_by_timeframe,
+    }))
+}
 
-            let s = RawStr::new(s);
-            let percent_decoded =
-                s.percent_decode()
-                    .map(|s| -> String { s.into() })
-                    .map_err(|_| {
-                        error!("Invalid URL-Encoded `state` param; dropping");
-                        "Invalid URL-encoded `state` param provided; can't parse.".to_string()
-                    })?;
+/// Compares two users' top artists and tracks.  Users that have made their comparisons
+/// friends-only can only be compared with when the request is authenticated as the user
+/// themselves or as the other user being compared, if they're friends.  Private users can only be
+/// compared with if the supplied private token grants access to them.
+#[get("/compare/<user1>/<user2>")]
+pub(crate) async fn compare_users(
+    conn1: DbConn,
+    conn2: DbConn,
+    conn3: DbConn,
+    conn4: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    current_user: Option<CurrentUser>,
+    access_token: PrivateAccessToken,
+    user1: String,
+    user2: String,
+) -> Result<Option<Json<ComparisonResult>>, Error> {
+    compute_comparison(
+        user1,
+        user2,
+        conn1,
+        conn2,
+        conn3,
+        conn4,
+        token_data,
+        current_user,
+        &access_token,
+    )
+    .await
+    .map(|res| res.map(Json))
+}
 
-            match serde_json::from_str(percent_decoded.as_ref()) {
-                Ok(CreateSharedPlaylistRequest { user1_id, user2_id }) => {
-                    let playlist = generate_shared_playlist(
-                        conn1,
-                        conn2,
-                        conn3,
-                        conn4,
-                        token_data,
-                        &access_token,
-                        &user1_id,
-                        &user2_id,
-                    )
-                    .await?;
+async fn build_related_artists_graph(
+    spotify_access_token: String,
+    artist_ids: &[&str],
+) -> Result<RelatedArtistsGraph, Error> {
+    // Get related artists for all of them
+    let related_artists =
+        get_multiple_related_artists(spotify_access_token.clone(), artist_ids).await?;
 
-                    match playlist {
-                        Some(playlist) => {
-                            let encoded_playlist = serde_json::to_string(&serde_json::json!({
-                                "uri": playlist.uri,
-                                "track_count": playlist.tracks.total,
-                                "name": playlist.name
-                            }))
-                            .map_err(|err| {
-                                error!("Error JSON-encoding playlist: {:?}", err);
-                                "Internal error while generating playlist".to_string()
-                            })?;
-                            let encoded_playlist =
-                                RawStr::percent_encode(&RawStr::new(encoded_playlist.as_str()));
-                            let redirect_url = format!(
-                                "{}/compare/{}/{}?playlist={}",
-                                CONF.website_url, user1_id, user2_id, encoded_playlist
-                            );
-                            return Ok(Redirect::to(redirect_url));
-                        },
-                        None =>
-                            return Err(format!(
-                                "One or both of the supplied users has never {}",
-                                "connected to Spotifytrack before"
-                            )),
-                    }
-                },
-                Err(err) => {
-                    if let Ok(CompareToRequest { compare_to }) =
-                        serde_json::from_str(percent_decoded.as_ref())
-                    {
-                        let redirect_url = format!(
-                            "{}/compare/{}/{}",
-                            CONF.website_url, compare_to, user_spotify_id
-                        );
-                        return Ok(Redirect::to(redirect_url));
-                    }
+    let all_artist_ids: FnvHashSet<String> = artist_ids
+        .iter()
+        .copied()
+        .map(String::from)
+        .chain(
+            related_artists
+                .iter()
+                .flat_map(|related_artists| related_artists.iter().cloned()),
+        )
+        .collect();
 
-                    warn!(
-                        "Error parsing JSON body of what we presume is a playlist generation \
-                         request: {:?}",
-                        err
-                    );
-                    return Err("Error parsing state param for presumed shared playlist \
-                                generation request"
-                        .into());
-                },
-            }
-        },
-        _ => (),
+    let mut related_artists_by_id = HashMap::default();
+    for (&artist_id, related_artists) in artist_ids.into_iter().zip(related_artists.iter()) {
+        related_artists_by_id.insert(artist_id.to_owned(), related_artists.clone());
     }
 
-    let redirect_url = match state {
-        Some(s) if s.starts_with("/") => format!("{}{}", CONF.website_url, s),
-        _ => format!("{}/stats/{}", CONF.website_url, user_spotify_id),
-    };
-
-    // Redirect the user to their stats page
-    Ok(Redirect::to(redirect_url))
-}
+    let all_artist_ids: Vec<_> = all_artist_ids.iter().map(String::as_str).collect();
+    let extra_artists = fetch_artists(&spotify_access_token, &all_artist_ids).await?;
 
-/// Returns `true` if the token is valid, false if it's not
-async fn validate_api_token(api_token_data: rocket::data::Data<'_>) -> Result<bool, String> {
-    let api_token = api_token_data
-        .open(1usize.mebibytes())
-        .into_string()
-        .await
-        .map_err(|err| {
-            error!("Error reading provided admin API token: {:?}", err);
-            String::from("Error reading post data body")
-        })?
-        .into_inner();
-    Ok(api_token == CONF.admin_api_token)
+    Ok(RelatedArtistsGraph {
+        extra_artists,
+        related_artists: related_artists_by_id,
+    })
 }
 
-async fn update_user_inner(
-    conn: &DbConn,
-    user_id: Option<String>,
-) -> Result<(), status::Custom<String>> {
-    use crate::schema::users::dsl::*;
+#[get("/stats/<user_id>/related_artists_graph")]
+pub(crate) async fn get_related_artists_graph(
+    conn: DbConn,
+    user_id: String,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    access_token: PrivateAccessToken,
+) -> Result<Option<Json<RelatedArtistsGraph>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, user_id).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        },
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let user_id = user.id;
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
 
-    // Get the least recently updated user
-    let mut user: User = match user_id.clone().map(|s| -> Result<String, _> {
-        let s = RawStr::new(s.as_str());
-        match s.percent_decode() {
-            Ok(decoded) => Ok(decoded.into()),
-            Err(err) => Err(err),
-        }
-    }) {
-        Some(s) => {
-            let user_id: String = s.map_err(|_| {
-                error!("Invalid `user_id` param provided to `/update/user`");
-                status::Custom(
-                    Status::BadRequest,
-                    String::from("Invalid `user_id` param; couldn't decode"),
-                )
+    // Start off by getting all artists for the user from all timeframes
+    let all_artists_for_user =
+        get_all_top_artists_for_user(&conn, user_id)
+            .await
+            .map_err(|err| {
+                error!("Error fetching all artists for user: {:?}", err);
+                Error::Internal("Internal DB error".into())
             })?;
+    let all_artist_ids_for_user: Vec<&str> = all_artists_for_user
+        .iter()
+        .map(|(_internal_id, spotify_id)| spotify_id.as_str())
+        .collect();
 
-            conn.run(move |conn| users.filter(spotify_id.eq(user_id)).first(conn))
-                .await
+    let out = build_related_artists_graph(spotify_access_token, &all_artist_ids_for_user).await?;
+    Ok(Some(Json(out)))
+}
+
+/// Returns a graph of the user's current top artists across all timeframes with edges between the
+/// artists that Spotify lists as related to each other, used to render the music taste map.
+#[get("/stats/<username>/artist_graph")]
+pub(crate) async fn get_artist_graph(
+    conn: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    username: String,
+    access_token: PrivateAccessToken,
+) -> Result<Option<Json<ArtistGraph>>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
         },
-        None =>
-            conn.run(move |conn| users.order_by(last_update_time).first(conn))
-                .await,
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
     }
-    .map_err(|err| {
-        error!("{:?}", err);
-        status::Custom(
-            Status::InternalServerError,
-            "Error querying user to update from database".into(),
-        )
-    })?;
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
 
-    if let Some(res) = db_util::refresh_user_access_token(&conn, &mut user)
-        .await
-        .map_err(|err| status::Custom(Status::InternalServerError, err))?
+    let artist_stats = match db_util::get_artist_stats(
+        &user,
+        conn,
+        &spotify_access_token,
+        None,
+        SnapshotFilter::default(),
+    )
+    .await?
     {
-        return Err(res);
-    }
+        Some(artist_stats) => artist_stats,
+        None => return Ok(None),
+    };
 
-    // Only update the user if it's been longer than the minimum update interval
-    let min_update_interval_seconds = crate::conf::CONF.min_update_interval;
-    let now = chrono::Utc::now().naive_utc();
-    let diff = now - user.last_update_time;
-    if user_id.is_none() && diff < min_update_interval_seconds {
-        let msg = format!(
-            "{} since last update; not updating anything right now.",
-            diff
-        );
-        info!("{}", msg);
-        return Err(status::Custom(Status::Ok, msg));
-    }
-    info!("{diff} since last update; proceeding with update.");
+    // The same artist is usually present in multiple timeframes
+    let mut seen_artist_ids = FnvHashSet::default();
+    let nodes: Vec<Artist> = artist_stats
+        .into_iter()
+        .map(|(_timeframe, artist)| artist)
+        .filter(|artist| seen_artist_ids.insert(artist.id.clone()))
+        .collect();
+    let artist_ids: Vec<&str> = nodes.iter().map(|artist| artist.id.as_str()).collect();
 
-    if let Err(err) =
-        crate::db_util::update_user_last_updated(&user, &conn, Utc::now().naive_utc()).await
-    {
-        error!(
-            "Error updating user {:?} last updated time: {:?}",
-            user, err
-        );
-        return Err(status::Custom(
-            Status::InternalServerError,
-            "Error updating user last updated time".into(),
-        ));
-    }
+    // Related artists are cached in Redis, so this only hits the Spotify API for new artists
+    let related_artists = get_multiple_related_artists(spotify_access_token, &artist_ids).await?;
+    let edges = crate::stats::build_artist_graph_edges(&artist_ids, &related_artists);
 
-    let stats = match crate::spotify_api::fetch_cur_stats(&user).await {
-        Ok(Some(stats)) => stats,
-        Ok(None) => {
-            error!(
-                "Error when fetching stats for user {:?}; no stats returned.",
-                user
-            );
-            return Err(status::Custom(
-                Status::InternalServerError,
-                "No data from Spotify API for that user".into(),
-            ));
-        },
-        Err(err) => {
-            error!("Error fetching user stats: {:?}", err);
-            return Err(status::Custom(
-                Status::InternalServerError,
-                "Error fetching user stats".into(),
-            ));
-        },
-    };
+    Ok(Some(Json(ArtistGraph { nodes, edges })))
+}
 
-    crate::spotify_api::store_stats_snapshot(&conn, &user, stats)
-        .await
-        .map_err(|err| status::Custom(Status::InternalServerError, err))?;
+#[get("/related_artists/<artist_id>")]
+pub(crate) async fn get_related_artists(
+    artist_id: String,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+) -> Result<Option<Json<RelatedArtistsGraph>>, Error> {
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
 
-    info!("Successfully updated user {}", user.spotify_id);
+    let related_artist_ids =
+        get_multiple_related_artists(spotify_access_token.clone(), &[&artist_id]).await?;
+    let related_artist_ids = match related_artist_ids.into_iter().next() {
+        Some(ids) => ids,
+        None => {
+            error!("Empty vec returned from `get_multiple_related_artists`");
+            return Ok(None);
+        },
+    };
+    let related_artist_ids = related_artist_ids
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
 
-    Ok(())
+    let out = build_related_artists_graph(spotify_access_token, &related_artist_ids).await?;
+    Ok(Some(Json(out)))
 }
 
-/// This route is internal and hit by the cron job that is called to periodically update the stats
-/// for the least recently updated user.
-#[post("/update_user?<user_id>&<count>", data = "<api_token_data>")]
-pub(crate) async fn update_user(
-    conn: DbConn,
-    api_token_data: rocket::data::Data<'_>,
-    user_id: Option<String>,
-    count: Option<usize>,
-) -> Result<status::Custom<String>, String> {
-    if !validate_api_token(api_token_data).await? {
-        return Ok(status::Custom(
-            Status::Unauthorized,
-            "Invalid API token supplied".into(),
-        ));
+/// Pairs each stored global chart entry with its fetched entity metadata, grouping them by
+/// timeframe.
+fn build_global_chart<T: serde::Serialize + Clone>(
+    rows: Vec<(Timeframe, u16, String, i64, i64, NaiveDateTime)>,
+    items_by_id: HashMap<String, T>,
+) -> GlobalChart<T> {
+    let computed_at = rows.iter().map(|row| row.5).max();
+    let mut charts = TimeFrames::default();
+    for (timeframe, ranking, spotify_id, user_count, score, _) in rows {
+        charts.add_item(timeframe, GlobalChartEntry {
+            ranking: ranking + 1,
+            user_count,
+            score,
+            item: items_by_id[&spotify_id].clone(),
+        });
     }
 
-    if let Some(user_id) = user_id {
-        if let Err(status) = update_user_inner(&conn, Some(user_id)).await {
-            user_updates_failure_total().inc();
-            return Ok(status);
-        }
-        user_updates_success_total().inc();
-        return Ok(status::Custom(Status::Ok, "User updated".into()));
+    GlobalChart {
+        computed_at,
+        charts,
     }
+}
 
-    let count = count.unwrap_or(1);
-    let mut success_count = 0usize;
-    let mut fail_count = 0usize;
-    for _ in 0..count {
-        let success = update_user_inner(&conn, None).await.is_ok();
-        if success {
-            user_updates_success_total().inc();
-            success_count += 1;
-        } else {
-            user_updates_failure_total().inc();
-            fail_count += 1;
-        }
-        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-    }
+/// Returns the most popular artists across all users for each timeframe.  The charts are
+/// precomputed by the update scheduler, so they may be up to
+/// `GLOBAL_CHARTS_REFRESH_INTERVAL_SECONDS` out of date.
+#[get("/charts/top_artists?<limit>")]
+pub(crate) async fn get_global_top_artists(
+    conn: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    limit: Option<u16>,
+) -> Result<Json<GlobalChart<Artist>>, Error> {
+    let limit = limit
+        .unwrap_or(50)
+        .clamp(1, db_util::GLOBAL_CHART_SIZE as u16);
+    let rows = db_util::get_global_chart(&conn, ExportEntity::Artists, limit).await?;
 
-    Ok(status::Custom(
-        Status::Ok,
-        format!(
-            "Successfully updated {} user(s); failed to update {} user(s)",
-            success_count, fail_count
-        ),
-    ))
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().await;
+        token_data.get().await
+    }?;
+    let spotify_ids = rows.iter().map(|row| row.2.as_str()).collect::<Vec<_>>();
+    let artists = crate::spotify_api::fetch_artists(&spotify_access_token, &spotify_ids).await?;
+
+    Ok(Json(build_global_chart(rows, artists)))
 }
 
-#[post("/populate_tracks_artists_mapping_table", data = "<api_token_data>")]
-pub(crate) async fn populate_tracks_artists_mapping_table(
+/// Returns the most popular tracks across all users for each timeframe.  See
+/// `get_global_top_artists`.
+#[get("/charts/top_tracks?<limit>")]
+pub(crate) async fn get_global_top_tracks(
     conn: DbConn,
-    api_token_data: rocket::data::Data<'_>,
-    token_data: &State<Mutex<SpotifyTokenData>>,
-) -> Result<status::Custom<String>, String> {
-    if !validate_api_token(api_token_data).await? {
-        return Ok(status::Custom(
-            Status::Unauthorized,
-            "Invalid API token supplied".into(),
-        ));
-    }
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    limit: Option<u16>,
+) -> Result<Json<GlobalChart<Track>>, Error> {
+    let limit = limit
+        .unwrap_or(50)
+        .clamp(1, db_util::GLOBAL_CHART_SIZE as u16);
+    let rows = db_util::get_global_chart(&conn, ExportEntity::Tracks, limit).await?;
 
     let spotify_access_token = {
         let token_data = &mut *(&*token_data).lock().await;
         token_data.get().await
     }?;
+    let spotify_ids = rows.iter().map(|row| row.2.as_str()).collect::<Vec<_>>();
+    let tracks = crate::spotify_api::fetch_tracks(&spotify_access_token, &spotify_ids).await?;
 
-    crate::db_util::populate_tracks_artists_table(&conn, &spotify_access_token).await?;
-
-    Ok(status::Custom(
-        Status::Ok,
-        "Sucessfully populated mapping table".into(),
-    ))
+    Ok(Json(build_global_chart(rows, tracks)))
 }
 
-#[post("/populate_artists_genres_mapping_table", data = "<api_token_data>")]
-pub(crate) async fn populate_artists_genres_mapping_table(
+/// Returns the users that rank the artist highest in their latest snapshot.  Only users that have
+/// opted in via their settings are listed.
+#[get("/leaderboard/artist/<artist_id>?<limit>")]
+pub(crate) async fn get_artist_leaderboard(
     conn: DbConn,
-    api_token_data: rocket::data::Data<'_>,
-    token_data: &State<Mutex<SpotifyTokenData>>,
-) -> Result<status::Custom<String>, String> {
-    if !validate_api_token(api_token_data).await? {
-        return Ok(status::Custom(
-            Status::Unauthorized,
-            "Invalid API token supplied".into(),
-        ));
-    }
-
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    artist_id: String,
+    limit: Option<u32>,
+) -> Result<Option<Json<ArtistLeaderboard>>, Error> {
     let spotify_access_token = {
         let token_data = &mut *(&*token_data).lock().await;
         token_data.get().await
     }?;
+    let artist = match crate::spotify_api::fetch_artists(&spotify_access_token, &[&artist_id])
+        .await?
+        .remove(artist_id.as_str())
+    {
+        Some(artist) => artist,
+        None => return Ok(None),
+    };
 
-    crate::db_util::populate_artists_genres_table(&conn, &spotify_access_token).await?;
+    let limit = limit.unwrap_or(50).clamp(1, 100);
+    let entries = db_util::get_artist_leaderboard(&conn, artist_id, limit)
+        .await?
+        .into_iter()
+        .enumerate()
+        .map(
+            |(i, (spotify_id, vanity_slug, display_name, best_ranking))| ArtistLeaderboardEntry {
+                position: i + 1,
+                spotify_id,
+                vanity_slug,
+                display_name,
+                best_ranking: best_ranking + 1,
+            },
+        )
+        .collect();
 
-    Ok(status::Custom(
-        Status::Ok,
-        "Sucessfully populated mapping table".into(),
-    ))
+    Ok(Some(Json(ArtistLeaderboard { artist, entries })))
 }
 
-async fn compute_comparison(
-    user1: String,
-    user2: String,
-    conn1: DbConn,
-    conn2: DbConn,
-    conn3: DbConn,
-    conn4: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
-) -> Result<Option<UserComparison>, String> {
-    let (user1_res, user2_res) = tokio::join!(
-        async move {
-            db_util::get_user_by_spotify_id(&conn1, user1)
-                .await
-                .map(|user_opt| user_opt.map(|user| (user, conn1)))
-        },
-        async move {
-            db_util::get_user_by_spotify_id(&conn2, user2)
-                .await
-                .map(|user_opt| user_opt.map(|user| (user, conn2)))
-        },
-    );
-    let (user1, conn1) = match user1_res? {
-        Some(user) => user,
-        None => {
-            return Ok(None);
-        },
+/// Returns the totals displayed on the about page.  They're computed by the update scheduler, so
+/// they may be up to `SYSTEM_STATS_REFRESH_INTERVAL_SECONDS` out of date.
+#[get("/about/stats")]
+pub(crate) async fn get_about_stats(conn: DbConn) -> Result<Json<AboutStats>, Error> {
+    let stats = db_util::get_latest_system_stats(&conn)
+        .await?
+        .ok_or_else(|| Error::NotFound("Stats haven't been computed yet".into()))?;
+    Ok(Json(AboutStats {
+        user_count: stats.user_count,
+        snapshot_count: stats.snapshot_count,
+        computed_at: stats.computed_at,
+    }))
+}
+
+/// How long the global summary is served from the cache before being recomputed
+const GLOBAL_SUMMARY_CACHE_TTL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+/// Returns anonymized aggregates across all users, limited to those enabled with
+/// `PUBLIC_STATS_FIELDS`.  Totals come from the last time the scheduler computed the system stats
+/// and the most tracked artist from the last time it refreshed the global charts.  The summary is
+/// cached for `GLOBAL_SUMMARY_CACHE_TTL`.
+#[get("/stats/global/summary")]
+pub(crate) async fn get_global_summary(
+    conn: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+) -> Result<Json<GlobalSummary>, Error> {
+    if CONF.public_stats_fields.is_empty() {
+        return Err(Error::NotFound(
+            "Public stats are disabled on this server".into(),
+        ));
+    }
+
+    // Keyed by the enabled fields so that a cached summary is never served with fields that have
+    // since been disabled
+    let cache_key = CONF
+        .public_stats_fields
+        .iter()
+        .map(|field| field.name())
+        .collect::<Vec<_>>()
+        .join(",");
+    let cached = block_in_place(|| {
+        get_fresh_hash_items::<GlobalSummary>(
+            "globalSummary",
+            &[&cache_key],
+            GLOBAL_SUMMARY_CACHE_TTL,
+        )
+    })
+    .unwrap_or_else(|err| {
+        warn!("Error checking cache for global summary: {}", err);
+        Vec::new()
+    })
+    .into_iter()
+    .next()
+    .flatten();
+    if let Some(summary) = cached {
+        return Ok(Json(summary));
+    }
+
+    let mut summary = GlobalSummary {
+        user_count: None,
+        snapshot_count: None,
+        most_tracked_artist: None,
+        median_genre_diversity: None,
     };
-    let (user2, conn2) = match user2_res? {
+
+    if CONF.is_public_stats_field_enabled(PublicStatsField::UserCount)
+        || CONF.is_public_stats_field_enabled(PublicStatsField::SnapshotCount)
+    {
+        if let Some(stats) = db_util::get_latest_system_stats(&conn).await? {
+            if CONF.is_public_stats_field_enabled(PublicStatsField::UserCount) {
+                summary.user_count = Some(stats.user_count);
+            }
+            if CONF.is_public_stats_field_enabled(PublicStatsField::SnapshotCount) {
+                summary.snapshot_count = Some(stats.snapshot_count);
+            }
+        }
+    }
+
+    if CONF.is_public_stats_field_enabled(PublicStatsField::MostTrackedArtist) {
+        let top_entry = db_util::get_global_chart(&conn, ExportEntity::Artists, 1)
+            .await?
+            .into_iter()
+            .find(|(timeframe, ..)| *timeframe == Timeframe::Long);
+        if let Some((_, _, spotify_id, user_count, ..)) = top_entry {
+            let spotify_access_token = {
+                let token_data = &mut *(&*token_data).lock().await;
+                token_data.get().await
+            }?;
+            let mut artists =
+                crate::spotify_api::fetch_artists(&spotify_access_token, &[&spotify_id]).await?;
+            summary.most_tracked_artist = artists
+                .remove(&spotify_id)
+                .map(|artist| MostTrackedArtist { artist, user_count });
+        }
+    }
+
+    if CONF.is_public_stats_field_enabled(PublicStatsField::MedianDiversity) {
+        let genre_entropies = db_util::get_latest_genre_entropies(&conn, Timeframe::Long).await?;
+        summary.median_genre_diversity = crate::public_stats::median(genre_entropies);
+    }
+
+    if let Err(err) =
+        block_in_place(|| set_hash_items("globalSummary", &[(cache_key.as_str(), &summary)]))
+    {
+        warn!("Error storing global summary in cache: {}", err);
+    }
+
+    Ok(Json(summary))
+}
+
+/// Parses an optional `timeframe` param, defaulting to the timeframe selected in the user's
+/// settings
+fn resolve_timeframe(
+    timeframe: Option<String>,
+    settings: &UserSettingsEntry,
+) -> Result<Timeframe, Error> {
+    match timeframe {
+        Some(timeframe) => timeframe.parse(),
+        None => Ok(settings.default_timeframe),
+    }
+}
+
+/// Renders the user's top artists and tracks for a timeframe into a PNG for social media link
+/// previews.  Rendered cards are cached in Redis until the user is next updated.
+#[get("/share/<username>/card.png?<timeframe>")]
+pub(crate) async fn get_share_card(
+    conn: DbConn,
+    conn2: DbConn,
+    username: String,
+    access_token: PrivateAccessToken,
+    conditional: ConditionalRequest,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    timeframe: Option<String>,
+) -> Result<Option<Conditional<(ContentType, Vec<u8>)>>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
         Some(user) => user,
-        None => {
-            return Ok(None);
-        },
+        None => return Ok(None),
     };
-    let (user1_id, user2_id) = (user1.id, user2.id);
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let settings = db_util::get_user_settings(&conn, &user).await?;
+    let timeframe = resolve_timeframe(timeframe, &settings)?;
+    let validators = CacheValidators::for_user(&user);
+    if conditional.is_fresh(&validators) {
+        return Ok(Some(Conditional::not_modified(validators)));
+    }
+
+    let cached =
+        block_in_place(|| get_cached_share_card(user.id, user.last_update_time, timeframe.name()))
+            .unwrap_or_else(|err| {
+                warn!("Error reading from cache; skipping it: {}", err);
+                None
+            });
+    if let Some(png) = cached {
+        return Ok(Some(Conditional::new((ContentType::PNG, png), validators)));
+    }
 
     let spotify_access_token = {
         let token_data = &mut *(&*token_data).lock().await;
         token_data.get().await
     }?;
-    let spotify_access_token_clone = spotify_access_token.clone();
-
-    let stats = tokio::try_join!(
-        crate::db_util::get_all_top_tracks_for_user(&conn1, user1_id)
-            .map_err(db_util::stringify_diesel_err),
-        crate::db_util::get_all_top_tracks_for_user(&conn2, user2_id)
-            .map_err(db_util::stringify_diesel_err),
-        crate::db_util::get_all_top_artists_for_user(&conn3, user1_id)
-            .map_err(db_util::stringify_diesel_err),
-        crate::db_util::get_all_top_artists_for_user(&conn4, user2_id)
-            .map_err(db_util::stringify_diesel_err),
-    )?;
-    let (user1_tracks, user2_tracks, user1_artists, user2_artists) = stats;
-
-    let tracks_intersection = async move {
-        let mut intersection = user1_tracks;
-        intersection.retain(|(id, _)| user2_tracks.iter().any(|(o_id, _)| *o_id == *id));
-
-        let spotify_ids = intersection
-            .iter()
-            .map(|(_, spotify_id)| spotify_id.as_str())
-            .collect::<Vec<_>>();
-        crate::spotify_api::fetch_tracks(&spotify_access_token, &spotify_ids).await
+    let filter = SnapshotFilter {
+        limit: Some(share_card::CARD_ITEM_COUNT as u8),
+        timeframes: vec![timeframe],
     };
-    let artists_intersection = async move {
-        let mut intersection = user1_artists;
-        intersection.retain(|(id, _)| user2_artists.iter().any(|(o_id, _)| *o_id == *id));
-
-        let spotify_ids = intersection
-            .iter()
-            .map(|(_, spotify_id)| spotify_id.as_str())
-            .collect::<Vec<_>>();
-        crate::spotify_api::fetch_artists(&spotify_access_token_clone, &spotify_ids).await
+    let snapshot =
+        match db_util::load_snapshot(conn, conn2, &user, None, &spotify_access_token, filter)
+            .await?
+        {
+            Some(snapshot) => snapshot,
+            None => return Ok(None),
+        };
+    let (artists, tracks) = match timeframe {
+        Timeframe::Short => (snapshot.artists.short, snapshot.tracks.short),
+        Timeframe::Medium => (snapshot.artists.medium, snapshot.tracks.medium),
+        Timeframe::Long => (snapshot.artists.long, snapshot.tracks.long),
     };
-    let intersections = tokio::try_join!(tracks_intersection, artists_intersection)?;
-    let (tracks_intersection, artists_intersection) = intersections;
 
-    Ok(Some(UserComparison {
-        tracks: tracks_intersection,
-        artists: artists_intersection,
-        genres: Vec::new(), // TODO
-        user1_username: user1.username,
-        user2_username: user2.username,
-    }))
-}
+    let card = ShareCard {
+        display_name: settings.display_name(&user).to_owned(),
+        timeframe: timeframe.label(),
+        artists: artists.into_iter().map(|artist| artist.name).collect(),
+        tracks: tracks
+            .into_iter()
+            .map(|track| {
+                let artist_name = track
+                    .artists
+                    .first()
+                    .map(|artist| artist.name.clone())
+                    .unwrap_or_default();
+                (track.name, artist_name)
+            })
+            .collect(),
+    };
+    let png = block_in_place(|| share_card::render_share_card(&card))?;
+    if let Err(err) = block_in_place(|| {
+        set_cached_share_card(user.id, user.last_update_time, timeframe.name(), &png)
+    }) {
+        warn!("Error writing to cache: {}", err);
+    }
 
-#[get("/compare/<user1>/<user2>")]
-pub(crate) async fn compare_users(
-    conn1: DbConn,
-    conn2: DbConn,
-    conn3: DbConn,
-    conn4: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
-    user1: String,
-    user2: String,
-) -> Result<Option<Json<UserComparison>>, String> {
-    compute_comparison(user1, user2, conn1, conn2, conn3, conn4, token_data)
-        .await
-        .map(|res| res.map(Json))
+    Ok(Some(Conditional::new((ContentType::PNG, png), validators)))
 }
 
-async fn build_related_artists_graph(
-    spotify_access_token: String,
-    artist_ids: &[&str],
-) -> Result<RelatedArtistsGraph, String> {
-    // Get related artists for all of them
-    let related_artists =
-        get_multiple_related_artists(spotify_access_token.clone(), artist_ids).await?;
-
-    let all_artist_ids: FnvHashSet<String> = artist_ids
-        .iter()
-        .copied()
-        .map(String::from)
-        .chain(
-            related_artists
-                .iter()
-                .flat_map(|related_artists| related_artists.iter().cloned()),
-        )
-        .collect();
-
-    let mut related_artists_by_id = HashMap::default();
-    for (&artist_id, related_artists) in artist_ids.into_iter().zip(related_artists.iter()) {
-        related_artists_by_id.insert(artist_id.to_owned(), related_artists.clone());
+/// Page to link to when sharing a user's stats on social media.  Crawlers don't run the frontend's
+/// JavaScript, so this serves the OpenGraph tags pointing at the user's share card and redirects
+/// browsers to their stats page.
+#[get("/share/<username>?<timeframe>")]
+pub(crate) async fn get_share_page(
+    conn: DbConn,
+    username: String,
+    access_token: PrivateAccessToken,
+    timeframe: Option<String>,
+) -> Result<Option<RawHtml<String>>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
     }
+    let settings = db_util::get_user_settings(&conn, &user).await?;
+    let timeframe = resolve_timeframe(timeframe, &settings)?;
+
+    let user_slug = user.vanity_slug.as_deref().unwrap_or(&user.spotify_id);
+    let stats_url = format!("{}/stats/{}", CONF.website_url, user_slug);
+    let card_url = format!(
+        "{}/share/{}/card.png?timeframe={}",
+        CONF.api_server_url, user_slug, timeframe
+    );
+    let title = share_card::escape_xml(&format!(
+        "{}'s Top Artists and Tracks on Spotifytrack",
+        settings.display_name(&user)
+    ));
+    let description = format!("Top artists and tracks &#8226; {}", timeframe.label());
+    let stats_url = share_card::escape_xml(&stats_url);
+    let card_url = share_card::escape_xml(&card_url);
+
+    Ok(Some(RawHtml(format!(
+        r#"<!DOCTYPE html>
+<html>
+  <head>
+    <meta charset="utf-8" />
+    <title>{title}</title>
+    <meta property="og:title" content="{title}" />
+    <meta property="og:description" content="{description}" />
+    <meta property="og:url" content="{stats_url}" />
+    <meta property="og:type" content="profile" />
+    <meta property="og:image" content="{card_url}" />
+    <meta property="og:image:width" content="{card_width}" />
+    <meta property="og:image:height" content="{card_height}" />
+    <meta name="twitter:card" content="summary_large_image" />
+    <meta name="twitter:title" content="{title}" />
+    <meta name="twitter:image" content="{card_url}" />
+    <meta http-equiv="refresh" content="0; url={stats_url}" />
+  </head>
+  <body>
+    <a href="{stats_url}">{title}</a>
+  </body>
+</html>
+"#,
+        title = title,
+        description = description,
+        stats_url = stats_url,
+        card_url = card_url,
+        card_width = share_card::CARD_WIDTH,
+        card_height = share_card::CARD_HEIGHT,
+    ))))
+}
 
-    let all_artist_ids: Vec<_> = all_artist_ids.iter().map(String::as_str).collect();
-    let extra_artists_list = fetch_artists(&spotify_access_token, &all_artist_ids).await?;
-    let mut extra_artists = HashMap::default();
-    for artist in extra_artists_list {
-        extra_artists.insert(artist.id.clone(), artist);
+/// Number of weeks of changes included in stats feeds
+const FEED_WEEK_COUNT: usize = 12;
+/// Max number of new entries and climbers listed for each of artists and tracks per week
+const FEED_MAX_CHANGES: usize = 3;
+
+/// Renders one list of a feed entry's changes as HTML.  `names_by_id` maps Spotify IDs to display
+/// names.
+fn render_ranking_changes(
+    heading: &str,
+    changes: &RankingChanges,
+    names_by_id: &HashMap<String, String>,
+) -> String {
+    let items = changes.describe(names_by_id);
+    if items.is_empty() {
+        return String::new();
     }
 
-    Ok(RelatedArtistsGraph {
-        extra_artists,
-        related_artists: related_artists_by_id,
-    })
+    let items = items
+        .iter()
+        .map(|item| format!("<li>{}</li>", share_card::escape_xml(item)))
+        .collect::<String>();
+    format!("<h3>{}</h3><ul>{}</ul>", heading, items)
 }
 
-#[get("/stats/<user_id>/related_artists_graph")]
-pub(crate) async fn get_related_artists_graph(
+/// Atom feed with an entry for each of the past several weeks summarizing how the user's top
+/// artists and tracks for `timeframe` (default the user's preferred timeframe) changed since the
+/// week before.  Each week is represented by the last snapshot stored during it.
+#[get("/stats/<username>/feed.atom?<timeframe>")]
+pub(crate) async fn get_stats_feed(
     conn: DbConn,
-    user_id: String,
-    token_data: &State<Mutex<SpotifyTokenData>>,
-) -> Result<Option<Json<RelatedArtistsGraph>>, String> {
-    let User { id: user_id, .. } = match db_util::get_user_by_spotify_id(&conn, user_id).await? {
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    username: String,
+    access_token: PrivateAccessToken,
+    timeframe: Option<String>,
+) -> Result<Option<(ContentType, String)>, Error> {
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
         Some(user) => user,
-        None => {
-            return Ok(None);
-        },
+        None => return Ok(None),
     };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+    let settings = db_util::get_user_settings(&conn, &user).await?;
+    let timeframe = resolve_timeframe(timeframe, &settings)?;
+
+    let artist_history =
+        db_util::get_full_rank_history(&conn, &user, ExportEntity::Artists).await?;
+    let track_history = db_util::get_full_rank_history(&conn, &user, ExportEntity::Tracks).await?;
+
+    // `(artist_changes, track_changes)` by the update time of the snapshot they lead up to
+    let mut changes_by_week: BTreeMap<
+        NaiveDateTime,
+        (Option<RankingChanges>, Option<RankingChanges>),
+    > = BTreeMap::new();
+    let weekly_artists = crate::stats::last_snapshot_per_week(&artist_history, timeframe);
+    for pair in weekly_artists.windows(2) {
+        let changes = crate::stats::diff_rankings(&pair[0].1, &pair[1].1, FEED_MAX_CHANGES);
+        changes_by_week.entry(pair[1].0).or_default().0 = Some(changes);
+    }
+    let weekly_tracks = crate::stats::last_snapshot_per_week(&track_history, timeframe);
+    for pair in weekly_tracks.windows(2) {
+        let changes = crate::stats::diff_rankings(&pair[0].1, &pair[1].1, FEED_MAX_CHANGES);
+        changes_by_week.entry(pair[1].0).or_default().1 = Some(changes);
+    }
+    let weeks: Vec<_> = changes_by_week
+        .into_iter()
+        .rev()
+        .filter(|(_, (artist_changes, track_changes))| {
+            artist_changes
+                .iter()
+                .chain(track_changes)
+                .any(|changes| !changes.is_empty())
+        })
+        .take(FEED_WEEK_COUNT)
+        .collect();
+
+    let artist_ids: Vec<&str> = weeks
+        .iter()
+        .flat_map(|(_, (artist_changes, _))| {
+            artist_changes.iter().flat_map(RankingChanges::spotify_ids)
+        })
+        .collect::<FnvHashSet<_>>()
+        .into_iter()
+        .collect();
+    let track_ids: Vec<&str> = weeks
+        .iter()
+        .flat_map(|(_, (_, track_changes))| {
+            track_changes.iter().flat_map(RankingChanges::spotify_ids)
+        })
+        .collect::<FnvHashSet<_>>()
+        .into_iter()
+        .collect();
+
     let spotify_access_token = {
         let token_data = &mut *(&*token_data).lock().await;
         token_data.get().await
     }?;
+    let artists = fetch_artists(&spotify_access_token, &artist_ids).await?;
+    let artist_names_by_id: HashMap<String, String> = artists
+        .into_iter()
+        .map(|(id, artist)| (id, artist.name))
+        .collect();
+    let tracks = crate::spotify_api::fetch_tracks(&spotify_access_token, &track_ids).await?;
+    let track_names_by_id: HashMap<String, String> = tracks
+        .into_iter()
+        .map(|(id, track)| {
+            let name = match track.artists.first() {
+                Some(artist) => format!("{} by {}", track.name, artist.name),
+                None => track.name,
+            };
+            (id, name)
+        })
+        .collect();
 
-    // Start off by getting all artists for the user from all timeframes
-    let all_artists_for_user =
-        get_all_top_artists_for_user(&conn, user_id)
-            .await
-            .map_err(|err| {
-                error!("Error fetching all artists for user: {:?}", err);
-                String::from("Internal DB error")
-            })?;
-    let all_artist_ids_for_user: Vec<&str> = all_artists_for_user
+    let user_slug = user.vanity_slug.as_deref().unwrap_or(&user.spotify_id);
+    let stats_url = format!("{}/stats/{}", CONF.website_url, user_slug);
+    let entries = weeks
         .iter()
-        .map(|(_internal_id, spotify_id)| spotify_id.as_str())
+        .map(|(update_time, (artist_changes, track_changes))| {
+            let headline = match (
+                artist_changes.as_ref().and_then(|changes| changes.new_top),
+                track_changes.as_ref().and_then(|changes| changes.new_top),
+            ) {
+                (Some(top_artist), _) => format!(
+                    "{} is the new top artist",
+                    artist_names_by_id
+                        .get(top_artist)
+                        .map(String::as_str)
+                        .unwrap_or(top_artist)
+                ),
+                (None, Some(top_track)) => format!(
+                    "{} is the new top track",
+                    track_names_by_id
+                        .get(top_track)
+                        .map(String::as_str)
+                        .unwrap_or(top_track)
+                ),
+                (None, None) => "Changes in top artists and tracks".to_owned(),
+            };
+            let week_start = update_time.date()
+                - chrono::Duration::days(update_time.weekday().num_days_from_monday() as i64);
+
+            let mut content_html = String::new();
+            if let Some(changes) = artist_changes {
+                content_html.push_str(&render_ranking_changes(
+                    "Artists",
+                    changes,
+                    &artist_names_by_id,
+                ));
+            }
+            if let Some(changes) = track_changes {
+                content_html.push_str(&render_ranking_changes(
+                    "Tracks",
+                    changes,
+                    &track_names_by_id,
+                ));
+            }
+
+            feed::AtomEntry {
+                id: format!(
+                    "{}#{}-{}",
+                    stats_url,
+                    timeframe,
+                    update_time.and_utc().timestamp()
+                ),
+                title: format!("Week of {}: {}", week_start.format("%Y-%m-%d"), headline),
+                link: stats_url.clone(),
+                updated: *update_time,
+                content_html,
+            }
+        })
         .collect();
 
-    let out = build_related_artists_graph(spotify_access_token, &all_artist_ids_for_user).await?;
-    Ok(Some(Json(out)))
+    let feed_url = format!(
+        "{}/stats/{}/feed.atom?timeframe={}",
+        CONF.api_server_url, user_slug, timeframe
+    );
+    let feed = feed::AtomFeed {
+        id: feed_url.clone(),
+        title: format!(
+            "{}'s Top Changes ({})",
+            settings.display_name(&user),
+            timeframe.label()
+        ),
+        link: stats_url.clone(),
+        self_link: feed_url,
+        updated: weeks
+            .first()
+            .map(|(update_time, _)| *update_time)
+            .unwrap_or(user.last_update_time),
+        entries,
+    };
+
+    Ok(Some((
+        ContentType::new("application", "atom+xml"),
+        feed.to_xml(),
+    )))
 }
 
-#[get("/related_artists/<artist_id>")]
-pub(crate) async fn get_related_artists(
-    artist_id: String,
-    token_data: &State<Mutex<SpotifyTokenData>>,
-) -> Result<Option<Json<RelatedArtistsGraph>>, String> {
+/// Searches the names of artists and tracks that have appeared in the user's history, returning
+/// each match along with a summary of when and how highly it was ranked.  Matches are sorted by the
+/// number of snapshots they appeared in.  `limit` applies separately to artists and tracks.
+#[get("/stats/<username>/search?<q>&<limit>")]
+pub(crate) async fn search_user_history(
+    conn: DbConn,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+    username: String,
+    access_token: PrivateAccessToken,
+    q: String,
+    limit: Option<u32>,
+) -> Result<Option<Json<HistorySearchResults>>, Error> {
+    let query = q.trim();
+    if query.chars().count() < 2 {
+        return Err(Error::BadRequest(String::from(
+            "`q` must be at least 2 characters long",
+        )));
+    }
+    let limit = limit.unwrap_or(10).clamp(1, 50);
+
+    let user = match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
+
+    let artist_ids =
+        db_util::search_user_history(&conn, &user, ExportEntity::Artists, query, limit).await?;
+    let track_ids =
+        db_util::search_user_history(&conn, &user, ExportEntity::Tracks, query, limit).await?;
+    let artist_history =
+        db_util::get_entities_rank_history(&conn, &user, ExportEntity::Artists, artist_ids.clone())
+            .await?;
+    let track_history =
+        db_util::get_entities_rank_history(&conn, &user, ExportEntity::Tracks, track_ids.clone())
+            .await?;
+    let mut artist_summaries = crate::stats::summarize_rank_history(&artist_history);
+    let mut track_summaries = crate::stats::summarize_rank_history(&track_history);
+
     let spotify_access_token = {
         let token_data = &mut *(&*token_data).lock().await;
         token_data.get().await
     }?;
+    let artist_ids: Vec<&str> = artist_ids.iter().map(String::as_str).collect();
+    let artists = fetch_artists(&spotify_access_token, &artist_ids).await?;
+    let track_ids: Vec<&str> = track_ids.iter().map(String::as_str).collect();
+    let tracks = crate::spotify_api::fetch_tracks(&spotify_access_token, &track_ids).await?;
 
-    let related_artist_ids =
-        get_multiple_related_artists(spotify_access_token.clone(), &[&artist_id]).await?;
-    let related_artist_ids = match related_artist_ids.into_iter().next() {
-        Some(ids) => ids,
-        None => {
-            error!("Empty vec returned from `get_multiple_related_artists`");
-            return Ok(None);
-        },
-    };
-    let related_artist_ids = related_artist_ids
-        .iter()
-        .map(String::as_str)
-        .collect::<Vec<_>>();
+    let mut artists: Vec<_> = artists
+        .into_iter()
+        .filter_map(|(id, item)| {
+            let history = artist_summaries.remove(id.as_str())?;
+            Some(HistorySearchMatch { item, history })
+        })
+        .collect();
+    artists.sort_by_key(|item| Reverse(item.history.snapshot_count));
+    let mut tracks: Vec<_> = tracks
+        .into_iter()
+        .filter_map(|(id, item)| {
+            let history = track_summaries.remove(id.as_str())?;
+            Some(HistorySearchMatch { item, history })
+        })
+        .collect();
+    tracks.sort_by_key(|item| Reverse(item.history.snapshot_count));
 
-    let out = build_related_artists_graph(spotify_access_token, &related_artist_ids).await?;
-    Ok(Some(Json(out)))
+    Ok(Some(Json(HistorySearchResults { artists, tracks })))
 }
 
 #[get("/display_name/<username>")]
 pub(crate) async fn get_display_name(
     conn: DbConn,
     username: String,
-) -> Result<Option<String>, String> {
-    match db_util::get_user_by_spotify_id(&conn, username).await? {
+) -> Result<Option<String>, Error> {
+    match db_util::get_user_by_vanity_slug_or_spotify_id(&conn, username).await? {
         Some(user) => {
-            let user_clone = user.clone();
-            tokio::task::spawn(async move {
-                if let Err(err) = db_util::update_user_last_viewed(&user_clone, &conn).await {
-                    error!(
-                        "Error updating user last viewed time for {}: {:?}",
-                        user_clone.username, err
-                    );
-                }
-            });
-
-            Ok(Some(user.username))
+            let settings = db_util::get_user_settings(&conn, &user).await?;
+            Ok(Some(settings.display_name(&user).to_owned()))
         },
         None => Ok(None),
     }
 }
 
-#[post("/dump_redis_related_artists_to_database", data = "<api_token_data>")]
+#[post("/dump_redis_related_artists_to_database")]
 pub(crate) async fn dump_redis_related_artists_to_database(
     conn: DbConn,
-    api_token_data: rocket::Data<'_>,
-) -> Result<status::Custom<String>, String> {
-    if !validate_api_token(api_token_data).await? {
-        return Ok(status::Custom(
-            Status::Unauthorized,
-            "Invalid API token supplied".into(),
-        ));
-    }
-
+    _admin: AdminToken,
+) -> Result<status::Custom<String>, Error> {
     let mut redis_conn = get_redis_conn()?;
     let all_values: Vec<String> = block_in_place(|| redis_conn.hgetall("related_artists"))
         .map_err(|err| {
             error!("Error with HGETALL on related artists data: {:?}", err);
-            String::from("Redis error")
+            Error::Internal("Redis error".into())
         })?;
 
     let mut all_mapped_spotify_ids: HashMap<String, i32> = HashMap::default();
@@ -1128,7 +5593,7 @@ pub(crate) async fn dump_redis_related_artists_to_database(
                 .await
                 .map_err(|err| {
                     error!("Error mapping spotify ids: {:?}", err);
-                    String::from("Error mapping spotify ids")
+                    Error::Internal("Error mapping spotify ids".into())
                 })?;
 
         for (k, v) in mapped_spotify_ids {
@@ -1157,7 +5622,7 @@ pub(crate) async fn dump_redis_related_artists_to_database(
             .await
             .map_err(|err| {
                 error!("DB error inserting related artist into DB: {:?}", err);
-                String::from("DB error")
+                Error::Internal("DB error".into())
             })?;
     }
 
@@ -1167,18 +5632,11 @@ pub(crate) async fn dump_redis_related_artists_to_database(
     ))
 }
 
-#[post("/crawl_related_artists", data = "<api_token_data>")]
+#[post("/crawl_related_artists")]
 pub(crate) async fn crawl_related_artists(
-    api_token_data: rocket::Data<'_>,
-    token_data: &State<Mutex<SpotifyTokenData>>,
-) -> Result<status::Custom<String>, String> {
-    if !validate_api_token(api_token_data).await? {
-        return Ok(status::Custom(
-            Status::Unauthorized,
-            "Invalid API token supplied".into(),
-        ));
-    }
-
+    _admin: AdminToken,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+) -> Result<status::Custom<String>, Error> {
     let spotify_access_token = {
         let token_data = &mut *(&*token_data).lock().await;
         token_data.get().await
@@ -1196,7 +5654,7 @@ pub(crate) async fn crawl_related_artists(
             "Error getting random related artist keys from Redis cache: {:?}",
             err
         );
-        String::from("Redis error")
+        Error::Internal("Redis error".into())
     })?;
 
     let mut all_related_artists: Vec<String> = Vec::new();
@@ -1206,7 +5664,7 @@ pub(crate) async fn crawl_related_artists(
             .hget("related_artists", artist_ids)
             .map_err(|err| {
                 error!("Error getting related artist from Redis: {:?}", err);
-                String::from("Redis error")
+                Error::Internal("Redis error".into())
             })
     })?;
 
@@ -1244,9 +5702,9 @@ pub(crate) async fn crawl_related_artists(
 #[get("/search_artist?<q>")]
 pub(crate) async fn search_artist(
     conn: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
     q: String,
-) -> Result<Json<Vec<ArtistSearchResult>>, String> {
+) -> Result<Json<Vec<ArtistSearchResult>>, Error> {
     let spotify_access_token = {
         let token_data = &mut *(&*token_data).lock().await;
         token_data.get().await
@@ -1255,10 +5713,10 @@ pub(crate) async fn search_artist(
     // First check cache
     let cached_item =
         block_in_place(|| get_hash_items::<Vec<ArtistSearchResult>>("artistSearch", &[&q]))
-            .map_err(|err| {
-                error!("Error checking cache for artist search results: {}", err);
-                String::from("Internal error with cache")
-            })?
+            .unwrap_or_else(|err| {
+                warn!("Error checking cache for artist search results: {}", err);
+                Vec::new()
+            })
             .into_iter()
             .next()
             .flatten();
@@ -1270,13 +5728,13 @@ pub(crate) async fn search_artist(
 
     // Hit the Spotify API and store in the cache
     let search_results = search_artists(&conn, spotify_access_token, &q).await?;
-    set_hash_items::<Vec<ArtistSearchResult>>("artistSearch", &[(&q, search_results.clone())])
-        .map_err(|err| {
-            error!("Error storing artist search in cache: {}", err);
-            String::from("Internal error with cache")
-        })?;
+    if let Err(err) =
+        set_hash_items::<Vec<ArtistSearchResult>>("artistSearch", &[(&q, search_results.clone())])
+    {
+        warn!("Error storing artist search in cache: {}", err);
+    }
     info!(
-        "Successfully hit Spotify API for artist search query={:?} and stored in cache",
+        "Successfully hit Spotify API for artist search query={:?}",
         q
     );
 
@@ -1294,8 +5752,8 @@ pub(crate) async fn get_average_artists_route(
     count: Option<usize>,
     artist_1_bias: Option<f32>,
     artist_2_bias: Option<f32>,
-    token_data: &State<Mutex<SpotifyTokenData>>,
-) -> Result<Json<AverageArtistsResponse>, String> {
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+) -> Result<Json<AverageArtistsResponse>, Error> {
     // Look up internal IDs for provided spotify IDs
     let internal_ids_by_spotify_id = get_internal_ids_by_spotify_id(
         &conn,
@@ -1304,11 +5762,19 @@ pub(crate) async fn get_average_artists_route(
     .await?;
     let artist_1_id = match internal_ids_by_spotify_id.get(&artist_1_spotify_id) {
         Some(id) => *id,
-        None => return Err(format!("No artist found with id={}", artist_1_spotify_id)),
+        None =>
+            return Err(Error::NotFound(format!(
+                "No artist found with id={}",
+                artist_1_spotify_id
+            ))),
     };
     let artist_2_id = match internal_ids_by_spotify_id.get(&artist_2_spotify_id) {
         Some(id) => *id,
-        None => return Err(format!("No artist found with id={}", artist_2_spotify_id)),
+        None =>
+            return Err(Error::NotFound(format!(
+                "No artist found with id={}",
+                artist_2_spotify_id
+            ))),
     };
     let count = count.unwrap_or(10).min(50);
     assert!(artist_1_id > 0);
@@ -1324,10 +5790,10 @@ pub(crate) async fn get_average_artists_route(
         Ok(res) => res,
         Err(err) => match err {
             ArtistEmbeddingError::ArtistIdNotFound(id) =>
-                return Err(format!(
+                return Err(Error::NotFound(format!(
                     "No artist found in embedding with internal id={}",
                     id
-                )),
+                ))),
         },
     };
 
@@ -1341,7 +5807,7 @@ pub(crate) async fn get_average_artists_route(
                      averaging: {:?}",
                     err
                 );
-                String::from("Internal database error")
+                Error::Internal("Internal database error".into())
             })?;
 
     let all_spotify_ids: Vec<&str> = artist_spotify_ids_by_internal_id
@@ -1385,9 +5851,7 @@ pub(crate) async fn get_average_artists_route(
                     return false;
                 },
             };
-            let was_fetched = fetched_artists
-                .iter()
-                .any(|a| a.id == *avg_artist_spotify_id);
+            let was_fetched = fetched_artists.contains_key(avg_artist_spotify_id);
             if !was_fetched {
                 error!(
                     "Failed to find artist metadata for artist with spotify_id={}",
@@ -1414,11 +5878,7 @@ pub(crate) async fn get_average_artists_route(
                     return None;
                 },
             };
-            let artist = match fetched_artists
-                .iter()
-                .find(|artist| artist.id == *avg_artist_spotify_id)
-                .cloned()
-            {
+            let artist = match fetched_artists.get(avg_artist_spotify_id).cloned() {
                 Some(artist) => artist,
                 None => {
                     warn!(
@@ -1477,8 +5937,8 @@ pub(crate) async fn get_average_artists_route(
 #[get("/artist_image_url/<artist_spotify_id>")]
 pub(crate) async fn get_artist_image_url(
     artist_spotify_id: String,
-    token_data: &State<Mutex<SpotifyTokenData>>,
-) -> Result<String, String> {
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+) -> Result<String, Error> {
     let spotify_access_token = {
         let token_data = &mut *(&*token_data).lock().await;
         token_data.get().await
@@ -1486,33 +5946,22 @@ pub(crate) async fn get_artist_image_url(
 
     let artist: Option<Artist> = fetch_artists(&spotify_access_token, &[&artist_spotify_id])
         .await?
-        .into_iter()
-        .next();
+        .remove(artist_spotify_id.as_str());
     let image = match artist
         .and_then(|artist| artist.images.and_then(|images| images.into_iter().next()))
     {
         Some(image) => image,
-        None => return Err(String::from("Not found")),
+        None => return Err(Error::NotFound(String::from("Not found"))),
     };
     Ok(image.url)
 }
 
-#[post(
-    "/refetch_cached_artists_missing_popularity?<count>",
-    data = "<api_token_data>"
-)]
+#[post("/refetch_cached_artists_missing_popularity?<count>")]
 pub(crate) async fn refetch_cached_artists_missing_popularity(
-    api_token_data: rocket::Data<'_>,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    _admin: AdminToken,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
     count: Option<usize>,
-) -> Result<status::Custom<String>, String> {
-    if !validate_api_token(api_token_data).await? {
-        return Ok(status::Custom(
-            Status::Unauthorized,
-            "Invalid API token supplied".into(),
-        ));
-    }
-
+) -> Result<status::Custom<String>, Error> {
     let spotify_access_token = {
         let token_data = &mut *(&*token_data).lock().await;
         token_data.get().await
@@ -1521,7 +5970,7 @@ pub(crate) async fn refetch_cached_artists_missing_popularity(
     let mut redis_conn = spawn_blocking(|| get_redis_conn()).await.unwrap()?;
 
     let (mut redis_conn, artist_spotify_ids) =
-        spawn_blocking(move || -> Result<(_, Vec<String>), String> {
+        spawn_blocking(move || -> Result<(_, Vec<String>), Error> {
             let artist_spotify_ids = redis::cmd("HRANDFIELD")
                 .arg(&CONF.artists_cache_hash_name)
                 .arg(count.unwrap_or(20).to_string())
@@ -1531,7 +5980,7 @@ pub(crate) async fn refetch_cached_artists_missing_popularity(
                         "Error getting random artist keys from Redis cache: {:?}",
                         err
                     );
-                    String::from("Redis error")
+                    Error::Internal("Redis error".into())
                 })?;
             Ok((redis_conn, artist_spotify_ids))
         })
@@ -1539,12 +5988,11 @@ pub(crate) async fn refetch_cached_artists_missing_popularity(
         .unwrap()?;
     let artist_spotify_ids: Vec<&str> = artist_spotify_ids.iter().map(String::as_str).collect();
     let mut artists = fetch_artists(&spotify_access_token, &artist_spotify_ids).await?;
-    artists.retain(|artist| artist.popularity.is_none());
+    artists.retain(|_, artist| artist.popularity.is_none());
     if artists.is_empty() {
         return Ok(status::Custom(Status::Ok, "No artists to refetch".into()));
     }
-    let artist_ids_needing_refetch: Vec<String> =
-        artists.iter().map(|artist| artist.id.clone()).collect();
+    let artist_ids_needing_refetch: Vec<String> = artists.into_keys().collect();
 
     // Delete from the cache and then re-fetch them to re-populate the cache from the Spotify API
     let artist_ids_needing_refetch_clone = artist_ids_needing_refetch.clone();
@@ -1565,9 +6013,18 @@ pub(crate) async fn refetch_cached_artists_missing_popularity(
     .unwrap()
     .map_err(|err| {
         error!("Error deleting artist ids from Redis cache: {}", err);
-        String::from("Redis error")
+        Error::Internal("Redis error".into())
     })?;
     info!("Deleted {} artists from Redis cache", deleted_artist_count);
+    crate::cache::hot_cache::invalidate(
+        &CONF.artists_cache_hash_name,
+        Some(
+            &artist_ids_needing_refetch
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>(),
+        ),
+    );
 
     let artist_ids_needing_refetch: Vec<&str> = artist_ids_needing_refetch
         .iter()
@@ -1595,8 +6052,8 @@ pub(crate) struct JSONMimeTypeSetterResponder {
 #[get("/packed_3d_artist_coords")]
 pub(crate) async fn get_packed_3d_artist_coords_route(
     conn: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
-) -> Result<JSONMimeTypeSetterResponder, String> {
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
+) -> Result<JSONMimeTypeSetterResponder, Error> {
     let spotify_access_token = {
         let token_data = &mut *(&*token_data).lock().await;
         token_data.get().await
@@ -1611,9 +6068,9 @@ pub(crate) async fn get_packed_3d_artist_coords_route(
 #[post("/map_artist_data_by_internal_ids", data = "<artist_internal_ids>")]
 pub(crate) async fn get_artists_by_internal_ids(
     conn: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
     artist_internal_ids: Json<Vec<i32>>,
-) -> Result<Json<Vec<Option<String>>>, String> {
+) -> Result<Json<Vec<Option<String>>>, Error> {
     let spotify_access_token = {
         let token_data = &mut *(&*token_data).lock().await;
         token_data.get().await
@@ -1628,7 +6085,7 @@ pub(crate) async fn get_artists_by_internal_ids(
                     "Error getting artist spotify IDs by internal IDs: {:?}",
                     err
                 );
-                String::from("Internal DB error")
+                Error::Internal("Internal DB error".into())
             })?;
     let artist_spotify_ids = artist_internal_ids
         .iter()
@@ -1646,10 +6103,7 @@ pub(crate) async fn get_artists_by_internal_ids(
             .into_iter()
             .map(|internal_id| {
                 let spotify_id = artist_spotify_ids_by_internal_id.get(&internal_id)?;
-                artists
-                    .iter()
-                    .find(|artist| artist.id == *spotify_id)
-                    .map(|artist| artist.name.clone())
+                artists.get(spotify_id).map(|artist| artist.name.clone())
             })
             .collect(),
     ))
@@ -1692,7 +6146,7 @@ async fn get_packed_artist_relationships_by_internal_ids_inner(
     conn: &DbConn,
     spotify_access_token: String,
     artist_internal_ids: Vec<i32>,
-) -> Result<Vec<u8>, String> {
+) -> Result<Vec<u8>, Error> {
     let tok = start();
     let artist_spotify_ids_by_internal_id =
         get_artist_spotify_ids_by_internal_id(&conn, artist_internal_ids.clone())
@@ -1702,7 +6156,7 @@ async fn get_packed_artist_relationships_by_internal_ids_inner(
                     "Error getting artist spotify IDs by internal IDs: {:?}",
                     err
                 );
-                String::from("Internal DB error")
+                Error::Internal("Internal DB error".into())
             })?;
 
     mark(tok, "Converted to spotify IDs");
@@ -1754,9 +6208,9 @@ async fn get_packed_artist_relationships_by_internal_ids_inner(
 )]
 pub(crate) async fn get_packed_artist_relationships_by_internal_ids(
     conn: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
     artist_internal_ids: Json<Vec<i32>>,
-) -> Result<JSONMimeTypeSetterResponder, String> {
+) -> Result<JSONMimeTypeSetterResponder, Error> {
     let spotify_access_token = {
         let token_data = &mut *(&*token_data).lock().await;
         token_data.get().await
@@ -1781,10 +6235,10 @@ lazy_static::lazy_static! {
 #[get("/map_artist_relationships_chunk?<chunk_size>&<chunk_ix>")]
 pub(crate) async fn get_artist_relationships_chunk(
     conn: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
     chunk_size: u32,
     chunk_ix: u32,
-) -> Result<JSONMimeTypeSetterResponder, String> {
+) -> Result<JSONMimeTypeSetterResponder, Error> {
     let spotify_access_token = {
         let token_data = &mut *(&*token_data).lock().await;
         token_data.get().await
@@ -1830,9 +6284,9 @@ pub(crate) async fn get_artist_relationships_chunk(
 #[get("/get_preview_urls_by_internal_id/<artist_internal_id>")]
 pub(crate) async fn get_preview_urls_by_internal_id(
     conn: DbConn,
-    token_data: &State<Mutex<SpotifyTokenData>>,
+    token_data: &State<Arc<Mutex<SpotifyTokenData>>>,
     artist_internal_id: i32,
-) -> Result<Json<Option<Vec<String>>>, String> {
+) -> Result<Json<Option<Vec<String>>>, Error> {
     let spotify_access_token = {
         let token_data = &mut *(&*token_data).lock().await;
         token_data.get().await
@@ -1846,7 +6300,7 @@ pub(crate) async fn get_preview_urls_by_internal_id(
                     "Error getting artist spotify IDs by internal IDs: {:?}",
                     err
                 );
-                String::from("Internal DB error")
+                Error::Internal("Internal DB error".into())
             })?;
 
     let spotify_id = match spotify_ids_by_internal_id.get(&artist_internal_id).cloned() {
@@ -1871,19 +6325,23 @@ pub(crate) async fn get_preview_urls_by_internal_id(
 pub(crate) async fn get_top_artists_internal_ids_for_user(
     conn: DbConn,
     user_id: String,
-) -> Result<Option<Json<Vec<i32>>>, String> {
+    access_token: PrivateAccessToken,
+) -> Result<Option<Json<Vec<i32>>>, Error> {
     let user = match db_util::get_user_by_spotify_id(&conn, user_id).await? {
         Some(user) => user,
         None => {
             return Ok(None);
         },
     };
+    if !access_token.grants_access_to(&user) {
+        return Ok(None);
+    }
 
     let top_artists = get_all_top_artists_for_user(&conn, user.id)
         .await
         .map_err(|err| {
             error!("Error getting top artists for user: {:?}", err);
-            String::from("Internal DB error")
+            Error::Internal("Internal DB error".into())
         })?;
     Ok(Some(Json(
         top_artists
@@ -1893,26 +6351,16 @@ pub(crate) async fn get_top_artists_internal_ids_for_user(
     )))
 }
 
-#[post(
-    "/transfer_user_data_to_external_storage/<user_id>",
-    data = "<api_token_data>"
-)]
+#[post("/transfer_user_data_to_external_storage/<user_id>")]
 pub(crate) async fn transfer_user_data_to_external_storage(
-    api_token_data: rocket::Data<'_>,
     conn: DbConn,
+    _admin: AdminToken,
     user_id: String,
-) -> Result<status::Custom<String>, String> {
-    if !validate_api_token(api_token_data).await? {
-        return Ok(status::Custom(
-            Status::Unauthorized,
-            "Invalid API token supplied".into(),
-        ));
-    }
-
+) -> Result<status::Custom<String>, Error> {
     let user = match db_util::get_user_by_spotify_id(&conn, user_id).await? {
         Some(user) => user,
         None => {
-            return Err(String::from("User not found"));
+            return Err(Error::NotFound(String::from("User not found")));
         },
     };
 
@@ -1928,26 +6376,16 @@ pub(crate) async fn transfer_user_data_to_external_storage(
     Ok(status::Custom(Status::Ok, String::new()))
 }
 
-#[post(
-    "/transfer_user_data_from_external_storage/<user_id>",
-    data = "<api_token_data>"
-)]
+#[post("/transfer_user_data_from_external_storage/<user_id>")]
 pub(crate) async fn transfer_user_data_from_external_storage(
-    api_token_data: rocket::Data<'_>,
     conn: DbConn,
+    _admin: AdminToken,
     user_id: String,
-) -> Result<status::Custom<String>, String> {
-    if !validate_api_token(api_token_data).await? {
-        return Ok(status::Custom(
-            Status::Unauthorized,
-            "Invalid API token supplied".into(),
-        ));
-    }
-
+) -> Result<status::Custom<String>, Error> {
     let user = match db_util::get_user_by_spotify_id(&conn, user_id).await? {
         Some(user) => user,
         None => {
-            return Err(String::from("User not found"));
+            return Err(Error::NotFound(String::from("User not found")));
         },
     };
 
@@ -1964,27 +6402,19 @@ pub(crate) async fn transfer_user_data_from_external_storage(
 }
 
 #[post(
-    "/bulk_transfer_user_data_to_external_storage/<user_count>?<only_already_stored>&<concurrency>",
-    data = "<api_token_data>"
+    "/bulk_transfer_user_data_to_external_storage/<user_count>?<only_already_stored>&<concurrency>"
 )]
 pub(crate) async fn bulk_transfer_user_data_to_external_storage(
-    api_token_data: rocket::Data<'_>,
     conn0: DbConn,
     conn1: DbConn,
     conn2: DbConn,
     conn3: DbConn,
     conn4: DbConn,
+    _admin: AdminToken,
     user_count: u32,
     only_already_stored: Option<bool>,
     concurrency: Option<usize>,
-) -> Result<status::Custom<String>, String> {
-    if !validate_api_token(api_token_data).await? {
-        return Ok(status::Custom(
-            Status::Unauthorized,
-            "Invalid API token supplied".into(),
-        ));
-    }
-
+) -> Result<status::Custom<String>, Error> {
     // Only transfer data for users that haven't viewed their profile in the past 4 months
     let cutoff_time: NaiveDateTime = Utc::now().naive_utc() - chrono::Duration::days(120);
 
@@ -2006,7 +6436,7 @@ pub(crate) async fn bulk_transfer_user_data_to_external_storage(
         .await
         .map_err(|err| {
             error!("Error getting users from DB for bulk transfer: {:?}", err);
-            String::from("Internal DB error")
+            Error::Internal("Internal DB error".into())
         })?;
     let usernames = users
         .iter()
@@ -2053,3 +6483,159 @@ pub(crate) async fn bulk_transfer_user_data_to_external_storage(
 
     Ok(status::Custom(Status::Ok, String::new()))
 }
+
+/// Downsamples old artist and track snapshots according to the configured retention policy.  Users
+/// are processed in order of their internal ID in batches of `batch_size`; the returned message
+/// includes the `after_user_id` to pass to continue with the next batch.
+#[post("/admin/compact_history?<after_user_id>&<batch_size>")]
+pub(crate) async fn compact_history(
+    conn: DbConn,
+    _admin: AdminToken,
+    after_user_id: Option<i64>,
+    batch_size: Option<i64>,
+) -> Result<status::Custom<String>, Error> {
+    use crate::schema::users;
+
+    let after_user_id = after_user_id.unwrap_or(0);
+    let batch_size = batch_size.unwrap_or(100).clamp(1, 5000);
+
+    // Users with data in external storage don't have any snapshots in the database to compact
+    let (user_ids, total_user_count) = conn
+        .run(move |conn| -> QueryResult<(Vec<i64>, i64)> {
+            let user_ids = users::table
+                .filter(users::dsl::external_data_retrieved.eq(true))
+                .filter(users::dsl::id.gt(after_user_id))
+                .order_by(users::dsl::id)
+                .limit(batch_size)
+                .select(users::dsl::id)
+                .load(conn)?;
+            let total_user_count = users::table
+                .filter(users::dsl::external_data_retrieved.eq(true))
+                .count()
+                .get_result(conn)?;
+            Ok((user_ids, total_user_count))
+        })
+        .await?;
+
+    let policy = crate::retention::RetentionPolicy::from_conf();
+    let now = Utc::now().naive_utc();
+    let mut deleted_row_count = 0;
+    let mut failed_user_count = 0;
+    for (i, &user_id) in user_ids.iter().enumerate() {
+        match crate::retention::compact_user_history(&conn, user_id, policy, now).await {
+            Ok(deleted) => {
+                deleted_row_count += deleted;
+                info!(
+                    "[{}/{}] Compacted history for user id={}; deleted {} rows",
+                    i + 1,
+                    user_ids.len(),
+                    user_id,
+                    deleted
+                );
+            },
+            Err(err) => {
+                failed_user_count += 1;
+                error!("Error compacting history for user id={}: {}", user_id, err);
+            },
+        }
+    }
+
+    let msg = match user_ids.last() {
+        Some(last_user_id) => format!(
+            "Compacted history for {} user(s) ({} failed), deleting {} rows; {} eligible users \
+             total.  Next batch: after_user_id={}",
+            user_ids.len(),
+            failed_user_count,
+            deleted_row_count,
+            total_user_count,
+            last_user_id
+        ),
+        None => format!(
+            "No users left to compact after user id={}; {} eligible users total",
+            after_user_id, total_user_count
+        ),
+    };
+    info!("{}", msg);
+    Ok(status::Custom(Status::Ok, msg))
+}
+
+#[test]
+fn snapshot_timestamps() {
+    use chrono::TimeZone;
+
+    let update_time = chrono::NaiveDate::from_ymd_opt(2026, 10, 16)
+        .unwrap()
+        .and_hms_opt(12, 30, 0)
+        .unwrap();
+    // Times listed by `/snapshots` are localized into the user's timezone
+    let listed: LocalDateTime = chrono_tz::Tz::America__New_York
+        .from_utc_datetime(&update_time)
+        .fixed_offset();
+    let listed = serde_json::to_value(listed).unwrap();
+    assert_eq!(listed, "2026-10-16T08:30:00-04:00");
+    assert_eq!(
+        parse_snapshot_timestamp(listed.as_str().unwrap()),
+        Some(update_time)
+    );
+
+    assert_eq!(
+        parse_snapshot_timestamp(&update_time.and_utc().timestamp().to_string()),
+        Some(update_time)
+    );
+    assert_eq!(
+        parse_snapshot_timestamp("2026-10-16T12:30:00"),
+        Some(update_time)
+    );
+    assert_eq!(parse_snapshot_timestamp("yesterday"), None);
+}
+
+#[test]
+fn private_linked_accounts_are_not_merged() {
+    let now = chrono::Utc::now().naive_utc();
+    let linked_user = |spotify_id: &str| User {
+        id: 0,
+        creation_time: now,
+        last_update_time: now,
+        spotify_id: spotify_id.to_owned(),
+        username: spotify_id.to_owned(),
+        token: String::new(),
+        refresh_token: String::new(),
+        external_data_retrieved: true,
+        last_viewed: now,
+        last_external_data_store: now,
+        is_private: false,
+        private_token: None,
+        consecutive_update_failures: 0,
+        timezone: None,
+        vanity_slug: None,
+        deactivated_at: None,
+        update_tier: 0,
+        min_update_interval_seconds: None,
+        failed_update_attempts: 0,
+        next_update_attempt_at: None,
+    };
+    let public = linked_user("public");
+    let mut private = linked_user("private");
+    private.is_private = true;
+    private.private_token = Some("private-token".to_owned());
+    let mut deactivated = linked_user("deactivated");
+    deactivated.deactivated_at = Some(now);
+    let linked_users = vec![public, private, deactivated];
+
+    let visible_ids = |access_token: PrivateAccessToken| -> Vec<String> {
+        access_token
+            .accessible_users(linked_users.clone())
+            .into_iter()
+            .map(|user| user.spotify_id)
+            .collect()
+    };
+    assert_eq!(visible_ids(PrivateAccessToken(None)), vec!["public"]);
+    assert_eq!(
+        visible_ids(PrivateAccessToken(Some("primary-token".to_owned()))),
+        vec!["public"]
+    );
+    assert_eq!(
+        visible_ids(PrivateAccessToken(Some("private-token".to_owned()))),
+        vec!["public", "private"]
+    );
+}
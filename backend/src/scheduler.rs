@@ -0,0 +1,492 @@
+//! Background scheduler that keeps user stats up to date.  It repeatedly picks out all users that
+//! haven't been updated within the minimum update interval and updates them, least recently updated
+//! first, running up to `CONF.scheduler_concurrency` updates at once.
+//!
+//! The scheduler also periodically refreshes the global charts and the totals stored in
+//! `system_stats`, re-fetches stale artist and track metadata, writes buffered profile views, moves
+//! users between update tiers, and captures and polls the recently played tracks of users that
+//! signed up recently between passes.  Users in higher tiers are updated more often and are updated
+//! first when several users are due at once.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{NaiveDateTime, Utc};
+use fnv::FnvHashSet as HashSet;
+use rocket::{Orbit, Rocket};
+
+use crate::{
+    alerts::{self, AlertKind},
+    cache,
+    cache::metadata_store::{self, MetadataTable},
+    conf::CONF,
+    db_util,
+    error::Error,
+    events::{publish_admin_event, AdminEvent},
+    export::ExportEntity,
+    metrics::{
+        metadata_entities_refreshed_total, user_updates_failure_total, user_updates_success_total,
+    },
+    models::SchedulerStatus,
+    profile_views,
+    routes::update_user_inner,
+    spotify_api::MAX_BATCH_ENTITY_COUNT,
+    DbConn, SpotifyTokenData,
+};
+
+/// Time each worker waits between consecutive user updates to avoid hammering the Spotify API
+const DELAY_BETWEEN_UPDATES: Duration = Duration::from_secs(1);
+/// Time waited between consecutive batches of metadata refreshed from the Spotify API
+const DELAY_BETWEEN_METADATA_REFRESH_BATCHES: Duration = Duration::from_secs(1);
+
+lazy_static::lazy_static! {
+    static ref STATUS: Mutex<SchedulerStatus> = Mutex::new(SchedulerStatus {
+        enabled: CONF.scheduler_enabled,
+        concurrency: CONF.scheduler_concurrency,
+        ..Default::default()
+    });
+}
+
+pub(crate) fn get_status() -> SchedulerStatus { STATUS.lock().unwrap().clone() }
+
+/// Checks out a database connection for each worker and spawns the scheduler.  Called once Rocket
+/// has launched so that the database pool and the managed Spotify token are available.
+pub(crate) async fn start(rocket: &Rocket<Orbit>) {
+    if !CONF.scheduler_enabled {
+        info!("Update scheduler is disabled; users will only be updated via `/update_user`");
+        return;
+    }
+
+    let token_data = match rocket.state::<Arc<tokio::sync::Mutex<SpotifyTokenData>>>() {
+        Some(token_data) => Arc::clone(token_data),
+        None => {
+            error!("Spotify token isn't managed by Rocket; not starting update scheduler");
+            return;
+        },
+    };
+
+    let worker_count = CONF.scheduler_concurrency.max(1);
+    let mut conns = Vec::with_capacity(worker_count);
+    for _ in 0..worker_count {
+        match DbConn::get_one(rocket).await {
+            Some(conn) => conns.push(conn),
+            None => {
+                error!(
+                    "Failed to check out database connection for update scheduler; not starting"
+                );
+                return;
+            },
+        }
+    }
+
+    info!("Starting update scheduler with {} worker(s)", worker_count);
+    tokio::task::spawn(run(conns, token_data));
+}
+
+async fn run(conns: Vec<DbConn>, token_data: Arc<tokio::sync::Mutex<SpotifyTokenData>>) {
+    let mut last_charts_refresh: Option<Instant> = None;
+    let mut next_system_stats_refresh: Option<Instant> = None;
+    let mut last_metadata_refresh: Option<Instant> = None;
+    let mut last_update_tier_refresh: Option<Instant> = None;
+    let mut last_onboarding_poll: Option<Instant> = None;
+    // Dry runs don't mark users as updated, so users that have been dry-run are tracked here to
+    // keep them from being fetched again on every pass
+    let mut dry_run_user_ids: HashSet<String> = HashSet::default();
+    loop {
+        // Dry runs don't write anything, so the jobs that do are skipped
+        if !CONF.scheduler_dry_run {
+            let charts_due = last_charts_refresh
+                .map(|last_refresh| last_refresh.elapsed() >= CONF.global_charts_refresh_interval)
+                .unwrap_or(true);
+            if charts_due {
+                refresh_global_charts(&conns[0]).await;
+                last_charts_refresh = Some(Instant::now());
+            }
+
+            let next_stats_refresh = match next_system_stats_refresh {
+                Some(next_refresh) => next_refresh,
+                None => get_next_system_stats_refresh(&conns[0]).await,
+            };
+            if Instant::now() >= next_stats_refresh {
+                refresh_system_stats(&conns[0]).await;
+                next_system_stats_refresh =
+                    Some(Instant::now() + CONF.system_stats_refresh_interval);
+            } else {
+                next_system_stats_refresh = Some(next_stats_refresh);
+            }
+
+            let metadata_refresh_due = last_metadata_refresh
+                .map(|last_refresh| last_refresh.elapsed() >= CONF.metadata_refresh_interval)
+                .unwrap_or(true);
+            if metadata_refresh_due {
+                if let Some(max_age) = CONF.metadata_refresh_max_age {
+                    refresh_stale_metadata(&token_data, max_age).await;
+                }
+                last_metadata_refresh = Some(Instant::now());
+            }
+
+            // Tiers are computed from profile views, so views buffered since the last pass are
+            // written first
+            profile_views::flush_profile_views(&conns[0]).await;
+
+            let update_tier_refresh_due = last_update_tier_refresh
+                .map(|last_refresh| last_refresh.elapsed() >= CONF.update_tier_refresh_interval)
+                .unwrap_or(true);
+            if update_tier_refresh_due {
+                refresh_update_tiers(&conns[0]).await;
+                last_update_tier_refresh = Some(Instant::now());
+            }
+
+            crate::onboarding::capture_initial_histories(&conns[0]).await;
+
+            let onboarding_poll_due = last_onboarding_poll
+                .map(|last_poll| last_poll.elapsed() >= CONF.onboarding_poll_interval)
+                .unwrap_or(true);
+            if onboarding_poll_due {
+                poll_onboarding_users(&conns[0]).await;
+                last_onboarding_poll = Some(Instant::now());
+            }
+        }
+
+        let now = Utc::now().naive_utc();
+        let due_users = match db_util::get_users_due_for_update(&conns[0], now).await {
+            Ok(due_users) if CONF.scheduler_dry_run => due_users
+                .into_iter()
+                .filter(|(user_id, _)| !dry_run_user_ids.contains(user_id))
+                .collect(),
+            Ok(due_users) => due_users,
+            Err(err) => {
+                error!("Error fetching users due for update: {}", err);
+                tokio::time::sleep(CONF.scheduler_poll_interval).await;
+                continue;
+            },
+        };
+
+        if due_users.is_empty() {
+            tokio::time::sleep(CONF.scheduler_poll_interval).await;
+            continue;
+        }
+
+        check_update_lag(&due_users, now);
+        let due_user_ids: Vec<String> = due_users.into_iter().map(|(user_id, _)| user_id).collect();
+        if CONF.scheduler_dry_run {
+            dry_run_user_ids.extend(due_user_ids.iter().cloned());
+        }
+        run_pass(&conns, due_user_ids, CONF.scheduler_dry_run).await;
+    }
+}
+
+/// Alerts if the most overdue user is further behind than the configured threshold, which means
+/// that updates aren't keeping up with the number of users.
+fn check_update_lag(due_users: &[(String, NaiveDateTime)], now: NaiveDateTime) {
+    let Some(threshold) = CONF.alert_update_lag_threshold else {
+        return;
+    };
+    let Some(oldest_due_at) = due_users.iter().map(|(_, due_at)| *due_at).min() else {
+        return;
+    };
+    let lag = match (now - oldest_due_at).to_std() {
+        Ok(lag) => lag,
+        Err(_) => return,
+    };
+
+    if lag > threshold {
+        alerts::send_alert(
+            AlertKind::UpdateQueueLag,
+            format!(
+                "Update queue is lagging; the most overdue of {} user(s) waiting to be updated \
+                 has been due for {} minutes",
+                due_users.len(),
+                lag.as_secs() / 60
+            ),
+        );
+    }
+}
+
+/// Recomputes the global artist and track charts.  Failures are logged and retried at the next
+/// refresh interval.
+pub(crate) async fn refresh_global_charts(conn: &DbConn) {
+    info!("Refreshing global charts");
+    for entity in [ExportEntity::Artists, ExportEntity::Tracks] {
+        if let Err(err) = db_util::refresh_global_charts(conn, entity).await {
+            error!("Error refreshing global {:?} chart: {}", entity, err);
+            return;
+        }
+    }
+
+    STATUS.lock().unwrap().last_charts_refresh_at = Some(Utc::now().naive_utc());
+}
+
+/// Re-fetches artist and track metadata that was stored more than `max_age` ago, oldest first, so
+/// that details like image URLs don't go stale.  At most `CONF.metadata_refresh_max_batches`
+/// batches of each are refreshed at a time; the rest are picked up by later refreshes.  Failures
+/// are logged and retried at the next refresh.
+pub(crate) async fn refresh_stale_metadata(
+    token_data: &tokio::sync::Mutex<SpotifyTokenData>,
+    max_age: Duration,
+) {
+    let spotify_access_token = match token_data.lock().await.get().await {
+        Ok(token) => token,
+        Err(err) => {
+            error!(
+                "Error fetching Spotify access token for metadata refresh: {}",
+                err
+            );
+            return;
+        },
+    };
+    let cutoff = Utc::now().naive_utc()
+        - chrono::Duration::from_std(max_age)
+            .expect("`METADATA_REFRESH_MAX_AGE_SECONDS` is validated on startup");
+    let max_count = CONF.metadata_refresh_max_batches * MAX_BATCH_ENTITY_COUNT;
+
+    for (table, table_name) in [
+        (MetadataTable::Artists, "artists"),
+        (MetadataTable::Tracks, "tracks"),
+    ] {
+        let stale_ids = match tokio::task::block_in_place(|| {
+            metadata_store::get_stale_metadata_ids(table, cutoff, max_count as i64)
+        }) {
+            Ok(stale_ids) => stale_ids,
+            Err(err) => {
+                error!("Error finding stale {} metadata: {}", table_name, err);
+                continue;
+            },
+        };
+        if stale_ids.is_empty() {
+            continue;
+        }
+        info!(
+            "Refreshing metadata of {} stale {}",
+            stale_ids.len(),
+            table_name
+        );
+
+        let stale_ids: Vec<&str> = stale_ids.iter().map(String::as_str).collect();
+        for (batch_ix, batch) in stale_ids.chunks(MAX_BATCH_ENTITY_COUNT).enumerate() {
+            if batch_ix > 0 {
+                tokio::time::sleep(DELAY_BETWEEN_METADATA_REFRESH_BATCHES).await;
+            }
+            let unavailable_ids = match crate::spotify_api::refresh_entity_metadata(
+                &spotify_access_token,
+                table,
+                batch,
+            )
+            .await
+            {
+                Ok(unavailable_ids) => unavailable_ids,
+                Err(err) => {
+                    error!("Error refreshing {} metadata: {}", table_name, err);
+                    break;
+                },
+            };
+            metadata_entities_refreshed_total(table_name)
+                .inc_by((batch.len() - unavailable_ids.len()) as u64);
+
+            // Entities that Spotify no longer has keep their last known metadata.  It's marked as
+            // fetched so that they don't stay at the front of the queue forever.
+            let unavailable_ids: Vec<&str> = unavailable_ids.iter().map(String::as_str).collect();
+            if let Err(err) = tokio::task::block_in_place(|| {
+                metadata_store::touch_metadata_items(table, &unavailable_ids)
+            }) {
+                warn!(
+                    "Error marking unavailable {} as refreshed: {}",
+                    table_name, err
+                );
+            }
+        }
+    }
+
+    STATUS.lock().unwrap().last_metadata_refresh_at = Some(Utc::now().naive_utc());
+}
+
+/// Moves users between update tiers based on how recently their profiles were viewed.  Failures
+/// are logged and retried at the next refresh interval.
+pub(crate) async fn refresh_update_tiers(conn: &DbConn) {
+    match db_util::refresh_update_tiers(conn, Utc::now().naive_utc()).await {
+        Ok(changed_count) =>
+            if changed_count > 0 {
+                info!("Moved {} user(s) to a different update tier", changed_count);
+            },
+        Err(err) => {
+            error!("Error refreshing update tiers: {}", err);
+            return;
+        },
+    }
+
+    STATUS.lock().unwrap().last_update_tier_refresh_at = Some(Utc::now().naive_utc());
+}
+
+/// Polls the recently played tracks of users that signed up recently (see `crate::onboarding`)
+pub(crate) async fn poll_onboarding_users(conn: &DbConn) {
+    match crate::onboarding::poll_onboarding_users(conn).await {
+        Ok((success_count, failure_count)) =>
+            if success_count + failure_count > 0 {
+                info!(
+                    "Polled recently played tracks of {} onboarding user(s); {} failed",
+                    success_count + failure_count,
+                    failure_count
+                );
+            },
+        Err(err) => {
+            error!("Error polling onboarding users: {}", err);
+            return;
+        },
+    }
+
+    STATUS.lock().unwrap().last_onboarding_poll_at = Some(Utc::now().naive_utc());
+}
+
+/// System stats are stored in the database, so they shouldn't be recomputed every time the server
+/// restarts.  Returns when the next refresh is due based on when they were last computed.
+async fn get_next_system_stats_refresh(conn: &DbConn) -> Instant {
+    let latest = match db_util::get_latest_system_stats(conn).await {
+        Ok(latest) => latest,
+        Err(err) => {
+            error!("Error fetching latest system stats: {}", err);
+            None
+        },
+    };
+    let elapsed =
+        latest.and_then(|latest| (Utc::now().naive_utc() - latest.computed_at).to_std().ok());
+
+    match elapsed {
+        Some(elapsed) if elapsed < CONF.system_stats_refresh_interval =>
+            Instant::now() + (CONF.system_stats_refresh_interval - elapsed),
+        _ => Instant::now(),
+    }
+}
+
+/// Computes and stores totals for capacity planning.  Failures are logged and retried at the next
+/// refresh interval.
+pub(crate) async fn refresh_system_stats(conn: &DbConn) {
+    info!("Refreshing system stats");
+    let cache_size_bytes = match tokio::task::block_in_place(cache::get_used_memory_bytes) {
+        Ok(cache_size_bytes) => Some(cache_size_bytes),
+        Err(err) => {
+            warn!("Error fetching cache size for system stats: {}", err);
+            None
+        },
+    };
+    if let Err(err) = db_util::store_system_stats(conn, cache_size_bytes).await {
+        error!("Error storing system stats: {}", err);
+        return;
+    }
+
+    STATUS.lock().unwrap().last_system_stats_refresh_at = Some(Utc::now().naive_utc());
+}
+
+/// Updates all of the provided users, sharing them between one worker per connection.  If `dry_run`
+/// is set, the updates are dry runs whose reports are logged.  Returns the number of users that
+/// were updated successfully and the number whose updates failed.
+pub(crate) async fn run_pass(
+    conns: &[DbConn],
+    due_user_ids: Vec<String>,
+    dry_run: bool,
+) -> (usize, usize) {
+    info!(
+        "Update scheduler starting pass over {} user(s)",
+        due_user_ids.len()
+    );
+    publish_admin_event(|| AdminEvent::UpdatePassStarted {
+        user_count: due_user_ids.len(),
+    });
+    {
+        let mut status = STATUS.lock().unwrap();
+        status.queue_depth = due_user_ids.len();
+        status.last_run_started_at = Some(Utc::now().naive_utc());
+        status.last_run_success_count = 0;
+        status.last_run_failure_count = 0;
+    }
+
+    let queue = &Mutex::new(VecDeque::from(due_user_ids));
+    let workers = conns.iter().map(|conn| async move {
+        loop {
+            let user_id = {
+                let mut queue = queue.lock().unwrap();
+                let user_id = queue.pop_front();
+                STATUS.lock().unwrap().queue_depth = queue.len();
+                user_id
+            };
+            let Some(user_id) = user_id else {
+                break;
+            };
+
+            publish_admin_event(|| AdminEvent::UserUpdateStarted {
+                spotify_id: user_id.clone(),
+            });
+            let start = Instant::now();
+            let res = if dry_run {
+                dry_run_user(conn, user_id.clone()).await
+            } else {
+                update_user_inner(conn, Some(user_id.clone()))
+                    .await
+                    .map(|_outcome| ())
+            };
+            publish_admin_event(|| AdminEvent::UserUpdateFinished {
+                spotify_id: user_id.clone(),
+                duration_ms: start.elapsed().as_millis() as u64,
+                error: res.as_ref().err().map(|err| err.to_string()),
+            });
+            {
+                let mut status = STATUS.lock().unwrap();
+                match res {
+                    Ok(()) => {
+                        if !dry_run {
+                            user_updates_success_total().inc();
+                        }
+                        status.last_run_success_count += 1;
+                        status.total_success_count += 1;
+                    },
+                    Err(err) => {
+                        warn!("Scheduled update failed for user {}: {}", user_id, err);
+                        if !dry_run {
+                            user_updates_failure_total().inc();
+                        }
+                        status.last_run_failure_count += 1;
+                        status.total_failure_count += 1;
+                    },
+                }
+            }
+
+            tokio::time::sleep(DELAY_BETWEEN_UPDATES).await;
+        }
+    });
+    futures::future::join_all(workers).await;
+
+    let status = {
+        let mut status = STATUS.lock().unwrap();
+        status.last_run_finished_at = Some(Utc::now().naive_utc());
+        status.clone()
+    };
+    info!(
+        "Update scheduler finished pass; {} succeeded, {} failed",
+        status.last_run_success_count, status.last_run_failure_count
+    );
+    publish_admin_event(|| AdminEvent::UpdatePassFinished {
+        success_count: status.last_run_success_count,
+        failure_count: status.last_run_failure_count,
+    });
+    (status.last_run_success_count, status.last_run_failure_count)
+}
+
+/// Performs a dry-run update of the user and logs its report
+async fn dry_run_user(conn: &DbConn, user_id: String) -> Result<(), Error> {
+    match crate::dry_run::dry_run_update(conn, user_id.clone()).await? {
+        Some(report) => {
+            info!(
+                "Dry run update of user {}: {}",
+                user_id,
+                serde_json::to_string(&report).unwrap_or_default()
+            );
+            Ok(())
+        },
+        None => Err(Error::NotFound(format!(
+            "No user with Spotify ID {}",
+            user_id
+        ))),
+    }
+}
@@ -1,6 +1,35 @@
 // @generated automatically by Diesel CLI.
 
 diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    api_keys (id) {
+        id -> Bigint,
+        name -> Varchar,
+        key_hash -> Char,
+        scopes -> Varchar,
+        rate_limit_per_minute -> Integer,
+        created_at -> Datetime,
+        revoked_at -> Nullable<Datetime>,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    artist_rank_deltas (id) {
+        id -> Bigint,
+        user_id -> Bigint,
+        update_time -> Datetime,
+        mapped_spotify_id -> Integer,
+        timeframe -> Unsigned<Tinyint>,
+        ranking -> Unsigned<Tinyint>,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
     artist_rank_snapshots (id) {
         id -> Bigint,
         user_id -> Bigint,
@@ -12,6 +41,8 @@ diesel::table! {
 }
 
 diesel::table! {
+    use crate::db_backend::sql_types::*;
+
     artist_stats_history (id) {
         id -> Bigint,
         spotify_id -> Varchar,
@@ -22,6 +53,19 @@ diesel::table! {
 }
 
 diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    artists (spotify_id) {
+        spotify_id -> Varchar,
+        metadata -> Mediumtext,
+        last_fetched -> Datetime,
+        name -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
     artists_genres (id) {
         id -> Bigint,
         artist_id -> Integer,
@@ -30,6 +74,8 @@ diesel::table! {
 }
 
 diesel::table! {
+    use crate::db_backend::sql_types::*;
+
     artists_users_first_seen (user_id, mapped_spotify_id) {
         user_id -> Bigint,
         mapped_spotify_id -> Integer,
@@ -38,6 +84,179 @@ diesel::table! {
 }
 
 diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    diversity_scores (user_id, update_time, timeframe) {
+        user_id -> Bigint,
+        update_time -> Datetime,
+        timeframe -> Unsigned<Tinyint>,
+        genre_entropy -> Float,
+        artist_gini -> Float,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    email_subscriptions (user_id) {
+        user_id -> Bigint,
+        email -> Varchar,
+        unsubscribe_token -> Varchar,
+        created_at -> Datetime,
+        last_sent_at -> Nullable<Datetime>,
+        confirmation_token -> Nullable<Varchar>,
+        confirmed_at -> Nullable<Datetime>,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    followed_artists (id) {
+        id -> Bigint,
+        user_id -> Bigint,
+        mapped_spotify_id -> Integer,
+        followed_at -> Datetime,
+        unfollowed_at -> Nullable<Datetime>,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    friendships (requester_id, addressee_id) {
+        requester_id -> Bigint,
+        addressee_id -> Bigint,
+        requested_at -> Datetime,
+        accepted_at -> Nullable<Datetime>,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    genre_weights (user_id, update_time, timeframe, genre) {
+        user_id -> Bigint,
+        update_time -> Datetime,
+        timeframe -> Unsigned<Tinyint>,
+        genre -> Varchar,
+        weight -> Unsigned<Integer>,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    global_charts (entity_type, timeframe, ranking) {
+        entity_type -> Unsigned<Tinyint>,
+        timeframe -> Unsigned<Tinyint>,
+        ranking -> Unsigned<Smallint>,
+        mapped_spotify_id -> Integer,
+        user_count -> Bigint,
+        score -> Bigint,
+        computed_at -> Datetime,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    latest_artist_stats (user_id, timeframe, ranking) {
+        user_id -> Bigint,
+        timeframe -> Unsigned<Tinyint>,
+        ranking -> Unsigned<Tinyint>,
+        mapped_spotify_id -> Integer,
+        update_time -> Datetime,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    latest_track_stats (user_id, timeframe, ranking) {
+        user_id -> Bigint,
+        timeframe -> Unsigned<Tinyint>,
+        ranking -> Unsigned<Tinyint>,
+        mapped_spotify_id -> Integer,
+        update_time -> Datetime,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    library_snapshots (user_id, update_time) {
+        user_id -> Bigint,
+        update_time -> Datetime,
+        saved_track_count -> Unsigned<Integer>,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    linked_accounts (primary_user_id, linked_user_id) {
+        primary_user_id -> Bigint,
+        linked_user_id -> Bigint,
+        created_at -> Datetime,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    outbox_events (id) {
+        id -> Bigint,
+        user_id -> Bigint,
+        event_type -> Varchar,
+        payload -> Text,
+        created_at -> Datetime,
+        attempt_count -> Integer,
+        next_attempt_at -> Datetime,
+        delivered_at -> Nullable<Datetime>,
+        last_error -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    popularity_history (user_id, update_time, timeframe, entity_type, ranking) {
+        user_id -> Bigint,
+        update_time -> Datetime,
+        timeframe -> Unsigned<Tinyint>,
+        entity_type -> Unsigned<Tinyint>,
+        ranking -> Unsigned<Tinyint>,
+        mapped_spotify_id -> Integer,
+        popularity -> Unsigned<Tinyint>,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    profile_views (user_id, view_date) {
+        user_id -> Bigint,
+        view_date -> Datetime,
+        view_count -> Integer,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    recently_played (id) {
+        id -> Bigint,
+        user_id -> Bigint,
+        mapped_spotify_id -> Integer,
+        played_at -> Datetime,
+        duration_ms -> Unsigned<Integer>,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
     related_artists (artist_spotify_id) {
         artist_spotify_id -> Integer,
         related_artists_json -> Text,
@@ -45,6 +264,30 @@ diesel::table! {
 }
 
 diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    saved_tracks (user_id, mapped_spotify_id) {
+        user_id -> Bigint,
+        mapped_spotify_id -> Integer,
+        added_at -> Datetime,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    snapshot_updates (user_id, update_time, timeframe) {
+        user_id -> Bigint,
+        update_time -> Datetime,
+        timeframe -> Unsigned<Tinyint>,
+        artist_count -> Unsigned<Tinyint>,
+        track_count -> Unsigned<Tinyint>,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
     spotify_items (id) {
         id -> Integer,
         spotify_id -> Varchar,
@@ -52,6 +295,54 @@ diesel::table! {
 }
 
 diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    system_stats (id) {
+        id -> Bigint,
+        computed_at -> Datetime,
+        user_count -> Bigint,
+        snapshot_count -> Bigint,
+        table_row_counts -> Text,
+        database_size_bytes -> Bigint,
+        cache_size_bytes -> Nullable<Bigint>,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    top_tracks_playlists (user_id, timeframe) {
+        user_id -> Bigint,
+        timeframe -> Unsigned<Tinyint>,
+        playlist_spotify_id -> Varchar,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    track_isrc_map (spotify_id) {
+        spotify_id -> Varchar,
+        isrc -> Varchar,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    track_rank_deltas (id) {
+        id -> Bigint,
+        user_id -> Bigint,
+        update_time -> Datetime,
+        mapped_spotify_id -> Integer,
+        timeframe -> Unsigned<Tinyint>,
+        ranking -> Unsigned<Tinyint>,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
     track_rank_snapshots (id) {
         id -> Bigint,
         user_id -> Bigint,
@@ -63,6 +354,8 @@ diesel::table! {
 }
 
 diesel::table! {
+    use crate::db_backend::sql_types::*;
+
     track_stats_history (id) {
         id -> Bigint,
         followers -> Unsigned<Bigint>,
@@ -72,6 +365,19 @@ diesel::table! {
 }
 
 diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    tracks (spotify_id) {
+        spotify_id -> Varchar,
+        metadata -> Mediumtext,
+        last_fetched -> Datetime,
+        name -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
     tracks_artists (id) {
         id -> Integer,
         track_id -> Integer,
@@ -80,6 +386,8 @@ diesel::table! {
 }
 
 diesel::table! {
+    use crate::db_backend::sql_types::*;
+
     tracks_users_first_seen (user_id, mapped_spotify_id) {
         user_id -> Bigint,
         mapped_spotify_id -> Integer,
@@ -88,6 +396,35 @@ diesel::table! {
 }
 
 diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    update_errors (id) {
+        id -> Bigint,
+        user_id -> Bigint,
+        error_time -> Datetime,
+        error_message -> Text,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
+    user_settings (user_id) {
+        user_id -> Bigint,
+        display_name -> Nullable<Varchar>,
+        default_timeframe -> Unsigned<Tinyint>,
+        update_frequency -> Unsigned<Tinyint>,
+        entity_fetch_count -> Nullable<Unsigned<Tinyint>>,
+        leaderboard_opt_in -> Bool,
+        friends_only_comparisons -> Bool,
+        now_playing_public -> Bool,
+        library_public -> Bool,
+    }
+}
+
+diesel::table! {
+    use crate::db_backend::sql_types::*;
+
     users (id) {
         id -> Bigint,
         creation_time -> Datetime,
@@ -99,26 +436,88 @@ diesel::table! {
         external_data_retrieved -> Bool,
         last_viewed -> Timestamp,
         last_external_data_store -> Timestamp,
+        is_private -> Bool,
+        private_token -> Nullable<Varchar>,
+        consecutive_update_failures -> Integer,
+        timezone -> Nullable<Varchar>,
+        vanity_slug -> Nullable<Varchar>,
+        deactivated_at -> Nullable<Datetime>,
+        update_tier -> Unsigned<Tinyint>,
+        min_update_interval_seconds -> Nullable<Integer>,
+        failed_update_attempts -> Integer,
+        next_update_attempt_at -> Nullable<Datetime>,
     }
 }
 
+diesel::joinable!(artist_rank_deltas -> spotify_items (mapped_spotify_id));
+diesel::joinable!(artist_rank_deltas -> users (user_id));
 diesel::joinable!(artist_rank_snapshots -> spotify_items (mapped_spotify_id));
 diesel::joinable!(artist_rank_snapshots -> users (user_id));
 diesel::joinable!(artists_genres -> spotify_items (artist_id));
+diesel::joinable!(followed_artists -> spotify_items (mapped_spotify_id));
+diesel::joinable!(diversity_scores -> users (user_id));
+diesel::joinable!(email_subscriptions -> users (user_id));
+diesel::joinable!(genre_weights -> users (user_id));
+diesel::joinable!(followed_artists -> users (user_id));
+diesel::joinable!(global_charts -> spotify_items (mapped_spotify_id));
+diesel::joinable!(latest_artist_stats -> spotify_items (mapped_spotify_id));
+diesel::joinable!(latest_artist_stats -> users (user_id));
+diesel::joinable!(latest_track_stats -> spotify_items (mapped_spotify_id));
+diesel::joinable!(latest_track_stats -> users (user_id));
+diesel::joinable!(library_snapshots -> users (user_id));
+diesel::joinable!(outbox_events -> users (user_id));
+diesel::joinable!(popularity_history -> users (user_id));
+diesel::joinable!(profile_views -> users (user_id));
+diesel::joinable!(recently_played -> spotify_items (mapped_spotify_id));
+diesel::joinable!(recently_played -> users (user_id));
 diesel::joinable!(related_artists -> spotify_items (artist_spotify_id));
+diesel::joinable!(saved_tracks -> spotify_items (mapped_spotify_id));
+diesel::joinable!(saved_tracks -> users (user_id));
+diesel::joinable!(snapshot_updates -> users (user_id));
+diesel::joinable!(top_tracks_playlists -> users (user_id));
+diesel::joinable!(track_rank_deltas -> spotify_items (mapped_spotify_id));
+diesel::joinable!(track_rank_deltas -> users (user_id));
 diesel::joinable!(track_rank_snapshots -> spotify_items (mapped_spotify_id));
 diesel::joinable!(track_rank_snapshots -> users (user_id));
+diesel::joinable!(update_errors -> users (user_id));
+diesel::joinable!(user_settings -> users (user_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    api_keys,
+    artist_rank_deltas,
     artist_rank_snapshots,
     artist_stats_history,
+    artists,
     artists_genres,
     artists_users_first_seen,
+    diversity_scores,
+    email_subscriptions,
+    followed_artists,
+    friendships,
+    genre_weights,
+    global_charts,
+    latest_artist_stats,
+    latest_track_stats,
+    library_snapshots,
+    linked_accounts,
+    outbox_events,
+    popularity_history,
+    profile_views,
+    recently_played,
     related_artists,
+    saved_tracks,
+    snapshot_updates,
     spotify_items,
+    system_stats,
+    top_tracks_playlists,
+    track_isrc_map,
+    track_rank_deltas,
     track_rank_snapshots,
     track_stats_history,
+    tracks,
     tracks_artists,
     tracks_users_first_seen,
+    update_errors,
+    user_settings,
     users,
 );
@@ -0,0 +1,45 @@
+//! Authenticated encryption of small values with AES-256-GCM, shared by token encryption and
+//! session cookies.  Sealed values are a random nonce followed by the ciphertext, encoded with the
+//! caller's choice of base64 engine.
+
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Key, Nonce,
+};
+use base64::Engine;
+
+use crate::error::Error;
+
+const NONCE_LEN: usize = 12;
+
+pub(crate) type SealingKey = [u8; 32];
+
+/// Encrypts `plaintext` with a new random nonce and encodes the nonce and ciphertext with `engine`
+pub(crate) fn seal(key: &SealingKey, engine: &impl Engine, plaintext: &[u8]) -> String {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .expect("Failed to encrypt value");
+
+    let mut payload = nonce.to_vec();
+    payload.extend_from_slice(&ciphertext);
+    engine.encode(payload)
+}
+
+/// Decodes and decrypts a value produced by `seal`, failing if it was sealed with a different key
+/// or has been tampered with
+pub(crate) fn open(key: &SealingKey, engine: &impl Engine, sealed: &str) -> Result<Vec<u8>, Error> {
+    let payload = engine
+        .decode(sealed)
+        .map_err(|err| Error::Internal(format!("Invalid encoding: {}", err)))?;
+    if payload.len() < NONCE_LEN {
+        return Err(Error::Internal("Sealed value is too short".into()));
+    }
+
+    let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| Error::Internal("Failed to decrypt; the key may have changed".to_owned()))
+}
@@ -0,0 +1,389 @@
+//! The `seed` subcommand, which fills a development database with fake users and months of their
+//! history so that the frontend can be worked on locally with realistic data.  Nothing is fetched
+//! from Spotify.
+//!
+//! Every user gets a snapshot for each day of the seeded period.  Their taste is generated from the
+//! RNG seed: each artist has a base score for the user that rises and falls over time along a slow
+//! wave, and the medium and long timeframes average it over longer windows than the short one.  The
+//! metadata of the fake artists and tracks is written to the metadata store so that the server can
+//! serve it without asking Spotify.  Seeded users have fake access tokens, so the update scheduler
+//! should be disabled with `SCHEDULER_ENABLED=false` while using them.
+//!
+//! Seeding again deletes the seed users' existing snapshots before storing new ones, so the seeded
+//! history is replaced rather than duplicated even though it always ends at the current time.
+
+use std::f32::consts::PI;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::RunQueryDsl;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rocket::{Ignite, Rocket};
+use tokio::task::block_in_place;
+
+use crate::{
+    cache::metadata_store::{self, MetadataTable},
+    db_util,
+    error::Error,
+    models::{
+        Album, Artist, ExternalIds, NewUser, StatsSnapshot, StoredToken, Timeframe, Track, User,
+    },
+    DbConn,
+};
+
+pub(crate) const USAGE: &str =
+    "Usage: spotify-homepage-backend seed [--users <count>] [--days <count>] [--seed <number>]";
+
+const DEFAULT_USER_COUNT: usize = 10;
+const DEFAULT_DAYS: u32 = 180;
+const SEED_ARTIST_COUNT: usize = 150;
+const SEED_TRACKS_PER_ARTIST: usize = 4;
+/// Number of top artists and tracks stored for each timeframe, which matches what's fetched from
+/// Spotify for real users
+const SEED_TOP_COUNT: usize = 50;
+const SEED_GENRES: [&str; 12] = [
+    "indie rock",
+    "hyperpop",
+    "jazz",
+    "lo-fi",
+    "k-pop",
+    "korean pop",
+    "hip hop",
+    "synthpop",
+    "ambient",
+    "folk",
+    "techno",
+    "r&b",
+];
+/// Days before each snapshot that a timeframe's scores are averaged over
+const SHORT_TERM_SAMPLE_DAYS: [u32; 1] = [0];
+const MEDIUM_TERM_SAMPLE_DAYS: [u32; 6] = [0, 30, 60, 90, 120, 150];
+const LONG_TERM_SAMPLE_DAYS: [u32; 8] = [0, 90, 180, 270, 360, 450, 540, 630];
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SeedArgs {
+    pub user_count: usize,
+    /// Number of days of history to generate for each user
+    pub days: u32,
+    /// Seed of the RNG that the catalog and users' history are generated from
+    pub seed: u64,
+}
+
+/// Parses the arguments that follow `seed`
+pub(crate) fn parse_args(args: impl IntoIterator<Item = String>) -> Result<SeedArgs, Error> {
+    let mut seed_args = SeedArgs {
+        user_count: DEFAULT_USER_COUNT,
+        days: DEFAULT_DAYS,
+        seed: 0,
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let val = args.next();
+        let invalid = || Error::BadRequest(format!("`{}` requires a non-negative integer", arg));
+        match arg.as_str() {
+            "--users" =>
+                seed_args.user_count = val.and_then(|val| val.parse().ok()).ok_or_else(invalid)?,
+            "--days" =>
+                seed_args.days = val.and_then(|val| val.parse().ok()).ok_or_else(invalid)?,
+            "--seed" =>
+                seed_args.seed = val.and_then(|val| val.parse().ok()).ok_or_else(invalid)?,
+            _ => return Err(Error::BadRequest(format!("Unknown argument `{}`", arg))),
+        }
+    }
+    Ok(seed_args)
+}
+
+fn seed_artist_id(ix: usize) -> String { format!("seedartist{:04}", ix) }
+
+fn seed_track_id(ix: usize) -> String { format!("seedtrack{:04}", ix) }
+
+fn seed_user_id(ix: usize) -> String { format!("seeduser{:03}", ix) }
+
+/// Generates the fake artists and tracks that all seeded users listen to.  Track `i` is by artist
+/// `i / SEED_TRACKS_PER_ARTIST`.
+fn build_catalog(rng: &mut StdRng) -> (Vec<Artist>, Vec<Track>) {
+    let artists: Vec<Artist> = (0..SEED_ARTIST_COUNT)
+        .map(|ix| {
+            let genre_count = rng.gen_range(1..=3);
+            let mut genres: Vec<String> = Vec::with_capacity(genre_count);
+            while genres.len() < genre_count {
+                let genre = SEED_GENRES[rng.gen_range(0..SEED_GENRES.len())];
+                if !genres.iter().any(|existing| existing == genre) {
+                    genres.push(genre.to_owned());
+                }
+            }
+
+            Artist {
+                genres: Some(genres),
+                id: seed_artist_id(ix),
+                images: Some(Vec::new()),
+                name: format!("Seed Artist {}", ix),
+                popularity: Some(rng.gen_range(5..=95)),
+            }
+        })
+        .collect();
+
+    let tracks = (0..SEED_ARTIST_COUNT * SEED_TRACKS_PER_ARTIST)
+        .map(|ix| {
+            let artist = artists[ix / SEED_TRACKS_PER_ARTIST].clone();
+            Track {
+                album: Album {
+                    artists: vec![artist.clone()],
+                    id: format!("seedalbum{:04}", ix / 2),
+                    images: Vec::new(),
+                    name: format!("Seed Album {}", ix / 2),
+                },
+                artists: vec![artist],
+                duration_ms: Some(rng.gen_range(120_000..360_000)),
+                id: seed_track_id(ix),
+                name: format!("Seed Track {}", ix),
+                popularity: Some(rng.gen_range(5..=95)),
+                preview_url: None,
+                external_ids: Some(ExternalIds {
+                    isrc: Some(format!("SEED{:08}", ix)),
+                }),
+            }
+        })
+        .collect();
+
+    (artists, tracks)
+}
+
+/// How much a seeded user listens to each artist and track over time
+struct Taste {
+    /// `(base score, period in days, phase)` of each artist
+    artists: Vec<(f32, f32, f32)>,
+    /// Score of each track relative to the other tracks by its artist
+    tracks: Vec<f32>,
+}
+
+impl Taste {
+    fn generate(rng: &mut StdRng) -> Self {
+        Taste {
+            // Cubing makes most artists minor and a few into favorites
+            artists: (0..SEED_ARTIST_COUNT)
+                .map(|_| {
+                    (
+                        rng.gen::<f32>().powi(3),
+                        rng.gen_range(30.0..240.0),
+                        rng.gen_range(0.0..2. * PI),
+                    )
+                })
+                .collect(),
+            tracks: (0..SEED_ARTIST_COUNT * SEED_TRACKS_PER_ARTIST)
+                .map(|_| rng.gen())
+                .collect(),
+        }
+    }
+
+    fn artist_score(&self, artist_ix: usize, day: f32, sample_days: &[u32]) -> f32 {
+        let (base, period, phase) = self.artists[artist_ix];
+        let total: f32 = sample_days
+            .iter()
+            .map(|&days_ago| {
+                let day = day - days_ago as f32;
+                base * (1.5 + (2. * PI * day / period + phase).sin())
+            })
+            .sum();
+        total / sample_days.len() as f32
+    }
+
+    /// Returns the indices of the top entities for the timeframe as of `day`, from the top down
+    fn top_entities(&self, timeframe: Timeframe, day: f32) -> (Vec<usize>, Vec<usize>) {
+        let sample_days: &[u32] = match timeframe {
+            Timeframe::Short => &SHORT_TERM_SAMPLE_DAYS,
+            Timeframe::Medium => &MEDIUM_TERM_SAMPLE_DAYS,
+            Timeframe::Long => &LONG_TERM_SAMPLE_DAYS,
+        };
+        let artist_scores: Vec<f32> = (0..SEED_ARTIST_COUNT)
+            .map(|ix| self.artist_score(ix, day, sample_days))
+            .collect();
+        let top = |scores: Vec<f32>| {
+            let mut ranking: Vec<usize> = (0..scores.len()).collect();
+            ranking.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+            ranking.truncate(SEED_TOP_COUNT);
+            ranking
+        };
+
+        let track_scores: Vec<f32> = self
+            .tracks
+            .iter()
+            .enumerate()
+            .map(|(ix, score)| artist_scores[ix / SEED_TRACKS_PER_ARTIST] * score)
+            .collect();
+        (top(artist_scores), top(track_scores))
+    }
+
+    fn build_snapshot(
+        &self,
+        user: &User,
+        update_time: NaiveDateTime,
+        day: f32,
+        artists: &[Artist],
+        tracks: &[Track],
+    ) -> StatsSnapshot {
+        let mut snapshot = StatsSnapshot::new(user.localize(update_time));
+        for timeframe in Timeframe::ALL {
+            let (top_artists, top_tracks) = self.top_entities(timeframe, day);
+            for ix in top_artists {
+                snapshot.artists.add_item(timeframe, artists[ix].clone());
+            }
+            for ix in top_tracks {
+                snapshot.tracks.add_item(timeframe, tracks[ix].clone());
+            }
+        }
+        snapshot
+    }
+}
+
+async fn get_or_create_user(
+    conn: &DbConn,
+    spotify_id: String,
+    username: String,
+    creation_time: NaiveDateTime,
+) -> Result<User, Error> {
+    if let Some(user) = db_util::get_user_by_spotify_id(conn, spotify_id.clone()).await? {
+        return Ok(user);
+    }
+
+    let new_user = NewUser {
+        creation_time,
+        last_update_time: creation_time,
+        spotify_id: spotify_id.clone(),
+        username,
+        token: StoredToken("seed-access-token".to_owned()),
+        refresh_token: StoredToken("seed-refresh-token".to_owned()),
+        private_token: Some(db_util::generate_private_token()),
+    };
+    let query = diesel::insert_into(crate::schema::users::table).values(new_user);
+    conn.run(move |conn| query.execute(conn)).await?;
+    db_util::get_user_by_spotify_id(conn, spotify_id)
+        .await?
+        .ok_or_else(|| Error::Internal("Seeded user not found after inserting it".to_owned()))
+}
+
+/// Deletes all of the snapshots of a seed user that already existed, from a previous seeding
+async fn delete_seeded_snapshots(conn: &DbConn, user: &User) -> Result<(), Error> {
+    let (artist_update_times, track_update_times) =
+        db_util::get_snapshot_update_times(conn, user.id).await?;
+    if artist_update_times.is_empty() && track_update_times.is_empty() {
+        return Ok(());
+    }
+
+    let deleted_count =
+        db_util::delete_snapshots(conn, user.id, artist_update_times, track_update_times).await?;
+    info!(
+        "Deleted {} previously seeded rows for user {}",
+        deleted_count, user.spotify_id
+    );
+    Ok(())
+}
+
+/// Seeds the database, returning the process's exit code
+pub(crate) async fn run(args: SeedArgs, rocket: &Rocket<Ignite>) -> i32 {
+    match seed(args, rocket).await {
+        Ok(()) => 0,
+        Err(err) => {
+            error!("Error seeding database: {}", err);
+            1
+        },
+    }
+}
+
+async fn seed(args: SeedArgs, rocket: &Rocket<Ignite>) -> Result<(), Error> {
+    let conn = DbConn::get_one(rocket)
+        .await
+        .ok_or_else(|| Error::Internal("Failed to check out database connection".to_owned()))?;
+    let mut rng = StdRng::seed_from_u64(args.seed);
+
+    let (artists, tracks) = build_catalog(&mut rng);
+    block_in_place(|| {
+        let artist_pairs: Vec<(&str, &Artist)> = artists
+            .iter()
+            .map(|artist| (artist.id.as_str(), artist))
+            .collect();
+        metadata_store::set_metadata_items(MetadataTable::Artists, &artist_pairs)?;
+        let track_pairs: Vec<(&str, &Track)> = tracks
+            .iter()
+            .map(|track| (track.id.as_str(), track))
+            .collect();
+        metadata_store::set_metadata_items(MetadataTable::Tracks, &track_pairs)
+    })?;
+    info!(
+        "Stored metadata for {} seeded artists and {} seeded tracks",
+        artists.len(),
+        tracks.len()
+    );
+
+    let now = Utc::now().naive_utc();
+    let start_time = now - Duration::days(args.days as i64);
+    for user_ix in 0..args.user_count {
+        let taste = Taste::generate(&mut rng);
+        let user = get_or_create_user(
+            &conn,
+            seed_user_id(user_ix),
+            format!("Seed User {}", user_ix),
+            start_time,
+        )
+        .await?;
+        delete_seeded_snapshots(&conn, &user).await?;
+
+        for day in 0..=args.days {
+            let update_time = start_time + Duration::days(day as i64);
+            let snapshot = taste.build_snapshot(&user, update_time, day as f32, &artists, &tracks);
+            crate::spotify_api::store_stats_snapshot(&conn, &user, snapshot).await?;
+        }
+        db_util::update_user_last_updated(&user, &conn, now).await?;
+        info!(
+            "Seeded {} days of history for user {}",
+            args.days + 1,
+            user.spotify_id
+        );
+    }
+
+    info!("Seeded {} user(s)", args.user_count);
+    Ok(())
+}
+
+#[test]
+fn seeded_history() {
+    assert_eq!(
+        parse_args(["--users", "3", "--seed", "7"].map(String::from)).ok(),
+        Some(SeedArgs {
+            user_count: 3,
+            days: DEFAULT_DAYS,
+            seed: 7,
+        })
+    );
+    assert!(parse_args(["--users".to_owned()]).is_err());
+    assert!(parse_args(["--days".to_owned(), "-1".to_owned()]).is_err());
+    assert!(parse_args(["--everyone".to_owned()]).is_err());
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let (artists, tracks) = build_catalog(&mut rng);
+    assert_eq!(tracks.len(), SEED_ARTIST_COUNT * SEED_TRACKS_PER_ARTIST);
+    assert_eq!(tracks[5].artists[0].id, artists[1].id);
+
+    let taste = Taste::generate(&mut rng);
+    for timeframe in Timeframe::ALL {
+        let (top_artists, top_tracks) = taste.top_entities(timeframe, 100.);
+        assert_eq!(top_artists.len(), SEED_TOP_COUNT);
+        assert_eq!(top_tracks.len(), SEED_TOP_COUNT);
+        let mut deduped = top_artists.clone();
+        deduped.sort_unstable();
+        deduped.dedup();
+        assert_eq!(deduped.len(), SEED_TOP_COUNT);
+    }
+    assert_ne!(
+        taste.top_entities(Timeframe::Short, 0.),
+        taste.top_entities(Timeframe::Short, 60.)
+    );
+
+    // The same seed always generates the same history
+    let mut rng = StdRng::seed_from_u64(0);
+    let (same_artists, _) = build_catalog(&mut rng);
+    assert_eq!(same_artists[10].genres, artists[10].genres);
+    assert_eq!(
+        Taste::generate(&mut rng).top_entities(Timeframe::Medium, 30.),
+        taste.top_entities(Timeframe::Medium, 30.)
+    );
+}
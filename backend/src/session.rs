@@ -0,0 +1,193 @@
+//! Session cookies proving ownership of a Spotify account.  `oauth_cb` issues one to users once
+//! they've authorized so that the website can make authenticated requests on their behalf without
+//! holding on to a Spotify access token.
+//!
+//! Sessions are the user's Spotify ID and an expiry time sealed with AES-256-GCM using the key
+//! from `SESSION_SECRET`, so they can't be forged or altered without the key.
+//!
+//! Clients without a session can instead authenticate with a Spotify access token, which is checked
+//! by looking up its owner with Spotify.  Verified tokens are cached per instance for
+//! `VERIFIED_TOKEN_CACHE_TTL`, keyed by their hash, so that those clients don't spend the user's
+//! Spotify rate limit on every request.  A token revoked in the meantime keeps being accepted until
+//! its cached verification expires.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use fnv::FnvHashMap as HashMap;
+use rocket::http::{Cookie, CookieJar, SameSite};
+use sha2::{Digest, Sha256};
+
+use crate::{conf::CONF, sealing};
+
+pub(crate) const SESSION_COOKIE_NAME: &str = "spotifytrack_session";
+
+const VERIFIED_TOKEN_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+/// Once this many tokens are cached, expired verifications are dropped.  If the cache is still
+/// full, the oldest verification is evicted.
+const MAX_CACHED_VERIFIED_TOKENS: usize = 10_000;
+
+lazy_static::lazy_static! {
+    /// Spotify IDs of the owners of recently verified access tokens along with when they were
+    /// verified, keyed by token hash
+    static ref VERIFIED_TOKEN_CACHE: Mutex<HashMap<String, (Instant, String)>> =
+        Mutex::new(HashMap::default());
+}
+
+pub(crate) type SessionKey = [u8; 32];
+
+fn seal_session(key: &SessionKey, spotify_id: &str, expires_at: DateTime<Utc>) -> String {
+    let plaintext = format!("{}:{}", expires_at.timestamp(), spotify_id);
+    sealing::seal(key, &URL_SAFE_NO_PAD, plaintext.as_bytes())
+}
+
+/// Returns the Spotify ID of the user the session belongs to if it's authentic and hasn't expired
+fn open_session(key: &SessionKey, session: &str, now: DateTime<Utc>) -> Option<String> {
+    let plaintext = sealing::open(key, &URL_SAFE_NO_PAD, session).ok()?;
+    let plaintext = String::from_utf8(plaintext).ok()?;
+    let (expires_at, spotify_id) = plaintext.split_once(':')?;
+    if expires_at.parse::<i64>().ok()? <= now.timestamp() {
+        return None;
+    }
+    Some(spotify_id.to_owned())
+}
+
+/// Issues a session cookie for the user, replacing any existing one
+pub(crate) fn start_session(cookies: &CookieJar<'_>, spotify_id: &str) {
+    let expires_at = Utc::now() + CONF.session_ttl;
+    let cookie = Cookie::build(
+        SESSION_COOKIE_NAME,
+        seal_session(&CONF.session_key, spotify_id, expires_at),
+    )
+    .path("/")
+    .http_only(true)
+    .secure(CONF.api_server_url.starts_with("https://"))
+    .same_site(SameSite::Lax)
+    .max_age(rocket::time::Duration::seconds(
+        CONF.session_ttl.num_seconds(),
+    ))
+    .finish();
+    cookies.add(cookie);
+}
+
+pub(crate) fn end_session(cookies: &CookieJar<'_>) {
+    cookies.remove(Cookie::build(SESSION_COOKIE_NAME, "").path("/").finish());
+}
+
+/// Returns the Spotify ID of the user that the request's session cookie belongs to, if it has a
+/// valid one
+pub(crate) fn get_session_user(cookies: &CookieJar<'_>) -> Option<String> {
+    let session = cookies.get(SESSION_COOKIE_NAME)?;
+    open_session(&CONF.session_key, session.value(), Utc::now())
+}
+
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn get_cached_token_user(
+    cache: &HashMap<String, (Instant, String)>,
+    token: &str,
+    now: Instant,
+) -> Option<String> {
+    let (verified_at, spotify_id) = cache.get(&hash_token(token))?;
+    if now.saturating_duration_since(*verified_at) >= VERIFIED_TOKEN_CACHE_TTL {
+        return None;
+    }
+    Some(spotify_id.clone())
+}
+
+fn insert_cached_token_user(
+    cache: &mut HashMap<String, (Instant, String)>,
+    token: &str,
+    spotify_id: String,
+    now: Instant,
+) {
+    let token_hash = hash_token(token);
+    if cache.len() >= MAX_CACHED_VERIFIED_TOKENS && !cache.contains_key(&token_hash) {
+        cache.retain(|_, (verified_at, _)| {
+            now.saturating_duration_since(*verified_at) < VERIFIED_TOKEN_CACHE_TTL
+        });
+    }
+    if cache.len() >= MAX_CACHED_VERIFIED_TOKENS && !cache.contains_key(&token_hash) {
+        let oldest_token_hash = cache
+            .iter()
+            .min_by_key(|(_, (verified_at, _))| *verified_at)
+            .map(|(token_hash, _)| token_hash.clone());
+        if let Some(oldest_token_hash) = oldest_token_hash {
+            cache.remove(&oldest_token_hash);
+        }
+    }
+    cache.insert(token_hash, (now, spotify_id));
+}
+
+/// Returns the Spotify ID of the owner of the access token if it was verified recently
+pub(crate) fn get_verified_token_user(token: &str) -> Option<String> {
+    get_cached_token_user(&VERIFIED_TOKEN_CACHE.lock().unwrap(), token, Instant::now())
+}
+
+/// Records that Spotify confirmed the access token belongs to the user with the given Spotify ID
+pub(crate) fn cache_verified_token_user(token: &str, spotify_id: String) {
+    insert_cached_token_user(
+        &mut VERIFIED_TOKEN_CACHE.lock().unwrap(),
+        token,
+        spotify_id,
+        Instant::now(),
+    );
+}
+
+#[test]
+fn session_round_trip() {
+    let key = [3u8; 32];
+    let now = Utc::now();
+    let session = seal_session(&key, "some:user", now + chrono::Duration::days(1));
+    assert_eq!(
+        open_session(&key, &session, now).as_deref(),
+        Some("some:user")
+    );
+
+    // Expired sessions, sessions sealed with another key, and tampered sessions are all rejected
+    assert_eq!(
+        open_session(&key, &session, now + chrono::Duration::days(2)),
+        None
+    );
+    assert_eq!(open_session(&[4u8; 32], &session, now), None);
+    let mut tampered = session.into_bytes();
+    let last = tampered.len() - 1;
+    tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+    assert_eq!(
+        open_session(&key, std::str::from_utf8(&tampered).unwrap(), now),
+        None
+    );
+    assert_eq!(open_session(&key, "not a session", now), None);
+}
+
+#[test]
+fn verified_token_cache() {
+    let mut cache = HashMap::default();
+    let now = Instant::now();
+    insert_cached_token_user(&mut cache, "token", "user".to_owned(), now);
+    assert_eq!(
+        get_cached_token_user(&cache, "token", now + Duration::from_secs(1)).as_deref(),
+        Some("user")
+    );
+    assert_eq!(get_cached_token_user(&cache, "other token", now), None);
+    assert_eq!(
+        get_cached_token_user(&cache, "token", now + VERIFIED_TOKEN_CACHE_TTL),
+        None
+    );
+    // Tokens themselves aren't kept in memory
+    assert!(cache.keys().all(|token_hash| token_hash != "token"));
+
+    for i in 0..MAX_CACHED_VERIFIED_TOKENS {
+        insert_cached_token_user(&mut cache, &i.to_string(), "user".to_owned(), now);
+    }
+    assert_eq!(cache.len(), MAX_CACHED_VERIFIED_TOKENS);
+}
@@ -0,0 +1,157 @@
+//! Renders users' top artists and tracks into PNG images sized for social media link previews.
+//! Cards are built as SVG documents and rasterized with `resvg` using a bundled font so that
+//! rendering doesn't depend on the fonts installed on the server.
+
+use std::sync::Arc;
+
+use resvg::{
+    tiny_skia::{Pixmap, Transform},
+    usvg::{self, fontdb},
+};
+
+use crate::error::Error;
+
+/// Recommended size for OpenGraph images
+pub(crate) const CARD_WIDTH: u32 = 1200;
+pub(crate) const CARD_HEIGHT: u32 = 630;
+/// Number of artists and tracks shown on each card
+pub(crate) const CARD_ITEM_COUNT: usize = 5;
+/// Names longer than this are truncated so they don't overflow their column
+const MAX_NAME_CHARS: usize = 30;
+const FONT_FAMILY: &str = "Aldrich";
+static FONT_DATA: &[u8] = include_bytes!("../assets/Aldrich-Regular.ttf");
+
+lazy_static::lazy_static! {
+    static ref FONT_DB: Arc<fontdb::Database> = {
+        let mut db = fontdb::Database::new();
+        db.load_font_data(FONT_DATA.to_vec());
+        Arc::new(db)
+    };
+}
+
+pub(crate) struct ShareCard {
+    pub display_name: String,
+    pub timeframe: &'static str,
+    pub artists: Vec<String>,
+    /// `(track_name, artist_name)`
+    pub tracks: Vec<(String, String)>,
+}
+
+pub(crate) fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn truncate(s: &str) -> String {
+    if s.chars().count() <= MAX_NAME_CHARS {
+        return s.to_owned();
+    }
+
+    let mut truncated: String = s.chars().take(MAX_NAME_CHARS - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+fn build_column(svg: &mut String, x: u32, heading: &str, items: &[(String, Option<String>)]) {
+    svg.push_str(&format!(
+        r##"<text x="{}" y="200" font-size="30" fill="#1db954">{}</text>"##,
+        x, heading
+    ));
+    for (i, (name, subtitle)) in items.iter().take(CARD_ITEM_COUNT).enumerate() {
+        let y = 260 + i as u32 * 72;
+        svg.push_str(&format!(
+            r##"<text x="{}" y="{}" font-size="28" fill="#ffffff">{}. {}</text>"##,
+            x,
+            y,
+            i + 1,
+            escape_xml(&truncate(name))
+        ));
+        if let Some(subtitle) = subtitle {
+            svg.push_str(&format!(
+                r##"<text x="{}" y="{}" font-size="20" fill="#b3b3b3">{}</text>"##,
+                x + 36,
+                y + 28,
+                escape_xml(&truncate(subtitle))
+            ));
+        }
+    }
+}
+
+fn build_svg(card: &ShareCard) -> String {
+    let mut svg = format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="{font}"><rect width="{w}" height="{h}" fill="#121212"/><text x="60" y="90" font-size="48" fill="#ffffff">{name}</text><text x="60" y="135" font-size="28" fill="#b3b3b3">Top Artists and Tracks &#8226; {timeframe}</text>"##,
+        w = CARD_WIDTH,
+        h = CARD_HEIGHT,
+        font = FONT_FAMILY,
+        name = escape_xml(&truncate(&card.display_name)),
+        timeframe = card.timeframe,
+    );
+
+    let artists: Vec<_> = card
+        .artists
+        .iter()
+        .map(|name| (name.clone(), None))
+        .collect();
+    build_column(&mut svg, 60, "Artists", &artists);
+    let tracks: Vec<_> = card
+        .tracks
+        .iter()
+        .map(|(name, artist)| (name.clone(), Some(artist.clone())))
+        .collect();
+    build_column(&mut svg, 620, "Tracks", &tracks);
+
+    svg.push_str(&format!(
+        r##"<text x="{}" y="{}" font-size="22" fill="#1db954" text-anchor="end">spotifytrack.net</text></svg>"##,
+        CARD_WIDTH - 40,
+        CARD_HEIGHT - 30
+    ));
+    svg
+}
+
+/// Renders the card as a PNG
+pub(crate) fn render_share_card(card: &ShareCard) -> Result<Vec<u8>, Error> {
+    let svg = build_svg(card);
+    let opts = usvg::Options {
+        font_family: FONT_FAMILY.to_owned(),
+        fontdb: FONT_DB.clone(),
+        ..Default::default()
+    };
+    let tree = usvg::Tree::from_str(&svg, &opts).map_err(|err| {
+        error!("Error parsing share card SVG: {:?}", err);
+        Error::Internal("Error rendering share card".into())
+    })?;
+
+    let mut pixmap = Pixmap::new(CARD_WIDTH, CARD_HEIGHT).expect("Invalid share card dimensions");
+    resvg::render(&tree, Transform::default(), &mut pixmap.as_mut());
+    pixmap.encode_png().map_err(|err| {
+        error!("Error encoding share card PNG: {:?}", err);
+        Error::Internal("Error rendering share card".into())
+    })
+}
+
+#[test]
+fn share_card_rendering() {
+    let card = ShareCard {
+        display_name: "<b>Ben & Jerry</b>".into(),
+        timeframe: "Last 4 Weeks",
+        artists: vec!["Boards of Canada".into(), "Aphex Twin".into()],
+        tracks: vec![("Roygbiv".into(), "Boards of Canada".into())],
+    };
+    let svg = build_svg(&card);
+    assert!(svg.contains("&lt;b&gt;Ben &amp; Jerry&lt;/b&gt;"));
+    assert!(!svg.contains("<b>"));
+    assert_eq!(truncate(&"a".repeat(40)).chars().count(), MAX_NAME_CHARS);
+
+    let png = render_share_card(&card).unwrap();
+    assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+}
@@ -1,11 +1,21 @@
+use fnv::FnvHashMap as HashMap;
 use rand::prelude::*;
 
 use crate::{
-    db_util::stringify_diesel_err,
+    error::Error,
     models::{Track, User},
     DbConn,
 };
 
+/// Top entities are used in the order they're ranked, so this puts the fetched entities back in the
+/// order of the IDs they were requested for
+fn in_ranking_order<T: Clone>(spotify_ids: &[&str], entities_by_id: HashMap<String, T>) -> Vec<T> {
+    spotify_ids
+        .iter()
+        .map(|spotify_id| entities_by_id[*spotify_id].clone())
+        .collect()
+}
+
 pub(crate) async fn generate_shared_playlist_track_spotify_ids(
     conn1: DbConn,
     conn2: DbConn,
@@ -14,7 +24,7 @@ pub(crate) async fn generate_shared_playlist_track_spotify_ids(
     user1: &User,
     user2: &User,
     spotify_access_token: &str,
-) -> Result<Vec<String>, String> {
+) -> Result<Vec<String>, Error> {
     let (user1_id, user2_id) = (user1.id, user2.id);
 
     let (user1_tracks, user2_tracks, user1_artists, user2_artists) = tokio::join!(
@@ -29,8 +39,9 @@ pub(crate) async fn generate_shared_playlist_track_spotify_ids(
 
                     crate::spotify_api::fetch_tracks(&spotify_access_token, &track_spotify_ids)
                         .await
+                        .map(|entities_by_id| in_ranking_order(&track_spotify_ids, entities_by_id))
                 },
-                Err(err) => Err(stringify_diesel_err(err)),
+                Err(err) => Err(err.into()),
             }
         },
         async move {
@@ -44,8 +55,9 @@ pub(crate) async fn generate_shared_playlist_track_spotify_ids(
 
                     crate::spotify_api::fetch_tracks(&spotify_access_token, &track_spotify_ids)
                         .await
+                        .map(|entities_by_id| in_ranking_order(&track_spotify_ids, entities_by_id))
                 },
-                Err(err) => Err(stringify_diesel_err(err)),
+                Err(err) => Err(err.into()),
             }
         },
         async move {
@@ -59,8 +71,9 @@ pub(crate) async fn generate_shared_playlist_track_spotify_ids(
 
                     crate::spotify_api::fetch_artists(spotify_access_token, &artist_spotify_ids)
                         .await
+                        .map(|entities_by_id| in_ranking_order(&artist_spotify_ids, entities_by_id))
                 },
-                Err(err) => Err(stringify_diesel_err(err)),
+                Err(err) => Err(err.into()),
             }
         },
         async move {
@@ -74,8 +87,9 @@ pub(crate) async fn generate_shared_playlist_track_spotify_ids(
 
                     crate::spotify_api::fetch_artists(spotify_access_token, &artist_spotify_ids)
                         .await
+                        .map(|entities_by_id| in_ranking_order(&artist_spotify_ids, entities_by_id))
                 },
-                Err(err) => Err(stringify_diesel_err(err)),
+                Err(err) => Err(err.into()),
             }
         },
     );
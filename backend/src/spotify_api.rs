@@ -3,41 +3,78 @@ use std::{
     time::{Duration, Instant},
 };
 
-use chrono::Utc;
+use chrono::{NaiveDateTime, Utc};
 use diesel::prelude::*;
-use fnv::FnvHashMap as HashMap;
+use fnv::{FnvHashMap as HashMap, FnvHashSet as HashSet};
+use futures::{stream, StreamExt, TryStreamExt};
 use reqwest::{self, StatusCode};
 use rocket::http::RawStr;
-use serde::{Deserialize, Serialize};
+use serde::{de::IgnoredAny, Deserialize, Serialize};
 use tokio::{
     sync::{mpsc::channel, Mutex, RwLock},
     task::block_in_place,
 };
+use tracing::Instrument;
 
+pub(crate) mod client;
+#[cfg(test)]
+pub(crate) mod mock;
+
+use self::client::spotify_client;
 use crate::{
+    alerts::AlertKind,
+    cache::metadata_store::MetadataTable,
     conf::CONF,
+    db_backend::{insert_or_ignore, upsert},
     db_util::get_internal_ids_by_spotify_id,
+    error::Error,
+    export::ExportEntity,
     metrics::{
         spotify_api_requests_failure_total, spotify_api_requests_rate_limited_total,
         spotify_api_requests_success_total, spotify_api_requests_total, spotify_api_response_time,
     },
     models::{
         AccessTokenResponse, Artist, ArtistGenrePair, ArtistSearchResult, CreatePlaylistRequest,
-        GetRelatedArtistsResponse, NewArtistHistoryEntry, NewTrackHistoryEntry, Playlist,
-        SpotifyBatchArtistsResponse, SpotifyBatchTracksResponse, SpotifyResponse, StatsSnapshot,
-        TopArtistsResponse, TopTracksResponse, Track, TrackArtistPair, UpdatePlaylistResponse,
-        User, UserProfile,
+        DiversityScoreEntry, FollowedArtistsResponse, GenreWeightEntry, GetRelatedArtistsResponse,
+        HasSpotifyId, LatestStatsEntry, NewArtistHistoryEntry, NewFollowedArtistEntry,
+        NewLibrarySnapshotEntry, NewRecentlyPlayedEntry, NewSavedTrackEntry, NewTrackHistoryEntry,
+        PlayHistoryItem, PlaybackState, Playlist, PopularityEntry, RecentlyPlayedResponse,
+        RecommendationsResponse, SavedTracksResponse, SpotifyBatchArtistsResponse,
+        SpotifyBatchAudioFeaturesResponse, SpotifyBatchTracksResponse, SpotifyResponse,
+        StatsSnapshot, Timeframe, TopArtistsResponse, TopTracksResponse, Track, TrackArtistPair,
+        TrackAudioFeatures, TrackIsrcMapping, UpdatePlaylistResponse, User, UserProfile,
     },
     DbConn,
 };
 
-const _SPOTIFY_USER_RECENTLY_PLAYED_URL: &str =
+const SPOTIFY_USER_RECENTLY_PLAYED_URL: &str =
     "https://api.spotify.com/v1/me/player/recently-played";
+const SPOTIFY_USER_PLAYBACK_STATE_URL: &str = "https://api.spotify.com/v1/me/player";
+const SPOTIFY_USER_FOLLOWED_ARTISTS_URL: &str =
+    "https://api.spotify.com/v1/me/following?type=artist";
 const SPOTIFY_USER_PROFILE_INFO_URL: &str = "https://api.spotify.com/v1/me";
+const SPOTIFY_USER_SAVED_TRACKS_URL: &str = "https://api.spotify.com/v1/me/tracks";
 const SPOTIFY_BATCH_TRACKS_URL: &str = "https://api.spotify.com/v1/tracks";
 const SPOTIFY_BATCH_ARTISTS_URL: &str = "https://api.spotify.com/v1/artists";
+const SPOTIFY_BATCH_AUDIO_FEATURES_URL: &str = "https://api.spotify.com/v1/audio-features";
 const SPOTIFY_APP_TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
-const ENTITY_FETCH_COUNT: usize = 50;
+const SPOTIFY_RECOMMENDATIONS_URL: &str = "https://api.spotify.com/v1/recommendations";
+/// Max number of seed artists, tracks, and genres combined that can be provided when fetching
+/// recommendations
+pub(crate) const MAX_RECOMMENDATION_SEEDS: usize = 5;
+/// Max number of recommended tracks that can be fetched at once
+pub(crate) const MAX_RECOMMENDATIONS_LIMIT: usize = 100;
+/// Max number of items Spotify returns per page of top entities, recently played tracks, or
+/// followed artists
+pub(crate) const MAX_ENTITY_FETCH_COUNT: usize = 50;
+/// Max number of top artists or tracks that Spotify exposes per timeframe across all pages
+pub(crate) const MAX_TOP_ENTITY_COUNT: usize = 99;
+const RATE_LIMIT_BASE_BACKOFF_SECS: u64 = 5;
+const RATE_LIMIT_MAX_BACKOFF_SECS: u64 = 120;
+/// Upper bound on the number of pages followed when fetching a user's recently played tracks
+const MAX_RECENTLY_PLAYED_PAGES: usize = 10;
+/// Upper bound on the number of pages followed when fetching a user's followed artists
+const MAX_FOLLOWED_ARTISTS_PAGES: usize = 100;
 const REQWEST_CLIENT_LIFETIME_SECS: u64 = 60 * 5;
 
 lazy_static::lazy_static! {
@@ -60,80 +97,207 @@ pub(crate) async fn get_reqwest_client() -> reqwest::Client {
     client_cache.1.clone()
 }
 
-fn get_top_entities_url(entity_type: &str, timeframe: &str) -> String {
+fn get_top_entities_url(
+    entity_type: &str,
+    timeframe: Timeframe,
+    limit: usize,
+    offset: usize,
+) -> String {
     format!(
-        "https://api.spotify.com/v1/me/top/{}?limit={}&time_range={}_term",
-        entity_type, ENTITY_FETCH_COUNT, timeframe
+        "https://api.spotify.com/v1/me/top/{}?limit={}&offset={}&time_range={}_term",
+        entity_type,
+        limit,
+        offset,
+        timeframe.name()
     )
 }
 
-async fn process_spotify_res<R: for<'de> Deserialize<'de> + Clone + std::fmt::Debug>(
+/// Sends the request produced by `build_req`, retrying if Spotify rate limits us.  The delay
+/// between attempts is taken from the `Retry-After` header of the 429 response if present, falling
+/// back to exponential backoff otherwise.  After `CONF.spotify_api_max_attempts` attempts, the last
+/// rate limited response is returned as-is.
+#[tracing::instrument(skip_all, fields(endpoint = endpoint_name))]
+async fn send_spotify_request(
     url: &str,
-    res: Result<reqwest::Response, reqwest::Error>,
-) -> Result<R, String> {
-    let res = res.map_err(|err| -> String {
-        error!("Error communicating with Spotify API: {:?}", err);
-        "Error communicating with from the Spotify API".into()
-    })?;
+    endpoint_name: &'static str,
+    build_req: impl Fn() -> reqwest::RequestBuilder,
+) -> Result<reqwest::Response, Error> {
+    let mut attempt = 1;
+    loop {
+        spotify_api_requests_total(endpoint_name).inc();
+        let req = build_req().build().map_err(|err| {
+            error!("Error building request to Spotify API: {:?}", err);
+            spotify_api_requests_failure_total(endpoint_name).inc();
+            Error::SpotifyApi("Error building request to the Spotify API".into())
+        })?;
+        let start = Instant::now();
+        let res = spotify_client().execute(req).await;
+        crate::events::publish_admin_event(|| crate::events::AdminEvent::SpotifyApiRequest {
+            endpoint: endpoint_name,
+            status: res.as_ref().ok().map(|res| res.status().as_u16()),
+            duration_ms: start.elapsed().as_millis() as u64,
+        });
+        let res = res.map_err(|err| {
+            error!("Error communicating with Spotify API: {:?}", err);
+            spotify_api_requests_failure_total(endpoint_name).inc();
+            Error::SpotifyApi("Error communicating with from the Spotify API".into())
+        })?;
+
+        if res.status() != StatusCode::TOO_MANY_REQUESTS {
+            return Ok(res);
+        }
+
+        spotify_api_requests_rate_limited_total(endpoint_name).inc();
+        if attempt >= CONF.spotify_api_max_attempts {
+            error!(
+                "Rate limited when hitting url={} and giving up after {} attempts",
+                url, attempt
+            );
+            crate::alerts::send_alert(
+                AlertKind::SpotifyRateLimited,
+                format!(
+                    "Requests to the Spotify API endpoint {} are still being rate limited after \
+                     {} attempts",
+                    endpoint_name, attempt
+                ),
+            );
+            return Ok(res);
+        }
 
+        let retry_after = res
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|val| val.to_str().ok())
+            .and_then(|val| val.trim().parse::<u64>().ok());
+        let delay_secs = retry_after
+            .unwrap_or_else(|| RATE_LIMIT_BASE_BACKOFF_SECS << (attempt - 1).min(6))
+            .min(RATE_LIMIT_MAX_BACKOFF_SECS);
+        warn!(
+            "Rate limited when hitting url={}, waiting {} seconds before retrying (attempt {}/{}, \
+             Retry-After={:?})...",
+            url, delay_secs, attempt, CONF.spotify_api_max_attempts, retry_after
+        );
+        tokio::time::sleep(Duration::from_secs(delay_secs)).await;
+        attempt += 1;
+    }
+}
+
+async fn process_spotify_res<R: for<'de> Deserialize<'de> + Clone + std::fmt::Debug>(
+    url: &str,
+    res: reqwest::Response,
+) -> Result<R, Error> {
     if res.status() == StatusCode::TOO_MANY_REQUESTS {
         warn!("Rate limited when making request to URL={}", url);
-        return Err("Rate Limited".into());
+        return Err(Error::SpotifyRateLimited);
+    }
+
+    if res.status() == StatusCode::UNAUTHORIZED {
+        warn!("Got 401 Unauthorized when making request to URL={}", url);
+        return Err(Error::SpotifyUnauthorized);
+    }
+
+    if res.status() == StatusCode::FORBIDDEN {
+        warn!("Got 403 Forbidden when making request to URL={}", url);
+        return Err(Error::SpotifyForbidden);
     }
 
     if !res.status().is_success() {
+        let status = res.status();
+        let body = res.text().await;
+        // Spotify's token endpoint responds with `invalid_grant` if the user revoked our access
+        if status == StatusCode::BAD_REQUEST
+            && body
+                .as_deref()
+                .map(|body| body.contains("invalid_grant"))
+                .unwrap_or(false)
+        {
+            warn!("Got `invalid_grant` when making request to URL={}", url);
+            return Err(Error::SpotifyUnauthorized);
+        }
+
         error!(
             "Got bad status code of {} from Spotify API: {:?}",
-            res.status(),
-            res.text().await
+            status, body
         );
-        return Err("Got bad response from Spotify API".into());
+        return Err(Error::SpotifyApi(
+            "Got bad response from Spotify API".into(),
+        ));
     }
 
     res.json::<SpotifyResponse<R>>()
         .await
-        .map_err(|err| -> String {
+        .map_err(|err| {
             error!("Error decoding response from Spotify API: {:?}.", err,);
-            "Error decoding response from Spotify API".into()
+            Error::SpotifyApi("Error decoding response from Spotify API".into())
         })?
         .into_result()
 }
 
+/// Shared wrapper for making requests to the Spotify API.  Handles rate limiting, response parsing,
+/// and recording metrics.
+async fn spotify_api_request<R: for<'de> Deserialize<'de> + Clone + std::fmt::Debug>(
+    url: &str,
+    endpoint_name: &'static str,
+    build_req: impl Fn() -> reqwest::RequestBuilder,
+) -> Result<R, Error> {
+    let start = Instant::now();
+    let res = send_spotify_request(url, endpoint_name, build_req).await?;
+    match process_spotify_res(url, res).await {
+        Ok(res) => {
+            spotify_api_requests_success_total(endpoint_name).inc();
+            spotify_api_response_time(endpoint_name).observe(start.elapsed().as_nanos() as u64);
+            Ok(res)
+        },
+        Err(err) => {
+            spotify_api_requests_failure_total(endpoint_name).inc();
+            Err(err)
+        },
+    }
+}
+
 pub(crate) async fn spotify_user_api_request<
     T: for<'de> Deserialize<'de> + std::fmt::Debug + Clone,
 >(
     url: &str,
     token: &str,
     endpoint_name: &'static str,
-) -> Result<T, String> {
-    spotify_api_requests_total(endpoint_name).inc();
+) -> Result<T, Error> {
     let client = get_reqwest_client().await;
+    spotify_api_request(url, endpoint_name, || client.get(url).bearer_auth(token)).await
+}
 
-    let mut start = Instant::now();
-    loop {
-        let res = client.get(url).bearer_auth(token).send().await;
+pub(crate) async fn get_user_profile_info(token: &str) -> Result<UserProfile, Error> {
+    spotify_user_api_request(SPOTIFY_USER_PROFILE_INFO_URL, token, "user_profile_info").await
+}
 
-        match process_spotify_res(&url, res).await {
-            Ok(res) => {
-                spotify_api_requests_success_total(endpoint_name).inc();
-                spotify_api_response_time(endpoint_name).observe(start.elapsed().as_nanos() as u64);
-                return Ok(res);
-            },
-            Err(err) if err.contains("Rate Limited") => {
-                spotify_api_requests_rate_limited_total(endpoint_name).inc();
-                tokio::time::sleep(Duration::from_secs(5)).await;
-                start = Instant::now();
-            },
-            Err(err) => {
-                spotify_api_requests_failure_total(endpoint_name).inc();
-                return Err(err);
-            },
-        }
-    }
+/// Form parameters whose values are credentials and must never be written to the logs
+const SENSITIVE_PARAMS: &[&str] = &["refresh_token", "access_token", "code", "client_secret"];
+
+/// Builds the log line for a POST to the Spotify API with the values of credential parameters
+/// redacted, since the logs are shipped off-host.
+fn describe_server_api_request(url: &str, params: &HashMap<&str, &str>) -> String {
+    let mut params: Vec<String> = params
+        .iter()
+        .map(|(&key, &val)| {
+            let val = if SENSITIVE_PARAMS.contains(&key) {
+                "<redacted>"
+            } else {
+                val
+            };
+            format!("{}={}", key, val)
+        })
+        .collect();
+    params.sort_unstable();
+    format!(
+        "Hitting Spotify API POST at URL {}, params: [{}]",
+        url,
+        params.join(", ")
+    )
 }
 
-pub(crate) async fn get_user_profile_info(token: &str) -> Result<UserProfile, String> {
-    spotify_user_api_request(SPOTIFY_USER_PROFILE_INFO_URL, token, "user_profile_info").await
+/// Builds the log line for a JSON request made on behalf of a user.  The bearer token is left out.
+fn describe_user_json_api_request<T: std::fmt::Debug>(url: &str, body: &T) -> String {
+    format!("Hitting Spotify API at URL {}, params: {:?}", url, body)
 }
 
 pub(crate) async fn spotify_server_api_request<
@@ -142,39 +306,17 @@ pub(crate) async fn spotify_server_api_request<
     url: &str,
     params: HashMap<&str, &str>,
     endpoint_name: &'static str,
-) -> Result<T, String> {
+) -> Result<T, Error> {
     let client = get_reqwest_client().await;
 
-    let mut start = Instant::now();
-    loop {
-        info!(
-            "Hitting Spotify API POST at URL {}, params: {:?}",
-            url, params
-        );
-        let res = client
+    info!("{}", describe_server_api_request(url, &params));
+    spotify_api_request(url, endpoint_name, || {
+        client
             .post(url)
             .header("Authorization", CONF.get_authorization_header_content())
             .form(&params)
-            .send()
-            .await;
-
-        match process_spotify_res(&url, res).await {
-            Ok(res) => {
-                spotify_api_requests_success_total(endpoint_name).inc();
-                spotify_api_response_time(endpoint_name).observe(start.elapsed().as_nanos() as u64);
-                return Ok(res);
-            },
-            Err(err) if err.contains("Rate Limited") => {
-                spotify_api_requests_rate_limited_total(endpoint_name).inc();
-                tokio::time::sleep(Duration::from_secs(5)).await;
-                start = Instant::now();
-            },
-            Err(err) => {
-                spotify_api_requests_failure_total(endpoint_name).inc();
-                return Err(err);
-            },
-        }
-    }
+    })
+    .await
 }
 
 pub(crate) async fn spotify_server_get_request<
@@ -183,39 +325,16 @@ pub(crate) async fn spotify_server_get_request<
     bearer_token: &str,
     url: &str,
     endpoint_name: &'static str,
-) -> Result<T, String> {
+) -> Result<T, Error> {
     let client = get_reqwest_client().await;
 
-    let mut start = Instant::now();
-    loop {
-        info!("Hitting Spotify API GET at URL {}", url,);
-        let res = client
+    info!("Hitting Spotify API GET at URL {}", url,);
+    spotify_api_request(url, endpoint_name, || {
+        client
             .get(url)
             .header("Authorization", format!("Bearer {}", bearer_token))
-            .send()
-            .await;
-
-        match process_spotify_res(&url, res).await {
-            Ok(res) => {
-                spotify_api_requests_success_total(endpoint_name).inc();
-                spotify_api_response_time(endpoint_name).observe(start.elapsed().as_nanos() as u64);
-                return Ok(res);
-            },
-            Err(err) if err.contains("Rate Limited") => {
-                warn!(
-                    "Rate limited when hitting url={}, waiting 5 seconds before retrying...",
-                    url
-                );
-                spotify_api_requests_rate_limited_total(endpoint_name).inc();
-                tokio::time::sleep(Duration::from_secs(5)).await;
-                start = Instant::now();
-            },
-            Err(err) => {
-                spotify_api_requests_failure_total(endpoint_name).inc();
-                return Err(err);
-            },
-        }
-    }
+    })
+    .await
 }
 
 async fn spotify_user_json_api_get_request<
@@ -224,35 +343,14 @@ async fn spotify_user_json_api_get_request<
     bearer_token: &str,
     url: String,
     endpoint_name: &'static str,
-) -> Result<R, String> {
+) -> Result<R, Error> {
     let client = get_reqwest_client().await;
 
-    let mut start = Instant::now();
-    loop {
-        info!("Hitting Spotify API at URL {}", url);
-
-        let res = client.get(&url).bearer_auth(bearer_token).send().await;
-        match process_spotify_res(&url, res).await {
-            Ok(res) => {
-                spotify_api_requests_success_total(endpoint_name).inc();
-                spotify_api_response_time(endpoint_name).observe(start.elapsed().as_nanos() as u64);
-                return Ok(res);
-            },
-            Err(err) if err.contains("Rate Limited") => {
-                warn!(
-                    "Rate limited when hitting url={}, waiting 5 seconds before retrying...",
-                    url
-                );
-                spotify_api_requests_rate_limited_total(endpoint_name).inc();
-                tokio::time::sleep(Duration::from_secs(5)).await;
-                start = Instant::now();
-            },
-            Err(err) => {
-                spotify_api_requests_failure_total(endpoint_name).inc();
-                return Err(err);
-            },
-        }
-    }
+    info!("Hitting Spotify API at URL {}", url);
+    spotify_api_request(&url, endpoint_name, || {
+        client.get(&url).bearer_auth(bearer_token)
+    })
+    .await
 }
 
 pub(crate) async fn spotify_user_json_api_request<
@@ -263,50 +361,27 @@ pub(crate) async fn spotify_user_json_api_request<
     url: &str,
     body: &T,
     endpoint_name: &'static str,
-) -> Result<R, String> {
+) -> Result<R, Error> {
     let client = get_reqwest_client().await;
 
-    info!(
-        "Hitting Spotify API at URL {}, params: {:?}, bearer_token={}",
-        url, body, bearer_token
-    );
-    let start = Instant::now();
-    let res = client
-        .post(url)
-        .header("Authorization", format!("Bearer {}", bearer_token))
-        .json(body)
-        .send()
-        .await;
-    match &res {
-        Ok(res) if res.status().is_success() => {
-            spotify_api_requests_success_total(endpoint_name).inc();
-            spotify_api_response_time(endpoint_name).observe(start.elapsed().as_nanos() as u64);
-        },
-        Ok(res) =>
-            if res.status() == StatusCode::TOO_MANY_REQUESTS {
-                error!("Rate limited when hitting URL={url}");
-                spotify_api_requests_rate_limited_total(endpoint_name).inc();
-            } else {
-                spotify_api_requests_failure_total(endpoint_name).inc();
-            },
-        Err(err) => {
-            spotify_api_requests_failure_total(endpoint_name).inc();
-            error!("Error communicating with Spotify API: {:?}", err);
-            return Err("Error communicating with from the Spotify API".into());
-        },
-    }
-
-    process_spotify_res(url, res).await
+    info!("{}", describe_user_json_api_request(url, body));
+    spotify_api_request(url, endpoint_name, || {
+        client
+            .post(url)
+            .header("Authorization", format!("Bearer {}", bearer_token))
+            .json(body)
+    })
+    .await
 }
 
-pub(crate) async fn fetch_auth_token() -> Result<AccessTokenResponse, String> {
+pub(crate) async fn fetch_auth_token() -> Result<AccessTokenResponse, Error> {
     let mut params = HashMap::default();
     params.insert("grant_type", "client_credentials");
 
     spotify_server_api_request(SPOTIFY_APP_TOKEN_URL, params, "fetch_auth_token").await
 }
 
-pub(crate) async fn refresh_user_token(refresh_token: &str) -> Result<String, String> {
+pub(crate) async fn refresh_user_token(refresh_token: &str) -> Result<String, Error> {
     let mut params = HashMap::default();
     params.insert("grant_type", "refresh_token");
     params.insert("refresh_token", refresh_token);
@@ -316,64 +391,99 @@ pub(crate) async fn refresh_user_token(refresh_token: &str) -> Result<String, St
     Ok(res.access_token)
 }
 
-pub(crate) async fn fetch_cur_stats(user: &User) -> Result<Option<StatsSnapshot>, String> {
+/// Fetches the user's current top `entity_fetch_count` tracks and artists for each timeframe.
+/// Spotify returns at most `MAX_ENTITY_FETCH_COUNT` items per request, so deeper rankings are
+/// fetched as multiple pages which are requested concurrently.
+pub(crate) async fn fetch_cur_stats(
+    user: &User,
+    entity_fetch_count: usize,
+) -> Result<Option<StatsSnapshot>, Error> {
+    let page_offsets: Vec<usize> = (0..entity_fetch_count)
+        .step_by(MAX_ENTITY_FETCH_COUNT)
+        .collect();
+    let request_count = 6 * page_offsets.len();
+
     // Use the user's token to fetch their current stats
     let (tx, mut rx) = channel::<(
         &'static str,
-        &'static str,
-        Result<reqwest::Response, String>,
-    )>(6);
-
-    // Create tasks for each of the inner requests (we have to make 6; one for each of the three
-    // timeframes, and then that multiplied by each of the two entities (tracks and artists)).
-    info!("Kicking off 6 API requests on separate tokio tasks...");
+        Timeframe,
+        usize,
+        Result<reqwest::Response, Error>,
+    )>(request_count);
+
+    // Create tasks for each of the inner requests (we have to make at least 6; one for each of the
+    // three timeframes, and then that multiplied by each of the two entities (tracks and artists),
+    // and then that multiplied by the number of pages).
+    info!("Kicking off {request_count} API requests on separate tokio tasks...");
     for entity_type in ["tracks", "artists"] {
-        for timeframe in ["short", "medium", "long"] {
-            let token = user.token.clone();
-            let tx = tx.clone();
-
-            tokio::task::spawn(async move {
-                let start = Instant::now();
-                let endpoint_name = match entity_type {
-                    "tracks" => "top_tracks",
-                    "artists" => "top_artists",
-                    _ => unreachable!(),
-                };
-                spotify_api_requests_total(endpoint_name).inc();
-
-                let client = get_reqwest_client().await;
-                let res: Result<reqwest::Response, String> = client
-                    .get(&get_top_entities_url(entity_type, timeframe))
-                    .bearer_auth(token)
-                    .send()
-                    .await
-                    .map_err(|_err| -> String {
-                        "Error requesting latest user stats from the Spotify API".into()
-                    });
-                match &res {
-                    Ok(_) => {
-                        spotify_api_requests_success_total(endpoint_name).inc();
-                        spotify_api_response_time(endpoint_name)
-                            .observe(start.elapsed().as_nanos() as u64);
-                    },
-                    Err(err) => {
-                        spotify_api_requests_failure_total(endpoint_name).inc();
-                        error!("Error fetching top {entity_type} for timeframe {timeframe}: {err}");
-                    },
-                }
-
-                let _ = tx.send((entity_type, timeframe, res)).await;
-            });
+        for timeframe in Timeframe::ALL {
+            for &offset in &page_offsets {
+                let limit = MAX_ENTITY_FETCH_COUNT.min(entity_fetch_count - offset);
+                let token = user.token.clone();
+                let tx = tx.clone();
+
+                tokio::task::spawn(
+                    async move {
+                        let start = Instant::now();
+                        let endpoint_name = match entity_type {
+                            "tracks" => "top_tracks",
+                            "artists" => "top_artists",
+                            _ => unreachable!(),
+                        };
+
+                        let client = get_reqwest_client().await;
+                        let url = get_top_entities_url(entity_type, timeframe, limit, offset);
+                        let res: Result<reqwest::Response, Error> =
+                            send_spotify_request(&url, endpoint_name, || {
+                                client.get(&url).bearer_auth(&token)
+                            })
+                            .await
+                            .map_err(|_err| {
+                                Error::SpotifyApi(
+                                    "Error requesting latest user stats from the Spotify API"
+                                        .into(),
+                                )
+                            });
+                        match &res {
+                            Ok(_) => {
+                                spotify_api_requests_success_total(endpoint_name).inc();
+                                spotify_api_response_time(endpoint_name)
+                                    .observe(start.elapsed().as_nanos() as u64);
+                            },
+                            Err(err) => {
+                                spotify_api_requests_failure_total(endpoint_name).inc();
+                                error!(
+                                    "Error fetching top {entity_type} for timeframe {timeframe}: \
+                                     {err}"
+                                );
+                            },
+                        }
+
+                        let _ = tx.send((entity_type, timeframe, offset, res)).await;
+                    }
+                    .in_current_span(),
+                );
+            }
         }
     }
 
-    let mut stats_snapshot = StatsSnapshot::new(Utc::now().naive_utc());
+    // Pages can arrive in any order, so they're collected by offset and assembled once they've all
+    // been received
+    let mut track_pages: HashMap<Timeframe, Vec<(usize, Vec<Track>)>> = HashMap::default();
+    let mut artist_pages: HashMap<Timeframe, Vec<(usize, Vec<Artist>)>> = HashMap::default();
 
-    // Wait for all 6 requests to return back and then
-    info!("Waiting for all 6 inner stats requests to return...");
-    for _ in 0..6 {
+    // Wait for all requests to return back and then
+    info!("Waiting for all {request_count} inner stats requests to return...");
+    for _ in 0..request_count {
         match rx.recv().await.unwrap() {
-            ("tracks", timeframe, res) => {
+            (_, _, _, Ok(res)) if res.status() == StatusCode::UNAUTHORIZED => {
+                warn!(
+                    "Got 401 Unauthorized when fetching stats for user {}; token is likely expired",
+                    user.spotify_id
+                );
+                return Err(Error::SpotifyUnauthorized);
+            },
+            ("tracks", timeframe, offset, res) => {
                 let res = res?;
                 if res.status() != StatusCode::OK {
                     error!(
@@ -387,31 +497,31 @@ pub(crate) async fn fetch_cur_stats(user: &User) -> Result<Option<StatsSnapshot>
                 }
 
                 let parsed_res: TopTracksResponse = if cfg!(debug_assertions) {
-                    let res_text = res.text().await.map_err(|err| -> String {
+                    let res_text = res.text().await.map_err(|err| {
                         error!("Error reading top tracks response: {:?}", err);
-                        "Error reading response from Spotify".into()
+                        Error::SpotifyApi("Error reading response from Spotify".into())
                     })?;
-                    serde_json::from_str(&res_text).map_err(|err| -> String {
+                    serde_json::from_str(&res_text).map_err(|err| {
                         error!("Error parsing top tracks response; got: {}", res_text);
-                        format!("Error parsing response from Spotify: {:?}", err)
+                        Error::SpotifyApi(format!("Error parsing response from Spotify: {:?}", err))
                     })?
                 } else {
-                    res.json().await.map_err(|err| -> String {
+                    res.json().await.map_err(|err| {
                         error!("Error parsing top tracks response: {:?}", err);
-                        "Error parsing response from Spotify".into()
+                        Error::SpotifyApi("Error parsing response from Spotify".into())
                     })?
                 };
 
-                for top_track in parsed_res.items.into_iter().filter_map(|x| x) {
-                    stats_snapshot.tracks.add_item(timeframe, top_track);
-                }
+                track_pages.entry(timeframe).or_default().push((
+                    offset,
+                    parsed_res.items.into_iter().filter_map(|x| x).collect(),
+                ));
             },
-            ("artists", timeframe, res) => {
-                let parsed_res: TopArtistsResponse =
-                    res?.json().await.map_err(|err| -> String {
-                        error!("Error parsing top artists response: {:?}", err);
-                        "Error parsing response from Spotify".into()
-                    })?;
+            ("artists", timeframe, offset, res) => {
+                let parsed_res: TopArtistsResponse = res?.json().await.map_err(|err| {
+                    error!("Error parsing top artists response: {:?}", err);
+                    Error::SpotifyApi("Error parsing response from Spotify".into())
+                })?;
 
                 if parsed_res
                     .items
@@ -427,37 +537,77 @@ pub(crate) async fn fetch_cur_stats(user: &User) -> Result<Option<StatsSnapshot>
                     );
                 }
 
-                for top_artist in parsed_res.items.into_iter() {
-                    stats_snapshot.artists.add_item(timeframe, top_artist);
-                }
+                artist_pages
+                    .entry(timeframe)
+                    .or_default()
+                    .push((offset, parsed_res.items));
             },
             _ => unreachable!(),
         }
     }
 
+    let mut stats_snapshot = StatsSnapshot::new(Utc::now().fixed_offset());
+    for (timeframe, mut pages) in track_pages {
+        pages.sort_unstable_by_key(|(offset, _)| *offset);
+        for top_track in pages.into_iter().flat_map(|(_, tracks)| tracks) {
+            stats_snapshot.tracks.add_item(timeframe, top_track);
+        }
+    }
+    for (timeframe, mut pages) in artist_pages {
+        pages.sort_unstable_by_key(|(offset, _)| *offset);
+        for top_artist in pages.into_iter().flat_map(|(_, artists)| artists) {
+            stats_snapshot.artists.add_item(timeframe, top_artist);
+        }
+    }
+
     Ok(Some(stats_snapshot))
 }
 
-fn map_timeframe_to_timeframe_id(timeframe: &str) -> u8 {
-    match timeframe {
-        "short" => 0,
-        "medium" => 1,
-        "long" => 2,
-        _ => panic!(
-            "Tried to convert invalid timeframe to id: \"{}\"",
-            timeframe
-        ),
+/// Wrapper around `fetch_cur_stats` that handles the user's access token having expired.  If
+/// Spotify responds with a 401, the token is refreshed using the user's refresh token, the new
+/// token is persisted to the database, and the fetch is retried once.  The number of entities
+/// fetched is taken from the user's settings.
+pub(crate) async fn fetch_cur_stats_with_token_refresh(
+    conn: &DbConn,
+    user: &mut User,
+) -> Result<Option<StatsSnapshot>, Error> {
+    let entity_fetch_count = crate::db_util::get_user_settings(conn, user)
+        .await?
+        .entity_fetch_count();
+    match fetch_cur_stats(user, entity_fetch_count).await {
+        Err(Error::SpotifyUnauthorized) => {
+            info!(
+                "Refreshing access token for user {} after 401 and retrying stats fetch",
+                user.spotify_id
+            );
+            crate::db_util::refresh_user_access_token(conn, user).await?;
+
+            fetch_cur_stats(user, entity_fetch_count).await
+        },
+        res => res,
     }
 }
 
-/// For each track and artist timeframe, store a row in the `track_rank_snapshots` and
-/// `artist_rank_snapshots` tables respectively
+/// Stores the snapshot's rankings in the `artist_rank_deltas` and `track_rank_deltas` tables and
+/// records how many entries each timeframe has in `snapshot_updates`.  With delta encoding enabled,
+/// only the rankings that changed since the user's previous snapshot are stored.  An alert is sent
+/// if storing snapshots keeps failing.
 pub(crate) async fn store_stats_snapshot(
     conn: &DbConn,
     user: &User,
     stats: StatsSnapshot,
-) -> Result<(), String> {
-    let update_time = stats.last_update_time;
+) -> Result<(), Error> {
+    let res = store_stats_snapshot_inner(conn, user, stats).await;
+    crate::alerts::record_snapshot_store_result(res.is_ok());
+    res
+}
+
+async fn store_stats_snapshot_inner(
+    conn: &DbConn,
+    user: &User,
+    stats: StatsSnapshot,
+) -> Result<(), Error> {
+    let update_time = stats.last_update_time.naive_utc();
 
     let genres_by_artist_id: HashMap<String, Vec<String>> = stats
         .artists
@@ -476,8 +626,103 @@ pub(crate) async fn store_stats_snapshot(
         });
     let mapped_artist_spotify_ids =
         crate::db_util::get_internal_ids_by_spotify_id(conn, genres_by_artist_id.keys()).await?;
+    let track_spotify_ids: Vec<String> = stats
+        .tracks
+        .iter()
+        .flat_map(|(_artist_timeframe, tracks)| tracks.iter().map(|track| track.id.clone()))
+        .collect::<Vec<_>>();
+    let mapped_track_spotify_ids =
+        crate::db_util::get_internal_ids_by_spotify_id(conn, track_spotify_ids.iter()).await?;
 
-    let artist_entries: Vec<NewArtistHistoryEntry> = stats
+    let diversity_scores: Vec<DiversityScoreEntry> = stats
+        .artists
+        .iter()
+        .zip(stats.tracks.iter())
+        .filter(|((_timeframe, artists), (_, tracks))| !artists.is_empty() || !tracks.is_empty())
+        .map(|((timeframe, artists), (_, tracks))| {
+            let primary_artist_ids: Vec<&str> = tracks
+                .iter()
+                .filter_map(|track| track.artists.first())
+                .map(|artist| artist.id.as_str())
+                .collect();
+            let genre_slugs: Vec<String> = artists
+                .iter()
+                .flat_map(|artist| {
+                    crate::genres::normalize_genres(
+                        artist.genres.iter().flatten().map(String::as_str),
+                    )
+                })
+                .map(|genre| genre.slug)
+                .collect();
+
+            DiversityScoreEntry {
+                user_id: user.id,
+                update_time,
+                timeframe,
+                genre_entropy: crate::stats::compute_genre_entropy(
+                    genre_slugs.iter().map(String::as_str),
+                ),
+                artist_gini: crate::stats::compute_artist_gini(&primary_artist_ids),
+            }
+        })
+        .collect();
+    let genre_weights: Vec<GenreWeightEntry> = stats
+        .artists
+        .iter()
+        .flat_map(|(timeframe, artists)| {
+            crate::stats::compute_genre_weights(artists, crate::stats::MAX_SNAPSHOT_GENRES)
+                .into_iter()
+                .map(move |(genre, weight)| GenreWeightEntry {
+                    user_id: user.id,
+                    update_time,
+                    timeframe,
+                    genre,
+                    weight: weight as u32,
+                })
+        })
+        .collect();
+
+    // Spotify's popularity scores change over time, so they're recorded as of this snapshot
+    let (mapped_artist_ids, mapped_track_ids) =
+        (&mapped_artist_spotify_ids, &mapped_track_spotify_ids);
+    let artist_popularities = stats.artists.iter().flat_map(|(timeframe, artists)| {
+        artists
+            .iter()
+            .enumerate()
+            .filter_map(move |(ranking, artist)| {
+                let popularity = artist.popularity?;
+                Some(PopularityEntry {
+                    user_id: user.id,
+                    update_time,
+                    timeframe,
+                    entity_type: crate::db_util::entity_type_code(ExportEntity::Artists),
+                    ranking: ranking as u8,
+                    mapped_spotify_id: mapped_artist_ids[&artist.id],
+                    popularity: popularity.min(100) as u8,
+                })
+            })
+    });
+    let track_popularities = stats.tracks.iter().flat_map(|(timeframe, tracks)| {
+        tracks
+            .iter()
+            .enumerate()
+            .filter_map(move |(ranking, track)| {
+                let popularity = track.popularity?;
+                Some(PopularityEntry {
+                    user_id: user.id,
+                    update_time,
+                    timeframe,
+                    entity_type: crate::db_util::entity_type_code(ExportEntity::Tracks),
+                    ranking: ranking as u8,
+                    mapped_spotify_id: mapped_track_ids[&track.id],
+                    popularity: popularity.min(100) as u8,
+                })
+            })
+    });
+    let popularity_entries: Vec<PopularityEntry> =
+        artist_popularities.chain(track_popularities).collect();
+
+    let mut artist_entries: Vec<NewArtistHistoryEntry> = stats
         .artists
         .into_iter()
         .flat_map(|(artist_timeframe, artists)| {
@@ -490,32 +735,13 @@ pub(crate) async fn store_stats_snapshot(
                         user_id: user.id,
                         mapped_spotify_id: mapped_artist_spotify_ids[&artist_spotify_id],
                         update_time,
-                        timeframe: map_timeframe_to_timeframe_id(&artist_timeframe),
+                        timeframe: artist_timeframe,
                         ranking: artist_ranking as u8,
                     }
                 })
         })
         .collect();
 
-    conn.run(move |conn| {
-        diesel::insert_into(crate::schema::artist_rank_snapshots::table)
-            .values(&artist_entries)
-            .execute(conn)
-    })
-    .await
-    .map_err(|err| -> String {
-        println!("Error inserting row: {:?}", err);
-        "Error inserting user into database".into()
-    })?;
-
-    let track_spotify_ids: Vec<String> = stats
-        .tracks
-        .iter()
-        .flat_map(|(_artist_timeframe, tracks)| tracks.iter().map(|track| track.id.clone()))
-        .collect::<Vec<_>>();
-    let mapped_track_spotify_ids =
-        crate::db_util::get_internal_ids_by_spotify_id(conn, track_spotify_ids.iter()).await?;
-
     // Create track/artist mapping entries for each (track, artist) pair
     let track_artist_pairs: Vec<TrackArtistPair> = stats
         .tracks
@@ -535,96 +761,525 @@ pub(crate) async fn store_stats_snapshot(
             })
         })
         .collect();
-    conn.run(move |conn| {
-        diesel::insert_or_ignore_into(crate::schema::tracks_artists::table)
-            .values(&track_artist_pairs)
-            .execute(conn)
-    })
-    .await
-    .map_err(|err| -> String {
-        error!("Error inserting track/artist mappings: {:?}", err);
-        "Error inserting track/artist metadata into database".into()
-    })?;
 
-    // Create artist/genre mapping entries for each (artist, genre) pair
-    let artist_genre_pairs: Vec<ArtistGenrePair> = genres_by_artist_id
-        .into_iter()
-        .flat_map(|(artist_id, genres)| {
-            let artist_id: i32 = *mapped_artist_spotify_ids
-                .get(&artist_id)
-                .expect("No entry in artist id mapping");
+    // Record the ISRC of each track so that duplicate releases of the same recording can be merged
+    let track_isrc_mappings: Vec<TrackIsrcMapping> = stats
+        .tracks
+        .iter()
+        .flat_map(|(_track_timeframe, tracks)| tracks.iter())
+        .filter_map(|track| {
+            let isrc = track.external_ids.as_ref()?.isrc.clone()?;
+            Some(TrackIsrcMapping {
+                spotify_id: track.id.clone(),
+                isrc,
+            })
+        })
+        .collect();
+
+    // Create artist/genre mapping entries for each (artist, genre) pair
+    let artist_genre_pairs: Vec<ArtistGenrePair> = genres_by_artist_id
+        .into_iter()
+        .flat_map(|(artist_id, genres)| {
+            let artist_id: i32 = *mapped_artist_spotify_ids
+                .get(&artist_id)
+                .expect("No entry in artist id mapping");
+
+            genres
+                .into_iter()
+                .map(move |genre| ArtistGenrePair { artist_id, genre })
+        })
+        .collect();
+
+    let mut track_entries: Vec<NewTrackHistoryEntry> = stats
+        .tracks
+        .into_iter()
+        .flat_map(|(track_timeframe, tracks)| {
+            tracks
+                .into_iter()
+                .enumerate()
+                .map(move |(track_ranking, track)| (track_timeframe, track_ranking, track.id))
+                .map(
+                    |(track_timeframe, track_ranking, track_spotify_id)| NewTrackHistoryEntry {
+                        user_id: user.id,
+                        mapped_spotify_id: mapped_track_spotify_ids[&track_spotify_id],
+                        update_time,
+                        timeframe: track_timeframe,
+                        ranking: track_ranking as u8,
+                    },
+                )
+        })
+        .collect();
+
+    let snapshot_updates = crate::db_util::count_snapshot_entries(
+        artist_entries.iter().map(|entry| {
+            (
+                entry.user_id,
+                entry.update_time,
+                entry.timeframe,
+                entry.ranking,
+            )
+        }),
+        track_entries.iter().map(|entry| {
+            (
+                entry.user_id,
+                entry.update_time,
+                entry.timeframe,
+                entry.ranking,
+            )
+        }),
+    );
+    // The latest stats are stored in full regardless of delta encoding
+    let latest_artist_entries: Vec<LatestStatsEntry> = artist_entries
+        .iter()
+        .map(|entry| LatestStatsEntry {
+            user_id: entry.user_id,
+            timeframe: entry.timeframe,
+            ranking: entry.ranking,
+            mapped_spotify_id: entry.mapped_spotify_id,
+            update_time,
+        })
+        .collect();
+    let latest_track_entries: Vec<LatestStatsEntry> = track_entries
+        .iter()
+        .map(|entry| LatestStatsEntry {
+            user_id: entry.user_id,
+            timeframe: entry.timeframe,
+            ranking: entry.ranking,
+            mapped_spotify_id: entry.mapped_spotify_id,
+            update_time,
+        })
+        .collect();
+
+    // Side effects of the new snapshot are delivered by the outbox dispatcher once it's committed
+    let outbox_event = crate::outbox::OutboxEvent::SnapshotStored { update_time }
+        .to_new_row(user.id, Utc::now().naive_utc());
+
+    // Everything is stored in a single transaction so that a failure partway through doesn't leave
+    // a partial snapshot behind.  Snapshot rows are unique by (user, update time, timeframe,
+    // ranking), so rows are replaced rather than duplicated if the same snapshot is stored twice.
+    use crate::schema::users;
+    let user_id = user.id;
+    let updated_row_count = conn
+        .run(move |conn| {
+            conn.transaction(|| -> QueryResult<usize> {
+                // Only rankings that changed since the previous snapshot need to be stored.  The
+                // user's row is locked first so that concurrent updates of the same user don't
+                // both encode their deltas against the same previous snapshot.
+                if CONF.snapshot_delta_encoding {
+                    users::table
+                        .filter(users::id.eq(user_id))
+                        .select(users::id)
+                        .for_update()
+                        .first::<i64>(conn)?;
+                    let (previous_artist_rankings, previous_track_rankings) =
+                        crate::db_util::load_previous_snapshot_rankings(
+                            conn,
+                            user_id,
+                            update_time,
+                        )?;
+                    crate::db_util::retain_changed_rankings(
+                        &mut artist_entries,
+                        &previous_artist_rankings,
+                        |entry| (entry.timeframe, entry.ranking, entry.mapped_spotify_id),
+                    );
+                    crate::db_util::retain_changed_rankings(
+                        &mut track_entries,
+                        &previous_track_rankings,
+                        |entry| (entry.timeframe, entry.ranking, entry.mapped_spotify_id),
+                    );
+                }
+
+                upsert!(
+                    crate::schema::artist_rank_deltas::table,
+                    &artist_entries,
+                    (
+                        crate::schema::artist_rank_deltas::user_id,
+                        crate::schema::artist_rank_deltas::update_time,
+                        crate::schema::artist_rank_deltas::timeframe,
+                        crate::schema::artist_rank_deltas::ranking,
+                    ),
+                    (crate::schema::artist_rank_deltas::mapped_spotify_id)
+                )
+                .execute(conn)?;
+                upsert!(
+                    crate::schema::track_rank_deltas::table,
+                    &track_entries,
+                    (
+                        crate::schema::track_rank_deltas::user_id,
+                        crate::schema::track_rank_deltas::update_time,
+                        crate::schema::track_rank_deltas::timeframe,
+                        crate::schema::track_rank_deltas::ranking,
+                    ),
+                    (crate::schema::track_rank_deltas::mapped_spotify_id)
+                )
+                .execute(conn)?;
+                crate::db_util::merge_snapshot_updates(conn, &snapshot_updates)?;
+                crate::db_util::replace_latest_stats(
+                    conn,
+                    user_id,
+                    update_time,
+                    &latest_artist_entries,
+                    &latest_track_entries,
+                )?;
+                upsert!(
+                    crate::schema::diversity_scores::table,
+                    &diversity_scores,
+                    (
+                        crate::schema::diversity_scores::user_id,
+                        crate::schema::diversity_scores::update_time,
+                        crate::schema::diversity_scores::timeframe,
+                    ),
+                    (
+                        crate::schema::diversity_scores::genre_entropy,
+                        crate::schema::diversity_scores::artist_gini,
+                    )
+                )
+                .execute(conn)?;
+                upsert!(
+                    crate::schema::genre_weights::table,
+                    &genre_weights,
+                    (
+                        crate::schema::genre_weights::user_id,
+                        crate::schema::genre_weights::update_time,
+                        crate::schema::genre_weights::timeframe,
+                        crate::schema::genre_weights::genre,
+                    ),
+                    (crate::schema::genre_weights::weight)
+                )
+                .execute(conn)?;
+                upsert!(
+                    crate::schema::popularity_history::table,
+                    &popularity_entries,
+                    (
+                        crate::schema::popularity_history::user_id,
+                        crate::schema::popularity_history::update_time,
+                        crate::schema::popularity_history::timeframe,
+                        crate::schema::popularity_history::entity_type,
+                        crate::schema::popularity_history::ranking,
+                    ),
+                    (
+                        crate::schema::popularity_history::mapped_spotify_id,
+                        crate::schema::popularity_history::popularity,
+                    )
+                )
+                .execute(conn)?;
+                insert_or_ignore!(crate::schema::tracks_artists::table, &track_artist_pairs)
+                    .execute(conn)?;
+                insert_or_ignore!(crate::schema::artists_genres::table, &artist_genre_pairs)
+                    .execute(conn)?;
+                insert_or_ignore!(crate::schema::track_isrc_map::table, &track_isrc_mappings)
+                    .execute(conn)?;
+                diesel::insert_into(crate::schema::outbox_events::table)
+                    .values(&outbox_event)
+                    .execute(conn)?;
+
+                // Update the user to have a last update time that matches all of the new updates
+                diesel::update(users::table.filter(users::id.eq(user_id)))
+                    .set(users::last_update_time.eq(update_time))
+                    .execute(conn)
+            })
+        })
+        .await
+        .map_err(|err| {
+            error!("Error storing stats snapshot: {:?}", err);
+            Error::Internal("Error storing stats snapshot in database".into())
+        })?;
+
+    if updated_row_count != 1 {
+        error!(
+            "Updated {} rows when setting last update time for user with spotify_id={}, but \
+             should have updated 1.",
+            updated_row_count, user.spotify_id
+        );
+    }
+
+    crate::outbox::notify_dispatcher();
+
+    Ok(())
+}
+
+/// Fetches the user's recently played tracks, following the cursor-based `next` links returned by
+/// Spotify.  If `after` is provided, only plays after that time are requested.
+pub(crate) async fn fetch_recently_played(
+    token: &str,
+    after: Option<NaiveDateTime>,
+) -> Result<Vec<PlayHistoryItem>, Error> {
+    let mut url = match after {
+        Some(after) => format!(
+            "{}?limit={}&after={}",
+            SPOTIFY_USER_RECENTLY_PLAYED_URL,
+            MAX_ENTITY_FETCH_COUNT,
+            after.and_utc().timestamp_millis()
+        ),
+        None => format!(
+            "{}?limit={}",
+            SPOTIFY_USER_RECENTLY_PLAYED_URL, MAX_ENTITY_FETCH_COUNT
+        ),
+    };
+
+    let mut items = Vec::new();
+    for _ in 0..MAX_RECENTLY_PLAYED_PAGES {
+        let res: RecentlyPlayedResponse =
+            spotify_user_api_request(&url, token, "recently_played").await?;
+        let page_was_empty = res.items.is_empty();
+        items.extend(res.items);
+
+        match res.next {
+            Some(next) if !page_was_empty => url = next,
+            _ => break,
+        }
+    }
+
+    Ok(items)
+}
+
+/// Stores a row in the `recently_played` table for each of the provided plays.  Plays that have
+/// already been stored for the user are ignored.  Returns the number of newly inserted rows.
+pub(crate) async fn store_recently_played(
+    conn: &DbConn,
+    user: &User,
+    items: Vec<PlayHistoryItem>,
+) -> Result<usize, Error> {
+    if items.is_empty() {
+        return Ok(0);
+    }
+
+    let track_spotify_ids: Vec<String> = items.iter().map(|item| item.track.id.clone()).collect();
+    let mapped_track_spotify_ids =
+        crate::db_util::get_internal_ids_by_spotify_id(conn, track_spotify_ids.iter()).await?;
+
+    let entries: Vec<NewRecentlyPlayedEntry> = items
+        .into_iter()
+        .map(|item| NewRecentlyPlayedEntry {
+            user_id: user.id,
+            mapped_spotify_id: mapped_track_spotify_ids[&item.track.id],
+            played_at: item.played_at.naive_utc(),
+            duration_ms: item.track.duration_ms.unwrap_or(0) as u32,
+        })
+        .collect();
+
+    conn.run(move |conn| {
+        insert_or_ignore!(crate::schema::recently_played::table, &entries).execute(conn)
+    })
+    .await
+    .map_err(|err| {
+        error!("Error inserting recently played tracks: {:?}", err);
+        Error::Internal("Error inserting recently played tracks into database".into())
+    })
+}
+
+/// Fetches all plays for the user since the most recently stored one and stores them.
+pub(crate) async fn update_recently_played(conn: &DbConn, user: &User) -> Result<usize, Error> {
+    let last_played_at = crate::db_util::get_last_recently_played_time(conn, user.id).await?;
+    let items = fetch_recently_played(&user.token, last_played_at).await?;
+    store_recently_played(conn, user, items).await
+}
+
+/// Fetches what the user is currently listening to.  Returns `None` if nothing is playing, which
+/// Spotify signals with an empty 204 response.
+pub(crate) async fn fetch_playback_state(token: &str) -> Result<Option<PlaybackState>, Error> {
+    let endpoint_name = "playback_state";
+    let url = SPOTIFY_USER_PLAYBACK_STATE_URL;
+    let client = get_reqwest_client().await;
+    let start = Instant::now();
+    let res =
+        send_spotify_request(url, endpoint_name, || client.get(url).bearer_auth(token)).await?;
+    let res = if res.status() == StatusCode::NO_CONTENT {
+        Ok(None)
+    } else {
+        process_spotify_res(url, res).await.map(Some)
+    };
+    match res {
+        Ok(res) => {
+            spotify_api_requests_success_total(endpoint_name).inc();
+            spotify_api_response_time(endpoint_name).observe(start.elapsed().as_nanos() as u64);
+            Ok(res)
+        },
+        Err(err) => {
+            spotify_api_requests_failure_total(endpoint_name).inc();
+            Err(err)
+        },
+    }
+}
+
+/// Fetches what the user is currently listening to with their stored access token, refreshing it
+/// and retrying if Spotify rejects it
+pub(crate) async fn fetch_playback_state_with_token_refresh(
+    conn: &DbConn,
+    user: &mut User,
+) -> Result<Option<PlaybackState>, Error> {
+    match fetch_playback_state(&user.token).await {
+        Err(Error::SpotifyUnauthorized) => {
+            info!(
+                "Refreshing access token for user {} after 401 and retrying playback state fetch",
+                user.spotify_id
+            );
+            crate::db_util::refresh_user_access_token(conn, user).await?;
+
+            fetch_playback_state(&user.token).await
+        },
+        res => res,
+    }
+}
+
+/// Fetches all of the artists the user follows, up to `MAX_FOLLOWED_ARTISTS_PAGES` pages of them.
+/// Also returns whether the list was truncated because the user follows more artists than that.
+pub(crate) async fn fetch_followed_artists(token: &str) -> Result<(Vec<Artist>, bool), Error> {
+    let mut url = format!(
+        "{}&limit={}",
+        SPOTIFY_USER_FOLLOWED_ARTISTS_URL, MAX_ENTITY_FETCH_COUNT
+    );
+
+    let mut artists = Vec::new();
+    for _ in 0..MAX_FOLLOWED_ARTISTS_PAGES {
+        let res: FollowedArtistsResponse =
+            spotify_user_api_request(&url, token, "followed_artists").await?;
+        let page_was_empty = res.artists.items.is_empty();
+        artists.extend(res.artists.items);
+
+        match res.artists.next {
+            Some(next) if !page_was_empty => url = next,
+            _ => return Ok((artists, false)),
+        }
+    }
+
+    Ok((artists, true))
+}
+
+/// Diffs the user's currently followed artists against the follows stored in the database,
+/// recording new follows and marking artists that are no longer followed as unfollowed.  Returns
+/// `(followed_count, unfollowed_count)`.
+///
+/// If the user follows too many artists to fetch them all, only new follows are recorded since the
+/// artists that weren't fetched can't be told apart from ones that were unfollowed.
+pub(crate) async fn update_followed_artists(
+    conn: &DbConn,
+    user: &User,
+) -> Result<(usize, usize), Error> {
+    let (followed_artists, truncated) = fetch_followed_artists(&user.token).await?;
+    if truncated {
+        warn!(
+            "User {} follows more artists than can be fetched; skipping unfollow detection",
+            user.spotify_id
+        );
+    }
+    let followed_artist_spotify_ids: Vec<String> = followed_artists
+        .iter()
+        .map(|artist| artist.id.clone())
+        .collect();
+    let mapped_artist_spotify_ids =
+        crate::db_util::get_internal_ids_by_spotify_id(conn, followed_artist_spotify_ids.iter())
+            .await?;
+    let currently_followed: HashSet<i32> = mapped_artist_spotify_ids.values().copied().collect();
+
+    let active_follows = crate::db_util::get_active_follows(conn, user.id).await?;
+    let previously_followed: HashSet<i32> = active_follows
+        .iter()
+        .map(|(_id, mapped_spotify_id)| *mapped_spotify_id)
+        .collect();
 
-            genres
-                .into_iter()
-                .map(move |genre| ArtistGenrePair { artist_id, genre })
+    let now = Utc::now().naive_utc();
+    let new_follows: Vec<NewFollowedArtistEntry> = currently_followed
+        .difference(&previously_followed)
+        .map(|&mapped_spotify_id| NewFollowedArtistEntry {
+            user_id: user.id,
+            mapped_spotify_id,
+            followed_at: now,
         })
         .collect();
+    let unfollowed_row_ids: Vec<i64> = if truncated {
+        Vec::new()
+    } else {
+        active_follows
+            .into_iter()
+            .filter(|(_id, mapped_spotify_id)| !currently_followed.contains(mapped_spotify_id))
+            .map(|(id, _mapped_spotify_id)| id)
+            .collect()
+    };
 
-    // Delete all old artist/genre entries for the artists we have here and insert the new ones,
-    // making sure that the entries we have are all valid and up-to-date
-    let query = diesel::insert_or_ignore_into(crate::schema::artists_genres::table)
-        .values(artist_genre_pairs);
-    conn.run(move |conn| query.execute(conn))
+    let counts = (new_follows.len(), unfollowed_row_ids.len());
+    crate::db_util::store_follow_changes(conn, new_follows, unfollowed_row_ids, now)
         .await
-        .map_err(|err| -> String {
-            error!("Error inserting artist/genre mappings: {:?}", err);
-            "Error inserting artist/genre mappings into database".into()
+        .map_err(|err| {
+            error!("Error storing followed artist changes: {:?}", err);
+            Error::Internal("Error storing followed artists into database".into())
         })?;
+    Ok(counts)
+}
 
-    let track_entries: Vec<NewTrackHistoryEntry> = stats
-        .tracks
-        .into_iter()
-        .flat_map(|(track_timeframe, tracks)| {
-            tracks
-                .into_iter()
-                .enumerate()
-                .map(move |(track_ranking, track)| (track_timeframe, track_ranking, track.id))
-                .map(
-                    |(track_timeframe, track_ranking, track_spotify_id)| NewTrackHistoryEntry {
-                        user_id: user.id,
-                        mapped_spotify_id: mapped_track_spotify_ids[&track_spotify_id],
-                        update_time,
-                        timeframe: map_timeframe_to_timeframe_id(&track_timeframe),
-                        ranking: track_ranking as u8,
-                    },
-                )
-        })
-        .collect();
+/// Fetches the number of tracks in the user's saved tracks library along with the `limit` most
+/// recently saved ones
+pub(crate) async fn fetch_saved_tracks(
+    token: &str,
+    limit: usize,
+) -> Result<SavedTracksResponse, Error> {
+    let url = format!("{}?limit={}", SPOTIFY_USER_SAVED_TRACKS_URL, limit);
+    spotify_user_api_request(&url, token, "saved_tracks").await
+}
 
-    conn.run(move |conn| {
-        diesel::insert_into(crate::schema::track_rank_snapshots::table)
-            .values(&track_entries)
-            .execute(conn)
-    })
-    .await
-    .map_err(|err| -> String {
-        error!("Error inserting row: {:?}", err);
-        "Error inserting user into database".into()
-    })?;
+/// Records the current size of the user's saved tracks library along with its most recently saved
+/// tracks.  Returns the number of tracks in the library, or `None` if neither its size nor its most
+/// recently saved track changed since the last snapshot, in which case nothing is stored.
+pub(crate) async fn update_library_snapshot(
+    conn: &DbConn,
+    user: &User,
+) -> Result<Option<u32>, Error> {
+    // Only the newest save is fetched at first so that unchanged libraries are skipped cheaply
+    let newest = fetch_saved_tracks(&user.token, 1).await?;
+    let newest_save = newest
+        .items
+        .first()
+        .map(|item| (item.track.id.clone(), item.added_at.naive_utc()));
+    let last_size = crate::db_util::get_latest_library_size(conn, user.id).await?;
+    let last_newest_save = crate::db_util::get_newest_saved_tracks(conn, user.id, 1)
+        .await?
+        .into_iter()
+        .next();
+    if last_size == Some(newest.total) && last_newest_save == newest_save {
+        return Ok(None);
+    }
 
-    // Update the user to have a last update time that matches all of the new updates
-    let updated_row_count =
-        crate::db_util::update_user_last_updated(&user, &conn, update_time).await?;
+    let res = fetch_saved_tracks(&user.token, MAX_ENTITY_FETCH_COUNT).await?;
+    let track_spotify_ids: Vec<String> =
+        res.items.iter().map(|item| item.track.id.clone()).collect();
+    let mapped_track_spotify_ids =
+        get_internal_ids_by_spotify_id(conn, track_spotify_ids.iter()).await?;
 
-    if updated_row_count != 1 {
-        error!(
-            "Updated {} rows when setting last update time for user with spotify_id={}, but \
-             should have updated 1.",
-            updated_row_count, user.spotify_id
-        );
-    }
+    let snapshot = NewLibrarySnapshotEntry {
+        user_id: user.id,
+        update_time: Utc::now().naive_utc(),
+        saved_track_count: res.total,
+    };
+    let saves: Vec<NewSavedTrackEntry> = res
+        .items
+        .into_iter()
+        .map(|item| NewSavedTrackEntry {
+            user_id: user.id,
+            mapped_spotify_id: mapped_track_spotify_ids[&item.track.id],
+            added_at: item.added_at.naive_utc(),
+        })
+        .collect();
 
-    Ok(())
+    crate::db_util::store_library_snapshot(conn, snapshot, saves)
+        .await
+        .map_err(|err| {
+            error!("Error storing library snapshot: {:?}", err);
+            Error::Internal("Error storing library snapshot into database".into())
+        })?;
+    Ok(Some(res.total))
 }
 
-const MAX_BATCH_ENTITY_COUNT: usize = 50;
+/// Max number of entities that can be requested from Spotify's batch endpoints at once
+pub(crate) const MAX_BATCH_ENTITY_COUNT: usize = 50;
+/// Max number of batch requests made at once when fetching entities missing from the cache
+const MAX_CONCURRENT_BATCH_FETCHES: usize = 4;
 
 async fn fetch_batch_entities<'a, T: for<'de> Deserialize<'de>>(
     base_url: &str,
     token: &str,
     spotify_entity_ids: &[&str],
     endpoint_name: &'static str,
-) -> Result<T, String> {
+) -> Result<T, Error> {
     let url = if base_url.contains('?') {
         base_url.into()
     } else {
@@ -632,154 +1287,334 @@ async fn fetch_batch_entities<'a, T: for<'de> Deserialize<'de>>(
     };
     let client = get_reqwest_client().await;
 
-    loop {
-        let start = Instant::now();
-        spotify_api_requests_total(endpoint_name).inc();
-        let res = client
-            .get(&url)
-            .bearer_auth(token)
-            .send()
-            .await
-            .map_err(|err| {
-                error!("Error requesting batch data from the Spotify API: {}", err);
-                String::from("Error requesting batch data from the Spotify API")
-            })?;
-
-        if res.status() == StatusCode::TOO_MANY_REQUESTS {
-            warn!("Rate limited when hitting URL={}", url);
-            spotify_api_requests_rate_limited_total(endpoint_name).inc();
-            tokio::time::sleep(Duration::from_secs(5)).await;
-            continue;
-        }
+    let start = Instant::now();
+    let res = send_spotify_request(&url, endpoint_name, || client.get(&url).bearer_auth(token))
+        .await
+        .map_err(|err| {
+            error!("Error requesting batch data from the Spotify API: {}", err);
+            Error::SpotifyApi("Error requesting batch data from the Spotify API".into())
+        })?;
 
-        if res.status().is_success() {
-            spotify_api_requests_success_total(endpoint_name).inc();
-            spotify_api_response_time(endpoint_name).observe(start.elapsed().as_nanos() as u64);
-        } else {
+    if res.status().is_success() {
+        spotify_api_requests_success_total(endpoint_name).inc();
+        spotify_api_response_time(endpoint_name).observe(start.elapsed().as_nanos() as u64);
+    } else {
+        error!(
+            "Got bad status code of {} from Spotify API: {:?}",
+            res.status(),
+            res.text().await
+        );
+        spotify_api_requests_failure_total(endpoint_name).inc();
+        return Err(Error::SpotifyApi(
+            "Got bad response from Spotify API".into(),
+        ));
+    }
+
+    if cfg!(debug_assertions) {
+        let res = res.text().await.map_err(|err| {
+            error!("Error reading response from Spotify API: {:?}", err);
+            Error::SpotifyApi("Error reading response from the Spotify API".into())
+        })?;
+        serde_json::from_str(&res).map_err(|err| {
             error!(
-                "Got bad status code of {} from Spotify API: {:?}",
-                res.status(),
-                res.text().await
+                "Error decoding JSON from Spotify API: {:?}, url={}, res={}",
+                err, url, res
             );
-            spotify_api_requests_failure_total(endpoint_name).inc();
-            return Err("Got bad response from Spotify API".into());
-        }
+            Error::SpotifyApi("Error reading data from the Spotify API".into())
+        })
+    } else {
+        res.json().await.map_err(|err| {
+            error!(
+                "Error decoding JSON from Spotify API: {:?}, url={}",
+                err, url
+            );
+            Error::SpotifyApi("Error reading data from the Spotify API".into())
+        })
+    }
+}
 
-        if cfg!(debug_assertions) {
-            let res = res.text().await.map_err(|err| -> String {
-                error!("Error reading response from Spotify API: {:?}", err);
-                "Error reading response from the Spotify API".into()
-            })?;
-            return serde_json::from_str(&res).map_err(|err| -> String {
-                error!(
-                    "Error decoding JSON from Spotify API: {:?}, url={}, res={}",
-                    err, url, res
-                );
-                "Error reading data from the Spotify API".into()
-            });
-        } else {
-            return res.json().await.map_err(|err| -> String {
-                error!(
-                    "Error decoding JSON from Spotify API: {:?}, url={}",
-                    err, url
+/// Lines up the entities returned from a batch endpoint with the ids they were requested for.
+/// Spotify returns them in the order they were requested, but matching them by ID ensures that
+/// metadata is never attributed to the wrong entity if any are omitted or reordered.  IDs that
+/// were requested more than once each get a copy of the entity.
+fn align_by_id<T: HasSpotifyId + Clone>(
+    spotify_ids: &[&str],
+    items: Vec<Option<T>>,
+) -> Vec<Option<T>> {
+    let items_by_id: HashMap<String, T> = items
+        .into_iter()
+        .flatten()
+        .map(|item| (item.get_spotify_id().to_owned(), item))
+        .collect();
+    spotify_ids
+        .iter()
+        .map(|id| items_by_id.get(*id).cloned())
+        .collect()
+}
+
+/// Removes repeated IDs, keeping the first occurrence of each.  The same entity is often requested
+/// more than once, such as when it's in the user's top entities for several timeframes.
+fn dedupe_ids<'a>(spotify_ids: &[&'a str]) -> Vec<&'a str> {
+    let mut seen = HashSet::default();
+    spotify_ids
+        .iter()
+        .copied()
+        .filter(|id| seen.insert(*id))
+        .collect()
+}
+
+/// `fetch_with_cache` returns an entity for each requested id in order, so this just pairs them up
+fn key_by_requested_id<T>(spotify_ids: &[&str], entities: Vec<T>) -> HashMap<String, T> {
+    spotify_ids
+        .iter()
+        .map(|id| (*id).to_owned())
+        .zip(entities)
+        .collect()
+}
+
+fn fill_placeholders<T>(
+    spotify_ids: &[&str],
+    items: Vec<Option<T>>,
+    placeholder: fn(&str) -> T,
+) -> Vec<T> {
+    spotify_ids
+        .iter()
+        .zip(items)
+        .map(|(id, item)| {
+            item.unwrap_or_else(|| {
+                warn!(
+                    "Entity {} is unavailable from Spotify; using a placeholder",
+                    id
                 );
-                "Error reading data from the Spotify API".into()
-            });
-        };
+                placeholder(id)
+            })
+        })
+        .collect()
+}
+
+/// Fetches a single batch of entities from the Spotify API and writes them to the cache and
+/// metadata store.  Entities that Spotify doesn't have are returned as `None`.
+async fn fetch_chunk_with_cache<
+    ResponseType: for<'de> Deserialize<'de>,
+    T: Clone + Send + Sync + Serialize + for<'de> Deserialize<'de> + 'static,
+>(
+    cache_key: &str,
+    metadata_table: Option<MetadataTable>,
+    api_url: &str,
+    endpoint_name: &'static str,
+    spotify_access_token: &str,
+    chunk: &[&str],
+    map_response_to_items: fn(&[&str], ResponseType) -> Result<Vec<Option<T>>, Error>,
+) -> Result<Vec<Option<T>>, Error> {
+    let res: ResponseType =
+        fetch_batch_entities(api_url, spotify_access_token, chunk, endpoint_name).await?;
+    let fetched_data = map_response_to_items(chunk, res)?;
+    if fetched_data.len() != chunk.len() {
+        error!(
+            "Spotify API returned {} items from {} for a batch of {} ids",
+            fetched_data.len(),
+            endpoint_name,
+            chunk.len()
+        );
+        return Err(Error::SpotifyApi(
+            "Unexpected number of items returned from the Spotify API".into(),
+        ));
+    }
+
+    // Update the cache and metadata store with the missing items
+    let kv_pairs = chunk
+        .iter()
+        .zip(&fetched_data)
+        .filter_map(|(id, datum)| Some((*id, datum.as_ref()?)))
+        .collect::<Vec<_>>();
+    crate::cache::hot_cache::set_items(cache_key, kv_pairs.iter().copied());
+    if let Err(err) = block_in_place(|| crate::cache::set_hash_items(cache_key, &kv_pairs)) {
+        warn!("Error writing to cache: {}", err);
+    }
+    if let Some(metadata_table) = metadata_table {
+        if let Err(err) = block_in_place(|| {
+            crate::cache::metadata_store::set_metadata_items(metadata_table, &kv_pairs)
+        }) {
+            warn!("Error writing to metadata store: {}", err);
+        }
     }
+
+    Ok(fetched_data)
 }
 
+/// Fetches entities from the Spotify API, checking the in-process hot cache first, then the Redis
+/// cache, and then the database metadata store (if `metadata_table` is provided) for any entities
+/// missing from it.  Entities fetched from the API are written back to both.  Entries older than
+/// `CONF.entity_cache_ttl` are treated as missing.
+///
+/// `map_response_to_items` is given the IDs requested in each batch and must return one entry per
+/// requested ID, in the same order, with `None` for any entities that Spotify no longer has.  Those
+/// are replaced with expired metadata from the metadata store if there is any or else with the
+/// result of `placeholder`, and aren't cached.
 async fn fetch_with_cache<
     ResponseType: for<'de> Deserialize<'de>,
-    T: Clone + Serialize + for<'de> Deserialize<'de>,
+    T: Clone + Send + Sync + Serialize + for<'de> Deserialize<'de> + 'static,
 >(
     cache_key: &str,
+    metadata_table: Option<MetadataTable>,
     api_url: &str,
     endpoint_name: &'static str,
     spotify_access_token: &str,
     spotify_ids: &[&str],
-    map_response_to_items: fn(ResponseType) -> Result<Vec<T>, String>,
-) -> Result<Vec<T>, String> {
-    // First, try to get as many items as we can from the cache
+    map_response_to_items: fn(&[&str], ResponseType) -> Result<Vec<Option<T>>, Error>,
+    placeholder: fn(&str) -> T,
+) -> Result<Vec<T>, Error> {
+    let missing_indices = |items: &[Option<T>]| -> Vec<usize> {
+        items
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| item.is_none())
+            .map(|(i, _)| i)
+            .collect()
+    };
+
+    // First, try to get as many items as we can from the hot cache and then the cache
     info!("Checking cache for {} spotify ids...", spotify_ids.len());
-    let cache_res = block_in_place(|| crate::cache::get_hash_items::<T>(cache_key, spotify_ids))?;
-
-    // Fire off a request to Spotify to fill in the missing items
-    let mut missing_indices = Vec::new();
-    let mut missing_ids = Vec::new();
-    for (i, datum) in cache_res.iter().enumerate() {
-        if datum.is_none() {
-            missing_indices.push(i);
-            missing_ids.push(spotify_ids[i]);
+    let mut items = crate::cache::hot_cache::get_items::<T>(cache_key, spotify_ids);
+    let mut missing = missing_indices(&items);
+    if !missing.is_empty() {
+        let missing_ids: Vec<&str> = missing.iter().map(|&i| spotify_ids[i]).collect();
+        // The cache is only an optimization, so errors here fall back to fetching everything
+        let cached = block_in_place(|| match CONF.entity_cache_ttl {
+            Some(ttl) => crate::cache::get_fresh_hash_items::<T>(cache_key, &missing_ids, ttl),
+            None => crate::cache::get_hash_items::<T>(cache_key, &missing_ids),
+        })
+        .unwrap_or_else(|err| {
+            warn!("Error reading from cache; skipping it: {}", err);
+            vec![None; missing_ids.len()]
+        });
+        crate::cache::hot_cache::set_items(
+            cache_key,
+            missing_ids
+                .iter()
+                .zip(&cached)
+                .filter_map(|(id, item)| Some((*id, item.as_ref()?))),
+        );
+        for (&i, item) in missing.iter().zip(cached) {
+            items[i] = item;
         }
+        missing = missing_indices(&items);
     }
     info!(
         "{}/{} items found in the cache.",
-        cache_res.len() - missing_indices.len(),
+        items.len() - missing.len(),
         spotify_ids.len()
     );
 
-    if missing_indices.is_empty() {
-        return Ok(cache_res.into_iter().map(Option::unwrap).collect());
+    // Then fall back to the metadata stored in the database.  This is only a fallback, so errors
+    // here aren't fatal.  Expired metadata is kept around in case Spotify no longer has the entity.
+    let mut expired_items: HashMap<&str, T> = HashMap::default();
+    if let (Some(metadata_table), false) = (metadata_table, missing.is_empty()) {
+        let missing_ids: Vec<&str> = missing.iter().map(|&i| spotify_ids[i]).collect();
+        let fresh_cutoff = match CONF.entity_cache_ttl {
+            Some(ttl) =>
+                Utc::now().naive_utc()
+                    - chrono::Duration::from_std(ttl)
+                        .expect("`ENTITY_CACHE_TTL_SECONDS` is validated on startup"),
+            None => NaiveDateTime::UNIX_EPOCH,
+        };
+        match block_in_place(|| {
+            crate::cache::metadata_store::get_metadata_items::<T>(metadata_table, &missing_ids)
+        }) {
+            Ok(stored) => {
+                let found: Vec<(&str, T)> = missing
+                    .iter()
+                    .zip(stored)
+                    .filter_map(|(&i, stored)| {
+                        let (item, last_fetched) = stored?;
+                        if last_fetched <= fresh_cutoff {
+                            expired_items.insert(spotify_ids[i], item);
+                            return None;
+                        }
+                        items[i] = Some(item.clone());
+                        Some((spotify_ids[i], item))
+                    })
+                    .collect();
+                info!("{} more items found in the metadata store.", found.len());
+                crate::cache::hot_cache::set_items(
+                    cache_key,
+                    found.iter().map(|(id, item)| (*id, item)),
+                );
+                if let Err(err) = block_in_place(|| crate::cache::set_hash_items(cache_key, &found))
+                {
+                    warn!("Error writing to cache: {}", err);
+                }
+                missing = missing_indices(&items);
+            },
+            Err(err) => warn!("Error reading from metadata store: {}", err),
+        }
     }
 
-    let mut fetched_entities = Vec::with_capacity(missing_indices.len());
-    for (chunk_ix, chunk) in missing_ids.chunks(MAX_BATCH_ENTITY_COUNT).enumerate() {
-        info!("Fetching chunk {}...", chunk_ix);
-        let res: ResponseType =
-            fetch_batch_entities(api_url, spotify_access_token, chunk, endpoint_name).await?;
-        let fetched_artist_data = map_response_to_items(res)?;
-
-        for i in 0..chunk.len() {
-            debug_assert_eq!(
-                chunk[i],
-                missing_ids[(chunk_ix * MAX_BATCH_ENTITY_COUNT) + i]
-            );
-        }
+    if missing.is_empty() {
+        return Ok(items.into_iter().map(Option::unwrap).collect());
+    }
 
-        // Update the cache with the missing items
-        block_in_place(|| {
-            crate::cache::set_hash_items(
+    // Fire off requests to Spotify to fill in the missing items, fetching each one only once
+    let missing_ids: Vec<&str> =
+        dedupe_ids(&missing.iter().map(|&i| spotify_ids[i]).collect::<Vec<_>>());
+    let chunks: Vec<&[&str]> = missing_ids.chunks(MAX_BATCH_ENTITY_COUNT).collect();
+    info!("Fetching {} chunks...", chunks.len());
+    let chunk_futures: Vec<_> = chunks
+        .iter()
+        .map(|chunk| {
+            fetch_chunk_with_cache(
                 cache_key,
-                &fetched_artist_data
-                    .iter()
-                    .enumerate()
-                    .map(|(i, datum)| (chunk[i], datum))
-                    .collect::<Vec<_>>(),
+                metadata_table,
+                api_url,
+                endpoint_name,
+                spotify_access_token,
+                chunk,
+                map_response_to_items,
             )
-        })?;
-
-        fetched_entities.extend(fetched_artist_data)
+        })
+        .collect();
+    // Chunks are fetched concurrently, but `buffered` yields their results in order
+    let fetched_chunks: Vec<Vec<Option<T>>> = stream::iter(chunk_futures)
+        .buffered(MAX_CONCURRENT_BATCH_FETCHES)
+        .try_collect()
+        .await?;
+
+    let mut fetched_by_id: HashMap<&str, T> = HashMap::default();
+    for (chunk, fetched_data) in chunks.into_iter().zip(fetched_chunks) {
+        let fetched_data = chunk
+            .iter()
+            .zip(fetched_data)
+            .map(|(id, datum)| datum.or_else(|| expired_items.remove(id)))
+            .collect();
+        fetched_by_id.extend(chunk.iter().copied().zip(fill_placeholders(
+            chunk,
+            fetched_data,
+            placeholder,
+        )));
     }
     info!("Fetched all chunks.");
 
-    let mut i = 0;
-    let combined_results = cache_res
-        .into_iter()
-        .map(|opt| {
-            opt.unwrap_or_else(|| {
-                // We could avoid this clone by reversing the direction in which we fetch the items
-                // but that's 100% premature and likely useless optimization
-                let val = fetched_entities[i].clone();
-                i += 1;
-                val
-            })
-        })
-        .collect::<Vec<_>>();
-    Ok(combined_results)
+    for i in missing {
+        items[i] = Some(fetched_by_id[spotify_ids[i]].clone());
+    }
+    Ok(items.into_iter().map(Option::unwrap).collect())
 }
 
+/// Fetches the artists with the provided ids, keyed by id.  Every provided id has an entry;
+/// artists that are no longer available from Spotify are filled in with placeholders.
 pub(crate) async fn fetch_artists(
     spotify_access_token: &str,
     spotify_ids: &[&str],
-) -> Result<Vec<Artist>, String> {
+) -> Result<HashMap<String, Artist>, Error> {
     let mut entities = fetch_with_cache::<SpotifyBatchArtistsResponse, _>(
         &CONF.artists_cache_hash_name,
+        Some(MetadataTable::Artists),
         SPOTIFY_BATCH_ARTISTS_URL,
         "fetch_artists",
         spotify_access_token,
         spotify_ids,
-        |res: SpotifyBatchArtistsResponse| Ok(res.artists),
+        |ids, res: SpotifyBatchArtistsResponse| Ok(align_by_id(ids, res.artists)),
+        Artist::placeholder,
     )
     .await?;
 
@@ -791,20 +1626,24 @@ pub(crate) async fn fetch_artists(
         }
     }
 
-    Ok(entities)
+    Ok(key_by_requested_id(spotify_ids, entities))
 }
 
+/// Fetches the tracks with the provided ids, keyed by id.  Every provided id has an entry; tracks
+/// that are no longer available from Spotify are filled in with placeholders.
 pub(crate) async fn fetch_tracks(
     spotify_access_token: &str,
     spotify_ids: &[&str],
-) -> Result<Vec<Track>, String> {
+) -> Result<HashMap<String, Track>, Error> {
     let mut entities = fetch_with_cache::<SpotifyBatchTracksResponse, _>(
         &CONF.tracks_cache_hash_name,
+        Some(MetadataTable::Tracks),
         SPOTIFY_BATCH_TRACKS_URL,
         "fetch_tracks",
         spotify_access_token,
         spotify_ids,
-        |res: SpotifyBatchTracksResponse| Ok(res.tracks),
+        |ids, res: SpotifyBatchTracksResponse| Ok(align_by_id(ids, res.tracks)),
+        Track::placeholder,
     )
     .await?;
 
@@ -814,7 +1653,120 @@ pub(crate) async fn fetch_tracks(
         }
     }
 
-    Ok(entities)
+    Ok(key_by_requested_id(spotify_ids, entities))
+}
+
+/// Fetches every entity that isn't already in the cache so that it's repopulated, such as after
+/// Redis is flushed.  Entities are resolved through the same path as `fetch_artists` and
+/// `fetch_tracks`, so the metadata store is used before Spotify.  Waits `batch_delay` between each
+/// batch to stay well under Spotify's rate limits.  Returns the number of entities repopulated.
+pub(crate) async fn warm_entity_cache(
+    spotify_access_token: &str,
+    metadata_table: MetadataTable,
+    spotify_ids: &[&str],
+    batch_delay: Duration,
+) -> Result<usize, Error> {
+    let hash_name = match metadata_table {
+        MetadataTable::Artists => &CONF.artists_cache_hash_name,
+        MetadataTable::Tracks => &CONF.tracks_cache_hash_name,
+    };
+    let cached = block_in_place(|| match CONF.entity_cache_ttl {
+        Some(ttl) => crate::cache::get_fresh_hash_items::<IgnoredAny>(hash_name, spotify_ids, ttl),
+        None => crate::cache::get_hash_items::<IgnoredAny>(hash_name, spotify_ids),
+    })?;
+    let missing_ids: Vec<&str> = spotify_ids
+        .iter()
+        .zip(cached)
+        .filter(|(_, cached)| cached.is_none())
+        .map(|(id, _)| *id)
+        .collect();
+    info!(
+        "Warming cache with {}/{} uncached {:?}",
+        missing_ids.len(),
+        spotify_ids.len(),
+        metadata_table
+    );
+
+    for (chunk_ix, chunk) in missing_ids.chunks(MAX_BATCH_ENTITY_COUNT).enumerate() {
+        if chunk_ix > 0 {
+            tokio::time::sleep(batch_delay).await;
+        }
+        match metadata_table {
+            MetadataTable::Artists => drop(fetch_artists(spotify_access_token, chunk).await?),
+            MetadataTable::Tracks => drop(fetch_tracks(spotify_access_token, chunk).await?),
+        }
+    }
+    Ok(missing_ids.len())
+}
+
+/// Re-fetches a batch of at most `MAX_BATCH_ENTITY_COUNT` entities from Spotify whether or not
+/// they're cached, replacing them in the cache and metadata store.  Returns the IDs of the
+/// entities that Spotify no longer has, which are left untouched.
+pub(crate) async fn refresh_entity_metadata(
+    spotify_access_token: &str,
+    metadata_table: MetadataTable,
+    spotify_ids: &[&str],
+) -> Result<Vec<String>, Error> {
+    let unavailable: Vec<bool> = match metadata_table {
+        MetadataTable::Artists => fetch_chunk_with_cache(
+            &CONF.artists_cache_hash_name,
+            Some(metadata_table),
+            SPOTIFY_BATCH_ARTISTS_URL,
+            "fetch_artists",
+            spotify_access_token,
+            spotify_ids,
+            |ids, res: SpotifyBatchArtistsResponse| Ok(align_by_id(ids, res.artists)),
+        )
+        .await?
+        .iter()
+        .map(Option::is_none)
+        .collect(),
+        MetadataTable::Tracks => fetch_chunk_with_cache(
+            &CONF.tracks_cache_hash_name,
+            Some(metadata_table),
+            SPOTIFY_BATCH_TRACKS_URL,
+            "fetch_tracks",
+            spotify_access_token,
+            spotify_ids,
+            |ids, res: SpotifyBatchTracksResponse| Ok(align_by_id(ids, res.tracks)),
+        )
+        .await?
+        .iter()
+        .map(Option::is_none)
+        .collect(),
+    };
+
+    Ok(spotify_ids
+        .iter()
+        .zip(unavailable)
+        .filter(|(_, unavailable)| *unavailable)
+        .map(|(id, _)| (*id).to_owned())
+        .collect())
+}
+
+/// Fetches audio features for the provided tracks.  The returned entries line up with the
+/// provided ids and are `None` for tracks that Spotify doesn't have audio features for.
+pub(crate) async fn fetch_audio_features(
+    spotify_access_token: &str,
+    spotify_ids: &[&str],
+) -> Result<Vec<Option<TrackAudioFeatures>>, Error> {
+    fetch_with_cache::<SpotifyBatchAudioFeaturesResponse, _>(
+        &CONF.audio_features_cache_hash_name,
+        None,
+        SPOTIFY_BATCH_AUDIO_FEATURES_URL,
+        "fetch_audio_features",
+        spotify_access_token,
+        spotify_ids,
+        // Tracks without audio features are cached as such rather than treated as unavailable
+        |ids, res: SpotifyBatchAudioFeaturesResponse| {
+            Ok(align_by_id(ids, res.audio_features)
+                .into_iter()
+                .map(Some)
+                .collect())
+        },
+        |_| None,
+    )
+    .await
 }
 
 pub(crate) async fn create_playlist(
@@ -822,8 +1774,9 @@ pub(crate) async fn create_playlist(
     user: &User,
     name: String,
     description: Option<String>,
+    public: bool,
     track_spotify_ids: &[String],
-) -> Result<Playlist, String> {
+) -> Result<Playlist, Error> {
     let url = format!(
         "https://api.spotify.com/v1/users/{user_id}/playlists",
         user_id = user.spotify_id
@@ -831,7 +1784,7 @@ pub(crate) async fn create_playlist(
     let body = CreatePlaylistRequest {
         name,
         description,
-        public: Some(true),
+        public: Some(public),
         ..Default::default()
     };
 
@@ -869,10 +1822,58 @@ pub(crate) async fn create_playlist(
     Ok(created_playlist)
 }
 
+/// Fetches tracks recommended by Spotify based on the provided seeds.  At most
+/// `MAX_RECOMMENDATION_SEEDS` seeds can be provided in total.
+pub(crate) async fn fetch_recommendations(
+    spotify_access_token: &str,
+    seed_artist_ids: &[&str],
+    seed_track_ids: &[&str],
+    limit: usize,
+) -> Result<Vec<Track>, Error> {
+    let url = format!(
+        "{}?seed_artists={}&seed_tracks={}&limit={}",
+        SPOTIFY_RECOMMENDATIONS_URL,
+        seed_artist_ids.join(","),
+        seed_track_ids.join(","),
+        limit.min(MAX_RECOMMENDATIONS_LIMIT)
+    );
+    let res: RecommendationsResponse =
+        spotify_user_json_api_get_request(spotify_access_token, url, "fetch_recommendations")
+            .await?;
+    Ok(res.tracks)
+}
+
+/// Replaces all tracks in the playlist with the provided ones, returning the new snapshot ID.  At
+/// most 100 tracks can be provided.
+pub(crate) async fn replace_playlist_tracks(
+    bearer_token: &str,
+    playlist_id: &str,
+    track_uris: &[String],
+) -> Result<String, Error> {
+    let url = format!(
+        "https://api.spotify.com/v1/playlists/{}/tracks",
+        playlist_id
+    );
+    let body = serde_json::json!({ "uris": track_uris });
+    let client = get_reqwest_client().await;
+
+    info!(
+        "Replacing tracks of playlist id {} with {} tracks...",
+        playlist_id,
+        track_uris.len()
+    );
+    let UpdatePlaylistResponse { snapshot_id } =
+        spotify_api_request(&url, "replace_playlist_tracks", || {
+            client.put(&url).bearer_auth(bearer_token).json(&body)
+        })
+        .await?;
+    Ok(snapshot_id)
+}
+
 pub(crate) async fn get_related_artists(
     bearer_token: &str,
     artist_id: &str,
-) -> Result<Vec<Artist>, String> {
+) -> Result<Vec<Artist>, Error> {
     let url = format!(
         "https://api.spotify.com/v1/artists/{}/related-artists",
         artist_id
@@ -886,11 +1887,18 @@ pub(crate) async fn get_related_artists(
 pub(crate) async fn get_multiple_related_artists(
     bearer_token: String,
     artist_ids: &[&str],
-) -> Result<Vec<Vec<String>>, String> {
+) -> Result<Vec<Vec<String>>, Error> {
     // Pull those from the cache that can be pulled
     let cache_results = block_in_place(|| {
         crate::cache::get_hash_items::<Vec<String>>("related_artists", artist_ids)
-    })?;
+    })
+    .unwrap_or_else(|err| {
+        warn!(
+            "Error reading related artists from cache; skipping it: {}",
+            err
+        );
+        vec![None; artist_ids.len()]
+    });
 
     let mut output = vec![None; artist_ids.len()];
     let mut uncached_ids: Vec<String> = Vec::new();
@@ -917,23 +1925,26 @@ pub(crate) async fn get_multiple_related_artists(
         let tx = tx.clone();
         let work = Arc::clone(&work);
 
-        tokio::task::spawn(async move {
-            loop {
-                let artist_id = match { work.lock().await.pop() } {
-                    Some(id) => id,
-                    None => {
-                        debug!("No more items to fetch, worker exiting");
+        tokio::task::spawn(
+            async move {
+                loop {
+                    let artist_id = match { work.lock().await.pop() } {
+                        Some(id) => id,
+                        None => {
+                            debug!("No more items to fetch, worker exiting");
+                            break;
+                        },
+                    };
+
+                    let related_artists_res = get_related_artists(&bearer_token, &artist_id).await;
+                    if let Err(_) = tx.send((artist_id, related_artists_res)) {
+                        warn!("Receiver dropped; exiting related artists fetch worker");
                         break;
-                    },
-                };
-
-                let related_artists_res = get_related_artists(&bearer_token, &artist_id).await;
-                if let Err(_) = tx.send((artist_id, related_artists_res)) {
-                    warn!("Receiver dropped; exiting related artists fetch worker");
-                    break;
+                    }
                 }
             }
-        });
+            .in_current_span(),
+        );
     }
 
     let fetched_results = block_in_place(|| {
@@ -954,9 +1965,9 @@ pub(crate) async fn get_multiple_related_artists(
                         "No response on channel in 30 seconds when fetching related artists; \
                          giving up"
                     );
-                    return Err(String::from(
+                    return Err(Error::SpotifyApi(String::from(
                         "Error fetching related artists from Spotify API",
-                    ));
+                    )));
                 },
             };
             fetched_so_far += 1;
@@ -985,7 +1996,11 @@ pub(crate) async fn get_multiple_related_artists(
 
         kv_pairs_to_cache.push((artist_id, related_artists));
     }
-    block_in_place(|| crate::cache::set_hash_items("related_artists", &kv_pairs_to_cache))?;
+    if let Err(err) =
+        block_in_place(|| crate::cache::set_hash_items("related_artists", &kv_pairs_to_cache))
+    {
+        warn!("Error writing related artists to cache: {}", err);
+    }
 
     Ok(output
         .into_iter()
@@ -1001,7 +2016,7 @@ pub(crate) async fn get_multiple_related_artists(
 pub(crate) async fn fetch_top_tracks_for_artist(
     spotify_access_token: &str,
     artist_spotify_id: &str,
-) -> Result<Vec<Track>, String> {
+) -> Result<Vec<Track>, Error> {
     #[derive(Deserialize)]
     struct FetchTopTracksForArtistResponse {
         pub tracks: Vec<Track>,
@@ -1014,11 +2029,13 @@ pub(crate) async fn fetch_top_tracks_for_artist(
 
     Ok(fetch_with_cache::<FetchTopTracksForArtistResponse, _>(
         "top-tracks",
+        None,
         &url,
         "fetch_top_tracks_for_artist",
         spotify_access_token,
         &[artist_spotify_id],
-        |res| Ok(vec![res.tracks]),
+        |_, res| Ok(vec![Some(res.tracks)]),
+        |_| Vec::new(),
     )
     .await?
     .into_iter()
@@ -1030,7 +2047,7 @@ pub(crate) async fn search_artists(
     conn: &DbConn,
     bearer_token: String,
     query: &str,
-) -> Result<Vec<ArtistSearchResult>, String> {
+) -> Result<Vec<ArtistSearchResult>, Error> {
     #[derive(Clone, Debug, Deserialize)]
     struct SpotifyArtistsSearchResponseInner {
         #[allow(dead_code)]
@@ -1068,3 +2085,111 @@ pub(crate) async fn search_artists(
         })
         .collect())
 }
+
+/// Searches for tracks matching the provided track and artist names, returning at most `limit` of
+/// the top results.  Plain keyword search is used rather than field filters so that results are
+/// returned even if the names don't exactly match Spotify's; callers are expected to pick the best
+/// match themselves.
+pub(crate) async fn search_tracks(
+    bearer_token: &str,
+    artist_name: &str,
+    track_name: &str,
+    limit: usize,
+) -> Result<Vec<Track>, Error> {
+    #[derive(Clone, Debug, Deserialize)]
+    struct SpotifyTracksSearchResponseInner {
+        pub items: Vec<Track>,
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    struct SpotifyTracksSearchResponse {
+        pub tracks: SpotifyTracksSearchResponseInner,
+    }
+
+    let query = format!("{} {}", track_name, artist_name);
+    let url = format!(
+        "https://api.spotify.com/v1/search?q={}&type=track&limit={}",
+        RawStr::new(&query).percent_encode(),
+        limit
+    );
+    let res = spotify_server_get_request::<SpotifyTracksSearchResponse>(
+        bearer_token,
+        &url,
+        "search_tracks",
+    )
+    .await?;
+
+    Ok(res.tracks.items)
+}
+
+#[test]
+fn batch_placeholders_preserve_alignment() {
+    let ids = ["a", "b", "c"];
+    let items = vec![
+        Some(Artist::placeholder("a")),
+        None,
+        Some(Artist::placeholder("c")),
+    ];
+    let filled = fill_placeholders(&ids, items, |id| {
+        let mut artist = Artist::placeholder(id);
+        artist.name = format!("missing {}", id);
+        artist
+    });
+
+    let names: Vec<&str> = filled.iter().map(|artist| artist.name.as_str()).collect();
+    assert_eq!(names, vec![
+        "Unavailable Artist",
+        "missing b",
+        "Unavailable Artist"
+    ]);
+    let ids: Vec<&str> = filled.iter().map(|artist| artist.id.as_str()).collect();
+    assert_eq!(ids, vec!["a", "b", "c"]);
+
+    // Items returned out of order or omitted are matched up with the requested IDs by ID
+    let aligned = align_by_id(&["a", "b", "c"], vec![
+        Some(Artist::placeholder("c")),
+        None,
+        Some(Artist::placeholder("a")),
+    ]);
+    let aligned_ids: Vec<Option<&str>> = aligned
+        .iter()
+        .map(|artist| artist.as_ref().map(|artist| artist.id.as_str()))
+        .collect();
+    assert_eq!(aligned_ids, vec![Some("a"), None, Some("c")]);
+
+    // Repeated IDs each get the entity rather than a placeholder after the first
+    let ids = ["a", "b", "a"];
+    let deduped = dedupe_ids(&ids);
+    assert_eq!(deduped, vec!["a", "b"]);
+    let aligned = align_by_id(&deduped, vec![
+        Some(Artist::placeholder("a")),
+        Some(Artist::placeholder("b")),
+    ]);
+    assert!(aligned.iter().all(Option::is_some));
+    let aligned = align_by_id(&ids, vec![
+        Some(Artist::placeholder("a")),
+        Some(Artist::placeholder("b")),
+        Some(Artist::placeholder("a")),
+    ]);
+    let aligned_ids: Vec<Option<&str>> = aligned
+        .iter()
+        .map(|artist| artist.as_ref().map(|artist| artist.id.as_str()))
+        .collect();
+    assert_eq!(aligned_ids, vec![Some("a"), Some("b"), Some("a")]);
+}
+
+#[test]
+fn request_log_lines_omit_tokens() {
+    let mut params = HashMap::default();
+    params.insert("grant_type", "refresh_token");
+    params.insert("refresh_token", "secret-refresh-token");
+    let line = describe_server_api_request(SPOTIFY_APP_TOKEN_URL, &params);
+    assert!(!line.contains("secret-refresh-token"));
+    assert!(line.contains("grant_type=refresh_token"));
+    assert!(line.contains("refresh_token=<redacted>"));
+
+    let body = serde_json::json!({ "name": "playlist" });
+    let line = describe_user_json_api_request("https://api.spotify.com/v1/me/playlists", &body);
+    assert!(line.contains("playlist"));
+    assert!(!line.contains("Bearer"));
+}
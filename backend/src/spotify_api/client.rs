@@ -0,0 +1,36 @@
+//! Transport that requests to Spotify are sent with.  Requests are sent over HTTP with `reqwest`
+//! unless another client is installed with `set_spotify_client`, which tests use to serve canned
+//! responses from `MockSpotifyClient` without needing credentials or network access.
+
+use std::sync::{Arc, RwLock};
+
+use super::get_reqwest_client;
+
+#[rocket::async_trait]
+pub(crate) trait SpotifyClient: Send + Sync {
+    async fn execute(&self, request: reqwest::Request) -> reqwest::Result<reqwest::Response>;
+}
+
+/// Sends requests to Spotify over the network
+pub(crate) struct HttpSpotifyClient;
+
+#[rocket::async_trait]
+impl SpotifyClient for HttpSpotifyClient {
+    async fn execute(&self, request: reqwest::Request) -> reqwest::Result<reqwest::Response> {
+        get_reqwest_client().await.execute(request).await
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref SPOTIFY_CLIENT: RwLock<Arc<dyn SpotifyClient>> = RwLock::new(Arc::new(HttpSpotifyClient));
+}
+
+pub(crate) fn spotify_client() -> Arc<dyn SpotifyClient> {
+    Arc::clone(&SPOTIFY_CLIENT.read().unwrap())
+}
+
+/// Replaces the client that all subsequent requests to Spotify are sent with
+#[cfg(test)]
+pub(crate) fn set_spotify_client(client: Arc<dyn SpotifyClient>) {
+    *SPOTIFY_CLIENT.write().unwrap() = client;
+}
@@ -0,0 +1,215 @@
+//! Stand-in for the Spotify API used by tests.  Every response is generated from a fixed set of
+//! mock artists and tracks, so the same requests always get the same responses.
+//!
+//! The mock user's top artists and tracks are ordered differently for each timeframe: in order for
+//! `short`, reversed for `medium`, and rotated by a quarter for `long`.
+
+use reqwest::{Method, StatusCode};
+use serde_json::{json, Value};
+
+use super::client::SpotifyClient;
+
+pub(crate) const MOCK_USER_ID: &str = "mock-user";
+pub(crate) const MOCK_USER_DISPLAY_NAME: &str = "Mock User";
+pub(crate) const MOCK_ACCESS_TOKEN: &str = "mock-access-token";
+pub(crate) const MOCK_ARTIST_COUNT: usize = 20;
+pub(crate) const MOCK_TRACK_COUNT: usize = 30;
+const MOCK_GENRES: [&str; 4] = ["hyperpop", "indie rock", "jazz", "lo-fi"];
+
+pub(crate) fn mock_artist_id(ix: usize) -> String { format!("mockartist{:02}", ix) }
+
+pub(crate) fn mock_track_id(ix: usize) -> String { format!("mocktrack{:02}", ix) }
+
+fn mock_artist(ix: usize) -> Value {
+    json!({
+        "id": mock_artist_id(ix),
+        "name": format!("Mock Artist {}", ix),
+        "genres": [MOCK_GENRES[ix % MOCK_GENRES.len()]],
+        "images": [{ "url": format!("https://i.scdn.co/image/mockartist{:02}", ix) }],
+        "popularity": 100 - ix,
+    })
+}
+
+fn mock_track(ix: usize) -> Value {
+    let artist = mock_artist(ix % MOCK_ARTIST_COUNT);
+    json!({
+        "id": mock_track_id(ix),
+        "name": format!("Mock Track {}", ix),
+        "album": {
+            "id": format!("mockalbum{:02}", ix),
+            "name": format!("Mock Album {}", ix),
+            "images": [],
+            "artists": [artist.clone()],
+        },
+        "artists": [artist],
+        "duration_ms": 180_000 + ix * 1000,
+        "preview_url": null,
+        "external_ids": { "isrc": format!("MOCK{:08}", ix) },
+        "popularity": 100 - ix,
+    })
+}
+
+fn mock_audio_features(ix: usize) -> Value {
+    let ratio = ix as f32 / MOCK_TRACK_COUNT as f32;
+    json!({
+        "id": mock_track_id(ix),
+        "danceability": ratio,
+        "energy": 1. - ratio,
+        "valence": ratio,
+        "tempo": 90. + ix as f32,
+        "acousticness": ratio,
+        "instrumentalness": 0.,
+        "speechiness": 0.05,
+        "liveness": 0.1,
+        "loudness": -8.,
+    })
+}
+
+/// Indices of the mock user's top entities for a timeframe, from the top down
+pub(crate) fn mock_top_ranking(entity_count: usize, timeframe: &str) -> Vec<usize> {
+    let mut ranking: Vec<usize> = (0..entity_count).collect();
+    match timeframe {
+        "medium" => ranking.reverse(),
+        "long" => ranking.rotate_left(entity_count / 4),
+        _ => (),
+    }
+    ranking
+}
+
+fn parse_mock_ix(id: &str, prefix: &str, count: usize) -> Option<usize> {
+    id.strip_prefix(prefix)?
+        .parse()
+        .ok()
+        .filter(|&ix| ix < count)
+}
+
+/// Looks up each of the comma-separated IDs in the `ids` query param, with `null` for unknown ones
+fn lookup_ids(
+    url: &reqwest::Url,
+    prefix: &str,
+    count: usize,
+    build: fn(usize) -> Value,
+) -> Vec<Value> {
+    let ids = url
+        .query_pairs()
+        .find(|(key, _)| key == "ids")
+        .map(|(_, ids)| ids.into_owned())
+        .unwrap_or_default();
+    ids.split(',')
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            parse_mock_ix(id, prefix, count)
+                .map(build)
+                .unwrap_or(Value::Null)
+        })
+        .collect()
+}
+
+fn top_entities(url: &reqwest::Url, entity_type: &str) -> Option<Value> {
+    let query_param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, val)| val.into_owned())
+    };
+    let limit: usize = query_param("limit")?.parse().ok()?;
+    let offset: usize = query_param("offset")?.parse().ok()?;
+    let time_range = query_param("time_range")?;
+    let timeframe = time_range.strip_suffix("_term")?;
+
+    let (entity_count, build): (usize, fn(usize) -> Value) = match entity_type {
+        "artists" => (MOCK_ARTIST_COUNT, mock_artist),
+        "tracks" => (MOCK_TRACK_COUNT, mock_track),
+        _ => return None,
+    };
+    let items: Vec<Value> = mock_top_ranking(entity_count, timeframe)
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .map(build)
+        .collect();
+    Some(json!({ "items": items, "total": entity_count, "limit": limit, "offset": offset }))
+}
+
+/// Returns the status and JSON body that Spotify would respond to the request with
+fn respond(method: &Method, url: &reqwest::Url) -> (StatusCode, Value) {
+    let body = match (method, url.path()) {
+        (&Method::POST, "/api/token") => Some(json!({
+            "access_token": MOCK_ACCESS_TOKEN,
+            "refresh_token": "mock-refresh-token",
+            "token_type": "Bearer",
+            "scope": "user-top-read",
+            "expires_in": 3600,
+        })),
+        (&Method::GET, "/v1/me") => Some(json!({
+            "id": MOCK_USER_ID,
+            "display_name": MOCK_USER_DISPLAY_NAME,
+            "images": [],
+        })),
+        (&Method::GET, "/v1/artists") => Some(json!({
+            "artists": lookup_ids(url, "mockartist", MOCK_ARTIST_COUNT, mock_artist),
+        })),
+        (&Method::GET, "/v1/tracks") => Some(json!({
+            "tracks": lookup_ids(url, "mocktrack", MOCK_TRACK_COUNT, mock_track),
+        })),
+        (&Method::GET, "/v1/audio-features") => Some(json!({
+            "audio_features": lookup_ids(url, "mocktrack", MOCK_TRACK_COUNT, mock_audio_features),
+        })),
+        (&Method::GET, path) => path
+            .strip_prefix("/v1/me/top/")
+            .and_then(|entity_type| top_entities(url, entity_type)),
+        _ => None,
+    };
+
+    match body {
+        Some(body) => (StatusCode::OK, body),
+        None => (
+            StatusCode::NOT_FOUND,
+            json!({ "error": { "status": 404, "message": "No mock response for this endpoint" } }),
+        ),
+    }
+}
+
+pub(crate) struct MockSpotifyClient;
+
+#[rocket::async_trait]
+impl SpotifyClient for MockSpotifyClient {
+    async fn execute(&self, request: reqwest::Request) -> reqwest::Result<reqwest::Response> {
+        let (status, body) = respond(request.method(), request.url());
+        let res = http::Response::builder()
+            .status(status.as_u16())
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+            .expect("Failed to build mock response");
+        Ok(reqwest::Response::from(res))
+    }
+}
+
+#[test]
+fn mock_spotify_responses() {
+    use crate::models::{SpotifyBatchArtistsResponse, TopTracksResponse, UserProfile};
+
+    let get = |url: &str| respond(&Method::GET, &reqwest::Url::parse(url).unwrap());
+
+    let (status, body) = get("https://api.spotify.com/v1/me");
+    assert_eq!(status, StatusCode::OK);
+    let profile: UserProfile = serde_json::from_value(body).unwrap();
+    assert_eq!(profile.id, MOCK_USER_ID);
+
+    let (_, body) =
+        get("https://api.spotify.com/v1/me/top/tracks?limit=2&offset=1&time_range=medium_term");
+    let top_tracks: TopTracksResponse = serde_json::from_value(body).unwrap();
+    let top_track_ids: Vec<String> = top_tracks
+        .items
+        .into_iter()
+        .map(|track| track.unwrap().id)
+        .collect();
+    assert_eq!(top_track_ids, vec![mock_track_id(28), mock_track_id(27)]);
+
+    let (_, body) = get("https://api.spotify.com/v1/artists?ids=mockartist03,unknown");
+    let artists: SpotifyBatchArtistsResponse = serde_json::from_value(body).unwrap();
+    assert_eq!(artists.artists[0].as_ref().unwrap().id, mock_artist_id(3));
+    assert!(artists.artists[1].is_none());
+
+    let (status, _) = get("https://api.spotify.com/v1/recommendations");
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
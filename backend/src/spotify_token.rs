@@ -1,5 +1,13 @@
 use chrono;
 
+use crate::error::Error;
+
+/// Tokens are refreshed this long before they actually expire so that requests made with them
+/// don't start failing partway through a route handler.
+const REFRESH_BEFORE_EXPIRY_SECS: i64 = 5 * 60;
+
+/// Spotify API token for the application itself, shared between all routes and background tasks as
+/// Rocket managed state.
 pub(crate) struct SpotifyTokenData {
     pub token: String,
     pub expiry: chrono::DateTime<chrono::Local>,
@@ -18,7 +26,7 @@ impl SpotifyTokenData {
         s
     }
 
-    pub(crate) async fn refresh(&mut self) -> Result<(), String> {
+    pub(crate) async fn refresh(&mut self) -> Result<(), Error> {
         let crate::models::AccessTokenResponse {
             access_token,
             expires_in,
@@ -29,24 +37,35 @@ impl SpotifyTokenData {
             "Got new Spotify access token; expires in: {} seconds",
             expires_in
         );
-        self.expiry = chrono::Local::now() + chrono::Duration::seconds((expires_in as i64) - 10);
+        self.expiry = chrono::Local::now() + chrono::Duration::seconds(expires_in as i64);
         info!("Current Spotify access token is good until {}", self.expiry);
         Ok(())
     }
 
-    pub(crate) async fn get(&mut self) -> Result<String, String> {
+    fn needs_refresh(&self, now: chrono::DateTime<chrono::Local>) -> bool {
+        now + chrono::Duration::seconds(REFRESH_BEFORE_EXPIRY_SECS) > self.expiry
+    }
+
+    pub(crate) async fn get(&mut self) -> Result<String, Error> {
         let now = chrono::Local::now();
-        if now > self.expiry {
+        if self.needs_refresh(now) {
             info!(
-                "Current token expired at {} (it's {} now); refreshing...",
+                "Current token expires at {} (it's {} now); refreshing...",
                 self.expiry, now
             );
-            self.refresh().await?;
+
+            match self.refresh().await {
+                Ok(()) => (),
+                // The current token is still usable, so we can try again on the next request
+                Err(err) if now < self.expiry => warn!(
+                    "Failed to refresh Spotify access token ahead of its expiry; continuing to \
+                     use the current one for now: {}",
+                    err
+                ),
+                Err(err) => return Err(err),
+            }
         }
-        info!(
-            "Current token doesn't expire until {} and is still valid.",
-            self.expiry
-        );
+
         Ok(self.token.clone())
     }
 }
@@ -1,27 +1,44 @@
 use std::cmp::Reverse;
 
-use chrono::NaiveDateTime;
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime};
 use fnv::{FnvHashMap as HashMap, FnvHashSet as HashSet};
+use schemars::JsonSchema;
 
-use crate::models::{Artist, TimeFrames};
+use crate::{
+    genres::{normalize_genre, normalize_genres, NormalizedGenre},
+    models::{
+        AggregatedRanking, AggregatedTimelinePeriod, Artist, ArtistGraphEdge, GenreScore,
+        HasSpotifyId, MoodProfile, RankHistorySummary, TimeFrames, Timeframe, TimeframeCrossover,
+        TrackAudioFeatures,
+    },
+};
 
 /// This is a pretty arbitrary algorithm with the goal of assigning a score to an item based on how
 /// many total items there are and the item's rank in the collection.  It is used to construct the
 /// genres treemap on the frontend.
+/// Rankings past the first `total_items` are given no weight
 fn weight_data_point(total_items: usize, ranking: usize) -> usize {
+    if ranking >= total_items {
+        return 0;
+    }
     (((total_items - ranking) as f32)
         .powf(2.7 * ((total_items - ranking) as f32 / total_items as f32))) as usize
 }
 
-/// Give an array of top artists, extrapolates the most listened-to genres for each update.
+/// Give an array of top artists, extrapolates the most listened-to genres for each update.  Genres
+/// are keyed by their canonical slugs, and the display names of the genres are returned with them.
 pub(crate) fn get_top_genres_by_artists(
     artists_by_id: &HashMap<String, Artist>,
     updates: &[(NaiveDateTime, TimeFrames<String>)],
     weight: bool,
-) -> (Vec<NaiveDateTime>, HashMap<String, Vec<Option<usize>>>) {
+) -> (
+    Vec<NaiveDateTime>,
+    HashMap<String, Vec<Option<usize>>>,
+    HashMap<String, String>,
+) {
     let mut all_timestamps: Vec<NaiveDateTime> = Vec::with_capacity(updates.len());
     let mut all_genre_counts: Vec<HashMap<String, usize>> = Vec::new();
-    let mut all_genres: HashSet<String> = HashSet::default();
+    let mut display_names: HashMap<String, String> = HashMap::default();
 
     for (dt, update) in updates {
         all_timestamps.push(*dt);
@@ -35,14 +52,14 @@ pub(crate) fn get_top_genres_by_artists(
                 if let Some(genres) =
                     artist.and_then(|artist| artist.genres.as_ref().map(|v| v.as_slice()))
                 {
-                    for genre in genres {
-                        all_genres.insert(genre.clone());
-                        let count = genre_counts.entry(genre.clone()).or_insert(0);
+                    for genre in normalize_genres(genres.iter().map(String::as_str)) {
+                        let count = genre_counts.entry(genre.slug.clone()).or_insert(0);
                         *count += if weight {
                             weight_data_point(artist_count, i)
                         } else {
                             1
                         };
+                        display_names.insert(genre.slug, genre.display_name);
                     }
                 }
             }
@@ -52,8 +69,8 @@ pub(crate) fn get_top_genres_by_artists(
     }
 
     let mut counts_by_genre = HashMap::default();
-    for genre in all_genres {
-        counts_by_genre.insert(genre, Vec::with_capacity(all_timestamps.len()));
+    for genre in display_names.keys() {
+        counts_by_genre.insert(genre.clone(), Vec::with_capacity(all_timestamps.len()));
     }
     for counts_by_genre_for_update in all_genre_counts {
         for (genre, scores) in counts_by_genre.iter_mut() {
@@ -62,7 +79,309 @@ pub(crate) fn get_top_genres_by_artists(
         }
     }
 
-    (all_timestamps, counts_by_genre)
+    (all_timestamps, counts_by_genre, display_names)
+}
+
+/// Aggregates the genres of a user's current top artists across all timeframes into a single
+/// breakdown of canonical genres, weighting each artist's genres by its ranking within its
+/// timeframe.  Artists are
+/// expected to be in ranking order within each timeframe, as returned by
+/// `db_util::get_artist_stats`.
+pub(crate) fn compute_genre_breakdown(artist_stats: &[(Timeframe, Artist)]) -> Vec<GenreScore> {
+    let mut artist_count_by_timeframe: HashMap<Timeframe, usize> = HashMap::default();
+    for (timeframe, _artist) in artist_stats {
+        *artist_count_by_timeframe.entry(*timeframe).or_insert(0) += 1;
+    }
+
+    let mut rank_by_timeframe: HashMap<Timeframe, usize> = HashMap::default();
+    let mut scores_by_genre: HashMap<String, GenreScore> = HashMap::default();
+    for (timeframe, artist) in artist_stats {
+        let ranking = rank_by_timeframe.entry(*timeframe).or_insert(0);
+        let weight = weight_data_point(artist_count_by_timeframe[timeframe], *ranking);
+        *ranking += 1;
+
+        for genre in normalize_genres(artist.genres.iter().flatten().map(String::as_str)) {
+            let entry = scores_by_genre
+                .entry(genre.slug.clone())
+                .or_insert_with(|| GenreScore {
+                    genre: genre.slug,
+                    display_name: genre.display_name,
+                    score: 0,
+                    artist_count: 0,
+                });
+            entry.score += weight;
+            entry.artist_count += 1;
+        }
+    }
+
+    let mut genres: Vec<GenreScore> = scores_by_genre.into_values().collect();
+    genres.sort_unstable_by(|a, b| b.score.cmp(&a.score).then_with(|| a.genre.cmp(&b.genre)));
+    genres
+}
+
+/// Max number of genres whose weights are stored with each timeframe of a snapshot
+pub(crate) const MAX_SNAPSHOT_GENRES: usize = 30;
+
+/// Weights the canonical genres of a timeframe's top artists, which are expected to be in ranking
+/// order, by the artists' rankings in the same way as the genres treemap.  Returns the `max_genres`
+/// genres with the highest weights, from highest to lowest.
+pub(crate) fn compute_genre_weights(artists: &[Artist], max_genres: usize) -> Vec<(String, usize)> {
+    let mut weights_by_genre: HashMap<String, usize> = HashMap::default();
+    for (ranking, artist) in artists.iter().enumerate() {
+        let weight = weight_data_point(artists.len(), ranking);
+        for genre in normalize_genres(artist.genres.iter().flatten().map(String::as_str)) {
+            *weights_by_genre.entry(genre.slug).or_insert(0) += weight;
+        }
+    }
+
+    let mut weights: Vec<(String, usize)> = weights_by_genre
+        .into_iter()
+        .filter(|(_genre, weight)| *weight > 0)
+        .collect();
+    weights.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    weights.truncate(max_genres);
+    weights
+}
+
+/// Lines up stored genre weights, which must be sorted by update time, into a timeline.  Returns
+/// the update times of the snapshots along with each genre's weight in every one of them, sorted
+/// by the genres' peak weights and limited to the `max_genres` genres with the highest peaks.
+/// Genres are normalized since older snapshots stored Spotify's labels rather than canonical
+/// slugs, and the weights of synonyms in the same snapshot are summed.
+pub(crate) fn build_genre_timeline(
+    weights: &[(NaiveDateTime, &str, u32)],
+    max_genres: usize,
+) -> (Vec<NaiveDateTime>, Vec<(NormalizedGenre, Vec<Option<u32>>)>) {
+    let mut timestamps: Vec<NaiveDateTime> = Vec::new();
+    let mut weights_by_genre: HashMap<String, (NormalizedGenre, Vec<Option<u32>>)> =
+        HashMap::default();
+    for &(update_time, genre, weight) in weights {
+        if timestamps.last() != Some(&update_time) {
+            timestamps.push(update_time);
+        }
+        let genre = normalize_genre(genre);
+        let (_genre, genre_weights) = weights_by_genre
+            .entry(genre.slug.clone())
+            .or_insert_with(|| (genre, Vec::new()));
+        genre_weights.resize(timestamps.len(), None);
+        let last = genre_weights.last_mut().unwrap();
+        *last = Some(last.unwrap_or(0) + weight);
+    }
+
+    let mut genres: Vec<(NormalizedGenre, Vec<Option<u32>>)> = weights_by_genre
+        .into_values()
+        .map(|(genre, mut genre_weights)| {
+            genre_weights.resize(timestamps.len(), None);
+            (genre, genre_weights)
+        })
+        .collect();
+    let peak = |genre_weights: &[Option<u32>]| genre_weights.iter().flatten().max().copied();
+    genres.sort_unstable_by(|a, b| {
+        peak(&b.1)
+            .cmp(&peak(&a.1))
+            .then_with(|| a.0.slug.cmp(&b.0.slug))
+    });
+    genres.truncate(max_genres);
+    (timestamps, genres)
+}
+
+/// Zero-based ranking at which an entity counts half as much as the top one toward a mainstream
+/// score, so the 11th-ranked entity counts half
+const MAINSTREAM_HALF_WEIGHT_RANKING: f32 = 10.;
+
+/// Computes how mainstream a list of top artists or tracks is from their `(ranking, popularity)`,
+/// as the mean of their Spotify popularity scores weighted so that higher-ranked entities count
+/// for more.  Returns a score from 0 to 100, or `None` if there are no entities.
+pub(crate) fn compute_mainstream_score(
+    entities: impl IntoIterator<Item = (u8, u8)>,
+) -> Option<f32> {
+    let (weighted_sum, total_weight) = entities.into_iter().fold(
+        (0., 0.),
+        |(weighted_sum, total_weight), (ranking, popularity)| {
+            let weight = 1. / (1. + ranking as f32 / MAINSTREAM_HALF_WEIGHT_RANKING);
+            (
+                weighted_sum + weight * popularity as f32,
+                total_weight + weight,
+            )
+        },
+    );
+    if total_weight == 0. {
+        None
+    } else {
+        Some(weighted_sum / total_weight)
+    }
+}
+
+/// Fits a line to `(time, value)` points with least squares and returns its slope in units per 30
+/// days.  Returns `None` if there are fewer than two distinct times.
+pub(crate) fn compute_linear_trend(points: &[(NaiveDateTime, f32)]) -> Option<f32> {
+    let first_time = points.first()?.0;
+    let days: Vec<(f64, f64)> = points
+        .iter()
+        .map(|(time, value)| {
+            let days = (*time - first_time).num_seconds() as f64 / (60. * 60. * 24.);
+            (days, *value as f64)
+        })
+        .collect();
+    let count = days.len() as f64;
+    let mean_x = days.iter().map(|(x, _)| x).sum::<f64>() / count;
+    let mean_y = days.iter().map(|(_, y)| y).sum::<f64>() / count;
+
+    let variance: f64 = days.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    if variance == 0. {
+        return None;
+    }
+    let covariance: f64 = days.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+    Some((covariance / variance * 30.) as f32)
+}
+
+/// Computes the Shannon entropy, in bits, of the distribution of the provided genres.  Each artist
+/// contributes one occurrence of each of its genres.  Returns 0 if no genres are provided.
+pub(crate) fn compute_genre_entropy<'a>(genres: impl IntoIterator<Item = &'a str>) -> f32 {
+    let mut counts: HashMap<&str, usize> = HashMap::default();
+    for genre in genres {
+        *counts.entry(genre).or_insert(0) += 1;
+    }
+    let total: usize = counts.values().sum();
+
+    counts
+        .values()
+        .map(|&count| {
+            let p = count as f32 / total as f32;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Computes the Gini coefficient of how many times each artist appears in `artist_ids`, which
+/// holds the primary artist of each of a user's top tracks.  The counts are padded with artists
+/// that have no tracks up to the number of tracks so that a list where every track is by the same
+/// artist is maximally concentrated rather than trivially equal.  Returns 0 if no tracks are
+/// provided.
+pub(crate) fn compute_artist_gini(artist_ids: &[&str]) -> f32 {
+    let track_count = artist_ids.len();
+    if track_count == 0 {
+        return 0.;
+    }
+
+    let mut counts_by_artist: HashMap<&str, usize> = HashMap::default();
+    for &artist_id in artist_ids {
+        *counts_by_artist.entry(artist_id).or_insert(0) += 1;
+    }
+    let mut counts: Vec<usize> = counts_by_artist.into_values().collect();
+    counts.resize(track_count, 0);
+    counts.sort_unstable();
+
+    // Gini = (2 * sum(i * x_i) / (n * sum(x_i))) - (n + 1) / n with 1-indexed ascending x_i.  Every
+    // track is counted once, so sum(x_i) is the track count, which is also n.
+    let weighted_sum: usize = counts
+        .iter()
+        .enumerate()
+        .map(|(i, &count)| (i + 1) * count)
+        .sum();
+    let n = track_count as f32;
+    (2. * weighted_sum as f32) / (n * n) - (n + 1.) / n
+}
+
+/// Computes the mean audio features of the provided tracks.  Returns `None` if no tracks are
+/// provided.
+pub(crate) fn compute_mood_profile(features: &[&TrackAudioFeatures]) -> Option<MoodProfile> {
+    if features.is_empty() {
+        return None;
+    }
+
+    let mean = |get_feature: fn(&TrackAudioFeatures) -> f32| -> f32 {
+        features.iter().map(|f| get_feature(f)).sum::<f32>() / features.len() as f32
+    };
+
+    Some(MoodProfile {
+        danceability: mean(|f| f.danceability),
+        energy: mean(|f| f.energy),
+        valence: mean(|f| f.valence),
+        tempo: mean(|f| f.tempo),
+        acousticness: mean(|f| f.acousticness),
+        instrumentalness: mean(|f| f.instrumentalness),
+        speechiness: mean(|f| f.speechiness),
+        liveness: mean(|f| f.liveness),
+        loudness: mean(|f| f.loudness),
+        track_count: features.len(),
+    })
+}
+
+/// Returns the percentage of the union of both sets of items which are present in both of them.
+pub(crate) fn compute_overlap_percentage(items1: &[String], items2: &[String]) -> f32 {
+    let items1: HashSet<&String> = items1.iter().collect();
+    let items2: HashSet<&String> = items2.iter().collect();
+    let union_count = items1.union(&items2).count();
+    if union_count == 0 {
+        return 0.;
+    }
+
+    (items1.intersection(&items2).count() as f32 / union_count as f32) * 100.
+}
+
+/// Finds the entities of a snapshot that have moved between timeframes.  Takes `(timeframe,
+/// entity)` pairs ordered by timeframe and then ranking, like those returned by
+/// `db_util::get_artist_stats`.
+pub(crate) fn compute_timeframe_crossover<T: HasSpotifyId + Clone>(
+    entities: &[(Timeframe, T)],
+) -> TimeframeCrossover<T> {
+    let ids_for_timeframe = |timeframe: Timeframe| -> HashSet<&str> {
+        entities
+            .iter()
+            .filter(|(entity_timeframe, _)| *entity_timeframe == timeframe)
+            .map(|(_, entity)| entity.get_spotify_id())
+            .collect()
+    };
+    let medium_ids = ids_for_timeframe(Timeframe::Medium);
+    let long_ids = ids_for_timeframe(Timeframe::Long);
+    let short_ids = ids_for_timeframe(Timeframe::Short);
+
+    let entities_in = |timeframe: Timeframe, keep: &dyn Fn(&str) -> bool| -> Vec<T> {
+        entities
+            .iter()
+            .filter(|(entity_timeframe, entity)| {
+                *entity_timeframe == timeframe && keep(entity.get_spotify_id())
+            })
+            .map(|(_, entity)| entity.clone())
+            .collect()
+    };
+    TimeframeCrossover {
+        graduated: entities_in(Timeframe::Short, &|id| {
+            medium_ids.contains(id) || long_ids.contains(id)
+        }),
+        fallen_out: entities_in(Timeframe::Long, &|id| {
+            !short_ids.contains(id) && !medium_ids.contains(id)
+        }),
+    }
+}
+
+/// Replaces the IDs of tracks that are duplicate releases of the same recording with their
+/// canonical ID, keeping only the highest-ranked of them in each timeframe of each update.
+/// `canonical_ids` only needs to contain the tracks whose canonical ID differs from their own.
+pub(crate) fn merge_duplicate_tracks(
+    track_rank_snapshots: &mut [(NaiveDateTime, TimeFrames<String>)],
+    canonical_ids: &HashMap<String, String>,
+) {
+    if canonical_ids.is_empty() {
+        return;
+    }
+
+    for (_update_timestamp, track_stats_for_update) in track_rank_snapshots {
+        for track_ids in [
+            &mut track_stats_for_update.short,
+            &mut track_stats_for_update.medium,
+            &mut track_stats_for_update.long,
+        ] {
+            let mut seen_ids = HashSet::default();
+            let merged_ids: Vec<String> = track_ids
+                .drain(..)
+                .map(|id| canonical_ids.get(&id).cloned().unwrap_or(id))
+                .filter(|id| seen_ids.insert(id.clone()))
+                .collect();
+            *track_ids = merged_ids;
+        }
+    }
 }
 
 /// Gets a list of all tracks for a given artist that a user has ever had in their top tracks for
@@ -130,3 +449,975 @@ pub(crate) fn compute_genre_ranking_history(
 
     (timestamps, artist_rankings, popularity_history)
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ListeningTimeGranularity {
+    Day,
+    Week,
+}
+
+impl ListeningTimeGranularity {
+    pub(crate) fn parse(granularity: &str) -> Option<Self> {
+        match granularity {
+            "day" => Some(Self::Day),
+            "week" => Some(Self::Week),
+            _ => None,
+        }
+    }
+
+    /// Returns the first day of the period containing the provided time.  Weeks start on Monday.
+    fn period_start(self, time: NaiveDateTime) -> NaiveDate {
+        let date = time.date();
+        match self {
+            Self::Day => date,
+            Self::Week => date - Duration::days(date.weekday().num_days_from_monday() as i64),
+        }
+    }
+}
+
+/// Estimates the time spent listening to each track, bucketed into periods of the provided
+/// granularity.  `plays` are `(track_spotify_id, played_at, track_duration_ms)` and must be sorted
+/// by `played_at` in ascending order.
+///
+/// Spotify only tells us when each track was played, so we assume that each track was listened to
+/// in full unless the next play happened before it would have finished, in which case the time
+/// until the next play is used instead.
+///
+/// Returns `(period_start, ms_listened_by_track_spotify_id)` for each period with at least one
+/// play, in ascending order.
+pub(crate) fn estimate_listening_time(
+    plays: &[(String, NaiveDateTime, u32)],
+    granularity: ListeningTimeGranularity,
+) -> Vec<(NaiveDate, HashMap<String, u64>)> {
+    let mut periods: Vec<(NaiveDate, HashMap<String, u64>)> = Vec::new();
+
+    for (i, (track_id, played_at, duration_ms)) in plays.iter().enumerate() {
+        let mut listened_ms = *duration_ms as u64;
+        if let Some((_, next_played_at, _)) = plays.get(i + 1) {
+            let gap_ms = (*next_played_at - *played_at).num_milliseconds().max(0) as u64;
+            listened_ms = listened_ms.min(gap_ms);
+        }
+
+        let period_start = granularity.period_start(*played_at);
+        if periods.last().map(|(start, _)| *start) != Some(period_start) {
+            periods.push((period_start, HashMap::default()));
+        }
+        let (_, ms_by_track_id) = periods.last_mut().unwrap();
+        *ms_by_track_id.entry(track_id.clone()).or_insert(0) += listened_ms;
+    }
+
+    periods
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TimelineGranularity {
+    Week,
+    Month,
+}
+
+impl TimelineGranularity {
+    pub(crate) fn parse(granularity: &str) -> Option<Self> {
+        match granularity {
+            "week" => Some(Self::Week),
+            "month" => Some(Self::Month),
+            _ => None,
+        }
+    }
+
+    /// Returns the ISO-8601 label of the period containing the provided time
+    fn period_label(self, time: NaiveDateTime) -> String {
+        match self {
+            Self::Week => {
+                let week = time.date().iso_week();
+                format!("{}-W{:02}", week.year(), week.week())
+            },
+            Self::Month => format!("{}-{:02}", time.year(), time.month()),
+        }
+    }
+}
+
+/// Aggregates how often each entity appeared in the top list for a single timeframe along with
+/// its average rank, bucketed into periods of the provided granularity.  `history` is
+/// `(update_time, timeframe, ranking, spotify_id)` and must be sorted by `update_time` in
+/// ascending order.
+///
+/// Returns one entry for each period with at least one snapshot, in ascending order.
+pub(crate) fn aggregate_rank_history(
+    history: &[(NaiveDateTime, Timeframe, u8, String)],
+    selected_timeframe: Timeframe,
+    granularity: TimelineGranularity,
+) -> Vec<AggregatedTimelinePeriod> {
+    // `(period, update_times, (appearance_count, rank_sum) by spotify id)`
+    let mut periods: Vec<(
+        String,
+        HashSet<NaiveDateTime>,
+        HashMap<&str, (usize, usize)>,
+    )> = Vec::new();
+
+    for (update_time, timeframe, ranking, spotify_id) in history {
+        if *timeframe != selected_timeframe {
+            continue;
+        }
+
+        let period = granularity.period_label(*update_time);
+        if periods.last().map(|(label, ..)| label) != Some(&period) {
+            periods.push((period, HashSet::default(), HashMap::default()));
+        }
+        let (_, update_times, stats_by_id) = periods.last_mut().unwrap();
+        update_times.insert(*update_time);
+        let (appearance_count, rank_sum) = stats_by_id.entry(spotify_id.as_str()).or_default();
+        *appearance_count += 1;
+        *rank_sum += *ranking as usize + 1;
+    }
+
+    periods
+        .into_iter()
+        .map(|(period, update_times, stats_by_id)| {
+            let mut rankings: Vec<AggregatedRanking> = stats_by_id
+                .into_iter()
+                .map(
+                    |(spotify_id, (appearance_count, rank_sum))| AggregatedRanking {
+                        spotify_id: spotify_id.to_owned(),
+                        appearance_count,
+                        average_rank: rank_sum as f32 / appearance_count as f32,
+                    },
+                )
+                .collect();
+            rankings.sort_unstable_by(|a, b| {
+                b.appearance_count
+                    .cmp(&a.appearance_count)
+                    .then(a.average_rank.total_cmp(&b.average_rank))
+            });
+
+            AggregatedTimelinePeriod {
+                period,
+                snapshot_count: update_times.len(),
+                rankings,
+            }
+        })
+        .collect()
+}
+
+/// Returns the last snapshot stored in each ISO-8601 week for a single timeframe as
+/// `(update_time, spotify_ids)` with the IDs ordered by rank.  `history` has the same format as for
+/// `aggregate_rank_history` and must also be sorted by `ranking` within each snapshot.
+pub(crate) fn last_snapshot_per_week(
+    history: &[(NaiveDateTime, Timeframe, u8, String)],
+    selected_timeframe: Timeframe,
+) -> Vec<(NaiveDateTime, Vec<&str>)> {
+    let mut snapshots: Vec<(NaiveDateTime, Vec<&str>)> = Vec::new();
+    for (update_time, timeframe, _, spotify_id) in history {
+        if *timeframe != selected_timeframe {
+            continue;
+        }
+
+        match snapshots.last_mut() {
+            Some((last_update_time, spotify_ids)) if last_update_time == update_time =>
+                spotify_ids.push(spotify_id),
+            Some((last_update_time, spotify_ids))
+                if TimelineGranularity::Week.period_label(*last_update_time)
+                    == TimelineGranularity::Week.period_label(*update_time) =>
+            {
+                *last_update_time = *update_time;
+                spotify_ids.clear();
+                spotify_ids.push(spotify_id);
+            },
+            _ => snapshots.push((*update_time, vec![spotify_id.as_str()])),
+        }
+    }
+    snapshots
+}
+
+/// Rank of a single entity in every snapshot of a timeframe
+#[derive(Debug, PartialEq)]
+pub(crate) struct DenseRankSeries<'a> {
+    pub spotify_id: &'a str,
+    pub appearance_count: usize,
+    /// 1-indexed rank in each snapshot, or `None` for snapshots that the entity wasn't in
+    pub ranks: Vec<Option<u8>>,
+}
+
+/// Builds rank series for the `top_n` entities that appeared in the most snapshots of a single
+/// timeframe, with ties broken by average rank.  Every series has a rank for each of the returned
+/// snapshot times so that they can be plotted directly.  `history` has the same format as for
+/// `aggregate_rank_history` and must also be sorted by `update_time` in ascending order.
+pub(crate) fn build_dense_rank_series(
+    history: &[(NaiveDateTime, Timeframe, u8, String)],
+    selected_timeframe: Timeframe,
+    top_n: usize,
+) -> (Vec<NaiveDateTime>, Vec<DenseRankSeries<'_>>) {
+    let mut snapshot_times: Vec<NaiveDateTime> = Vec::new();
+    // `(snapshot index, ranking)` of each appearance by spotify id
+    let mut appearances_by_id: HashMap<&str, Vec<(usize, u8)>> = HashMap::default();
+    for (update_time, timeframe, ranking, spotify_id) in history {
+        if *timeframe != selected_timeframe {
+            continue;
+        }
+
+        if snapshot_times.last() != Some(update_time) {
+            snapshot_times.push(*update_time);
+        }
+        appearances_by_id
+            .entry(spotify_id.as_str())
+            .or_default()
+            .push((snapshot_times.len() - 1, *ranking));
+    }
+
+    let mut top_entities: Vec<(&str, Vec<(usize, u8)>)> = appearances_by_id.into_iter().collect();
+    let rank_sum = |appearances: &[(usize, u8)]| -> usize {
+        appearances
+            .iter()
+            .map(|(_, ranking)| *ranking as usize)
+            .sum()
+    };
+    // Comparing `a_sum / a_count` with `b_sum / b_count` without dividing
+    top_entities.sort_unstable_by(|(a_id, a), (b_id, b)| {
+        b.len()
+            .cmp(&a.len())
+            .then((rank_sum(a) * b.len()).cmp(&(rank_sum(b) * a.len())))
+            .then(a_id.cmp(b_id))
+    });
+    top_entities.truncate(top_n);
+
+    let series = top_entities
+        .into_iter()
+        .map(|(spotify_id, appearances)| {
+            let mut ranks = vec![None; snapshot_times.len()];
+            for (snapshot_ix, ranking) in &appearances {
+                ranks[*snapshot_ix] = Some(ranking + 1);
+            }
+            DenseRankSeries {
+                spotify_id,
+                appearance_count: appearances.len(),
+                ranks,
+            }
+        })
+        .collect();
+    (snapshot_times, series)
+}
+
+/// Notable differences between two snapshots of a top list.  Ranks are 1-indexed.
+#[derive(Debug, PartialEq)]
+pub(crate) struct RankingChanges<'a> {
+    /// Set if the top-ranked entity changed
+    pub new_top: Option<&'a str>,
+    /// `(spotify_id, rank)` of entities that weren't in the previous snapshot, highest ranked
+    /// first
+    pub new_entries: Vec<(&'a str, usize)>,
+    /// `(spotify_id, previous_rank, rank)` of entities that moved up, biggest climbs first
+    pub climbers: Vec<(&'a str, usize, usize)>,
+}
+
+impl<'a> RankingChanges<'a> {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.new_top.is_none() && self.new_entries.is_empty() && self.climbers.is_empty()
+    }
+
+    /// Returns the IDs of all entities included in the changes
+    pub(crate) fn spotify_ids(&self) -> impl Iterator<Item = &'a str> + '_ {
+        self.new_top
+            .into_iter()
+            .chain(self.new_entries.iter().map(|(spotify_id, _)| *spotify_id))
+            .chain(self.climbers.iter().map(|(spotify_id, ..)| *spotify_id))
+    }
+
+    /// Describes each change in plain text.  `names_by_id` maps Spotify IDs to display names.
+    pub(crate) fn describe(&self, names_by_id: &HashMap<String, String>) -> Vec<String> {
+        let name = |spotify_id: &str| {
+            names_by_id
+                .get(spotify_id)
+                .map(String::as_str)
+                .unwrap_or(spotify_id)
+                .to_owned()
+        };
+
+        let mut items = Vec::new();
+        if let Some(top) = self.new_top {
+            items.push(format!("New #1: {}", name(top)));
+        }
+        for (spotify_id, rank) in &self.new_entries {
+            items.push(format!("New at #{}: {}", rank, name(spotify_id)));
+        }
+        for (spotify_id, previous_rank, rank) in &self.climbers {
+            items.push(format!(
+                "Up {} to #{}: {}",
+                previous_rank - rank,
+                rank,
+                name(spotify_id)
+            ));
+        }
+        items
+    }
+}
+
+/// Diffs two snapshots of a top list, each given as Spotify IDs ordered by rank.  At most
+/// `max_items` new entries and climbers are returned.
+pub(crate) fn diff_rankings<'a>(
+    previous: &[&str],
+    current: &[&'a str],
+    max_items: usize,
+) -> RankingChanges<'a> {
+    let previous_ranks: HashMap<&str, usize> = previous
+        .iter()
+        .enumerate()
+        .map(|(i, spotify_id)| (*spotify_id, i + 1))
+        .collect();
+
+    let mut new_entries = Vec::new();
+    let mut climbers = Vec::new();
+    for (i, spotify_id) in current.iter().enumerate() {
+        let rank = i + 1;
+        match previous_ranks.get(spotify_id) {
+            None => new_entries.push((*spotify_id, rank)),
+            Some(&previous_rank) if previous_rank > rank =>
+                climbers.push((*spotify_id, previous_rank, rank)),
+            Some(_) => (),
+        }
+    }
+    new_entries.truncate(max_items);
+    climbers.sort_by_key(|&(_, previous_rank, rank)| (Reverse(previous_rank - rank), rank));
+    climbers.truncate(max_items);
+
+    RankingChanges {
+        new_top: current
+            .first()
+            .copied()
+            .filter(|top| previous.first() != Some(top)),
+        new_entries,
+        climbers,
+    }
+}
+
+/// Returns the update time of the last snapshot of the most recent week in `history` along with
+/// its changes since the last snapshot of the week before, or `None` if there aren't two weeks of
+/// snapshots.  See `last_snapshot_per_week` for the format of `history`.
+pub(crate) fn latest_weekly_changes(
+    history: &[(NaiveDateTime, Timeframe, u8, String)],
+    timeframe: Timeframe,
+    max_items: usize,
+) -> Option<(NaiveDateTime, RankingChanges<'_>)> {
+    let weekly = last_snapshot_per_week(history, timeframe);
+    match weekly.as_slice() {
+        [.., previous, current] =>
+            Some((current.0, diff_rankings(&previous.1, &current.1, max_items))),
+        _ => None,
+    }
+}
+
+/// Merges several top lists of Spotify IDs ordered by rank, such as the same timeframe's top
+/// artists from each of a user's linked accounts, into a single ranking of at most `max_items`
+/// items.  Items score more the higher they're ranked in each list they appear in, so items ranked
+/// highly by several accounts come first.  Ties are broken by the best rank the item has in any
+/// list.
+pub(crate) fn merge_rankings<'a>(rankings: &[&'a [String]], max_items: usize) -> Vec<&'a str> {
+    let list_len = rankings
+        .iter()
+        .map(|ranking| ranking.len())
+        .max()
+        .unwrap_or(0);
+    // spotify_id -> (score, best_rank)
+    let mut scores: HashMap<&str, (usize, usize)> = HashMap::default();
+    for ranking in rankings {
+        for (rank, spotify_id) in ranking.iter().enumerate() {
+            let (score, best_rank) = scores.entry(spotify_id.as_str()).or_insert((0, rank));
+            *score += list_len - rank;
+            *best_rank = (*best_rank).min(rank);
+        }
+    }
+
+    let mut merged: Vec<_> = scores.into_iter().collect();
+    merged.sort_unstable_by_key(|&(spotify_id, (score, best_rank))| {
+        (Reverse(score), best_rank, spotify_id)
+    });
+    merged
+        .into_iter()
+        .take(max_items)
+        .map(|(spotify_id, _)| spotify_id)
+        .collect()
+}
+
+/// Approximate lengths of the windows that Spotify computes short and medium term top lists over
+const SHORT_TERM_DAYS: i64 = 28;
+const MEDIUM_TERM_DAYS: i64 = 182;
+
+/// How often snapshots are synthesized from listening history.  Imported histories span years, so
+/// they get monthly snapshots, while the few days of history captured when users sign up get daily
+/// ones.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum SyntheticSnapshotPeriod {
+    Day,
+    Month,
+}
+
+impl SyntheticSnapshotPeriod {
+    /// Returns the first day of the period containing the provided time
+    fn start(self, time: NaiveDateTime) -> NaiveDate {
+        match self {
+            SyntheticSnapshotPeriod::Day => time.date(),
+            SyntheticSnapshotPeriod::Month => time.date().with_day(1).unwrap(),
+        }
+    }
+}
+
+/// Returns the time of the last play in each period of `plays`, which are `(played_at,
+/// spotify_id)` sorted by `played_at` in ascending order.  Used as the update times of snapshots
+/// synthesized from listening history.
+pub(crate) fn synthetic_snapshot_times(
+    plays: &[(NaiveDateTime, &str)],
+    period: SyntheticSnapshotPeriod,
+) -> Vec<NaiveDateTime> {
+    let mut snapshot_times: Vec<NaiveDateTime> = Vec::new();
+    for &(played_at, _) in plays {
+        match snapshot_times.last_mut() {
+            Some(last_played_at) if period.start(*last_played_at) == period.start(played_at) =>
+                *last_played_at = played_at,
+            _ => snapshot_times.push(played_at),
+        }
+    }
+    snapshot_times
+}
+
+/// Ranks the entities played at or before `until` by play count for each timeframe, approximating
+/// the top lists Spotify would have returned at that time.  Short and medium term rankings only
+/// count plays from the preceding 4 weeks and 6 months respectively, while every play counts
+/// towards the long term ranking.  Ties go to the most recently played entity.  `plays` has the
+/// same format as for `synthetic_snapshot_times`, and the rankings are indexed by timeframe ID.
+pub(crate) fn synthesize_rankings<'a>(
+    plays: &[(NaiveDateTime, &'a str)],
+    until: NaiveDateTime,
+    max_items: usize,
+) -> [Vec<&'a str>; 3] {
+    let window_starts = [
+        Some(until - Duration::days(SHORT_TERM_DAYS)),
+        Some(until - Duration::days(MEDIUM_TERM_DAYS)),
+        None,
+    ];
+
+    let mut rankings: [Vec<&str>; 3] = Default::default();
+    for (ranking, window_start) in rankings.iter_mut().zip(window_starts) {
+        // spotify_id -> (play_count, last_played_at)
+        let mut play_counts: HashMap<&str, (usize, NaiveDateTime)> = HashMap::default();
+        for &(played_at, spotify_id) in plays {
+            if window_start.map_or(false, |start| played_at <= start) || played_at > until {
+                continue;
+            }
+
+            let (play_count, last_played_at) =
+                play_counts.entry(spotify_id).or_insert((0, played_at));
+            *play_count += 1;
+            *last_played_at = (*last_played_at).max(played_at);
+        }
+
+        let mut ranked: Vec<_> = play_counts.into_iter().collect();
+        ranked.sort_unstable_by_key(|&(spotify_id, (play_count, last_played_at))| {
+            (Reverse(play_count), Reverse(last_played_at), spotify_id)
+        });
+        *ranking = ranked
+            .into_iter()
+            .take(max_items)
+            .map(|(spotify_id, _)| spotify_id)
+            .collect();
+    }
+    rankings
+}
+
+/// Summarizes the rank history of each entity in `history`, which is `(spotify_id, update_time,
+/// timeframe, ranking)` sorted by `update_time` in ascending order.
+pub(crate) fn summarize_rank_history(
+    history: &[(String, NaiveDateTime, Timeframe, u8)],
+) -> HashMap<&str, RankHistorySummary> {
+    let mut summaries: HashMap<&str, (RankHistorySummary, HashSet<NaiveDateTime>)> =
+        HashMap::default();
+    for (spotify_id, update_time, timeframe, ranking) in history {
+        let (summary, update_times) = summaries.entry(spotify_id.as_str()).or_insert_with(|| {
+            (
+                RankHistorySummary {
+                    first_seen: *update_time,
+                    last_seen: *update_time,
+                    snapshot_count: 0,
+                    best_ranks: [None; 3],
+                },
+                HashSet::default(),
+            )
+        });
+        summary.last_seen = *update_time;
+        update_times.insert(*update_time);
+        if let Some(best_rank) = summary.best_ranks.get_mut(timeframe.id() as usize) {
+            let rank = ranking + 1;
+            *best_rank = Some(best_rank.map_or(rank, |best_rank| best_rank.min(rank)));
+        }
+    }
+
+    summaries
+        .into_iter()
+        .map(|(spotify_id, (mut summary, update_times))| {
+            summary.snapshot_count = update_times.len();
+            (spotify_id, summary)
+        })
+        .collect()
+}
+
+/// Picks how many of the user's top artists and top tracks to use as seeds when fetching
+/// recommendations, alternating between them starting with artists so that both are represented.
+/// Returns `(artist_seed_count, track_seed_count)`.
+pub(crate) fn recommendation_seed_counts(
+    available_artists: usize,
+    available_tracks: usize,
+    max_seeds: usize,
+) -> (usize, usize) {
+    let (mut artist_count, mut track_count) = (0, 0);
+    while artist_count + track_count < max_seeds
+        && (artist_count < available_artists || track_count < available_tracks)
+    {
+        if artist_count < available_artists
+            && (artist_count <= track_count || track_count == available_tracks)
+        {
+            artist_count += 1;
+        } else {
+            track_count += 1;
+        }
+    }
+    (artist_count, track_count)
+}
+
+/// Builds the edges between the given artists from each artist's list of related artists, which is
+/// parallel to `artist_ids`.  Related artists outside of `artist_ids` are ignored.
+pub(crate) fn build_artist_graph_edges(
+    artist_ids: &[&str],
+    related_artists: &[Vec<String>],
+) -> Vec<ArtistGraphEdge> {
+    let pairs: HashSet<(&str, &str)> = artist_ids
+        .iter()
+        .zip(related_artists)
+        .flat_map(|(&source, related)| related.iter().map(move |target| (source, target.as_str())))
+        .collect();
+    let node_ids: HashSet<&str> = artist_ids.iter().copied().collect();
+
+    let mut edges = Vec::new();
+    for (&source, related) in artist_ids.iter().zip(related_artists) {
+        for target in related {
+            let target = target.as_str();
+            if target == source || !node_ids.contains(target) {
+                continue;
+            }
+
+            let mutual = pairs.contains(&(target, source));
+            // Mutual pairs are emitted once, from the side that sorts first
+            if mutual && target < source {
+                continue;
+            }
+            edges.push(ArtistGraphEdge {
+                source: source.to_owned(),
+                target: target.to_owned(),
+                mutual,
+            });
+        }
+    }
+    edges
+}
+
+#[test]
+fn listening_time_estimation() {
+    let at = |day: u32, hour: u32, min: u32| {
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_hms_opt(hour, min, 0)
+            .unwrap()
+    };
+    let plays = vec![
+        // Skipped after one minute
+        ("a".to_string(), at(1, 12, 0), 180_000),
+        ("b".to_string(), at(1, 12, 1), 180_000),
+        // Followed by a long gap, so it's assumed to have been listened to in full
+        ("a".to_string(), at(2, 20, 0), 180_000),
+        ("b".to_string(), at(8, 9, 0), 240_000),
+    ];
+
+    let daily = estimate_listening_time(&plays, ListeningTimeGranularity::Day);
+    assert_eq!(daily.len(), 3);
+    assert_eq!(daily[0].0, at(1, 0, 0).date());
+    assert_eq!(daily[0].1["a"], 60_000);
+    assert_eq!(daily[0].1["b"], 180_000);
+    assert_eq!(daily[1].1["a"], 180_000);
+    assert_eq!(daily[2].1["b"], 240_000);
+
+    // Jan 1 2024 is a Monday and Jan 8 starts the following week
+    let weekly = estimate_listening_time(&plays, ListeningTimeGranularity::Week);
+    assert_eq!(weekly.len(), 2);
+    assert_eq!(weekly[0].1["a"], 240_000);
+    assert_eq!(weekly[1].0, at(8, 0, 0).date());
+}
+
+#[test]
+fn rank_history_aggregation() {
+    let at = |month: u32, day: u32| {
+        NaiveDate::from_ymd_opt(2024, month, day)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+    };
+    let history = vec![
+        (at(1, 1), Timeframe::Short, 0, "a".to_string()),
+        (at(1, 1), Timeframe::Short, 1, "b".to_string()),
+        (at(1, 1), Timeframe::Medium, 0, "c".to_string()),
+        (at(1, 3), Timeframe::Short, 2, "a".to_string()),
+        (at(1, 3), Timeframe::Short, 0, "b".to_string()),
+        (at(1, 8), Timeframe::Short, 0, "b".to_string()),
+        (at(2, 1), Timeframe::Short, 0, "a".to_string()),
+    ];
+
+    let weekly = aggregate_rank_history(&history, Timeframe::Short, TimelineGranularity::Week);
+    let labels: Vec<&str> = weekly.iter().map(|period| period.period.as_str()).collect();
+    assert_eq!(labels, vec!["2024-W01", "2024-W02", "2024-W05"]);
+    assert_eq!(weekly[0].snapshot_count, 2);
+    // Both appeared twice, but "b" has the better average rank
+    assert_eq!(weekly[0].rankings[0].spotify_id, "b");
+    assert_eq!(weekly[0].rankings[0].average_rank, 1.5);
+    assert_eq!(weekly[0].rankings[1].average_rank, 2.);
+
+    let monthly = aggregate_rank_history(&history, Timeframe::Short, TimelineGranularity::Month);
+    let labels: Vec<&str> = monthly
+        .iter()
+        .map(|period| period.period.as_str())
+        .collect();
+    assert_eq!(labels, vec!["2024-01", "2024-02"]);
+    assert_eq!(monthly[0].snapshot_count, 3);
+    assert_eq!(monthly[0].rankings[0].spotify_id, "b");
+    assert_eq!(monthly[0].rankings[0].appearance_count, 3);
+}
+
+#[test]
+fn dense_rank_series() {
+    let at = |day: u32| {
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+    };
+    let history = vec![
+        (at(1), Timeframe::Short, 0, "a".to_string()),
+        (at(1), Timeframe::Short, 1, "b".to_string()),
+        (at(1), Timeframe::Medium, 0, "c".to_string()),
+        (at(2), Timeframe::Short, 0, "b".to_string()),
+        (at(2), Timeframe::Short, 1, "c".to_string()),
+        (at(3), Timeframe::Short, 0, "a".to_string()),
+        (at(3), Timeframe::Short, 1, "b".to_string()),
+    ];
+
+    let (snapshot_times, series) = build_dense_rank_series(&history, Timeframe::Short, 2);
+    assert_eq!(snapshot_times, vec![at(1), at(2), at(3)]);
+    assert_eq!(series, vec![
+        DenseRankSeries {
+            spotify_id: "b",
+            appearance_count: 3,
+            ranks: vec![Some(2), Some(1), Some(2)],
+        },
+        DenseRankSeries {
+            spotify_id: "a",
+            appearance_count: 2,
+            ranks: vec![Some(1), None, Some(1)],
+        },
+    ]);
+
+    let (snapshot_times, series) = build_dense_rank_series(&history, Timeframe::Medium, 10);
+    assert_eq!(snapshot_times, vec![at(1)]);
+    assert_eq!(series.len(), 1);
+    assert!(build_dense_rank_series(&history, Timeframe::Long, 10)
+        .1
+        .is_empty());
+}
+
+#[test]
+fn recommendation_seed_selection() {
+    assert_eq!(recommendation_seed_counts(10, 10, 5), (3, 2));
+    assert_eq!(recommendation_seed_counts(1, 10, 5), (1, 4));
+    assert_eq!(recommendation_seed_counts(10, 0, 5), (5, 0));
+    assert_eq!(recommendation_seed_counts(2, 1, 5), (2, 1));
+    assert_eq!(recommendation_seed_counts(0, 0, 5), (0, 0));
+}
+
+#[test]
+fn weekly_ranking_diffs() {
+    let at = |day: u32| {
+        NaiveDate::from_ymd_opt(2024, 3, day)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+    };
+    let history: Vec<(NaiveDateTime, Timeframe, u8, String)> = [
+        (at(4), Timeframe::Short, 0, "a"),
+        (at(4), Timeframe::Short, 1, "b"),
+        (at(4), Timeframe::Long, 0, "z"),
+        (at(6), Timeframe::Short, 0, "b"),
+        (at(6), Timeframe::Short, 1, "a"),
+        (at(11), Timeframe::Short, 0, "c"),
+        (at(11), Timeframe::Short, 1, "a"),
+        (at(11), Timeframe::Short, 2, "d"),
+        (at(11), Timeframe::Short, 3, "b"),
+    ]
+    .into_iter()
+    .map(|(time, timeframe, ranking, id)| (time, timeframe, ranking, id.to_owned()))
+    .collect();
+    // 2024-03-04 and 2024-03-06 are in the same week, so only the later snapshot is kept
+    assert_eq!(last_snapshot_per_week(&history, Timeframe::Short), vec![
+        (at(6), vec!["b", "a"]),
+        (at(11), vec!["c", "a", "d", "b"]),
+    ]);
+
+    assert_eq!(
+        diff_rankings(&["b", "e", "f", "a"], &["c", "a", "f", "b"], 1),
+        RankingChanges {
+            new_top: Some("c"),
+            new_entries: vec![("c", 1)],
+            climbers: vec![("a", 4, 2)],
+        }
+    );
+    assert!(diff_rankings(&["a", "b"], &["a", "b"], 3).is_empty());
+    assert_eq!(
+        latest_weekly_changes(&history, Timeframe::Short, 5),
+        Some((at(11), RankingChanges {
+            new_top: Some("c"),
+            new_entries: vec![("c", 1), ("d", 3)],
+            climbers: Vec::new(),
+        }))
+    );
+    assert_eq!(latest_weekly_changes(&history, Timeframe::Long, 5), None);
+
+    let entity_history: Vec<(String, NaiveDateTime, Timeframe, u8)> = history
+        .iter()
+        .filter(|(.., id)| id == "a" || id == "z")
+        .map(|(time, timeframe, ranking, id)| (id.clone(), *time, *timeframe, *ranking))
+        .collect();
+    let summaries = summarize_rank_history(&entity_history);
+    let summary = &summaries["a"];
+    assert_eq!((summary.first_seen, summary.last_seen), (at(4), at(11)));
+    assert_eq!(summary.snapshot_count, 3);
+    assert_eq!(summary.best_ranks, [Some(1), None, None]);
+    assert_eq!(summaries["z"].best_ranks, [None, None, Some(1)]);
+}
+
+#[test]
+fn linked_ranking_merging() {
+    let to_ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+    let personal = to_ids(&["a", "b", "c"]);
+    let family = to_ids(&["b", "d", "c"]);
+
+    // b is ranked highly by both accounts so it outscores a, which only one account ranks first.  c
+    // and d tie on score and d wins with its better rank.
+    assert_eq!(merge_rankings(&[&personal, &family], 10), vec![
+        "b", "a", "d", "c"
+    ]);
+    assert_eq!(merge_rankings(&[&personal, &family], 2), vec!["b", "a"]);
+    assert_eq!(merge_rankings(&[&personal], 10), vec!["a", "b", "c"]);
+    assert!(merge_rankings(&[], 10).is_empty());
+}
+
+#[test]
+fn synthetic_snapshot_rankings() {
+    let date = |month: u32, day: u32| {
+        NaiveDate::from_ymd_opt(2021, month, day)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+    };
+    let plays = vec![
+        (date(1, 5), "a"),
+        (date(1, 6), "a"),
+        (date(1, 7), "a"),
+        (date(1, 20), "b"),
+        (date(5, 1), "b"),
+        (date(5, 2), "c"),
+        (date(5, 3), "c"),
+        (date(9, 1), "d"),
+    ];
+
+    assert_eq!(
+        synthetic_snapshot_times(&plays, SyntheticSnapshotPeriod::Month),
+        vec![date(1, 20), date(5, 3), date(9, 1)]
+    );
+    assert_eq!(
+        synthetic_snapshot_times(&plays[4..7], SyntheticSnapshotPeriod::Day),
+        vec![date(5, 1), date(5, 2), date(5, 3)]
+    );
+
+    let [short, _, long] = synthesize_rankings(&plays, date(5, 3), 10);
+    assert_eq!(short, vec!["c", "b"]);
+    // b and c have the same number of plays but c was played more recently
+    assert_eq!(long, vec!["a", "c", "b"]);
+
+    let [short, medium, long] = synthesize_rankings(&plays, date(9, 1), 2);
+    assert_eq!(short, vec!["d"]);
+    assert_eq!(medium, vec!["c", "d"]);
+    assert_eq!(long, vec!["a", "c"]);
+}
+
+#[test]
+fn artist_graph_edge_building() {
+    let to_ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+    let artist_ids = ["a", "b", "c"];
+    let related_artists = vec![to_ids(&["b", "x"]), to_ids(&["a", "c"]), to_ids(&["c"])];
+
+    assert_eq!(
+        build_artist_graph_edges(&artist_ids, &related_artists),
+        vec![
+            ArtistGraphEdge {
+                source: "a".to_owned(),
+                target: "b".to_owned(),
+                mutual: true,
+            },
+            ArtistGraphEdge {
+                source: "b".to_owned(),
+                target: "c".to_owned(),
+                mutual: false,
+            },
+        ]
+    );
+    assert!(build_artist_graph_edges(&[], &[]).is_empty());
+}
+
+#[test]
+fn diversity_scores() {
+    assert_eq!(compute_genre_entropy(["rock", "rock"]), 0.);
+    assert_eq!(compute_genre_entropy(["rock", "pop", "jazz", "folk"]), 2.);
+    assert_eq!(compute_genre_entropy([]), 0.);
+
+    assert_eq!(compute_artist_gini(&["a", "b", "c", "d"]), 0.);
+    assert_eq!(compute_artist_gini(&["a", "a", "a", "a"]), 0.75);
+    assert_eq!(compute_artist_gini(&["a", "a", "b", "b"]), 0.5);
+    assert_eq!(compute_artist_gini(&[]), 0.);
+}
+
+#[test]
+fn genre_timeline() {
+    let artist = |genres: &[&str]| Artist {
+        genres: Some(genres.iter().map(|genre| genre.to_string()).collect()),
+        ..Artist::placeholder("")
+    };
+    let artists = [
+        artist(&["hyperpop", "pop"]),
+        artist(&["pop"]),
+        artist(&["jazz"]),
+    ];
+    let weights = compute_genre_weights(&artists, 2);
+    assert_eq!(weights[0].0, "pop");
+    assert_eq!(weights[1].0, "hyperpop");
+    assert_eq!(
+        weights[0].1,
+        weight_data_point(3, 0) + weight_data_point(3, 1)
+    );
+    assert!(compute_genre_weights(&[], MAX_SNAPSHOT_GENRES).is_empty());
+
+    let time = |day| {
+        NaiveDate::from_ymd_opt(2024, 3, day)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+    };
+    let (timestamps, genres) = build_genre_timeline(
+        &[
+            (time(1), "pop", 10),
+            (time(2), "hyperpop", 30),
+            (time(2), "pop", 5),
+            (time(3), "jazz", 1),
+            (time(3), "k-pop", 3),
+            (time(3), "korean pop", 4),
+        ],
+        3,
+    );
+    assert_eq!(timestamps, vec![time(1), time(2), time(3)]);
+    let genres: Vec<(&str, &str, Vec<Option<u32>>)> = genres
+        .iter()
+        .map(|(genre, weights)| {
+            (
+                genre.slug.as_str(),
+                genre.display_name.as_str(),
+                weights.clone(),
+            )
+        })
+        .collect();
+    assert_eq!(genres, vec![
+        ("hyperpop", "hyperpop", vec![None, Some(30), None]),
+        ("pop", "pop", vec![Some(10), Some(5), None]),
+        ("k-pop", "K-Pop", vec![None, None, Some(7)]),
+    ]);
+}
+
+#[test]
+fn popularity_trends() {
+    let time = |day| {
+        NaiveDate::from_ymd_opt(2024, 3, day)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+    };
+    assert_eq!(
+        compute_linear_trend(&[(time(1), 50.), (time(11), 60.), (time(21), 70.)]),
+        Some(30.)
+    );
+    assert_eq!(
+        compute_linear_trend(&[(time(1), 80.), (time(16), 70.)]),
+        Some(-20.)
+    );
+    assert_eq!(
+        compute_linear_trend(&[(time(1), 50.), (time(1), 60.)]),
+        None
+    );
+    assert_eq!(compute_linear_trend(&[(time(1), 50.)]), None);
+    assert_eq!(compute_linear_trend(&[]), None);
+}
+
+#[test]
+fn mainstream_scores() {
+    let score = |entities: &[(u8, u8)]| compute_mainstream_score(entities.iter().copied());
+    assert_eq!(score(&[]), None);
+    assert!((score(&[(0, 40), (1, 40), (2, 40)]).unwrap() - 40.).abs() < 1e-4);
+    // The 11th-ranked entity (ranking 10) counts half as much as the top one
+    assert!((score(&[(0, 90), (10, 30)]).unwrap() - 70.).abs() < 1e-4);
+    assert!(score(&[(0, 80), (1, 20)]).unwrap() > 50.);
+}
+
+#[test]
+fn timeframe_crossover() {
+    let artist = Artist::placeholder;
+    let entities = vec![
+        (Timeframe::Short, artist("a")),
+        (Timeframe::Short, artist("b")),
+        (Timeframe::Short, artist("c")),
+        (Timeframe::Medium, artist("c")),
+        (Timeframe::Medium, artist("d")),
+        (Timeframe::Long, artist("e")),
+        (Timeframe::Long, artist("d")),
+        (Timeframe::Long, artist("a")),
+        (Timeframe::Long, artist("f")),
+    ];
+    let crossover = compute_timeframe_crossover(&entities);
+    let ids = |artists: &[Artist]| -> Vec<String> {
+        artists.iter().map(|artist| artist.id.clone()).collect()
+    };
+    assert_eq!(ids(&crossover.graduated), vec!["a", "c"]);
+    assert_eq!(ids(&crossover.fallen_out), vec!["e", "f"]);
+}
+
+#[test]
+fn duplicate_track_merging() {
+    let to_ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+    let update_time = NaiveDate::from_ymd_opt(2024, 1, 1)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap();
+    let mut history = vec![(update_time, TimeFrames {
+        short: to_ids(&["single", "b", "album"]),
+        medium: to_ids(&["b", "album"]),
+        long: Vec::new(),
+    })];
+    let canonical_ids: HashMap<String, String> = [("single".to_owned(), "album".to_owned())]
+        .into_iter()
+        .collect();
+
+    merge_duplicate_tracks(&mut history, &canonical_ids);
+    assert_eq!(history[0].1.short, to_ids(&["album", "b"]));
+    assert_eq!(history[0].1.medium, to_ids(&["b", "album"]));
+    assert!(history[0].1.long.is_empty());
+}
@@ -0,0 +1,72 @@
+//! Encryption of users' Spotify access and refresh tokens at rest.  Tokens are encrypted with
+//! AES-256-GCM using the key from `TOKEN_ENCRYPTION_KEY` and stored as a prefixed, base64-encoded
+//! nonce followed by the ciphertext.  Tokens are stored in plaintext if no key is configured.
+//!
+//! Values without the prefix are treated as plaintext when read so that rows written before
+//! encryption was enabled keep working until they're encrypted by `POST /admin/encrypt_tokens`.
+
+use base64::engine::general_purpose::STANDARD;
+
+use crate::{conf::CONF, error::Error, sealing};
+
+const ENCRYPTED_TOKEN_PREFIX: &str = "enc1:";
+
+pub(crate) type TokenEncryptionKey = [u8; 32];
+
+pub(crate) fn is_encrypted(stored: &str) -> bool { stored.starts_with(ENCRYPTED_TOKEN_PREFIX) }
+
+/// Encrypts a token for storage with the configured key, returning it unchanged if encryption is
+/// disabled
+pub(crate) fn encrypt_token(token: &str) -> String {
+    match &CONF.token_encryption_key {
+        Some(key) => encrypt_token_with_key(key, token),
+        None => token.to_owned(),
+    }
+}
+
+/// Decrypts a stored token with the configured key.  Tokens that were stored in plaintext are
+/// returned as-is.
+pub(crate) fn decrypt_token(stored: &str) -> Result<String, Error> {
+    if !is_encrypted(stored) {
+        return Ok(stored.to_owned());
+    }
+
+    match &CONF.token_encryption_key {
+        Some(key) => decrypt_token_with_key(key, stored),
+        None => Err(Error::Internal(
+            "Found an encrypted token, but `TOKEN_ENCRYPTION_KEY` isn't set".into(),
+        )),
+    }
+}
+
+fn encrypt_token_with_key(key: &TokenEncryptionKey, token: &str) -> String {
+    format!(
+        "{}{}",
+        ENCRYPTED_TOKEN_PREFIX,
+        sealing::seal(key, &STANDARD, token.as_bytes())
+    )
+}
+
+fn decrypt_token_with_key(key: &TokenEncryptionKey, stored: &str) -> Result<String, Error> {
+    let plaintext = sealing::open(key, &STANDARD, &stored[ENCRYPTED_TOKEN_PREFIX.len()..])
+        .map_err(|err| Error::Internal(format!("Failed to decrypt token: {}", err)))?;
+    String::from_utf8(plaintext)
+        .map_err(|_| Error::Internal("Decrypted token isn't valid UTF-8".to_owned()))
+}
+
+#[test]
+fn token_encryption_round_trip() {
+    let key = [7u8; 32];
+    let encrypted = encrypt_token_with_key(&key, "spotify-token");
+    assert!(is_encrypted(&encrypted));
+    assert!(!encrypted.contains("spotify-token"));
+    // Every encryption uses a new nonce
+    assert_ne!(encrypted, encrypt_token_with_key(&key, "spotify-token"));
+    assert_eq!(
+        decrypt_token_with_key(&key, &encrypted).ok().as_deref(),
+        Some("spotify-token")
+    );
+
+    assert!(decrypt_token_with_key(&[8u8; 32], &encrypted).is_err());
+    assert!(!is_encrypted("spotify-token"));
+}